@@ -0,0 +1,136 @@
+use {
+    http::{header::*, *},
+    http_body_util::*,
+    kutil::std::immutable::*,
+    std::{convert::*, future, result::Result, task::*},
+    tower::*,
+    tower_http_response_cache::{
+        cache::{
+            implementation::{lru::*, moka::*},
+            *,
+        },
+        *,
+    },
+};
+
+/// Body sizes by name.
+#[allow(unused)]
+pub const BODY_SIZES: &[(&str, usize)] = &[("small", 1024), ("medium", 64 * 1024)];
+
+/// `Accept-Encoding` values. We store all of their representations while priming, so that the
+/// measured requests are all hits.
+#[allow(unused)]
+pub const ACCEPT_ENCODINGS: &[&str] = &["identity", "gzip"];
+
+/// Worker thread counts for throughput.
+#[allow(unused)]
+pub const THREADS: &[usize] = &[1, 4, 16, 32];
+
+/// Service under benchmark.
+pub type BenchService = DefaultCachingService<StaticUpstream, ()>;
+
+/// Caching service in which all requests for "/" are hits.
+#[allow(unused)]
+pub async fn primed_service(body_size: usize) -> BenchService {
+    primed_service_for(body_size, ACCEPT_ENCODINGS).await
+}
+
+/// Caching service in which requests for "/" with these `Accept-Encoding` values are hits.
+pub async fn primed_service_for(body_size: usize, accept_encodings: &[&str]) -> BenchService {
+    let cache = moka::future::Cache::<CommonCacheKey, _, _>::builder()
+        .for_http_response()
+        .max_capacity(64 * 1024 * 1024)
+        .build();
+
+    let mut service = DefaultCachingLayer::<()>::default()
+        .cache(MokaCacheImplementation::new(cache))
+        .layer(StaticUpstream::new(body_size));
+
+    // The first is a miss, the rest add their representations
+    for accept_encoding in accept_encodings {
+        hit(&mut service, accept_encoding).await;
+    }
+
+    service
+}
+
+/// Like [primed_service] but with the simple LRU cache implementation, which can answer lookups
+/// without waiting (see [get_if_present](Cache::get_if_present)).
+#[allow(unused)]
+pub async fn primed_lru_service(
+    body_size: usize,
+) -> CachingService<StaticUpstream, (), SimpleLruCache> {
+    let mut service = CachingLayer::<(), SimpleLruCache>::default()
+        .cache(SimpleLruCache::new(64 * 1024 * 1024, None))
+        .layer(StaticUpstream::new(body_size));
+
+    for accept_encoding in ACCEPT_ENCODINGS {
+        hit(&mut service, accept_encoding).await;
+    }
+
+    service
+}
+
+/// Request for "/".
+pub fn request(accept_encoding: &str) -> Request<()> {
+    Request::get("/")
+        .header(ACCEPT_ENCODING, accept_encoding)
+        .body(())
+        .expect("Request::get")
+}
+
+/// Request "/" and read the body.
+///
+/// Returns the body size.
+pub async fn hit<CacheT>(
+    service: &mut CachingService<StaticUpstream, (), CacheT>,
+    accept_encoding: &str,
+) -> usize
+where
+    CacheT: Cache,
+{
+    let response = service
+        .oneshot_ready(request(accept_encoding))
+        .await
+        .expect("oneshot_ready");
+    response
+        .into_body()
+        .collect()
+        .await
+        .expect("collect")
+        .to_bytes()
+        .len()
+}
+
+//
+// StaticUpstream
+//
+
+/// Upstream that always responds with the same (compressible) body.
+#[derive(Clone)]
+pub struct StaticUpstream {
+    body: Bytes,
+}
+
+impl StaticUpstream {
+    /// Constructor.
+    pub fn new(body_size: usize) -> Self {
+        let mut body = "Hello, world!\n".repeat(body_size / 14 + 1);
+        body.truncate(body_size);
+        Self { body: body.into() }
+    }
+}
+
+impl Service<Request<()>> for StaticUpstream {
+    type Response = Response<Full<Bytes>>;
+    type Error = Infallible;
+    type Future = future::Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _context: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _request: Request<()>) -> Self::Future {
+        future::ready(Ok(Response::new(Full::new(self.body.clone()))))
+    }
+}
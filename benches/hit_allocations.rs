@@ -0,0 +1,71 @@
+mod common;
+
+use {
+    common::*,
+    std::{alloc::*, sync::atomic::*},
+    tokio::runtime::*,
+};
+
+// Asserts the number of heap allocations made by the middleware for a single hit
+//
+// Counts from the call until the response is returned, so the request (made by the client) and
+// reading the body (done by the server) are not included. The Tower boxed future, the cache key,
+// and the response's header map are inherent. Anything beyond the budget is a regression.
+
+const MAX_HIT_ALLOCATIONS: usize = 32;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, pointer: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(pointer, layout) }
+    }
+
+    unsafe fn realloc(&self, pointer: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(pointer, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn main() {
+    let runtime = Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Builder::build");
+
+    runtime.block_on(async {
+        let mut failed = false;
+
+        for (name, body_size) in BODY_SIZES {
+            let mut service = primed_service(*body_size).await;
+
+            for accept_encoding in ACCEPT_ENCODINGS {
+                // Warm up (lazy initialization in the cache, tracing callsites, etc.)
+                hit(&mut service, accept_encoding).await;
+
+                let request = request(accept_encoding);
+                let before = ALLOCATIONS.load(Ordering::Relaxed);
+                let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+                let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+                drop(response);
+
+                println!("{}/{}: {} allocations", name, accept_encoding, allocations);
+                if allocations > MAX_HIT_ALLOCATIONS {
+                    failed = true;
+                }
+            }
+        }
+
+        assert!(!failed, "more than {} allocations for a hit", MAX_HIT_ALLOCATIONS);
+    });
+}
@@ -0,0 +1,34 @@
+mod common;
+
+use {common::*, criterion::*, tokio::runtime::Runtime};
+
+// Hit throughput for a 64 KiB body served from a stored representation, by coding
+//
+// Every coding here is stored while priming, so the measured requests neither encode nor decode,
+// and differences are in header handling and in copying the body through its wrapper.
+
+const BODY_SIZE: usize = 64 * 1024;
+
+const CODINGS: &[&str] = &["identity", "br"];
+
+fn hit_codings(criterion: &mut Criterion) {
+    let runtime = Runtime::new().expect("Runtime::new");
+    let service = runtime.block_on(primed_service_for(BODY_SIZE, CODINGS));
+
+    let mut group = criterion.benchmark_group("hit (64 KiB)");
+    group.throughput(Throughput::Elements(1));
+
+    for accept_encoding in CODINGS {
+        group.bench_function(*accept_encoding, |bencher| {
+            bencher.to_async(&runtime).iter(|| {
+                let mut service = service.clone();
+                async move { hit(&mut service, accept_encoding).await }
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, hit_codings);
+criterion_main!(benches);
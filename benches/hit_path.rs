@@ -0,0 +1,56 @@
+mod common;
+
+use {common::*, criterion::*, tokio::runtime::Runtime};
+
+// Latency of a single hit, by body size and coding
+//
+// The "hit (lru)" group uses a cache that answers lookups without waiting. To compare a change,
+// run with `--save-baseline before` and then `--baseline before`.
+
+fn hit_path(criterion: &mut Criterion) {
+    let runtime = Runtime::new().expect("Runtime::new");
+
+    let mut group = criterion.benchmark_group("hit");
+    group.throughput(Throughput::Elements(1));
+
+    for (name, body_size) in BODY_SIZES {
+        let service = runtime.block_on(primed_service(*body_size));
+
+        for accept_encoding in ACCEPT_ENCODINGS {
+            group.bench_function(format!("{}/{}", name, accept_encoding), |bencher| {
+                bencher.to_async(&runtime).iter(|| {
+                    // This is what Tower does for every call anyway
+                    let mut service = service.clone();
+                    async move { hit(&mut service, accept_encoding).await }
+                })
+            });
+        }
+    }
+
+    group.finish();
+}
+
+fn hit_path_lru(criterion: &mut Criterion) {
+    let runtime = Runtime::new().expect("Runtime::new");
+
+    let mut group = criterion.benchmark_group("hit (lru)");
+    group.throughput(Throughput::Elements(1));
+
+    for (name, body_size) in BODY_SIZES {
+        let service = runtime.block_on(primed_lru_service(*body_size));
+
+        for accept_encoding in ACCEPT_ENCODINGS {
+            group.bench_function(format!("{}/{}", name, accept_encoding), |bencher| {
+                bencher.to_async(&runtime).iter(|| {
+                    let mut service = service.clone();
+                    async move { hit(&mut service, accept_encoding).await }
+                })
+            });
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, hit_path, hit_path_lru);
+criterion_main!(benches);
@@ -0,0 +1,79 @@
+mod common;
+
+use {
+    common::*,
+    std::{env, time::*},
+    tokio::{runtime::*, task::*},
+};
+
+// Hit throughput (requests/second) by body size, coding, and worker threads
+//
+// Runs one request loop per worker thread against an in-process service (no sockets). Prints a
+// Markdown table, for PERFORMANCE.md.
+//
+// Set BENCH_SECONDS to change the measurement time per row (the default is 5).
+
+fn main() {
+    let seconds = env::var("BENCH_SECONDS")
+        .ok()
+        .and_then(|seconds| seconds.parse().ok())
+        .unwrap_or(5);
+    let duration = Duration::from_secs(seconds);
+
+    println!("| Body | Accept-Encoding | Threads | Requests/s |");
+    println!("|------|-----------------|--------:|-----------:|");
+
+    for (name, body_size) in BODY_SIZES {
+        for accept_encoding in ACCEPT_ENCODINGS {
+            for threads in THREADS {
+                let requests_per_second =
+                    throughput(*body_size, accept_encoding, *threads, duration);
+                println!(
+                    "| {} | {} | {} | {:.0} |",
+                    name, accept_encoding, threads, requests_per_second
+                );
+            }
+        }
+    }
+}
+
+fn throughput(
+    body_size: usize,
+    accept_encoding: &'static str,
+    threads: usize,
+    duration: Duration,
+) -> f64 {
+    let runtime = Builder::new_multi_thread()
+        .worker_threads(threads)
+        .enable_all()
+        .build()
+        .expect("Builder::build");
+
+    runtime.block_on(async move {
+        let service = primed_service(body_size).await;
+
+        let start = Instant::now();
+        let deadline = start + duration;
+
+        let tasks: Vec<_> = (0..threads)
+            .map(|_| {
+                let mut service = service.clone();
+                spawn(async move {
+                    let mut requests = 0u64;
+                    while Instant::now() < deadline {
+                        hit(&mut service, accept_encoding).await;
+                        requests += 1;
+                    }
+                    requests
+                })
+            })
+            .collect();
+
+        let mut requests = 0;
+        for task in tasks {
+            requests += task.await.expect("task");
+        }
+
+        requests as f64 / start.elapsed().as_secs_f64()
+    })
+}
@@ -8,7 +8,7 @@ use {
     tokio::{net::*, *},
     tower_http::trace::*,
     tower_http_response_cache::{
-        cache::{axum::*, implementation::moka::*, *},
+        cache::{axum::*, implementation::moka::*, middleware::*, *},
         *,
     },
 };
@@ -143,6 +143,11 @@ async fn main() {
             "/quickie2",
             get(("This response also has a custom cache duration of 1 ms\n",)),
         )
+        .route(
+            "/quickie3",
+            get(("This response has a route policy with a cache duration of 1 ms\n",))
+                .cache(RoutePolicy::default().duration(Duration::from_millis(1))),
+        )
         .route(
             "/png",
             get((
@@ -39,6 +39,17 @@ const MAX_BODY_SIZE: usize = 1024; // 1 KiB
 async fn main() {
     utils::init_tracing();
 
+    let listener = TcpListener::bind("[::]:8080")
+        .await
+        .expect("TcpListener::bind");
+    // If IPv6 is disabled on your machine (for shame!):
+    // let listener = TcpListener::bind("0.0.0.0:8080").await.expect("bind");
+    tracing::info!("bound to: {:?}", listener.local_addr());
+    serve(listener, router()).await.expect("axum::serve");
+}
+
+/// Router with our caching layer.
+pub fn router() -> Router {
     // Construct a Moka cache according to your preferences
 
     let cache = Cache::<CommonCacheKey, _, _>::builder()
@@ -55,7 +66,7 @@ async fn main() {
 
     // All you need to do is add our layer to the router
 
-    let router = Router::default()
+    Router::default()
         .route("/", get(("Hello, world!\n",)))
         .layer(
             CachingLayer::default()
@@ -63,13 +74,5 @@ async fn main() {
                 .max_cacheable_body_size(MAX_BODY_SIZE)
                 .keep_identity_encoding(false),
         )
-        .layer(TraceLayer::new_for_http());
-
-    let listener = TcpListener::bind("[::]:8080")
-        .await
-        .expect("TcpListener::bind");
-    // If IPv6 is disabled on your machine (for shame!):
-    // let listener = TcpListener::bind("0.0.0.0:8080").await.expect("bind");
-    tracing::info!("bound to: {:?}", listener.local_addr());
-    serve(listener, router).await.expect("axum::serve");
+        .layer(TraceLayer::new_for_http())
 }
@@ -0,0 +1,223 @@
+mod utils;
+
+use {
+    http::{header::*, *},
+    http_body_util::*,
+    hyper::{body::*, server::conn::http1},
+    hyper_util::{
+        client::legacy::Client,
+        rt::{TokioExecutor, TokioIo},
+        service::*,
+    },
+    moka::future::Cache as MokaCache,
+    std::{
+        convert::*,
+        net::SocketAddr,
+        sync::{atomic::*, *},
+        time::*,
+    },
+    tokio::{net::*, *},
+    tower::*,
+    tower_http_response_cache::{
+        cache::{implementation::moka::*, middleware::*, *},
+        *,
+    },
+};
+
+// (See hyper_plain.rs first)
+//
+// Caching the responses of an outbound Hyper client
+//
+// We start a mock "partner" server and then send it requests through a cached client. The partner
+// controls caching via standard headers: its quotes are fresh for 1 second and have an ETag, while
+// its account data is private and its news varies by cookie (neither is stored).
+//
+// Pay attention to the tracing log to see what our middleware and the cache are doing!
+
+const CACHE_SIZE: u64 = 1024 * 1024; // 1 MiB
+
+// Expired entries are retained for this long so that they can be revalidated
+const CACHE_GRACE: Duration = Duration::from_secs(60);
+
+const FRESHNESS: Duration = Duration::from_secs(1);
+
+#[main]
+async fn main() {
+    utils::init_tracing();
+
+    let partner = Partner::default();
+    let address = partner.clone().serve().await;
+
+    let cache = MokaCache::<CommonCacheKey, _, _>::builder()
+        .name("client")
+        .for_http_response_with_grace(CACHE_GRACE)
+        .max_capacity(CACHE_SIZE)
+        .build();
+
+    let cache = MokaCacheImplementation::new(cache);
+
+    // Hyper's Incoming can't be created from bytes, thus the ClientBody adapter
+
+    let client = Client::builder(TokioExecutor::new()).build_http::<Empty<Bytes>>();
+
+    let mut service = ServiceBuilder::new()
+        .layer(DefaultCachingLayer::<Empty<Bytes>>::for_client().cache(cache.clone()))
+        .map_response(|response: Response<Incoming>| response.map(ClientBody::Upstream))
+        .service(client);
+
+    let mut get = async |path: &str| {
+        let request = Request::get(format!("http://{}{}", address, path))
+            .body(Empty::new())
+            .expect("Request::builder");
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+        let body = response.into_body().collect().await.expect("collect").to_bytes();
+        String::from_utf8_lossy(&body).into_owned()
+    };
+
+    // Miss, then hit
+    assert_eq!(get("/quote").await, "quote v1\n");
+    assert_eq!(get("/quote").await, "quote v1\n");
+    assert_eq!(partner.calls("/quote"), 1);
+
+    // Expired, so we revalidate with If-None-Match and the partner answers 304
+    time::sleep(FRESHNESS + Duration::from_millis(500)).await;
+    assert_eq!(get("/quote").await, "quote v1\n");
+    assert_eq!(partner.calls("/quote"), 2);
+    assert_eq!(partner.not_modified.load(Ordering::Relaxed), 1);
+
+    // Fresh again
+    assert_eq!(get("/quote").await, "quote v1\n");
+    assert_eq!(partner.calls("/quote"), 2);
+
+    // The partner changed the quote and told us out of band, so we invalidate (this works for
+    // all layers sharing the backend)
+    partner.version.fetch_add(1, Ordering::Relaxed);
+    cache.invalidate_all().await;
+    assert_eq!(get("/quote").await, "quote v2\n");
+    assert_eq!(partner.calls("/quote"), 3);
+
+    // Cache-Control: private (not stored)
+    get("/account").await;
+    get("/account").await;
+    assert_eq!(partner.calls("/account"), 2);
+
+    // Vary: Cookie (not stored)
+    get("/news").await;
+    get("/news").await;
+    assert_eq!(partner.calls("/news"), 2);
+
+    tracing::info!("all good!");
+}
+
+//
+// Partner
+//
+
+#[derive(Clone, Default)]
+struct Partner {
+    version: Arc<AtomicU64>,
+    quote_calls: Arc<AtomicU64>,
+    account_calls: Arc<AtomicU64>,
+    news_calls: Arc<AtomicU64>,
+    not_modified: Arc<AtomicU64>,
+}
+
+impl Partner {
+    async fn serve(self) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("TcpListener::bind");
+        let address = listener.local_addr().expect("TcpListener::local_addr");
+        tracing::info!("partner bound to: {}", address);
+
+        spawn(async move {
+            loop {
+                let (stream, _address) = listener.accept().await.expect("TcpListener::accept");
+
+                let partner = self.clone();
+                let service = TowerToHyperService::new(service_fn(move |request| {
+                    let partner = partner.clone();
+                    async move { Ok::<_, Infallible>(partner.route(request)) }
+                }));
+
+                spawn(async move {
+                    if let Err(error) = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await
+                    {
+                        tracing::error!("partner connection: {}", error);
+                    }
+                });
+            }
+        });
+
+        address
+    }
+
+    fn calls(&self, path: &str) -> u64 {
+        match path {
+            "/quote" => &self.quote_calls,
+            "/account" => &self.account_calls,
+            "/news" => &self.news_calls,
+            _ => panic!("unknown path: {}", path),
+        }
+        .load(Ordering::Relaxed)
+    }
+
+    fn route(&self, request: Request<Incoming>) -> Response<Full<Bytes>> {
+        let cache_control = format!("max-age={}", FRESHNESS.as_secs());
+
+        match request.uri().path() {
+            "/quote" => {
+                self.quote_calls.fetch_add(1, Ordering::Relaxed);
+
+                let version = self.version.load(Ordering::Relaxed) + 1;
+                let etag = format!("\"v{}\"", version);
+
+                if request
+                    .headers()
+                    .get(IF_NONE_MATCH)
+                    .is_some_and(|if_none_match| if_none_match == etag.as_str())
+                {
+                    self.not_modified.fetch_add(1, Ordering::Relaxed);
+                    return Response::builder()
+                        .status(StatusCode::NOT_MODIFIED)
+                        .header(ETAG, etag)
+                        .header(CACHE_CONTROL, cache_control)
+                        .body(Full::default())
+                        .expect("Response::builder");
+                }
+
+                Response::builder()
+                    .header(ETAG, etag)
+                    .header(CACHE_CONTROL, cache_control)
+                    .body(Full::from(format!("quote v{}\n", version)))
+                    .expect("Response::builder")
+            }
+
+            "/account" => {
+                self.account_calls.fetch_add(1, Ordering::Relaxed);
+
+                Response::builder()
+                    .header(CACHE_CONTROL, format!("private, {}", cache_control))
+                    .body(Full::from("account\n"))
+                    .expect("Response::builder")
+            }
+
+            "/news" => {
+                self.news_calls.fetch_add(1, Ordering::Relaxed);
+
+                Response::builder()
+                    .header(CACHE_CONTROL, cache_control)
+                    .header(VARY, "Cookie")
+                    .body(Full::from("news\n"))
+                    .expect("Response::builder")
+            }
+
+            _ => Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Full::default())
+                .expect("Response::builder"),
+        }
+    }
+}
@@ -0,0 +1,127 @@
+mod utils;
+
+use {
+    http::*,
+    http_body_util::*,
+    hyper::{body::*, server::conn::http1},
+    hyper_util::{rt::TokioIo, service::*},
+    moka::future::Cache,
+    std::{convert::*, result::Result, time::*},
+    tokio::{net::*, *},
+    tower::*,
+    tower_http_response_cache::{
+        cache::{implementation::moka::*, *},
+        *,
+    },
+};
+
+// (See basic.rs first)
+//
+// Plain Hyper server (no axum) with Kutil's caching middleware for Tower
+//
+// Pay attention to the tracing log to see what our middleware and the cache are doing!
+// (Entries will be expired from the cache after 10 seconds)
+//
+// You can send requests via CLI. Some fun examples:
+//
+//   curl http://localhost:8080
+//
+//   curl --verbose --compressed http://localhost:8080/long
+//
+//   curl http://localhost:8080/nevercache
+
+const CACHE_SIZE: u64 = 1024 * 1024; // 1 MiB
+
+const CACHE_DURATION: Duration = Duration::from_secs(10);
+
+const MAX_BODY_SIZE: usize = 16 * 1024; // 16 KiB
+
+#[main]
+async fn main() {
+    utils::init_tracing();
+
+    let service = service();
+
+    let listener = TcpListener::bind("[::]:8080")
+        .await
+        .expect("TcpListener::bind");
+    tracing::info!("bound to: {:?}", listener.local_addr());
+
+    loop {
+        let (stream, _address) = listener.accept().await.expect("TcpListener::accept");
+
+        // Hyper has its own Service trait, thus the adapter
+        let service = TowerToHyperService::new(service.clone());
+
+        spawn(async move {
+            if let Err(error) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::error!("connection: {}", error);
+            }
+        });
+    }
+}
+
+/// Caching service over our route.
+///
+/// Hyper gives us Incoming request bodies, but the route ignores them, so any type will do.
+pub fn service<RequestBodyT>() -> CachingService<
+    impl 'static
+        + Service<
+            Request<RequestBodyT>,
+            Response = Response<Full<Bytes>>,
+            Error = Infallible,
+            Future: Send,
+        >
+        + Clone
+        + Send,
+    RequestBodyT,
+    MokaCacheImplementation,
+    CommonCacheKey,
+>
+where
+    RequestBodyT: 'static + Send,
+{
+    let cache = Cache::<CommonCacheKey, _, _>::builder()
+        .name("http")
+        .for_http_response()
+        .max_capacity(CACHE_SIZE)
+        .time_to_live(CACHE_DURATION)
+        .build();
+
+    let cache = MokaCacheImplementation::new(cache);
+
+    // Our layer is applied to a plain Tower service
+    //
+    // The response body must implement From<Bytes> (and so must its data), which is the case
+    // for http-body-util's Full (see the route function below)
+
+    ServiceBuilder::new()
+        .layer(
+            DefaultCachingLayer::default()
+                .cache(cache)
+                .max_cacheable_body_size(MAX_BODY_SIZE),
+        )
+        .service(service_fn(route))
+}
+
+async fn route<RequestBodyT>(request: Request<RequestBodyT>) -> Result<Response<Full<Bytes>>, Infallible> {
+    Ok(match request.uri().path() {
+        "/" => Response::new(Full::from("Hello, world!\n")),
+
+        // Long enough to be worth encoding
+        "/long" => Response::new(Full::from("Hello, world!\n".repeat(100))),
+
+        "/nevercache" => Response::builder()
+            .header("XX-Cache", "false")
+            .body(Full::from("This response is never cached\n"))
+            .expect("Response::builder"),
+
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Full::default())
+            .expect("Response::builder"),
+    })
+}
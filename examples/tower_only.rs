@@ -0,0 +1,89 @@
+mod utils;
+
+use {
+    http::{header::*, *},
+    http_body_util::*,
+    kutil::std::immutable::*,
+    moka::future::Cache,
+    std::{
+        convert::*,
+        sync::{atomic::*, *},
+    },
+    tokio::*,
+    tower::*,
+    tower_http_response_cache::{
+        cache::{implementation::moka::*, *},
+        *,
+    },
+};
+
+// (See basic.rs first)
+//
+// Driving the caching service directly, without an HTTP server
+//
+// This is a useful template for embedding the middleware in tests or in non-server contexts.
+// Pay attention to the tracing log to see what our middleware and the cache are doing!
+
+#[main]
+async fn main() {
+    utils::init_tracing();
+
+    // We count upstream calls in order to show hits
+    let upstream_calls = Arc::new(AtomicUsize::default());
+
+    let mut service = service(upstream_calls.clone());
+
+    // Miss, then hit
+    for _ in 0..2 {
+        let response = service
+            .oneshot_ready(Request::get("/").body(()).expect("Request::get"))
+            .await
+            .expect("oneshot_ready");
+        tracing::info!("X-Cache: {:?}", response.headers().get("x-cache"));
+        let body = response.into_body().collect().await.expect("collect");
+        tracing::info!("body size: {}", body.to_bytes().len());
+    }
+
+    // Hit with encoding (encoded from the cached entry)
+    let response = service
+        .oneshot_ready(
+            Request::get("/")
+                .header(ACCEPT_ENCODING, "gzip")
+                .body(())
+                .expect("Request::get"),
+        )
+        .await
+        .expect("oneshot_ready");
+    tracing::info!("Content-Encoding: {:?}", response.headers().get(CONTENT_ENCODING));
+
+    tracing::info!("upstream calls: {}", upstream_calls.load(Ordering::Relaxed));
+}
+
+/// Caching service over an upstream that counts its calls.
+pub fn service(
+    upstream_calls: Arc<AtomicUsize>,
+) -> CachingService<
+    impl 'static
+        + Service<Request<()>, Response = Response<Full<Bytes>>, Error = Infallible, Future: Send>
+        + Clone
+        + Send,
+    (),
+    MokaCacheImplementation,
+    CommonCacheKey,
+> {
+    let cache = Cache::<CommonCacheKey, _, _>::builder()
+        .for_http_response()
+        .max_capacity(1024 * 1024)
+        .build();
+
+    let upstream = service_fn(move |_request: Request<()>| {
+        upstream_calls.fetch_add(1, Ordering::Relaxed);
+        let body = Full::<Bytes>::from("Hello, world!\n".repeat(100));
+        async { Ok::<_, Infallible>(Response::new(body)) }
+    });
+
+    DefaultCachingLayer::default()
+        .cache(MokaCacheImplementation::new(cache))
+        .expose_cache_status_header(HeaderName::from_static("x-cache"))
+        .layer(upstream)
+}
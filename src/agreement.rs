@@ -0,0 +1,211 @@
+use super::cache::*;
+
+use {
+    http::{header::*, *},
+    std::{fmt, result::Result},
+};
+
+//
+// KeyAgreement
+//
+
+/// Result of [key_agreement_test].
+///
+/// Compare the [Display](fmt::Display) of results from different platforms, architectures, or
+/// builds: if they differ then the processes would not share cache entries.
+#[derive(Clone, Debug)]
+pub struct KeyAgreement {
+    /// [CANONICAL_KEY_FORM_VERSION].
+    pub version: u8,
+
+    /// [stable_hash] of the concatenated (big-endian) key digests.
+    pub digest: u64,
+
+    /// [Canonical digest](CanonicalKeyForm::canonical_digest) of the key of each request, in
+    /// corpus order.
+    pub key_digests: Vec<u64>,
+}
+
+impl fmt::Display for KeyAgreement {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "v{}:{:016x}", self.version, self.digest)
+    }
+}
+
+/// Create the key of each request in the corpus with [CacheKey::for_request] and digest them.
+///
+/// See [key_agreement_test_with].
+pub fn key_agreement_test<CacheKeyT>(corpus: &[Request<()>]) -> KeyAgreement
+where
+    CacheKeyT: CacheKey + CanonicalKeyForm,
+{
+    key_agreement_test_with(corpus, |request| {
+        CacheKeyT::for_request(request.method(), request.uri(), request.headers())
+    })
+}
+
+/// Create the key of each request in the corpus and digest them.
+///
+/// `key_for` should create keys the way the application does, e.g. including the changes made by
+/// its cache key hook. Each key is created twice, and the function panics if the two differ,
+/// because then the keys are not even deterministic within the process.
+///
+/// Intended to be called from a test that each CI platform runs, with the results compared:
+///
+/// ```no_run
+/// # use tower_http_response_cache::{agreement::*, cache::*};
+/// # macro_rules! include_str { ($path:literal) => { "" }; }
+/// #[test]
+/// fn key_agreement() {
+///     let agreement = key_agreement_test::<CommonCacheKey>(&synthetic_key_corpus());
+///     assert_eq!(agreement.to_string(), include_str!("key-agreement.txt").trim());
+/// }
+/// ```
+pub fn key_agreement_test_with<CacheKeyT>(
+    corpus: &[Request<()>],
+    key_for: impl Fn(&Request<()>) -> CacheKeyT,
+) -> KeyAgreement
+where
+    CacheKeyT: CacheKey + CanonicalKeyForm,
+{
+    let mut bytes = Vec::with_capacity(corpus.len() * 8);
+    let mut key_digests = Vec::with_capacity(corpus.len());
+
+    for request in corpus {
+        let key_digest = key_for(request).canonical_digest();
+        assert_eq!(
+            key_digest,
+            key_for(request).canonical_digest(),
+            "nondeterministic key for {} {}",
+            request.method(),
+            request.uri()
+        );
+
+        bytes.extend_from_slice(&key_digest.to_be_bytes());
+        key_digests.push(key_digest);
+    }
+
+    KeyAgreement {
+        version: CANONICAL_KEY_FORM_VERSION,
+        digest: stable_hash(&bytes),
+        key_digests,
+    }
+}
+
+/// Synthetic corpus of requests that exercise key canonicalization.
+///
+/// Includes unordered and repeated query parameters, percent-encoding, non-ASCII and mixed-case
+/// paths, absolute-form targets, and content negotiation headers.
+pub fn synthetic_key_corpus() -> Vec<Request<()>> {
+    const TARGETS: &[&str] = &[
+        "/",
+        "/index.html",
+        "/Index.HTML",
+        "/a/b/c/",
+        "/search?q=cache&lang=en",
+        "/search?lang=en&q=cache",
+        "/search?q=a&q=b&q=a",
+        "/search?q=%E7%BC%93%E5%AD%98",
+        "/search?q=caf%C3%A9&empty=",
+        "/%7Euser/caf%C3%A9",
+        "/path%2Fwith%2Fslashes",
+        "/api/v1/items?limit=10&offset=20&sort=-created",
+        "https://Example.com:8443/absolute?x=1",
+        "http://example.com/absolute",
+    ];
+
+    let mut corpus = Vec::default();
+
+    for method in [Method::GET, Method::HEAD, Method::OPTIONS] {
+        for target in TARGETS {
+            for (name, value) in [
+                (None, ""),
+                (Some(ACCEPT_ENCODING), "gzip, br;q=0.9"),
+                (Some(ACCEPT_LANGUAGE), "de-AT, en;q=0.5"),
+                (Some(HOST), "Example.com"),
+            ] {
+                let mut request = Request::builder().method(method.clone()).uri(*target);
+                if let Some(name) = name {
+                    request = request.header(name, value);
+                }
+                corpus.push(request.body(()).expect("request"));
+            }
+        }
+    }
+
+    corpus
+}
+
+/// Representative [CommonCacheKey]s and the hex of their exact
+/// [canonical bytes](CanonicalKeyForm::canonical_bytes).
+///
+/// These pin the canonical form: caches shared between deployments of different versions of
+/// this library (e.g. a persistent remote tier) only keep working across an upgrade if the form
+/// is unchanged. A change to [CommonCacheKey] that breaks them would orphan every shared entry.
+/// Either make the change continuity-preserving (a new field with a new tag, omitted when absent;
+/// see [write_canonical_field]), or, if it's deliberate, increment
+/// [CANONICAL_KEY_FORM_VERSION], update the fixtures, and document the migration (see
+/// [KeyMigratingCache]).
+///
+/// See [check_canonical_key_fixtures].
+pub fn canonical_key_fixtures() -> Vec<(CommonCacheKey, &'static str)> {
+    let key = |method: Method, path: &str| {
+        CommonCacheKey::new(method, Some(path.into()), None, None, None, None, None, None, None)
+    };
+
+    let mut absolute = key(Method::GET, "/absolute");
+    absolute.scheme = Some(uri::Scheme::HTTPS);
+    absolute.host = Some("example.com".into());
+    absolute.port = Some(8443);
+
+    let mut partitioned = key(Method::GET, "/reports");
+    partitioned.generation = Some(7);
+    partitioned.partition = Some("tenant-1".into());
+
+    vec![
+        (key(Method::GET, "/"), "01010000000347455402000000012f"),
+        (
+            key(Method::HEAD, "/index.html"),
+            "01010000000448454144020000000b2f696e6465782e68746d6c",
+        ),
+        (
+            absolute,
+            "01010000000347455402000000092f6162736f6c75746504000000056874747073050000000b6578616d70\
+             6c652e636f6d060000000220fb",
+        ),
+        (
+            partitioned,
+            "01010000000347455402000000082f7265706f7274730a0000000800000000000000070b000000087465\
+             6e616e742d31",
+        ),
+    ]
+}
+
+/// Check the [canonical_key_fixtures].
+///
+/// Returns a description of each mismatch. Intended to be called from a test:
+///
+/// ```no_run
+/// # use tower_http_response_cache::agreement::*;
+/// #[test]
+/// fn canonical_key_form() {
+///     check_canonical_key_fixtures().unwrap();
+/// }
+/// ```
+pub fn check_canonical_key_fixtures() -> Result<(), Vec<String>> {
+    let mismatches: Vec<_> = canonical_key_fixtures()
+        .into_iter()
+        .filter_map(|(key, expected)| {
+            let actual: String =
+                key.canonical_bytes().iter().map(|byte| format!("{:02x}", byte)).collect();
+            (actual != expected)
+                .then(|| format!("canonical form of {}: {} != {}", key, actual, expected))
+        })
+        .collect();
+
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(mismatches)
+    }
+}
@@ -1,9 +1,24 @@
-use super::{super::super::cache::*, headers::*};
+use super::{
+    super::super::cache::{middleware::*, *},
+    headers::*,
+};
 
-use ::axum::{extract::*, http::*, response::Response};
+use {
+    ::axum::{
+        extract::*,
+        http::*,
+        response::{IntoResponse, Response},
+    },
+    std::{sync::atomic::*, time::*},
+};
+
+#[cfg(feature = "paced-purge")]
+use std::result::Result;
 
 /// Axum request handler that resets the cache and returns [no_content_handler].
 ///
+/// Failures are logged but not reported. See [reset_cache_report_handler].
+///
 /// Expects the cache to be available as state. See
 /// [Router::with_state](::axum::Router::with_state).
 pub async fn reset_cache_handler<CacheT, CacheKeyT>(State(cache): State<CacheT>) -> Response
@@ -12,11 +27,606 @@ where
     CacheKeyT: CacheKey,
 {
     tracing::info!("resetting cache");
-    cache.invalidate_all().await;
+    let outcome = cache.invalidate_all_with_outcome().await;
+    if !outcome.is_success() {
+        tracing::error!("could not reset cache: {}", outcome);
+    }
+    no_content_handler().await
+}
+
+/// Axum request handler that resets the cache and returns the [InvalidationOutcome] as JSON.
+///
+/// The body has the operation, the number of entries before the reset (null if unknown), the
+/// outcome of each tier (for tiered caches), the duration, and a correlation ID for the audit
+/// trail (the request's `X-Request-Id`, or generated). The correlation ID is also logged.
+///
+/// Responds with [StatusCode::OK] if all tiers succeeded, [StatusCode::MULTI_STATUS] if only
+/// some did, and [StatusCode::INTERNAL_SERVER_ERROR] if none did.
+///
+/// Expects the cache to be available as state. See
+/// [Router::with_state](::axum::Router::with_state).
+pub async fn reset_cache_report_handler<CacheT, CacheKeyT>(
+    State(cache): State<CacheT>,
+    headers: HeaderMap,
+) -> Response
+where
+    CacheT: Cache<CacheKeyT>,
+    CacheKeyT: CacheKey,
+{
+    let correlation_id = headers
+        .get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+        .unwrap_or_else(generate_correlation_id);
+
+    tracing::info!("resetting cache ({})", correlation_id);
+    let start = Instant::now();
+    let outcome = cache.invalidate_all_with_outcome().await;
+    let duration = start.elapsed();
+
+    let status = if outcome.is_success() {
+        StatusCode::OK
+    } else {
+        tracing::error!("could not reset cache ({}): {}", correlation_id, outcome);
+        if outcome.is_partial() {
+            StatusCode::MULTI_STATUS
+        } else {
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+
+    let json = format!(
+        "{{\"operation\":\"reset\",\"success\":{},\"outcome\":{},\"duration_ms\":{},\
+         \"correlation_id\":\"{}\"}}\n",
+        outcome.is_success(),
+        invalidation_json(&outcome),
+        duration.as_millis(),
+        json_escape(&correlation_id),
+    );
+
+    (status, [(header::CONTENT_TYPE, "application/json")], json)
+        .do_not_encode()
+        .do_not_cache()
+}
+
+/// Axum request handler that engages a [CacheOverride] and returns [no_content_handler].
+///
+/// Query parameters:
+///
+/// * `duration`: required, parsed using [duration-str](https://github.com/baoyachi/duration-str).
+///   There is deliberately no way to engage an indefinite bypass via HTTP.
+/// * `mode`: "reads" (the default) or "all". See [BypassMode].
+/// * `by`: optional, recorded as who set the override.
+///
+/// Responds with [StatusCode::BAD_REQUEST] if the parameters are invalid.
+///
+/// Expects the override to be available as state. See
+/// [CachingLayer::cache_override](super::super::super::CachingLayer::cache_override).
+pub async fn bypass_cache_handler(
+    State(cache_override): State<CacheOverride>,
+    RawQuery(query): RawQuery,
+) -> Response {
+    let mut duration = None;
+    let mut mode = BypassMode::Reads;
+    let mut by = None;
+
+    for (name, value) in query
+        .as_deref()
+        .unwrap_or_default()
+        .split("&")
+        .filter_map(|pair| pair.split_once("="))
+    {
+        match name {
+            "duration" => match duration_str::parse(value) {
+                Ok(value) => duration = Some(value),
+                Err(_) => return bad_request("invalid duration"),
+            },
+
+            "mode" => match value {
+                "reads" => mode = BypassMode::Reads,
+                "all" => mode = BypassMode::All,
+                _ => return bad_request("invalid mode"),
+            },
+
+            "by" => by = Some(value.into()),
+
+            _ => {}
+        }
+    }
+
+    let Some(duration) = duration else {
+        return bad_request("missing duration");
+    };
+
+    cache_override.engage(mode, duration, by);
     no_content_handler().await
 }
 
+/// Axum request handler that resumes a [CacheOverride] and returns [no_content_handler].
+///
+/// Expects the override to be available as state.
+pub async fn resume_cache_handler(State(cache_override): State<CacheOverride>) -> Response {
+    cache_override.resume();
+    no_content_handler().await
+}
+
+/// Axum request handler that returns the [CacheOverride] status as text.
+///
+/// Expects the override to be available as state.
+pub async fn cache_override_status_handler(
+    State(cache_override): State<CacheOverride>,
+) -> Response {
+    let status = match cache_override.status() {
+        Some(status) => status.to_string(),
+        None => "none".into(),
+    };
+
+    (status + "\n").do_not_encode().do_not_cache()
+}
+
+/// Axum request handler that returns the [EntryStats] top keys as JSON.
+///
+/// Query parameters:
+///
+/// * `n`: optional number of keys, defaults to 20.
+///
+/// Expects the stats to be available as state. See
+/// [CachingLayer::entry_stats](super::super::super::CachingLayer::entry_stats).
+pub async fn top_keys_handler(
+    State(entry_stats): State<EntryStats>,
+    RawQuery(query): RawQuery,
+) -> Response {
+    let mut n = 20;
+
+    for (name, value) in query
+        .as_deref()
+        .unwrap_or_default()
+        .split("&")
+        .filter_map(|pair| pair.split_once("="))
+    {
+        if name == "n" {
+            match value.parse() {
+                Ok(value) => n = value,
+                Err(_) => return bad_request("invalid n"),
+            }
+        }
+    }
+
+    let keys: Vec<_> = entry_stats
+        .top_keys(n)
+        .into_iter()
+        .map(|key_stats| {
+            format!(
+                "{{\"key\":\"{}\",\"hits\":{},\"error\":{},\"bytes_served\":{},\"weight\":{},\
+                 \"last_hit_age_ms\":{}}}",
+                json_escape(&key_stats.key),
+                key_stats.hits,
+                key_stats.error,
+                key_stats.bytes_served,
+                key_stats.weight,
+                key_stats.last_hit_age.as_millis()
+            )
+        })
+        .collect();
+
+    let json = format!(
+        "{{\"never_hit\":{},\"top_keys\":[{}]}}\n",
+        entry_stats.never_hit(),
+        keys.join(",")
+    );
+
+    ([(header::CONTENT_TYPE, "application/json")], json)
+        .do_not_encode()
+        .do_not_cache()
+}
+
+/// Axum request handler that returns the [BodySizeObserver] histogram as JSON, with a
+/// recommended `max_cacheable_body_size`.
+///
+/// Query parameters:
+///
+/// * `fraction`: optional target fraction of traffic for the recommendation, defaults to 0.95.
+///
+/// Expects the observer to be available as state. See
+/// [CachingLayer::body_sizes](super::super::super::CachingLayer::body_sizes).
+pub async fn body_sizes_handler(
+    State(body_sizes): State<BodySizeObserver>,
+    RawQuery(query): RawQuery,
+) -> Response {
+    let mut fraction = 0.95;
+
+    for (name, value) in query
+        .as_deref()
+        .unwrap_or_default()
+        .split("&")
+        .filter_map(|pair| pair.split_once("="))
+    {
+        if name == "fraction" {
+            match value.parse() {
+                Ok(value) => fraction = value,
+                Err(_) => return bad_request("invalid fraction"),
+            }
+        }
+    }
+
+    let buckets: Vec<_> = body_sizes
+        .histogram()
+        .into_iter()
+        .map(|bucket| {
+            format!(
+                "{{\"upper_bound\":{},\"stored\":{},\"rejected\":{},\"hits\":{}}}",
+                json_optional(bucket.upper_bound),
+                bucket.stored,
+                bucket.rejected,
+                bucket.hits
+            )
+        })
+        .collect();
+
+    let json = format!(
+        "{{\"fraction\":{},\"recommended_max_body_size\":{},\"buckets\":[{}]}}\n",
+        fraction,
+        json_optional(body_sizes.recommend_max_body_size(fraction)),
+        buckets.join(",")
+    );
+
+    ([(header::CONTENT_TYPE, "application/json")], json)
+        .do_not_encode()
+        .do_not_cache()
+}
+
+/// Axum request handler that returns the [PolicyDescription] as JSON, for API consumers.
+///
+/// Query parameters:
+///
+/// * `format`: optional "json" (the default) or "markdown" (see
+///   [to_markdown](PolicyDescription::to_markdown)).
+///
+/// Expects the describer to be available as state. See
+/// [CachingLayer::policy_describer](super::super::super::CachingLayer::policy_describer).
+pub async fn policy_description_handler(
+    State(describer): State<PolicyDescriber>,
+    RawQuery(query): RawQuery,
+) -> Response {
+    let mut markdown = false;
+
+    for (name, value) in query
+        .as_deref()
+        .unwrap_or_default()
+        .split("&")
+        .filter_map(|pair| pair.split_once("="))
+    {
+        if name == "format" {
+            match value {
+                "json" => markdown = false,
+                "markdown" => markdown = true,
+                _ => return bad_request("invalid format"),
+            }
+        }
+    }
+
+    let description = describer.describe();
+
+    if markdown {
+        return ([(header::CONTENT_TYPE, "text/markdown")], description.to_markdown())
+            .do_not_encode()
+            .do_not_cache();
+    }
+
+    let routes: Vec<_> = description
+        .routes
+        .iter()
+        .map(|route| {
+            format!(
+                "{{\"path\":\"{}\",\"cached\":{},\"duration_secs\":{},\"encoded\":{}}}",
+                json_escape(&route.path),
+                json_optional(route.cached),
+                json_optional(route.duration.map(|duration| duration.as_secs())),
+                json_optional(route.encoded)
+            )
+        })
+        .collect();
+
+    let json = format!(
+        "{{\"methods\":{},\"cached_by_default\":{},\"application_rules\":{},\
+         \"duration_sources\":{},\"duration_jitter\":{},\"min_body_size\":{},\
+         \"max_body_size\":{},\"key_headers\":{},\"varied_headers\":{},\"application_key\":{},\
+         \"partitioned\":{},\"bypass_conditions\":{},\"encodings\":{},\"respects_no_transform\":{},\
+         \"operational_override\":{},\"routes\":[{}],\"control_headers\":{}}}\n",
+        json_strings(description.methods.iter().map(Method::as_str)),
+        description.cached_by_default,
+        description.application_rules,
+        json_strings(description.duration_sources.iter().copied()),
+        description.duration_jitter,
+        description.min_body_size,
+        description.max_body_size,
+        json_strings(description.key_headers.iter().map(HeaderName::as_str)),
+        description.varied_headers,
+        description.application_key,
+        description.partitioned,
+        json_strings(description.bypass_conditions.iter().copied()),
+        json_strings(description.encodings.iter().map(String::as_str)),
+        description.respects_no_transform,
+        match &description.operational_override {
+            Some(operational_override) => format!("\"{}\"", json_escape(operational_override)),
+            None => "null".into(),
+        },
+        routes.join(","),
+        json_strings(description.control_headers.iter().map(HeaderName::as_str)),
+    );
+
+    ([(header::CONTENT_TYPE, "application/json")], json)
+        .do_not_encode()
+        .do_not_cache()
+}
+
+/// Axum request handler that returns the status of [PurgeJobs] as JSON.
+///
+/// Query parameters:
+///
+/// * `id`: optional job ID. Without it, returns all retained jobs.
+///
+/// Responds with [StatusCode::NOT_FOUND] if there is no such job.
+///
+/// Expects the jobs to be available as state. See
+/// [CachingLayer::purge_jobs](super::super::super::CachingLayer::purge_jobs).
+#[cfg(feature = "paced-purge")]
+pub async fn purge_jobs_handler(
+    State(purge_jobs): State<PurgeJobs>,
+    RawQuery(query): RawQuery,
+) -> Response {
+    let id = match purge_job_id(query.as_deref()) {
+        Ok(id) => id,
+        Err(message) => return bad_request(message),
+    };
+
+    let json = match id {
+        Some(id) => match purge_jobs.status(id) {
+            Some(status) => purge_job_json(&status),
+            None => return (StatusCode::NOT_FOUND, "no such job").into_response().do_not_cache(),
+        },
+
+        None => {
+            let statuses: Vec<_> = purge_jobs.statuses().iter().map(purge_job_json).collect();
+            format!("[{}]", statuses.join(","))
+        }
+    };
+
+    ([(header::CONTENT_TYPE, "application/json")], json + "\n")
+        .do_not_encode()
+        .do_not_cache()
+}
+
+/// Axum request handler that cancels a [PurgeJobs] job and returns [no_content_handler].
+///
+/// Query parameters:
+///
+/// * `id`: job ID.
+/// * `lift_fence`: optional "true" to also take down the job's purge fence (see
+///   [lift_fence](PurgeJobs::lift_fence)).
+///
+/// Responds with [StatusCode::NOT_FOUND] if there is no such running job (or fence).
+///
+/// Expects the jobs to be available as state.
+#[cfg(feature = "paced-purge")]
+pub async fn cancel_purge_job_handler(
+    State(purge_jobs): State<PurgeJobs>,
+    RawQuery(query): RawQuery,
+) -> Response {
+    let id = match purge_job_id(query.as_deref()) {
+        Ok(Some(id)) => id,
+        Ok(None) => return bad_request("missing id"),
+        Err(message) => return bad_request(message),
+    };
+
+    let lift_fence = query
+        .as_deref()
+        .unwrap_or_default()
+        .split("&")
+        .any(|pair| pair == "lift_fence=true");
+
+    // A job that already stopped can still have its fence lifted
+    let found = purge_jobs.cancel(id, lift_fence) || (lift_fence && purge_jobs.lift_fence(id));
+
+    if found {
+        no_content_handler().await
+    } else {
+        (StatusCode::NOT_FOUND, "no such job").into_response().do_not_cache()
+    }
+}
+
+/// Maximum number of entries that [weight_audit_handler] samples.
+pub const MAX_WEIGHT_AUDIT_SAMPLE: usize = 256;
+
+/// Axum request handler that samples entries with a [WeightAudit] and returns the
+/// [WeightAuditReport] as JSON.
+///
+/// Query parameters:
+///
+/// * `n`: optional number of entries, defaults to 32. At most [MAX_WEIGHT_AUDIT_SAMPLE].
+///
+/// Expects the cache and the audit to be available as state. See
+/// [CachingLayer::weight_audit](super::super::super::CachingLayer::weight_audit).
+pub async fn weight_audit_handler<CacheT, CacheKeyT>(
+    State((cache, weight_audit)): State<(CacheT, WeightAudit<CacheKeyT>)>,
+    RawQuery(query): RawQuery,
+) -> Response
+where
+    CacheT: Cache<CacheKeyT>,
+    CacheKeyT: CacheKey,
+{
+    let mut n = 32;
+
+    for (name, value) in query
+        .as_deref()
+        .unwrap_or_default()
+        .split("&")
+        .filter_map(|pair| pair.split_once("="))
+    {
+        if name == "n" {
+            match value.parse() {
+                Ok(value) => n = value,
+                Err(_) => return bad_request("invalid n"),
+            }
+        }
+    }
+
+    let report = weight_audit.sample(&cache, n.min(MAX_WEIGHT_AUDIT_SAMPLE)).await;
+
+    let divergent: Vec<_> = report
+        .divergent
+        .iter()
+        .map(|sample| {
+            format!(
+                "{{\"key\":\"{}\",\"declared\":{},\"actual\":{},\"ratio\":{}}}",
+                json_escape(&sample.key),
+                sample.declared,
+                sample.actual,
+                sample.ratio()
+            )
+        })
+        .collect();
+
+    let json = format!(
+        "{{\"sampled\":{},\"missing\":{},\"min_ratio\":{},\"median_ratio\":{},\
+         \"max_ratio\":{},\"total_ratio\":{},\"divergence\":{},\"divergent\":[{}]}}\n",
+        report.sampled,
+        report.missing,
+        report.min_ratio,
+        report.median_ratio,
+        report.max_ratio,
+        report.total_ratio,
+        weight_audit.divergence,
+        divergent.join(",")
+    );
+
+    ([(header::CONTENT_TYPE, "application/json")], json)
+        .do_not_encode()
+        .do_not_cache()
+}
+
+/// Axum request handler that purges all entries with a tag (or dependency token) and returns
+/// the number of purged entries as JSON.
+///
+/// Expects the tag as the only path parameter, e.g. in a route for `/cache/tags/{tag}`, and the
+/// cache to be available as state. See [DependencyCache::purge_dependency].
+pub async fn purge_tag_handler<CacheT, CacheKeyT>(
+    State(cache): State<DependencyCache<CacheT, CacheKeyT>>,
+    Path(tag): Path<String>,
+) -> Response
+where
+    CacheT: Cache<CacheKeyT>,
+    CacheKeyT: CacheKey,
+{
+    tracing::info!("purging tag: {}", tag);
+    let purged = cache.purge_dependency(&tag).await;
+
+    let json = format!("{{\"tag\":\"{}\",\"purged\":{}}}\n", json_escape(&tag), purged);
+    ([(header::CONTENT_TYPE, "application/json")], json)
+        .do_not_encode()
+        .do_not_cache()
+}
+
 /// Axum request handler with no content, no encoding, and no caching.
 pub async fn no_content_handler() -> Response {
     StatusCode::NO_CONTENT.do_not_encode().do_not_cache()
 }
+
+// Bad request with an explanation.
+fn bad_request(message: &'static str) -> Response {
+    (StatusCode::BAD_REQUEST, message).into_response().do_not_cache()
+}
+
+// JSON for an invalidation outcome, including its tiers.
+fn invalidation_json(outcome: &InvalidationOutcome) -> String {
+    let tiers: Vec<_> = outcome
+        .tiers
+        .iter()
+        .map(|(tier, outcome)| {
+            format!(
+                "{{\"tier\":\"{}\",\"outcome\":{}}}",
+                json_escape(tier),
+                invalidation_json(outcome)
+            )
+        })
+        .collect();
+
+    format!(
+        "{{\"count\":{},\"error\":{},\"tiers\":[{}]}}",
+        json_optional(outcome.count),
+        match &outcome.error {
+            Some(error) => format!("\"{}\"", json_escape(error)),
+            None => "null".into(),
+        },
+        tiers.join(",")
+    )
+}
+
+// Process-unique correlation ID.
+fn generate_correlation_id() -> String {
+    static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+    let started = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    format!("{:x}-{:x}", started, SEQUENCE.fetch_add(1, Ordering::Relaxed))
+}
+
+// Job ID from the query, if present.
+#[cfg(feature = "paced-purge")]
+fn purge_job_id(query: Option<&str>) -> Result<Option<u64>, &'static str> {
+    for (name, value) in query
+        .unwrap_or_default()
+        .split("&")
+        .filter_map(|pair| pair.split_once("="))
+    {
+        if name == "id" {
+            return value.parse().map(Some).map_err(|_| "invalid id");
+        }
+    }
+    Ok(None)
+}
+
+// JSON for a purge job status.
+#[cfg(feature = "paced-purge")]
+fn purge_job_json(status: &PurgeJobStatus) -> String {
+    format!(
+        "{{\"id\":{},\"expression\":\"{}\",\"state\":\"{}\",\"total\":{},\"scanned\":{},\
+         \"invalidated\":{},\"elapsed_ms\":{}}}",
+        status.id,
+        json_escape(&status.expression.to_string()),
+        status.state,
+        status.total,
+        status.scanned,
+        status.invalidated,
+        status.elapsed.as_millis()
+    )
+}
+
+// JSON value or null.
+fn json_optional(value: Option<impl ToString>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => "null".into(),
+    }
+}
+
+// JSON array of strings.
+fn json_strings<'this>(strings: impl Iterator<Item = &'this str>) -> String {
+    let strings: Vec<_> = strings.map(|string| format!("\"{}\"", json_escape(string))).collect();
+    format!("[{}]", strings.join(","))
+}
+
+// Escape a string for JSON.
+fn json_escape(string: &str) -> String {
+    let mut escaped = String::with_capacity(string.len());
+    for c in string.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
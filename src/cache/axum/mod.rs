@@ -1,5 +1,6 @@
 mod handlers;
 mod headers;
+mod route;
 
 #[allow(unused_imports)]
-pub use {handlers::*, headers::*};
+pub use {handlers::*, headers::*, route::*};
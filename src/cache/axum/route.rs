@@ -0,0 +1,45 @@
+use super::super::middleware::*;
+
+use ::axum::{middleware::*, response::Response, routing::*};
+
+//
+// CachedRoute
+//
+
+/// Attach a [RoutePolicy] to a route.
+///
+/// The policy travels with the handler, so that renaming or moving the route won't orphan it.
+///
+/// Example:
+///
+/// ```no_run
+/// # use {
+/// #     axum::{routing::*, *},
+/// #     std::time::*,
+/// #     tower_http_response_cache::cache::{axum::*, middleware::*},
+/// # };
+/// # async fn handler() {}
+/// # let router = Router::<()>::default();
+/// # let ten_minutes = Duration::from_secs(10 * 60);
+/// let router =
+///     router.route("/pricing", get(handler).cache(RoutePolicy::default().duration(ten_minutes)));
+/// ```
+pub trait CachedRoute {
+    /// Attach a [RoutePolicy] to this route's responses.
+    fn cache(self, policy: RoutePolicy) -> Self;
+}
+
+impl<StateT> CachedRoute for MethodRouter<StateT>
+where
+    StateT: 'static + Clone + Send + Sync,
+{
+    fn cache(self, policy: RoutePolicy) -> Self {
+        self.layer(map_response(move |mut response: Response| {
+            let policy = policy.clone();
+            async move {
+                response.extensions_mut().insert(policy);
+                response
+            }
+        }))
+    }
+}
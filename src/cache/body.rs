@@ -1,11 +1,11 @@
-use super::{configuration::*, weight::*};
+use super::{coding::*, configuration::*, key::*, weight::*};
 
 use {
     kutil::{
         std::{collections::*, immutable::*},
-        transcoding::{transcode::*, *},
+        transcoding::*,
     },
-    std::io,
+    std::{io, sync::*},
 };
 
 //
@@ -16,145 +16,357 @@ use {
 #[derive(Clone, Debug, Default)]
 pub struct CachedBody {
     /// Representations.
-    pub representations: FastHashMap<Encoding, ImmutableBytes>,
+    pub representations: FastHashMap<CodingId, ImmutableBytes>,
+
+    // Digests of representations, computed lazily.
+    //
+    // Shared by clones. That's safe because a clone only ever adds representations, and a
+    // representation never changes once stored. (Removing representations detaches them.)
+    digests: Arc<Mutex<FastHashMap<CodingId, u64>>>,
 }
 
 impl CachedBody {
+    /// Constructor.
+    pub fn new(representations: FastHashMap<CodingId, ImmutableBytes>) -> Self {
+        Self {
+            representations,
+            digests: Default::default(),
+        }
+    }
+
+    /// Constructor with known digests (e.g. deserialized along with the representations).
+    ///
+    /// Digests of codings that we don't have are ignored.
+    pub fn new_with_digests(
+        representations: FastHashMap<CodingId, ImmutableBytes>,
+        mut digests: FastHashMap<CodingId, u64>,
+    ) -> Self {
+        digests.retain(|coding, _digest| representations.contains_key(coding));
+        Self {
+            representations,
+            digests: Arc::new(Mutex::new(digests)),
+        }
+    }
+
     /// Constructor with an initial representation.
     ///
-    /// If the `preferred_encoding` is different from the `encoding` then we will reencode.
+    /// If the `preferred_coding` is different from the `encoding` then we will reencode.
     ///
     /// If an [Identity](Encoding::Identity) is created during this reencoding then it will also be
     /// stored if `keep_identity_encoding` is true.
     pub async fn new_with(
         bytes: ImmutableBytes,
         encoding: Encoding,
-        preferred_encoding: Encoding,
+        preferred_coding: CodingId,
         configuration: &EncodingConfiguration,
     ) -> io::Result<Self> {
         let mut representations = FastHashMap::default();
+        let coding = CodingId::from(encoding);
+        let requested = preferred_coding.clone();
 
-        if preferred_encoding == encoding {
-            // It's already in the preferred encoding
-            representations.insert(preferred_encoding, bytes);
-        } else if encoding == Encoding::Identity {
-            tracing::debug!("encoding to {}", preferred_encoding);
+        if preferred_coding == coding {
+            // It's already in the preferred coding
+            representations.insert(preferred_coding, bytes);
+        } else if coding.is_identity() {
+            tracing::debug!("encoding to {}", preferred_coding);
 
-            let encoded_bytes = bytes.encode(&preferred_encoding).await?;
+            match encode(&preferred_coding, &bytes, configuration).await? {
+                Some(encoded_bytes) => {
+                    representations.insert(preferred_coding, encoded_bytes);
+                    if configuration.keep_identity_encoding {
+                        representations.insert(CodingId::IDENTITY, bytes);
+                    }
+                }
 
-            representations.insert(preferred_encoding, encoded_bytes);
-            if configuration.keep_identity_encoding {
-                representations.insert(Encoding::Identity, bytes);
+                None => {
+                    representations.insert(CodingId::IDENTITY, bytes);
+                }
             }
-        } else if preferred_encoding == Encoding::Identity {
-            tracing::debug!("decoding from {}", encoding);
+        } else if preferred_coding.is_identity() {
+            tracing::debug!("decoding from {}", coding);
 
-            let identity_bytes = bytes.decode(&encoding).await?;
+            let identity_bytes = coding.decode(&bytes, &configuration.transcoders).await?;
 
-            representations.insert(Encoding::Identity, identity_bytes);
+            representations.insert(CodingId::IDENTITY, identity_bytes);
         } else {
-            tracing::debug!("reencoding from {} to {}", encoding, preferred_encoding);
+            tracing::debug!("reencoding from {} to {}", coding, preferred_coding);
 
-            let identity_bytes = bytes.decode(&encoding).await?;
-            let encoded_bytes = identity_bytes.encode(&preferred_encoding).await?;
+            let identity_bytes = coding.decode(&bytes, &configuration.transcoders).await?;
 
-            representations.insert(preferred_encoding, encoded_bytes);
-            if configuration.keep_identity_encoding {
-                representations.insert(Encoding::Identity, identity_bytes);
+            match encode(&preferred_coding, &identity_bytes, configuration).await? {
+                Some(encoded_bytes) => {
+                    representations.insert(preferred_coding, encoded_bytes);
+                    if configuration.keep_identity_encoding {
+                        representations.insert(CodingId::IDENTITY, identity_bytes);
+                    }
+                }
+
+                None => {
+                    // Fall back to the source coding
+                    representations.insert(coding, bytes);
+                    if configuration.keep_identity_encoding {
+                        representations.insert(CodingId::IDENTITY, identity_bytes);
+                    }
+                }
             }
         }
 
-        Ok(Self { representations })
+        let mut body = Self::new(representations);
+        body.limit_representations(&requested, configuration);
+        Ok(body)
     }
 
-    /// Returns the body [ImmutableBytes] in the specified encoding.
+    /// Returns the body [ImmutableBytes] in the specified coding, together with the coding.
     ///
-    /// If we don't have the specified encoding then we will reencode from another encoding,
-    /// storing the result so that we won't have to encode it again.
+    /// If we don't have the specified coding then we will reencode from another coding, storing
+    /// the result so that we won't have to encode it again.
     ///
     /// If an [Identity](Encoding::Identity) is created during this reencoding then it will also be
     /// stored if `keep_identity_encoding` is true.
     ///
-    /// Returns a modified clone if reencoding caused a new encoding to be stored. Note that
-    /// cloning should be cheap due to our use of [ImmutableBytes].
+    /// If the encoding fails verification (see
+    /// [TranscodeVerification](super::verification::TranscodeVerification)) then the returned
+    /// coding will be [Identity](Encoding::Identity) instead of the specified coding.
+    ///
+    /// Returns a modified clone if reencoding caused a new coding to be stored. Note that cloning
+    /// should be cheap due to our use of [ImmutableBytes].
     pub async fn get(
         &self,
-        encoding: &Encoding,
+        coding: &CodingId,
         configuration: &EncodingConfiguration,
-    ) -> io::Result<(ImmutableBytes, Option<Self>)> {
-        match (self.representations.get(encoding), encoding) {
-            (Some(bytes), _) => Ok((bytes.clone(), None)),
+    ) -> io::Result<(ImmutableBytes, CodingId, Option<Self>)> {
+        if let Some(bytes) = self.representations.get(coding) {
+            return Ok((bytes.clone(), coding.clone(), None));
+        }
 
-            (None, Encoding::Identity) => {
-                // Decode
-                for from_encoding in ENCODINGS_BY_DECODING_COST {
-                    if let Some(bytes) = self.representations.get(from_encoding) {
-                        tracing::debug!("decoding from {}", from_encoding);
+        let (identity_bytes, identity_is_new) =
+            match self.representations.get(&CodingId::IDENTITY) {
+                Some(identity_bytes) => (identity_bytes.clone(), false),
 
-                        let identity_bytes = bytes.decode(from_encoding).await?;
+                None => match self.cheapest_to_decode() {
+                    Some((from_coding, bytes)) => {
+                        if coding.is_identity() {
+                            tracing::debug!("decoding from {}", from_coding);
+                        } else {
+                            tracing::debug!("reencoding from {} to {}", from_coding, coding);
+                        }
 
-                        let mut modified = self.clone();
-                        modified
-                            .representations
-                            .insert(Encoding::Identity, identity_bytes.clone());
+                        (
+                            from_coding
+                                .decode(bytes, &configuration.transcoders)
+                                .await?,
+                            true,
+                        )
+                    }
 
-                        return Ok((identity_bytes, Some(modified)));
+                    None => {
+                        // This should never happen (but we don't want to panic here!)
+                        tracing::error!("no encodings");
+                        return Ok((Default::default(), coding.clone(), None));
                     }
-                }
+                },
+            };
 
-                // This should never happen (but we don't want to panic here!)
-                tracing::error!("no encodings");
-                Ok((Default::default(), None))
+        let mut modified = self.clone();
+
+        if coding.is_identity() {
+            modified
+                .representations
+                .insert(CodingId::IDENTITY, identity_bytes.clone());
+            modified.limit_representations(&CodingId::IDENTITY, configuration);
+            return Ok((identity_bytes, CodingId::IDENTITY, Some(modified)));
+        }
+
+        if !identity_is_new {
+            tracing::debug!("encoding to {}", coding);
+        }
+
+        let bytes = encode(coding, &identity_bytes, configuration).await?;
+
+        if identity_is_new && configuration.keep_identity_encoding {
+            modified
+                .representations
+                .insert(CodingId::IDENTITY, identity_bytes.clone());
+        }
+
+        match bytes {
+            Some(bytes) => {
+                modified
+                    .representations
+                    .insert(coding.clone(), bytes.clone());
+                modified.limit_representations(coding, configuration);
+
+                Ok((bytes, coding.clone(), Some(modified)))
+            }
+
+            // Fall back to the source
+            None => Ok((
+                identity_bytes,
+                CodingId::IDENTITY,
+                (identity_is_new && configuration.keep_identity_encoding).then_some(modified),
+            )),
+        }
+    }
+
+    /// Add the representations of another body of the same content that we don't have.
+    ///
+    /// Returns true if any were added.
+    pub fn add_representations_from(&mut self, other: &Self) -> bool {
+        let mut added = false;
+        for (coding, bytes) in &other.representations {
+            if !self.representations.contains_key(coding) {
+                self.representations.insert(coding.clone(), bytes.clone());
+                added = true;
             }
+        }
+        added
+    }
 
-            (None, to_encoding) => {
-                // Reencode
-                if let Some(identity_bytes) = self.representations.get(&Encoding::Identity) {
-                    tracing::debug!("encoding to {}", to_encoding);
+    /// Remove the representations for which `keep` returns false.
+    ///
+    /// Never removes the last representation. Returns the number removed.
+    pub fn retain_representations<KeepT>(&mut self, keep: KeepT) -> usize
+    where
+        KeepT: Fn(&CodingId) -> bool,
+    {
+        let removed: Vec<_> = self
+            .representations
+            .keys()
+            .filter(|coding| !keep(coding))
+            .cloned()
+            .collect();
 
-                    let bytes = identity_bytes.encode(to_encoding).await?;
+        if removed.is_empty() || (removed.len() == self.representations.len()) {
+            return 0;
+        }
 
-                    let mut modified = self.clone();
-                    modified
-                        .representations
-                        .insert(to_encoding.clone(), bytes.clone());
+        for coding in &removed {
+            self.representations.remove(coding);
+        }
 
-                    Ok((bytes, Some(modified)))
+        // A removed coding might be added again with different bytes
+        self.digests = Default::default();
+
+        removed.len()
+    }
+
+    /// Remove representations beyond `max_cached_encodings`, if configured.
+    ///
+    /// Keeps `requested` first, then [Identity](Encoding::Identity) (the cheapest source for
+    /// reencoding), then the smallest of the others. Returns the number removed.
+    pub fn limit_representations(
+        &mut self,
+        requested: &CodingId,
+        configuration: &EncodingConfiguration,
+    ) -> usize {
+        let Some(max) = configuration.max_cached_encodings else {
+            return 0;
+        };
+
+        if self.representations.len() <= max {
+            return 0;
+        }
+
+        let mut ranked: Vec<_> = self
+            .representations
+            .iter()
+            .map(|(coding, bytes)| {
+                let rank = if coding == requested {
+                    0
+                } else if coding.is_identity() {
+                    1
                 } else {
-                    for from_encoding in ENCODINGS_BY_DECODING_COST {
-                        if let Some(bytes) = self.representations.get(from_encoding) {
-                            tracing::debug!("reencoding from {} to {}", from_encoding, to_encoding);
-
-                            let identity_bytes = bytes.decode(from_encoding).await?;
-                            let bytes = identity_bytes.encode(to_encoding).await?;
-
-                            let mut modified = self.clone();
-                            if configuration.keep_identity_encoding {
-                                modified
-                                    .representations
-                                    .insert(Encoding::Identity, identity_bytes);
-                            }
-                            modified
-                                .representations
-                                .insert(to_encoding.clone(), bytes.clone());
-
-                            return Ok((bytes, Some(modified)));
-                        }
-                    }
+                    2
+                };
+                ((rank, bytes.len()), coding.clone())
+            })
+            .collect();
+        ranked.sort_by_key(|(rank, _)| *rank);
 
-                    // This should never happen (but we don't want to panic here!)
-                    tracing::error!("no encodings");
-                    Ok((Default::default(), None))
-                }
+        let kept: Vec<_> = ranked.into_iter().take(max.max(1)).map(|(_, coding)| coding).collect();
+        let removed = self.retain_representations(|coding| kept.contains(coding));
+        if removed != 0 {
+            tracing::debug!("removed {} representations beyond the maximum", removed);
+        }
+        removed
+    }
+
+    /// Digest of a representation.
+    ///
+    /// Computed lazily and then remembered. This is the [stable_hash] of the bytes, so it is the
+    /// same in every process and is [serialized](super::CachedResponse::serialize) with them. It
+    /// is not cryptographic, so it can identify candidates for deduplication but a match must
+    /// still be confirmed by comparing the bytes.
+    pub fn digest(&self, coding: &CodingId) -> Option<u64> {
+        let bytes = self.representations.get(coding)?;
+
+        let mut digests = self.digests.lock().expect("lock");
+        Some(*digests.entry(coding.clone()).or_insert_with(|| stable_hash(bytes)))
+    }
+
+    /// Share representations with another body.
+    ///
+    /// Representations that are identical to the other body's are replaced by its
+    /// [ImmutableBytes], so that our duplicates can be dropped.
+    ///
+    /// Returns true if any representation was replaced.
+    pub fn share_representations_with(&mut self, other: &Self) -> bool {
+        let codings: Vec<_> = self
+            .representations
+            .keys()
+            .filter(|coding| {
+                self.digest(coding).is_some()
+                    && self.digest(coding) == other.digest(coding)
+                    && self.representations.get(coding) == other.representations.get(coding)
+            })
+            .cloned()
+            .collect();
+
+        for coding in &codings {
+            if let Some(bytes) = other.representations.get(coding) {
+                self.representations.insert(coding.clone(), bytes.clone());
+            }
+        }
+
+        !codings.is_empty()
+    }
+
+    /// The representation that [get](Self::get) would reencode from for the specified coding.
+    ///
+    /// Returns [None] if we have the coding (or no representations at all).
+    pub fn reencoding_source(&self, coding: &CodingId) -> Option<CodingId> {
+        if self.representations.contains_key(coding) {
+            None
+        } else if self.representations.contains_key(&CodingId::IDENTITY) {
+            Some(CodingId::IDENTITY)
+        } else {
+            self.cheapest_to_decode().map(|(coding, _)| coding.clone())
+        }
+    }
+
+    // The representation that is cheapest to decode.
+    //
+    // Custom codings are considered more expensive than all built-in ones.
+    fn cheapest_to_decode(&self) -> Option<(&CodingId, &ImmutableBytes)> {
+        for encoding in ENCODINGS_BY_DECODING_COST {
+            if let Some((coding, bytes)) = self
+                .representations
+                .get_key_value(&CodingId::Builtin(*encoding))
+            {
+                return Some((coding, bytes));
             }
         }
+
+        self.representations
+            .iter()
+            .find(|(coding, _)| matches!(coding, CodingId::Custom(_)))
     }
 }
 
 impl CacheWeight for CachedBody {
     fn cache_weight(&self) -> usize {
         const SELF_SIZE: usize = size_of::<CachedBody>();
-        const ENTRY_SIZE: usize = size_of::<Encoding>() + size_of::<ImmutableBytes>();
+        const ENTRY_SIZE: usize = size_of::<CodingId>() + size_of::<ImmutableBytes>();
 
         let mut size = SELF_SIZE;
 
@@ -165,3 +377,30 @@ impl CacheWeight for CachedBody {
         size
     }
 }
+
+// Encode from Identity, with verification if configured.
+//
+// Returns None if the coding is not available or if verification failed.
+async fn encode(
+    coding: &CodingId,
+    identity_bytes: &ImmutableBytes,
+    configuration: &EncodingConfiguration,
+) -> io::Result<Option<ImmutableBytes>> {
+    if !coding.is_available() {
+        tracing::warn!("not encoding to {} (feature not enabled)", coding);
+        return Ok(None);
+    }
+
+    match &configuration.verification {
+        Some(verification) => {
+            verification
+                .encode(coding, identity_bytes, &configuration.transcoders)
+                .await
+        }
+
+        None => coding
+            .encode(identity_bytes, &configuration.transcoders)
+            .await
+            .map(Some),
+    }
+}
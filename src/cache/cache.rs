@@ -1,4 +1,6 @@
-use super::{key::*, response::*};
+use super::{invalidation::*, key::*, response::*, self_test::*};
+
+use std::sync::*;
 
 //
 // Cache
@@ -20,6 +22,18 @@ where
     /// constraint. Implementations can simply use `async fn put`.
     fn get(&self, key: &CacheKeyT) -> impl Future<Output = Option<CachedResponseRef>> + Send;
 
+    /// Get an entry from the cache without waiting, if the cache can answer immediately.
+    ///
+    /// [None] means that the caller should fall back to [get](Self::get). `Some` is an
+    /// authoritative answer, in which `Some(None)` means that there is no entry. The middleware
+    /// tries this first on every lookup, so implementations should return [None] quickly when
+    /// they can't answer.
+    ///
+    /// The default implementation returns [None].
+    fn get_if_present(&self, _key: &CacheKeyT) -> Option<Option<CachedResponseRef>> {
+        None
+    }
+
     /// Put an entry in the cache.
     ///
     /// The cache should take into consideration the [CachedResponse::duration] if set.
@@ -28,6 +42,68 @@ where
     /// constraint. Implementations can simply use `async fn put`.
     fn put(&self, key: CacheKeyT, cached_response: CachedResponseRef) -> impl Future<Output = ()> + Send;
 
+    /// Capture the current invalidation fence for a key.
+    ///
+    /// Should be called before reading (or computing) the data that will later be passed to
+    /// [put_fenced](Self::put_fenced).
+    ///
+    /// The default implementation does not support fencing and returns the default [Fence].
+    fn fence(&self, _key: &CacheKeyT) -> Fence {
+        Default::default()
+    }
+
+    /// Put an entry in the cache unless the key has been invalidated since the fence was captured.
+    ///
+    /// This guards against "resurrecting" data derived from a pre-invalidation state.
+    ///
+    /// The default implementation does not support fencing and always calls [put](Self::put).
+    fn put_fenced(
+        &self,
+        key: CacheKeyT,
+        cached_response: CachedResponseRef,
+        _fence: Fence,
+    ) -> impl Future<Output = PutOutcome> + Send {
+        async move {
+            self.put(key, cached_response).await;
+            PutOutcome::Stored
+        }
+    }
+
+    /// Update an entry in place.
+    ///
+    /// `update` is called with the current entry and returns its replacement, or [None] to leave
+    /// it as is. Returns true if the replacement was stored. Nothing is stored if the key has no
+    /// entry, so an invalidated entry is never resurrected.
+    ///
+    /// Implementations should make this compare-and-swap: if the entry is replaced by someone else
+    /// in the meantime then the update should not be applied. The default implementation can only
+    /// narrow that window: it gets the entry again before putting and gives up if it changed.
+    /// Note that it thus never applies for caches that don't return the same [CachedResponseRef]
+    /// on every get (e.g. deserializing ones).
+    fn update<UpdateT>(&self, key: CacheKeyT, update: UpdateT) -> impl Future<Output = bool> + Send
+    where
+        UpdateT: FnOnce(CachedResponseRef) -> Option<CachedResponseRef> + Send,
+    {
+        async move {
+            let Some(current) = self.get(&key).await else {
+                return false;
+            };
+
+            let Some(updated) = update(current.clone()) else {
+                return false;
+            };
+
+            match self.get(&key).await {
+                Some(latest) if Arc::ptr_eq(&latest, &current) => {
+                    self.put(key, updated).await;
+                    true
+                }
+
+                _ => false,
+            }
+        }
+    }
+
     /// Invalidate a cache entry.
     ///
     /// Note that this is an `async` function written in longer form in order to include the `Send`
@@ -39,4 +115,118 @@ where
     /// Note that this is an `async` function written in longer form in order to include the `Send`
     /// constraint. Implementations can simply use `async fn invalidate_all`.
     fn invalidate_all(&self) -> impl Future<Output = ()> + Send;
+
+    /// Invalidate all cache entries, reporting the outcome.
+    ///
+    /// Unlike [invalidate_all](Self::invalidate_all), this can report failures, so caches that
+    /// can fail (e.g. remote backends) should implement it. Caches that wrap or combine other
+    /// caches should delegate to it, so that tiers can report their own outcomes.
+    ///
+    /// The default implementation gets the [entry_count](Self::entry_count) and calls
+    /// [invalidate_all](Self::invalidate_all), which is assumed to succeed.
+    fn invalidate_all_with_outcome(&self) -> impl Future<Output = InvalidationOutcome> + Send {
+        async move {
+            let count = self.entry_count();
+            self.invalidate_all().await;
+            InvalidationOutcome::new(count)
+        }
+    }
+
+    /// Invalidate all cache entries with keys that match a predicate.
+    ///
+    /// Useful for invalidating all entries that depend on some data, e.g. by tagging keys with
+    /// [extensions](CommonCacheKey::extensions) and matching them with
+    /// [has_extension](CommonCacheKey::has_extension).
+    ///
+    /// Caches that wrap or combine other caches should delegate to it if they can't
+    /// [enumerate](Self::keys) their keys. The [count](InvalidationOutcome::count) is of the
+    /// invalidated entries, if known.
+    ///
+    /// The default implementation [invalidates](Self::invalidate) the matching [keys](Self::keys),
+    /// and fails if they can't be enumerated.
+    fn invalidate_matching<PredicateT>(
+        &self,
+        predicate: PredicateT,
+    ) -> impl Future<Output = InvalidationOutcome> + Send
+    where
+        PredicateT: 'static + Fn(&CacheKeyT) -> bool + Send + Sync,
+    {
+        async move {
+            let Some(keys) = self.keys() else {
+                return InvalidationOutcome::failed("unsupported");
+            };
+
+            let mut count = 0;
+            for key in keys.iter().filter(|key| predicate(key)) {
+                self.invalidate(key).await;
+                count += 1;
+            }
+
+            InvalidationOutcome::new(Some(count))
+        }
+    }
+
+    /// Verify that the cache works.
+    ///
+    /// Intended to be called before serving traffic, because cache errors otherwise silently
+    /// degrade to misses.
+    ///
+    /// The default implementation is [round_trip_self_test]. Implementations can extend it with
+    /// backend-specific checks.
+    fn self_test(&self) -> impl Future<Output = Result<SelfTestReport, SelfTestError>> + Send {
+        round_trip_self_test(self)
+    }
+
+    /// Number of entries, if known.
+    ///
+    /// The default implementation returns [None].
+    fn entry_count(&self) -> Option<u64> {
+        None
+    }
+
+    /// Total weight of entries, if known.
+    ///
+    /// The default implementation returns [None].
+    fn weighted_size(&self) -> Option<u64> {
+        None
+    }
+
+    /// Snapshot of the keys of all entries, if enumeration is supported.
+    ///
+    /// Entries might be added or removed after the snapshot is taken.
+    ///
+    /// The default implementation returns [None].
+    fn keys(&self) -> Option<Vec<CacheKeyT>> {
+        None
+    }
+}
+
+//
+// Fence
+//
+
+/// Invalidation fence.
+///
+/// See [Cache::fence].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Fence {
+    /// Epoch (bumped by invalidating all entries).
+    pub epoch: u64,
+
+    /// Invalidation sequence number.
+    pub sequence: u64,
+}
+
+//
+// PutOutcome
+//
+
+/// Outcome of [Cache::put_fenced].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PutOutcome {
+    /// Stored.
+    Stored,
+
+    /// Rejected because the key was invalidated after the fence was captured.
+    RejectedStale,
 }
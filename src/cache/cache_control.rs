@@ -0,0 +1,177 @@
+use {
+    http::header::*,
+    kutil::http::*,
+    std::time::*,
+};
+
+//
+// CacheControl
+//
+
+/// The `Cache-Control` directives that matter to a shared cache.
+///
+/// Unknown directives are ignored, as are invalid delta-seconds. Multiple header lines are
+/// equivalent to one comma-separated value. See
+/// [IETF RFC 9111 section 5.2](https://datatracker.ietf.org/doc/html/rfc9111#section-5.2).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CacheControl {
+    /// `no-store`.
+    pub no_store: bool,
+
+    /// `no-cache` (unqualified or qualified).
+    pub no_cache: bool,
+
+    /// `private` (unqualified or qualified).
+    pub private: bool,
+
+    /// `no-transform`.
+    pub no_transform: bool,
+
+    /// `max-age`.
+    pub max_age: Option<Duration>,
+
+    /// `s-maxage`.
+    pub s_maxage: Option<Duration>,
+}
+
+impl CacheControl {
+    /// Parse.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let mut cache_control = Self::default();
+
+        for value in headers.string_values(CACHE_CONTROL) {
+            for directive in value.split(',') {
+                let (name, argument) = match directive.split_once('=') {
+                    Some((name, argument)) => {
+                        (name.trim(), Some(argument.trim().trim_matches('"')))
+                    }
+
+                    None => (directive.trim(), None),
+                };
+
+                let seconds = || {
+                    argument
+                        .and_then(|argument| argument.parse().ok())
+                        .map(Duration::from_secs)
+                };
+
+                if name.eq_ignore_ascii_case("no-store") {
+                    cache_control.no_store = true;
+                } else if name.eq_ignore_ascii_case("no-cache") {
+                    cache_control.no_cache = true;
+                } else if name.eq_ignore_ascii_case("private") {
+                    cache_control.private = true;
+                } else if name.eq_ignore_ascii_case("no-transform") {
+                    cache_control.no_transform = true;
+                } else if name.eq_ignore_ascii_case("max-age") {
+                    cache_control.max_age = seconds();
+                } else if name.eq_ignore_ascii_case("s-maxage") {
+                    cache_control.s_maxage = seconds();
+                }
+            }
+        }
+
+        cache_control
+    }
+
+    /// Whether a shared cache may store the response.
+    ///
+    /// We treat `no-cache` as forbidding storage because we don't revalidate on every use.
+    pub fn is_storable(&self) -> bool {
+        !self.no_store && !self.no_cache && !self.private
+    }
+
+    /// Whether the response is stale as soon as it is received, i.e. with `s-maxage=0` or (without
+    /// `s-maxage`) `max-age=0`.
+    pub fn is_stale_on_arrival(&self) -> bool {
+        self.s_maxage.or(self.max_age) == Some(Duration::ZERO)
+    }
+
+    /// Freshness lifetime minus the upstream `Age`.
+    ///
+    /// `s-maxage` takes precedence over `max-age`, which takes precedence over `Expires` (relative
+    /// to `Date`, or to `now` if there is no `Date`). An invalid `Expires` means already expired.
+    /// See
+    /// [IETF RFC 9111 section 4.2.1](https://datatracker.ietf.org/doc/html/rfc9111#section-4.2.1).
+    pub fn freshness(&self, headers: &HeaderMap, now: SystemTime) -> Option<Duration> {
+        let lifetime = match self.s_maxage.or(self.max_age) {
+            Some(lifetime) => lifetime,
+
+            None => {
+                if !headers.contains_key(EXPIRES) {
+                    return None;
+                }
+
+                match headers.date_value(EXPIRES) {
+                    Some(expires) => {
+                        let date =
+                            headers.date_value(DATE).map(SystemTime::from).unwrap_or(now);
+                        SystemTime::from(expires).duration_since(date).unwrap_or_default()
+                    }
+
+                    None => Duration::ZERO,
+                }
+            }
+        };
+
+        let age = headers
+            .parse_value::<u64>(AGE)
+            .map(Duration::from_secs)
+            .unwrap_or_default();
+        Some(lifetime.saturating_sub(age))
+    }
+}
+
+/// Whether the `Cache-Control` header has the `no-transform` directive.
+///
+/// The directive forbids intermediaries from transforming the content, which for us means that
+/// we must not change its content coding. It applies both to requests and to responses. See
+/// [IETF RFC 9111 section 5.2.2.6](https://datatracker.ietf.org/doc/html/rfc9111#section-5.2.2.6)
+/// and [section 5.2.1.6](https://datatracker.ietf.org/doc/html/rfc9111#section-5.2.1.6).
+pub fn has_no_transform(headers: &HeaderMap) -> bool {
+    headers.string_values(CACHE_CONTROL).iter().any(|value| {
+        value
+            .split(',')
+            .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"))
+    })
+}
+
+/// Whether the `Vary` header only names request headers that are in `keyed`, i.e. that are
+/// already accounted for by the cache key.
+///
+/// `Vary: *` never is.
+pub fn vary_is_keyed(headers: &HeaderMap, keyed: &[HeaderName]) -> bool {
+    headers.string_values(VARY).iter().all(|value| {
+        value
+            .split(',')
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty())
+            .all(|name| {
+                name != "*" && keyed.iter().any(|keyed| keyed.as_str().eq_ignore_ascii_case(name))
+            })
+    })
+}
+
+/// The request header names in the `Vary` header, lowercase and deduplicated, excluding those in
+/// `keyed`.
+///
+/// Returns [None] for `Vary: *` or for an invalid name.
+pub fn vary_names(headers: &HeaderMap, keyed: &[HeaderName]) -> Option<Vec<HeaderName>> {
+    let mut names = Vec::default();
+
+    for value in headers.string_values(VARY) {
+        for name in value.split(',').map(|name| name.trim()).filter(|name| !name.is_empty()) {
+            if name == "*" {
+                return None;
+            }
+
+            let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+            if !keyed.contains(&name) && !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+
+    names.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    Some(names)
+}
@@ -0,0 +1,187 @@
+use {
+    http::header::*,
+    kutil::{
+        http::*,
+        std::{collections::*, immutable::*},
+        transcoding::{transcode::*, *},
+    },
+    std::{fmt, io, sync::*},
+};
+
+//
+// CodingId
+//
+
+/// Content coding identifier.
+///
+/// Extends the built-in [Encoding] with custom codings, e.g. dictionary-based or experimental
+/// codings, for which a [Transcoder] is registered in a [TranscoderRegistry].
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum CodingId {
+    /// Built-in coding.
+    Builtin(Encoding),
+
+    /// Custom coding (its `Content-Encoding` token).
+    Custom(&'static str),
+}
+
+impl CodingId {
+    /// Identity coding.
+    pub const IDENTITY: Self = Self::Builtin(Encoding::Identity);
+
+    /// Whether this is the identity coding.
+    pub fn is_identity(&self) -> bool {
+        matches!(self, Self::Builtin(Encoding::Identity))
+    }
+
+    /// Whether the coding is available, i.e. its crate feature is enabled.
+    ///
+    /// Custom codings are always considered available here. Their [Transcoder] is looked up in
+    /// the [TranscoderRegistry] when used.
+    pub fn is_available(&self) -> bool {
+        match self {
+            Self::Builtin(encoding) => match encoding {
+                Encoding::Identity => true,
+                Encoding::Brotli => cfg!(feature = "brotli"),
+                Encoding::Deflate => cfg!(feature = "deflate"),
+                Encoding::GZip => cfg!(feature = "gzip"),
+                Encoding::Zstandard => cfg!(feature = "zstd"),
+            },
+
+            Self::Custom(_) => true,
+        }
+    }
+
+    /// The built-in encoding, or [Identity](Encoding::Identity) for custom codings and unavailable
+    /// encodings.
+    ///
+    /// Custom codings can only be used for cached responses, so this is what we use for
+    /// streaming.
+    pub fn builtin_or_identity(&self) -> Encoding {
+        match self {
+            Self::Builtin(encoding) => {
+                if self.is_available() {
+                    *encoding
+                } else {
+                    tracing::warn!("not encoding to {} (feature not enabled)", encoding);
+                    Encoding::Identity
+                }
+            }
+
+            Self::Custom(name) => {
+                tracing::debug!(
+                    "not encoding to {} (custom coding cannot be streamed)",
+                    name
+                );
+                Encoding::Identity
+            }
+        }
+    }
+
+    /// Encode from [Identity](Encoding::Identity).
+    pub async fn encode(
+        &self,
+        identity_bytes: &ImmutableBytes,
+        transcoders: &TranscoderRegistry,
+    ) -> io::Result<ImmutableBytes> {
+        match self {
+            Self::Builtin(encoding) => identity_bytes.encode(encoding).await,
+            Self::Custom(name) => transcoders.get(name)?.encode(identity_bytes),
+        }
+    }
+
+    /// Decode to [Identity](Encoding::Identity).
+    pub async fn decode(
+        &self,
+        bytes: &ImmutableBytes,
+        transcoders: &TranscoderRegistry,
+    ) -> io::Result<ImmutableBytes> {
+        match self {
+            Self::Builtin(encoding) => bytes.decode(encoding).await,
+            Self::Custom(name) => transcoders.get(name)?.decode(bytes),
+        }
+    }
+}
+
+impl From<Encoding> for CodingId {
+    fn from(encoding: Encoding) -> Self {
+        Self::Builtin(encoding)
+    }
+}
+
+impl IntoHeaderValue for CodingId {
+    fn into_header_value(self) -> HeaderValue {
+        match self {
+            Self::Builtin(encoding) => encoding.into_header_value(),
+            Self::Custom(name) => HeaderValue::from_static(name),
+        }
+    }
+}
+
+// As its `Content-Encoding` token
+impl fmt::Display for CodingId {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Builtin(encoding) => {
+                fmt::Display::fmt(&EncodingHeaderValue::from(*encoding), formatter)
+            }
+            Self::Custom(name) => fmt::Display::fmt(name, formatter),
+        }
+    }
+}
+
+//
+// Transcoder
+//
+
+/// Transcoder for a custom coding.
+pub trait Transcoder
+where
+    Self: Send + Sync,
+{
+    /// Encode from [Identity](Encoding::Identity).
+    fn encode(&self, identity_bytes: &ImmutableBytes) -> io::Result<ImmutableBytes>;
+
+    /// Decode to [Identity](Encoding::Identity).
+    fn decode(&self, bytes: &ImmutableBytes) -> io::Result<ImmutableBytes>;
+}
+
+//
+// TranscoderRegistry
+//
+
+/// Registry of [Transcoder] for custom codings.
+///
+/// Cloning is cheap.
+#[derive(Clone, Default)]
+pub struct TranscoderRegistry {
+    transcoders: Arc<FastHashMap<&'static str, Arc<dyn Transcoder>>>,
+}
+
+impl TranscoderRegistry {
+    /// Register a transcoder.
+    pub fn register(&mut self, name: &'static str, transcoder: Arc<dyn Transcoder>) {
+        Arc::make_mut(&mut self.transcoders).insert(name, transcoder);
+    }
+
+    /// Get a transcoder.
+    pub fn get(&self, name: &str) -> io::Result<&Arc<dyn Transcoder>> {
+        self.transcoders
+            .get(name)
+            .ok_or_else(|| io::Error::other(format!("no transcoder for coding: {}", name)))
+    }
+
+    /// Registered coding names.
+    pub fn names(&self) -> impl Iterator<Item = &'static str> {
+        self.transcoders.keys().copied()
+    }
+}
+
+impl fmt::Debug for TranscoderRegistry {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_list()
+            .entries(self.transcoders.keys())
+            .finish()
+    }
+}
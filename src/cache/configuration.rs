@@ -1,4 +1,11 @@
-use super::hooks::*;
+use super::{
+    cache_control::*, coding::*, heuristic::*, hooks::*, jitter::*, key::*, validators::*, verification::*,
+};
+
+use {
+    http::{header::*, StatusCode},
+    std::{sync::*, time::*},
+};
 
 //
 // CachingConfiguration
@@ -16,8 +23,133 @@ pub struct CachingConfiguration {
     /// Cacheable by default.
     pub cacheable_by_default: bool,
 
+    /// Cache validators for oversized responses.
+    pub cache_validators_for_oversized: bool,
+
     /// Cache duration (hook).
     pub cache_duration: Option<CacheDurationHook>,
+
+    /// Cacheable status codes other than success (2xx).
+    pub cacheable_status_codes: Arc<[StatusCode]>,
+
+    /// Default cache duration for entries with status codes other than success (2xx).
+    pub negative_duration: Option<Duration>,
+
+    /// Respect standard `Cache-Control` and `Vary` response headers.
+    pub respect_cache_control: bool,
+
+    /// Heuristic freshness.
+    pub heuristic_freshness: Option<HeuristicConfig>,
+
+    /// Cache duration jitter.
+    pub ttl_jitter: Option<JitterConfig>,
+
+    /// Transform before store (hook).
+    pub transform_before_store: Option<TransformHook>,
+
+    /// What the triggering request receives if transformed.
+    pub transform_policy: TransformPolicy,
+
+    /// Age accounting for served entries.
+    pub age_accounting: AgeAccounting,
+
+    /// When to give stored entries a synthetic `Last-Modified`.
+    pub synthetic_last_modified: SyntheticLastModified,
+
+    /// Clock (hook).
+    pub clock: Option<ClockHook>,
+
+    /// How cache keys are rendered in logs.
+    pub key_log_policy: KeyLogPolicy,
+}
+
+impl CachingConfiguration {
+    /// Whether responses with a status code are cacheable: success (2xx), or one of the
+    /// [cacheable_status_codes](Self::cacheable_status_codes).
+    ///
+    /// `304 Not Modified`, informational (1xx), and server error (5xx) status codes are never
+    /// cacheable.
+    pub fn is_cacheable_status(&self, status: StatusCode) -> bool {
+        status.is_success()
+            || (status != StatusCode::NOT_MODIFIED
+                && !status.is_informational()
+                && !status.is_server_error()
+                && self.cacheable_status_codes.contains(&status))
+    }
+
+    /// Current time according to the clock hook, or the system time if not provided.
+    pub fn now(&self) -> SystemTime {
+        match &self.clock {
+            Some(clock) => clock(),
+            None => SystemTime::now(),
+        }
+    }
+}
+
+//
+// AgeAccounting
+//
+
+/// How to account for the time an entry was held in the cache when serving it.
+///
+/// Without this, downstream caches (e.g. a CDN) would consider a served entry to be brand new and
+/// would cache it for their full duration.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum AgeAccounting {
+    /// Set `Date` to the serve time.
+    RefreshDate,
+
+    /// Keep the stored `Date` and set `Age` to the time held in our cache plus the upstream
+    /// `Age` captured at store time.
+    #[default]
+    EmitAge,
+
+    /// Both set `Date` to the serve time and set `Age`.
+    Both,
+}
+
+//
+// SyntheticLastModified
+//
+
+/// When to give a stored entry a synthetic `Last-Modified` (its creation time, in whole seconds)
+/// if the upstream response doesn't have one.
+///
+/// Synthetic validators allow clients to revalidate with `If-Modified-Since`, but they are only
+/// accurate to the second. Content that changes more often than that would get spurious
+/// `304 Not Modified` responses. Entries can also opt out individually via the
+/// `XX-No-Synthetic-Validators` response header.
+///
+/// If the response has a [RoutePolicy](super::middleware::RoutePolicy) data version then a
+/// deterministic weak `ETag` derived from it is used instead (unless it already has an `ETag`), so
+/// that all replicas agree.
+///
+/// Entries without any validators are always served in full (conditional requests can't match).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum SyntheticLastModified {
+    /// Always.
+    #[default]
+    Always,
+
+    /// Never.
+    Never,
+
+    /// Only for entries with at least this duration (or without a duration), so that
+    /// micro-cached entries don't get synthetic validators.
+    OnlyIfDurationAtLeast(Duration),
+}
+
+impl SyntheticLastModified {
+    /// Whether it applies to an entry with the duration.
+    pub fn applies(&self, duration: Option<Duration>) -> bool {
+        match self {
+            Self::Always => true,
+            Self::Never => false,
+            Self::OnlyIfDurationAtLeast(min_duration) => {
+                duration.is_none_or(|duration| duration >= *min_duration)
+            }
+        }
+    }
 }
 
 //
@@ -35,4 +167,49 @@ pub struct EncodingConfiguration {
 
     /// Keep identity encoding.
     pub keep_identity_encoding: bool,
+
+    /// Transcoders for custom codings.
+    pub transcoders: TranscoderRegistry,
+
+    /// Transcode verification.
+    pub verification: Option<TranscodeVerification>,
+
+    /// Validator policy for transformed responses.
+    pub on_the_fly_validators: OnTheFlyValidatorPolicy,
+
+    /// Respect `Cache-Control: no-transform`.
+    pub respect_no_transform: bool,
+
+    /// Treat all responses as if they had `Cache-Control: no-transform`.
+    pub never_transform: bool,
+
+    /// Codings that may be served, besides [Identity](kutil::transcoding::Encoding::Identity).
+    ///
+    /// Set from the enabled codings by
+    /// [MiddlewareConfiguration](super::middleware::MiddlewareConfiguration). [None] means all.
+    pub enabled_codings: Option<Arc<[CodingId]>>,
+
+    /// Prune representations in codings that are not enabled when an entry is modified.
+    pub prune_disabled_representations: bool,
+
+    /// Maximum number of representations per entry.
+    pub max_cached_encodings: Option<usize>,
+}
+
+impl EncodingConfiguration {
+    /// Whether a coding may be served.
+    ///
+    /// [Identity](kutil::transcoding::Encoding::Identity) always may.
+    pub fn is_enabled(&self, coding: &CodingId) -> bool {
+        coding.is_identity()
+            || self
+                .enabled_codings
+                .as_ref()
+                .is_none_or(|enabled_codings| enabled_codings.contains(coding))
+    }
+
+    /// Whether the message's content coding must not be changed.
+    pub fn no_transform(&self, headers: &HeaderMap) -> bool {
+        self.never_transform || (self.respect_no_transform && has_no_transform(headers))
+    }
 }
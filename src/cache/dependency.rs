@@ -0,0 +1,259 @@
+use super::{cache::*, invalidation::*, key::*, response::*, self_test::*};
+
+use {http::HeaderName, kutil::std::collections::*, std::sync::*};
+
+/// Default maximum number of keys indexed by [DependencyCache].
+pub const DEFAULT_MAX_DEPENDENT_KEYS: usize = 64 * 1024;
+
+/// `XX-Cache-Tags` header name.
+///
+/// Comma-separated tags of a response (surrogate keys), e.g. `product-42, category-7`. They are
+/// stored as [dependency tokens](CachedResponse::dependencies), so that a [DependencyCache] can
+/// purge all entries with a tag.
+pub const XX_CACHE_TAGS: HeaderName = HeaderName::from_static("xx-cache-tags");
+
+//
+// DependencyCache
+//
+
+/// [Cache] wrapper that indexes entries by their
+/// [dependency tokens](CachedResponse::dependencies), so that they can be purged by token.
+///
+/// Applications can thus invalidate all responses rendered from some data (e.g. `product:42`)
+/// where they invalidate the data itself, without knowing which URLs it affects. Tokens are
+/// recorded by handlers (see [CacheDependencies](super::middleware::CacheDependencies)) or
+/// declared as tags in the [XX_CACHE_TAGS] response header.
+///
+/// The index is updated by all puts and invalidations through this wrapper, so it should be the
+/// outermost wrapper (or at least outside of any wrapper that puts by itself). Keys of entries
+/// that the inner cache evicts by itself remain indexed until they are put or invalidated again,
+/// which is harmless: purging them is a no-op.
+///
+/// The index is bounded. When the bound is reached, arbitrary indexed entries are invalidated to
+/// make room, because an entry that isn't indexed can't be purged.
+///
+/// Cloning is cheap and clones share state.
+#[derive(Clone, Debug)]
+pub struct DependencyCache<CacheT, CacheKeyT = CommonCacheKey> {
+    /// Inner cache.
+    pub inner: CacheT,
+
+    /// How keys are rendered in logs.
+    pub key_log_policy: KeyLogPolicy,
+
+    index: Arc<Mutex<DependencyIndex<CacheKeyT>>>,
+}
+
+impl<CacheT, CacheKeyT> DependencyCache<CacheT, CacheKeyT> {
+    /// Constructor.
+    pub fn new(inner: CacheT) -> Self {
+        Self::new_with_capacity(inner, DEFAULT_MAX_DEPENDENT_KEYS)
+    }
+
+    /// Constructor.
+    ///
+    /// `capacity` is the maximum number of keys to index.
+    pub fn new_with_capacity(inner: CacheT, capacity: usize) -> Self {
+        Self {
+            inner,
+            key_log_policy: Default::default(),
+            index: Arc::new(Mutex::new(DependencyIndex {
+                keys: FastHashMap::default(),
+                tokens: FastHashMap::default(),
+                capacity,
+            })),
+        }
+    }
+
+    /// Set key log policy.
+    pub fn with_key_log_policy(mut self, key_log_policy: KeyLogPolicy) -> Self {
+        self.key_log_policy = key_log_policy;
+        self
+    }
+}
+
+impl<CacheT, CacheKeyT> DependencyCache<CacheT, CacheKeyT>
+where
+    CacheT: Cache<CacheKeyT>,
+    CacheKeyT: CacheKey,
+{
+    /// Invalidate all entries that recorded a dependency token (or declared it as a tag).
+    ///
+    /// Returns the number of invalidated keys.
+    pub async fn purge_dependency(&self, token: &str) -> usize {
+        let keys = self.index.lock().expect("lock").remove_token(token);
+
+        for key in &keys {
+            self.inner.invalidate(key).await;
+        }
+
+        tracing::debug!("purged dependency {}: {} keys", token, keys.len());
+        keys.len()
+    }
+
+    /// Number of indexed tokens.
+    pub fn token_count(&self) -> usize {
+        self.index.lock().expect("lock").keys.len()
+    }
+
+    // Index an entry and then invalidate the entries evicted from the index to make room.
+    async fn index(&self, key: &CacheKeyT, cached_response: &CachedResponse) {
+        let evicted = {
+            let mut index = self.index.lock().expect("lock");
+            index.remove_key(key);
+
+            if cached_response.dependencies.is_empty() {
+                return;
+            }
+
+            let mut evicted = Vec::default();
+            while index.tokens.len() >= index.capacity.max(1)
+                && let Some(evicted_key) = index.tokens.keys().next().cloned()
+            {
+                index.remove_key(&evicted_key);
+                evicted.push(evicted_key);
+            }
+
+            index.insert(key, &cached_response.dependencies);
+            evicted
+        };
+
+        for evicted_key in evicted {
+            tracing::debug!(
+                "evicted from dependency index: {}",
+                evicted_key.display_for_logs(&self.key_log_policy)
+            );
+            self.inner.invalidate(&evicted_key).await;
+        }
+    }
+}
+
+impl<CacheT, CacheKeyT> Cache<CacheKeyT> for DependencyCache<CacheT, CacheKeyT>
+where
+    CacheT: Cache<CacheKeyT>,
+    CacheKeyT: CacheKey,
+{
+    async fn get(&self, key: &CacheKeyT) -> Option<CachedResponseRef> {
+        self.inner.get(key).await
+    }
+
+    fn get_if_present(&self, key: &CacheKeyT) -> Option<Option<CachedResponseRef>> {
+        self.inner.get_if_present(key)
+    }
+
+    async fn put(&self, key: CacheKeyT, cached_response: CachedResponseRef) {
+        self.index(&key, &cached_response).await;
+        self.inner.put(key, cached_response).await
+    }
+
+    fn fence(&self, key: &CacheKeyT) -> Fence {
+        self.inner.fence(key)
+    }
+
+    async fn put_fenced(
+        &self,
+        key: CacheKeyT,
+        cached_response: CachedResponseRef,
+        fence: Fence,
+    ) -> PutOutcome {
+        self.index(&key, &cached_response).await;
+        self.inner.put_fenced(key, cached_response, fence).await
+    }
+
+    async fn update<UpdateT>(&self, key: CacheKeyT, update: UpdateT) -> bool
+    where
+        UpdateT: FnOnce(CachedResponseRef) -> Option<CachedResponseRef> + Send,
+    {
+        // Updates keep the dependencies (see CachedResponse::clone_with_body)
+        self.inner.update(key, update).await
+    }
+
+    async fn invalidate(&self, key: &CacheKeyT) {
+        self.index.lock().expect("lock").remove_key(key);
+        self.inner.invalidate(key).await
+    }
+
+    async fn invalidate_all(&self) {
+        {
+            let mut index = self.index.lock().expect("lock");
+            index.keys.clear();
+            index.tokens.clear();
+        }
+        self.inner.invalidate_all().await
+    }
+
+    async fn invalidate_all_with_outcome(&self) -> InvalidationOutcome {
+        {
+            let mut index = self.index.lock().expect("lock");
+            index.keys.clear();
+            index.tokens.clear();
+        }
+        self.inner.invalidate_all_with_outcome().await
+    }
+
+    async fn self_test(&self) -> Result<SelfTestReport, SelfTestError> {
+        self.inner.self_test().await
+    }
+
+    fn entry_count(&self) -> Option<u64> {
+        self.inner.entry_count()
+    }
+
+    fn weighted_size(&self) -> Option<u64> {
+        self.inner.weighted_size()
+    }
+
+    fn keys(&self) -> Option<Vec<CacheKeyT>> {
+        self.inner.keys()
+    }
+}
+
+//
+// DependencyIndex
+//
+
+#[derive(Debug)]
+struct DependencyIndex<CacheKeyT> {
+    // Token to keys
+    keys: FastHashMap<Arc<str>, FastHashSet<CacheKeyT>>,
+
+    // Key to tokens
+    tokens: FastHashMap<CacheKeyT, Arc<[Arc<str>]>>,
+
+    capacity: usize,
+}
+
+impl<CacheKeyT> DependencyIndex<CacheKeyT>
+where
+    CacheKeyT: CacheKey,
+{
+    fn insert(&mut self, key: &CacheKeyT, tokens: &Arc<[Arc<str>]>) {
+        for token in tokens.iter() {
+            self.keys.entry(token.clone()).or_default().insert(key.clone());
+        }
+        self.tokens.insert(key.clone(), tokens.clone());
+    }
+
+    fn remove_key(&mut self, key: &CacheKeyT) {
+        let Some(tokens) = self.tokens.remove(key) else {
+            return;
+        };
+
+        for token in tokens.iter() {
+            if let Some(keys) = self.keys.get_mut(token) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.keys.remove(token);
+                }
+            }
+        }
+    }
+
+    fn remove_token(&mut self, token: &str) -> Vec<CacheKeyT> {
+        let keys: Vec<_> = self.keys.get(token).into_iter().flatten().cloned().collect();
+        for key in &keys {
+            self.remove_key(key);
+        }
+        keys
+    }
+}
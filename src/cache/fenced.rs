@@ -0,0 +1,202 @@
+use super::{cache::*, invalidation::*, key::*, response::*, self_test::*};
+
+use {
+    kutil::std::collections::*,
+    std::sync::{atomic::*, *},
+};
+
+/// Default maximum number of recent invalidations tracked by [FencedCache].
+pub const DEFAULT_RECENT_INVALIDATIONS: usize = 1024;
+
+//
+// FencedCache
+//
+
+/// [Cache] wrapper with invalidation fencing.
+///
+/// Guards against entry "resurrection": a request misses, calls the upstream, the key is
+/// invalidated meanwhile, and then the (now stale) response is stored. With this wrapper such a
+/// [put_fenced](Cache::put_fenced) will be rejected with [PutOutcome::RejectedStale].
+///
+/// Recent invalidations are tracked per key. The number tracked is bounded: when the bound is
+/// exceeded they are forgotten and the epoch is bumped instead, which fences everything that was
+/// captured before. [invalidate_all](Cache::invalidate_all) likewise bumps the epoch.
+#[derive(Clone, Debug)]
+pub struct FencedCache<CacheT, CacheKeyT = CommonCacheKey> {
+    /// Inner cache.
+    pub inner: CacheT,
+
+    /// How keys are rendered in logs.
+    pub key_log_policy: KeyLogPolicy,
+
+    fences: Arc<Fences<CacheKeyT>>,
+}
+
+impl<CacheT, CacheKeyT> FencedCache<CacheT, CacheKeyT> {
+    /// Constructor.
+    pub fn new(inner: CacheT) -> Self {
+        Self::new_with_capacity(inner, DEFAULT_RECENT_INVALIDATIONS)
+    }
+
+    /// Constructor.
+    ///
+    /// `capacity` is the maximum number of recent invalidations to track.
+    pub fn new_with_capacity(inner: CacheT, capacity: usize) -> Self {
+        Self {
+            inner,
+            key_log_policy: Default::default(),
+            fences: Arc::new(Fences {
+                epoch: AtomicU64::new(0),
+                sequence: AtomicU64::new(0),
+                recent: Mutex::new(FastHashMap::default()),
+                capacity,
+            }),
+        }
+    }
+
+    /// Set key log policy.
+    pub fn with_key_log_policy(mut self, key_log_policy: KeyLogPolicy) -> Self {
+        self.key_log_policy = key_log_policy;
+        self
+    }
+}
+
+impl<CacheT, CacheKeyT> Cache<CacheKeyT> for FencedCache<CacheT, CacheKeyT>
+where
+    CacheT: Cache<CacheKeyT>,
+    CacheKeyT: CacheKey,
+{
+    async fn get(&self, key: &CacheKeyT) -> Option<CachedResponseRef> {
+        self.inner.get(key).await
+    }
+
+    fn get_if_present(&self, key: &CacheKeyT) -> Option<Option<CachedResponseRef>> {
+        self.inner.get_if_present(key)
+    }
+
+    async fn put(&self, key: CacheKeyT, cached_response: CachedResponseRef) {
+        self.inner.put(key, cached_response).await
+    }
+
+    fn fence(&self, _key: &CacheKeyT) -> Fence {
+        Fence {
+            epoch: self.fences.epoch.load(Ordering::Acquire),
+            sequence: self.fences.sequence.load(Ordering::Acquire),
+        }
+    }
+
+    async fn put_fenced(
+        &self,
+        key: CacheKeyT,
+        cached_response: CachedResponseRef,
+        fence: Fence,
+    ) -> PutOutcome {
+        if self.fences.is_stale(&key, fence) {
+            tracing::debug!(
+                "put rejected (stale): {}",
+                key.display_for_logs(&self.key_log_policy)
+            );
+            return PutOutcome::RejectedStale;
+        }
+
+        self.inner.put(key.clone(), cached_response).await;
+
+        // An invalidation might have happened while we were putting
+        if self.fences.is_stale(&key, fence) {
+            tracing::debug!(
+                "put rejected (stale): {}",
+                key.display_for_logs(&self.key_log_policy)
+            );
+            self.inner.invalidate(&key).await;
+            return PutOutcome::RejectedStale;
+        }
+
+        PutOutcome::Stored
+    }
+
+    async fn update<UpdateT>(&self, key: CacheKeyT, update: UpdateT) -> bool
+    where
+        UpdateT: FnOnce(CachedResponseRef) -> Option<CachedResponseRef> + Send,
+    {
+        // Updates never resurrect entries, so they need no fencing
+        self.inner.update(key, update).await
+    }
+
+    async fn invalidate(&self, key: &CacheKeyT) {
+        self.fences.invalidate(key);
+        self.inner.invalidate(key).await
+    }
+
+    async fn invalidate_all(&self) {
+        self.fences.invalidate_all();
+        self.inner.invalidate_all().await
+    }
+
+    async fn invalidate_all_with_outcome(&self) -> InvalidationOutcome {
+        self.fences.invalidate_all();
+        self.inner.invalidate_all_with_outcome().await
+    }
+
+    async fn self_test(&self) -> Result<SelfTestReport, SelfTestError> {
+        self.inner.self_test().await
+    }
+
+    fn entry_count(&self) -> Option<u64> {
+        self.inner.entry_count()
+    }
+
+    fn weighted_size(&self) -> Option<u64> {
+        self.inner.weighted_size()
+    }
+
+    fn keys(&self) -> Option<Vec<CacheKeyT>> {
+        self.inner.keys()
+    }
+}
+
+//
+// Fences
+//
+
+#[derive(Debug)]
+struct Fences<CacheKeyT> {
+    epoch: AtomicU64,
+    sequence: AtomicU64,
+    recent: Mutex<FastHashMap<CacheKeyT, u64>>,
+    capacity: usize,
+}
+
+impl<CacheKeyT> Fences<CacheKeyT>
+where
+    CacheKeyT: CacheKey,
+{
+    fn is_stale(&self, key: &CacheKeyT, fence: Fence) -> bool {
+        if self.epoch.load(Ordering::Acquire) != fence.epoch {
+            return true;
+        }
+
+        match self.recent.lock().expect("lock").get(key) {
+            Some(sequence) => *sequence > fence.sequence,
+            None => false,
+        }
+    }
+
+    fn invalidate(&self, key: &CacheKeyT) {
+        let mut recent = self.recent.lock().expect("lock");
+
+        if recent.len() >= self.capacity {
+            // Forget recent invalidations; the epoch fences them all
+            recent.clear();
+            self.epoch.fetch_add(1, Ordering::AcqRel);
+        }
+
+        let sequence = self.sequence.fetch_add(1, Ordering::AcqRel) + 1;
+        recent.insert(key.clone(), sequence);
+    }
+
+    fn invalidate_all(&self) {
+        let mut recent = self.recent.lock().expect("lock");
+        recent.clear();
+        self.epoch.fetch_add(1, Ordering::AcqRel);
+    }
+}
@@ -0,0 +1,116 @@
+use {
+    http::header::*,
+    kutil::http::*,
+    std::{fmt, time::*},
+};
+
+//
+// HeuristicConfig
+//
+
+/// Heuristic freshness configuration.
+///
+/// See [IETF RFC 9111 section 4.2.2](https://datatracker.ietf.org/doc/html/rfc9111#section-4.2.2).
+#[derive(Clone, Copy, Debug)]
+pub struct HeuristicConfig {
+    /// Fraction of the time since `Last-Modified`.
+    pub fraction: f64,
+
+    /// Minimum duration.
+    pub min: Duration,
+
+    /// Maximum duration.
+    pub max: Duration,
+
+    /// Whether `Last-Modified` is required. If false, responses without it get the minimum
+    /// duration.
+    pub require_last_modified: bool,
+}
+
+impl HeuristicConfig {
+    /// The heuristic duration, if applicable.
+    ///
+    /// Not applicable if the response has an explicit expiration (`Cache-Control` with `max-age` or
+    /// `s-maxage`, or `Expires`) or if `Last-Modified` is in the future.
+    pub fn duration(&self, headers: &HeaderMap, now: SystemTime) -> Option<Duration> {
+        if has_explicit_expiration(headers) {
+            return None;
+        }
+
+        let Some(last_modified) = headers.last_modified() else {
+            return (!self.require_last_modified).then_some(self.min);
+        };
+
+        // Errors if in the future
+        let age = now.duration_since(SystemTime::from(last_modified)).ok()?;
+
+        Some(age.mul_f64(self.fraction).clamp(self.min, self.max))
+    }
+}
+
+impl Default for HeuristicConfig {
+    fn default() -> Self {
+        Self {
+            fraction: 0.1,
+            min: Duration::from_secs(60),
+            max: Duration::from_secs(60 * 60 * 24),
+            require_last_modified: true,
+        }
+    }
+}
+
+//
+// DurationSource
+//
+
+/// Source of a cache duration.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DurationSource {
+    /// `XX-Cache-Duration` header.
+    Explicit,
+
+    /// Route policy.
+    Policy,
+
+    /// Cache duration hook.
+    Hook,
+
+    /// Standard `Cache-Control` or `Expires` header.
+    CacheControl,
+
+    /// Default for status codes other than success (2xx).
+    Negative,
+
+    /// Heuristic freshness.
+    Heuristic,
+
+    /// None of the above, so the cache implementation's default applies.
+    Default,
+}
+
+impl fmt::Display for DurationSource {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Explicit => write!(formatter, "explicit"),
+            Self::Policy => write!(formatter, "policy"),
+            Self::Hook => write!(formatter, "hook"),
+            Self::CacheControl => write!(formatter, "cache-control"),
+            Self::Negative => write!(formatter, "negative"),
+            Self::Heuristic => write!(formatter, "heuristic"),
+            Self::Default => write!(formatter, "default"),
+        }
+    }
+}
+
+fn has_explicit_expiration(headers: &HeaderMap) -> bool {
+    if headers.contains_key(EXPIRES) {
+        return true;
+    }
+
+    headers.string_values(CACHE_CONTROL).iter().any(|value| {
+        value.split(',').any(|directive| {
+            let name = directive.split('=').next().unwrap_or_default().trim();
+            name.eq_ignore_ascii_case("max-age") || name.eq_ignore_ascii_case("s-maxage")
+        })
+    })
+}
@@ -1,12 +1,24 @@
 use {
     http::*,
-    std::{sync::*, time::*},
+    kutil::std::immutable::*,
+    std::{error::Error, result::Result, sync::*, time::*},
 };
 
+/// Hook to get the current time.
+pub type ClockHook = Arc<Box<dyn Fn() -> SystemTime + Send + Sync>>;
+
 /// Hook to get a response's cache duration.
 pub type CacheDurationHook =
     Arc<Box<dyn Fn(CacheDurationHookContext) -> Option<Duration> + Send + Sync>>;
 
+/// Hook to transform a response body before storing it.
+///
+/// Returns [None] to keep the original body.
+pub type TransformHook = Arc<Box<dyn Fn(TransformHookContext) -> TransformResult + Send + Sync>>;
+
+/// Result of [TransformHook].
+pub type TransformResult = Result<Option<TransformedBody>, Box<dyn Error + Send + Sync>>;
+
 //
 // CacheDurationHookContext
 //
@@ -26,3 +38,69 @@ impl<'this> CacheDurationHookContext<'this> {
         Self { uri, headers }
     }
 }
+
+//
+// TransformHookContext
+//
+
+/// Context for [TransformHook].
+pub struct TransformHookContext<'this> {
+    /// URI.
+    pub uri: &'this Uri,
+
+    /// Headers.
+    pub headers: &'this HeaderMap,
+
+    /// Body (Identity).
+    pub bytes: &'this ImmutableBytes,
+}
+
+impl<'this> TransformHookContext<'this> {
+    /// Constructor.
+    pub fn new(uri: &'this Uri, headers: &'this HeaderMap, bytes: &'this ImmutableBytes) -> Self {
+        Self { uri, headers, bytes }
+    }
+}
+
+//
+// TransformedBody
+//
+
+/// Transformed body returned by [TransformHook].
+#[derive(Clone, Debug)]
+pub struct TransformedBody {
+    /// Body (Identity).
+    pub bytes: ImmutableBytes,
+
+    /// Headers to set, replacing existing values (e.g. an updated `Content-Type`).
+    pub headers: HeaderMap,
+}
+
+impl TransformedBody {
+    /// Constructor.
+    pub fn new(bytes: ImmutableBytes) -> Self {
+        Self {
+            bytes,
+            headers: Default::default(),
+        }
+    }
+}
+
+//
+// TransformPolicy
+//
+
+/// What the request that triggered a [TransformHook] receives.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum TransformPolicy {
+    /// The upstream body unchanged. The transformed body is what's stored for subsequent
+    /// requests.
+    ///
+    /// We still wait for the transformation, but defer encoding the transformed body to the
+    /// requests that need it.
+    #[default]
+    OriginalFirst,
+
+    /// The transformed body, too.
+    TransformedToo,
+}
@@ -0,0 +1,326 @@
+use super::{
+    super::{cache::*, key::*, response::*},
+    lru::*,
+};
+
+use {
+    kutil::std::{collections::*, immutable::*},
+    std::{fmt::Write, sync::*, time::*},
+};
+
+//
+// ByteStore
+//
+
+/// Minimal byte store, e.g. a platform's own caching facade.
+///
+/// Deliberately the lowest common denominator: values are opaque bytes and keys are strings. See
+/// [ByteStoreCache].
+///
+/// Implementations should ensure that cloning is cheap and clones always refer to the same shared
+/// state.
+#[allow(async_fn_in_trait)]
+pub trait ByteStore
+where
+    Self: 'static + Clone + Send + Sync,
+{
+    /// Get a value.
+    fn get(&self, key: &str) -> impl Future<Output = Option<ImmutableBytes>> + Send;
+
+    /// Put a value, to be removed after the TTL if there is one.
+    fn put(
+        &self,
+        key: String,
+        bytes: ImmutableBytes,
+        ttl: Option<Duration>,
+    ) -> impl Future<Output = ()> + Send;
+
+    /// Delete a value.
+    fn delete(&self, key: &str) -> impl Future<Output = ()> + Send;
+
+    /// Delete all values.
+    fn clear(&self) -> impl Future<Output = ()> + Send;
+}
+
+//
+// ByteStoreCache
+//
+
+/// Default for [ByteStoreCache::max_key_length].
+pub const DEFAULT_BYTE_STORE_MAX_KEY_LENGTH: usize = 250;
+
+/// [Cache] adapter over a [ByteStore].
+///
+/// Keys are rendered as the hex of their [canonical form](CanonicalKeyForm), after the prefix.
+/// If that would be longer than the maximum key length then the
+/// [digest](CanonicalKeyForm::canonical_digest) and the length of the canonical form are used
+/// instead. Entries are stored in the [serialized](CachedResponse::serialize) format, with a TTL
+/// of their remaining [duration](CachedResponse::duration) plus the grace period. Entries that
+/// fail to deserialize (e.g. written by an incompatible version) are misses.
+///
+/// Decoding hot entries over and over is wasteful, so deserialized entries can be
+/// [memoized](Self::with_memo) in process. Invalidation through this adapter also invalidates
+/// the memo, but writes by other processes are only seen once the memoized entry expires or is
+/// evicted.
+///
+/// Compared to the Moka implementation, these capabilities degrade gracefully:
+///
+/// * No key enumeration, entry count, or weighted size: [keys](Cache::keys),
+///   [entry_count](Cache::entry_count), and [weighted_size](Cache::weighted_size) return [None],
+///   so features that need them (e.g. bans, tag invalidation, and reset counts) report that
+///   they are unsupported.
+/// * No fencing: [put_fenced](Cache::put_fenced) always puts.
+/// * [update](Cache::update) is not compare-and-swap (it compares the memoized entry, if any).
+/// * No eviction events: the memo cannot notice changes made by other processes.
+///
+/// Cloning is cheap and clones refer to the same shared state.
+#[derive(Clone, Debug)]
+pub struct ByteStoreCache<ByteStoreT, CacheKeyT = CommonCacheKey> {
+    /// Byte store.
+    pub store: ByteStoreT,
+
+    /// Key prefix.
+    pub key_prefix: Arc<str>,
+
+    /// Maximum rendered key length (including the prefix).
+    pub max_key_length: usize,
+
+    /// Grace period.
+    ///
+    /// Expired entries are retained for this long beyond their duration so that they can be
+    /// refreshed.
+    pub grace: Duration,
+
+    /// Tokens of custom codings (see [CachedResponse::deserialize]).
+    pub custom_codings: Arc<[&'static str]>,
+
+    /// In-process memo of deserialized entries.
+    pub memo: Option<SimpleLruCache<CacheKeyT>>,
+
+    /// How keys are rendered in logs.
+    pub key_log_policy: KeyLogPolicy,
+}
+
+impl<ByteStoreT, CacheKeyT> ByteStoreCache<ByteStoreT, CacheKeyT>
+where
+    ByteStoreT: ByteStore,
+    CacheKeyT: CacheKey + CanonicalKeyForm,
+{
+    /// Constructor.
+    pub fn new(store: ByteStoreT) -> Self {
+        Self {
+            store,
+            key_prefix: "".into(),
+            max_key_length: DEFAULT_BYTE_STORE_MAX_KEY_LENGTH,
+            grace: Default::default(),
+            custom_codings: Arc::new([]),
+            memo: None,
+            key_log_policy: Default::default(),
+        }
+    }
+
+    /// Set key prefix.
+    ///
+    /// The default is no prefix.
+    pub fn with_key_prefix(mut self, key_prefix: impl Into<Arc<str>>) -> Self {
+        self.key_prefix = key_prefix.into();
+        self
+    }
+
+    /// Set maximum rendered key length.
+    ///
+    /// The default is [DEFAULT_BYTE_STORE_MAX_KEY_LENGTH].
+    pub fn with_max_key_length(mut self, max_key_length: usize) -> Self {
+        self.max_key_length = max_key_length;
+        self
+    }
+
+    /// Set grace period.
+    ///
+    /// The default is zero.
+    pub fn with_grace(mut self, grace: Duration) -> Self {
+        self.grace = grace;
+        if let Some(memo) = self.memo.take() {
+            self.memo = Some(memo.with_grace(grace));
+        }
+        self
+    }
+
+    /// Set tokens of custom codings.
+    ///
+    /// The default is none.
+    pub fn with_custom_codings(mut self, custom_codings: &[&'static str]) -> Self {
+        self.custom_codings = custom_codings.into();
+        self
+    }
+
+    /// Memoize deserialized entries in process, up to a maximum total weight.
+    ///
+    /// `max_age` bounds how long entries without a duration are memoized.
+    ///
+    /// [None] by default.
+    pub fn with_memo(mut self, max_weight: usize, max_age: Option<Duration>) -> Self {
+        self.memo = Some(
+            SimpleLruCache::new(max_weight, max_age)
+                .with_grace(self.grace)
+                .with_key_log_policy(self.key_log_policy.clone()),
+        );
+        self
+    }
+
+    /// Set key log policy.
+    pub fn with_key_log_policy(mut self, key_log_policy: KeyLogPolicy) -> Self {
+        if let Some(memo) = self.memo.take() {
+            self.memo = Some(memo.with_key_log_policy(key_log_policy.clone()));
+        }
+        self.key_log_policy = key_log_policy;
+        self
+    }
+
+    /// Render a key for the store.
+    pub fn render_key(&self, key: &CacheKeyT) -> String {
+        let canonical_bytes = key.canonical_bytes();
+
+        let hex_length = self.key_prefix.len() + canonical_bytes.len() * 2;
+
+        let mut rendered = String::with_capacity(hex_length);
+        rendered.push_str(&self.key_prefix);
+
+        if hex_length <= self.max_key_length {
+            for byte in &canonical_bytes {
+                let _ = write!(rendered, "{:02x}", byte);
+            }
+        } else {
+            let _ = write!(
+                rendered,
+                "h{:016x}-{:x}",
+                stable_hash(&canonical_bytes),
+                canonical_bytes.len()
+            );
+        }
+
+        rendered
+    }
+
+    // Remaining time until the entry's duration plus the grace period.
+    fn ttl(&self, cached_response: &CachedResponse) -> Option<Duration> {
+        cached_response.duration.map(|duration| {
+            let elapsed = SystemTime::now()
+                .duration_since(cached_response.created)
+                .unwrap_or_default();
+            duration.saturating_add(self.grace).saturating_sub(elapsed)
+        })
+    }
+}
+
+impl<ByteStoreT, CacheKeyT> Cache<CacheKeyT> for ByteStoreCache<ByteStoreT, CacheKeyT>
+where
+    ByteStoreT: ByteStore,
+    CacheKeyT: CacheKey + CanonicalKeyForm,
+{
+    async fn get(&self, key: &CacheKeyT) -> Option<CachedResponseRef> {
+        if let Some(memo) = &self.memo
+            && let Some(cached_response) = memo.get(key).await
+        {
+            return Some(cached_response);
+        }
+
+        let bytes = self.store.get(&self.render_key(key)).await?;
+        let cached_response = match CachedResponse::deserialize(&bytes, &self.custom_codings) {
+            Ok(cached_response) => Arc::new(cached_response),
+
+            Err(error) => {
+                tracing::warn!("{} for {}", error, key.display_for_logs(&self.key_log_policy));
+                return None;
+            }
+        };
+
+        if let Some(memo) = &self.memo {
+            memo.put(key.clone(), cached_response.clone()).await;
+        }
+
+        Some(cached_response)
+    }
+
+    async fn put(&self, key: CacheKeyT, cached_response: CachedResponseRef) {
+        let bytes = ImmutableBytes::from(cached_response.serialize());
+        let ttl = self.ttl(&cached_response);
+        self.store.put(self.render_key(&key), bytes, ttl).await;
+
+        if let Some(memo) = &self.memo {
+            memo.put(key, cached_response).await;
+        }
+    }
+
+    async fn invalidate(&self, key: &CacheKeyT) {
+        if let Some(memo) = &self.memo {
+            memo.invalidate(key).await;
+        }
+
+        self.store.delete(&self.render_key(key)).await;
+    }
+
+    async fn invalidate_all(&self) {
+        if let Some(memo) = &self.memo {
+            memo.invalidate_all().await;
+        }
+
+        self.store.clear().await;
+    }
+}
+
+//
+// HashMapByteStore
+//
+
+/// In-memory [ByteStore] over a hash map.
+///
+/// For tests and as a reference implementation. Values expire lazily, when gotten after their
+/// TTL.
+///
+/// Cloning is cheap and clones share state.
+#[derive(Clone, Debug, Default)]
+pub struct HashMapByteStore {
+    values: Arc<Mutex<FastHashMap<String, ExpiringBytes>>>,
+}
+
+// Bytes with an optional deadline.
+type ExpiringBytes = (ImmutableBytes, Option<Instant>);
+
+impl HashMapByteStore {
+    /// Number of values (including expired ones that have not been removed yet).
+    pub fn len(&self) -> usize {
+        self.values.lock().expect("lock").len()
+    }
+
+    /// Whether there are no values.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl ByteStore for HashMapByteStore {
+    async fn get(&self, key: &str) -> Option<ImmutableBytes> {
+        let mut values = self.values.lock().expect("lock");
+        let (bytes, deadline) = values.get(key)?;
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            values.remove(key);
+            return None;
+        }
+        Some(bytes.clone())
+    }
+
+    async fn put(&self, key: String, bytes: ImmutableBytes, ttl: Option<Duration>) {
+        // TTLs beyond what Instant can represent never expire
+        let deadline = ttl.and_then(|ttl| Instant::now().checked_add(ttl));
+        self.values.lock().expect("lock").insert(key, (bytes, deadline));
+    }
+
+    async fn delete(&self, key: &str) {
+        self.values.lock().expect("lock").remove(key);
+    }
+
+    async fn clear(&self) {
+        self.values.lock().expect("lock").clear();
+    }
+}
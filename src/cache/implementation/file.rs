@@ -0,0 +1,442 @@
+use super::super::{cache::*, invalidation::*, key::*, response::*};
+
+use {
+    kutil::std::collections::*,
+    std::{
+        collections::BTreeMap,
+        fmt, io,
+        path::*,
+        sync::{atomic::*, *},
+        time::*,
+    },
+    tokio::fs,
+};
+
+const ENTRY_EXTENSION: &str = "entry";
+const TEMPORARY_EXTENSION: &str = "tmp";
+
+//
+// FileCacheImplementation
+//
+
+/// Disk-backed cache implementation.
+///
+/// Each entry is a file in a directory, named after a hash of the key's [Display](fmt::Display).
+/// The file holds an expiry timestamp, the key (to detect hash collisions), and the
+/// [serialized](CachedResponse::serialize) entry, with its headers and all body representations.
+/// Files are written to a temporary file and then renamed, so readers never see partial writes.
+///
+/// An entry expires after its [duration](CachedResponse::duration) plus the
+/// [grace](Self::with_grace) period. Expired files are misses and are deleted when gotten.
+/// Corrupted files (e.g. written by an incompatible version or modified by someone else) are
+/// also misses and are deleted.
+///
+/// When the total size of the files exceeds the maximum, the oldest written are evicted. An entry
+/// that by itself is larger than the maximum is not stored.
+///
+/// Intended for large bodies that shouldn't be held in memory. Combine it with an in-memory
+/// cache via a [TieredCache](super::super::TieredCache) for a memory and disk hierarchy.
+///
+/// Key enumeration is not supported: [keys](Cache::keys) returns [None].
+///
+/// Requires the `file` feature and a Tokio runtime.
+///
+/// Cloning is cheap and clones refer to the same shared state.
+#[derive(Clone)]
+pub struct FileCacheImplementation {
+    /// Directory.
+    pub directory: Arc<PathBuf>,
+
+    /// Maximum total size of files in bytes.
+    pub max_size: u64,
+
+    /// Grace period.
+    ///
+    /// Expired entries are retained for this long beyond their duration so that they can be
+    /// refreshed.
+    pub grace: Duration,
+
+    /// Tokens of custom codings (see [CachedResponse::deserialize]).
+    pub custom_codings: Arc<[&'static str]>,
+
+    /// How keys are rendered in logs.
+    pub key_log_policy: KeyLogPolicy,
+
+    state: Arc<Mutex<FileCacheState>>,
+    temporary_sequence: Arc<AtomicU64>,
+}
+
+impl FileCacheImplementation {
+    /// Open a directory, creating it if necessary.
+    ///
+    /// Existing entry files are adopted (in order of modification time) and leftover temporary
+    /// files are deleted. Entries beyond the maximum size are evicted.
+    pub async fn open(directory: impl Into<PathBuf>, max_size: u64) -> io::Result<Self> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory).await?;
+
+        let mut files = Vec::default();
+        let mut read_dir = fs::read_dir(&directory).await?;
+        while let Some(dir_entry) = read_dir.next_entry().await? {
+            let path = dir_entry.path();
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some(ENTRY_EXTENSION) => {
+                    let metadata = dir_entry.metadata().await?;
+                    if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
+                        let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+                        files.push((modified, name.to_string(), metadata.len()));
+                    }
+                }
+
+                Some(TEMPORARY_EXTENSION) => remove_file(&path).await,
+
+                _ => {}
+            }
+        }
+
+        files.sort();
+
+        let mut state = FileCacheState::default();
+        for (_, name, size) in files {
+            state.insert(name, size);
+        }
+
+        let evicted = state.evict_until(max_size);
+
+        let file_cache = Self {
+            directory: directory.into(),
+            max_size,
+            grace: Default::default(),
+            custom_codings: Arc::new([]),
+            key_log_policy: Default::default(),
+            state: Arc::new(Mutex::new(state)),
+            temporary_sequence: Default::default(),
+        };
+
+        file_cache.remove_files(evicted).await;
+        Ok(file_cache)
+    }
+
+    /// Retain expired entries for a grace period.
+    ///
+    /// The default is zero.
+    pub fn with_grace(mut self, grace: Duration) -> Self {
+        self.grace = grace;
+        self
+    }
+
+    /// Set tokens of custom codings.
+    ///
+    /// The default is none.
+    pub fn with_custom_codings(mut self, custom_codings: &[&'static str]) -> Self {
+        self.custom_codings = custom_codings.into();
+        self
+    }
+
+    /// Set key log policy.
+    pub fn with_key_log_policy(mut self, key_log_policy: KeyLogPolicy) -> Self {
+        self.key_log_policy = key_log_policy;
+        self
+    }
+
+    /// Number of entry files (including expired ones that have not been removed yet).
+    pub fn len(&self) -> usize {
+        self.state.lock().expect("lock").files.len()
+    }
+
+    /// Whether there are no entry files.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Total size of entry files in bytes.
+    pub fn size(&self) -> u64 {
+        self.state.lock().expect("lock").size
+    }
+
+    /// File name for a key.
+    pub fn file_name<CacheKeyT>(&self, key: &CacheKeyT) -> String
+    where
+        CacheKeyT: CacheKey,
+    {
+        format!("{:016x}.{}", stable_hash(key.to_string().as_bytes()), ENTRY_EXTENSION)
+    }
+
+    // Delete all files, returning the number of entry files deleted.
+    async fn clear(&self) -> io::Result<u64> {
+        {
+            let mut state = self.state.lock().expect("lock");
+            state.files.clear();
+            state.order.clear();
+            state.size = 0;
+        }
+
+        let mut count = 0;
+        let mut read_dir = fs::read_dir(self.directory.as_path()).await?;
+        while let Some(dir_entry) = read_dir.next_entry().await? {
+            let path = dir_entry.path();
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some(ENTRY_EXTENSION) => {
+                    fs::remove_file(&path).await?;
+                    count += 1;
+                }
+
+                Some(TEMPORARY_EXTENSION) => remove_file(&path).await,
+
+                _ => {}
+            }
+        }
+
+        Ok(count)
+    }
+
+    // Forget and delete a file.
+    async fn forget(&self, name: &str) {
+        self.state.lock().expect("lock").remove(name);
+        remove_file(&self.directory.join(name)).await;
+    }
+
+    async fn remove_files(&self, names: Vec<String>) {
+        for name in names {
+            remove_file(&self.directory.join(name)).await;
+        }
+    }
+
+    // Header plus the serialized entry.
+    fn encode<CacheKeyT>(&self, key: &CacheKeyT, cached_response: &CachedResponse) -> Vec<u8>
+    where
+        CacheKeyT: CacheKey,
+    {
+        // Expiries beyond what SystemTime can represent are stored as none
+        let expiry = cached_response
+            .duration
+            .and_then(|duration| cached_response.created.checked_add(duration))
+            .and_then(|expiry| expiry.checked_add(self.grace))
+            .and_then(|expiry| expiry.duration_since(UNIX_EPOCH).ok())
+            .map(|expiry| expiry.as_millis().try_into().unwrap_or(u64::MAX))
+            .unwrap_or_default();
+
+        let key = key.to_string();
+        let serialized = cached_response.serialize();
+
+        let mut bytes = Vec::with_capacity(12 + key.len() + serialized.len());
+        bytes.extend_from_slice(&expiry.to_be_bytes());
+        bytes.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(key.as_bytes());
+        bytes.extend_from_slice(&serialized);
+        bytes
+    }
+
+    fn decode<CacheKeyT>(&self, key: &CacheKeyT, bytes: &[u8]) -> Result<Decoded, String>
+    where
+        CacheKeyT: CacheKey,
+    {
+        let (expiry, bytes) = bytes.split_at_checked(8).ok_or("truncated")?;
+        let (key_length, bytes) = bytes.split_at_checked(4).ok_or("truncated")?;
+        let key_length = u32::from_be_bytes(key_length.try_into().expect("4 bytes")) as usize;
+        let (stored_key, bytes) = bytes.split_at_checked(key_length).ok_or("truncated")?;
+
+        let expiry = u64::from_be_bytes(expiry.try_into().expect("8 bytes"));
+        if expiry != 0
+            && UNIX_EPOCH
+                .checked_add(Duration::from_millis(expiry))
+                .is_some_and(|expiry| expiry <= SystemTime::now())
+        {
+            return Ok(Decoded::Expired);
+        }
+
+        if stored_key != key.to_string().as_bytes() {
+            return Ok(Decoded::Collision);
+        }
+
+        CachedResponse::deserialize(bytes, &self.custom_codings)
+            .map(|cached_response| Decoded::Entry(Arc::new(cached_response)))
+            .map_err(|error| error.to_string())
+    }
+}
+
+impl<CacheKeyT> Cache<CacheKeyT> for FileCacheImplementation
+where
+    CacheKeyT: CacheKey,
+{
+    async fn get(&self, key: &CacheKeyT) -> Option<CachedResponseRef> {
+        let name = self.file_name(key);
+
+        let bytes = match fs::read(self.directory.join(&name)).await {
+            Ok(bytes) => bytes,
+
+            Err(error) => {
+                if error.kind() != io::ErrorKind::NotFound {
+                    tracing::warn!("could not read cache file {}: {}", name, error);
+                }
+                return None;
+            }
+        };
+
+        match self.decode(key, &bytes) {
+            Ok(Decoded::Entry(cached_response)) => return Some(cached_response),
+
+            // Another key's entry, which is still valid
+            Ok(Decoded::Collision) => return None,
+
+            Ok(Decoded::Expired) => {}
+
+            Err(reason) => {
+                let key = key.display_for_logs(&self.key_log_policy);
+                tracing::warn!("invalid cache file {} for {}: {}", name, key, reason);
+            }
+        }
+
+        self.forget(&name).await;
+        None
+    }
+
+    async fn put(&self, key: CacheKeyT, cached_response: CachedResponseRef) {
+        let bytes = self.encode(&key, &cached_response);
+        let size = bytes.len() as u64;
+        let name = self.file_name(&key);
+
+        if size > self.max_size {
+            tracing::debug!(
+                "too large for cache: {} for {}",
+                size,
+                key.display_for_logs(&self.key_log_policy)
+            );
+            self.forget(&name).await;
+            return;
+        }
+
+        let path = self.directory.join(&name);
+        let temporary_path = self.directory.join(format!(
+            "{}.{}.{}",
+            name,
+            self.temporary_sequence.fetch_add(1, Ordering::Relaxed),
+            TEMPORARY_EXTENSION
+        ));
+
+        let written = match fs::write(&temporary_path, &bytes).await {
+            Ok(()) => fs::rename(&temporary_path, &path).await,
+            Err(error) => Err(error),
+        };
+
+        if let Err(error) = written {
+            tracing::warn!(
+                "could not write cache file {} for {}: {}",
+                name,
+                key.display_for_logs(&self.key_log_policy),
+                error
+            );
+            remove_file(&temporary_path).await;
+            self.forget(&name).await;
+            return;
+        }
+
+        let evicted = {
+            let mut state = self.state.lock().expect("lock");
+            state.remove(&name);
+            state.insert(name, size);
+            state.evict_until(self.max_size)
+        };
+
+        self.remove_files(evicted).await;
+    }
+
+    async fn invalidate(&self, key: &CacheKeyT) {
+        self.forget(&self.file_name(key)).await;
+    }
+
+    async fn invalidate_all(&self) {
+        if let Err(error) = self.clear().await {
+            tracing::error!("could not clear cache directory: {}", error);
+        }
+    }
+
+    async fn invalidate_all_with_outcome(&self) -> InvalidationOutcome {
+        match self.clear().await {
+            Ok(count) => InvalidationOutcome::new(Some(count)),
+            Err(error) => InvalidationOutcome::failed(error),
+        }
+    }
+
+    fn entry_count(&self) -> Option<u64> {
+        Some(self.len() as u64)
+    }
+
+    fn weighted_size(&self) -> Option<u64> {
+        Some(self.size())
+    }
+}
+
+impl fmt::Debug for FileCacheImplementation {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.state.lock().expect("lock");
+        formatter
+            .debug_struct("FileCacheImplementation")
+            .field("directory", &self.directory)
+            .field("max_size", &self.max_size)
+            .field("grace", &self.grace)
+            .field("files", &state.files.len())
+            .field("size", &state.size)
+            .finish()
+    }
+}
+
+enum Decoded {
+    Entry(CachedResponseRef),
+    Expired,
+    Collision,
+}
+
+#[derive(Default)]
+struct FileCacheState {
+    // File names to size and write tick
+    files: FastHashMap<String, (u64, u64)>,
+
+    // Write ticks to file names, oldest first
+    order: BTreeMap<u64, String>,
+
+    size: u64,
+    tick: u64,
+}
+
+impl FileCacheState {
+    fn insert(&mut self, name: String, size: u64) {
+        self.tick += 1;
+        self.order.insert(self.tick, name.clone());
+        self.size += size;
+        self.files.insert(name, (size, self.tick));
+    }
+
+    fn remove(&mut self, name: &str) {
+        if let Some((size, tick)) = self.files.remove(name) {
+            self.order.remove(&tick);
+            self.size -= size;
+        }
+    }
+
+    // Evict the oldest files until within the maximum size, returning their names.
+    fn evict_until(&mut self, max_size: u64) -> Vec<String> {
+        let mut evicted = Vec::default();
+        while self.size > max_size {
+            let Some((_, name)) = self.order.pop_first() else {
+                break;
+            };
+
+            if let Some((size, _)) = self.files.remove(&name) {
+                self.size -= size;
+            }
+
+            evicted.push(name);
+        }
+        evicted
+    }
+}
+
+// Remove a file, logging failures other than it not existing.
+async fn remove_file(path: &Path) {
+    if let Err(error) = fs::remove_file(path).await
+        && error.kind() != io::ErrorKind::NotFound
+    {
+        tracing::warn!("could not remove cache file {}: {}", path.display(), error);
+    }
+}
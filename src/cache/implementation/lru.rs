@@ -0,0 +1,303 @@
+use super::super::{cache::*, key::*, response::*, weight::*};
+
+use {
+    kutil::std::collections::*,
+    std::{collections::BTreeMap, fmt, sync::*, time::*},
+};
+
+//
+// SimpleLruCache
+//
+
+/// Simple in-memory LRU cache implementation.
+///
+/// A lightweight alternative to the Moka implementation, e.g. for small embedded services and for
+/// tests, in which it provides deterministic eviction. Cloning is cheap and clones refer to the
+/// same shared state.
+///
+/// Entries are weighed like in the Moka implementation (key plus [CachedResponse] weight). When
+/// the total weight exceeds the maximum, the least recently used entries are evicted. An entry
+/// that by itself weighs more than the maximum is not stored.
+///
+/// An entry expires after its [duration](CachedResponse::duration), counted from its
+/// [creation](CachedResponse::created), or after the default TTL (counted from when it was put)
+/// if it has no duration. Expired entries are retained for the [grace](Self::with_grace) period
+/// (so that they can be refreshed) and are removed lazily when gotten afterwards, or when evicted.
+///
+/// All operations take a single lock, so [update](Cache::update) is atomic.
+#[derive(Clone)]
+pub struct SimpleLruCache<CacheKeyT = CommonCacheKey> {
+    /// Maximum total weight.
+    pub max_weight: usize,
+
+    /// TTL for entries without a duration.
+    pub default_ttl: Option<Duration>,
+
+    /// Grace period.
+    pub grace: Duration,
+
+    /// How keys are rendered in logs.
+    pub key_log_policy: KeyLogPolicy,
+
+    state: Arc<Mutex<SimpleLruCacheState<CacheKeyT>>>,
+}
+
+impl<CacheKeyT> SimpleLruCache<CacheKeyT>
+where
+    CacheKeyT: CacheKey,
+{
+    /// Constructor.
+    pub fn new(max_weight: usize, default_ttl: Option<Duration>) -> Self {
+        Self {
+            max_weight,
+            default_ttl,
+            grace: Default::default(),
+            key_log_policy: Default::default(),
+            state: Arc::new(Mutex::new(SimpleLruCacheState {
+                entries: Default::default(),
+                order: Default::default(),
+                weight: 0,
+                tick: 0,
+            })),
+        }
+    }
+
+    /// Retain expired entries for a grace period.
+    ///
+    /// The default is zero.
+    pub fn with_grace(mut self, grace: Duration) -> Self {
+        self.grace = grace;
+        self
+    }
+
+    /// Set key log policy.
+    pub fn with_key_log_policy(mut self, key_log_policy: KeyLogPolicy) -> Self {
+        self.key_log_policy = key_log_policy;
+        self
+    }
+
+    /// Number of entries (including expired ones that have not been removed yet).
+    pub fn len(&self) -> usize {
+        self.state.lock().expect("lock").entries.len()
+    }
+
+    /// Whether there are no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Total weight of entries.
+    pub fn weight(&self) -> usize {
+        self.state.lock().expect("lock").weight
+    }
+
+    /// Keys from least to most recently used.
+    pub fn keys_by_recency(&self) -> Vec<CacheKeyT> {
+        self.state.lock().expect("lock").order.values().cloned().collect()
+    }
+
+    // Get an entry.
+    fn get_now(&self, key: &CacheKeyT) -> Option<CachedResponseRef> {
+        let mut state = self.state.lock().expect("lock");
+
+        let entry = state.entries.get(key)?;
+        if entry.deadline.is_some_and(|deadline| SystemTime::now() >= deadline) {
+            state.remove(key);
+            return None;
+        }
+
+        let cached_response = entry.cached_response.clone();
+        state.touch(key);
+        Some(cached_response)
+    }
+
+    // When an entry would be removed.
+    //
+    // Deadlines beyond what SystemTime can represent are never reached.
+    fn deadline(&self, cached_response: &CachedResponse) -> Option<SystemTime> {
+        let deadline = match cached_response.duration {
+            Some(duration) => cached_response.created.checked_add(duration),
+            None => SystemTime::now().checked_add(self.default_ttl?),
+        };
+        deadline?.checked_add(self.grace)
+    }
+}
+
+impl<CacheKeyT> Cache<CacheKeyT> for SimpleLruCache<CacheKeyT>
+where
+    CacheKeyT: CacheKey,
+{
+    async fn get(&self, key: &CacheKeyT) -> Option<CachedResponseRef> {
+        self.get_now(key)
+    }
+
+    fn get_if_present(&self, key: &CacheKeyT) -> Option<Option<CachedResponseRef>> {
+        // We never wait
+        Some(self.get_now(key))
+    }
+
+    async fn put(&self, key: CacheKeyT, cached_response: CachedResponseRef) {
+        let weight = key.cache_weight() + cached_response.cache_weight();
+        let deadline = self.deadline(&cached_response);
+
+        let mut state = self.state.lock().expect("lock");
+        state.remove(&key);
+
+        if weight > self.max_weight {
+            let key = key.display_for_logs(&self.key_log_policy);
+            tracing::debug!("too heavy for cache: {} for {}", weight, key);
+            return;
+        }
+
+        while state.weight + weight > self.max_weight {
+            if !state.evict() {
+                break;
+            }
+        }
+
+        state.insert(key, cached_response, weight, deadline);
+    }
+
+    async fn update<UpdateT>(&self, key: CacheKeyT, update: UpdateT) -> bool
+    where
+        UpdateT: FnOnce(CachedResponseRef) -> Option<CachedResponseRef> + Send,
+    {
+        let mut state = self.state.lock().expect("lock");
+
+        let Some(entry) = state.entries.get(&key) else {
+            return false;
+        };
+
+        let Some(cached_response) = update(entry.cached_response.clone()) else {
+            return false;
+        };
+
+        // In place: the deadline is kept
+        let deadline = entry.deadline;
+        let weight = key.cache_weight() + cached_response.cache_weight();
+        state.remove(&key);
+        if weight > self.max_weight {
+            return false;
+        }
+
+        while state.weight + weight > self.max_weight {
+            if !state.evict() {
+                break;
+            }
+        }
+
+        state.insert(key, cached_response, weight, deadline);
+        true
+    }
+
+    async fn invalidate(&self, key: &CacheKeyT) {
+        self.state.lock().expect("lock").remove(key);
+    }
+
+    async fn invalidate_all(&self) {
+        let mut state = self.state.lock().expect("lock");
+        state.entries.clear();
+        state.order.clear();
+        state.weight = 0;
+    }
+
+    fn entry_count(&self) -> Option<u64> {
+        Some(self.len() as u64)
+    }
+
+    fn weighted_size(&self) -> Option<u64> {
+        Some(self.weight() as u64)
+    }
+
+    fn keys(&self) -> Option<Vec<CacheKeyT>> {
+        Some(self.state.lock().expect("lock").entries.keys().cloned().collect())
+    }
+}
+
+impl<CacheKeyT> fmt::Debug for SimpleLruCache<CacheKeyT> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.state.lock().expect("lock");
+        formatter
+            .debug_struct("SimpleLruCache")
+            .field("max_weight", &self.max_weight)
+            .field("default_ttl", &self.default_ttl)
+            .field("grace", &self.grace)
+            .field("entries", &state.entries.len())
+            .field("weight", &state.weight)
+            .finish()
+    }
+}
+
+struct SimpleLruCacheState<CacheKeyT> {
+    entries: FastHashMap<CacheKeyT, SimpleLruCacheEntry>,
+
+    // Recency ticks to keys, least recent first
+    order: BTreeMap<u64, CacheKeyT>,
+
+    weight: usize,
+    tick: u64,
+}
+
+impl<CacheKeyT> SimpleLruCacheState<CacheKeyT>
+where
+    CacheKeyT: CacheKey,
+{
+    fn insert(
+        &mut self,
+        key: CacheKeyT,
+        cached_response: CachedResponseRef,
+        weight: usize,
+        deadline: Option<SystemTime>,
+    ) {
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.weight += weight;
+        self.entries.insert(
+            key,
+            SimpleLruCacheEntry {
+                cached_response,
+                weight,
+                deadline,
+                tick: self.tick,
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &CacheKeyT) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.tick);
+            self.weight -= entry.weight;
+        }
+    }
+
+    fn touch(&mut self, key: &CacheKeyT) {
+        self.tick += 1;
+        if let Some(entry) = self.entries.get_mut(key)
+            && let Some(key) = self.order.remove(&entry.tick)
+        {
+            entry.tick = self.tick;
+            self.order.insert(self.tick, key);
+        }
+    }
+
+    // Evict the least recently used entry; false if there are none.
+    fn evict(&mut self) -> bool {
+        match self.order.pop_first() {
+            Some((_, key)) => {
+                if let Some(entry) = self.entries.remove(&key) {
+                    self.weight -= entry.weight;
+                }
+                true
+            }
+
+            None => false,
+        }
+    }
+}
+
+struct SimpleLruCacheEntry {
+    cached_response: CachedResponseRef,
+    weight: usize,
+    deadline: Option<SystemTime>,
+    tick: u64,
+}
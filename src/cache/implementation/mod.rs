@@ -1,3 +1,13 @@
+/// Byte store cache adapter.
+pub mod byte_store;
+
+/// Disk-backed cache implementation.
+#[cfg(feature = "file")]
+pub mod file;
+
+/// Simple LRU cache implementation.
+pub mod lru;
+
 /// Moka cache implementation.
 #[cfg(feature = "moka")]
 pub mod moka;
@@ -4,17 +4,39 @@ use super::{
     weigher::*,
 };
 
+use std::time::*;
+
 //
 // ForHttpResponse
 //
 
 /// Add support for [CachedResponse] weigher and [Expiry](moka::Expiry).
+///
+/// This sets the builder's `weigher` and `expire_after`, so don't set those yourself. The weights
+/// are estimates of memory use in bytes, so `max_capacity` should be set accordingly. See
+/// [CachedResponseExpiry] for how `time_to_live` and `time_to_idle` interact with entry
+/// durations.
+///
+/// It also enables invalidation closures, which
+/// [invalidate_matching](super::super::super::Cache::invalidate_matching) uses.
 pub trait ForHttpResponse
 where
     Self: Sized,
 {
     /// Add support for [CachedResponse] weigher and [Expiry](moka::Expiry).
-    fn for_http_response(self) -> Self;
+    fn for_http_response(self) -> Self {
+        self.for_http_response_with(Default::default())
+    }
+
+    /// Add support for [CachedResponse] weigher and [Expiry](moka::Expiry).
+    ///
+    /// Expired entries will be retained for the grace period. See [CachedResponseExpiry::grace].
+    fn for_http_response_with_grace(self, grace: Duration) -> Self {
+        self.for_http_response_with(CachedResponseExpiry::new(grace))
+    }
+
+    /// Add support for [CachedResponse] weigher and a configured [CachedResponseExpiry].
+    fn for_http_response_with(self, expiry: CachedResponseExpiry) -> Self;
 }
 
 impl<CacheKeyT> ForHttpResponse
@@ -22,7 +44,7 @@ impl<CacheKeyT> ForHttpResponse
 where
     CacheKeyT: CacheKey,
 {
-    fn for_http_response(self) -> Self {
-        self.weigher(weigher).expire_after(CachedResponseExpiry)
+    fn for_http_response_with(self, expiry: CachedResponseExpiry) -> Self {
+        self.weigher(weigher).expire_after(expiry).support_invalidation_closures()
     }
 }
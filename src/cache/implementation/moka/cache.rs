@@ -1,6 +1,12 @@
-use super::super::super::{cache::*, key::*, response::*};
+#[cfg(feature = "housekeeping")]
+use super::housekeeping::*;
 
-use std::{ops::*, sync::*};
+use super::super::super::{cache::*, invalidation::*, key::*, response::*};
+
+use {
+    moka::ops::compute::*,
+    std::{fmt, future, ops::*, sync::*},
+};
 
 //
 // MokaCacheImplementation
@@ -8,29 +14,199 @@ use std::{ops::*, sync::*};
 
 /// Moka cache implementation.
 ///
-/// Note that it is based on the `sync` version of Moka cache rather than its `future` version.
+/// Wraps the `future` version of Moka cache. Cloning is cheap and clones refer to the same shared
+/// state.
+///
+/// The inner cache is accessible via [Deref], e.g. for [policy](moka::future::Cache::policy)
+/// inspection.
 ///
-/// The reason is that our `get` is not `async`, and unfortunately the `future` version of Moka
-/// does not have a non-`async` version of its `get`.
-pub type MokaCacheImplementation<CacheKeyT = CommonCacheKey> = Arc<moka::future::Cache<CacheKeyT, CachedResponseRef>>;
+/// [update](Cache::update) is atomic with respect to other updates of the same key.
+#[derive(Clone)]
+pub struct MokaCacheImplementation<CacheKeyT = CommonCacheKey> {
+    /// Inner cache.
+    pub inner: moka::future::Cache<CacheKeyT, CachedResponseRef>,
+
+    /// Background housekeeping driver.
+    #[cfg(feature = "housekeeping")]
+    pub housekeeper: Option<Housekeeper<CacheKeyT>>,
+}
+
+impl<CacheKeyT> MokaCacheImplementation<CacheKeyT>
+where
+    CacheKeyT: CacheKey,
+{
+    /// Constructor.
+    pub fn new(inner: moka::future::Cache<CacheKeyT, CachedResponseRef>) -> Self {
+        Self {
+            inner,
+            #[cfg(feature = "housekeeping")]
+            housekeeper: None,
+        }
+    }
+
+    /// Constructor with optional background housekeeping (see [Housekeeper]).
+    ///
+    /// Panics if housekeeping is enabled and this is not called from within a Tokio runtime.
+    #[cfg(feature = "housekeeping")]
+    pub fn new_with_housekeeping(
+        inner: moka::future::Cache<CacheKeyT, CachedResponseRef>,
+        housekeeping: Option<HousekeepingConfig>,
+    ) -> Self {
+        let housekeeper =
+            housekeeping.map(|configuration| Housekeeper::spawn(inner.clone(), configuration));
+        Self { inner, housekeeper }
+    }
+
+    /// Run pending housekeeping tasks, such as evictions.
+    ///
+    /// Moka performs these lazily, so calling this is useful after bulk invalidation or when you
+    /// need deterministic eviction. Also see `Housekeeper` (requires the `housekeeping` feature)
+    /// for running them in the background.
+    pub async fn housekeep(&self) {
+        self.inner.run_pending_tasks().await
+    }
+
+    /// Number of entries.
+    ///
+    /// Might be inaccurate until [housekeep](Self::housekeep) is called.
+    pub fn len(&self) -> u64 {
+        self.inner.entry_count()
+    }
+
+    /// Whether there are no entries.
+    ///
+    /// Might be inaccurate until [housekeep](Self::housekeep) is called.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Total weight of entries.
+    ///
+    /// Might be inaccurate until [housekeep](Self::housekeep) is called.
+    pub fn weight(&self) -> u64 {
+        self.inner.weighted_size()
+    }
+}
 
 impl<CacheKeyT> Cache<CacheKeyT> for MokaCacheImplementation<CacheKeyT>
 where
     CacheKeyT: CacheKey,
 {
     async fn get(&self, key: &CacheKeyT) -> Option<CachedResponseRef> {
-        self.deref().get(key).await
+        self.inner.get(key).await
+    }
+
+    fn get_if_present(&self, _key: &CacheKeyT) -> Option<Option<CachedResponseRef>> {
+        // Moka's future cache can only read asynchronously. (contains_key is synchronous, but
+        // answering misses with it would bypass the popularity estimator that admission relies
+        // on.)
+        None
     }
 
     async fn put(&self, key: CacheKeyT, cached_response: CachedResponseRef) {
-        self.deref().insert(key, cached_response).await
+        self.inner.insert(key, cached_response).await;
+
+        #[cfg(feature = "housekeeping")]
+        if let Some(housekeeper) = &self.housekeeper {
+            housekeeper.record_insert();
+        }
+    }
+
+    async fn update<UpdateT>(&self, key: CacheKeyT, update: UpdateT) -> bool
+    where
+        UpdateT: FnOnce(CachedResponseRef) -> Option<CachedResponseRef> + Send,
+    {
+        let result = self
+            .inner
+            .entry(key)
+            .and_compute_with(move |entry| {
+                future::ready(match entry.and_then(|entry| update(entry.into_value())) {
+                    Some(cached_response) => Op::Put(cached_response),
+                    None => Op::Nop,
+                })
+            })
+            .await;
+
+        matches!(result, CompResult::ReplacedWith(_))
     }
 
     async fn invalidate(&self, key: &CacheKeyT) {
-        self.deref().invalidate(key).await
+        self.inner.invalidate(key).await
     }
 
     async fn invalidate_all(&self) {
-        self.deref().invalidate_all()
+        self.inner.invalidate_all()
+    }
+
+    async fn invalidate_matching<PredicateT>(&self, predicate: PredicateT) -> InvalidationOutcome
+    where
+        PredicateT: 'static + Fn(&CacheKeyT) -> bool + Send + Sync,
+    {
+        let predicate = Arc::new(predicate);
+
+        // Moka applies it lazily, so the count is unknown
+        let closure_predicate = predicate.clone();
+        match self.inner.invalidate_entries_if(move |key, _| closure_predicate(key)) {
+            Ok(_) => InvalidationOutcome::default(),
+
+            // Invalidation closures are not enabled in the builder
+            Err(_) => {
+                let mut count = 0;
+                for (key, _) in self.inner.iter() {
+                    if predicate(&key) {
+                        self.inner.invalidate(&*key).await;
+                        count += 1;
+                    }
+                }
+                InvalidationOutcome::new(Some(count))
+            }
+        }
+    }
+
+    fn entry_count(&self) -> Option<u64> {
+        Some(self.len())
+    }
+
+    fn weighted_size(&self) -> Option<u64> {
+        Some(self.weight())
+    }
+
+    fn keys(&self) -> Option<Vec<CacheKeyT>> {
+        Some(self.inner.iter().map(|(key, _)| key.as_ref().clone()).collect())
+    }
+}
+
+impl<CacheKeyT> Deref for MokaCacheImplementation<CacheKeyT> {
+    type Target = moka::future::Cache<CacheKeyT, CachedResponseRef>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<CacheKeyT> fmt::Debug for MokaCacheImplementation<CacheKeyT>
+where
+    CacheKeyT: CacheKey,
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        let mut debug = formatter.debug_struct("MokaCacheImplementation");
+        debug
+            .field("entry_count", &self.inner.entry_count())
+            .field("weighted_size", &self.inner.weighted_size());
+
+        #[cfg(feature = "housekeeping")]
+        debug.field("housekeeper", &self.housekeeper);
+
+        debug.finish()
+    }
+}
+
+impl<CacheKeyT> From<moka::future::Cache<CacheKeyT, CachedResponseRef>>
+    for MokaCacheImplementation<CacheKeyT>
+where
+    CacheKeyT: CacheKey,
+{
+    fn from(inner: moka::future::Cache<CacheKeyT, CachedResponseRef>) -> Self {
+        Self::new(inner)
     }
 }
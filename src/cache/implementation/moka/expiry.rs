@@ -7,7 +7,66 @@ use {duration_str::*, moka::*, std::time::*};
 //
 
 /// Moka [Expiry] for [CachedResponse].
-pub struct CachedResponseExpiry;
+///
+/// An entry expires after its [duration](CachedResponse::duration) plus the grace period,
+/// counted from its [creation](CachedResponse::created). Entries without a duration never expire
+/// by this policy.
+///
+/// Replacing an entry restarts its expiry according to the new entry's duration and creation
+/// time. Thus refreshing an entry restarts it fully, while an in-place update (e.g. adding a
+/// representation via [Cache::update](super::super::super::cache::Cache::update)) keeps the
+/// remaining time.
+///
+/// Note that Moka's own `time_to_live` and `time_to_idle` are applied independently of this
+/// policy: whichever expires first wins. Use [idle](Self::idle) instead of Moka's `time_to_idle`
+/// if you want idle expiry to be bounded by the entry's duration.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CachedResponseExpiry {
+    /// Grace period.
+    ///
+    /// Expired entries are retained for this long beyond their duration so that they can be
+    /// refreshed via a conditional upstream request. They will not be served without such a
+    /// refresh.
+    pub grace: Duration,
+
+    /// Optional idle time.
+    ///
+    /// If set, an entry will also expire if it hasn't been read for this long. Reading it never
+    /// extends it beyond its duration plus the grace period.
+    pub idle: Option<Duration>,
+}
+
+impl CachedResponseExpiry {
+    /// Constructor.
+    pub fn new(grace: Duration) -> Self {
+        Self { grace, idle: None }
+    }
+
+    /// Set idle time.
+    pub fn with_idle(mut self, idle: Duration) -> Self {
+        self.idle = Some(idle);
+        self
+    }
+
+    // Remaining time until the entry's duration plus the grace period.
+    fn remaining(&self, cached_response: &CachedResponseRef) -> Option<Duration> {
+        cached_response.duration.map(|duration| {
+            let elapsed = SystemTime::now()
+                .duration_since(cached_response.created)
+                .unwrap_or_default();
+            duration.saturating_add(self.grace).saturating_sub(elapsed)
+        })
+    }
+
+    // Bound by idle time.
+    fn with_idle_bound(&self, remaining: Option<Duration>) -> Option<Duration> {
+        match (remaining, self.idle) {
+            (Some(remaining), Some(idle)) => Some(remaining.min(idle)),
+            (None, Some(idle)) => Some(idle),
+            (remaining, None) => remaining,
+        }
+    }
+}
 
 impl<CacheKeyT> Expiry<CacheKeyT, CachedResponseRef> for CachedResponseExpiry
 where
@@ -23,6 +82,32 @@ where
             tracing::debug!("storing with duration: {}", duration.human_format());
         }
 
-        cached_response.duration
+        // Relative to the entry's creation time, which is earlier for entries loaded from
+        // another tier
+        self.with_idle_bound(self.remaining(cached_response))
+    }
+
+    fn expire_after_read(
+        &self,
+        _cache_key: &CacheKeyT,
+        cached_response: &CachedResponseRef,
+        _read_at: Instant,
+        duration_until_expiry: Option<Duration>,
+        _last_modified_at: Instant,
+    ) -> Option<Duration> {
+        match self.idle {
+            Some(_) => self.with_idle_bound(self.remaining(cached_response)),
+            None => duration_until_expiry,
+        }
+    }
+
+    fn expire_after_update(
+        &self,
+        _cache_key: &CacheKeyT,
+        cached_response: &CachedResponseRef,
+        _updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        self.with_idle_bound(self.remaining(cached_response))
     }
 }
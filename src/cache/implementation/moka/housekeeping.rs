@@ -0,0 +1,237 @@
+use super::super::super::{key::*, response::*};
+
+use {
+    std::{
+        fmt,
+        sync::{atomic::*, *},
+        time::*,
+    },
+    tokio::{
+        sync::Notify,
+        task::{JoinHandle, spawn},
+        time::timeout,
+    },
+};
+
+//
+// HousekeepingConfig
+//
+
+/// Configuration for [Housekeeper].
+#[derive(Clone, Copy, Debug)]
+pub struct HousekeepingConfig {
+    /// Interval between runs.
+    pub interval: Duration,
+
+    /// Also run immediately after this many inserts since the last run.
+    ///
+    /// [None] to run only at intervals.
+    pub insert_threshold: Option<u64>,
+}
+
+impl Default for HousekeepingConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            insert_threshold: Some(10_000),
+        }
+    }
+}
+
+//
+// Housekeeper
+//
+
+/// Background driver for Moka housekeeping.
+///
+/// Moka performs its maintenance (applying pending writes, evictions, calling the eviction
+/// listener) lazily, within whichever `get` or `insert` happens to trigger it, which shows up as
+/// latency spikes for unlucky requests. The housekeeper runs it periodically in a Tokio task
+/// instead, so that it happens off the request path.
+///
+/// Runs never overlap. Their execution times are recorded in [HousekeepingStats] so that the
+/// maintenance cost is visible.
+///
+/// The task ends when [shutdown](Self::shutdown) is called or when the cache is dropped.
+///
+/// Cloning is cheap and clones share state.
+pub struct Housekeeper<CacheKeyT = CommonCacheKey> {
+    state: Arc<HousekeeperState<CacheKeyT>>,
+}
+
+impl<CacheKeyT> Housekeeper<CacheKeyT>
+where
+    CacheKeyT: CacheKey,
+{
+    /// Constructor.
+    ///
+    /// Spawns the task. Panics if not called from within a Tokio runtime.
+    pub fn spawn(
+        cache: moka::future::Cache<CacheKeyT, CachedResponseRef>,
+        configuration: HousekeepingConfig,
+    ) -> Self {
+        let signal = Arc::new(HousekeeperSignal::default());
+
+        let state = Arc::new(HousekeeperState {
+            cache,
+            insert_threshold: configuration.insert_threshold,
+            inserts: Default::default(),
+            signal: signal.clone(),
+            task: Default::default(),
+            runs: Default::default(),
+            triggered_runs: Default::default(),
+            last_nanos: Default::default(),
+            max_nanos: Default::default(),
+            total_nanos: Default::default(),
+        });
+
+        let task = spawn(drive(Arc::downgrade(&state), signal, configuration.interval));
+        *state.task.lock().expect("lock") = Some(task);
+
+        Self { state }
+    }
+
+    /// Count an insert.
+    ///
+    /// Wakes the task if the insert threshold is reached.
+    pub fn record_insert(&self) {
+        let inserts = self.state.inserts.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.state.insert_threshold == Some(inserts) {
+            self.state.signal.notify.notify_one();
+        }
+    }
+
+    /// Statistics.
+    pub fn stats(&self) -> HousekeepingStats {
+        HousekeepingStats {
+            runs: self.state.runs.load(Ordering::Relaxed),
+            triggered_runs: self.state.triggered_runs.load(Ordering::Relaxed),
+            last: Duration::from_nanos(self.state.last_nanos.load(Ordering::Relaxed)),
+            max: Duration::from_nanos(self.state.max_nanos.load(Ordering::Relaxed)),
+            total: Duration::from_nanos(self.state.total_nanos.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Stop the task and wait for it to end.
+    ///
+    /// If a run is in progress it will be completed first.
+    pub async fn shutdown(&self) {
+        self.state.signal.shutdown.store(true, Ordering::Release);
+        self.state.signal.notify.notify_one();
+
+        let task = self.state.task.lock().expect("lock").take();
+        if let Some(task) = task {
+            _ = task.await;
+        }
+    }
+}
+
+impl<CacheKeyT> Clone for Housekeeper<CacheKeyT> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<CacheKeyT> fmt::Debug for Housekeeper<CacheKeyT> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("Housekeeper")
+            .field("insert_threshold", &self.state.insert_threshold)
+            .field("runs", &self.state.runs.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+//
+// HousekeepingStats
+//
+
+/// [Housekeeper] statistics.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HousekeepingStats {
+    /// Runs.
+    pub runs: u64,
+
+    /// Runs triggered by the insert threshold (rather than the interval).
+    pub triggered_runs: u64,
+
+    /// Execution time of the last run.
+    pub last: Duration,
+
+    /// Maximum execution time of a run.
+    pub max: Duration,
+
+    /// Total execution time of all runs.
+    pub total: Duration,
+}
+
+struct HousekeeperState<CacheKeyT> {
+    cache: moka::future::Cache<CacheKeyT, CachedResponseRef>,
+    insert_threshold: Option<u64>,
+    inserts: AtomicU64,
+    signal: Arc<HousekeeperSignal>,
+    task: Mutex<Option<JoinHandle<()>>>,
+    runs: AtomicU64,
+    triggered_runs: AtomicU64,
+    last_nanos: AtomicU64,
+    max_nanos: AtomicU64,
+    total_nanos: AtomicU64,
+}
+
+impl<CacheKeyT> HousekeeperState<CacheKeyT>
+where
+    CacheKeyT: CacheKey,
+{
+    async fn run(&self, triggered: bool) {
+        self.inserts.store(0, Ordering::Relaxed);
+
+        let start = Instant::now();
+        self.cache.run_pending_tasks().await;
+        let elapsed = start.elapsed();
+
+        let nanos = elapsed.as_nanos() as u64;
+        self.runs.fetch_add(1, Ordering::Relaxed);
+        if triggered {
+            self.triggered_runs.fetch_add(1, Ordering::Relaxed);
+        }
+        self.last_nanos.store(nanos, Ordering::Relaxed);
+        self.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+        self.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+
+        tracing::debug!("housekeeping took {:?}", elapsed);
+    }
+}
+
+// Kept apart from the state so that waiting for it does not keep the cache alive.
+#[derive(Default)]
+struct HousekeeperSignal {
+    notify: Notify,
+    shutdown: AtomicBool,
+}
+
+async fn drive<CacheKeyT>(
+    state: Weak<HousekeeperState<CacheKeyT>>,
+    signal: Arc<HousekeeperSignal>,
+    interval: Duration,
+) where
+    CacheKeyT: CacheKey,
+{
+    loop {
+        // Notify keeps a permit, so a wake that happens during a run is not lost
+        let triggered = timeout(interval, signal.notify.notified()).await.is_ok();
+
+        if signal.shutdown.load(Ordering::Acquire) {
+            break;
+        }
+
+        let Some(state) = state.upgrade() else {
+            break;
+        };
+
+        state.run(triggered).await;
+    }
+
+    tracing::debug!("housekeeping stopped");
+}
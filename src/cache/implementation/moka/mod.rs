@@ -1,7 +1,13 @@
 mod builder;
 mod cache;
 mod expiry;
+#[cfg(feature = "housekeeping")]
+mod housekeeping;
 mod weigher;
 
 #[allow(unused_imports)]
 pub use {builder::*, cache::*, expiry::*, weigher::*};
+
+#[cfg(feature = "housekeeping")]
+#[allow(unused_imports)]
+pub use housekeeping::*;
@@ -10,3 +10,21 @@ where
     tracing::debug!("{} for {}", weight, cache_key);
     weight
 }
+
+/// Moka cache entry weigher with a [KeyLogPolicy] for its debug logs.
+///
+/// Set it after [for_http_response](super::ForHttpResponse::for_http_response) to replace the
+/// default [weigher].
+pub fn weigher_with<CacheKeyT>(
+    key_log_policy: KeyLogPolicy,
+) -> impl Fn(&CacheKeyT, &CachedResponseRef) -> u32 + Send + Sync + 'static
+where
+    CacheKeyT: CacheKey,
+{
+    move |cache_key, cached_response| {
+        let weight = cache_key.cache_weight() + cached_response.cache_weight();
+        let weight = weight.try_into().unwrap_or(u32::MAX);
+        tracing::debug!("{} for {}", weight, cache_key.display_for_logs(&key_log_policy));
+        weight
+    }
+}
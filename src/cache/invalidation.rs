@@ -0,0 +1,97 @@
+use std::fmt;
+
+//
+// InvalidationOutcome
+//
+
+/// Outcome of [invalidate_all_with_outcome](super::cache::Cache::invalidate_all_with_outcome).
+#[derive(Clone, Debug, Default)]
+pub struct InvalidationOutcome {
+    /// Number of entries before invalidation, if known.
+    ///
+    /// For tiers, the largest of their counts (entries are usually in more than one tier).
+    pub count: Option<u64>,
+
+    /// Error, if failed.
+    pub error: Option<String>,
+
+    /// Outcomes of the tiers (empty if not tiered).
+    pub tiers: Vec<(String, InvalidationOutcome)>,
+}
+
+impl InvalidationOutcome {
+    /// Constructor for success.
+    pub fn new(count: Option<u64>) -> Self {
+        Self {
+            count,
+            ..Default::default()
+        }
+    }
+
+    /// Constructor for failure.
+    pub fn failed(error: impl ToString) -> Self {
+        Self {
+            error: Some(error.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Constructor for tiers.
+    pub fn tiered(tiers: Vec<(String, InvalidationOutcome)>) -> Self {
+        Self {
+            count: tiers.iter().filter_map(|(_, outcome)| outcome.count).max(),
+            error: None,
+            tiers,
+        }
+    }
+
+    /// Whether this and all tiers succeeded.
+    pub fn is_success(&self) -> bool {
+        self.error.is_none() && self.tiers.iter().all(|(_, outcome)| outcome.is_success())
+    }
+
+    /// Whether some tiers succeeded and some failed.
+    pub fn is_partial(&self) -> bool {
+        !self.is_success()
+            && self.error.is_none()
+            && self.tiers.iter().any(|(_, outcome)| outcome.error.is_none())
+    }
+
+    /// Errors, with the paths of the tiers in which they occurred (e.g. "first.next").
+    pub fn errors(&self) -> Vec<(String, String)> {
+        let mut errors = Vec::default();
+        if let Some(error) = &self.error {
+            errors.push((Default::default(), error.clone()));
+        }
+        for (tier, outcome) in &self.tiers {
+            for (inner_tier, error) in outcome.errors() {
+                let path = if inner_tier.is_empty() {
+                    tier.clone()
+                } else {
+                    format!("{}.{}", tier, inner_tier)
+                };
+                errors.push((path, error));
+            }
+        }
+        errors
+    }
+}
+
+impl fmt::Display for InvalidationOutcome {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self.count {
+            Some(count) => write!(formatter, "{} entries", count)?,
+            None => write!(formatter, "unknown entries")?,
+        }
+
+        for (tier, error) in self.errors() {
+            if tier.is_empty() {
+                write!(formatter, ", failed: {}", error)?;
+            } else {
+                write!(formatter, ", {} failed: {}", tier, error)?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+use std::{cell::*, time::*};
+
+//
+// JitterConfig
+//
+
+/// Cache duration jitter configuration.
+///
+/// Entries stored in a burst (e.g. after a deploy or a cache flush) would otherwise all expire at
+/// the same time and cause a stampede on the upstream. Jitter adds a uniformly random offset of
+/// up to ±`fraction` of the duration, capped at `max`, once, when an entry's duration is
+/// resolved (when it's stored and when it's refreshed).
+///
+/// Durations shorter than `min_duration` are not jittered, and jittered durations are never
+/// shorter than it, so that micro-cached entries keep their exact durations.
+///
+/// The random numbers come from a cheap per-thread generator that is seeded from the
+/// [clock](super::CachingConfiguration::clock) on first use, so that a fixed test clock gives
+/// deterministic results.
+#[derive(Clone, Copy, Debug)]
+pub struct JitterConfig {
+    /// Maximum offset as a fraction of the duration (0.0 to 1.0).
+    pub fraction: f64,
+
+    /// Maximum offset.
+    pub max: Duration,
+
+    /// Minimum duration to jitter.
+    pub min_duration: Duration,
+}
+
+impl JitterConfig {
+    /// Constructor.
+    pub fn new(fraction: f64, max: Duration) -> Self {
+        Self {
+            fraction: fraction.clamp(0.0, 1.0),
+            max,
+            ..Default::default()
+        }
+    }
+
+    /// Set minimum duration to jitter.
+    pub fn min_duration(mut self, min_duration: Duration) -> Self {
+        self.min_duration = min_duration;
+        self
+    }
+
+    /// Jittered duration.
+    ///
+    /// `now` is used to seed the generator on first use in this thread.
+    pub fn jitter(&self, duration: Duration, now: SystemTime) -> Duration {
+        if duration < self.min_duration {
+            return duration;
+        }
+
+        let spread = duration.mul_f64(self.fraction).min(self.max);
+        if spread.is_zero() {
+            return duration;
+        }
+
+        // Uniform in [-1.0, 1.0)
+        let random = next_random(now) * 2.0 - 1.0;
+        let offset = spread.mul_f64(random.abs());
+
+        let jittered = if random < 0.0 {
+            duration.saturating_sub(offset)
+        } else {
+            duration.saturating_add(offset)
+        };
+
+        jittered.max(self.min_duration)
+    }
+}
+
+impl Default for JitterConfig {
+    fn default() -> Self {
+        Self {
+            fraction: 0.1,
+            max: Duration::from_secs(60 * 5),
+            min_duration: Duration::from_secs(1),
+        }
+    }
+}
+
+thread_local! {
+    // xorshift64* state (0 means not seeded)
+    static JITTER_STATE: Cell<u64> = const { Cell::new(0) };
+}
+
+// Uniform in [0.0, 1.0).
+fn next_random(now: SystemTime) -> f64 {
+    JITTER_STATE.with(|state| {
+        let mut x = state.get();
+
+        if x == 0 {
+            // splitmix64 of the clock
+            let nanos = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+            let mut z = nanos.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            x = (z ^ (z >> 31)) | 1;
+        }
+
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+
+        // Top 53 bits
+        (x.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 53) as f64
+    })
+}
@@ -0,0 +1,31 @@
+use std::{future, task::*};
+
+/// Poll futures concurrently (within the current task) until all are ready.
+pub async fn join_all<FutureT>(futures: Vec<FutureT>) -> Vec<FutureT::Output>
+where
+    FutureT: Future,
+{
+    let mut futures: Vec<_> = futures.into_iter().map(|future| Some(Box::pin(future))).collect();
+    let mut outputs: Vec<_> = futures.iter().map(|_| None).collect();
+
+    future::poll_fn(|context| {
+        let mut pending = false;
+        for (future, output) in futures.iter_mut().zip(outputs.iter_mut()) {
+            if let Some(inner) = future {
+                match inner.as_mut().poll(context) {
+                    Poll::Ready(value) => {
+                        *output = Some(value);
+                        *future = None;
+                    }
+
+                    Poll::Pending => pending = true,
+                }
+            }
+        }
+
+        if pending { Poll::Pending } else { Poll::Ready(()) }
+    })
+    .await;
+
+    outputs.into_iter().flatten().collect()
+}
@@ -0,0 +1,226 @@
+use super::common::*;
+
+use std::hash::*;
+
+/// Version of the canonical key form.
+///
+/// It covers both the byte layout of [CanonicalKeyForm] and the [stable_hash] algorithm, and is
+/// the first byte of every [canonical_bytes](CanonicalKeyForm::canonical_bytes). Any change to
+/// either must increment it.
+pub const CANONICAL_KEY_FORM_VERSION: u8 = 1;
+
+//
+// CanonicalKeyForm
+//
+
+/// Canonical serialized form of a cache key.
+///
+/// Caches shared by several processes (e.g. replicas with a common remote tier) rely on every
+/// process producing the same key for the same request. The canonical form makes that
+/// checkable: it must be identical on all platforms, architectures, and processes, and equal
+/// keys must have equal forms. Thus it must never depend on hash map iteration order, random
+/// seeds, pointer values, or locale- and platform-specific formatting. Collections must be
+/// written in a defined (e.g. sorted) order, and numbers in big-endian.
+///
+/// Key types used with caches that serialize keys must implement it. To compare the keys
+/// produced on different platforms, see the `agreement` module (`test-util` feature).
+///
+/// # Key evolution
+///
+/// Fields are tagged, and absent (or default) fields are not written. Thus a new optional field
+/// gets a new tag, and keys that don't use it keep their form, so deployments that upgrade
+/// without using the new field keep sharing entries with those that haven't upgraded. Tags are
+/// never reused or reordered.
+///
+/// Any other change (to the layout, to an existing field's encoding, or to [stable_hash]) is
+/// breaking: it must increment [CANONICAL_KEY_FORM_VERSION], update the pinned fixtures in the
+/// `agreement` module, and come with a migration note. Deployments can then use a
+/// [KeyMigratingCache](super::super::KeyMigratingCache) to fall back to entries stored under the
+/// old form while they transition. The same applies when a deployment starts using a new field.
+pub trait CanonicalKeyForm {
+    /// Write the canonical form, without the version.
+    fn write_canonical(&self, bytes: &mut Vec<u8>);
+
+    /// The canonical form, prefixed with [CANONICAL_KEY_FORM_VERSION].
+    fn canonical_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![CANONICAL_KEY_FORM_VERSION];
+        self.write_canonical(&mut bytes);
+        bytes
+    }
+
+    /// [stable_hash] of the [canonical_bytes](Self::canonical_bytes).
+    fn canonical_digest(&self) -> u64 {
+        stable_hash(&self.canonical_bytes())
+    }
+}
+
+/// Stable 64-bit hash: FNV-1a with the standard offset basis and prime.
+///
+/// Use it wherever keys or key components are hashed into something that is shared between
+/// processes. It is not resistant to collision attacks, so don't use it for in-process tables
+/// that are exposed to untrusted input.
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x00000100000001b3;
+
+    let mut hash = OFFSET_BASIS;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(PRIME);
+    }
+    hash
+}
+
+/// Keyed 64-bit hash: SipHash-2-4.
+///
+/// Unlike [stable_hash], it can't be computed (or reversed by trying candidates) without the
+/// key, so use it for hashing secrets, e.g. credentials, into keys. It is stable for the same key,
+/// so processes that share a cache must share the key.
+pub fn keyed_hash(key: &[u8; 16], bytes: &[u8]) -> u64 {
+    fn round(v: &mut [u64; 4]) {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+
+    let k0 = u64::from_le_bytes(key[..8].try_into().expect("8 bytes"));
+    let k1 = u64::from_le_bytes(key[8..].try_into().expect("8 bytes"));
+    let mut v = [
+        k0 ^ 0x736f6d6570736575,
+        k1 ^ 0x646f72616e646f6d,
+        k0 ^ 0x6c7967656e657261,
+        k1 ^ 0x7465646279746573,
+    ];
+
+    let mut compress = |word: u64| {
+        v[3] ^= word;
+        round(&mut v);
+        round(&mut v);
+        v[0] ^= word;
+    };
+
+    let chunks = bytes.chunks_exact(8);
+    let remainder = chunks.remainder();
+    for chunk in chunks {
+        compress(u64::from_le_bytes(chunk.try_into().expect("8 bytes")));
+    }
+
+    // The last word has the length in its top byte
+    let mut last = [0; 8];
+    last[..remainder.len()].copy_from_slice(remainder);
+    last[7] = bytes.len() as u8;
+    compress(u64::from_le_bytes(last));
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+/// Random key for [keyed_hash], different in every call.
+pub fn random_hash_key() -> [u8; 16] {
+    let mut key = [0; 16];
+    for half in key.chunks_exact_mut(8) {
+        // Every RandomState has new random keys
+        half.copy_from_slice(&RandomState::new().hash_one(0u8).to_le_bytes());
+    }
+    key
+}
+
+/// Write a length-prefixed field: the tag, the length as a big-endian `u32`, and the value.
+///
+/// Fields must be written in the order of their tags. Absent fields are not written, so adding a
+/// new optional field does not change the form of keys without it.
+pub fn write_canonical_field(bytes: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    bytes.push(tag);
+    bytes.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(value);
+}
+
+impl CanonicalKeyForm for CommonCacheKey {
+    /// Tags:
+    ///
+    /// 1. method
+    /// 2. path
+    /// 3. query: a field per value, each a length-prefixed name and value, sorted by name and
+    ///    then value
+    /// 4. scheme
+    /// 5. host
+    /// 6. port (big-endian `u16`)
+    /// 7. media type
+    /// 8. languages: a field per language, sorted
+    /// 9. extensions: a field per entry, each a length-prefixed key and value, sorted by key
+    /// 10. generation (big-endian `u64`)
+    /// 11. partition
+    fn write_canonical(&self, bytes: &mut Vec<u8>) {
+        write_canonical_field(bytes, 1, self.method.as_str().as_bytes());
+
+        if let Some(path) = &self.path {
+            write_canonical_field(bytes, 2, AsRef::<str>::as_ref(path).as_bytes());
+        }
+
+        // Sorted because they are BTreeMaps and BTreeSets
+        if let Some(query) = &self.query {
+            for (name, values) in query {
+                for value in values {
+                    let mut pair = Vec::default();
+                    write_length_prefixed(&mut pair, AsRef::<str>::as_ref(name).as_bytes());
+                    write_length_prefixed(&mut pair, AsRef::<str>::as_ref(value).as_bytes());
+                    write_canonical_field(bytes, 3, &pair);
+                }
+            }
+        }
+
+        if let Some(scheme) = &self.scheme {
+            write_canonical_field(bytes, 4, scheme.as_str().as_bytes());
+        }
+
+        if let Some(host) = &self.host {
+            write_canonical_field(bytes, 5, AsRef::<str>::as_ref(host).as_bytes());
+        }
+
+        if let Some(port) = self.port {
+            write_canonical_field(bytes, 6, &port.to_be_bytes());
+        }
+
+        if let Some(media_type) = &self.media_type {
+            write_canonical_field(bytes, 7, media_type.to_string().as_bytes());
+        }
+
+        if let Some(languages) = &self.languages {
+            for language in languages {
+                write_canonical_field(bytes, 8, language.to_string().as_bytes());
+            }
+        }
+
+        if let Some(extensions) = &self.extensions {
+            for (key, value) in extensions {
+                let mut entry = Vec::default();
+                write_length_prefixed(&mut entry, key);
+                write_length_prefixed(&mut entry, value);
+                write_canonical_field(bytes, 9, &entry);
+            }
+        }
+
+        if let Some(generation) = self.generation {
+            write_canonical_field(bytes, 10, &generation.to_be_bytes());
+        }
+
+        if let Some(partition) = &self.partition {
+            write_canonical_field(bytes, 11, AsRef::<str>::as_ref(partition).as_bytes());
+        }
+    }
+}
+
+fn write_length_prefixed(bytes: &mut Vec<u8>, value: &[u8]) {
+    bytes.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(value);
+}
@@ -1,4 +1,4 @@
-use super::{super::weight::*, key::*};
+use super::{super::weight::*, key::*, logging::*};
 
 use {
     http::{header::*, uri::*, *},
@@ -24,17 +24,20 @@ pub struct CommonCacheKey {
 
     /// Optional scheme.
     ///
-    /// Not set by default but reserved for custom use.
+    /// Not set by default but reserved for custom use. Will be set if host partitioning is
+    /// enabled.
     pub scheme: Option<Scheme>,
 
     /// Optional host.
     ///
-    /// Not set by default but reserved for custom use.
+    /// Not set by default but reserved for custom use. Will be set if host partitioning is
+    /// enabled.
     pub host: Option<ImmutableString>,
 
     /// Optional port.
     ///
-    /// Not set by default but reserved for custom use.
+    /// Not set by default but reserved for custom use. Will be set if host partitioning is
+    /// enabled.
     pub port: Option<u16>,
 
     /// Optional media type.
@@ -44,13 +47,26 @@ pub struct CommonCacheKey {
 
     /// Optional languages (sorted).
     ///
-    /// Not set by default but reserved for custom use.
+    /// Not set by default but reserved for custom use. Will be set to the negotiated language if
+    /// [LanguageNegotiation](super::super::middleware::LanguageNegotiation) is configured.
     pub languages: Option<BTreeSet<Language>>,
 
     /// Optional extensions (sorted by key).
     ///
     /// Not set by default but reserved for custom use.
     pub extensions: Option<BTreeMap<ImmutableBytes, ImmutableBytes>>,
+
+    /// Optional generation.
+    ///
+    /// Will be set if [CacheGenerations](super::super::middleware::CacheGenerations) is
+    /// configured.
+    pub generation: Option<u64>,
+
+    /// Optional partition.
+    ///
+    /// Will be set if [PartitionPolicy](super::super::middleware::PartitionPolicy) is configured
+    /// and the path matches.
+    pub partition: Option<ImmutableString>,
 }
 
 impl CommonCacheKey {
@@ -76,8 +92,20 @@ impl CommonCacheKey {
             media_type,
             languages,
             extensions,
+            generation: None,
+            partition: None,
         }
     }
+
+    /// Whether the key has an extension.
+    ///
+    /// With [invalidate_matching](super::super::Cache::invalidate_matching) this invalidates all
+    /// entries tagged with an extension, e.g. `product:123`.
+    pub fn has_extension(&self, extension: &[u8]) -> bool {
+        self.extensions
+            .as_ref()
+            .is_some_and(|extensions| extensions.keys().any(|key| **key == *extension))
+    }
 }
 
 impl CacheKey for CommonCacheKey {
@@ -90,7 +118,8 @@ impl CacheKey for CommonCacheKey {
                     path_and_query.decoded_query_map(),
                 )
             })
-            .unwrap_or_default();
+            // Never an empty path, e.g. for an authority-form target
+            .unwrap_or_else(|| (Some("/".into()), None));
 
         Self::new(
             method.clone(),
@@ -104,6 +133,78 @@ impl CacheKey for CommonCacheKey {
             None,
         )
     }
+
+    fn set_negotiated_language(&mut self, language: Language) {
+        self.languages = Some([language].into());
+    }
+
+    fn set_query_parameter(&mut self, name: &str, value: Option<&str>) {
+        match value {
+            Some(value) => {
+                self.query
+                    .get_or_insert_default()
+                    .insert(name.into(), [value.into()].into());
+            }
+
+            None => {
+                if let Some(query) = &mut self.query {
+                    query.remove(name);
+                    if query.is_empty() {
+                        self.query = None;
+                    }
+                }
+            }
+        }
+    }
+
+    fn set_origin(&mut self, scheme: Option<&Scheme>, host: Option<&str>, port: Option<u16>) {
+        self.scheme = scheme.cloned();
+        self.host = host.map(|host| host.into());
+        self.port = port;
+    }
+
+    fn set_generation(&mut self, generation: u64) {
+        self.generation = Some(generation);
+    }
+
+    fn set_partition(&mut self, partition: &str) {
+        self.partition = Some(partition.into());
+    }
+
+    fn set_varied_header(&mut self, name: &HeaderName, value: Option<&HeaderValue>) {
+        // A missing header is distinct from an empty one
+        let value = match value {
+            Some(value) => [b"=", value.as_bytes()].concat(),
+            None => Vec::default(),
+        };
+
+        self.extensions
+            .get_or_insert_default()
+            .insert(format!("vary:{}", name).into_bytes().into(), value.into());
+    }
+
+    fn set_header_digest(&mut self, name: &HeaderName, digest: Option<u64>) {
+        // A missing header is distinct from any value
+        let digest = match digest {
+            Some(digest) => digest.to_be_bytes().to_vec(),
+            None => Vec::default(),
+        };
+
+        self.extensions
+            .get_or_insert_default()
+            .insert(format!("header:{}", name).into_bytes().into(), digest.into());
+    }
+
+    fn path(&self) -> Option<&str> {
+        self.path.as_ref().map(AsRef::<str>::as_ref)
+    }
+
+    fn display_for_logs<'this>(
+        &'this self,
+        policy: &'this KeyLogPolicy,
+    ) -> impl fmt::Display + 'this {
+        policy.capped(CommonCacheKeyForLogs { key: self, policy })
+    }
 }
 
 impl CacheWeight for CommonCacheKey {
@@ -142,12 +243,21 @@ impl CacheWeight for CommonCacheKey {
             }
         }
 
+        if let Some(partition) = &self.partition {
+            size += partition.len();
+        }
+
         size
     }
 }
 
-impl fmt::Display for CommonCacheKey {
-    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+impl CommonCacheKey {
+    // Write with redaction and truncation.
+    fn write_for_logs(
+        &self,
+        formatter: &mut fmt::Formatter,
+        policy: &KeyLogPolicy,
+    ) -> fmt::Result {
         let scheme = self
             .scheme
             .as_ref()
@@ -156,13 +266,13 @@ impl fmt::Display for CommonCacheKey {
         let host = self
             .host
             .as_ref()
-            .map(|host| AsRef::<str>::as_ref(host))
+            .map(AsRef::<str>::as_ref)
             .unwrap_or_default();
         let port = self.port.map(|port| port.to_string()).unwrap_or_default();
         let path = self
             .path
             .as_ref()
-            .map(|path| AsRef::<str>::as_ref(path))
+            .map(AsRef::<str>::as_ref)
             .unwrap_or_default();
 
         let query = self
@@ -171,11 +281,16 @@ impl fmt::Display for CommonCacheKey {
             .map(|parameter| {
                 let mut string = String::default();
                 for (key, values) in parameter {
+                    let redacted = policy.redacts(key);
                     for value in values {
                         if !string.is_empty() {
                             string += "&"
                         }
-                        string += &format!("{}={}", key, value);
+                        if redacted {
+                            string += &format!("{}={}", key, REDACTED);
+                        } else {
+                            string += &format!("{}={}", key, value);
+                        }
                     }
                 }
                 string
@@ -219,7 +334,52 @@ impl fmt::Display for CommonCacheKey {
         write!(
             formatter,
             "{}|{}|{}|{}|{}|{}|{}|{}|{}",
-            self.method, scheme, host, port, path, query, media_type, languages, extensions
-        )
+            self.method,
+            scheme,
+            policy.truncate_component(host),
+            port,
+            policy.truncate_component(path),
+            policy.truncate_component(&query),
+            policy.truncate_component(&media_type),
+            policy.truncate_component(&languages),
+            policy.truncate_component(&extensions)
+        )?;
+
+        if let Some(generation) = self.generation {
+            write!(formatter, "|{}", generation)?;
+        }
+
+        if let Some(partition) = &self.partition {
+            write!(
+                formatter,
+                "|partition={}",
+                policy.truncate_component(AsRef::<str>::as_ref(partition))
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for CommonCacheKey {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        self.write_for_logs(formatter, &KeyLogPolicy::default())
+    }
+}
+
+//
+// CommonCacheKeyForLogs
+//
+
+/// [CommonCacheKey] rendered according to a [KeyLogPolicy].
+#[derive(Clone, Debug)]
+pub struct CommonCacheKeyForLogs<'this> {
+    key: &'this CommonCacheKey,
+    policy: &'this KeyLogPolicy,
+}
+
+impl<'this> fmt::Display for CommonCacheKeyForLogs<'this> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        self.key.write_for_logs(formatter, self.policy)
     }
 }
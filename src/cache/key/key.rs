@@ -1,7 +1,8 @@
-use super::super::weight::*;
+use super::{super::weight::*, logging::*};
 
 use {
     http::{header::*, uri::*, *},
+    kutil::http::*,
     std::{fmt, hash::*},
 };
 
@@ -16,6 +17,99 @@ where
 {
     /// Create a cache key for a request.
     fn for_request(method: &Method, uri: &Uri, headers: &HeaderMap) -> Self;
+
+    /// Create the cache key under which a `HEAD` request is looked up.
+    ///
+    /// `HEAD` requests are answered from these entries but never stored.
+    ///
+    /// The default implementation creates the key for a `GET` request, so that `HEAD` requests
+    /// are answered from the entries cached by `GET`.
+    fn for_head_lookup(uri: &Uri, headers: &HeaderMap) -> Self {
+        Self::for_request(&Method::GET, uri, headers)
+    }
+
+    /// Set the negotiated language.
+    ///
+    /// The language will always be one of the supported languages configured for
+    /// [LanguageNegotiation](super::super::middleware::LanguageNegotiation).
+    ///
+    /// The default implementation does nothing.
+    fn set_negotiated_language(&mut self, _language: Language) {}
+
+    /// Set a query parameter, or remove it if `value` is [None].
+    ///
+    /// Used by [BustParamPolicy](super::super::middleware::BustParamPolicy).
+    ///
+    /// The default implementation does nothing.
+    fn set_query_parameter(&mut self, _name: &str, _value: Option<&str>) {}
+
+    /// Set the effective origin of the request.
+    ///
+    /// Used for host partitioning, with the origin resolved according to the trust policy for
+    /// forwarded headers (see
+    /// [RequestOrigin](super::super::middleware::RequestOrigin)).
+    ///
+    /// The default implementation does nothing.
+    fn set_origin(&mut self, _scheme: Option<&Scheme>, _host: Option<&str>, _port: Option<u16>) {}
+
+    /// Set the cache generation.
+    ///
+    /// Used by [CacheGenerations](super::super::middleware::CacheGenerations). Keys for
+    /// different generations must not be equal.
+    ///
+    /// The default implementation does nothing.
+    fn set_generation(&mut self, _generation: u64) {}
+
+    /// Set the partition.
+    ///
+    /// Used by [PartitionPolicy](super::super::middleware::PartitionPolicy). Keys for different
+    /// partitions must not be equal. The identifier is empty for the dedicated unpartitioned
+    /// bucket, which must be distinct from not having a partition at all.
+    ///
+    /// The default implementation does nothing.
+    fn set_partition(&mut self, _partition: &str) {}
+
+    /// Set the value of a request header that the response varies on.
+    ///
+    /// Used by [VaryKeys](super::super::middleware::VaryKeys). Keys for different values must not
+    /// be equal. A missing header is [None].
+    ///
+    /// The default implementation does nothing.
+    fn set_varied_header(&mut self, _name: &HeaderName, _value: Option<&HeaderValue>) {}
+
+    /// Set the digest of the values of a request header that partitions the cache.
+    ///
+    /// Used by
+    /// [partition_cache_by_headers](super::super::super::CachingLayer::partition_cache_by_headers).
+    /// Keys for different digests must not be equal. A missing header is [None].
+    ///
+    /// There is no default implementation, because ignoring the digest would share entries
+    /// between requests with different header values (e.g. different users' cookies).
+    fn set_header_digest(&mut self, name: &HeaderName, digest: Option<u64>);
+
+    /// Path, if the key has one.
+    ///
+    /// Used for banning by path prefix (see
+    /// [AdminMethodConfig](super::super::middleware::AdminMethodConfig)).
+    ///
+    /// The default implementation returns [None].
+    fn path(&self) -> Option<&str> {
+        None
+    }
+
+    /// Representation for logs and error messages.
+    ///
+    /// Use this instead of [Display](fmt::Display) wherever the key is logged. The full
+    /// [Display](fmt::Display) remains available for explicit operator use.
+    ///
+    /// The default implementation delegates to [Display](fmt::Display), capped at the policy's
+    /// maximum length.
+    fn display_for_logs<'this>(
+        &'this self,
+        policy: &'this KeyLogPolicy,
+    ) -> impl fmt::Display + 'this {
+        policy.capped(self)
+    }
 }
 
 //
@@ -0,0 +1,152 @@
+use std::{borrow::*, fmt};
+
+/// Replaces redacted query values in logs.
+pub const REDACTED: &str = "[redacted]";
+
+// Ellipsis, '#', and 8 hex digits.
+const HASH_SUFFIX_LENGTH: usize = '…'.len_utf8() + 1 + 8;
+
+//
+// KeyLogPolicy
+//
+
+/// How cache keys are rendered in logs and error messages.
+///
+/// Keys can include user input, notably query strings, which might be sensitive and can be
+/// arbitrarily long. This policy can redact query values and cap lengths. Truncated values end
+/// with an ellipsis and a hash of the full value, so that distinct keys remain distinguishable.
+///
+/// The default renders keys in full, as their [Display](fmt::Display) does. See
+/// [redacted](Self::redacted) for a privacy-conscious preset.
+///
+/// See [CacheKey::display_for_logs](super::CacheKey::display_for_logs).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct KeyLogPolicy {
+    /// Query parameters whose values are redacted.
+    ///
+    /// Names are compared case-insensitively.
+    pub sensitive_query_params: Vec<String>,
+
+    /// Whether to redact all query values.
+    pub redact_query_values: bool,
+
+    /// Maximum length of each key component in bytes.
+    ///
+    /// Should be larger than the hash suffix (12 bytes).
+    pub max_component_length: Option<usize>,
+
+    /// Maximum length of the rendered key in bytes.
+    ///
+    /// Should be larger than the hash suffix (12 bytes).
+    pub max_length: Option<usize>,
+}
+
+impl KeyLogPolicy {
+    /// Redact all query values, truncate components to 64 bytes, and cap keys at 256 bytes.
+    pub fn redacted() -> Self {
+        Self {
+            sensitive_query_params: Default::default(),
+            redact_query_values: true,
+            max_component_length: Some(64),
+            max_length: Some(256),
+        }
+    }
+
+    /// Add a sensitive query parameter.
+    pub fn with_sensitive_query_param(mut self, name: impl Into<String>) -> Self {
+        self.sensitive_query_params.push(name.into());
+        self
+    }
+
+    /// Set maximum component length.
+    pub fn with_max_component_length(mut self, max_component_length: usize) -> Self {
+        self.max_component_length = Some(max_component_length);
+        self
+    }
+
+    /// Set maximum length.
+    pub fn with_max_length(mut self, max_length: usize) -> Self {
+        self.max_length = Some(max_length);
+        self
+    }
+
+    /// Whether the value of a query parameter is redacted.
+    pub fn redacts(&self, name: &str) -> bool {
+        self.redact_query_values
+            || self
+                .sensitive_query_params
+                .iter()
+                .any(|sensitive| sensitive.eq_ignore_ascii_case(name))
+    }
+
+    /// Truncate a key component if it is longer than the maximum.
+    pub fn truncate_component<'this>(&self, component: &'this str) -> Cow<'this, str> {
+        truncate(component, self.max_component_length)
+    }
+
+    /// Wrap a rendered key so that it is capped at the maximum length.
+    pub fn capped<DisplayT>(&self, display: DisplayT) -> CappedDisplay<'_, DisplayT>
+    where
+        DisplayT: fmt::Display,
+    {
+        CappedDisplay {
+            display,
+            policy: self,
+        }
+    }
+}
+
+//
+// CappedDisplay
+//
+
+/// [Display](fmt::Display) capped at the [KeyLogPolicy] maximum length.
+///
+/// Rendering happens only when displayed, so it costs nothing if the log level is disabled.
+#[derive(Clone, Debug)]
+pub struct CappedDisplay<'this, DisplayT> {
+    display: DisplayT,
+    policy: &'this KeyLogPolicy,
+}
+
+impl<'this, DisplayT> fmt::Display for CappedDisplay<'this, DisplayT>
+where
+    DisplayT: fmt::Display,
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self.policy.max_length {
+            Some(max_length) => {
+                let rendered = self.display.to_string();
+                formatter.write_str(&truncate(&rendered, Some(max_length)))
+            }
+
+            None => fmt::Display::fmt(&self.display, formatter),
+        }
+    }
+}
+
+// Truncate with an ellipsis and a hash of the full value.
+fn truncate(value: &str, max_length: Option<usize>) -> Cow<'_, str> {
+    match max_length {
+        Some(max_length) if value.len() > max_length => {
+            let mut length = max_length.saturating_sub(HASH_SUFFIX_LENGTH);
+            while !value.is_char_boundary(length) {
+                length -= 1;
+            }
+
+            Cow::Owned(format!("{}…#{:08x}", &value[..length], hash(value)))
+        }
+
+        _ => Cow::Borrowed(value),
+    }
+}
+
+// FNV-1a, folded to 32 bits. Stable across builds and platforms, so logs can be compared.
+fn hash(value: &str) -> u32 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in value.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    (hash ^ (hash >> 32)) as u32
+}
@@ -1,5 +1,7 @@
+mod canonical;
 mod common;
 mod key;
+mod logging;
 
 #[allow(unused_imports)]
-pub use {common::*, key::*};
+pub use {canonical::*, common::*, key::*, logging::*};
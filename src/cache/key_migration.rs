@@ -0,0 +1,265 @@
+use super::{cache::*, invalidation::*, key::*, response::*, self_test::*};
+
+use std::{
+    fmt,
+    sync::{atomic::*, *},
+    time::*,
+};
+
+/// Legacy key hook for [KeyMigratingCache].
+pub type LegacyKeyHook<CacheKeyT> =
+    Arc<Box<dyn Fn(&CacheKeyT) -> Option<CacheKeyT> + Send + Sync>>;
+
+//
+// KeyMigratingCache
+//
+
+/// [Cache] wrapper for a transition between key forms.
+///
+/// Adding an optional field to [CommonCacheKey] does not change the
+/// [canonical form](CanonicalKeyForm) of keys without it, so shared entries survive upgrades.
+/// But when a deployment starts *using* a new key dimension (e.g. enables partitioning or
+/// generations), all of its keys change, and all existing entries would be orphaned at once.
+///
+/// This wrapper eases the transition. During the transition window, a miss for a key is looked
+/// up again under its legacy form (as computed by the legacy key hook, e.g. by clearing the new
+/// field). A hit there is served and re-stored under the new key, so each entry falls back at
+/// most once per process. Watch [fallback_hits](KeyMigrationStats::fallback_hits) to know when
+/// the transition is complete, and then remove the wrapper.
+///
+/// The legacy entry itself is kept, because processes that haven't been upgraded yet may still
+/// use it. Invalidating a key also invalidates its legacy form, and re-storing is
+/// [fenced](Cache::put_fenced), so an invalidation during the transition is never undone by a
+/// fallback hit (provided that the inner cache supports fencing, e.g. a
+/// [FencedCache](super::FencedCache)).
+///
+/// Cloning is cheap and clones share state.
+#[derive(Clone)]
+pub struct KeyMigratingCache<CacheT, CacheKeyT = CommonCacheKey> {
+    /// Inner cache.
+    pub inner: CacheT,
+
+    /// Computes the legacy form of a key ([None] if there is none).
+    pub legacy_key: LegacyKeyHook<CacheKeyT>,
+
+    /// End of the transition window.
+    pub until: SystemTime,
+
+    /// How keys are rendered in logs.
+    pub key_log_policy: KeyLogPolicy,
+
+    counters: Arc<KeyMigrationCounters>,
+}
+
+impl<CacheT, CacheKeyT> KeyMigratingCache<CacheT, CacheKeyT> {
+    /// Constructor.
+    ///
+    /// The transition window starts now.
+    pub fn new(
+        inner: CacheT,
+        transition: Duration,
+        legacy_key: impl Fn(&CacheKeyT) -> Option<CacheKeyT> + 'static + Send + Sync,
+    ) -> Self {
+        Self {
+            inner,
+            legacy_key: Arc::new(Box::new(legacy_key)),
+            until: SystemTime::now() + transition,
+            key_log_policy: Default::default(),
+            counters: Default::default(),
+        }
+    }
+
+    /// Set the end of the transition window.
+    ///
+    /// Useful for aligning the window of all processes, e.g. to the time of the deploy.
+    pub fn with_until(mut self, until: SystemTime) -> Self {
+        self.until = until;
+        self
+    }
+
+    /// Set key log policy.
+    pub fn with_key_log_policy(mut self, key_log_policy: KeyLogPolicy) -> Self {
+        self.key_log_policy = key_log_policy;
+        self
+    }
+
+    /// Whether we are within the transition window.
+    pub fn in_transition(&self) -> bool {
+        SystemTime::now() < self.until
+    }
+
+    /// Stats.
+    pub fn stats(&self) -> KeyMigrationStats {
+        KeyMigrationStats {
+            fallback_hits: self.counters.fallback_hits.load(Ordering::Relaxed),
+            fallback_misses: self.counters.fallback_misses.load(Ordering::Relaxed),
+            rekey_rejected: self.counters.rekey_rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl<CacheT, CacheKeyT> KeyMigratingCache<CacheT, CacheKeyT>
+where
+    CacheKeyT: CacheKey,
+{
+    // The legacy form if it differs from the key.
+    fn legacy_form(&self, key: &CacheKeyT) -> Option<CacheKeyT> {
+        (self.legacy_key)(key).filter(|legacy_key| legacy_key != key)
+    }
+}
+
+impl<CacheT, CacheKeyT> Cache<CacheKeyT> for KeyMigratingCache<CacheT, CacheKeyT>
+where
+    CacheT: Cache<CacheKeyT>,
+    CacheKeyT: CacheKey,
+{
+    async fn get(&self, key: &CacheKeyT) -> Option<CachedResponseRef> {
+        if let Some(cached_response) = self.inner.get(key).await {
+            return Some(cached_response);
+        }
+
+        if !self.in_transition() {
+            return None;
+        }
+
+        let legacy_key = self.legacy_form(key)?;
+
+        // Captured before reading, so that a concurrent invalidation rejects the re-store
+        let fence = self.inner.fence(key);
+
+        let Some(cached_response) = self.inner.get(&legacy_key).await else {
+            self.counters.fallback_misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+
+        self.counters.fallback_hits.fetch_add(1, Ordering::Relaxed);
+        tracing::debug!("legacy key hit: {}", key.display_for_logs(&self.key_log_policy));
+
+        if self.inner.put_fenced(key.clone(), cached_response.clone(), fence).await
+            == PutOutcome::RejectedStale
+        {
+            // Invalidated while we were reading
+            self.counters.rekey_rejected.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        Some(cached_response)
+    }
+
+    fn get_if_present(&self, key: &CacheKeyT) -> Option<Option<CachedResponseRef>> {
+        // During the transition a miss falls back to the legacy key, which needs get
+        match self.inner.get_if_present(key) {
+            Some(Some(cached_response)) => Some(Some(cached_response)),
+            Some(None) if !self.in_transition() => Some(None),
+            _ => None,
+        }
+    }
+
+    async fn put(&self, key: CacheKeyT, cached_response: CachedResponseRef) {
+        self.inner.put(key, cached_response).await
+    }
+
+    fn fence(&self, key: &CacheKeyT) -> Fence {
+        self.inner.fence(key)
+    }
+
+    async fn put_fenced(
+        &self,
+        key: CacheKeyT,
+        cached_response: CachedResponseRef,
+        fence: Fence,
+    ) -> PutOutcome {
+        self.inner.put_fenced(key, cached_response, fence).await
+    }
+
+    async fn update<UpdateT>(&self, key: CacheKeyT, update: UpdateT) -> bool
+    where
+        UpdateT: FnOnce(CachedResponseRef) -> Option<CachedResponseRef> + Send,
+    {
+        self.inner.update(key, update).await
+    }
+
+    async fn invalidate(&self, key: &CacheKeyT) {
+        self.inner.invalidate(key).await;
+
+        // Otherwise a later fallback would serve it again
+        if let Some(legacy_key) = self.legacy_form(key) {
+            self.inner.invalidate(&legacy_key).await;
+        }
+    }
+
+    async fn invalidate_all(&self) {
+        self.inner.invalidate_all().await
+    }
+
+    async fn invalidate_all_with_outcome(&self) -> InvalidationOutcome {
+        self.inner.invalidate_all_with_outcome().await
+    }
+
+    async fn self_test(&self) -> Result<SelfTestReport, SelfTestError> {
+        self.inner.self_test().await
+    }
+
+    fn entry_count(&self) -> Option<u64> {
+        self.inner.entry_count()
+    }
+
+    fn weighted_size(&self) -> Option<u64> {
+        self.inner.weighted_size()
+    }
+
+    fn keys(&self) -> Option<Vec<CacheKeyT>> {
+        self.inner.keys()
+    }
+}
+
+impl<CacheT, CacheKeyT> fmt::Debug for KeyMigratingCache<CacheT, CacheKeyT>
+where
+    CacheT: fmt::Debug,
+{
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("KeyMigratingCache")
+            .field("inner", &self.inner)
+            .field("until", &self.until)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+//
+// KeyMigrationStats
+//
+
+/// [KeyMigratingCache] stats.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KeyMigrationStats {
+    /// Misses that were hits under the legacy key form.
+    ///
+    /// When this stops growing the transition is complete.
+    pub fallback_hits: u64,
+
+    /// Misses that were also misses under the legacy key form.
+    pub fallback_misses: u64,
+
+    /// Fallback hits that could not be re-stored because the key was invalidated meanwhile
+    /// (served as misses).
+    pub rekey_rejected: u64,
+}
+
+impl fmt::Display for KeyMigrationStats {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            formatter,
+            "fallback hits: {}, fallback misses: {}, re-key rejected: {}",
+            self.fallback_hits, self.fallback_misses, self.rekey_rejected
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+struct KeyMigrationCounters {
+    fallback_hits: AtomicU64,
+    fallback_misses: AtomicU64,
+    rekey_rejected: AtomicU64,
+}
@@ -0,0 +1,60 @@
+use {
+    kutil::http::*,
+    std::{error::*, fmt, io},
+};
+
+//
+// LengthMismatch
+//
+
+/// The body did not match its declared `Content-Length`.
+///
+/// Returned (wrapped in a [ReadBodyError]) by
+/// [CachedResponse::new_for](super::CachedResponse::new_for) instead of storing a truncated or
+/// overlong entry. The body pieces are still returned, so the response can be passed through.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LengthMismatch {
+    /// Declared size.
+    pub declared: usize,
+
+    /// Read size. When the body was longer than declared we stop reading, so this is only a
+    /// lower bound.
+    pub read: usize,
+}
+
+impl LengthMismatch {
+    /// Constructor.
+    pub fn new(declared: usize, read: usize) -> Self {
+        Self { declared, read }
+    }
+
+    /// The length mismatch, if that is what the error is.
+    pub fn of(error: &ReadBodyError) -> Option<&Self> {
+        match error {
+            ReadBodyError::IO(error) => error.get_ref().and_then(|error| error.downcast_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for LengthMismatch {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        if self.read > self.declared {
+            write!(formatter, "length mismatch: declared {} bytes, read more", self.declared)
+        } else {
+            write!(
+                formatter,
+                "length mismatch: declared {} bytes, read {}",
+                self.declared, self.read
+            )
+        }
+    }
+}
+
+impl Error for LengthMismatch {}
+
+impl From<LengthMismatch> for ReadBodyError {
+    fn from(length_mismatch: LengthMismatch) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, length_mismatch).into()
+    }
+}
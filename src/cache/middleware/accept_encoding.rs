@@ -0,0 +1,281 @@
+use {
+    http::header::*,
+    std::{
+        fmt,
+        sync::{atomic::*, *},
+    },
+};
+
+/// Default maximum number of `Accept-Encoding` members (see [AcceptEncoding]).
+pub const DEFAULT_MAX_ACCEPT_ENCODING_MEMBERS: usize = 32;
+
+//
+// AcceptEncoding
+//
+
+/// Normalized request `Accept-Encoding`.
+///
+/// Clients send all kinds of malformed values. Rather than leaving their interpretation to the
+/// negotiator, they are normalized with these semantics:
+///
+/// * An empty value, or one in which all members are ignored, means that only
+///   [Identity](kutil::transcoding::Encoding::Identity) is acceptable.
+/// * Empty members (e.g. `gzip, , br` or `;q=1`), members with invalid coding names, and members
+///   with invalid weights (e.g. `gzip;q=` or `gzip;q=2`) are ignored, while the rest are honored.
+/// * Coding names compare case-insensitively (they are lowercased).
+/// * Duplicate codings take the maximum weight.
+/// * Beyond the maximum number of members only Identity is acceptable.
+///
+/// The normalized value, which is what the [EncodingNegotiator](super::EncodingNegotiator) gets,
+/// depends only on the header values, so that identical values always negotiate the same coding.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AcceptEncoding {
+    /// Lowercase coding names with weights in thousandths, in order of first appearance.
+    pub members: Vec<(String, u16)>,
+
+    /// Whether only Identity is acceptable.
+    pub identity_only: bool,
+
+    /// Anomalies found while parsing.
+    pub anomalies: Vec<AcceptEncodingAnomaly>,
+}
+
+impl AcceptEncoding {
+    /// Parse request headers.
+    ///
+    /// Returns [None] if there is no `Accept-Encoding` header.
+    pub fn from_headers(headers: &HeaderMap, max_members: usize) -> Option<Self> {
+        let mut values = headers.get_all(ACCEPT_ENCODING).iter().peekable();
+        values.peek()?;
+
+        let mut accept_encoding = Self::default();
+        let mut count = 0;
+        let mut empty = true;
+
+        for value in values {
+            let Ok(value) = value.to_str() else {
+                accept_encoding.anomaly(AcceptEncodingAnomaly::InvalidCoding);
+                empty = false;
+                continue;
+            };
+
+            if value.trim().is_empty() {
+                continue;
+            }
+            empty = false;
+
+            for member in value.split(',') {
+                let member = member.trim();
+                if member.is_empty() {
+                    accept_encoding.anomaly(AcceptEncodingAnomaly::EmptyMember);
+                    continue;
+                }
+
+                count += 1;
+                if count > max_members {
+                    accept_encoding.anomaly(AcceptEncodingAnomaly::TooManyMembers);
+                    accept_encoding.members.clear();
+                    accept_encoding.identity_only = true;
+                    return Some(accept_encoding);
+                }
+
+                accept_encoding.add_member(member);
+            }
+        }
+
+        if empty {
+            accept_encoding.anomaly(AcceptEncodingAnomaly::Empty);
+        }
+
+        // Including when all members were ignored
+        if accept_encoding.members.is_empty() {
+            accept_encoding.identity_only = true;
+        }
+
+        Some(accept_encoding)
+    }
+
+    /// Normalized header value.
+    ///
+    /// Weights are only included if they are not 1.
+    pub fn to_header_value(&self) -> String {
+        let mut value = String::new();
+        for (name, weight) in &self.members {
+            if !value.is_empty() {
+                value.push_str(", ");
+            }
+            value.push_str(name);
+            if *weight != 1000 {
+                value.push_str(&format!(";q={}.{:03}", weight / 1000, weight % 1000));
+            }
+        }
+        value
+    }
+
+    fn add_member(&mut self, member: &str) {
+        let mut parameters = member.split(';');
+        let name = parameters.next().unwrap_or_default().trim();
+
+        if name.is_empty() {
+            self.anomaly(AcceptEncodingAnomaly::EmptyMember);
+            return;
+        }
+
+        if !name.bytes().all(is_token_byte) {
+            self.anomaly(AcceptEncodingAnomaly::InvalidCoding);
+            return;
+        }
+
+        let mut weight = 1000;
+        for parameter in parameters {
+            let Some((key, value)) = parameter.split_once('=') else {
+                continue;
+            };
+
+            if key.trim().eq_ignore_ascii_case("q") {
+                match parse_weight(value.trim()) {
+                    Some(parsed) => weight = parsed,
+                    None => {
+                        self.anomaly(AcceptEncodingAnomaly::InvalidWeight);
+                        return;
+                    }
+                }
+            }
+        }
+
+        let name = name.to_ascii_lowercase();
+        match self.members.iter_mut().find(|(member_name, _)| *member_name == name) {
+            Some((_, member_weight)) => {
+                *member_weight = (*member_weight).max(weight);
+                self.anomaly(AcceptEncodingAnomaly::Duplicate);
+            }
+
+            None => self.members.push((name, weight)),
+        }
+    }
+
+    fn anomaly(&mut self, anomaly: AcceptEncodingAnomaly) {
+        if !self.anomalies.contains(&anomaly) {
+            self.anomalies.push(anomaly);
+        }
+    }
+}
+
+//
+// AcceptEncodingAnomaly
+//
+
+/// Class of malformed request `Accept-Encoding` (see [AcceptEncoding]).
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum AcceptEncodingAnomaly {
+    /// Empty value.
+    Empty,
+
+    /// Empty member.
+    EmptyMember,
+
+    /// Invalid coding name.
+    InvalidCoding,
+
+    /// Invalid weight.
+    InvalidWeight,
+
+    /// Duplicate coding.
+    Duplicate,
+
+    /// Too many members.
+    TooManyMembers,
+}
+
+impl AcceptEncodingAnomaly {
+    /// All anomaly classes.
+    pub const ALL: [Self; 6] = [
+        Self::Empty,
+        Self::EmptyMember,
+        Self::InvalidCoding,
+        Self::InvalidWeight,
+        Self::Duplicate,
+        Self::TooManyMembers,
+    ];
+}
+
+impl fmt::Display for AcceptEncodingAnomaly {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(
+            match self {
+                Self::Empty => "empty",
+                Self::EmptyMember => "empty-member",
+                Self::InvalidCoding => "invalid-coding",
+                Self::InvalidWeight => "invalid-weight",
+                Self::Duplicate => "duplicate",
+                Self::TooManyMembers => "too-many-members",
+            },
+            formatter,
+        )
+    }
+}
+
+//
+// AcceptEncodingAnomalyCounter
+//
+
+/// Counts requests with malformed `Accept-Encoding` by [AcceptEncodingAnomaly] class.
+///
+/// A request with several anomalies is counted once for each of their classes.
+///
+/// Cloning is cheap and clones share state.
+#[derive(Clone, Debug, Default)]
+pub struct AcceptEncodingAnomalyCounter {
+    counts: Arc<[AtomicU64; AcceptEncodingAnomaly::ALL.len()]>,
+}
+
+impl AcceptEncodingAnomalyCounter {
+    /// Record the anomalies of a request.
+    pub fn record(&self, anomalies: &[AcceptEncodingAnomaly]) {
+        for anomaly in anomalies {
+            tracing::debug!("malformed Accept-Encoding: {}", anomaly);
+            self.counts[*anomaly as usize].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Number of requests with an anomaly class.
+    pub fn count(&self, anomaly: AcceptEncodingAnomaly) -> u64 {
+        self.counts[anomaly as usize].load(Ordering::Relaxed)
+    }
+
+    /// Number of requests for all anomaly classes.
+    pub fn counts(&self) -> impl Iterator<Item = (AcceptEncodingAnomaly, u64)> + '_ {
+        AcceptEncodingAnomaly::ALL
+            .into_iter()
+            .map(|anomaly| (anomaly, self.count(anomaly)))
+    }
+}
+
+// Weight in thousandths.
+//
+// See [IETF RFC 9110 section 12.4.2](https://datatracker.ietf.org/doc/html/rfc9110#section-12.4.2).
+fn parse_weight(weight: &str) -> Option<u16> {
+    let (integer, fraction) = weight.split_once('.').unwrap_or((weight, ""));
+    if fraction.len() > 3 || !fraction.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    let integer: u16 = match integer {
+        "0" => 0,
+        "1" => 1,
+        _ => return None,
+    };
+
+    let mut thousandths = 0;
+    for (index, byte) in fraction.bytes().enumerate() {
+        thousandths += (byte - b'0') as u16 * 10u16.pow(2 - index as u32);
+    }
+
+    let weight = integer * 1000 + thousandths;
+    (weight <= 1000).then_some(weight)
+}
+
+// See [IETF RFC 9110 section 5.6.2](https://datatracker.ietf.org/doc/html/rfc9110#section-5.6.2).
+fn is_token_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
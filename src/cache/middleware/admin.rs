@@ -0,0 +1,496 @@
+use super::{
+    super::{cache::*, key::*, response::*},
+    configuration::*,
+    forwarded::*,
+    hooks::*,
+    request::*,
+};
+
+use {
+    http::{header::*, request::*, *},
+    std::{
+        fmt,
+        net::{IpAddr, SocketAddr},
+        sync::*,
+    },
+};
+
+/// `X-Ban-Path-Prefix` header name.
+pub const X_BAN_PATH_PREFIX: HeaderName = HeaderName::from_static("x-ban-path-prefix");
+
+/// `X-Ban-Tag` header name.
+pub const X_BAN_TAG: HeaderName = HeaderName::from_static("x-ban-tag");
+
+/// `Cache-Tag` header name.
+pub const CACHE_TAG: HeaderName = HeaderName::from_static("cache-tag");
+
+//
+// AdminVerb
+//
+
+/// Cache administration verb.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum AdminVerb {
+    /// Invalidate the entries for the request's URI.
+    Purge,
+
+    /// Invalidate the entries matching a [BanExpression].
+    Ban,
+}
+
+impl fmt::Display for AdminVerb {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(
+            match self {
+                Self::Purge => "purge",
+                Self::Ban => "ban",
+            },
+            formatter,
+        )
+    }
+}
+
+//
+// AdminMethodConfig
+//
+
+/// In-band cache administration via Varnish-style request methods.
+///
+/// Authorized requests with these methods are handled by the middleware and never reach the inner
+/// service:
+///
+/// * `PURGE` invalidates the entries for the request's URI, with keys created exactly as for a
+///   `GET` (and `HEAD`, if cacheable), for all known variants: the supported languages of
+///   [LanguageNegotiation](super::language::LanguageNegotiation) and the current and previous
+///   [CacheGenerations](super::generation::CacheGenerations). Responds with `200 OK` and the
+///   number of invalidated entries, or `404 Not Found` if there were none.
+/// * `BAN` invalidates the entries matching a [BanExpression] read from the request headers
+///   (`X-Ban-Path-Prefix` or `X-Ban-Tag` by default). Responds with `200 OK` and the number of
+///   invalidated entries. Requires a cache that supports [keys](Cache::keys), and a
+///   [CacheKey] that supports [path](CacheKey::path) for prefixes.
+///
+/// Authorization is mandatory. Unauthorized requests are passed to the inner service untouched,
+/// so that the feature is invisible to outsiders. (Such requests are never cached, because these
+/// methods are not idempotent.)
+///
+/// Every handled request is logged and reported to the audit hook, if configured.
+#[derive(Clone)]
+pub struct AdminMethodConfig {
+    /// Method for purging ([None] to disable).
+    pub purge_method: Option<Method>,
+
+    /// Method for banning ([None] to disable).
+    pub ban_method: Option<Method>,
+
+    /// Request header with a path prefix to ban ([None] to disable).
+    pub ban_path_prefix_header: Option<HeaderName>,
+
+    /// Request header with a tag to ban ([None] to disable).
+    pub ban_tag_header: Option<HeaderName>,
+
+    /// Response header with the tags of an entry.
+    ///
+    /// Values are comma- or space-separated (so that `Surrogate-Key` works, too).
+    pub tag_header: HeaderName,
+
+    /// Authorization (hook).
+    pub authorize: AdminAuthorizationHook,
+
+    /// Audit (hook).
+    pub on_audit: Option<AdminAuditHook>,
+}
+
+impl AdminMethodConfig {
+    /// Constructor.
+    ///
+    /// `PURGE` and `BAN` are enabled, with `X-Ban-Path-Prefix`, `X-Ban-Tag`, and `Cache-Tag`.
+    pub fn new(
+        authorize: impl Fn(AdminAuthorizationHookContext) -> bool + 'static + Send + Sync,
+    ) -> Self {
+        Self {
+            purge_method: Some(Method::from_bytes(b"PURGE").expect("method")),
+            ban_method: Some(Method::from_bytes(b"BAN").expect("method")),
+            ban_path_prefix_header: Some(X_BAN_PATH_PREFIX),
+            ban_tag_header: Some(X_BAN_TAG),
+            tag_header: CACHE_TAG,
+            authorize: Arc::new(Box::new(authorize)),
+            on_audit: None,
+        }
+    }
+
+    /// Constructor with a shared secret that must be provided in a request header.
+    pub fn with_shared_secret(header: HeaderName, secret: impl Into<String>) -> Self {
+        let secret = secret.into();
+        Self::new(move |context| {
+            context
+                .headers
+                .get(&header)
+                .is_some_and(|value| constant_time_eq(value.as_bytes(), secret.as_bytes()))
+        })
+    }
+
+    /// Constructor with a peer address predicate.
+    ///
+    /// The peer address is taken from the request extensions, so it must be made available there
+    /// (e.g. with axum's `into_make_service_with_connect_info`). Requests without it are never
+    /// authorized.
+    pub fn trusting_peers(predicate: impl Fn(IpAddr) -> bool + 'static + Send + Sync) -> Self {
+        Self::new(move |context| context.peer.is_some_and(|peer| predicate(peer.ip())))
+    }
+
+    /// Set purge method.
+    pub fn with_purge_method(mut self, purge_method: Option<Method>) -> Self {
+        self.purge_method = purge_method;
+        self
+    }
+
+    /// Set ban method.
+    pub fn with_ban_method(mut self, ban_method: Option<Method>) -> Self {
+        self.ban_method = ban_method;
+        self
+    }
+
+    /// Set ban path prefix header.
+    pub fn with_ban_path_prefix_header(
+        mut self,
+        ban_path_prefix_header: Option<HeaderName>,
+    ) -> Self {
+        self.ban_path_prefix_header = ban_path_prefix_header;
+        self
+    }
+
+    /// Set ban tag header.
+    pub fn with_ban_tag_header(mut self, ban_tag_header: Option<HeaderName>) -> Self {
+        self.ban_tag_header = ban_tag_header;
+        self
+    }
+
+    /// Set tag header.
+    pub fn with_tag_header(mut self, tag_header: HeaderName) -> Self {
+        self.tag_header = tag_header;
+        self
+    }
+
+    /// Set audit hook.
+    ///
+    /// Called synchronously for every handled request, so keep it cheap.
+    pub fn with_audit(
+        mut self,
+        on_audit: impl Fn(AdminAuditEvent) + 'static + Send + Sync,
+    ) -> Self {
+        self.on_audit = Some(Arc::new(Box::new(on_audit)));
+        self
+    }
+
+    /// The verb for a request, if it is an authorized admin request.
+    pub fn verb<RequestBodyT>(&self, request: &Request<RequestBodyT>) -> Option<AdminVerb> {
+        let method = request.method();
+        let verb = if self.purge_method.as_ref() == Some(method) {
+            AdminVerb::Purge
+        } else if self.ban_method.as_ref() == Some(method) {
+            AdminVerb::Ban
+        } else {
+            return None;
+        };
+
+        if (self.authorize)(AdminAuthorizationHookContext::new(
+            verb,
+            request.uri(),
+            peer_address(request),
+            request.headers(),
+        )) {
+            Some(verb)
+        } else {
+            tracing::debug!("unauthorized {}: {}", verb, request.uri());
+            None
+        }
+    }
+
+    /// Purge the entries for a request's URI.
+    ///
+    /// Returns the number of invalidated entries.
+    pub async fn purge<RequestBodyT, CacheT, CacheKeyT>(
+        &self,
+        request: &mut Request<RequestBodyT>,
+        configuration: &MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+    ) -> usize
+    where
+        CacheT: Cache<CacheKeyT>,
+        CacheKeyT: CacheKey,
+    {
+        invalidate_uri_cache_keys(request, configuration).await
+    }
+
+    /// The [BanExpression] in request headers.
+    pub fn ban_expression(&self, headers: &HeaderMap) -> Option<BanExpression> {
+        let value = |header: &Option<HeaderName>| {
+            header
+                .as_ref()
+                .and_then(|header| headers.get(header))
+                .and_then(|value| value.to_str().ok())
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(String::from)
+        };
+
+        if let Some(prefix) = value(&self.ban_path_prefix_header) {
+            Some(BanExpression::PathPrefix(prefix))
+        } else {
+            value(&self.ban_tag_header).map(BanExpression::Tag)
+        }
+    }
+
+    /// Report to the log and to the audit hook.
+    pub fn audit(&self, event: AdminAuditEvent) {
+        tracing::info!(
+            "{} {} by {}: invalidated {}",
+            event.verb,
+            event.target,
+            event
+                .peer
+                .map(|peer| peer.to_string())
+                .unwrap_or_else(|| "unknown".into()),
+            event.invalidated
+        );
+
+        if let Some(on_audit) = &self.on_audit {
+            on_audit(event);
+        }
+    }
+}
+
+impl fmt::Debug for AdminMethodConfig {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("AdminMethodConfig")
+            .field("purge_method", &self.purge_method)
+            .field("ban_method", &self.ban_method)
+            .field("ban_path_prefix_header", &self.ban_path_prefix_header)
+            .field("ban_tag_header", &self.ban_tag_header)
+            .field("tag_header", &self.tag_header)
+            .finish()
+    }
+}
+
+//
+// BanExpression
+//
+
+/// Which entries to ban.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum BanExpression {
+    /// Entries whose key's [path](CacheKey::path) starts with a prefix.
+    PathPrefix(String),
+
+    /// Entries tagged with a tag (see [AdminMethodConfig::tag_header]).
+    Tag(String),
+}
+
+impl BanExpression {
+    /// Whether an entry matches.
+    pub fn matches<CacheKeyT>(
+        &self,
+        key: &CacheKeyT,
+        cached_response: &CachedResponse,
+        tag_header: &HeaderName,
+    ) -> bool
+    where
+        CacheKeyT: CacheKey,
+    {
+        match self {
+            Self::PathPrefix(prefix) => {
+                key.path().is_some_and(|path| path.starts_with(prefix.as_str()))
+            }
+
+            Self::Tag(tag) => has_tag(cached_response, tag_header, tag),
+        }
+    }
+
+    /// Invalidate matching entries.
+    ///
+    /// Returns the number of invalidated entries, or [None] if the cache doesn't support
+    /// [keys](Cache::keys).
+    pub async fn invalidate<CacheT, CacheKeyT>(
+        &self,
+        cache: &CacheT,
+        tag_header: &HeaderName,
+    ) -> Option<usize>
+    where
+        CacheT: Cache<CacheKeyT>,
+        CacheKeyT: CacheKey,
+    {
+        let mut invalidated = 0;
+        for key in cache.keys()? {
+            let matches = match self {
+                Self::PathPrefix(prefix) => {
+                    key.path().is_some_and(|path| path.starts_with(prefix.as_str()))
+                }
+
+                Self::Tag(tag) => cache
+                    .get(&key)
+                    .await
+                    .is_some_and(|cached_response| has_tag(&cached_response, tag_header, tag)),
+            };
+
+            if matches {
+                cache.invalidate(&key).await;
+                invalidated += 1;
+            }
+        }
+
+        Some(invalidated)
+    }
+}
+
+impl fmt::Display for BanExpression {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::PathPrefix(prefix) => write!(formatter, "path prefix {}", prefix),
+            Self::Tag(tag) => write!(formatter, "tag {}", tag),
+        }
+    }
+}
+
+//
+// AdminAuditEvent
+//
+
+/// Context for [AdminAuditHook].
+#[derive(Clone, Debug)]
+pub struct AdminAuditEvent {
+    /// Verb.
+    pub verb: AdminVerb,
+
+    /// Peer address (the actor), if available in the request extensions.
+    pub peer: Option<SocketAddr>,
+
+    /// Target (the URI for purges, the [BanExpression] for bans).
+    pub target: String,
+
+    /// Number of invalidated entries.
+    pub invalidated: usize,
+}
+
+/// The cache keys for the request's URI, created exactly as for a `GET` (and `HEAD`, if
+/// cacheable), for all known variants (see [AdminMethodConfig]). Partitioned paths (see
+/// [PartitionPolicy](super::partition::PartitionPolicy)) get the keys of the request's own
+/// partition.
+///
+/// The request's method is changed while creating the keys and restored afterwards.
+pub fn uri_cache_keys<RequestBodyT, CacheT, CacheKeyT>(
+    request: &mut Request<RequestBodyT>,
+    configuration: &MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+) -> Vec<CacheKeyT>
+where
+    CacheKeyT: CacheKey,
+{
+    let origin = RequestOrigin::resolve(request, configuration.trusted_forwarded.as_ref());
+
+    let languages: Vec<_> = match &configuration.language_negotiation {
+        Some(language_negotiation) => {
+            language_negotiation.supported.iter().cloned().map(Some).collect()
+        }
+
+        None => vec![None],
+    };
+
+    let generations: Vec<_> = match &configuration.generations {
+        Some(generations) => {
+            let mut current = vec![Some(generations.current())];
+            current.extend(generations.previous().map(Some));
+            current
+        }
+
+        None => vec![None],
+    };
+
+    let partition = configuration
+        .partition
+        .as_ref()
+        .and_then(|partition_policy| partition_policy.resolve(request));
+
+    let original_method = request.method().clone();
+    let mut keys = Vec::default();
+    for method in [Method::GET, Method::HEAD] {
+        if !configuration.methods.allows(&method) {
+            continue;
+        }
+
+        *request.method_mut() = method;
+        for language in &languages {
+            let mut key =
+                request.cache_key_for_origin(language.as_ref(), &origin, configuration);
+
+            if let Some(partition) = &partition {
+                key.set_partition(partition.identifier());
+            }
+
+            // Without observing values, which would affect cache-busting for real requests
+            for bust_params in &configuration.bust_params {
+                bust_params.strip(request.uri(), &mut key);
+            }
+
+            for generation in &generations {
+                let mut key = key.clone();
+                if let Some(generation) = generation {
+                    key.set_generation(*generation);
+                }
+
+                // By default `HEAD` is looked up under the `GET` keys
+                if !keys.contains(&key) {
+                    keys.push(key);
+                }
+            }
+        }
+    }
+
+    *request.method_mut() = original_method;
+    keys
+}
+
+/// Invalidate the entries for the request's URI (see [uri_cache_keys]).
+///
+/// Returns the number of invalidated entries.
+pub async fn invalidate_uri_cache_keys<RequestBodyT, CacheT, CacheKeyT>(
+    request: &mut Request<RequestBodyT>,
+    configuration: &MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+) -> usize
+where
+    CacheT: Cache<CacheKeyT>,
+    CacheKeyT: CacheKey,
+{
+    let Some(cache) = &configuration.cache else {
+        return 0;
+    };
+
+    let mut invalidated = 0;
+    for key in uri_cache_keys(request, configuration) {
+        if cache.get(&key).await.is_some() {
+            cache.invalidate(&key).await;
+            invalidated += 1;
+        }
+    }
+
+    invalidated
+}
+
+// Whether an entry has a tag.
+fn has_tag(cached_response: &CachedResponse, tag_header: &HeaderName, tag: &str) -> bool {
+    cached_response
+        .headers()
+        .get_all(tag_header)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split([',', ' ']))
+        .any(|value| value.trim() == tag)
+}
+
+// Compare without short-circuiting, so that timing doesn't reveal the secret.
+fn constant_time_eq(value: &[u8], other: &[u8]) -> bool {
+    value.len() == other.len()
+        && value
+            .iter()
+            .zip(other)
+            .fold(0, |difference, (byte, other_byte)| difference | (byte ^ other_byte))
+            == 0
+}
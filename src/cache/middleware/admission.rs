@@ -0,0 +1,303 @@
+use super::super::response::*;
+
+use std::{
+    fmt,
+    hash::*,
+    mem,
+    sync::{atomic::*, *},
+};
+
+// Counters per key in each filter (double hashing).
+const DEPTH: usize = 4;
+
+//
+// AdmissionConfig
+//
+
+/// Configuration for [AdmissionPolicy].
+#[derive(Clone, Copy, Debug)]
+pub struct AdmissionConfig {
+    /// Counters per filter generation.
+    ///
+    /// Memory use is 6 bytes per counter: two generations each of the frequency sketch and the
+    /// negative and positive histories.
+    pub width: usize,
+
+    /// Filters rotate (the current generation becomes the previous one, and the previous one is
+    /// discarded) after this many insertions, so that old observations decay.
+    pub rotate_every: u64,
+
+    /// Observations required for admission, e.g. 2 for "cache on second request".
+    pub required_observations: u8,
+
+    /// Observations required for keys whose entries were previously evicted with at most
+    /// `one_hit_wonder_hits` hits.
+    pub negative_required_observations: u8,
+
+    /// An evicted entry with at most this many hits is a "one-hit wonder".
+    pub one_hit_wonder_hits: u64,
+
+    /// Keys whose entries were previously evicted with at least this many hits are admitted on
+    /// first observation.
+    ///
+    /// [None] to disable the fast path.
+    pub positive_hits: Option<u64>,
+}
+
+impl Default for AdmissionConfig {
+    fn default() -> Self {
+        Self {
+            width: 16 * 1024,
+            rotate_every: 10 * 16 * 1024,
+            required_observations: 2,
+            negative_required_observations: 4,
+            one_hit_wonder_hits: 1,
+            positive_hits: Some(8),
+        }
+    }
+}
+
+//
+// AdmissionPolicy
+//
+
+/// Admission policy for storing misses.
+///
+/// Keys are admitted into the cache only after they have been observed (missed) the required
+/// number of times, as estimated by a frequency sketch. This keeps one-off requests from evicting
+/// useful entries.
+///
+/// The decision also consults an [AdmissionHistory] of evicted entries: keys whose entries were
+/// "one-hit wonders" must clear a higher bar, while keys whose entries earned many hits are
+/// admitted immediately. History requires the cache implementation to call
+/// [record_eviction](Self::record_eviction), e.g. from Moka's eviction listener.
+///
+/// All structures are fixed-size (see [AdmissionConfig::width]) regardless of the number of keys.
+/// Being probabilistic they can have false positives, but the worst case is only that a key is
+/// admitted one request later (or sooner) than it should be. It never affects the correctness of
+/// cached data.
+///
+/// Cloning is cheap and clones share state.
+#[derive(Clone)]
+pub struct AdmissionPolicy {
+    state: Arc<AdmissionState>,
+}
+
+impl AdmissionPolicy {
+    /// Constructor.
+    pub fn new(configuration: AdmissionConfig) -> Self {
+        Self {
+            state: Arc::new(AdmissionState {
+                configuration,
+                hasher: Default::default(),
+                frequency: Mutex::new(RotatingFilter::new(
+                    configuration.width,
+                    configuration.rotate_every,
+                )),
+                history: AdmissionHistory::new(configuration.width, configuration.rotate_every),
+                admitted: Default::default(),
+                rejected: Default::default(),
+                penalized: Default::default(),
+                fast_tracked: Default::default(),
+            }),
+        }
+    }
+
+    /// Observe a miss for a key and decide whether to admit its response.
+    pub fn admit(&self, key: &impl Hash) -> bool {
+        let configuration = &self.state.configuration;
+        let hash = self.state.hasher.hash_one(key);
+
+        let observations = self.state.frequency.lock().expect("lock").increment(hash);
+
+        if configuration.positive_hits.is_some() && self.state.history.is_positive(hash) {
+            self.state.fast_tracked.fetch_add(1, Ordering::Relaxed);
+            self.state.admitted.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+
+        let negative = self.state.history.is_negative(hash);
+        let required = if negative {
+            configuration.negative_required_observations
+        } else {
+            configuration.required_observations
+        };
+
+        if observations >= required {
+            self.state.admitted.fetch_add(1, Ordering::Relaxed);
+            true
+        } else {
+            if negative && observations >= configuration.required_observations {
+                self.state.penalized.fetch_add(1, Ordering::Relaxed);
+            }
+            self.state.rejected.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    }
+
+    /// Record an eviction.
+    pub fn record_eviction(&self, key: &impl Hash, cached_response: &CachedResponse) {
+        let configuration = &self.state.configuration;
+        let hash = self.state.hasher.hash_one(key);
+        let hits = cached_response.hits.load(Ordering::Relaxed);
+
+        if hits <= configuration.one_hit_wonder_hits {
+            self.state.history.record_negative(hash);
+        } else if let Some(positive_hits) = configuration.positive_hits
+            && hits >= positive_hits
+        {
+            self.state.history.record_positive(hash);
+        }
+    }
+
+    /// Statistics.
+    pub fn stats(&self) -> AdmissionStats {
+        AdmissionStats {
+            admitted: self.state.admitted.load(Ordering::Relaxed),
+            rejected: self.state.rejected.load(Ordering::Relaxed),
+            penalized: self.state.penalized.load(Ordering::Relaxed),
+            fast_tracked: self.state.fast_tracked.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for AdmissionPolicy {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl fmt::Debug for AdmissionPolicy {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("AdmissionPolicy")
+            .field("configuration", &self.state.configuration)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+//
+// AdmissionStats
+//
+
+/// [AdmissionPolicy] statistics.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AdmissionStats {
+    /// Admitted misses.
+    pub admitted: u64,
+
+    /// Rejected misses.
+    pub rejected: u64,
+
+    /// Rejected misses that would have been admitted if not for negative history.
+    pub penalized: u64,
+
+    /// Misses admitted on first observation due to positive history.
+    pub fast_tracked: u64,
+}
+
+//
+// AdmissionHistory
+//
+
+/// Outcomes of evicted entries, for [AdmissionPolicy].
+///
+/// Negative (one-hit wonders) and positive (many hits) outcomes are kept in separate rotating
+/// counting filters of key hashes.
+pub struct AdmissionHistory {
+    negative: Mutex<RotatingFilter>,
+    positive: Mutex<RotatingFilter>,
+}
+
+impl AdmissionHistory {
+    fn new(width: usize, rotate_every: u64) -> Self {
+        Self {
+            negative: Mutex::new(RotatingFilter::new(width, rotate_every)),
+            positive: Mutex::new(RotatingFilter::new(width, rotate_every)),
+        }
+    }
+
+    fn record_negative(&self, hash: u64) {
+        self.negative.lock().expect("lock").increment(hash);
+    }
+
+    fn record_positive(&self, hash: u64) {
+        self.positive.lock().expect("lock").increment(hash);
+    }
+
+    fn is_negative(&self, hash: u64) -> bool {
+        self.negative.lock().expect("lock").estimate(hash) > 0
+    }
+
+    fn is_positive(&self, hash: u64) -> bool {
+        self.positive.lock().expect("lock").estimate(hash) > 0
+    }
+}
+
+struct AdmissionState {
+    configuration: AdmissionConfig,
+    hasher: RandomState,
+    frequency: Mutex<RotatingFilter>,
+    history: AdmissionHistory,
+    admitted: AtomicU64,
+    rejected: AtomicU64,
+    penalized: AtomicU64,
+    fast_tracked: AtomicU64,
+}
+
+// Two generations of a counting filter with saturating counters. Estimates can only be too high
+// (due to collisions), never too low, until the observation is rotated out.
+struct RotatingFilter {
+    current: Vec<u8>,
+    previous: Vec<u8>,
+    insertions: u64,
+    rotate_every: u64,
+}
+
+impl RotatingFilter {
+    fn new(width: usize, rotate_every: u64) -> Self {
+        let width = width.max(DEPTH);
+        Self {
+            current: vec![0; width],
+            previous: vec![0; width],
+            insertions: 0,
+            rotate_every: rotate_every.max(1),
+        }
+    }
+
+    // Returns the new estimate.
+    fn increment(&mut self, hash: u64) -> u8 {
+        self.insertions += 1;
+        if self.insertions >= self.rotate_every {
+            mem::swap(&mut self.current, &mut self.previous);
+            self.current.fill(0);
+            self.insertions = 0;
+        }
+
+        let width = self.current.len();
+        for index in indexes(hash, width) {
+            self.current[index] = self.current[index].saturating_add(1);
+        }
+
+        self.estimate(hash)
+    }
+
+    fn estimate(&self, hash: u64) -> u8 {
+        let width = self.current.len();
+        let mut current = u8::MAX;
+        let mut previous = u8::MAX;
+        for index in indexes(hash, width) {
+            current = current.min(self.current[index]);
+            previous = previous.min(self.previous[index]);
+        }
+        current.max(previous)
+    }
+}
+
+// Kirsch-Mitzenmacher double hashing.
+fn indexes(hash: u64, width: usize) -> impl Iterator<Item = usize> {
+    let (first, second) = (hash as u32 as u64, (hash >> 32) | 1);
+    (0..DEPTH as u64)
+        .map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % width as u64) as usize)
+}
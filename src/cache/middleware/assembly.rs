@@ -0,0 +1,415 @@
+use super::{super::key::*, immutable::*};
+
+use {
+    http::header::*,
+    kutil::std::{collections::*, immutable::*},
+    std::{fmt, ops::Range, result::Result, sync::*, time::*},
+};
+
+/// Default time-to-live of incomplete [RangeAssembly] assemblies.
+pub const DEFAULT_ASSEMBLY_TTL: Duration = Duration::from_secs(30);
+
+/// Default maximum total size of in-flight [RangeAssembly] assemblies (64 MiB).
+pub const DEFAULT_MAX_ASSEMBLING_SIZE: usize = 64 * 1024 * 1024;
+
+//
+// RangeAssembly
+//
+
+/// Assembles full representations from upstream `206 Partial Content` responses.
+///
+/// Intended for upstreams that answer ranged requests efficiently but full requests slowly (e.g.
+/// object stores). For matching paths, a `206` whose `Content-Range` declares a total size within
+/// `max_object_size` is passed through to the client as is, while its bytes are kept in a
+/// [PartialBody] for the cache key. Once the received ranges cover the whole object it is
+/// promoted to a normal cache entry, with the headers of the most recent contribution (minus the
+/// range-specific ones), and is subject to the usual cacheability checks.
+///
+/// Only Identity (not encoded) ranges with a known total size are assembled.
+///
+/// Incomplete assemblies are dropped after the TTL. An assembly is abandoned if a contribution
+/// conflicts with it: a different `ETag` or total size (the representation changed), or
+/// overlapping bytes that differ. New assemblies are refused if they would exceed the maximum
+/// total size of in-flight assemblies.
+///
+/// Once assembled, ranges are served from the entry (see
+/// [serve_ranges](super::super::super::CachingLayer::serve_ranges)).
+///
+/// Requires the `range-assembly` feature.
+///
+/// Cloning is cheap and clones share state.
+pub struct RangeAssembly<CacheKeyT> {
+    /// Matcher.
+    pub matcher: PathMatcher,
+
+    /// Maximum object size.
+    pub max_object_size: usize,
+
+    /// Time-to-live of incomplete assemblies.
+    pub ttl: Duration,
+
+    /// Maximum total size of in-flight assemblies.
+    pub max_assembling_size: usize,
+
+    state: Arc<Mutex<RangeAssemblyState<CacheKeyT>>>,
+}
+
+impl<CacheKeyT> RangeAssembly<CacheKeyT>
+where
+    CacheKeyT: CacheKey,
+{
+    /// Constructor.
+    pub fn new(matcher: PathMatcher, max_object_size: usize) -> Self {
+        Self {
+            matcher,
+            max_object_size,
+            ttl: DEFAULT_ASSEMBLY_TTL,
+            max_assembling_size: DEFAULT_MAX_ASSEMBLING_SIZE,
+            state: Default::default(),
+        }
+    }
+
+    /// Set time-to-live of incomplete assemblies.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Set maximum total size of in-flight assemblies.
+    pub fn max_assembling_size(mut self, max_assembling_size: usize) -> Self {
+        self.max_assembling_size = max_assembling_size;
+        self
+    }
+
+    /// The `Content-Range` of an upstream response if we should assemble it.
+    pub fn assemblable(&self, path: &str, response_headers: &HeaderMap) -> Option<ContentRange> {
+        if !self.matcher.matches(path) || response_headers.contains_key(CONTENT_ENCODING) {
+            return None;
+        }
+
+        let content_range = ContentRange::from_headers(response_headers)?;
+        (content_range.total <= self.max_object_size).then_some(content_range)
+    }
+
+    /// Contribute a received range.
+    ///
+    /// Returns the complete object's headers and bytes if this contribution completed it.
+    pub fn contribute(
+        &self,
+        key: &CacheKeyT,
+        content_range: &ContentRange,
+        headers: &HeaderMap,
+        bytes: &[u8],
+    ) -> Option<(HeaderMap, ImmutableBytes)> {
+        if bytes.len() != content_range.size() {
+            tracing::debug!("not assembling (incomplete range)");
+            return None;
+        }
+
+        let mut state = self.state.lock().expect("lock");
+        state.expire(self.ttl);
+
+        if !state.assemblies.contains_key(key) {
+            if state.assembling_size + content_range.total > self.max_assembling_size {
+                tracing::debug!("not assembling (too many in flight)");
+                state.refused += 1;
+                return None;
+            }
+
+            state.assembling_size += content_range.total;
+            state
+                .assemblies
+                .insert(key.clone(), PartialBody::new(content_range.total, headers));
+        }
+
+        let partial_body = state.assemblies.get_mut(key).expect("assembly");
+        let conflict = partial_body.add(content_range, headers, bytes).is_err();
+        let complete = partial_body.is_complete();
+
+        if conflict || complete {
+            let partial_body = state.assemblies.remove(key).expect("assembly");
+            state.assembling_size -= partial_body.total_size;
+
+            if conflict {
+                tracing::debug!("assembly abandoned (conflict)");
+                state.abandoned += 1;
+                return None;
+            }
+
+            tracing::debug!("assembly complete");
+            state.completed += 1;
+            return Some(partial_body.into_parts());
+        }
+
+        None
+    }
+
+    /// Statistics.
+    pub fn stats(&self) -> RangeAssemblyStats {
+        let state = self.state.lock().expect("lock");
+        RangeAssemblyStats {
+            in_flight: state.assemblies.len(),
+            assembling_size: state.assembling_size,
+            completed: state.completed,
+            abandoned: state.abandoned,
+            expired: state.expired,
+            refused: state.refused,
+        }
+    }
+}
+
+impl<CacheKeyT> Clone for RangeAssembly<CacheKeyT> {
+    fn clone(&self) -> Self {
+        // Not derived, because that would require CacheKeyT to be Clone
+        Self {
+            matcher: self.matcher.clone(),
+            max_object_size: self.max_object_size,
+            ttl: self.ttl,
+            max_assembling_size: self.max_assembling_size,
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<CacheKeyT> fmt::Debug for RangeAssembly<CacheKeyT> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("RangeAssembly")
+            .field("matcher", &self.matcher)
+            .field("max_object_size", &self.max_object_size)
+            .field("ttl", &self.ttl)
+            .field("max_assembling_size", &self.max_assembling_size)
+            .finish()
+    }
+}
+
+//
+// RangeAssemblyStats
+//
+
+/// [RangeAssembly] statistics.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RangeAssemblyStats {
+    /// Number of in-flight assemblies.
+    pub in_flight: usize,
+
+    /// Total size of in-flight assemblies.
+    pub assembling_size: usize,
+
+    /// Number of completed assemblies.
+    pub completed: u64,
+
+    /// Number of assemblies abandoned due to conflicting contributions.
+    pub abandoned: u64,
+
+    /// Number of incomplete assemblies dropped after their TTL.
+    pub expired: u64,
+
+    /// Number of assemblies refused due to the maximum total size.
+    pub refused: u64,
+}
+
+//
+// PartialBody
+//
+
+/// Sparse body assembled from received ranges.
+///
+/// The buffer is allocated for the whole object up front, so its size is known when admitting
+/// it.
+#[derive(Clone, Debug)]
+pub struct PartialBody {
+    /// Total size.
+    pub total_size: usize,
+
+    /// Headers of the most recent contribution.
+    pub headers: HeaderMap,
+
+    /// When the assembly started.
+    pub started: Instant,
+
+    bytes: Vec<u8>,
+
+    // Sorted, disjoint, and non-adjacent
+    received: Vec<Range<usize>>,
+}
+
+impl PartialBody {
+    /// Constructor.
+    pub fn new(total_size: usize, headers: &HeaderMap) -> Self {
+        Self {
+            total_size,
+            headers: headers.clone(),
+            started: Instant::now(),
+            bytes: vec![0; total_size],
+            received: Default::default(),
+        }
+    }
+
+    /// Add a received range.
+    ///
+    /// Returns an error if it conflicts with what was received so far.
+    pub fn add(
+        &mut self,
+        content_range: &ContentRange,
+        headers: &HeaderMap,
+        bytes: &[u8],
+    ) -> Result<(), AssemblyConflict> {
+        if content_range.total != self.total_size
+            || headers.get(ETAG) != self.headers.get(ETAG)
+        {
+            return Err(AssemblyConflict);
+        }
+
+        let range = content_range.start..content_range.end + 1;
+
+        // Overlapping bytes must be identical
+        for received in &self.received {
+            let start = received.start.max(range.start);
+            let end = received.end.min(range.end);
+            if start < end
+                && self.bytes[start..end] != bytes[start - range.start..end - range.start]
+            {
+                return Err(AssemblyConflict);
+            }
+        }
+
+        self.bytes[range.clone()].copy_from_slice(bytes);
+        self.headers = headers.clone();
+
+        // Merge with overlapping and adjacent ranges
+        let mut merged = range;
+        self.received.retain(|received| {
+            if received.start <= merged.end && merged.start <= received.end {
+                merged.start = merged.start.min(received.start);
+                merged.end = merged.end.max(received.end);
+                false
+            } else {
+                true
+            }
+        });
+        let index = self.received.partition_point(|received| received.start < merged.start);
+        self.received.insert(index, merged);
+
+        Ok(())
+    }
+
+    /// Number of bytes received.
+    pub fn received(&self) -> usize {
+        self.received.iter().map(|received| received.len()).sum()
+    }
+
+    /// Whether the whole object was received.
+    pub fn is_complete(&self) -> bool {
+        self.received.first() == Some(&(0..self.total_size))
+    }
+
+    /// Into the headers (without the range-specific ones) and bytes.
+    pub fn into_parts(mut self) -> (HeaderMap, ImmutableBytes) {
+        self.headers.remove(CONTENT_RANGE);
+        self.headers.remove(CONTENT_LENGTH);
+        (self.headers, self.bytes.into())
+    }
+}
+
+//
+// ContentRange
+//
+
+/// Parsed `Content-Range` response header for a byte range with a known total size.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ContentRange {
+    /// First byte position.
+    pub start: usize,
+
+    /// Last byte position (inclusive).
+    pub end: usize,
+
+    /// Total size.
+    pub total: usize,
+}
+
+impl ContentRange {
+    /// Parse from response headers.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        Self::parse(headers.get(CONTENT_RANGE)?.to_str().ok()?)
+    }
+
+    /// Parse, e.g. `bytes 0-499/1234`.
+    pub fn parse(content_range: &str) -> Option<Self> {
+        let (range, total) = content_range.trim().strip_prefix("bytes ")?.split_once('/')?;
+        let (start, end) = range.split_once('-')?;
+
+        let content_range = Self {
+            start: start.trim().parse().ok()?,
+            end: end.trim().parse().ok()?,
+            total: total.trim().parse().ok()?,
+        };
+
+        (content_range.start <= content_range.end && content_range.end < content_range.total)
+            .then_some(content_range)
+    }
+
+    /// Size of the range.
+    pub fn size(&self) -> usize {
+        self.end - self.start + 1
+    }
+}
+
+//
+// AssemblyConflict
+//
+
+/// A contribution conflicts with a [PartialBody].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AssemblyConflict;
+
+impl fmt::Display for AssemblyConflict {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt("conflicting range", formatter)
+    }
+}
+
+struct RangeAssemblyState<CacheKeyT> {
+    assemblies: FastHashMap<CacheKeyT, PartialBody>,
+    assembling_size: usize,
+    completed: u64,
+    abandoned: u64,
+    expired: u64,
+    refused: u64,
+}
+
+impl<CacheKeyT> RangeAssemblyState<CacheKeyT> {
+    fn expire(&mut self, ttl: Duration) {
+        let mut expired = 0;
+        let mut expired_size = 0;
+        self.assemblies.retain(|_key, partial_body| {
+            if partial_body.started.elapsed() < ttl {
+                true
+            } else {
+                expired += 1;
+                expired_size += partial_body.total_size;
+                false
+            }
+        });
+
+        if expired != 0 {
+            tracing::debug!("assemblies expired: {}", expired);
+            self.expired += expired;
+            self.assembling_size -= expired_size;
+        }
+    }
+}
+
+impl<CacheKeyT> Default for RangeAssemblyState<CacheKeyT> {
+    fn default() -> Self {
+        Self {
+            assemblies: FastHashMap::default(),
+            assembling_size: 0,
+            completed: 0,
+            abandoned: 0,
+            expired: 0,
+            refused: 0,
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use super::{hooks::*, request_body::*};
+
+use {
+    http::*,
+    http_body::*,
+    kutil::{
+        http::*,
+        std::{error::*, immutable::*},
+    },
+};
+
+//
+// BodyKey
+//
+
+/// Cache keys that depend on the request body, e.g. for read-only `POST` APIs such as GraphQL.
+///
+/// Applies to requests that have a body or a non-idempotent method. Their body is buffered and
+/// handed to the hook, which can update the cache key (e.g. with a digest of the query), and is
+/// then put back into the request for the inner service. Request trailers are discarded.
+///
+/// Only bodies with a declared `Content-Length` of at most `max_body_size` are buffered. Other
+/// requests skip the cache, and their body is not read. A body that is longer than declared
+/// can't be put back, so such a request is answered with `400 Bad Request`.
+///
+/// See [cache_key_with_body](super::super::super::CachingLayer::cache_key_with_body).
+pub struct BodyKey<CacheKeyT, RequestBodyT> {
+    /// Maximum body size.
+    pub max_body_size: usize,
+
+    /// Cache key (hook).
+    pub hook: CacheKeyBodyHook<CacheKeyT, RequestBodyT>,
+
+    buffer: RequestBodyBuffer<RequestBodyT>,
+}
+
+impl<CacheKeyT, RequestBodyT> BodyKey<CacheKeyT, RequestBodyT> {
+    /// Constructor.
+    pub fn new(max_body_size: usize, hook: CacheKeyBodyHook<CacheKeyT, RequestBodyT>) -> Self
+    where
+        RequestBodyT: 'static + Body + From<ImmutableBytes> + Send + Unpin,
+        RequestBodyT::Error: Into<CapturedError>,
+    {
+        Self {
+            max_body_size,
+            hook,
+            buffer: RequestBodyBuffer::new(),
+        }
+    }
+
+    /// Whether the request is keyed by its body.
+    pub fn applies(&self, request: &Request<RequestBodyT>) -> bool {
+        !request.method().is_idempotent()
+            || request.headers().content_length().is_some_and(|size| size > 0)
+    }
+
+    /// Buffer the request body and call the hook.
+    pub async fn apply(
+        &self,
+        request: Request<RequestBodyT>,
+        cache_key: &mut CacheKeyT,
+    ) -> BodyKeyOutcome<RequestBodyT> {
+        match self.buffer.buffer(request, self.max_body_size).await {
+            BufferedRequestBody::Buffered(request, body) => {
+                (self.hook)(CacheKeyBodyHookContext::new(cache_key, &request, &body));
+                BodyKeyOutcome::Keyed(request)
+            }
+
+            BufferedRequestBody::NotBuffered(request) => BodyKeyOutcome::Skip(request),
+            BufferedRequestBody::Failed(error) => BodyKeyOutcome::Failed(error),
+        }
+    }
+}
+
+impl<CacheKeyT, RequestBodyT> Clone for BodyKey<CacheKeyT, RequestBodyT> {
+    fn clone(&self) -> Self {
+        Self {
+            max_body_size: self.max_body_size,
+            hook: self.hook.clone(),
+            buffer: self.buffer.clone(),
+        }
+    }
+}
+
+//
+// BodyKeyOutcome
+//
+
+/// Outcome of [BodyKey::apply].
+pub enum BodyKeyOutcome<RequestBodyT> {
+    /// The cache key was updated. The request has the buffered body.
+    Keyed(Request<RequestBodyT>),
+
+    /// The body was not read, and the request should skip the cache.
+    Skip(Request<RequestBodyT>),
+
+    /// The body could not be read, and the request is lost.
+    Failed(String),
+}
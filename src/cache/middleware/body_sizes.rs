@@ -0,0 +1,146 @@
+use super::super::response::*;
+
+use std::sync::{atomic::*, *};
+
+/// Number of [BodySizeObserver] buckets.
+///
+/// Bucket `i` counts sizes up to 2^(i+8) bytes (256 bytes for the first bucket, 1 GiB for the
+/// last finite bucket). The last bucket counts larger sizes.
+pub const BODY_SIZE_BUCKETS: usize = 24;
+
+//
+// BodySizeObserver
+//
+
+/// Observes the body sizes of cacheable responses, in order to help choose
+/// `max_cacheable_body_size`.
+///
+/// Only responses that pass all other cacheability checks are observed, so that the distribution
+/// reflects what we would actually cache. For each size bucket it counts:
+///
+/// * `stored`: responses that were stored.
+/// * `rejected`: responses that were not stored because they were too big. The declared
+///   `Content-Length` is used if available. Otherwise, because we stop reading at the maximum,
+///   the size is only known to be greater than the maximum, and that is what's recorded.
+/// * `hits`: hits of entries by their size.
+///
+/// Each observation is a single atomic increment.
+///
+/// See [recommend_max_body_size](Self::recommend_max_body_size).
+///
+/// Cloning is cheap and clones share state.
+#[derive(Clone, Debug, Default)]
+pub struct BodySizeObserver {
+    state: Arc<BodySizeObserverState>,
+}
+
+impl BodySizeObserver {
+    /// Record a stored entry.
+    pub fn record_stored(&self, cached_response: &CachedResponse) {
+        self.state.stored[bucket(body_size(cached_response))].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a response rejected for being too big.
+    pub fn record_rejected(&self, size: usize) {
+        self.state.rejected[bucket(size)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a hit.
+    pub fn record_hit(&self, cached_response: &CachedResponse) {
+        self.state.hits[bucket(body_size(cached_response))].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The histogram, from the smallest to the largest bucket.
+    pub fn histogram(&self) -> Vec<BodySizeBucket> {
+        (0..BODY_SIZE_BUCKETS)
+            .map(|index| BodySizeBucket {
+                upper_bound: upper_bound(index),
+                stored: self.state.stored[index].load(Ordering::Relaxed),
+                rejected: self.state.rejected[index].load(Ordering::Relaxed),
+                hits: self.state.hits[index].load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Recommend a `max_cacheable_body_size` that would admit a fraction (0 to 1) of the observed
+    /// traffic.
+    ///
+    /// Traffic is the sum of all counts, so that size classes are weighted by their hits. The
+    /// recommendation is the upper bound of the smallest bucket at which the cumulative traffic
+    /// reaches the target fraction.
+    ///
+    /// It is purely advisory and never applied. Returns [None] if nothing was observed or if the
+    /// target can only be reached with the last (unbounded) bucket.
+    pub fn recommend_max_body_size(&self, target_fraction: f64) -> Option<usize> {
+        let traffic: Vec<_> = self.histogram().iter().map(BodySizeBucket::traffic).collect();
+
+        let total: u64 = traffic.iter().sum();
+        if total == 0 {
+            return None;
+        }
+
+        let target = total as f64 * target_fraction.clamp(0., 1.);
+        let mut cumulative = 0;
+        for (index, traffic) in traffic.into_iter().enumerate() {
+            cumulative += traffic;
+            if cumulative as f64 >= target {
+                return upper_bound(index);
+            }
+        }
+
+        None
+    }
+}
+
+//
+// BodySizeBucket
+//
+
+/// [BodySizeObserver] histogram bucket.
+#[derive(Clone, Copy, Debug)]
+pub struct BodySizeBucket {
+    /// Inclusive upper bound of the size ([None] for the last bucket).
+    pub upper_bound: Option<usize>,
+
+    /// Stored responses.
+    pub stored: u64,
+
+    /// Responses rejected for being too big.
+    pub rejected: u64,
+
+    /// Hits.
+    pub hits: u64,
+}
+
+impl BodySizeBucket {
+    /// Traffic (the sum of all counts).
+    pub fn traffic(&self) -> u64 {
+        self.stored + self.rejected + self.hits
+    }
+}
+
+#[derive(Debug, Default)]
+struct BodySizeObserverState {
+    stored: [AtomicU64; BODY_SIZE_BUCKETS],
+    rejected: [AtomicU64; BODY_SIZE_BUCKETS],
+    hits: [AtomicU64; BODY_SIZE_BUCKETS],
+}
+
+// Size of the body as received from the upstream.
+fn body_size(cached_response: &CachedResponse) -> usize {
+    let representations = &cached_response.body.representations;
+    match representations.get(&cached_response.original_coding) {
+        Some(bytes) => bytes.len(),
+        None => representations.values().map(|bytes| bytes.len()).max().unwrap_or_default(),
+    }
+}
+
+// Index of the smallest bucket with an upper bound that is at least the size.
+fn bucket(size: usize) -> usize {
+    let bits = usize::BITS - size.saturating_sub(1).leading_zeros();
+    (bits.saturating_sub(8) as usize).min(BODY_SIZE_BUCKETS - 1)
+}
+
+fn upper_bound(index: usize) -> Option<usize> {
+    (index < BODY_SIZE_BUCKETS - 1).then(|| 1 << (index + 8))
+}
@@ -0,0 +1,248 @@
+use std::{
+    fmt,
+    sync::{atomic::*, *},
+    time::*,
+};
+
+//
+// BudgetPhase
+//
+
+/// Phase in which an [OverheadBudget] was exhausted.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum BudgetPhase {
+    /// Cache verification, invalidation, or lookup.
+    Lookup,
+
+    /// Waiting for a per-resource upstream permit.
+    Queue,
+
+    /// Serving a hit.
+    Hit,
+
+    /// Handling a miss (before storing).
+    Miss,
+
+    /// Storing (or refreshing) an entry and serving it.
+    Store,
+}
+
+impl BudgetPhase {
+    /// All phases.
+    pub const ALL: [Self; 5] = [
+        Self::Lookup,
+        Self::Queue,
+        Self::Hit,
+        Self::Miss,
+        Self::Store,
+    ];
+
+    /// As a decision for the [DecisionTrail](super::trail::DecisionTrail).
+    pub fn decision(&self) -> &'static str {
+        match self {
+            Self::Lookup => "over budget (lookup)",
+            Self::Queue => "over budget (queue)",
+            Self::Hit => "over budget (hit)",
+            Self::Miss => "over budget (miss)",
+            Self::Store => "over budget (store)",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+impl fmt::Display for BudgetPhase {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(
+            match self {
+                Self::Lookup => "lookup",
+                Self::Queue => "queue",
+                Self::Hit => "hit",
+                Self::Miss => "miss",
+                Self::Store => "store",
+            },
+            formatter,
+        )
+    }
+}
+
+//
+// OverheadBudget
+//
+
+/// Maximum time that the caching layer may add to a request.
+///
+/// Each request gets an [OverheadDeadline]. Time spent awaiting the inner service is excluded,
+/// as that is the application's latency rather than ours. The deadline is checked before each
+/// potentially expensive operation, and such operations are abandoned when it passes. The layer
+/// then fails open, falling back to the most direct correct behavior available at that point:
+///
+/// * If the upstream response is in hand, it is served as is (uncached and not encoded).
+/// * If only a cache entry is in hand, a stored representation that needs no further work is
+///   served (the negotiated coding or Identity).
+/// * Otherwise the upstream is called and its response is served as is.
+///
+/// Exhausting the budget is never itself an error. It is counted per [BudgetPhase], and the
+/// response gets an [OverheadExceeded] extension.
+///
+/// Abandoning an operation midway requires the `overhead-budget` feature (Tokio timers). Without
+/// it the deadline is only checked between operations. Reading and encoding the body of a miss
+/// is never abandoned midway, because the upstream body would be lost, so for misses the budget
+/// is checked before it starts.
+///
+/// Cloning is cheap and clones share state.
+#[derive(Clone)]
+pub struct OverheadBudget {
+    state: Arc<OverheadBudgetState>,
+}
+
+impl OverheadBudget {
+    /// Constructor.
+    pub fn new(max_overhead: Duration) -> Self {
+        Self {
+            state: Arc::new(OverheadBudgetState {
+                max_overhead,
+                exhausted: Default::default(),
+            }),
+        }
+    }
+
+    /// Maximum overhead.
+    pub fn max_overhead(&self) -> Duration {
+        self.state.max_overhead
+    }
+
+    /// Deadline for a request starting now.
+    pub fn deadline(&self) -> OverheadDeadline {
+        OverheadDeadline {
+            budget: self.clone(),
+            start: Instant::now(),
+            exhausted: None,
+        }
+    }
+
+    /// Number of requests that exhausted the budget in a phase.
+    pub fn exhausted(&self, phase: BudgetPhase) -> u64 {
+        self.state.exhausted[phase.index()].load(Ordering::Relaxed)
+    }
+
+    /// Number of requests that exhausted the budget in each phase.
+    pub fn exhausted_by_phase(&self) -> Vec<(BudgetPhase, u64)> {
+        BudgetPhase::ALL
+            .iter()
+            .map(|phase| (*phase, self.exhausted(*phase)))
+            .collect()
+    }
+}
+
+impl fmt::Debug for OverheadBudget {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("OverheadBudget")
+            .field("max_overhead", &self.state.max_overhead)
+            .field("exhausted", &self.exhausted_by_phase())
+            .finish()
+    }
+}
+
+//
+// OverheadDeadline
+//
+
+/// [OverheadBudget] deadline for a request.
+#[derive(Clone, Debug)]
+pub struct OverheadDeadline {
+    budget: OverheadBudget,
+    start: Instant,
+    exhausted: Option<BudgetPhase>,
+}
+
+impl OverheadDeadline {
+    /// Overhead so far, excluding time spent awaiting the upstream.
+    pub fn overhead(&self, upstream: Duration) -> Duration {
+        self.start.elapsed().saturating_sub(upstream)
+    }
+
+    /// Remaining budget.
+    pub fn remaining(&self, upstream: Duration) -> Duration {
+        self.budget
+            .max_overhead()
+            .saturating_sub(self.overhead(upstream))
+    }
+
+    /// Whether the budget is exhausted, either because it was spent or because an operation was
+    /// abandoned.
+    pub fn is_exhausted(&self, upstream: Duration) -> bool {
+        self.exhausted.is_some() || self.remaining(upstream).is_zero()
+    }
+
+    /// The phase in which the budget was exhausted, if it was.
+    pub fn exhausted_phase(&self) -> Option<BudgetPhase> {
+        self.exhausted
+    }
+
+    /// Mark as exhausted.
+    ///
+    /// Only the first phase is counted.
+    pub fn exhaust(&mut self, phase: BudgetPhase) {
+        if self.exhausted.is_none() {
+            tracing::debug!("overhead budget exhausted ({})", phase);
+            self.budget.state.exhausted[phase.index()].fetch_add(1, Ordering::Relaxed);
+            self.exhausted = Some(phase);
+        }
+    }
+
+    /// Run a future within the remaining budget.
+    ///
+    /// Returns [None] if the budget is exhausted, in which case the future is not run to
+    /// completion. With the `overhead-budget` feature it is dropped as soon as the budget runs out
+    /// (though it is always polled at least once, so futures that can complete without waiting
+    /// are never abandoned). Without the feature it is only checked whether the budget is
+    /// exhausted before starting.
+    pub async fn within<FutureT>(
+        &self,
+        upstream: Duration,
+        future: FutureT,
+    ) -> Option<FutureT::Output>
+    where
+        FutureT: Future,
+    {
+        if self.exhausted.is_some() {
+            return None;
+        }
+
+        #[cfg(feature = "overhead-budget")]
+        {
+            ::tokio::time::timeout(self.remaining(upstream), future)
+                .await
+                .ok()
+        }
+
+        #[cfg(not(feature = "overhead-budget"))]
+        {
+            if self.remaining(upstream).is_zero() {
+                None
+            } else {
+                Some(future.await)
+            }
+        }
+    }
+}
+
+//
+// OverheadExceeded
+//
+
+/// Response extension for requests that exhausted the [OverheadBudget].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct OverheadExceeded {
+    /// Phase.
+    pub phase: BudgetPhase,
+}
+
+struct OverheadBudgetState {
+    max_overhead: Duration,
+    exhausted: [AtomicU64; BudgetPhase::ALL.len()],
+}
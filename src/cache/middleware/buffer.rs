@@ -0,0 +1,162 @@
+use std::sync::{atomic::*, *};
+
+//
+// BufferBudget
+//
+
+/// Budget of bytes buffered by all in-flight stores.
+///
+/// Per-response limits don't bound the aggregate: many concurrent misses that each buffer a body
+/// up to the maximum cacheable size can add up to more memory than is available, and this is
+/// most likely to happen during miss storms.
+///
+/// Before a miss is buffered we reserve its worst case: the declared `Content-Length` (or the
+/// maximum cacheable body size if it's not declared), twice if we will encode it (for the
+/// encoded copy). If the worst case doesn't fit but the body alone does, we store it without
+/// encoding. If even that doesn't fit then the response is passed through without caching.
+/// Requests never wait and never fail because of the budget.
+///
+/// Once the body is read, the reservation shrinks to the actual size. It is released when the
+/// store is staged, or if the request fails or is cancelled.
+///
+/// Cloning is cheap and clones share state.
+#[derive(Clone, Debug)]
+pub struct BufferBudget {
+    state: Arc<BufferBudgetState>,
+}
+
+impl BufferBudget {
+    /// Constructor.
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            state: Arc::new(BufferBudgetState {
+                max_bytes,
+                used: AtomicUsize::new(0),
+                high_water: AtomicUsize::new(0),
+                refused: AtomicU64::new(0),
+                reduced: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Reserve bytes, if they fit.
+    pub fn try_reserve(&self, bytes: usize) -> Option<BufferReservation> {
+        let reserved = self.state.used.fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+            used.checked_add(bytes).filter(|used| *used <= self.state.max_bytes)
+        });
+
+        if let Ok(used) = reserved {
+            self.state.high_water.fetch_max(used + bytes, Ordering::Relaxed);
+            Some(BufferReservation {
+                state: self.state.clone(),
+                bytes,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Reserve the worst case of a store (see [BufferBudget]).
+    ///
+    /// Returns the reservation and whether there is room for encoding.
+    pub fn reserve_store(
+        &self,
+        body_size: usize,
+        encode: bool,
+    ) -> Option<(BufferReservation, bool)> {
+        if encode && let Some(reservation) = self.try_reserve(body_size.saturating_mul(2)) {
+            return Some((reservation, true));
+        }
+
+        match self.try_reserve(body_size) {
+            Some(reservation) => {
+                if encode {
+                    self.state.reduced.fetch_add(1, Ordering::Relaxed);
+                }
+                Some((reservation, false))
+            }
+
+            None => {
+                self.state.refused.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Statistics.
+    pub fn stats(&self) -> BufferBudgetStats {
+        BufferBudgetStats {
+            max_bytes: self.state.max_bytes,
+            used: self.state.used.load(Ordering::Relaxed),
+            high_water: self.state.high_water.load(Ordering::Relaxed),
+            refused: self.state.refused.load(Ordering::Relaxed),
+            reduced: self.state.reduced.load(Ordering::Relaxed),
+        }
+    }
+}
+
+//
+// BufferReservation
+//
+
+/// Reservation of bytes from a [BufferBudget].
+///
+/// Released when dropped.
+#[derive(Debug)]
+pub struct BufferReservation {
+    state: Arc<BufferBudgetState>,
+    bytes: usize,
+}
+
+impl BufferReservation {
+    /// Reserved bytes.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Release bytes beyond this size.
+    pub fn shrink_to(&mut self, bytes: usize) {
+        if bytes < self.bytes {
+            self.state.used.fetch_sub(self.bytes - bytes, Ordering::AcqRel);
+            self.bytes = bytes;
+        }
+    }
+}
+
+impl Drop for BufferReservation {
+    fn drop(&mut self) {
+        self.shrink_to(0);
+    }
+}
+
+//
+// BufferBudgetStats
+//
+
+/// [BufferBudget] statistics.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BufferBudgetStats {
+    /// Budget.
+    pub max_bytes: usize,
+
+    /// Currently reserved bytes.
+    pub used: usize,
+
+    /// Highest reserved bytes.
+    pub high_water: usize,
+
+    /// Stores that were skipped because they didn't fit.
+    pub refused: u64,
+
+    /// Stores that were not encoded because the encoded copy didn't fit.
+    pub reduced: u64,
+}
+
+#[derive(Debug)]
+struct BufferBudgetState {
+    max_bytes: usize,
+    used: AtomicUsize,
+    high_water: AtomicUsize,
+    refused: AtomicU64,
+    reduced: AtomicU64,
+}
@@ -0,0 +1,216 @@
+use super::{super::key::*, immutable::*};
+
+use {
+    http::*,
+    kutil::{http::*, std::collections::*},
+    std::{cmp::*, fmt, mem::*, sync::*},
+};
+
+// Maximum number of (path, parameter) pairs to track or warn about.
+const MAX_TRACKED: usize = 16 * 1024;
+
+//
+// BustParamPolicy
+//
+
+/// Handling of cache-busting query parameters (e.g. `?v=<build>` or `?cachebust=<timestamp>`)
+/// for matching paths.
+///
+/// Cloning is cheap and clones share state.
+#[derive(Clone)]
+pub struct BustParamPolicy {
+    /// Matcher.
+    pub matcher: PathMatcher,
+
+    /// Parameters.
+    pub parameters: Vec<(String, BustParamMode)>,
+
+    /// Whether to compare values numerically when deciding which is older. Values that are not
+    /// numbers are always compared lexicographically.
+    pub numeric: bool,
+
+    /// Whether to warn (once per path) about stripped parameters, to help find the pattern.
+    pub warn_on_strip: bool,
+
+    state: Arc<BustParamState>,
+}
+
+impl BustParamPolicy {
+    /// Constructor.
+    pub fn new(matcher: PathMatcher) -> Self {
+        Self {
+            matcher,
+            parameters: Default::default(),
+            numeric: false,
+            warn_on_strip: false,
+            state: Default::default(),
+        }
+    }
+
+    /// Add a parameter.
+    pub fn parameter(mut self, name: impl ToString, mode: BustParamMode) -> Self {
+        self.parameters.push((name.to_string(), mode));
+        self
+    }
+
+    /// Set numeric comparison.
+    pub fn numeric(mut self, numeric: bool) -> Self {
+        self.numeric = numeric;
+        self
+    }
+
+    /// Set warn on strip.
+    pub fn warn_on_strip(mut self, warn_on_strip: bool) -> Self {
+        self.warn_on_strip = warn_on_strip;
+        self
+    }
+
+    /// Apply to a cache key.
+    ///
+    /// Returns the keys to invalidate, i.e. those with older values of
+    /// [KeyAndExpireOld](BustParamMode::KeyAndExpireOld) parameters.
+    pub fn apply<CacheKeyT>(&self, uri: &Uri, cache_key: &mut CacheKeyT) -> Vec<CacheKeyT>
+    where
+        CacheKeyT: CacheKey,
+    {
+        let mut invalidations = Vec::default();
+
+        let path = uri.path();
+        if !self.matcher.matches(path) {
+            return invalidations;
+        }
+
+        let Some(query) = uri
+            .path_and_query()
+            .and_then(|path_and_query| path_and_query.decoded_query_map())
+        else {
+            return invalidations;
+        };
+
+        for (name, mode) in &self.parameters {
+            // Multiple values make no sense for these conventions, so we use the greatest
+            let Some(value) = query.get(name.as_str()).and_then(|values| values.last()) else {
+                continue;
+            };
+
+            match mode {
+                BustParamMode::KeyAndExpireOld => {
+                    if let Some(old_value) = self.observe(path, name, value) {
+                        tracing::debug!("{} changed for {}: {} is stale", name, path, old_value);
+                        let mut old_cache_key = cache_key.clone();
+                        old_cache_key.set_query_parameter(name, Some(&old_value));
+                        invalidations.push(old_cache_key);
+                    }
+                }
+
+                BustParamMode::Strip => {
+                    cache_key.set_query_parameter(name, None);
+                    if self.warn_on_strip {
+                        self.warn(path, name);
+                    }
+                }
+            }
+        }
+
+        invalidations
+    }
+
+    /// Apply only the [Strip](BustParamMode::Strip) parameters to a cache key.
+    ///
+    /// Unlike [apply](Self::apply) this does not observe values.
+    pub fn strip<CacheKeyT>(&self, uri: &Uri, cache_key: &mut CacheKeyT)
+    where
+        CacheKeyT: CacheKey,
+    {
+        if !self.matcher.matches(uri.path()) {
+            return;
+        }
+
+        let Some(query) = uri
+            .path_and_query()
+            .and_then(|path_and_query| path_and_query.decoded_query_map())
+        else {
+            return;
+        };
+
+        for (name, mode) in &self.parameters {
+            if *mode == BustParamMode::Strip && query.contains_key(name.as_str()) {
+                cache_key.set_query_parameter(name, None);
+            }
+        }
+    }
+
+    // Record the value and return the previous one if the new one is newer.
+    fn observe(&self, path: &str, name: &str, value: &str) -> Option<String> {
+        let mut last_seen = self.state.last_seen.lock().expect("lock");
+        let key = (path.to_string(), name.to_string());
+
+        match last_seen.get_mut(&key) {
+            Some(last_value) => {
+                if self.compare(value, last_value) == Ordering::Greater {
+                    Some(replace(last_value, value.into()))
+                } else {
+                    None
+                }
+            }
+
+            None => {
+                if last_seen.len() < MAX_TRACKED {
+                    last_seen.insert(key, value.into());
+                }
+                None
+            }
+        }
+    }
+
+    fn compare(&self, value: &str, other_value: &str) -> Ordering {
+        if self.numeric
+            && let Ok(value) = value.parse::<f64>()
+            && let Ok(other_value) = other_value.parse::<f64>()
+        {
+            return value.total_cmp(&other_value);
+        }
+
+        value.cmp(other_value)
+    }
+
+    fn warn(&self, path: &str, name: &str) {
+        let mut warned = self.state.warned.lock().expect("lock");
+        if warned.len() < MAX_TRACKED && warned.insert(path.into()) {
+            tracing::warn!("stripping cache-busting parameter {} for {}", name, path);
+        }
+    }
+}
+
+impl fmt::Debug for BustParamPolicy {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("BustParamPolicy")
+            .field("matcher", &self.matcher)
+            .field("parameters", &self.parameters)
+            .field("numeric", &self.numeric)
+            .field("warn_on_strip", &self.warn_on_strip)
+            .finish()
+    }
+}
+
+//
+// BustParamMode
+//
+
+/// How to handle a cache-busting query parameter.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BustParamMode {
+    /// The parameter is part of the key. When a newer value is first seen for a path, the entry
+    /// for the previous value is invalidated.
+    KeyAndExpireOld,
+
+    /// The parameter is removed from the key so that it cannot pollute the cache.
+    Strip,
+}
+
+#[derive(Default)]
+struct BustParamState {
+    last_seen: Mutex<FastHashMap<(String, String), String>>,
+    warned: Mutex<FastHashSet<String>>,
+}
@@ -0,0 +1,195 @@
+use std::{
+    fmt,
+    sync::{atomic::*, *},
+    time::*,
+};
+
+// The state packs the deadline (milliseconds since `base`) with the mode in the lowest 2 bits.
+// Zero means no override.
+const MODE_BITS: u64 = 2;
+const MODE_MASK: u64 = (1 << MODE_BITS) - 1;
+const MODE_READS: u64 = 1;
+const MODE_ALL: u64 = 2;
+
+//
+// CacheOverride
+//
+
+/// Operational cache bypass ("big red button").
+///
+/// Intended for incident response, e.g. when you suspect the cache is serving bad data. An
+/// override is always time-boxed: it clears itself when its deadline passes, so nobody can forget
+/// to turn it off.
+///
+/// Checking it on the hot path is a single atomic load.
+///
+/// Cloning is cheap and clones share state.
+#[derive(Clone)]
+pub struct CacheOverride {
+    state: Arc<OverrideState>,
+}
+
+impl CacheOverride {
+    /// Stop serving from the cache, but keep storing, until the duration passes.
+    ///
+    /// Requests will go upstream and their responses will overwrite existing entries, so that the
+    /// cache re-converges to good data.
+    pub fn bypass_reads(&self, until: Duration) {
+        self.engage(BypassMode::Reads, until, None);
+    }
+
+    /// Stop both serving from and storing to the cache until the duration passes.
+    pub fn bypass_all(&self, until: Duration) {
+        self.engage(BypassMode::All, until, None);
+    }
+
+    /// Engage an override, recording who set it.
+    pub fn engage(&self, mode: BypassMode, until: Duration, set_by: Option<String>) {
+        let deadline = (self.state.base.elapsed() + until).as_millis() as u64;
+        let mode_bits = match mode {
+            BypassMode::Reads => MODE_READS,
+            BypassMode::All => MODE_ALL,
+        };
+
+        // Hold the lock so that the state and its setter stay consistent
+        let mut current_set_by = self.state.set_by.lock().expect("lock");
+        *current_set_by = set_by;
+        self.state
+            .state
+            .store((deadline << MODE_BITS) | mode_bits, Ordering::Release);
+
+        tracing::warn!(
+            "cache override engaged: {} for {:?}{}",
+            mode,
+            until,
+            current_set_by
+                .as_ref()
+                .map(|set_by| format!(" by {}", set_by))
+                .unwrap_or_default()
+        );
+    }
+
+    /// Resume normal behavior.
+    pub fn resume(&self) {
+        let mut set_by = self.state.set_by.lock().expect("lock");
+        *set_by = None;
+        self.state.state.store(0, Ordering::Release);
+        tracing::warn!("cache override resumed");
+    }
+
+    /// The active bypass mode, if any.
+    pub fn mode(&self) -> Option<BypassMode> {
+        let state = self.state.state.load(Ordering::Acquire);
+        if state == 0 {
+            return None;
+        }
+
+        if self.state.base.elapsed().as_millis() as u64 >= (state >> MODE_BITS) {
+            // Expired; only clear if nobody has changed it meanwhile
+            _ = self
+                .state
+                .state
+                .compare_exchange(state, 0, Ordering::AcqRel, Ordering::Relaxed);
+            return None;
+        }
+
+        match state & MODE_MASK {
+            MODE_READS => Some(BypassMode::Reads),
+            MODE_ALL => Some(BypassMode::All),
+            _ => None,
+        }
+    }
+
+    /// Status of the active override, if any.
+    pub fn status(&self) -> Option<OverrideStatus> {
+        let set_by = self.state.set_by.lock().expect("lock");
+
+        let state = self.state.state.load(Ordering::Acquire);
+        let mode = self.mode()?;
+        let deadline = Duration::from_millis(state >> MODE_BITS);
+
+        Some(OverrideStatus {
+            mode,
+            remaining: deadline.saturating_sub(self.state.base.elapsed()),
+            set_by: set_by.clone(),
+        })
+    }
+}
+
+impl Default for CacheOverride {
+    fn default() -> Self {
+        Self {
+            state: Arc::new(OverrideState {
+                base: Instant::now(),
+                state: AtomicU64::new(0),
+                set_by: Mutex::new(None),
+            }),
+        }
+    }
+}
+
+impl fmt::Debug for CacheOverride {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.debug_tuple("CacheOverride").field(&self.status()).finish()
+    }
+}
+
+//
+// BypassMode
+//
+
+/// Cache bypass mode.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BypassMode {
+    /// Don't serve from the cache, but keep storing.
+    Reads,
+
+    /// Don't serve from the cache and don't store.
+    All,
+}
+
+impl fmt::Display for BypassMode {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Reads => fmt::Display::fmt("bypass reads", formatter),
+            Self::All => fmt::Display::fmt("bypass all", formatter),
+        }
+    }
+}
+
+//
+// OverrideStatus
+//
+
+/// Status of an active [CacheOverride].
+#[derive(Clone, Debug)]
+pub struct OverrideStatus {
+    /// Mode.
+    pub mode: BypassMode,
+
+    /// Remaining time.
+    pub remaining: Duration,
+
+    /// Who set it.
+    pub set_by: Option<String>,
+}
+
+impl fmt::Display for OverrideStatus {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{} for {}s", self.mode, self.remaining.as_secs())?;
+        if let Some(set_by) = &self.set_by {
+            write!(formatter, " (set by {})", set_by)?;
+        }
+        Ok(())
+    }
+}
+
+//
+// OverrideState
+//
+
+struct OverrideState {
+    base: Instant,
+    state: AtomicU64,
+    set_by: Mutex<Option<String>>,
+}
@@ -0,0 +1,70 @@
+use {
+    http_body::*,
+    kutil::std::immutable::*,
+    std::{pin::*, task::*},
+};
+
+//
+// ClientBody
+//
+
+/// Response body for caching a client.
+///
+/// The caching layer requires response bodies that can be created from bytes, which client
+/// bodies (e.g. Hyper's `Incoming`) can't be. Wrapping them in this adapter lifts the
+/// requirement, e.g. with Tower's `map_response(|response| response.map(ClientBody::Upstream))`.
+#[derive(Debug)]
+pub enum ClientBody<BodyT> {
+    /// Upstream body.
+    Upstream(BodyT),
+
+    /// Bytes (taken when polled).
+    Bytes(Option<ImmutableBytes>),
+}
+
+impl<BodyT> From<ImmutableBytes> for ClientBody<BodyT> {
+    fn from(bytes: ImmutableBytes) -> Self {
+        Self::Bytes(Some(bytes))
+    }
+}
+
+impl<BodyT> Body for ClientBody<BodyT>
+where
+    BodyT: Body + Unpin,
+    BodyT::Data: From<ImmutableBytes>,
+{
+    type Data = BodyT::Data;
+    type Error = BodyT::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        match self.get_mut() {
+            Self::Upstream(body) => Pin::new(body).poll_frame(context),
+
+            Self::Bytes(bytes) => Poll::Ready(
+                bytes
+                    .take()
+                    .filter(|bytes| !bytes.is_empty())
+                    .map(|bytes| Ok(Frame::data(bytes.into()))),
+            ),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        match self {
+            Self::Upstream(body) => body.is_end_stream(),
+            Self::Bytes(bytes) => bytes.as_ref().is_none_or(|bytes| bytes.is_empty()),
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self {
+            Self::Upstream(body) => body.size_hint(),
+            Self::Bytes(bytes) => SizeHint::with_exact(
+                bytes.as_ref().map(|bytes| bytes.len() as u64).unwrap_or_default(),
+            ),
+        }
+    }
+}
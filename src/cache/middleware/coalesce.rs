@@ -0,0 +1,142 @@
+use {
+    kutil::std::collections::*,
+    std::{
+        collections::hash_map::Entry,
+        fmt, future,
+        hash::*,
+        sync::{atomic::*, *},
+        task::{Poll, Waker},
+    },
+};
+
+//
+// RequestCoalescing
+//
+
+/// Coalesces concurrent misses for the same cache key.
+///
+/// When a popular entry expires, many concurrent requests would miss at once and all call the
+/// upstream. Instead, the first request to miss becomes the leader and calls the upstream, while
+/// the others (followers) wait for it to finish (including storing the entry) and then look up
+/// the cache again, usually getting a hit.
+///
+/// If the leader fails, is cancelled, or its response turns out to be uncacheable, then the
+/// followers miss again and call the upstream themselves (without coalescing). Followers wait as
+/// long as the leader takes. A follower that is dropped (e.g. because the client disconnected)
+/// does not hold up anything.
+///
+/// Keys are tracked by hash, so a (rare) collision makes a request wait for an unrelated leader
+/// before looking up again.
+///
+/// Cloning is cheap and clones share state.
+#[derive(Clone, Default)]
+pub struct RequestCoalescing {
+    state: Arc<RequestCoalescingState>,
+}
+
+impl RequestCoalescing {
+    /// Become the leader for a key, or wait for the current leader to finish.
+    ///
+    /// Returns the leader's guard, or [None] after waiting for a leader.
+    pub async fn lead_or_follow(&self, key: &impl Hash) -> Option<CoalescingGuard> {
+        let hash = self.state.hasher.hash_one(key);
+
+        if let Entry::Vacant(entry) = self.state.leaders.lock().expect("lock").entry(hash) {
+            entry.insert(Default::default());
+            self.state.led.fetch_add(1, Ordering::Relaxed);
+            return Some(CoalescingGuard {
+                state: self.state.clone(),
+                hash,
+            });
+        }
+
+        self.state.followed.fetch_add(1, Ordering::Relaxed);
+
+        future::poll_fn(|context| {
+            let mut leaders = self.state.leaders.lock().expect("lock");
+            match leaders.get_mut(&hash) {
+                Some(waiters) => {
+                    if !waiters.iter().any(|waiter| waiter.will_wake(context.waker())) {
+                        waiters.push(context.waker().clone());
+                    }
+                    Poll::Pending
+                }
+
+                None => Poll::Ready(()),
+            }
+        })
+        .await;
+
+        None
+    }
+
+    /// Statistics.
+    pub fn stats(&self) -> RequestCoalescingStats {
+        RequestCoalescingStats {
+            in_flight: self.state.leaders.lock().expect("lock").len(),
+            led: self.state.led.load(Ordering::Relaxed),
+            followed: self.state.followed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl fmt::Debug for RequestCoalescing {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("RequestCoalescing")
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+//
+// CoalescingGuard
+//
+
+/// [RequestCoalescing] leader.
+///
+/// Followers are released when dropped.
+pub struct CoalescingGuard {
+    state: Arc<RequestCoalescingState>,
+    hash: u64,
+}
+
+impl Drop for CoalescingGuard {
+    fn drop(&mut self) {
+        let waiters = self.state.leaders.lock().expect("lock").remove(&self.hash);
+        for waiter in waiters.into_iter().flatten() {
+            waiter.wake();
+        }
+    }
+}
+
+impl fmt::Debug for CoalescingGuard {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.debug_struct("CoalescingGuard").finish()
+    }
+}
+
+//
+// RequestCoalescingStats
+//
+
+/// [RequestCoalescing] statistics.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RequestCoalescingStats {
+    /// Keys with a leader in flight.
+    pub in_flight: usize,
+
+    /// Misses that called the upstream as leaders.
+    pub led: u64,
+
+    /// Misses that waited for a leader.
+    pub followed: u64,
+}
+
+#[derive(Default)]
+struct RequestCoalescingState {
+    hasher: RandomState,
+    leaders: Mutex<FastHashMap<u64, Vec<Waker>>>,
+    led: AtomicU64,
+    followed: AtomicU64,
+}
@@ -1,6 +1,60 @@
-use super::{super::configuration::*, hooks::*};
+use super::{
+    super::{cache::*, coding::*, configuration::*, key::*, self_test::*},
+    accept_encoding::*,
+    admin::*,
+    admission::*,
+    body_key::*,
+    body_sizes::*,
+    buffer::*,
+    budget::*,
+    bust::*,
+    bypass::*,
+    coalesce::*,
+    conflict::*,
+    control::*,
+    dependencies::*,
+    entry_stats::*,
+    forwarded::*,
+    generation::*,
+    hooks::*,
+    immutable::*,
+    interop::*,
+    key_uri::*,
+    language::*,
+    learned::*,
+    load::*,
+    method::*,
+    negotiation::*,
+    partition::*,
+    quarantine::*,
+    resource::*,
+    slo::*,
+    stacking::*,
+    startup::*,
+    vary::*,
+    weight_audit::*,
+};
 
-use kutil::http::*;
+#[cfg(feature = "range-assembly")]
+use super::assembly::*;
+
+#[cfg(feature = "idempotency")]
+use super::idempotency::*;
+
+#[cfg(feature = "paced-purge")]
+use super::purge::*;
+
+#[cfg(feature = "stale-while-revalidate")]
+use super::revalidate::*;
+
+#[cfg(feature = "test-util")]
+use super::pipeline::*;
+
+use {
+    http::*,
+    kutil::http::*,
+    std::{result::Result, sync::*, time::*},
+};
 
 /// Encodings in order from most preferred to least.
 ///
@@ -8,10 +62,16 @@ use kutil::http::*;
 ///
 /// GZip and Deflate are almost identical, but we prefer GZip because it allows clients to check
 /// for errors.
+///
+/// Only encodings enabled via crate features are included.
 pub const ENCODINGS_BY_PREFERENCE: &[EncodingHeaderValue] = &[
+    #[cfg(feature = "brotli")]
     EncodingHeaderValue::Brotli,
+    #[cfg(feature = "gzip")]
     EncodingHeaderValue::GZip,
+    #[cfg(feature = "deflate")]
     EncodingHeaderValue::Deflate,
+    #[cfg(feature = "zstd")]
     EncodingHeaderValue::Zstandard,
 ];
 
@@ -33,6 +93,172 @@ pub struct MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT> {
     /// Cache key (hook).
     pub cache_key: Option<CacheKeyHook<CacheKeyT, RequestBodyT>>,
 
+    /// Cache key from the request body.
+    pub body_key: Option<BodyKey<CacheKeyT, RequestBodyT>>,
+
+    /// Trailers of stored responses (hook).
+    pub on_trailers: Option<TrailersHook>,
+
+    /// Stored entries (hook).
+    pub on_store: Option<StoreHook<CacheKeyT>>,
+
+    /// Cache events (hook).
+    pub on_cache_event: Option<CacheEventHook<CacheKeyT>>,
+
+    /// Cacheable request methods.
+    pub methods: MethodPolicy,
+
+    /// Whether to process our `XX-` response headers.
+    ///
+    /// If false they are removed from upstream responses and have no effect.
+    pub xx_headers: bool,
+
+    /// Instance label (for stacked layers).
+    pub label: Option<Arc<str>>,
+
+    /// Stacking role.
+    pub role: StackingRole,
+
+    /// Response header for the [CacheStatus](super::status::CacheStatus).
+    pub cache_status_header: Option<HeaderName>,
+
+    /// Response header for the time held in the cache.
+    pub cache_age_header: Option<HeaderName>,
+
+    /// Whether to serve byte ranges of cached entries.
+    pub serve_ranges: bool,
+
+    /// Cache keys according to the response `Vary` header.
+    pub vary_keys: Option<VaryKeys>,
+
+    /// Language negotiation.
+    pub language_negotiation: Option<Arc<LanguageNegotiation>>,
+
+    /// Log decisions for requests slower than this.
+    pub log_slow_over: Option<Duration>,
+
+    /// Overhead budget.
+    pub overhead_budget: Option<OverheadBudget>,
+
+    /// Operational override.
+    pub cache_override: CacheOverride,
+
+    /// Runtime control.
+    pub control: CachingControl,
+
+    /// Cache generations.
+    pub generations: Option<CacheGenerations>,
+
+    /// Load shedding.
+    pub load_shed: Option<LoadShedPolicy>,
+
+    /// Budget of bytes buffered by in-flight stores.
+    pub buffer_budget: Option<BufferBudget>,
+
+    /// Coalescing of concurrent misses.
+    pub coalescing: Option<RequestCoalescing>,
+
+    /// Serving of expired entries while refreshing them.
+    #[cfg(feature = "stale-while-revalidate")]
+    pub stale_while_revalidate: Option<StaleWhileRevalidate>,
+
+    /// Paced bulk invalidation for bans.
+    #[cfg(feature = "paced-purge")]
+    pub purge_jobs: Option<PurgeJobs>,
+
+    /// Admission policy for storing misses.
+    pub admission: Option<AdmissionPolicy>,
+
+    /// Learned bypass for keys that keep producing uncacheable responses.
+    pub learned_bypass: Option<LearnedBypass>,
+
+    /// Dependency recording.
+    ///
+    /// Each request gets a [fresh](CacheDependencies::fresh) accumulator with these limits.
+    pub dependencies: Option<CacheDependencies>,
+
+    /// Invalidation after failed conditional writes.
+    pub conflict_invalidation: Option<ConflictInvalidation>,
+
+    /// Cache verification on first use.
+    pub cache_verification: Option<CacheVerification>,
+
+    /// Serve-time validation of cache entries.
+    pub serve_validation: Option<ServeValidation>,
+
+    /// Immutable asset profile.
+    pub immutable_paths: Option<ImmutablePaths>,
+
+    /// Assembly of upstream ranges.
+    #[cfg(feature = "range-assembly")]
+    pub range_assembly: Option<RangeAssembly<CacheKeyT>>,
+
+    /// Deduplication of non-idempotent requests.
+    #[cfg(feature = "idempotency")]
+    pub idempotency: Option<IdempotentRequests<RequestBodyT>>,
+
+    /// Per-entry statistics.
+    pub entry_stats: Option<EntryStats>,
+
+    /// Body size observer.
+    pub body_sizes: Option<BodySizeObserver>,
+
+    /// Weight audit.
+    pub weight_audit: Option<WeightAudit<CacheKeyT>>,
+
+    /// Hit rate SLOs.
+    pub hit_rate_slos: Vec<HitRateSlo>,
+
+    /// Pipeline probe.
+    #[cfg(feature = "test-util")]
+    pub pipeline_probe: Option<PipelineProbe>,
+
+    /// Cache-busting query parameters.
+    pub bust_params: Vec<BustParamPolicy>,
+
+    /// Trust policy for forwarded headers.
+    pub trusted_forwarded: Option<TrustPolicy>,
+
+    /// Whether to partition cache keys by origin (scheme, host, and port).
+    pub partition_by_host: bool,
+
+    /// Partitioning of cache keys for matching paths.
+    pub partition: Option<PartitionPolicy>,
+
+    /// Requests with any of these headers skip the cache.
+    pub bypass_headers: Vec<HeaderName>,
+
+    /// Request headers whose values partition cache keys.
+    pub partition_headers: Vec<HeaderName>,
+
+    /// Key for the [keyed_hash] of [partition_headers](Self::partition_headers).
+    pub header_digest_key: [u8; 16],
+
+    /// Whether requests with `Authorization` skip the cache.
+    pub strict_privacy: bool,
+
+    /// Which URI to use for cache keys.
+    pub key_uri_source: KeyUriSource,
+
+    /// Whether to skip caching for requests that no route matched (requires the `axum` feature).
+    pub never_cache_unmatched: bool,
+
+    /// Whether to cache requests with absolute-form targets.
+    pub cache_absolute_form: bool,
+
+    /// In-band cache administration.
+    pub admin_methods: Option<AdminMethodConfig>,
+
+    /// Resource ID (hook).
+    pub resource_id: Option<ResourceIdHook<CacheKeyT>>,
+
+    /// Per-resource upstream concurrency limiter.
+    pub resource_limiter: Option<ResourceLimiter>,
+
+    /// Codes of configuration warnings to suppress (see
+    /// [ConfigurationDiagnostic](super::lint::ConfigurationDiagnostic)).
+    pub allowed_diagnostics: Vec<&'static str>,
+
     /// Inner configuration.
     pub inner: CachingConfiguration,
 }
@@ -46,16 +272,106 @@ impl<RequestBodyT, CacheT, CacheKeyT> Default
             cacheable_by_request: None,
             cacheable_by_response: None,
             cache_key: None,
+            body_key: None,
+            on_trailers: None,
+            on_store: None,
+            on_cache_event: None,
+            methods: Default::default(),
+            xx_headers: true,
+            label: None,
+            role: Default::default(),
+            cache_status_header: None,
+            cache_age_header: None,
+            serve_ranges: true,
+            vary_keys: None,
+            language_negotiation: None,
+            log_slow_over: None,
+            overhead_budget: None,
+            cache_override: Default::default(),
+            control: Default::default(),
+            generations: None,
+            load_shed: None,
+            buffer_budget: None,
+            coalescing: None,
+            #[cfg(feature = "stale-while-revalidate")]
+            stale_while_revalidate: None,
+            #[cfg(feature = "paced-purge")]
+            purge_jobs: None,
+            admission: None,
+            learned_bypass: None,
+            dependencies: None,
+            conflict_invalidation: None,
+            cache_verification: None,
+            serve_validation: None,
+            immutable_paths: None,
+            #[cfg(feature = "range-assembly")]
+            range_assembly: None,
+            #[cfg(feature = "idempotency")]
+            idempotency: None,
+            entry_stats: None,
+            body_sizes: None,
+            weight_audit: None,
+            hit_rate_slos: Default::default(),
+            #[cfg(feature = "test-util")]
+            pipeline_probe: None,
+            bust_params: Default::default(),
+            trusted_forwarded: None,
+            partition_by_host: false,
+            partition: None,
+            bypass_headers: Default::default(),
+            partition_headers: Default::default(),
+            header_digest_key: random_hash_key(),
+            strict_privacy: false,
+            key_uri_source: Default::default(),
+            never_cache_unmatched: false,
+            cache_absolute_form: false,
+            admin_methods: None,
+            resource_id: None,
+            resource_limiter: None,
+            allowed_diagnostics: Default::default(),
             inner: CachingConfiguration {
                 min_body_size: 0,
                 max_body_size: 1024 * 1024, // 1 MiB
                 cacheable_by_default: true,
+                cache_validators_for_oversized: false,
                 cache_duration: None,
+                cacheable_status_codes: Arc::new([]),
+                negative_duration: None,
+                respect_cache_control: false,
+                heuristic_freshness: None,
+                ttl_jitter: None,
+                transform_before_store: None,
+                transform_policy: Default::default(),
+                age_accounting: Default::default(),
+                synthetic_last_modified: Default::default(),
+                clock: None,
+                key_log_policy: Default::default(),
             },
         }
     }
 }
 
+impl<RequestBodyT, CacheT, CacheKeyT>
+    MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>
+where
+    CacheT: Cache<CacheKeyT>,
+    CacheKeyT: CacheKey,
+{
+    /// Run the cache self-test now.
+    ///
+    /// If [CacheVerification] is configured then it will remember the outcome.
+    pub async fn verify_cache(&self) -> Result<SelfTestReport, SelfTestError> {
+        let Some(cache) = &self.cache else {
+            return Err(SelfTestError::new("configure", "no cache"));
+        };
+
+        match &self.cache_verification {
+            Some(cache_verification) => cache_verification.verify(cache).await,
+            None => cache.self_test().await,
+        }
+    }
+}
+
 impl<RequestBodyT, CacheT, CacheKeyT> Clone
     for MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>
 where
@@ -69,11 +385,109 @@ where
             cacheable_by_request: self.cacheable_by_request.clone(),
             cacheable_by_response: self.cacheable_by_response.clone(),
             cache_key: self.cache_key.clone(),
+            body_key: self.body_key.clone(),
+            on_trailers: self.on_trailers.clone(),
+            on_store: self.on_store.clone(),
+            on_cache_event: self.on_cache_event.clone(),
+            methods: self.methods.clone(),
+            xx_headers: self.xx_headers,
+            label: self.label.clone(),
+            role: self.role,
+            cache_status_header: self.cache_status_header.clone(),
+            cache_age_header: self.cache_age_header.clone(),
+            serve_ranges: self.serve_ranges,
+            vary_keys: self.vary_keys.clone(),
+            language_negotiation: self.language_negotiation.clone(),
+            log_slow_over: self.log_slow_over,
+            overhead_budget: self.overhead_budget.clone(),
+            cache_override: self.cache_override.clone(),
+            control: self.control.clone(),
+            generations: self.generations.clone(),
+            load_shed: self.load_shed.clone(),
+            buffer_budget: self.buffer_budget.clone(),
+            coalescing: self.coalescing.clone(),
+            #[cfg(feature = "stale-while-revalidate")]
+            stale_while_revalidate: self.stale_while_revalidate.clone(),
+            #[cfg(feature = "paced-purge")]
+            purge_jobs: self.purge_jobs.clone(),
+            admission: self.admission.clone(),
+            learned_bypass: self.learned_bypass.clone(),
+            dependencies: self.dependencies.clone(),
+            conflict_invalidation: self.conflict_invalidation.clone(),
+            cache_verification: self.cache_verification.clone(),
+            serve_validation: self.serve_validation.clone(),
+            immutable_paths: self.immutable_paths.clone(),
+            #[cfg(feature = "range-assembly")]
+            range_assembly: self.range_assembly.clone(),
+            #[cfg(feature = "idempotency")]
+            idempotency: self.idempotency.clone(),
+            entry_stats: self.entry_stats.clone(),
+            body_sizes: self.body_sizes.clone(),
+            weight_audit: self.weight_audit.clone(),
+            hit_rate_slos: self.hit_rate_slos.clone(),
+            #[cfg(feature = "test-util")]
+            pipeline_probe: self.pipeline_probe.clone(),
+            bust_params: self.bust_params.clone(),
+            trusted_forwarded: self.trusted_forwarded.clone(),
+            partition_by_host: self.partition_by_host,
+            partition: self.partition.clone(),
+            bypass_headers: self.bypass_headers.clone(),
+            partition_headers: self.partition_headers.clone(),
+            header_digest_key: self.header_digest_key,
+            strict_privacy: self.strict_privacy,
+            key_uri_source: self.key_uri_source,
+            never_cache_unmatched: self.never_cache_unmatched,
+            cache_absolute_form: self.cache_absolute_form,
+            admin_methods: self.admin_methods.clone(),
+            resource_id: self.resource_id.clone(),
+            resource_limiter: self.resource_limiter.clone(),
+            allowed_diagnostics: self.allowed_diagnostics.clone(),
             inner: self.inner.clone(),
         }
     }
 }
 
+//
+// MiddlewareConfiguration
+//
+
+/// Middleware configuration.
+///
+/// Services keep it behind a single [Arc] so that capturing it for a request is cheap.
+pub struct MiddlewareConfiguration<RequestBodyT, CacheT, CacheKeyT> {
+    /// Caching configuration.
+    pub caching: MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+
+    /// Encoding configuration.
+    pub encoding: MiddlewareEncodingConfiguration,
+}
+
+impl<RequestBodyT, CacheT, CacheKeyT> MiddlewareConfiguration<RequestBodyT, CacheT, CacheKeyT> {
+    /// Constructor.
+    ///
+    /// Sets the inner encoding configuration's `enabled_codings`.
+    pub fn new(
+        caching: MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+        mut encoding: MiddlewareEncodingConfiguration,
+    ) -> Self {
+        encoding.inner.enabled_codings = Some(encoding.enabled_codings());
+        Self { caching, encoding }
+    }
+}
+
+impl<RequestBodyT, CacheT, CacheKeyT> Clone
+    for MiddlewareConfiguration<RequestBodyT, CacheT, CacheKeyT>
+where
+    CacheT: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            caching: self.caching.clone(),
+            encoding: self.encoding.clone(),
+        }
+    }
+}
+
 //
 // MiddlewareEncodingConfiguration
 //
@@ -82,7 +496,22 @@ where
 #[derive(Clone)]
 pub struct MiddlewareEncodingConfiguration {
     /// Enabled encodings in order of preference.
-    pub enabled_encodings_by_preference: Option<Vec<EncodingHeaderValue>>,
+    pub enabled_encodings_by_preference: Option<Arc<[EncodingHeaderValue]>>,
+
+    /// Enabled custom codings in order of preference.
+    ///
+    /// They are considered more preferred than all enabled encodings. Their transcoders are in
+    /// the inner configuration.
+    pub enabled_custom_codings_by_preference: Arc<[&'static str]>,
+
+    /// Encoding negotiator.
+    pub negotiator: EncodingNegotiatorRef,
+
+    /// Maximum number of `Accept-Encoding` members.
+    pub max_accept_encoding_members: usize,
+
+    /// Malformed `Accept-Encoding` counter.
+    pub accept_encoding_anomalies: AcceptEncodingAnomalyCounter,
 
     /// Encodable by request (hook).
     pub encodable_by_request: Option<EncodableHook>,
@@ -90,20 +519,62 @@ pub struct MiddlewareEncodingConfiguration {
     /// Encodable by response (hook).
     pub encodable_by_response: Option<EncodableHook>,
 
+    /// Assume that a compression middleware below us handles encoding.
+    pub assume_inner_compression: bool,
+
+    /// Compression conflict detector.
+    pub compression_conflicts: CompressionConflictDetector,
+
     /// Inner configuration.
     pub inner: EncodingConfiguration,
 }
 
+impl MiddlewareEncodingConfiguration {
+    /// Enabled codings, custom and built-in, in order of preference.
+    ///
+    /// Empty if encoding is disabled.
+    pub fn enabled_codings(&self) -> Arc<[CodingId]> {
+        match &self.enabled_encodings_by_preference {
+            Some(enabled_encodings) => self
+                .enabled_custom_codings_by_preference
+                .iter()
+                .map(|name| CodingId::Custom(name))
+                .chain(
+                    enabled_encodings
+                        .iter()
+                        .map(|encoding| CodingId::Builtin((*encoding).into())),
+                )
+                .collect(),
+
+            None => Default::default(),
+        }
+    }
+}
+
 impl Default for MiddlewareEncodingConfiguration {
     fn default() -> Self {
         Self {
             enabled_encodings_by_preference: Some(ENCODINGS_BY_PREFERENCE.into()),
+            enabled_custom_codings_by_preference: Default::default(),
+            negotiator: Arc::new(CommonEncodingNegotiator),
+            max_accept_encoding_members: DEFAULT_MAX_ACCEPT_ENCODING_MEMBERS,
+            accept_encoding_anomalies: Default::default(),
             encodable_by_request: None,
             encodable_by_response: None,
+            assume_inner_compression: false,
+            compression_conflicts: Default::default(),
             inner: EncodingConfiguration {
                 min_body_size: 0,
                 encodable_by_default: true,
                 keep_identity_encoding: true,
+                transcoders: Default::default(),
+                verification: None,
+                on_the_fly_validators: Default::default(),
+                respect_no_transform: true,
+                never_transform: false,
+                enabled_codings: None,
+                prune_disabled_representations: false,
+                max_cached_encodings: None,
             },
         }
     }
@@ -0,0 +1,108 @@
+use super::super::{cache::*, key::*};
+
+use http::{header::*, *};
+
+//
+// ConflictInvalidation
+//
+
+/// Invalidation of cached `GET` entries after a failed conditional write.
+///
+/// With ETag-based optimistic concurrency a client reads a resource, then writes it with
+/// `If-Match`. If the upstream responds `412 Precondition Failed` (or `409 Conflict`) then the
+/// resource has changed since the client read it, and if that read was served from our cache
+/// then the entry is stale. Serving it again would have the client retry the write with the same
+/// stale ETag, forever.
+///
+/// When a request with an unsafe method (e.g. `PUT`, `PATCH`, or `DELETE`) gets a response with
+/// one of the [statuses](Self::statuses) then the entries for its URI are invalidated, with keys
+/// created exactly as for a `GET` (see [uri_cache_keys](super::uri_cache_keys)).
+///
+/// With [etag_mismatch](Self::etag_mismatch) a response with any other status is also checked:
+/// if it has an `ETag` that differs from that of a cached entry then that entry is invalidated.
+#[derive(Clone, Debug)]
+pub struct ConflictInvalidation {
+    /// Response statuses that invalidate.
+    pub statuses: Vec<StatusCode>,
+
+    /// Whether to also invalidate entries with an `ETag` that differs from the response's.
+    pub etag_mismatch: bool,
+}
+
+impl ConflictInvalidation {
+    /// Constructor.
+    pub fn new(statuses: impl IntoIterator<Item = StatusCode>) -> Self {
+        Self {
+            statuses: statuses.into_iter().collect(),
+            etag_mismatch: false,
+        }
+    }
+
+    /// Set whether to also invalidate entries with an `ETag` that differs from the response's.
+    pub fn etag_mismatch(mut self, etag_mismatch: bool) -> Self {
+        self.etag_mismatch = etag_mismatch;
+        self
+    }
+
+    /// Whether a request with this method applies.
+    pub fn applies(&self, method: &Method) -> bool {
+        !method.is_safe()
+    }
+
+    /// Invalidate entries according to the response.
+    ///
+    /// Returns the number of invalidated entries.
+    pub async fn invalidate<CacheT, CacheKeyT>(
+        &self,
+        cache: &CacheT,
+        keys: &[CacheKeyT],
+        status: StatusCode,
+        headers: &HeaderMap,
+    ) -> usize
+    where
+        CacheT: Cache<CacheKeyT>,
+        CacheKeyT: CacheKey,
+    {
+        let conflict = self.statuses.contains(&status);
+        let etag = if self.etag_mismatch {
+            headers.get(ETAG)
+        } else {
+            None
+        };
+
+        if !conflict && etag.is_none() {
+            return 0;
+        }
+
+        let mut invalidated = 0;
+        for key in keys {
+            let Some(cached_response) = cache.get(key).await else {
+                continue;
+            };
+
+            let mismatch = etag.is_some_and(|etag| {
+                cached_response
+                    .headers()
+                    .get(ETAG)
+                    .is_some_and(|cached_etag| cached_etag != etag)
+            });
+
+            if conflict || mismatch {
+                cache.invalidate(key).await;
+                invalidated += 1;
+            }
+        }
+
+        if invalidated != 0 {
+            tracing::debug!("invalidated after conflict ({}): {}", status, invalidated);
+        }
+
+        invalidated
+    }
+}
+
+impl Default for ConflictInvalidation {
+    fn default() -> Self {
+        Self::new([StatusCode::PRECONDITION_FAILED, StatusCode::CONFLICT])
+    }
+}
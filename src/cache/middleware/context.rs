@@ -0,0 +1,262 @@
+use super::{
+    super::{coding::*, key::*},
+    budget::*,
+    coalesce::*,
+    configuration::*,
+    dependencies::*,
+    forwarded::*,
+    hooks::*,
+    partition::*,
+    pipeline::*,
+    request::*,
+    stacking::*,
+    store::*,
+    trail::*,
+};
+
+use {
+    http::{request::*, *},
+    kutil::http::*,
+    std::sync::*,
+};
+
+//
+// RequestCacheContext
+//
+
+/// Per-request caching context.
+///
+/// Computed once at the beginning of handling a request, so that the rest of the pipeline (and
+/// observability features) can read from one place instead of re-deriving these values.
+#[derive(Debug)]
+pub struct RequestCacheContext<CacheKeyT> {
+    /// Request method.
+    pub method: Method,
+
+    /// Request URI.
+    pub uri: Uri,
+
+    /// Effective origin (see [TrustPolicy]).
+    ///
+    /// Resolved only if keying can use it, i.e. if partitioning by host or if there is a
+    /// `cache_key` hook. Otherwise it is empty.
+    pub origin: RequestOrigin,
+
+    /// Request `Content-Length`.
+    pub content_length: Option<usize>,
+
+    /// Negotiated language.
+    pub language: Option<Language>,
+
+    /// Negotiated coding.
+    pub coding: CodingId,
+
+    /// Whether the request should skip the cache.
+    pub skip_cache: bool,
+
+    /// Cache key ([None] if skipping the cache).
+    pub cache_key: Option<CacheKeyT>,
+
+    /// Cache partition ([None] if the path isn't partitioned). See [PartitionPolicy].
+    pub partition: Option<Partition>,
+
+    /// Resource ID (see [ResourceLimiter](super::resource::ResourceLimiter)).
+    pub resource_id: Option<Arc<str>>,
+
+    /// Stale cache keys to invalidate (see [BustParamPolicy](super::bust::BustParamPolicy)).
+    pub bust_invalidations: Vec<CacheKeyT>,
+
+    /// Whether the path matches the immutable asset profile.
+    pub immutable: bool,
+
+    /// Whether the request has `Cache-Control: no-transform` (and we respect it).
+    ///
+    /// The response must then be in the coding in which the upstream produced it.
+    pub no_transform: bool,
+
+    /// Decision trail.
+    pub trail: DecisionTrail,
+
+    /// Pipeline stage.
+    pub stages: PipelineStages,
+
+    /// Overhead deadline ([None] if there is no [OverheadBudget]).
+    pub deadline: Option<OverheadDeadline>,
+
+    /// Cache write to commit once the response has been constructed.
+    pub pending_store: Option<PendingStore<CacheKeyT>>,
+
+    /// Held while we are the [RequestCoalescing] leader, until the cache write is committed.
+    pub coalescing_guard: Option<CoalescingGuard>,
+
+    /// Whether the upstream response turned out to be uncacheable (see
+    /// [LearnedBypass](super::learned::LearnedBypass)).
+    pub uncacheable: bool,
+
+    /// The dependency bound that constrained the duration of the stored entry, if any (see
+    /// [CacheDependencies::record_with_ttl]).
+    pub duration_constraint: Option<DependencyBound>,
+
+    /// Outcomes of the labeled layers below us (see [LayerOutcomes]).
+    pub inner_outcomes: Option<LayerOutcomes>,
+}
+
+impl<CacheKeyT> RequestCacheContext<CacheKeyT>
+where
+    CacheKeyT: CacheKey,
+{
+    /// Constructor.
+    ///
+    /// Calls the request hooks as necessary.
+    pub fn new<RequestBodyT, CacheT>(
+        request: &Request<RequestBodyT>,
+        caching_configuration: &MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+        encoding_configuration: &MiddlewareEncodingConfiguration,
+    ) -> Self {
+        // Resolving allocates, so don't bother if nobody will look
+        let origin = if caching_configuration.partition_by_host
+            || caching_configuration.cache_key.is_some()
+        {
+            RequestOrigin::resolve(request, caching_configuration.trusted_forwarded.as_ref())
+        } else {
+            Default::default()
+        };
+        let language = request.negotiate_language(caching_configuration);
+        let mut skip_cache = request.should_skip_cache(caching_configuration);
+        let mut trail = DecisionTrail::default();
+
+        let partition = match &caching_configuration.partition {
+            Some(partition_policy) if !skip_cache => {
+                let partition = partition_policy.resolve(request);
+                if let Some(partition) = &partition {
+                    tracing::debug!("partition: {}", partition);
+                    if *partition == Partition::Unpartitioned
+                        && partition_policy.unpartitioned == UnpartitionedPolicy::Bypass
+                    {
+                        tracing::debug!("skip (unpartitioned)");
+                        trail.decide("skip (unpartitioned)");
+                        skip_cache = true;
+                    }
+                }
+                partition
+            }
+
+            _ => None,
+        };
+
+        let mut cache_key = (!skip_cache).then(|| {
+            request.cache_key_for_origin(language.as_ref(), &origin, caching_configuration)
+        });
+
+        let mut bust_invalidations = Vec::default();
+        if let Some(cache_key) = &mut cache_key {
+            if let Some(partition) = &partition {
+                cache_key.set_partition(partition.identifier());
+            }
+
+            if let Some(generations) = &caching_configuration.generations {
+                cache_key.set_generation(generations.current());
+            }
+
+            for bust_params in &caching_configuration.bust_params {
+                bust_invalidations.extend(bust_params.apply(request.uri(), cache_key));
+            }
+        }
+
+        let resource_id = match (&caching_configuration.resource_id, &cache_key) {
+            (Some(resource_id_hook), Some(cache_key)) => resource_id_hook(
+                ResourceIdHookContext::new(request.uri(), request.headers(), cache_key),
+            )
+            .map(Arc::from),
+
+            _ => None,
+        };
+
+        let immutable = caching_configuration
+            .immutable_paths
+            .as_ref()
+            .is_some_and(|immutable_paths| immutable_paths.matches(request.uri().path()));
+
+        Self {
+            method: request.method().clone(),
+            uri: request.uri().clone(),
+            origin,
+            content_length: request.headers().content_length(),
+            language,
+            coding: request.select_encoding(encoding_configuration),
+            skip_cache,
+            cache_key,
+            partition,
+            resource_id,
+            bust_invalidations,
+            immutable,
+            no_transform: encoding_configuration.inner.no_transform(request.headers()),
+            trail,
+            stages: PipelineStages {
+                current: Some(PipelineStage::Request),
+                #[cfg(feature = "test-util")]
+                probe: caching_configuration.pipeline_probe.clone(),
+            },
+            deadline: caching_configuration
+                .overhead_budget
+                .as_ref()
+                .map(OverheadBudget::deadline),
+            pending_store: None,
+            coalescing_guard: None,
+            uncacheable: false,
+            duration_constraint: None,
+            inner_outcomes: None,
+        }
+    }
+
+    /// Enter a [PipelineStage].
+    pub fn enter(&mut self, stage: PipelineStage) {
+        let decision = self.trail.decisions.iter().last();
+        if !self.stages.enter(stage, decision) {
+            self.trail.decide("error (pipeline order)");
+        }
+    }
+
+    /// Stage the cache write for this request.
+    ///
+    /// There can be only one.
+    pub fn stage(&mut self, pending_store: PendingStore<CacheKeyT>) {
+        debug_assert!(self.pending_store.is_none(), "already staged");
+        self.pending_store = Some(pending_store);
+    }
+
+    /// Run a future within the remaining [OverheadBudget], if there is one.
+    ///
+    /// Time spent awaiting the upstream so far is excluded.
+    pub async fn within_budget<FutureT>(&self, future: FutureT) -> Option<FutureT::Output>
+    where
+        FutureT: Future,
+    {
+        match &self.deadline {
+            Some(deadline) => deadline.within(self.trail.upstream, future).await,
+            None => Some(future.await),
+        }
+    }
+
+    /// Whether the [OverheadBudget] is exhausted.
+    pub fn budget_exhausted(&self) -> bool {
+        self.deadline
+            .as_ref()
+            .is_some_and(|deadline| deadline.is_exhausted(self.trail.upstream))
+    }
+
+    /// Mark the [OverheadBudget] as exhausted.
+    pub fn exhaust_budget(&mut self, phase: BudgetPhase) {
+        if let Some(deadline) = &mut self.deadline {
+            deadline.exhaust(phase);
+            self.trail.decide(phase.decision());
+        }
+    }
+
+    /// The phase in which the [OverheadBudget] was exhausted, if it was.
+    pub fn budget_exhausted_phase(&self) -> Option<BudgetPhase> {
+        self.deadline
+            .as_ref()
+            .and_then(OverheadDeadline::exhausted_phase)
+    }
+}
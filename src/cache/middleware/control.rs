@@ -0,0 +1,233 @@
+use super::{
+    super::{cache::*, key::*},
+    configuration::*,
+};
+
+use {
+    kutil::http::*,
+    std::{
+        fmt,
+        sync::{atomic::*, *},
+    },
+};
+
+//
+// CachingControl
+//
+
+/// Runtime control of the caching middleware.
+///
+/// Changes take effect on subsequent requests, without rebuilding the service, e.g. from an
+/// admin endpoint during an incident. Overrides apply on top of the built configuration, and
+/// [clear_overrides](Self::clear_overrides) returns to it.
+///
+/// Checking whether caching is enabled on the hot path is a single atomic load. Each service
+/// builds its overridden configuration once per change of the overrides (see
+/// [ControlledConfiguration]), after which requests share it. For time-boxed bypasses see
+/// [CacheOverride](super::CacheOverride).
+///
+/// Cloning is cheap and clones share state.
+#[derive(Clone, Default)]
+pub struct CachingControl {
+    state: Arc<ControlState>,
+}
+
+impl CachingControl {
+    /// Enable or disable caching.
+    ///
+    /// While disabled, requests are not looked up and responses are not stored, as if the cache
+    /// were [bypassed](super::BypassMode::All). Encoding is not affected.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.state.disabled.store(!enabled, Ordering::Release);
+        tracing::warn!("caching {}", if enabled { "enabled" } else { "disabled" });
+    }
+
+    /// Whether caching is enabled.
+    pub fn is_enabled(&self) -> bool {
+        !self.state.disabled.load(Ordering::Acquire)
+    }
+
+    /// Override the maximum cacheable body size.
+    ///
+    /// It will not be less than the configured minimum.
+    pub fn set_max_cacheable_body_size(&self, max_body_size: usize) {
+        self.change_overrides(|overrides| overrides.max_body_size = Some(max_body_size));
+    }
+
+    /// Override the enabled encodings, in order from most preferred to least.
+    ///
+    /// See [enable_encodings](super::super::super::CachingLayer::enable_encodings).
+    pub fn set_enabled_encodings(&self, enabled_encodings: Vec<EncodingHeaderValue>) {
+        self.change_overrides(|overrides| {
+            overrides.enabled_encodings = Some(enabled_encodings.into())
+        });
+    }
+
+    /// Clear all overrides (but not [set_enabled](Self::set_enabled)).
+    pub fn clear_overrides(&self) {
+        self.change_overrides(|overrides| *overrides = Default::default());
+    }
+
+    // The generation of the overrides, or [None] if there are none.
+    fn generation(&self) -> Option<u64> {
+        self.state
+            .overridden
+            .load(Ordering::Acquire)
+            .then(|| self.state.generation.load(Ordering::Acquire))
+    }
+
+    // Change the overrides, starting a new generation.
+    fn change_overrides<ChangeT>(&self, change: ChangeT)
+    where
+        ChangeT: FnOnce(&mut ControlOverrides),
+    {
+        // Hold the lock so that concurrent changes aren't lost
+        let mut overrides = self.state.overrides.lock().expect("lock");
+        change(&mut overrides);
+        self.state.generation.fetch_add(1, Ordering::AcqRel);
+        self.state
+            .overridden
+            .store(!overrides.is_empty(), Ordering::Release);
+    }
+
+    // The overrides with their generation.
+    fn overrides(&self) -> (u64, ControlOverrides) {
+        let overrides = self.state.overrides.lock().expect("lock");
+        (
+            self.state.generation.load(Ordering::Acquire),
+            overrides.clone(),
+        )
+    }
+}
+
+impl fmt::Debug for CachingControl {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        let overrides = self.state.overrides.lock().expect("lock");
+        formatter
+            .debug_struct("CachingControl")
+            .field("enabled", &self.is_enabled())
+            .field("max_body_size", &overrides.max_body_size)
+            .field("enabled_encodings", &overrides.enabled_encodings)
+            .finish()
+    }
+}
+
+//
+// ControlledConfiguration
+//
+
+/// Configuration of a service with the overrides of its [CachingControl] applied.
+///
+/// The overridden configuration is built once per change of the overrides, by the first request
+/// after the change, and is then shared by all requests. Without overrides requests get the built
+/// configuration.
+///
+/// Cloning is cheap and clones share state.
+pub struct ControlledConfiguration<RequestBodyT, CacheT, CacheKeyT> {
+    /// Built configuration.
+    pub built: Arc<MiddlewareConfiguration<RequestBodyT, CacheT, CacheKeyT>>,
+
+    overridden: Arc<RwLock<Option<OverriddenConfiguration<RequestBodyT, CacheT, CacheKeyT>>>>,
+}
+
+impl<RequestBodyT, CacheT, CacheKeyT> ControlledConfiguration<RequestBodyT, CacheT, CacheKeyT>
+where
+    CacheT: Cache<CacheKeyT>,
+    CacheKeyT: CacheKey,
+{
+    /// Constructor.
+    pub fn new(built: MiddlewareConfiguration<RequestBodyT, CacheT, CacheKeyT>) -> Self {
+        Self {
+            built: Arc::new(built),
+            overridden: Default::default(),
+        }
+    }
+
+    /// Configuration for a request.
+    pub fn current(&self) -> Arc<MiddlewareConfiguration<RequestBodyT, CacheT, CacheKeyT>> {
+        let control = &self.built.caching.control;
+
+        let Some(generation) = control.generation() else {
+            return self.built.clone();
+        };
+
+        if let Some(overridden) = &*self.overridden.read().expect("lock")
+            && overridden.generation == generation
+        {
+            return overridden.configuration.clone();
+        }
+
+        let mut overridden = self.overridden.write().expect("lock");
+
+        // Another request might have built it while we were waiting for the lock
+        if let Some(overridden) = &*overridden
+            && overridden.generation >= generation
+        {
+            return overridden.configuration.clone();
+        }
+
+        let (generation, overrides) = control.overrides();
+        let mut configuration = (*self.built).clone();
+        overrides.apply(&mut configuration);
+        let configuration = Arc::new(configuration);
+
+        *overridden = Some(OverriddenConfiguration {
+            generation,
+            configuration: configuration.clone(),
+        });
+
+        configuration
+    }
+}
+
+impl<RequestBodyT, CacheT, CacheKeyT> Clone
+    for ControlledConfiguration<RequestBodyT, CacheT, CacheKeyT>
+{
+    fn clone(&self) -> Self {
+        Self {
+            built: self.built.clone(),
+            overridden: self.overridden.clone(),
+        }
+    }
+}
+
+struct OverriddenConfiguration<RequestBodyT, CacheT, CacheKeyT> {
+    generation: u64,
+    configuration: Arc<MiddlewareConfiguration<RequestBodyT, CacheT, CacheKeyT>>,
+}
+
+#[derive(Default)]
+struct ControlState {
+    disabled: AtomicBool,
+    overridden: AtomicBool,
+    generation: AtomicU64,
+    overrides: Mutex<ControlOverrides>,
+}
+
+#[derive(Clone, Default)]
+struct ControlOverrides {
+    max_body_size: Option<usize>,
+    enabled_encodings: Option<Arc<[EncodingHeaderValue]>>,
+}
+
+impl ControlOverrides {
+    fn is_empty(&self) -> bool {
+        self.max_body_size.is_none() && self.enabled_encodings.is_none()
+    }
+
+    fn apply<RequestBodyT, CacheT, CacheKeyT>(
+        self,
+        configuration: &mut MiddlewareConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+    ) {
+        if let Some(max_body_size) = self.max_body_size {
+            let caching = &mut configuration.caching.inner;
+            caching.max_body_size = max_body_size.max(caching.min_body_size);
+        }
+
+        if let Some(enabled_encodings) = self.enabled_encodings {
+            let encoding = &mut configuration.encoding;
+            encoding.enabled_encodings_by_preference = Some(enabled_encodings);
+            encoding.inner.enabled_codings = Some(encoding.enabled_codings());
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use {
+    duration_str::*,
+    std::{fmt, sync::*, time::*},
+};
+
+/// Default maximum number of dependency tokens per entry (see [CacheDependencies]).
+pub const DEFAULT_MAX_DEPENDENCIES: usize = 32;
+
+/// Default maximum length of a dependency token (see [CacheDependencies]).
+pub const DEFAULT_MAX_DEPENDENCY_LENGTH: usize = 256;
+
+//
+// CacheDependencies
+//
+
+/// Accumulator of dependency tokens for the current request.
+///
+/// If enabled, the middleware inserts it into the request extensions before calling the inner
+/// service for a cache miss. Handlers (or extractors) can then record the data that the response
+/// is rendered from, e.g. `product:42`. With axum you can use the `Extension<CacheDependencies>`
+/// extractor (as an `Option` if not all requests have it).
+///
+/// The middleware keeps its own clone, so it doesn't matter whether the extension makes it to
+/// the response. The recorded tokens are stored with the entry (see
+/// [CachedResponse::dependencies](super::super::CachedResponse::dependencies)) and never sent to
+/// the client. Use a [DependencyCache](super::super::DependencyCache) in order to purge entries
+/// by token.
+///
+/// Tokens beyond the maximum count, tokens longer than the maximum length, and empty tokens are
+/// ignored. Duplicates are recorded once.
+///
+/// Dependencies can also bound the freshness of the response (see
+/// [record_with_ttl](Self::record_with_ttl)). The entry's duration is then the shortest of its
+/// resolved duration and all recorded bounds, so that a page is only cached for as long as its
+/// shortest-lived part, no matter which code path rendered that part.
+///
+/// Cloning is cheap and clones share state.
+#[derive(Clone, Debug)]
+pub struct CacheDependencies {
+    /// Maximum number of tokens.
+    pub max_count: usize,
+
+    /// Maximum length of a token.
+    pub max_length: usize,
+
+    tokens: Arc<Mutex<Vec<Arc<str>>>>,
+    bound: Arc<Mutex<Option<DependencyBound>>>,
+}
+
+impl CacheDependencies {
+    /// Constructor.
+    pub fn new(max_count: usize, max_length: usize) -> Self {
+        Self {
+            max_count,
+            max_length,
+            tokens: Default::default(),
+            bound: Default::default(),
+        }
+    }
+
+    /// New accumulator with the same limits.
+    pub fn fresh(&self) -> Self {
+        Self::new(self.max_count, self.max_length)
+    }
+
+    /// Record a dependency token.
+    ///
+    /// Returns false if it was ignored.
+    pub fn record(&self, token: &str) -> bool {
+        if token.is_empty() || token.len() > self.max_length {
+            tracing::debug!("dependency token ignored (length {})", token.len());
+            return false;
+        }
+
+        let mut tokens = self.tokens.lock().expect("lock");
+        if tokens.iter().any(|recorded| **recorded == *token) {
+            return true;
+        }
+
+        if tokens.len() >= self.max_count {
+            tracing::debug!("dependency token ignored (too many): {}", token);
+            return false;
+        }
+
+        tokens.push(token.into());
+        true
+    }
+
+    /// Record a dependency token that bounds the freshness of the response.
+    ///
+    /// The bound applies even if the token itself is ignored. If several bounds are recorded, the
+    /// shortest applies (the first recorded of equal bounds).
+    ///
+    /// Returns false if the token was ignored.
+    pub fn record_with_ttl(&self, token: &str, ttl: Duration) -> bool {
+        {
+            let mut bound = self.bound.lock().expect("lock");
+            if bound.as_ref().is_none_or(|bound| ttl < bound.ttl) {
+                *bound = Some(DependencyBound {
+                    token: token.into(),
+                    ttl,
+                });
+            }
+        }
+
+        self.record(token)
+    }
+
+    /// The shortest recorded bound.
+    pub fn bound(&self) -> Option<DependencyBound> {
+        self.bound.lock().expect("lock").clone()
+    }
+
+    /// The shortest recorded bound if it constrains a duration.
+    ///
+    /// A [None] duration (the cache implementation's default) is always constrained, because we
+    /// can't know it.
+    pub fn constraint(&self, duration: Option<Duration>) -> Option<DependencyBound> {
+        self.bound().filter(|bound| duration.is_none_or(|duration| bound.ttl < duration))
+    }
+
+    /// The recorded tokens.
+    pub fn tokens(&self) -> Arc<[Arc<str>]> {
+        self.tokens.lock().expect("lock").as_slice().into()
+    }
+}
+
+impl Default for CacheDependencies {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_DEPENDENCIES, DEFAULT_MAX_DEPENDENCY_LENGTH)
+    }
+}
+
+//
+// DependencyBound
+//
+
+/// Freshness bound recorded by [CacheDependencies::record_with_ttl].
+///
+/// When it constrains the duration of a stored entry it is also a response extension and part of
+/// the [Store](super::events::CacheEventKind::Store) event, which helps explain short durations.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DependencyBound {
+    /// Dependency token.
+    pub token: Arc<str>,
+
+    /// Time to live.
+    pub ttl: Duration,
+}
+
+impl fmt::Display for DependencyBound {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{} ({})", self.token, self.ttl.human_format())
+    }
+}
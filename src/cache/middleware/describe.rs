@@ -0,0 +1,361 @@
+use super::{bypass::*, configuration::*, method::*, policy::*};
+
+use {
+    http::*,
+    kutil::http::*,
+    std::{fmt::Write, sync::*, time::*},
+};
+
+//
+// PolicyDescription
+//
+
+/// Description of the effective caching policy, for API consumers.
+///
+/// It answers "is this endpoint cached, for how long, and what affects it?" in consumer terms.
+/// Internal details, such as hooks, the cache implementation, and (unless
+/// [opted in](PolicyDescriber::control_headers)) the names of our control headers, are
+/// deliberately not included: application rules are only mentioned as existing.
+///
+/// Note that request headers, e.g. `Authorization` and `Cookie`, do not by themselves bypass the
+/// cache or affect the cache key. Only those listed do.
+///
+/// See [PolicyDescriber].
+#[derive(Clone, Debug)]
+pub struct PolicyDescription {
+    /// Cacheable request methods.
+    pub methods: Vec<Method>,
+
+    /// Whether responses are cached unless excluded (rather than only if included).
+    pub cached_by_default: bool,
+
+    /// Whether application rules may further exclude requests or responses.
+    pub application_rules: bool,
+
+    /// Sources of the cache duration, in order of precedence.
+    pub duration_sources: Vec<&'static str>,
+
+    /// Whether durations are randomly shortened in order to desynchronize expirations.
+    pub duration_jitter: bool,
+
+    /// Minimum cacheable body size.
+    pub min_body_size: usize,
+
+    /// Maximum cacheable body size.
+    pub max_body_size: usize,
+
+    /// Request headers that affect the cache key.
+    pub key_headers: Vec<HeaderName>,
+
+    /// Whether request headers named by the response `Vary` header affect the cache key.
+    pub varied_headers: bool,
+
+    /// Whether the application may add its own dimensions to the cache key.
+    pub application_key: bool,
+
+    /// Whether some paths are cached separately per client group (e.g. per tenant).
+    pub partitioned: bool,
+
+    /// Conditions that bypass the cache.
+    pub bypass_conditions: Vec<&'static str>,
+
+    /// Content encodings in which responses may be served, in order of preference.
+    pub encodings: Vec<String>,
+
+    /// Whether `Cache-Control: no-transform` prevents encoding.
+    pub respects_no_transform: bool,
+
+    /// Active operational override, if any.
+    pub operational_override: Option<String>,
+
+    /// Route policies.
+    pub routes: Vec<RouteDescription>,
+
+    /// Response control headers.
+    ///
+    /// Empty unless [opted in](PolicyDescriber::control_headers).
+    pub control_headers: Vec<HeaderName>,
+}
+
+impl PolicyDescription {
+    /// Constructor.
+    pub fn new<RequestBodyT, CacheT, CacheKeyT>(
+        caching: &MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+        encoding: &MiddlewareEncodingConfiguration,
+    ) -> Self {
+        let methods = match &caching.methods {
+            MethodPolicy::Idempotent => vec![
+                Method::GET,
+                Method::HEAD,
+                Method::OPTIONS,
+                Method::TRACE,
+                Method::PUT,
+                Method::DELETE,
+            ],
+            MethodPolicy::GetOnly => vec![Method::GET],
+            MethodPolicy::Listed(methods) => methods
+                .iter()
+                .filter(|method| method.is_idempotent() || caching.body_key.is_some())
+                .cloned()
+                .collect(),
+        };
+
+        let mut duration_sources = vec!["upstream", "route"];
+        if caching.inner.cache_duration.is_some() {
+            duration_sources.push("application");
+        }
+        if caching.inner.respect_cache_control {
+            duration_sources.push("Cache-Control");
+        }
+        if caching.inner.negative_duration.is_some() {
+            duration_sources.push("negative");
+        }
+        if caching.inner.heuristic_freshness.is_some() {
+            duration_sources.push("Last-Modified");
+        }
+        duration_sources.push("default");
+
+        let mut key_headers = Vec::default();
+        if encoding.enabled_encodings_by_preference.is_some() {
+            key_headers.push(header::ACCEPT_ENCODING);
+        }
+        if caching.language_negotiation.is_some() {
+            key_headers.push(header::ACCEPT_LANGUAGE);
+        }
+        if caching.partition_by_host {
+            key_headers.push(header::HOST);
+        }
+        key_headers.extend(caching.partition_headers.iter().cloned());
+
+        let mut bypass_conditions = vec!["response body size out of range"];
+        if !caching.cache_absolute_form {
+            bypass_conditions.push("absolute-form request target");
+        }
+        if caching.never_cache_unmatched {
+            bypass_conditions.push("no matching route");
+        }
+        if !caching.bypass_headers.is_empty() {
+            bypass_conditions.push("request has a bypass header");
+        }
+        if caching.strict_privacy {
+            bypass_conditions.push("request Authorization");
+        }
+        if caching.vary_keys.is_some() {
+            bypass_conditions.push("response Vary: *");
+        }
+        if caching.inner.respect_cache_control {
+            bypass_conditions.push("response Cache-Control no-store, no-cache, or private");
+            bypass_conditions.push("response Cache-Control max-age=0");
+            if caching.vary_keys.is_none() {
+                bypass_conditions.push("response Vary on a request header that is not in the key");
+            }
+        }
+
+        let encodings = if encoding.assume_inner_compression {
+            Vec::default()
+        } else {
+            let mut encodings: Vec<_> = encoding
+                .enabled_custom_codings_by_preference
+                .iter()
+                .map(|coding| coding.to_string())
+                .collect();
+            if let Some(enabled_encodings) = &encoding.enabled_encodings_by_preference {
+                encodings.extend(enabled_encodings.iter().map(|encoding| {
+                    let value: HeaderValue = (*encoding).into();
+                    value.to_str().unwrap_or_default().into()
+                }));
+            }
+            encodings
+        };
+
+        Self {
+            methods,
+            cached_by_default: caching.inner.cacheable_by_default,
+            application_rules: caching.cacheable_by_request.is_some()
+                || caching.cacheable_by_response.is_some(),
+            duration_sources,
+            duration_jitter: caching.inner.ttl_jitter.is_some(),
+            min_body_size: caching.inner.min_body_size,
+            max_body_size: caching.inner.max_body_size,
+            key_headers,
+            varied_headers: caching.vary_keys.is_some(),
+            application_key: caching.cache_key.is_some(),
+            partitioned: caching.partition.is_some(),
+            bypass_conditions,
+            encodings,
+            respects_no_transform: encoding.inner.respect_no_transform
+                && !encoding.inner.never_transform,
+            operational_override: None,
+            routes: Default::default(),
+            control_headers: Default::default(),
+        }
+    }
+
+    /// Render as a Markdown table, e.g. for inclusion in API documentation.
+    ///
+    /// Route policies are rendered as a second table.
+    pub fn to_markdown(&self) -> String {
+        let mut markdown = String::from("| Property | Value |\n| --- | --- |\n");
+
+        let mut row = |property: &str, value: String| {
+            let _ = writeln!(markdown, "| {} | {} |", property, value.replace('|', "\\|"));
+        };
+
+        row("Cached methods", join(self.methods.iter().map(Method::as_str)));
+        row("Cached by default", yes_no(self.cached_by_default));
+        row("Application rules", yes_no(self.application_rules));
+        row("Duration sources", join(self.duration_sources.iter().copied()));
+        row("Duration jitter", yes_no(self.duration_jitter));
+        row("Body size", format!("{} to {} bytes", self.min_body_size, self.max_body_size));
+        row("Key headers", join(self.key_headers.iter().map(HeaderName::as_str)));
+        row("Varied headers", yes_no(self.varied_headers));
+        row("Application key", yes_no(self.application_key));
+        row("Partitioned", yes_no(self.partitioned));
+        row("Bypassed for", join(self.bypass_conditions.iter().copied()));
+        row("Encodings", join(self.encodings.iter().map(String::as_str)));
+        row("Respects no-transform", yes_no(self.respects_no_transform));
+        row(
+            "Operational override",
+            self.operational_override.clone().unwrap_or_else(|| "none".into()),
+        );
+        if !self.control_headers.is_empty() {
+            row("Control headers", join(self.control_headers.iter().map(HeaderName::as_str)));
+        }
+
+        if !self.routes.is_empty() {
+            markdown.push_str("\n| Route | Cached | Duration | Encoded |\n");
+            markdown.push_str("| --- | --- | --- | --- |\n");
+            for route in &self.routes {
+                let _ = writeln!(
+                    markdown,
+                    "| {} | {} | {} | {} |",
+                    route.path.replace('|', "\\|"),
+                    route.cached.map(yes_no).unwrap_or_else(|| "default".into()),
+                    route
+                        .duration
+                        .map(|duration| format!("{}s", duration.as_secs()))
+                        .unwrap_or_else(|| "default".into()),
+                    route.encoded.map(yes_no).unwrap_or_else(|| "default".into()),
+                );
+            }
+        }
+
+        markdown
+    }
+}
+
+//
+// RouteDescription
+//
+
+/// Description of a [RoutePolicy] (see [PolicyDescription]).
+#[derive(Clone, Debug)]
+pub struct RouteDescription {
+    /// Path, as documented.
+    pub path: String,
+
+    /// Whether responses are cached ([None] means the default).
+    pub cached: Option<bool>,
+
+    /// Cache duration ([None] means the default).
+    pub duration: Option<Duration>,
+
+    /// Whether responses may be encoded ([None] means the default).
+    pub encoded: Option<bool>,
+}
+
+impl RouteDescription {
+    /// Constructor.
+    pub fn new(path: impl Into<String>, policy: &RoutePolicy) -> Self {
+        Self {
+            path: path.into(),
+            cached: policy.cacheable,
+            duration: policy.duration,
+            encoded: policy.encodable,
+        }
+    }
+}
+
+//
+// PolicyDescriber
+//
+
+/// Live source of [PolicyDescription].
+///
+/// The configuration is described when the describer is created (see
+/// [CachingLayer::policy_describer](super::super::super::CachingLayer::policy_describer)), but
+/// runtime state, such as an engaged [CacheOverride], is described on every call.
+///
+/// Route policies travel with the responses of their handlers, so the layer can't enumerate
+/// them. Register them with [route](Self::route) in order to include them.
+///
+/// Cloning is cheap and clones share state.
+#[derive(Clone, Debug)]
+pub struct PolicyDescriber {
+    description: Arc<PolicyDescription>,
+    cache_override: CacheOverride,
+    routes: Arc<RwLock<Vec<RouteDescription>>>,
+    xx_headers: bool,
+}
+
+impl PolicyDescriber {
+    /// Constructor.
+    pub fn new<RequestBodyT, CacheT, CacheKeyT>(
+        caching: &MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+        encoding: &MiddlewareEncodingConfiguration,
+    ) -> Self {
+        Self {
+            description: PolicyDescription::new(caching, encoding).into(),
+            cache_override: caching.cache_override.clone(),
+            routes: Default::default(),
+            xx_headers: caching.xx_headers,
+        }
+    }
+
+    /// Include the names of our response control headers (if enabled), for APIs whose
+    /// consumers are also upstream developers.
+    ///
+    /// The default is false.
+    pub fn control_headers(mut self, control_headers: bool) -> Self {
+        let mut description = (*self.description).clone();
+        description.control_headers = if control_headers && self.xx_headers {
+            vec![XX_CACHE, XX_CACHE_DURATION, XX_ENCODE]
+        } else {
+            Default::default()
+        };
+        self.description = description.into();
+        self
+    }
+
+    /// Register a route policy, replacing any previously registered for the path.
+    pub fn route(&self, path: impl Into<String>, policy: &RoutePolicy) {
+        let route = RouteDescription::new(path, policy);
+        let mut routes = self.routes.write().expect("lock");
+        routes.retain(|existing| existing.path != route.path);
+        routes.push(route);
+    }
+
+    /// Describe.
+    pub fn describe(&self) -> PolicyDescription {
+        let mut description = (*self.description).clone();
+        description.operational_override = self
+            .cache_override
+            .status()
+            .map(|status| format!("{} for {}s", status.mode, status.remaining.as_secs()));
+        description.routes = self.routes.read().expect("lock").clone();
+        description
+    }
+}
+
+fn join<'this>(values: impl Iterator<Item = &'this str>) -> String {
+    let values: Vec<_> = values.collect();
+    if values.is_empty() {
+        "none".into()
+    } else {
+        values.join(", ")
+    }
+}
+
+fn yes_no(value: bool) -> String {
+    if value { "yes" } else { "no" }.into()
+}
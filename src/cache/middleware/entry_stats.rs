@@ -0,0 +1,264 @@
+use super::super::{response::*, weight::*};
+
+use {
+    kutil::std::collections::*,
+    std::{
+        cmp::Reverse,
+        fmt,
+        sync::{atomic::*, *},
+        time::*,
+    },
+};
+
+/// We update the top keys once per this many hits of an entry.
+///
+/// This keeps the per-hit overhead at a single atomic increment.
+pub const ENTRY_STATS_SAMPLE_EVERY: u64 = 8;
+
+//
+// TopKConfig
+//
+
+/// Configuration for [EntryStats].
+#[derive(Clone, Copy, Debug)]
+pub struct TopKConfig {
+    /// Number of keys to track.
+    ///
+    /// Memory is bounded by this number regardless of how many keys there are.
+    pub k: usize,
+
+    /// Counts are halved every this long, so that the report reflects recent traffic.
+    pub decay: Duration,
+}
+
+impl Default for TopKConfig {
+    fn default() -> Self {
+        Self {
+            k: 100,
+            decay: Duration::from_secs(60 * 10),
+        }
+    }
+}
+
+//
+// EntryStats
+//
+
+/// Per-entry statistics.
+///
+/// Tracks the hottest keys using the SpaceSaving algorithm, in which a key's count can be
+/// overestimated by at most the count of the key it replaced (reported as `error`).
+///
+/// Also counts entries that were stored but never hit. This requires the cache implementation to
+/// call [record_eviction](Self::record_eviction), e.g. from Moka's eviction listener.
+///
+/// Cloning is cheap and clones share state.
+#[derive(Clone)]
+pub struct EntryStats {
+    state: Arc<EntryStatsState>,
+}
+
+impl EntryStats {
+    /// Constructor.
+    pub fn new(configuration: TopKConfig) -> Self {
+        Self {
+            state: Arc::new(EntryStatsState {
+                configuration,
+                top_keys: Mutex::new(SpaceSaving::new(configuration.k)),
+                never_hit: Default::default(),
+            }),
+        }
+    }
+
+    /// Record a hit.
+    ///
+    /// `bytes` is the size of the served body.
+    pub fn record_hit(
+        &self,
+        key: &impl fmt::Display,
+        cached_response: &CachedResponse,
+        bytes: usize,
+    ) {
+        let hits = cached_response.hits.fetch_add(1, Ordering::Relaxed) + 1;
+        if !hits.is_multiple_of(ENTRY_STATS_SAMPLE_EVERY) {
+            return;
+        }
+
+        let mut top_keys = self.state.top_keys.lock().expect("lock");
+        top_keys.decay_if_due(self.state.configuration.decay);
+        top_keys.record(
+            key.to_string(),
+            ENTRY_STATS_SAMPLE_EVERY,
+            bytes as u64 * ENTRY_STATS_SAMPLE_EVERY,
+            cached_response.cache_weight(),
+        );
+    }
+
+    /// Record an eviction.
+    pub fn record_eviction(&self, cached_response: &CachedResponse) {
+        if cached_response.hits.load(Ordering::Relaxed) == 0 {
+            self.state.never_hit.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Number of evicted entries that were stored but never hit.
+    pub fn never_hit(&self) -> u64 {
+        self.state.never_hit.load(Ordering::Relaxed)
+    }
+
+    /// The hottest keys, from hottest to coldest.
+    pub fn top_keys(&self, n: usize) -> Vec<KeyStats> {
+        let top_keys = self.state.top_keys.lock().expect("lock");
+
+        let mut keys: Vec<_> = top_keys
+            .counters
+            .iter()
+            .map(|counter| KeyStats {
+                key: counter.key.clone(),
+                hits: counter.hits,
+                error: counter.error,
+                bytes_served: counter.bytes_served,
+                weight: counter.weight,
+                last_hit_age: counter.last_hit.elapsed(),
+            })
+            .collect();
+
+        keys.sort_by_key(|key| Reverse(key.hits));
+        keys.truncate(n);
+        keys
+    }
+}
+
+impl fmt::Debug for EntryStats {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("EntryStats")
+            .field("configuration", &self.state.configuration)
+            .field("never_hit", &self.never_hit())
+            .finish()
+    }
+}
+
+//
+// KeyStats
+//
+
+/// Statistics for a key in [EntryStats::top_keys].
+#[derive(Clone, Debug)]
+pub struct KeyStats {
+    /// Key (its [Display](fmt::Display) representation).
+    pub key: String,
+
+    /// Hits (decayed, and possibly overestimated by up to `error`).
+    pub hits: u64,
+
+    /// Maximum overestimation of `hits`.
+    pub error: u64,
+
+    /// Bytes served (decayed).
+    pub bytes_served: u64,
+
+    /// Entry weight at the last hit.
+    pub weight: usize,
+
+    /// Time since the last hit.
+    pub last_hit_age: Duration,
+}
+
+struct EntryStatsState {
+    configuration: TopKConfig,
+    top_keys: Mutex<SpaceSaving>,
+    never_hit: AtomicU64,
+}
+
+// SpaceSaving top-K.
+//
+// See: Metwally, Agrawal, and El Abbadi, "Efficient Computation of Frequent and Top-k Elements
+// in Data Streams" (2005).
+struct SpaceSaving {
+    capacity: usize,
+    counters: Vec<Counter>,
+    indexes: FastHashMap<String, usize>,
+    last_decay: Instant,
+}
+
+impl SpaceSaving {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            counters: Default::default(),
+            indexes: Default::default(),
+            last_decay: Instant::now(),
+        }
+    }
+
+    fn record(&mut self, key: String, hits: u64, bytes: u64, weight: usize) {
+        let index = match self.indexes.get(&key) {
+            Some(index) => *index,
+
+            None => {
+                if self.counters.len() < self.capacity {
+                    self.counters.push(Counter::new(key.clone(), 0));
+                    self.counters.len() - 1
+                } else {
+                    // Replace the minimum, inheriting its count as error
+                    let (index, minimum) = self
+                        .counters
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, counter)| counter.hits)
+                        .map(|(index, counter)| (index, counter.hits))
+                        .expect("not empty");
+
+                    self.indexes.remove(&self.counters[index].key);
+                    self.counters[index] = Counter::new(key.clone(), minimum);
+                    index
+                }
+            }
+        };
+
+        self.indexes.insert(key, index);
+
+        let counter = &mut self.counters[index];
+        counter.hits += hits;
+        counter.bytes_served += bytes;
+        counter.weight = weight;
+        counter.last_hit = Instant::now();
+    }
+
+    fn decay_if_due(&mut self, decay: Duration) {
+        if self.last_decay.elapsed() < decay {
+            return;
+        }
+
+        for counter in &mut self.counters {
+            counter.hits /= 2;
+            counter.error /= 2;
+            counter.bytes_served /= 2;
+        }
+
+        self.last_decay = Instant::now();
+    }
+}
+
+struct Counter {
+    key: String,
+    hits: u64,
+    error: u64,
+    bytes_served: u64,
+    weight: usize,
+    last_hit: Instant,
+}
+
+impl Counter {
+    fn new(key: String, error: u64) -> Self {
+        Self {
+            key,
+            hits: error,
+            error,
+            bytes_served: 0,
+            weight: 0,
+            last_hit: Instant::now(),
+        }
+    }
+}
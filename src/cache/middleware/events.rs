@@ -0,0 +1,103 @@
+use super::{super::coding::*, dependencies::*};
+
+use {http::*, std::fmt};
+
+//
+// CacheEventKind
+//
+
+/// Kind of [CacheEvent].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CacheEventKind {
+    /// Served from the cache.
+    Hit,
+
+    /// Served `304 Not Modified` from the cache.
+    HitNotModified,
+
+    /// Not found in the cache (including expired entries). The request is sent to the upstream.
+    Miss,
+
+    /// A new entry was stored (after the response was constructed).
+    Store {
+        /// Stored encodings.
+        encodings: Vec<CodingId>,
+
+        /// Body size (the sum of all stored encodings).
+        size: usize,
+
+        /// The dependency bound that constrained the duration, if any (see
+        /// [CacheDependencies::record_with_ttl]).
+        constraint: Option<DependencyBound>,
+    },
+
+    /// The request went directly to the upstream, bypassing the cache.
+    SkipRequest {
+        /// Reason.
+        reason: &'static str,
+    },
+
+    /// The upstream response of a miss was not stored.
+    SkipResponse {
+        /// Reason.
+        reason: &'static str,
+    },
+
+    /// A hit was reencoded, and the new representation will be merged into the entry.
+    ReencodeInCache {
+        /// Representation we reencoded from.
+        from: CodingId,
+
+        /// New representation.
+        to: CodingId,
+    },
+}
+
+impl fmt::Display for CacheEventKind {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Hit => fmt::Display::fmt("hit", formatter),
+            Self::HitNotModified => fmt::Display::fmt("hit (not modified)", formatter),
+            Self::Miss => fmt::Display::fmt("miss", formatter),
+            Self::Store {
+                size,
+                constraint: Some(constraint),
+                ..
+            } => write!(formatter, "store ({} bytes, bounded by {})", size, constraint),
+            Self::Store { size, .. } => write!(formatter, "store ({} bytes)", size),
+            Self::SkipRequest { reason } => write!(formatter, "skip request ({})", reason),
+            Self::SkipResponse { reason } => write!(formatter, "skip response ({})", reason),
+            Self::ReencodeInCache { from, to } => {
+                write!(formatter, "reencode from {} to {}", from, to)
+            }
+        }
+    }
+}
+
+//
+// CacheEvent
+//
+
+/// Context for [CacheEventHook](super::hooks::CacheEventHook).
+///
+/// A request emits its events in order, e.g. [Miss](CacheEventKind::Miss) and then
+/// [Store](CacheEventKind::Store), or just [Hit](CacheEventKind::Hit).
+#[derive(Debug)]
+pub struct CacheEvent<'this, CacheKeyT> {
+    /// Request URI.
+    pub uri: &'this Uri,
+
+    /// Cache key, if the request has one. Its [Display](fmt::Display) is the same as is used for
+    /// logging.
+    pub key: Option<&'this CacheKeyT>,
+
+    /// Kind.
+    pub kind: CacheEventKind,
+}
+
+impl<'this, CacheKeyT> CacheEvent<'this, CacheKeyT> {
+    /// Constructor.
+    pub fn new(uri: &'this Uri, key: Option<&'this CacheKeyT>, kind: CacheEventKind) -> Self {
+        Self { uri, key, kind }
+    }
+}
@@ -0,0 +1,318 @@
+use super::hooks::*;
+
+use {
+    http::{header::*, request::*, uri::*},
+    kutil::std::immutable::*,
+    std::{
+        fmt,
+        mem::*,
+        net::{IpAddr, SocketAddr},
+        sync::*,
+    },
+};
+
+/// `X-Forwarded-Proto` header name.
+pub const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+
+/// `X-Forwarded-Host` header name.
+pub const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+//
+// TrustPolicy
+//
+
+/// Trust policy for forwarded headers.
+///
+/// Forwarded headers are used to resolve the [RequestOrigin] only if the immediate client (the
+/// peer) is a trusted proxy. Otherwise a client could forge them, e.g. in order to store entries
+/// under another host's keys.
+///
+/// The headers are never stripped from the request, only ignored for caching purposes.
+#[derive(Clone)]
+pub struct TrustPolicy {
+    /// Whether to honor the standard `Forwarded` header
+    /// ([IETF RFC 7239](https://datatracker.ietf.org/doc/html/rfc7239)).
+    pub forwarded: bool,
+
+    /// Whether to honor the legacy `X-Forwarded-Proto` and `X-Forwarded-Host` headers.
+    ///
+    /// If `Forwarded` is honored as well then it takes precedence.
+    pub x_forwarded: bool,
+
+    /// Whether the peer is a trusted proxy (hook).
+    pub trusted_proxy: TrustedProxyHook,
+}
+
+impl TrustPolicy {
+    /// Constructor.
+    ///
+    /// Both `Forwarded` and `X-Forwarded-*` are honored by default.
+    pub fn new(
+        trusted_proxy: impl Fn(TrustedProxyHookContext) -> bool + 'static + Send + Sync,
+    ) -> Self {
+        Self {
+            forwarded: true,
+            x_forwarded: true,
+            trusted_proxy: Arc::new(Box::new(trusted_proxy)),
+        }
+    }
+
+    /// Constructor with a peer address predicate.
+    ///
+    /// The peer address is taken from the request extensions, so it must be made available there
+    /// (e.g. with axum's `into_make_service_with_connect_info`). Requests without it are never
+    /// trusted.
+    pub fn trusting_peers(predicate: impl Fn(IpAddr) -> bool + 'static + Send + Sync) -> Self {
+        Self::new(move |context| context.peer.is_some_and(|peer| predicate(peer.ip())))
+    }
+
+    /// Set whether to honor `Forwarded`.
+    pub fn with_forwarded(mut self, forwarded: bool) -> Self {
+        self.forwarded = forwarded;
+        self
+    }
+
+    /// Set whether to honor `X-Forwarded-Proto` and `X-Forwarded-Host`.
+    pub fn with_x_forwarded(mut self, x_forwarded: bool) -> Self {
+        self.x_forwarded = x_forwarded;
+        self
+    }
+
+    /// Whether the request's peer is a trusted proxy.
+    pub fn is_trusted<RequestBodyT>(&self, request: &Request<RequestBodyT>) -> bool {
+        (self.trusted_proxy)(TrustedProxyHookContext::new(
+            peer_address(request),
+            request.headers(),
+        ))
+    }
+}
+
+impl fmt::Debug for TrustPolicy {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("TrustPolicy")
+            .field("forwarded", &self.forwarded)
+            .field("x_forwarded", &self.x_forwarded)
+            .finish()
+    }
+}
+
+//
+// RequestOrigin
+//
+
+/// Effective scheme, host, and port of a request.
+///
+/// Resolved once per request (see
+/// [RequestCacheContext::origin](super::context::RequestCacheContext::origin)) so that all keying
+/// and decision features use the same values.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RequestOrigin {
+    /// Scheme.
+    pub scheme: Option<Scheme>,
+
+    /// Host (lowercase).
+    pub host: Option<ImmutableString>,
+
+    /// Port.
+    pub port: Option<u16>,
+
+    /// Whether resolved from forwarded headers.
+    pub forwarded: bool,
+}
+
+impl RequestOrigin {
+    /// Resolve.
+    ///
+    /// Forwarded values are used only if the [TrustPolicy] trusts the peer. When there are
+    /// multiple values we take the first (the one added by the edge proxy). Each of the scheme
+    /// and the host falls back to the request's own if not forwarded.
+    pub fn resolve<RequestBodyT>(
+        request: &Request<RequestBodyT>,
+        trust_policy: Option<&TrustPolicy>,
+    ) -> Self {
+        let uri = request.uri();
+        let mut scheme = uri.scheme().cloned();
+        let mut authority = uri.authority().cloned().or_else(|| {
+            request
+                .headers()
+                .get(HOST)
+                .and_then(|host| Authority::try_from(host.as_bytes()).ok())
+        });
+        let mut forwarded = false;
+
+        if let Some(trust_policy) = trust_policy
+            && trust_policy.is_trusted(request)
+        {
+            let (forwarded_scheme, forwarded_authority) = forwarded_origin(request, trust_policy);
+
+            if let Some(forwarded_scheme) = forwarded_scheme {
+                scheme = Some(forwarded_scheme);
+                forwarded = true;
+            }
+
+            if let Some(forwarded_authority) = forwarded_authority {
+                authority = Some(forwarded_authority);
+                forwarded = true;
+            }
+        }
+
+        Self {
+            scheme,
+            host: authority
+                .as_ref()
+                .map(|authority| authority.host().to_ascii_lowercase().into()),
+            port: authority.as_ref().and_then(|authority| authority.port_u16()),
+            forwarded,
+        }
+    }
+
+    /// From the scheme and authority of a URI (e.g. an absolute-form request target).
+    pub fn from_uri(uri: &Uri) -> Self {
+        Self {
+            scheme: uri.scheme().cloned(),
+            host: uri.host().map(|host| host.to_ascii_lowercase().into()),
+            port: uri.port_u16(),
+            forwarded: false,
+        }
+    }
+}
+
+/// Parse a `Forwarded` header value into its elements, each a list of lowercase parameter names
+/// and their (unquoted) values.
+///
+/// See [IETF RFC 7239 section 4](https://datatracker.ietf.org/doc/html/rfc7239#section-4).
+pub fn parse_forwarded(value: &str) -> Vec<Vec<(String, String)>> {
+    let mut elements = Vec::default();
+    let mut element = Vec::default();
+    let mut name = String::default();
+    let mut parameter_value = String::default();
+    let mut in_value = false;
+    let mut in_quotes = false;
+    let mut escaped = false;
+
+    for c in value.chars() {
+        if in_quotes {
+            if escaped {
+                parameter_value.push(c);
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_quotes = false;
+            } else {
+                parameter_value.push(c);
+            }
+            continue;
+        }
+
+        match c {
+            '"' if in_value => in_quotes = true,
+            '=' if !in_value => in_value = true,
+
+            ';' | ',' => {
+                if !name.is_empty() {
+                    element.push((name.to_ascii_lowercase(), take(&mut parameter_value)));
+                }
+                name.clear();
+                parameter_value.clear();
+                in_value = false;
+
+                if c == ',' && !element.is_empty() {
+                    elements.push(take(&mut element));
+                }
+            }
+
+            c if c.is_whitespace() => {}
+
+            c => {
+                if in_value {
+                    parameter_value.push(c);
+                } else {
+                    name.push(c);
+                }
+            }
+        }
+    }
+
+    if !name.is_empty() {
+        element.push((name.to_ascii_lowercase(), parameter_value));
+    }
+    if !element.is_empty() {
+        elements.push(element);
+    }
+
+    elements
+}
+
+// Forwarded scheme and authority, if any (and valid).
+fn forwarded_origin<RequestBodyT>(
+    request: &Request<RequestBodyT>,
+    trust_policy: &TrustPolicy,
+) -> (Option<Scheme>, Option<Authority>) {
+    let headers = request.headers();
+    let mut scheme = None;
+    let mut authority = None;
+
+    if trust_policy.forwarded {
+        // Multiple header lines are equivalent to one comma-separated value
+        let first = headers
+            .get_all(FORWARDED)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(parse_forwarded)
+            .next();
+
+        if let Some(first) = first {
+            for (name, value) in first {
+                match name.as_str() {
+                    "proto" => scheme = value.parse().ok(),
+                    "host" => authority = Authority::try_from(value.as_str()).ok(),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    if trust_policy.x_forwarded {
+        if scheme.is_none() {
+            scheme = first_x_forwarded(request, &X_FORWARDED_PROTO)
+                .and_then(|value| value.parse().ok());
+        }
+
+        if authority.is_none() {
+            authority = first_x_forwarded(request, &X_FORWARDED_HOST)
+                .and_then(|value| Authority::try_from(value).ok());
+        }
+    }
+
+    (scheme, authority)
+}
+
+// First value of an X-Forwarded-* header.
+fn first_x_forwarded<'request, RequestBodyT>(
+    request: &'request Request<RequestBodyT>,
+    name: &HeaderName,
+) -> Option<&'request str> {
+    request
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty())
+}
+
+/// Peer address from the request extensions.
+///
+/// Supports axum's `ConnectInfo` (with the `axum` feature) as well as a plain [SocketAddr].
+pub fn peer_address<RequestBodyT>(request: &Request<RequestBodyT>) -> Option<SocketAddr> {
+    #[cfg(feature = "axum")]
+    if let Some(connect_info) =
+        request.extensions().get::<::axum::extract::ConnectInfo<SocketAddr>>()
+    {
+        return Some(connect_info.0);
+    }
+
+    request.extensions().get::<SocketAddr>().copied()
+}
@@ -0,0 +1,168 @@
+use std::{
+    fmt,
+    sync::{atomic::*, *},
+    time::*,
+};
+
+//
+// CacheGenerations
+//
+
+/// Cache generations, for instant rollback of cached content.
+///
+/// Cache keys are tagged with the current generation (see
+/// [CacheKey::set_generation](super::super::key::CacheKey::set_generation)), so bumping the
+/// generation (e.g. on deploy) effectively starts a new cache without invalidating the previous
+/// generation's entries, which age out by their TTL as usual.
+///
+/// The [ServeGeneration] switch decides whether the previous generation's entries are served. At
+/// most two lookups are made per request.
+///
+/// Cloning is cheap and clones share state.
+#[derive(Clone)]
+pub struct CacheGenerations {
+    state: Arc<GenerationsState>,
+}
+
+impl CacheGenerations {
+    /// Constructor.
+    pub fn new(generation: u64) -> Self {
+        Self {
+            state: Arc::new(GenerationsState {
+                base: Instant::now(),
+                current: AtomicU64::new(generation),
+                previous_if_missing: AtomicBool::new(false),
+                force_previous_deadline: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Current generation.
+    pub fn current(&self) -> u64 {
+        self.state.current.load(Ordering::Acquire)
+    }
+
+    /// Previous generation, if there is one.
+    pub fn previous(&self) -> Option<u64> {
+        self.current().checked_sub(1)
+    }
+
+    /// Start a new generation.
+    ///
+    /// Returns the new generation.
+    pub fn bump(&self) -> u64 {
+        let generation = self.state.current.fetch_add(1, Ordering::AcqRel) + 1;
+        tracing::info!("cache generation bumped to {}", generation);
+        generation
+    }
+
+    /// Set the serving mode.
+    ///
+    /// [ForcePrevious](ServeGeneration::ForcePrevious) is time-boxed: it reverts to the previous
+    /// mode when its duration passes.
+    pub fn serve(&self, mode: ServeGeneration) {
+        match mode {
+            ServeGeneration::Current => {
+                self.state.previous_if_missing.store(false, Ordering::Release);
+                self.state.force_previous_deadline.store(0, Ordering::Release);
+            }
+
+            ServeGeneration::PreviousIfMissing => {
+                self.state.previous_if_missing.store(true, Ordering::Release);
+                self.state.force_previous_deadline.store(0, Ordering::Release);
+            }
+
+            ServeGeneration::ForcePrevious(until) => {
+                // Make sure the deadline is never zero
+                let deadline = (self.state.base.elapsed() + until).as_millis() as u64 + 1;
+                self.state
+                    .force_previous_deadline
+                    .store(deadline, Ordering::Release);
+                tracing::warn!("forcing previous cache generation for {:?}", until);
+            }
+        }
+    }
+
+    /// The active serving mode.
+    ///
+    /// For [ForcePrevious](ServeGeneration::ForcePrevious) the duration is the remaining time.
+    pub fn mode(&self) -> ServeGeneration {
+        let deadline = self.state.force_previous_deadline.load(Ordering::Acquire);
+        if deadline != 0 {
+            let now = self.state.base.elapsed().as_millis() as u64;
+            if now < deadline {
+                return ServeGeneration::ForcePrevious(Duration::from_millis(deadline - now));
+            }
+
+            // Expired; only clear if nobody has changed it meanwhile
+            _ = self.state.force_previous_deadline.compare_exchange(
+                deadline,
+                0,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            );
+        }
+
+        if self.state.previous_if_missing.load(Ordering::Acquire) {
+            ServeGeneration::PreviousIfMissing
+        } else {
+            ServeGeneration::Current
+        }
+    }
+}
+
+impl Default for CacheGenerations {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl fmt::Debug for CacheGenerations {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("CacheGenerations")
+            .field("current", &self.current())
+            .field("mode", &self.mode())
+            .finish()
+    }
+}
+
+//
+// ServeGeneration
+//
+
+/// Which [CacheGenerations] generation to serve.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ServeGeneration {
+    /// Serve only the current generation.
+    #[default]
+    Current,
+
+    /// Serve the previous generation's entry when the current generation doesn't have one yet.
+    ///
+    /// Smooths the cold cache after a bump.
+    PreviousIfMissing,
+
+    /// Serve the previous generation's entry even if the current generation has one, until the
+    /// duration passes.
+    ///
+    /// Intended for incident response, while rolling back a bad deploy.
+    ForcePrevious(Duration),
+}
+
+impl fmt::Display for ServeGeneration {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Current => fmt::Display::fmt("current", formatter),
+            Self::PreviousIfMissing => fmt::Display::fmt("previous if missing", formatter),
+            Self::ForcePrevious(until) => write!(formatter, "force previous for {:?}", until),
+        }
+    }
+}
+
+struct GenerationsState {
+    base: Instant,
+    current: AtomicU64,
+    previous_if_missing: AtomicBool,
+    force_previous_deadline: AtomicU64,
+}
@@ -1,4 +1,11 @@
-use {http::request::*, http::*, kutil::transcoding::*, std::sync::*};
+use super::{super::coding::*, admin::*, events::*, forwarded::*, quarantine::*, store::*};
+
+use {
+    http::request::*,
+    http::*,
+    kutil::std::immutable::*,
+    std::{net::SocketAddr, result::Result, sync::*},
+};
 
 /// Hook to check if a request or a response is cacheable.
 pub type CacheableHook = Arc<Box<dyn Fn(CacheableHookContext) -> bool + Send + Sync>>;
@@ -10,6 +17,43 @@ pub type EncodableHook = Arc<Box<dyn Fn(EncodableHookContext) -> bool + Send + S
 pub type CacheKeyHook<CacheKeyT, RequestBodyT> =
     Arc<Box<dyn Fn(CacheKeyHookContext<CacheKeyT, RequestBodyT>) + Send + Sync>>;
 
+/// Hook to update a request's cache key from its body.
+pub type CacheKeyBodyHook<CacheKeyT, RequestBodyT> =
+    Arc<Box<dyn Fn(CacheKeyBodyHookContext<CacheKeyT, RequestBodyT>) + Send + Sync>>;
+
+/// Hook to receive the trailers of a response that is being stored.
+pub type TrailersHook = Arc<Box<dyn Fn(TrailersHookContext) + Send + Sync>>;
+
+/// Hook to check if a request's peer is a trusted proxy.
+pub type TrustedProxyHook = Arc<Box<dyn Fn(TrustedProxyHookContext) -> bool + Send + Sync>>;
+
+/// Hook to map a request to a resource ID.
+pub type ResourceIdHook<CacheKeyT> =
+    Arc<Box<dyn Fn(ResourceIdHookContext<CacheKeyT>) -> Option<String> + Send + Sync>>;
+
+/// Hook to derive a request's cache partition.
+pub type PartitionHook = Arc<Box<dyn Fn(PartitionHookContext) -> Option<String> + Send + Sync>>;
+
+/// Hook to check a cache entry before it is served.
+pub type ServeValidationHook =
+    Arc<Box<dyn Fn(ServeValidationHookContext) -> Result<(), String> + Send + Sync>>;
+
+/// Hook to receive an event for each quarantined cache entry.
+pub type QuarantineHook = Arc<Box<dyn Fn(QuarantineEvent) + Send + Sync>>;
+
+/// Hook to receive an event for each entry stored by the middleware.
+pub type StoreHook<CacheKeyT> = Arc<Box<dyn Fn(StoreEvent<CacheKeyT>) + Send + Sync>>;
+
+/// Hook to receive cache events (hits, misses, stores, etc.).
+pub type CacheEventHook<CacheKeyT> = Arc<Box<dyn Fn(CacheEvent<CacheKeyT>) + Send + Sync>>;
+
+/// Hook to authorize a cache administration request.
+pub type AdminAuthorizationHook =
+    Arc<Box<dyn Fn(AdminAuthorizationHookContext) -> bool + Send + Sync>>;
+
+/// Hook to receive an audit event for each handled cache administration request.
+pub type AdminAuditHook = Arc<Box<dyn Fn(AdminAuditEvent) + Send + Sync>>;
+
 //
 // CacheableHookContext
 //
@@ -38,8 +82,8 @@ impl<'this> CacheableHookContext<'this> {
 /// Context for [EncodableHook].
 #[derive(Clone, Debug)]
 pub struct EncodableHookContext<'this> {
-    /// Encoding.
-    pub encoding: &'this Encoding,
+    /// Coding.
+    pub coding: &'this CodingId,
 
     /// URI.
     pub uri: &'this Uri,
@@ -50,9 +94,9 @@ pub struct EncodableHookContext<'this> {
 
 impl<'this> EncodableHookContext<'this> {
     /// Constructor.
-    pub fn new(encoding: &'this Encoding, uri: &'this Uri, headers: &'this HeaderMap) -> Self {
+    pub fn new(coding: &'this CodingId, uri: &'this Uri, headers: &'this HeaderMap) -> Self {
         Self {
-            encoding,
+            coding,
             uri,
             headers,
         }
@@ -71,11 +115,226 @@ pub struct CacheKeyHookContext<'this, CacheKeyT, RequestBodyT> {
 
     /// Request.
     pub request: &'this Request<RequestBodyT>,
+
+    /// Resolved origin.
+    pub origin: &'this RequestOrigin,
 }
 
 impl<'this, CacheKeyT, RequestBodyT> CacheKeyHookContext<'this, CacheKeyT, RequestBodyT> {
     /// Constructor.
-    pub fn new(cache_key: &'this mut CacheKeyT, request: &'this Request<RequestBodyT>) -> Self {
-        Self { cache_key, request }
+    pub fn new(
+        cache_key: &'this mut CacheKeyT,
+        request: &'this Request<RequestBodyT>,
+        origin: &'this RequestOrigin,
+    ) -> Self {
+        Self {
+            cache_key,
+            request,
+            origin,
+        }
+    }
+}
+
+//
+// CacheKeyBodyHookContext
+//
+
+/// Context for [CacheKeyBodyHook].
+#[derive(Debug)]
+pub struct CacheKeyBodyHookContext<'this, CacheKeyT, RequestBodyT> {
+    /// Cache key.
+    pub cache_key: &'this mut CacheKeyT,
+
+    /// Request (with the buffered body put back).
+    pub request: &'this Request<RequestBodyT>,
+
+    /// Buffered body.
+    pub body: &'this ImmutableBytes,
+}
+
+impl<'this, CacheKeyT, RequestBodyT> CacheKeyBodyHookContext<'this, CacheKeyT, RequestBodyT> {
+    /// Constructor.
+    pub fn new(
+        cache_key: &'this mut CacheKeyT,
+        request: &'this Request<RequestBodyT>,
+        body: &'this ImmutableBytes,
+    ) -> Self {
+        Self {
+            cache_key,
+            request,
+            body,
+        }
+    }
+}
+
+//
+// TrailersHookContext
+//
+
+/// Context for [TrailersHook].
+#[derive(Clone, Debug)]
+pub struct TrailersHookContext<'this> {
+    /// URI.
+    pub uri: &'this Uri,
+
+    /// Trailers.
+    pub trailers: &'this HeaderMap,
+}
+
+impl<'this> TrailersHookContext<'this> {
+    /// Constructor.
+    pub fn new(uri: &'this Uri, trailers: &'this HeaderMap) -> Self {
+        Self { uri, trailers }
+    }
+}
+
+//
+// TrustedProxyHookContext
+//
+
+/// Context for [TrustedProxyHook].
+#[derive(Clone, Debug)]
+pub struct TrustedProxyHookContext<'this> {
+    /// Peer address, if available in the request extensions.
+    pub peer: Option<SocketAddr>,
+
+    /// Request headers.
+    pub headers: &'this HeaderMap,
+}
+
+impl<'this> TrustedProxyHookContext<'this> {
+    /// Constructor.
+    pub fn new(peer: Option<SocketAddr>, headers: &'this HeaderMap) -> Self {
+        Self { peer, headers }
+    }
+}
+
+//
+// ResourceIdHookContext
+//
+
+/// Context for [ResourceIdHook].
+#[derive(Debug)]
+pub struct ResourceIdHookContext<'this, CacheKeyT> {
+    /// URI.
+    pub uri: &'this Uri,
+
+    /// Request headers.
+    pub headers: &'this HeaderMap,
+
+    /// Cache key.
+    pub cache_key: &'this CacheKeyT,
+}
+
+impl<'this, CacheKeyT> ResourceIdHookContext<'this, CacheKeyT> {
+    /// Constructor.
+    pub fn new(uri: &'this Uri, headers: &'this HeaderMap, cache_key: &'this CacheKeyT) -> Self {
+        Self {
+            uri,
+            headers,
+            cache_key,
+        }
+    }
+}
+
+//
+// PartitionHookContext
+//
+
+/// Context for [PartitionHook].
+#[derive(Clone, Debug)]
+pub struct PartitionHookContext<'this> {
+    /// URI.
+    pub uri: &'this Uri,
+
+    /// Request headers.
+    pub headers: &'this HeaderMap,
+
+    /// Request extensions, e.g. for the authenticated tenant.
+    pub extensions: &'this Extensions,
+}
+
+impl<'this> PartitionHookContext<'this> {
+    /// Constructor.
+    pub fn new(uri: &'this Uri, headers: &'this HeaderMap, extensions: &'this Extensions) -> Self {
+        Self {
+            uri,
+            headers,
+            extensions,
+        }
+    }
+}
+
+//
+// ServeValidationHookContext
+//
+
+/// Context for [ServeValidationHook].
+#[derive(Clone, Debug)]
+pub struct ServeValidationHookContext<'this> {
+    /// URI.
+    pub uri: &'this Uri,
+
+    /// Cached response headers.
+    pub headers: &'this HeaderMap,
+
+    /// Length of the body as received from the upstream, if its representation is available.
+    pub body_length: Option<usize>,
+
+    /// Prefix of the body, if available.
+    pub body_prefix: Option<&'this [u8]>,
+}
+
+impl<'this> ServeValidationHookContext<'this> {
+    /// Constructor.
+    pub fn new(
+        uri: &'this Uri,
+        headers: &'this HeaderMap,
+        body_length: Option<usize>,
+        body_prefix: Option<&'this [u8]>,
+    ) -> Self {
+        Self {
+            uri,
+            headers,
+            body_length,
+            body_prefix,
+        }
+    }
+}
+
+//
+// AdminAuthorizationHookContext
+//
+
+/// Context for [AdminAuthorizationHook].
+#[derive(Clone, Debug)]
+pub struct AdminAuthorizationHookContext<'this> {
+    /// Verb.
+    pub verb: AdminVerb,
+
+    /// URI.
+    pub uri: &'this Uri,
+
+    /// Peer address, if available in the request extensions.
+    pub peer: Option<SocketAddr>,
+
+    /// Request headers.
+    pub headers: &'this HeaderMap,
+}
+
+impl<'this> AdminAuthorizationHookContext<'this> {
+    /// Constructor.
+    pub fn new(
+        verb: AdminVerb,
+        uri: &'this Uri,
+        peer: Option<SocketAddr>,
+        headers: &'this HeaderMap,
+    ) -> Self {
+        Self {
+            verb,
+            uri,
+            peer,
+            headers,
+        }
     }
 }
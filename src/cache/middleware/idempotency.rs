@@ -0,0 +1,511 @@
+use super::{immutable::*, request_body::*, responses::*};
+
+use {
+    http::{header::*, *},
+    http_body::*,
+    kutil::{
+        http::transcoding::*,
+        std::{collections::*, error::*, immutable::*},
+    },
+    std::{
+        fmt, future,
+        hash::*,
+        result::Result,
+        sync::*,
+        task::{Poll, Waker},
+        time::*,
+    },
+};
+
+/// `Idempotency-Key` request header.
+pub const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+
+/// `Idempotency-Replayed` response header.
+pub const IDEMPOTENCY_REPLAYED: HeaderName = HeaderName::from_static("idempotency-replayed");
+
+/// Default time-to-live of [IdempotencyConfig] responses (24 hours).
+pub const DEFAULT_IDEMPOTENCY_TTL: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// Default maximum [IdempotencyConfig] response body size (1 MiB).
+pub const DEFAULT_IDEMPOTENCY_MAX_BODY: usize = 1024 * 1024;
+
+/// Default time that [IdempotencyConfig] duplicates wait for the first execution.
+pub const DEFAULT_IDEMPOTENCY_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default maximum number of [IdempotencyConfig] entries.
+pub const DEFAULT_IDEMPOTENCY_CAPACITY: usize = 10 * 1024;
+
+// Request headers that identify the payload when we don't buffer the body.
+const FINGERPRINT_HEADERS: &[&str] = &[
+    "content-type",
+    "content-length",
+    "content-digest",
+    "repr-digest",
+    "digest",
+];
+
+//
+// IdempotencyConfig
+//
+
+/// Deduplication of non-idempotent requests carrying an `Idempotency-Key` header.
+///
+/// Clients retry requests (e.g. `POST`) with the same key and expect the same response without
+/// the operation being executed again. For matching paths, requests with a non-idempotent method
+/// are keyed by method, path, and `Idempotency-Key`:
+///
+/// * The first request is executed, and its response (of any status, including errors) is kept
+///   for the TTL, as received.
+/// * Retries within the TTL receive the kept response verbatim, with `Idempotency-Replayed: true`.
+/// * Duplicates that arrive during the first execution wait for it, rather than executing
+///   concurrently. If it takes longer than the wait timeout they receive `409 Conflict`. If it
+///   fails (no response, or a body larger than `max_body`) then nothing is kept, and the next
+///   duplicate executes.
+/// * A retry with a different payload receives `422 Unprocessable Content`.
+/// * If `require_key` is set then requests without the header receive `400 Bad Request`.
+///   Otherwise they are not affected.
+///
+/// This is entirely separate from response caching: kept responses are never encoded,
+/// conditional requests are not evaluated, and they are not in the cache, so they are not
+/// affected by invalidation.
+///
+/// The payload is fingerprinted by its `Content-Type` and body. The body is buffered (and then
+/// passed on) if it has a declared `Content-Length` of at most `max_body`. Other payloads are
+/// fingerprinted by their `Content-Type`, `Content-Length`, and digest (`Content-Digest`,
+/// `Repr-Digest`, or `Digest`) headers, so for them mismatches are reliably detected only for
+/// clients that send a digest.
+///
+/// Kept responses are in memory, and are lost on restart. If the capacity is reached then new
+/// keys are not deduplicated (and a warning is logged) until entries expire.
+///
+/// Requires the `idempotency` feature.
+///
+/// Cloning is cheap and clones share state.
+#[derive(Clone)]
+pub struct IdempotencyConfig {
+    /// Matcher.
+    pub matcher: PathMatcher,
+
+    /// Time-to-live of kept responses.
+    pub ttl: Duration,
+
+    /// Maximum body size of kept responses and of buffered request bodies.
+    pub max_body: usize,
+
+    /// Whether to reject matching requests without an `Idempotency-Key`.
+    pub require_key: bool,
+
+    /// Time that duplicates wait for the first execution.
+    pub wait_timeout: Duration,
+
+    /// Maximum number of entries.
+    pub capacity: usize,
+
+    state: Arc<IdempotencyState>,
+}
+
+impl IdempotencyConfig {
+    /// Constructor.
+    pub fn new(matcher: PathMatcher) -> Self {
+        Self {
+            matcher,
+            ttl: DEFAULT_IDEMPOTENCY_TTL,
+            max_body: DEFAULT_IDEMPOTENCY_MAX_BODY,
+            require_key: false,
+            wait_timeout: DEFAULT_IDEMPOTENCY_WAIT_TIMEOUT,
+            capacity: DEFAULT_IDEMPOTENCY_CAPACITY,
+            state: Default::default(),
+        }
+    }
+
+    /// Set time-to-live of kept responses.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Set maximum body size of kept responses and of buffered request bodies.
+    pub fn max_body(mut self, max_body: usize) -> Self {
+        self.max_body = max_body;
+        self
+    }
+
+    /// Set whether to reject matching requests without an `Idempotency-Key`.
+    pub fn require_key(mut self, require_key: bool) -> Self {
+        self.require_key = require_key;
+        self
+    }
+
+    /// Set time that duplicates wait for the first execution.
+    pub fn wait_timeout(mut self, wait_timeout: Duration) -> Self {
+        self.wait_timeout = wait_timeout;
+        self
+    }
+
+    /// Set maximum number of entries.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Whether the request is subject to deduplication.
+    pub fn applies(&self, method: &Method, path: &str) -> bool {
+        !method.is_idempotent() && self.matcher.matches(path)
+    }
+
+    /// Begin handling a request.
+    ///
+    /// `body` is the buffered request body, if any. Waits if a duplicate is executing.
+    pub async fn begin(
+        &self,
+        method: &Method,
+        path: &str,
+        headers: &HeaderMap,
+        body: Option<&[u8]>,
+    ) -> Idempotency {
+        let Some(key) = headers.get(IDEMPOTENCY_KEY) else {
+            return if self.require_key {
+                Idempotency::MissingKey
+            } else {
+                Idempotency::Bypass
+            };
+        };
+
+        // It's a structured field string, but we are lenient
+        let key = match key.to_str() {
+            Ok(key) if !key.trim().trim_matches('"').is_empty() => key.trim().trim_matches('"'),
+            _ => return Idempotency::MissingKey,
+        };
+
+        let key = IdempotencyKey {
+            method: method.clone(),
+            path: path.into(),
+            key: key.into(),
+        };
+
+        let fingerprint = match body {
+            Some(body) => self.state.hasher.hash_one((headers.get(CONTENT_TYPE), body)),
+
+            None => self.state.hasher.hash_one(
+                FINGERPRINT_HEADERS
+                    .iter()
+                    .map(|name| headers.get(*name))
+                    .collect::<Vec<_>>(),
+            ),
+        };
+
+        let waiting =
+            future::poll_fn(|context| self.poll_begin(&key, fingerprint, context.waker()));
+
+        match ::tokio::time::timeout(self.wait_timeout, waiting).await {
+            Ok(idempotency) => idempotency,
+            Err(_) => {
+                tracing::debug!("idempotency: timed out waiting for {}", key.key);
+                Idempotency::Conflict
+            }
+        }
+    }
+
+    fn poll_begin(
+        &self,
+        key: &IdempotencyKey,
+        fingerprint: u64,
+        waker: &Waker,
+    ) -> Poll<Idempotency> {
+        let mut entries = self.state.entries.lock().expect("lock");
+
+        if let Some(IdempotencyEntry::Done { expires, .. }) = entries.get(key)
+            && *expires <= Instant::now()
+        {
+            entries.remove(key);
+        }
+
+        match entries.get_mut(key) {
+            Some(IdempotencyEntry::Done {
+                fingerprint: kept_fingerprint,
+                response,
+                ..
+            }) => Poll::Ready(if *kept_fingerprint == fingerprint {
+                Idempotency::Replay(response.clone())
+            } else {
+                Idempotency::Mismatch
+            }),
+
+            Some(IdempotencyEntry::InFlight {
+                fingerprint: executing_fingerprint,
+                waiters,
+            }) => {
+                if *executing_fingerprint != fingerprint {
+                    Poll::Ready(Idempotency::Mismatch)
+                } else {
+                    if !waiters.iter().any(|waiter| waiter.will_wake(waker)) {
+                        waiters.push(waker.clone());
+                    }
+                    Poll::Pending
+                }
+            }
+
+            None => {
+                if entries.len() >= self.capacity {
+                    let now = Instant::now();
+                    entries.retain(|_, entry| match entry {
+                        IdempotencyEntry::Done { expires, .. } => *expires > now,
+                        IdempotencyEntry::InFlight { .. } => true,
+                    });
+
+                    if entries.len() >= self.capacity {
+                        tracing::warn!("idempotency: capacity reached, not deduplicating");
+                        return Poll::Ready(Idempotency::Bypass);
+                    }
+                }
+
+                entries.insert(
+                    key.clone(),
+                    IdempotencyEntry::InFlight {
+                        fingerprint,
+                        waiters: Default::default(),
+                    },
+                );
+
+                Poll::Ready(Idempotency::Execute(IdempotencyGuard {
+                    state: self.state.clone(),
+                    key: key.clone(),
+                    fingerprint,
+                    ttl: self.ttl,
+                }))
+            }
+        }
+    }
+}
+
+impl fmt::Debug for IdempotencyConfig {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("IdempotencyConfig")
+            .field("matcher", &self.matcher)
+            .field("ttl", &self.ttl)
+            .field("max_body", &self.max_body)
+            .field("require_key", &self.require_key)
+            .field("wait_timeout", &self.wait_timeout)
+            .field("capacity", &self.capacity)
+            .field("entries", &self.state.entries.lock().expect("lock").len())
+            .finish()
+    }
+}
+
+//
+// IdempotentRequests
+//
+
+/// [IdempotencyConfig] with the buffering of request bodies for payload fingerprints.
+///
+/// See [idempotency](super::super::super::CachingLayer::idempotency).
+pub struct IdempotentRequests<RequestBodyT> {
+    /// Configuration.
+    pub config: IdempotencyConfig,
+
+    buffer: RequestBodyBuffer<RequestBodyT>,
+}
+
+impl<RequestBodyT> IdempotentRequests<RequestBodyT> {
+    /// Constructor.
+    pub fn new(config: IdempotencyConfig) -> Self
+    where
+        RequestBodyT: 'static + Body + From<ImmutableBytes> + Send + Unpin,
+        RequestBodyT::Error: Into<CapturedError>,
+    {
+        Self {
+            config,
+            buffer: RequestBodyBuffer::new(),
+        }
+    }
+
+    /// Begin handling a request (see [IdempotencyConfig::begin]).
+    ///
+    /// The returned request has the buffered body. Returns an error if the body could not be
+    /// read, in which case the request is lost.
+    pub async fn begin(
+        &self,
+        request: Request<RequestBodyT>,
+    ) -> Result<(Request<RequestBodyT>, Idempotency), String> {
+        let (request, body) = match self.buffer.buffer(request, self.config.max_body).await {
+            BufferedRequestBody::Buffered(request, body) => (request, Some(body)),
+            BufferedRequestBody::NotBuffered(request) => (request, None),
+            BufferedRequestBody::Failed(error) => return Err(error),
+        };
+
+        let idempotency = self
+            .config
+            .begin(request.method(), request.uri().path(), request.headers(), body.as_deref())
+            .await;
+
+        Ok((request, idempotency))
+    }
+}
+
+impl<RequestBodyT> Clone for IdempotentRequests<RequestBodyT> {
+    fn clone(&self) -> Self {
+        Self {
+            config: self.config.clone(),
+            buffer: self.buffer.clone(),
+        }
+    }
+}
+
+//
+// Idempotency
+//
+
+/// How to handle a request subject to [IdempotencyConfig].
+#[derive(Debug)]
+pub enum Idempotency {
+    /// Execute it, and [complete](IdempotencyGuard::complete) the guard with its response.
+    Execute(IdempotencyGuard),
+
+    /// Replay the kept response.
+    Replay(Arc<IdempotentResponse>),
+
+    /// The key was used with a different payload (`422 Unprocessable Content`).
+    Mismatch,
+
+    /// A duplicate is still executing (`409 Conflict`).
+    Conflict,
+
+    /// The key is missing or invalid (`400 Bad Request`).
+    MissingKey,
+
+    /// Not deduplicated.
+    Bypass,
+}
+
+//
+// IdempotencyGuard
+//
+
+/// The first execution of an idempotency key.
+///
+/// If dropped without being completed then the key is released, and the next duplicate executes.
+#[derive(Debug)]
+pub struct IdempotencyGuard {
+    state: Arc<IdempotencyState>,
+    key: IdempotencyKey,
+    fingerprint: u64,
+    ttl: Duration,
+}
+
+impl IdempotencyGuard {
+    /// Keep the response for replay.
+    pub fn complete(self, response: IdempotentResponse) {
+        let entry = IdempotencyEntry::Done {
+            fingerprint: self.fingerprint,
+            response: response.into(),
+            expires: Instant::now() + self.ttl,
+        };
+        self.finish(Some(entry));
+    }
+
+    fn finish(&self, entry: Option<IdempotencyEntry>) {
+        let mut entries = self.state.entries.lock().expect("lock");
+
+        let previous = match entry {
+            Some(entry) => entries.insert(self.key.clone(), entry),
+            None => entries.remove(&self.key),
+        };
+
+        if let Some(IdempotencyEntry::InFlight { waiters, .. }) = previous {
+            for waiter in waiters {
+                waiter.wake();
+            }
+        }
+    }
+}
+
+impl Drop for IdempotencyGuard {
+    fn drop(&mut self) {
+        let in_flight = matches!(
+            self.state.entries.lock().expect("lock").get(&self.key),
+            Some(IdempotencyEntry::InFlight { .. })
+        );
+
+        if in_flight {
+            self.finish(None);
+        }
+    }
+}
+
+//
+// IdempotentResponse
+//
+
+/// Response kept by [IdempotencyConfig].
+#[derive(Clone, Debug)]
+pub struct IdempotentResponse {
+    /// Status.
+    pub status: StatusCode,
+
+    /// Headers.
+    pub headers: HeaderMap,
+
+    /// Body.
+    pub body: ImmutableBytes,
+}
+
+impl IdempotentResponse {
+    /// Constructor.
+    pub fn new(status: StatusCode, headers: HeaderMap, body: ImmutableBytes) -> Self {
+        Self {
+            status,
+            headers,
+            body,
+        }
+    }
+
+    /// To a [Response].
+    ///
+    /// If `replayed` then sets `Idempotency-Replayed: true`.
+    pub fn to_response<ResponseBodyT>(
+        &self,
+        replayed: bool,
+    ) -> Response<TranscodingBody<ResponseBodyT>>
+    where
+        ResponseBodyT: Body + From<ImmutableBytes>,
+        ResponseBodyT::Error: Into<CapturedError>,
+    {
+        let mut response = Response::new(ResponseBodyT::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        if replayed {
+            response
+                .headers_mut()
+                .insert(IDEMPOTENCY_REPLAYED, HeaderValue::from_static("true"));
+        }
+        response.map(|body| passthrough_with_trailers(body, Default::default()))
+    }
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct IdempotencyKey {
+    method: Method,
+    path: String,
+    key: String,
+}
+
+#[derive(Debug)]
+enum IdempotencyEntry {
+    InFlight {
+        fingerprint: u64,
+        waiters: Vec<Waker>,
+    },
+
+    Done {
+        fingerprint: u64,
+        response: Arc<IdempotentResponse>,
+        expires: Instant,
+    },
+}
+
+#[derive(Debug, Default)]
+struct IdempotencyState {
+    hasher: RandomState,
+    entries: Mutex<FastHashMap<IdempotencyKey, IdempotencyEntry>>,
+}
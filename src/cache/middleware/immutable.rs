@@ -0,0 +1,166 @@
+use {
+    http::*,
+    kutil::std::collections::*,
+    std::{fmt, sync::*, time::*},
+};
+
+/// Default `max-age` for immutable assets (one year).
+pub const DEFAULT_IMMUTABLE_MAX_AGE: Duration = Duration::from_secs(60 * 60 * 24 * 365);
+
+/// Hook for [PathMatcher].
+pub type PathMatcherHook = Arc<Box<dyn Fn(&str) -> bool + Send + Sync>>;
+
+//
+// ImmutablePaths
+//
+
+/// Immutable asset profile.
+///
+/// Intended for fingerprinted assets (e.g. `app.3f9ab2.js`) that cannot change by construction.
+/// For matching paths:
+///
+/// * Entries are stored for `max_age`, which is effectively until evicted by weight.
+/// * Any conditional request (`If-None-Match` or `If-Modified-Since`) for a cached entry is
+///   answered with `304 Not Modified` without comparing validators.
+/// * `Cache-Control: public, max-age=..., immutable` is set on served responses.
+///
+/// As a safety valve, if a stored entry is replaced by different content then the path is
+/// demoted to normal semantics (and an error is logged), because the assumption of immutability
+/// was evidently wrong.
+///
+/// Cloning is cheap and clones share state.
+#[derive(Clone)]
+pub struct ImmutablePaths {
+    /// Matcher.
+    pub matcher: PathMatcher,
+
+    /// Max age.
+    pub max_age: Duration,
+
+    demoted: Arc<Mutex<FastHashSet<String>>>,
+}
+
+impl ImmutablePaths {
+    /// Constructor.
+    pub fn new(matcher: PathMatcher) -> Self {
+        Self {
+            matcher,
+            max_age: DEFAULT_IMMUTABLE_MAX_AGE,
+            demoted: Default::default(),
+        }
+    }
+
+    /// Set max age.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Whether the path matches and has not been demoted.
+    pub fn matches(&self, path: &str) -> bool {
+        self.matcher.matches(path) && !self.demoted.lock().expect("lock").contains(path)
+    }
+
+    /// Demote a path to normal semantics.
+    pub fn demote(&self, path: &str) {
+        tracing::error!("immutable path changed content, demoting: {}", path);
+        self.demoted.lock().expect("lock").insert(path.into());
+    }
+
+    /// `Cache-Control` header value.
+    pub fn cache_control(&self) -> HeaderValue {
+        HeaderValue::try_from(format!("public, max-age={}, immutable", self.max_age.as_secs()))
+            .expect("ASCII")
+    }
+}
+
+impl From<PathMatcher> for ImmutablePaths {
+    fn from(matcher: PathMatcher) -> Self {
+        Self::new(matcher)
+    }
+}
+
+impl fmt::Debug for ImmutablePaths {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("ImmutablePaths")
+            .field("matcher", &self.matcher)
+            .field("max_age", &self.max_age)
+            .finish()
+    }
+}
+
+//
+// PathMatcher
+//
+
+/// URI path matcher.
+#[derive(Clone)]
+pub enum PathMatcher {
+    /// Path prefix.
+    Prefix(String),
+
+    /// Glob, in which `*` matches within a path segment and `**` matches across segments.
+    Glob(String),
+
+    /// Hook.
+    Hook(PathMatcherHook),
+}
+
+impl PathMatcher {
+    /// Hook constructor.
+    pub fn hook(hook: impl Fn(&str) -> bool + 'static + Send + Sync) -> Self {
+        Self::Hook(Arc::new(Box::new(hook)))
+    }
+
+    /// Whether the path matches.
+    pub fn matches(&self, path: &str) -> bool {
+        match self {
+            Self::Prefix(prefix) => path.starts_with(prefix.as_str()),
+            Self::Glob(glob) => glob_matches(glob.as_bytes(), path.as_bytes()),
+            Self::Hook(hook) => hook(path),
+        }
+    }
+}
+
+impl fmt::Debug for PathMatcher {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Prefix(prefix) => formatter.debug_tuple("Prefix").field(prefix).finish(),
+            Self::Glob(glob) => formatter.debug_tuple("Glob").field(glob).finish(),
+            Self::Hook(_) => formatter.write_str("Hook"),
+        }
+    }
+}
+
+fn glob_matches(glob: &[u8], path: &[u8]) -> bool {
+    match glob.split_first() {
+        None => path.is_empty(),
+
+        Some((b'*', rest)) => {
+            let (across_segments, rest) = match rest.split_first() {
+                Some((b'*', rest)) => (true, rest),
+                _ => (false, rest),
+            };
+
+            // Try every possible length for the wildcard
+            for length in 0..=path.len() {
+                if glob_matches(rest, &path[length..]) {
+                    return true;
+                }
+
+                if length < path.len() && !across_segments && path[length] == b'/' {
+                    break;
+                }
+            }
+
+            false
+        }
+
+        Some((byte, rest)) => {
+            path.split_first().is_some_and(|(path_byte, path_rest)| {
+                path_byte == byte && glob_matches(rest, path_rest)
+            })
+        }
+    }
+}
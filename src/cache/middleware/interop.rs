@@ -0,0 +1,55 @@
+use {
+    http::*,
+    kutil::{http::*, std::collections::*, transcoding::*},
+    std::sync::*,
+};
+
+/// Explanation of the compression conflict hazard and how to fix it.
+pub const COMPRESSION_CONFLICT_HELP: &str = "the inner service returned an already-encoded \
+response while this layer's encoding is enabled, which suggests a compression middleware (e.g. \
+tower-http's CompressionLayer) below the caching layer; either remove that middleware and let \
+this layer handle compression, or keep it and call CachingLayer::assume_inner_compression(true)";
+
+// Maximum number of routes to remember having warned about.
+const MAX_WARNED_ROUTES: usize = 1024;
+
+//
+// CompressionConflictDetector
+//
+
+/// Detects the hazard of stacking another compression middleware with this layer.
+///
+/// Warns once per route (path).
+///
+/// Cloning is cheap and clones share state.
+#[derive(Clone, Debug, Default)]
+pub struct CompressionConflictDetector {
+    warned: Arc<Mutex<FastHashSet<String>>>,
+}
+
+impl CompressionConflictDetector {
+    /// Check an upstream response.
+    ///
+    /// Returns true if we warned.
+    pub fn check(&self, uri: &Uri, headers: &HeaderMap) -> bool {
+        let encoding: Encoding = headers.content_encoding().into();
+        if encoding == Encoding::Identity {
+            return false;
+        }
+
+        let path = uri.path();
+        let mut warned = self.warned.lock().expect("lock");
+        if warned.contains(path) || warned.len() >= MAX_WARNED_ROUTES {
+            return false;
+        }
+
+        warned.insert(path.into());
+        tracing::warn!(
+            "compression conflict for {} ({}): {}",
+            path,
+            encoding,
+            COMPRESSION_CONFLICT_HELP
+        );
+        true
+    }
+}
@@ -0,0 +1,42 @@
+use http::{request::*, *};
+
+//
+// KeyUriSource
+//
+
+/// Which URI to use for cache keys.
+///
+/// With axum, where the layer is attached matters: a layer attached to a nested router (see
+/// `Router::nest`) sees the URI with the nesting prefix stripped, while a layer attached to the
+/// outer router sees the full URI. Using the original URI makes keys consistent regardless of
+/// the attachment point.
+///
+/// The recommended attachment point is the outer router, via `Router::layer`, so that a single
+/// cache serves all nested routers.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum KeyUriSource {
+    /// The original URI, from axum's `OriginalUri` request extension if available (requires the
+    /// `axum` feature), otherwise as seen.
+    #[default]
+    Original,
+
+    /// The URI as seen by the layer.
+    AsSeen,
+}
+
+impl KeyUriSource {
+    /// The URI for a request.
+    pub fn uri<'request, RequestBodyT>(
+        &self,
+        request: &'request Request<RequestBodyT>,
+    ) -> &'request Uri {
+        #[cfg(feature = "axum")]
+        if *self == Self::Original
+            && let Some(original_uri) = request.extensions().get::<::axum::extract::OriginalUri>()
+        {
+            return &original_uri.0;
+        }
+
+        request.uri()
+    }
+}
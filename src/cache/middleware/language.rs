@@ -0,0 +1,91 @@
+use {http::header::*, kutil::http::*};
+
+//
+// LanguageNegotiation
+//
+
+/// `Accept-Language` content negotiation against a bounded set of supported languages.
+///
+/// The first supported language is the default.
+///
+/// Negotiation follows the "lookup" scheme of
+/// [IETF RFC 4647 section 3.4](https://datatracker.ietf.org/doc/html/rfc4647#section-3.4): for each
+/// requested language, in order of preference, we progressively truncate its subtags until we find
+/// a supported language (e.g. `de-AT` → `de`). If nothing matches we fall back to the default.
+/// Languages with a weight of `q=0` are "not acceptable" per
+/// [IETF RFC 9110 section 12.4.2](https://datatracker.ietf.org/doc/html/rfc9110#section-12.4.2)
+/// and are skipped.
+///
+/// The result is always one of the supported languages and never the raw client value, which
+/// guarantees that the cache key space is bounded by the supported set.
+#[derive(Clone, Debug)]
+pub struct LanguageNegotiation {
+    /// Supported languages (the first is the default).
+    pub supported: Vec<Language>,
+}
+
+impl LanguageNegotiation {
+    /// Constructor.
+    ///
+    /// `supported` must not be empty!
+    pub fn new(supported: Vec<Language>) -> Self {
+        assert!(!supported.is_empty());
+        Self { supported }
+    }
+
+    /// The default language.
+    pub fn default_language(&self) -> &Language {
+        &self.supported[0]
+    }
+
+    /// Negotiate the best supported language for request headers.
+    pub fn negotiate(&self, headers: &HeaderMap) -> &Language {
+        for preference in headers.accept_language().0 {
+            if preference.weight == Weight::new(0) {
+                continue;
+            }
+
+            if let Selector::Specific(language) = preference.selector
+                && let Some(language) = self.lookup(&language)
+            {
+                return language;
+            }
+        }
+
+        self.default_language()
+    }
+
+    /// Find the supported language for a language, progressively truncating its subtags.
+    pub fn lookup(&self, language: &Language) -> Option<&Language> {
+        let language = language.to_string();
+        let mut subtags: Vec<_> = language.split("-").collect();
+
+        while !subtags.is_empty() {
+            let candidate = Language::from(subtags.join("-").as_str());
+            if let Some(language) = self.supported.iter().find(|language| **language == candidate) {
+                return Some(language);
+            }
+            subtags.pop();
+        }
+
+        None
+    }
+
+    /// Set `Content-Language` to the negotiated language and add `Accept-Language` to `Vary`.
+    pub fn set_response_headers(language: &Language, headers: &mut HeaderMap) {
+        headers.set_value(CONTENT_LANGUAGE, language.clone());
+
+        if !headers.get_all(VARY).iter().any(|value| {
+            value
+                .to_str()
+                .map(|value| {
+                    value
+                        .split(",")
+                        .any(|name| name.trim().eq_ignore_ascii_case(ACCEPT_LANGUAGE.as_str()))
+                })
+                .unwrap_or_default()
+        }) {
+            headers.append(VARY, HeaderValue::from_static("Accept-Language"));
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use {
+    kutil::std::collections::*,
+    std::{
+        fmt,
+        hash::*,
+        sync::{atomic::*, *},
+        time::*,
+    },
+};
+
+//
+// LearnedBypassConfig
+//
+
+/// Configuration for [LearnedBypass].
+#[derive(Clone, Copy, Debug)]
+pub struct LearnedBypassConfig {
+    /// How long an uncacheable outcome is remembered.
+    ///
+    /// Each further uncacheable outcome for the key extends it.
+    pub ttl: Duration,
+
+    /// Consecutive uncacheable outcomes required before a key is bypassed.
+    pub threshold: u32,
+
+    /// One of this many requests for a bypassed key still goes through the full path, so that
+    /// keys that become cacheable are rediscovered.
+    pub sample_one_in: u64,
+
+    /// Maximum number of tracked keys.
+    pub capacity: usize,
+}
+
+impl Default for LearnedBypassConfig {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_secs(60),
+            threshold: 8,
+            sample_one_in: 50,
+            capacity: 10 * 1024,
+        }
+    }
+}
+
+//
+// LearnedBypass
+//
+
+/// Learned bypass for keys that keep producing uncacheable responses.
+///
+/// Some routes are known to essentially never produce cacheable content but can't be excluded
+/// by path, because nearby routes are cacheable. For these, even the lookup is wasted work
+/// (especially for remote cache tiers). Once a key has had the threshold of consecutive
+/// uncacheable outcomes (the upstream response was skipped or couldn't be stored), its requests
+/// go directly to the upstream, as if the request had skipped the cache.
+///
+/// Sampling is deterministic: with `sample_one_in` of 50 exactly one of every 50 requests for
+/// bypassed keys goes through the full path. If that results in a store (or a hit) then the key
+/// is forgotten and caching resumes normally.
+///
+/// Only key hashes are tracked, in a bounded table. When it is full, expired keys are dropped,
+/// and if it is still full then new keys are not tracked.
+///
+/// Cloning is cheap and clones share state.
+#[derive(Clone)]
+pub struct LearnedBypass {
+    state: Arc<LearnedBypassState>,
+}
+
+impl LearnedBypass {
+    /// Constructor.
+    pub fn new(configuration: LearnedBypassConfig) -> Self {
+        Self {
+            state: Arc::new(LearnedBypassState {
+                configuration,
+                hasher: Default::default(),
+                observations: Default::default(),
+                sample_count: AtomicU64::new(0),
+                learned: AtomicU64::new(0),
+                bypassed: AtomicU64::new(0),
+                sampled: AtomicU64::new(0),
+                forgotten: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Whether to bypass the cache for a key.
+    pub fn bypass(&self, key: &impl Hash) -> bool {
+        let configuration = &self.state.configuration;
+        let hash = self.state.hasher.hash_one(key);
+
+        {
+            let mut observations = self.state.observations.lock().expect("lock");
+            match observations.get(&hash) {
+                Some(observation) if observation.last.elapsed() >= configuration.ttl => {
+                    observations.remove(&hash);
+                    return false;
+                }
+
+                Some(observation) if observation.count >= configuration.threshold => {}
+
+                _ => return false,
+            }
+        }
+
+        if self.sample() {
+            self.state.sampled.fetch_add(1, Ordering::Relaxed);
+            false
+        } else {
+            self.state.bypassed.fetch_add(1, Ordering::Relaxed);
+            true
+        }
+    }
+
+    /// Record an uncacheable outcome for a key.
+    pub fn learn(&self, key: &impl Hash) {
+        let configuration = &self.state.configuration;
+        let hash = self.state.hasher.hash_one(key);
+
+        let mut observations = self.state.observations.lock().expect("lock");
+
+        if !observations.contains_key(&hash) && observations.len() >= configuration.capacity {
+            observations
+                .retain(|_hash, observation| observation.last.elapsed() < configuration.ttl);
+            if observations.len() >= configuration.capacity {
+                return;
+            }
+        }
+
+        let observation = observations.entry(hash).or_insert(Observation {
+            count: 0,
+            last: Instant::now(),
+        });
+
+        // Expired observations don't count
+        if observation.last.elapsed() >= configuration.ttl {
+            observation.count = 0;
+        }
+
+        observation.count = observation.count.saturating_add(1);
+        observation.last = Instant::now();
+
+        if observation.count == configuration.threshold {
+            tracing::debug!("learned bypass");
+            self.state.learned.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Record a cacheable outcome (a store or a hit) for a key.
+    pub fn forget(&self, key: &impl Hash) {
+        let hash = self.state.hasher.hash_one(key);
+
+        let observation = self.state.observations.lock().expect("lock").remove(&hash);
+        if let Some(observation) = observation
+            && observation.count >= self.state.configuration.threshold
+        {
+            tracing::debug!("forgot bypass");
+            self.state.forgotten.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Statistics.
+    pub fn stats(&self) -> LearnedBypassStats {
+        LearnedBypassStats {
+            tracked: self.state.observations.lock().expect("lock").len(),
+            learned: self.state.learned.load(Ordering::Relaxed),
+            bypassed: self.state.bypassed.load(Ordering::Relaxed),
+            sampled: self.state.sampled.load(Ordering::Relaxed),
+            forgotten: self.state.forgotten.load(Ordering::Relaxed),
+        }
+    }
+
+    // Whether the current request for a bypassed key should go through the full path.
+    fn sample(&self) -> bool {
+        let sample_one_in = self.state.configuration.sample_one_in;
+        if sample_one_in <= 1 {
+            return true;
+        }
+
+        let count = self.state.sample_count.fetch_add(1, Ordering::Relaxed);
+        count.is_multiple_of(sample_one_in)
+    }
+}
+
+impl Default for LearnedBypass {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl fmt::Debug for LearnedBypass {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("LearnedBypass")
+            .field("configuration", &self.state.configuration)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+//
+// LearnedBypassStats
+//
+
+/// [LearnedBypass] statistics.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LearnedBypassStats {
+    /// Number of tracked keys.
+    pub tracked: usize,
+
+    /// Number of times a key reached the threshold.
+    pub learned: u64,
+
+    /// Bypassed requests.
+    pub bypassed: u64,
+
+    /// Requests for bypassed keys that went through the full path.
+    pub sampled: u64,
+
+    /// Number of times a bypassed key was forgotten due to a cacheable outcome.
+    pub forgotten: u64,
+}
+
+struct LearnedBypassState {
+    configuration: LearnedBypassConfig,
+    hasher: RandomState,
+    observations: Mutex<FastHashMap<u64, Observation>>,
+    sample_count: AtomicU64,
+    learned: AtomicU64,
+    bypassed: AtomicU64,
+    sampled: AtomicU64,
+    forgotten: AtomicU64,
+}
+
+struct Observation {
+    count: u32,
+    last: Instant,
+}
@@ -0,0 +1,391 @@
+use super::{configuration::*, method::*};
+
+use {
+    http::header::*,
+    std::{error::*, fmt},
+};
+
+//
+// ConfigurationRule
+//
+
+/// Configuration rule.
+///
+/// Checked when [CachingLayer](crate::CachingLayer) builds a service. To add a rule, implement
+/// this trait and add it to [configuration_rules].
+pub trait ConfigurationRule<RequestBodyT, CacheT, CacheKeyT>
+where
+    Self: Send + Sync,
+{
+    /// Check the configuration.
+    fn check(
+        &self,
+        caching: &MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+        encoding: &MiddlewareEncodingConfiguration,
+    ) -> Option<ConfigurationDiagnostic>;
+}
+
+/// All configuration rules.
+pub fn configuration_rules<RequestBodyT, CacheT, CacheKeyT>()
+-> Vec<Box<dyn ConfigurationRule<RequestBodyT, CacheT, CacheKeyT>>> {
+    vec![
+        Box::new(BodySizeRangeRule),
+        Box::new(DeadMinEncodableBodySizeRule),
+        Box::new(DeadKeepIdentityEncodingRule),
+        Box::new(CacheDurationPrecedenceRule),
+        Box::new(NoCacheRule),
+        Box::new(UnkeyedMethodsRule),
+        Box::new(PartitionedAuthorizationRule),
+    ]
+}
+
+/// Check the configuration against all [configuration_rules].
+///
+/// Warnings with codes in `allowed` are suppressed. Errors cannot be suppressed.
+pub fn check_configuration<RequestBodyT, CacheT, CacheKeyT>(
+    caching: &MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+    encoding: &MiddlewareEncodingConfiguration,
+    allowed: &[&str],
+) -> Vec<ConfigurationDiagnostic> {
+    configuration_rules()
+        .iter()
+        .filter_map(|rule| rule.check(caching, encoding))
+        .filter(|diagnostic| {
+            diagnostic.severity == DiagnosticSeverity::Error || !allowed.contains(&diagnostic.code)
+        })
+        .collect()
+}
+
+//
+// ConfigurationDiagnostic
+//
+
+/// Configuration diagnostic.
+#[derive(Clone, Debug)]
+pub struct ConfigurationDiagnostic {
+    /// Stable code, e.g. `E001`.
+    pub code: &'static str,
+
+    /// Severity.
+    pub severity: DiagnosticSeverity,
+
+    /// Message.
+    pub message: String,
+
+    /// Suggested fix.
+    pub suggestion: String,
+}
+
+impl ConfigurationDiagnostic {
+    /// Constructor.
+    pub fn new(
+        code: &'static str,
+        severity: DiagnosticSeverity,
+        message: impl Into<String>,
+        suggestion: impl Into<String>,
+    ) -> Self {
+        Self {
+            code,
+            severity,
+            message: message.into(),
+            suggestion: suggestion.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigurationDiagnostic {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            formatter,
+            "{} {}: {} ({})",
+            self.severity, self.code, self.message, self.suggestion
+        )
+    }
+}
+
+//
+// DiagnosticSeverity
+//
+
+/// [ConfigurationDiagnostic] severity.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DiagnosticSeverity {
+    /// Dead or suspicious setting.
+    Warning,
+
+    /// Contradiction that will malfunction.
+    Error,
+}
+
+impl fmt::Display for DiagnosticSeverity {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(
+            match self {
+                Self::Warning => "warning",
+                Self::Error => "error",
+            },
+            formatter,
+        )
+    }
+}
+
+//
+// ConfigurationError
+//
+
+/// Configuration error.
+///
+/// Lists all diagnostics, not just the errors.
+#[derive(Clone, Debug)]
+pub struct ConfigurationError {
+    /// Diagnostics.
+    pub diagnostics: Vec<ConfigurationDiagnostic>,
+}
+
+impl fmt::Display for ConfigurationError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "invalid caching layer configuration:")?;
+        for diagnostic in &self.diagnostics {
+            write!(formatter, "\n  {}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ConfigurationError {}
+
+//
+// BodySizeRangeRule
+//
+
+/// `E001`: the minimum cacheable body size is greater than the maximum.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BodySizeRangeRule;
+
+impl<RequestBodyT, CacheT, CacheKeyT> ConfigurationRule<RequestBodyT, CacheT, CacheKeyT>
+    for BodySizeRangeRule
+{
+    fn check(
+        &self,
+        caching: &MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+        _encoding: &MiddlewareEncodingConfiguration,
+    ) -> Option<ConfigurationDiagnostic> {
+        (caching.inner.min_body_size > caching.inner.max_body_size).then(|| {
+            ConfigurationDiagnostic::new(
+                "E001",
+                DiagnosticSeverity::Error,
+                format!(
+                    "min_cacheable_body_size ({}) is greater than max_cacheable_body_size ({})",
+                    caching.inner.min_body_size, caching.inner.max_body_size
+                ),
+                "lower min_cacheable_body_size or raise max_cacheable_body_size",
+            )
+        })
+    }
+}
+
+//
+// DeadMinEncodableBodySizeRule
+//
+
+/// `W001`: a minimum encodable body size is set but encoding is disabled.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DeadMinEncodableBodySizeRule;
+
+impl<RequestBodyT, CacheT, CacheKeyT> ConfigurationRule<RequestBodyT, CacheT, CacheKeyT>
+    for DeadMinEncodableBodySizeRule
+{
+    fn check(
+        &self,
+        _caching: &MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+        encoding: &MiddlewareEncodingConfiguration,
+    ) -> Option<ConfigurationDiagnostic> {
+        (encoding.enabled_encodings_by_preference.is_none() && encoding.inner.min_body_size != 0)
+            .then(|| {
+                ConfigurationDiagnostic::new(
+                    "W001",
+                    DiagnosticSeverity::Warning,
+                    "min_encodable_body_size has no effect because of disable_encoding",
+                    "remove min_encodable_body_size or enable_encodings",
+                )
+            })
+    }
+}
+
+//
+// DeadKeepIdentityEncodingRule
+//
+
+/// `W002`: not keeping Identity has no effect because no coding is enabled.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DeadKeepIdentityEncodingRule;
+
+impl<RequestBodyT, CacheT, CacheKeyT> ConfigurationRule<RequestBodyT, CacheT, CacheKeyT>
+    for DeadKeepIdentityEncodingRule
+{
+    fn check(
+        &self,
+        _caching: &MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+        encoding: &MiddlewareEncodingConfiguration,
+    ) -> Option<ConfigurationDiagnostic> {
+        (!encoding.inner.keep_identity_encoding && encoding.enabled_codings().is_empty()).then(
+            || {
+                ConfigurationDiagnostic::new(
+                    "W002",
+                    DiagnosticSeverity::Warning,
+                    "keep_identity_encoding(false) has no effect because no coding is enabled \
+                     (disable_encoding, or enable_encodings with none)",
+                    "remove keep_identity_encoding(false) or enable_encodings",
+                )
+            },
+        )
+    }
+}
+
+//
+// CacheDurationPrecedenceRule
+//
+
+/// `W003`: a cache duration hook takes precedence over `Cache-Control`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheDurationPrecedenceRule;
+
+impl<RequestBodyT, CacheT, CacheKeyT> ConfigurationRule<RequestBodyT, CacheT, CacheKeyT>
+    for CacheDurationPrecedenceRule
+{
+    fn check(
+        &self,
+        caching: &MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+        _encoding: &MiddlewareEncodingConfiguration,
+    ) -> Option<ConfigurationDiagnostic> {
+        (caching.inner.cache_duration.is_some() && caching.inner.respect_cache_control).then(|| {
+            ConfigurationDiagnostic::new(
+                "W003",
+                DiagnosticSeverity::Warning,
+                "the cache_duration hook takes precedence over respect_cache_control: \
+                 Cache-Control and Expires only apply when the hook returns None",
+                "return None from the cache_duration hook for responses that should follow \
+                 Cache-Control, or allow(\"W003\")",
+            )
+        })
+    }
+}
+
+//
+// NoCacheRule
+//
+
+/// `W004`: caching features are configured without a cache.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoCacheRule;
+
+impl<RequestBodyT, CacheT, CacheKeyT> ConfigurationRule<RequestBodyT, CacheT, CacheKeyT>
+    for NoCacheRule
+{
+    fn check(
+        &self,
+        caching: &MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+        _encoding: &MiddlewareEncodingConfiguration,
+    ) -> Option<ConfigurationDiagnostic> {
+        if caching.cache.is_some() {
+            return None;
+        }
+
+        let configured: Vec<_> = [
+            ("admission", caching.admission.is_some()),
+            ("max_inflight_buffer_bytes", caching.buffer_budget.is_some()),
+            ("learned_bypass", caching.learned_bypass.is_some()),
+            ("generations", caching.generations.is_some()),
+            ("verify_cache_on_first_use", caching.cache_verification.is_some()),
+            ("validate_on_serve", caching.serve_validation.is_some()),
+            ("admin_methods", caching.admin_methods.is_some()),
+            ("record_dependencies", caching.dependencies.is_some()),
+            ("conflict_invalidation", caching.conflict_invalidation.is_some()),
+            ("partition_by", caching.partition.is_some()),
+            ("vary_keys", caching.vary_keys.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(name, configured)| configured.then_some(name))
+        .collect();
+
+        (!configured.is_empty()).then(|| {
+            ConfigurationDiagnostic::new(
+                "W004",
+                DiagnosticSeverity::Warning,
+                format!("{} has no effect without a cache", configured.join(", ")),
+                "set a cache or remove these settings",
+            )
+        })
+    }
+}
+
+//
+// UnkeyedMethodsRule
+//
+
+/// `W005`: non-idempotent methods are listed as cacheable, but requests are not keyed by their
+/// body, so they are not cached.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UnkeyedMethodsRule;
+
+impl<RequestBodyT, CacheT, CacheKeyT> ConfigurationRule<RequestBodyT, CacheT, CacheKeyT>
+    for UnkeyedMethodsRule
+{
+    fn check(
+        &self,
+        caching: &MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+        _encoding: &MiddlewareEncodingConfiguration,
+    ) -> Option<ConfigurationDiagnostic> {
+        let MethodPolicy::Listed(methods) = &caching.methods else {
+            return None;
+        };
+
+        if caching.body_key.is_some() {
+            return None;
+        }
+
+        let unkeyed: Vec<_> = methods
+            .iter()
+            .filter(|method| !method.is_idempotent())
+            .map(|method| method.as_str())
+            .collect();
+
+        (!unkeyed.is_empty()).then(|| {
+            ConfigurationDiagnostic::new(
+                "W005",
+                DiagnosticSeverity::Warning,
+                format!("{} will not be cached without cache_key_with_body", unkeyed.join(", ")),
+                "set cache_key_with_body or remove these methods",
+            )
+        })
+    }
+}
+
+//
+// PartitionedAuthorizationRule
+//
+
+/// `W006`: keys are partitioned by `Authorization`, but requests with it skip the cache because
+/// of strict privacy.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PartitionedAuthorizationRule;
+
+impl<RequestBodyT, CacheT, CacheKeyT> ConfigurationRule<RequestBodyT, CacheT, CacheKeyT>
+    for PartitionedAuthorizationRule
+{
+    fn check(
+        &self,
+        caching: &MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+        _encoding: &MiddlewareEncodingConfiguration,
+    ) -> Option<ConfigurationDiagnostic> {
+        (caching.strict_privacy && caching.partition_headers.contains(&AUTHORIZATION)).then(|| {
+            ConfigurationDiagnostic::new(
+                "W006",
+                DiagnosticSeverity::Warning,
+                "partitioning by Authorization has no effect because strict_privacy skips the \
+                 cache for requests with it",
+                "remove Authorization from partition_cache_by_headers or disable strict_privacy",
+            )
+        })
+    }
+}
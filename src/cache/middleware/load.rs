@@ -0,0 +1,289 @@
+use std::{
+    fmt,
+    sync::{atomic::*, *},
+    time::*,
+};
+
+//
+// LoadProbe
+//
+
+/// Load signal for [LoadShedPolicy].
+pub trait LoadProbe
+where
+    Self: Send + Sync,
+{
+    /// Current load.
+    ///
+    /// The scale is up to the implementation, but must match the thresholds of the policy.
+    fn load(&self) -> f64;
+
+    /// Record the duration of a handled request.
+    ///
+    /// The default implementation does nothing.
+    fn record_handle(&self, _duration: Duration) {}
+}
+
+//
+// LoadShedLevel
+//
+
+/// Load shedding level.
+///
+/// Each level sheds the work of the previous levels, too. Existing hits are always served.
+#[derive(Clone, Copy, Debug, Default, Eq, Ord, PartialEq, PartialOrd)]
+pub enum LoadShedLevel {
+    /// Don't shed.
+    #[default]
+    Normal,
+
+    /// Skip eager encoding on stores (store Identity only).
+    SkipEncoding,
+
+    /// Skip storing on misses (pass through).
+    SkipStore,
+}
+
+impl LoadShedLevel {
+    fn from_u8(level: u8) -> Self {
+        match level {
+            1 => Self::SkipEncoding,
+            2 => Self::SkipStore,
+            _ => Self::Normal,
+        }
+    }
+}
+
+impl fmt::Display for LoadShedLevel {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Normal => fmt::Display::fmt("normal", formatter),
+            Self::SkipEncoding => fmt::Display::fmt("skip encoding", formatter),
+            Self::SkipStore => fmt::Display::fmt("skip store", formatter),
+        }
+    }
+}
+
+//
+// LoadShedPolicy
+//
+
+/// Load-adaptive degradation.
+///
+/// When the load signal reaches a level's threshold we escalate to that level. We de-escalate
+/// one level at a time, and only when the load drops below the current level's threshold minus
+/// the hysteresis, which prevents flapping.
+///
+/// Thresholds can be adjusted at runtime. Cloning is cheap and clones share state, so you can keep
+/// a clone as a handle.
+#[derive(Clone)]
+pub struct LoadShedPolicy {
+    probe: Arc<dyn LoadProbe>,
+    state: Arc<LoadShedState>,
+}
+
+impl LoadShedPolicy {
+    /// Constructor.
+    pub fn new(
+        probe: impl LoadProbe + 'static,
+        skip_encoding_threshold: f64,
+        skip_store_threshold: f64,
+        hysteresis: f64,
+    ) -> Self {
+        Self {
+            probe: Arc::new(probe),
+            state: Arc::new(LoadShedState {
+                level: AtomicU8::new(0),
+                skip_encoding_threshold: AtomicF64::new(skip_encoding_threshold),
+                skip_store_threshold: AtomicF64::new(skip_store_threshold),
+                hysteresis: AtomicF64::new(hysteresis),
+            }),
+        }
+    }
+
+    /// Set the threshold for [LoadShedLevel::SkipEncoding].
+    pub fn set_skip_encoding_threshold(&self, threshold: f64) {
+        self.state.skip_encoding_threshold.store(threshold);
+    }
+
+    /// Set the threshold for [LoadShedLevel::SkipStore].
+    pub fn set_skip_store_threshold(&self, threshold: f64) {
+        self.state.skip_store_threshold.store(threshold);
+    }
+
+    /// Set the hysteresis.
+    pub fn set_hysteresis(&self, hysteresis: f64) {
+        self.state.hysteresis.store(hysteresis);
+    }
+
+    /// Current level (without evaluating the load).
+    pub fn level(&self) -> LoadShedLevel {
+        LoadShedLevel::from_u8(self.state.level.load(Ordering::Relaxed))
+    }
+
+    /// Evaluate the load and update the level.
+    pub fn evaluate(&self) -> LoadShedLevel {
+        let load = self.probe.load();
+        let skip_encoding_threshold = self.state.skip_encoding_threshold.load();
+        let skip_store_threshold = self.state.skip_store_threshold.load();
+        let hysteresis = self.state.hysteresis.load();
+
+        let current = self.level();
+        let level = if load >= skip_store_threshold {
+            LoadShedLevel::SkipStore
+        } else if load >= skip_encoding_threshold {
+            current.max(LoadShedLevel::SkipEncoding)
+        } else {
+            current
+        };
+
+        // De-escalate one level at a time
+        let level = match level {
+            LoadShedLevel::SkipStore if load < skip_store_threshold - hysteresis => {
+                LoadShedLevel::SkipEncoding
+            }
+
+            LoadShedLevel::SkipEncoding if load < skip_encoding_threshold - hysteresis => {
+                LoadShedLevel::Normal
+            }
+
+            level => level,
+        };
+
+        if level != current {
+            tracing::info!("load shedding: {} (load {})", level, load);
+            self.state.level.store(level as u8, Ordering::Relaxed);
+        }
+
+        level
+    }
+
+    /// Record the duration of a handled request.
+    pub fn record_handle(&self, duration: Duration) {
+        self.probe.record_handle(duration);
+    }
+}
+
+impl fmt::Debug for LoadShedPolicy {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("LoadShedPolicy")
+            .field("level", &self.level())
+            .field("skip_encoding_threshold", &self.state.skip_encoding_threshold.load())
+            .field("skip_store_threshold", &self.state.skip_store_threshold.load())
+            .field("hysteresis", &self.state.hysteresis.load())
+            .finish()
+    }
+}
+
+//
+// HandleDurationProbe
+//
+
+/// [LoadProbe] based on an exponentially weighted moving average (EWMA) of this layer's own
+/// handle durations.
+///
+/// The load is the average divided by the reference duration, so 1.0 means that requests take as
+/// long as the reference on average.
+#[derive(Debug)]
+pub struct HandleDurationProbe {
+    /// Reference duration.
+    pub reference: Duration,
+
+    /// Weight of each new sample (0.0 to 1.0).
+    pub alpha: f64,
+
+    average: AtomicF64,
+}
+
+impl HandleDurationProbe {
+    /// Constructor.
+    pub fn new(reference: Duration, alpha: f64) -> Self {
+        Self {
+            reference,
+            alpha: alpha.clamp(0.0, 1.0),
+            average: AtomicF64::new(0.0),
+        }
+    }
+}
+
+impl LoadProbe for HandleDurationProbe {
+    fn load(&self) -> f64 {
+        self.average.load() / self.reference.as_secs_f64()
+    }
+
+    fn record_handle(&self, duration: Duration) {
+        // Races might lose a sample, which is fine for an average
+        let average = self.average.load();
+        self.average.store(average + self.alpha * (duration.as_secs_f64() - average));
+    }
+}
+
+//
+// TokioRuntimeProbe
+//
+
+/// [LoadProbe] based on the Tokio runtime's global (injection) queue depth.
+///
+/// The load is the queue depth divided by the reference depth.
+#[cfg(feature = "rt-metrics")]
+#[derive(Debug)]
+pub struct TokioRuntimeProbe {
+    /// Runtime handle.
+    pub handle: tokio::runtime::Handle,
+
+    /// Reference queue depth.
+    pub reference_queue_depth: usize,
+}
+
+#[cfg(feature = "rt-metrics")]
+impl TokioRuntimeProbe {
+    /// Constructor for the current runtime.
+    ///
+    /// Panics if not called from within a Tokio runtime.
+    pub fn new(reference_queue_depth: usize) -> Self {
+        Self {
+            handle: tokio::runtime::Handle::current(),
+            reference_queue_depth,
+        }
+    }
+}
+
+#[cfg(feature = "rt-metrics")]
+impl LoadProbe for TokioRuntimeProbe {
+    fn load(&self) -> f64 {
+        self.handle.metrics().global_queue_depth() as f64 / self.reference_queue_depth as f64
+    }
+}
+
+//
+// LoadShedState
+//
+
+struct LoadShedState {
+    level: AtomicU8,
+    skip_encoding_threshold: AtomicF64,
+    skip_store_threshold: AtomicF64,
+    hysteresis: AtomicF64,
+}
+
+//
+// AtomicF64
+//
+
+#[derive(Debug)]
+struct AtomicF64(AtomicU64);
+
+impl AtomicF64 {
+    fn new(value: f64) -> Self {
+        Self(AtomicU64::new(value.to_bits()))
+    }
+
+    fn load(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn store(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,36 @@
+use {http::*, std::sync::*};
+
+//
+// MethodPolicy
+//
+
+/// Which request methods are cacheable.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum MethodPolicy {
+    /// Idempotent methods.
+    #[default]
+    Idempotent,
+
+    /// `GET` only.
+    ///
+    /// Suitable for clients, for which the other idempotent methods (e.g. `PUT`) are usually
+    /// meant to have an effect every time.
+    GetOnly,
+
+    /// These methods only.
+    ///
+    /// Non-idempotent methods (e.g. `POST`) are only cached if requests are keyed by their body
+    /// (see [BodyKey](super::BodyKey)).
+    Listed(Arc<[Method]>),
+}
+
+impl MethodPolicy {
+    /// Whether the method is cacheable.
+    pub fn allows(&self, method: &Method) -> bool {
+        match self {
+            Self::Idempotent => method.is_idempotent(),
+            Self::GetOnly => method == Method::GET,
+            Self::Listed(methods) => methods.contains(method),
+        }
+    }
+}
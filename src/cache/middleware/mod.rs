@@ -1,7 +1,77 @@
+mod accept_encoding;
+mod admin;
+mod admission;
+mod body_key;
+mod body_sizes;
+#[cfg(feature = "range-assembly")]
+mod assembly;
+mod buffer;
+mod budget;
+mod bust;
+mod bypass;
+mod client;
+mod coalesce;
 mod configuration;
+mod conflict;
+mod context;
+mod control;
+mod dependencies;
+mod describe;
+mod entry_stats;
+mod events;
+mod forwarded;
+mod generation;
 mod hooks;
+#[cfg(feature = "idempotency")]
+mod idempotency;
+mod immutable;
+mod interop;
+mod key_uri;
+mod language;
+mod learned;
+mod lint;
+mod load;
+mod method;
+mod negotiation;
+mod partition;
+mod pipeline;
+mod policy;
+#[cfg(feature = "paced-purge")]
+mod purge;
+mod quarantine;
+mod range;
 mod request;
+mod request_body;
+mod resource;
 mod responses;
+#[cfg(feature = "stale-while-revalidate")]
+mod revalidate;
+mod slo;
+mod stacking;
+mod startup;
+mod status;
+mod store;
+mod target;
+mod trail;
+mod vary;
+mod warm;
+mod weight_audit;
 
 #[allow(unused_imports)]
-pub use {configuration::*, hooks::*, request::*, responses::*};
+pub use {accept_encoding::*, admin::*, admission::*, body_key::*, body_sizes::*, buffer::*, budget::*, bust::*, bypass::*, client::*, coalesce::*, configuration::*, conflict::*, context::*, control::*, dependencies::*, describe::*, entry_stats::*, events::*, forwarded::*, generation::*, hooks::*, immutable::*, interop::*, key_uri::*, language::*, learned::*, lint::*, load::*, method::*, negotiation::*, partition::*, pipeline::*, policy::*, quarantine::*, range::*, request::*, request_body::*, resource::*, responses::*, slo::*, stacking::*, startup::*, status::*, store::*, target::*, trail::*, vary::*, warm::*, weight_audit::*};
+
+#[cfg(feature = "range-assembly")]
+#[allow(unused_imports)]
+pub use assembly::*;
+
+#[cfg(feature = "idempotency")]
+#[allow(unused_imports)]
+pub use idempotency::*;
+
+#[cfg(feature = "paced-purge")]
+#[allow(unused_imports)]
+pub use purge::*;
+
+#[cfg(feature = "stale-while-revalidate")]
+#[allow(unused_imports)]
+pub use revalidate::*;
@@ -0,0 +1,112 @@
+use super::super::coding::*;
+
+use {kutil::http::*, std::sync::*};
+
+/// Common reference type for [EncodingNegotiator].
+pub type EncodingNegotiatorRef = Arc<dyn EncodingNegotiator>;
+
+//
+// EncodingNegotiator
+//
+
+/// `Accept-Encoding` content negotiation.
+pub trait EncodingNegotiator
+where
+    Self: Send + Sync,
+{
+    /// Select the best coding.
+    ///
+    /// `accept_encoding` are the raw request `Accept-Encoding` header values. `custom` and
+    /// `builtin` are the server's enabled codings, each in order from most preferred to least, with
+    /// custom codings considered more preferred than built-in ones.
+    /// [Identity](CodingId::IDENTITY) is not included as it is always acceptable.
+    ///
+    /// Return [None] for [Identity](CodingId::IDENTITY).
+    fn negotiate(
+        &self,
+        accept_encoding: &[&str],
+        custom: &[&'static str],
+        builtin: &[EncodingHeaderValue],
+    ) -> Option<CodingId>;
+}
+
+//
+// CommonEncodingNegotiator
+//
+
+/// Default [EncodingNegotiator].
+///
+/// A custom coding is selected only if the client explicitly accepts it.
+///
+/// Otherwise, built-in codings are negotiated according to the client's priorities. If there is a
+/// tie, we go by the order of the enabled codings, so `*` alone selects the most preferred one.
+/// Unknown codings are ignored, so a client that lists only those gets
+/// [Identity](CodingId::IDENTITY).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CommonEncodingNegotiator;
+
+impl EncodingNegotiator for CommonEncodingNegotiator {
+    fn negotiate(
+        &self,
+        accept_encoding: &[&str],
+        custom: &[&'static str],
+        builtin: &[EncodingHeaderValue],
+    ) -> Option<CodingId> {
+        for name in custom {
+            if accepts(accept_encoding, name) {
+                return Some(CodingId::Custom(name));
+            }
+        }
+
+        // Codings the client explicitly refuses are not candidates
+        let acceptable: Vec<_>;
+        let builtin = if builtin.iter().any(|coding| refuses(accept_encoding, (*coding).into())) {
+            acceptable = builtin
+                .iter()
+                .filter(|coding| !refuses(accept_encoding, (**coding).into()))
+                .copied()
+                .collect();
+            &acceptable
+        } else {
+            builtin
+        };
+
+        if builtin.is_empty() {
+            return None;
+        }
+
+        Preferences::<EncodingHeaderValue>::parse(&accept_encoding.to_vec())
+            .best(builtin)
+            .cloned()
+            .map(|best| CodingId::Builtin(best.into()))
+    }
+}
+
+// Whether the client explicitly accepts a coding (with a non-zero weight).
+fn accepts(accept_encoding: &[&str], name: &str) -> bool {
+    weight(accept_encoding, name).is_some_and(|weight| weight > 0.0)
+}
+
+// Whether the client explicitly refuses a coding (with a zero weight).
+fn refuses(accept_encoding: &[&str], name: &str) -> bool {
+    weight(accept_encoding, name) == Some(0.0)
+}
+
+// The first valid weight the client explicitly gives a coding.
+fn weight(accept_encoding: &[&str], name: &str) -> Option<f32> {
+    accept_encoding
+        .iter()
+        .flat_map(|value| value.split(","))
+        .find_map(|item| {
+            let mut split = item.splitn(2, ';');
+            let token = split.next().unwrap_or_default().trim();
+            if !token.eq_ignore_ascii_case(name) {
+                return None;
+            }
+
+            match split.next().and_then(|weight| weight.trim().strip_prefix("q=")) {
+                Some(weight) => weight.trim().parse().ok(),
+                None => Some(1.0),
+            }
+        })
+}
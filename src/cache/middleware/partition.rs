@@ -0,0 +1,139 @@
+use super::{hooks::*, immutable::*};
+
+use {
+    http::request::*,
+    std::{fmt, sync::*},
+};
+
+//
+// PartitionPolicy
+//
+
+/// Cache partitioning for matching paths, analogous to the double-keying of browser caches.
+///
+/// A shared cache lets clients probe, via timing, whether other clients have caused a resource to
+/// be cached. For endpoints where that presence leaks information across tenants (or embedding
+/// contexts) even though the bodies are properly keyed, the hook derives a partition identifier
+/// from the request (e.g. the authenticated tenant, or `Sec-Fetch-Site`), which is then part of
+/// the cache key (see [CacheKey::set_partition](super::super::CacheKey::set_partition)). Activity
+/// in one partition can thus never change the hits and misses observed in another.
+///
+/// Only matching paths are partitioned, because partitioning everything would tank the hit rate.
+///
+/// Requests for which the hook returns [None] (or an empty identifier) are never merged into
+/// another partition: according to [unpartitioned](Self::unpartitioned) they either skip the
+/// cache or share a dedicated bucket.
+///
+/// The partition is available for introspection as
+/// [RequestCacheContext::partition](super::RequestCacheContext::partition).
+#[derive(Clone)]
+pub struct PartitionPolicy {
+    /// Matcher.
+    pub matcher: PathMatcher,
+
+    /// Partition (hook).
+    pub partition: PartitionHook,
+
+    /// What to do with requests without a partition.
+    pub unpartitioned: UnpartitionedPolicy,
+}
+
+impl PartitionPolicy {
+    /// Constructor.
+    pub fn new(
+        matcher: PathMatcher,
+        partition: impl Fn(PartitionHookContext) -> Option<String> + 'static + Send + Sync,
+    ) -> Self {
+        Self {
+            matcher,
+            partition: Arc::new(Box::new(partition)),
+            unpartitioned: Default::default(),
+        }
+    }
+
+    /// Set what to do with requests without a partition.
+    pub fn unpartitioned(mut self, unpartitioned: UnpartitionedPolicy) -> Self {
+        self.unpartitioned = unpartitioned;
+        self
+    }
+
+    /// The partition of a request.
+    ///
+    /// Returns [None] if the path doesn't match.
+    pub fn resolve<RequestBodyT>(&self, request: &Request<RequestBodyT>) -> Option<Partition> {
+        if !self.matcher.matches(request.uri().path()) {
+            return None;
+        }
+
+        let partition = (self.partition)(PartitionHookContext::new(
+            request.uri(),
+            request.headers(),
+            request.extensions(),
+        ));
+
+        Some(match partition {
+            Some(partition) if !partition.is_empty() => Partition::Derived(partition.into()),
+            _ => Partition::Unpartitioned,
+        })
+    }
+}
+
+impl fmt::Debug for PartitionPolicy {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("PartitionPolicy")
+            .field("matcher", &self.matcher)
+            .field("unpartitioned", &self.unpartitioned)
+            .finish()
+    }
+}
+
+//
+// UnpartitionedPolicy
+//
+
+/// What [PartitionPolicy] does with requests without a partition.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum UnpartitionedPolicy {
+    /// Skip the cache.
+    #[default]
+    Bypass,
+
+    /// Share a dedicated bucket, which is distinct from all derived partitions.
+    Bucket,
+}
+
+//
+// Partition
+//
+
+/// Cache partition of a request (see [PartitionPolicy]).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Partition {
+    /// Derived by the hook.
+    Derived(Arc<str>),
+
+    /// Not derivable.
+    Unpartitioned,
+}
+
+impl Partition {
+    /// The identifier for [CacheKey::set_partition](super::super::CacheKey::set_partition).
+    ///
+    /// Empty for [Unpartitioned](Self::Unpartitioned), which is never a derived identifier.
+    pub fn identifier(&self) -> &str {
+        match self {
+            Self::Derived(partition) => partition,
+            Self::Unpartitioned => "",
+        }
+    }
+}
+
+impl fmt::Display for Partition {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Derived(partition) => fmt::Display::fmt(partition, formatter),
+            Self::Unpartitioned => fmt::Display::fmt("(unpartitioned)", formatter),
+        }
+    }
+}
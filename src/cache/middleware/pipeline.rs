@@ -0,0 +1,266 @@
+use std::fmt;
+
+#[cfg(feature = "test-util")]
+use std::sync::{atomic::*, *};
+
+//
+// PipelineStage
+//
+
+/// Stage of the caching pipeline.
+///
+/// A request goes through the stages in this (declared) order. Stages may be skipped (e.g. a hit
+/// skips [Upstream](Self::Upstream)) or repeated (e.g. a coalesced follower looks up again), but
+/// never revisited after a later stage. Every extension point belongs to a stage, so hooks,
+/// policies, and events always run in the same relative order.
+///
+/// Entering a stage out of order is a bug: it is logged as an error and recorded in the request's
+/// [DecisionTrail](super::DecisionTrail) as "error (pipeline order)". Debug builds panic, so that
+/// tests fail.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum PipelineStage {
+    /// Request checks and keying: skip checks, `cacheable_by_request`, language negotiation,
+    /// partitioning, the `cache_key` hook, and idempotency.
+    Request,
+
+    /// Cache lookup, including verification, bypass, and coalescing.
+    Lookup,
+
+    /// Evaluation of the entry: expiry, validators, serve-time validation, and conditional
+    /// requests. Hits are decided here.
+    Conditional,
+
+    /// Upstream call, including resource limits and route policies.
+    Upstream,
+
+    /// Response checks: `cacheable_by_response`, encoding validation, and body policies.
+    Response,
+
+    /// Feedback from the response: conflict invalidation and learned bypass.
+    Feedback,
+
+    /// Cache write, including the `on_store` hook.
+    Store,
+
+    /// Serve-time transformations of the response headers and extensions.
+    Serve,
+
+    /// Recording: slow request logs, load shedding, and hit rate SLOs.
+    Record,
+}
+
+impl PipelineStage {
+    /// All stages in order.
+    pub const ALL: [Self; 9] = [
+        Self::Request,
+        Self::Lookup,
+        Self::Conditional,
+        Self::Upstream,
+        Self::Response,
+        Self::Feedback,
+        Self::Store,
+        Self::Serve,
+        Self::Record,
+    ];
+
+    /// Name.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Request => "request",
+            Self::Lookup => "lookup",
+            Self::Conditional => "conditional",
+            Self::Upstream => "upstream",
+            Self::Response => "response",
+            Self::Feedback => "feedback",
+            Self::Store => "store",
+            Self::Serve => "serve",
+            Self::Record => "record",
+        }
+    }
+}
+
+impl fmt::Display for PipelineStage {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self.name(), formatter)
+    }
+}
+
+//
+// PipelineStages
+//
+
+/// Tracks the [PipelineStage] of a request.
+#[derive(Clone, Debug, Default)]
+pub struct PipelineStages {
+    /// Current stage ([None] before the first).
+    pub current: Option<PipelineStage>,
+
+    /// Probe.
+    #[cfg(feature = "test-util")]
+    pub probe: Option<PipelineProbe>,
+}
+
+impl PipelineStages {
+    /// Enter a stage.
+    ///
+    /// `decision` is the latest decision so far, for the probe.
+    ///
+    /// Returns false if the stage is out of order.
+    pub fn enter(&mut self, stage: PipelineStage, decision: Option<&'static str>) -> bool {
+        let in_order = match self.current {
+            Some(current) if stage < current => {
+                tracing::error!("pipeline stage out of order: {} after {}", stage, current);
+                false
+            }
+
+            _ => true,
+        };
+
+        debug_assert!(in_order, "pipeline stage out of order: {}", stage);
+
+        #[cfg(feature = "test-util")]
+        if let Some(probe) = &self.probe {
+            probe.record(stage, decision);
+        }
+
+        #[cfg(not(feature = "test-util"))]
+        let _ = decision;
+
+        self.current = Some(stage);
+        in_order
+    }
+}
+
+//
+// PipelineProbe
+//
+
+/// Records the [PipelineStage] sequence of requests.
+///
+/// Install it with `CachingLayer::pipeline_probe`. Hooks can record their invocations via
+/// [record_extension](Self::record_extension), so that a test can assert the exact order of all
+/// effects against a golden sequence (see [sequence](Self::sequence)).
+///
+/// Requires the `test-util` feature.
+///
+/// Cloning is cheap and clones share state.
+#[cfg(feature = "test-util")]
+#[derive(Clone, Debug, Default)]
+pub struct PipelineProbe {
+    records: Arc<Mutex<Vec<PipelineProbeRecord>>>,
+    order: Arc<AtomicUsize>,
+}
+
+#[cfg(feature = "test-util")]
+impl PipelineProbe {
+    /// Record entering a stage.
+    pub fn record(&self, stage: PipelineStage, decision: Option<&'static str>) {
+        self.push(PipelineProbeEntry::Stage(stage), decision.map(String::from));
+    }
+
+    /// Record an invocation of an extension point (e.g. a hook), with a summary of its inputs.
+    pub fn record_extension(&self, extension: &'static str, summary: impl fmt::Display) {
+        self.push(PipelineProbeEntry::Extension(extension), Some(summary.to_string()));
+    }
+
+    /// Records so far, in invocation order.
+    pub fn records(&self) -> Vec<PipelineProbeRecord> {
+        self.records.lock().expect("lock").clone()
+    }
+
+    /// Stages entered so far, in order.
+    pub fn stages(&self) -> Vec<PipelineStage> {
+        self.records
+            .lock()
+            .expect("lock")
+            .iter()
+            .filter_map(|record| match record.entry {
+                PipelineProbeEntry::Stage(stage) => Some(stage),
+                PipelineProbeEntry::Extension(_) => None,
+            })
+            .collect()
+    }
+
+    /// Records so far as a golden sequence, one per line.
+    ///
+    /// Stages are plain names and extensions are prefixed with "+", e.g.:
+    ///
+    /// ```text
+    /// request
+    /// +cache_key /index.html
+    /// lookup
+    /// conditional
+    /// +on_cache_event hit
+    /// serve
+    /// record
+    /// ```
+    pub fn sequence(&self) -> String {
+        let mut sequence = String::default();
+        for record in self.records.lock().expect("lock").iter() {
+            sequence += &record.to_string();
+            sequence.push('\n');
+        }
+        sequence
+    }
+
+    /// Clear the records (e.g. between scenarios).
+    pub fn clear(&self) {
+        self.records.lock().expect("lock").clear();
+        self.order.store(0, Ordering::Relaxed);
+    }
+
+    fn push(&self, entry: PipelineProbeEntry, summary: Option<String>) {
+        let order = self.order.fetch_add(1, Ordering::Relaxed);
+        self.records.lock().expect("lock").push(PipelineProbeRecord {
+            order,
+            entry,
+            summary,
+        });
+    }
+}
+
+//
+// PipelineProbeRecord
+//
+
+/// Record of a [PipelineProbe].
+#[cfg(feature = "test-util")]
+#[derive(Clone, Debug)]
+pub struct PipelineProbeRecord {
+    /// Invocation order.
+    pub order: usize,
+
+    /// What was invoked.
+    pub entry: PipelineProbeEntry,
+
+    /// Summary of inputs: the latest decision for stages, provided by the extension otherwise.
+    pub summary: Option<String>,
+}
+
+#[cfg(feature = "test-util")]
+impl fmt::Display for PipelineProbeRecord {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.entry, &self.summary) {
+            (PipelineProbeEntry::Stage(stage), _) => write!(formatter, "{}", stage),
+            (PipelineProbeEntry::Extension(extension), Some(summary)) => {
+                write!(formatter, "+{} {}", extension, summary)
+            }
+            (PipelineProbeEntry::Extension(extension), None) => write!(formatter, "+{}", extension),
+        }
+    }
+}
+
+//
+// PipelineProbeEntry
+//
+
+/// What a [PipelineProbeRecord] records.
+#[cfg(feature = "test-util")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PipelineProbeEntry {
+    /// Entering a stage.
+    Stage(PipelineStage),
+
+    /// Invocation of an extension point.
+    Extension(&'static str),
+}
@@ -0,0 +1,147 @@
+use super::super::configuration::*;
+
+use std::time::*;
+
+//
+// RoutePolicy
+//
+
+/// Per-route caching policy.
+///
+/// Attach it as a response extension (e.g. via `CachedRoute` for axum) and the caching layer will
+/// use it with priority over its own configuration for that response only. The `XX-Cache`,
+/// `XX-Encode`, and `XX-Cache-Duration` response headers still take precedence.
+///
+/// Unlike headers, extensions never leak to clients. The extension is removed before the
+/// response is stored or sent downstream.
+#[derive(Clone, Debug, Default)]
+pub struct RoutePolicy {
+    /// Cache duration.
+    pub duration: Option<Duration>,
+
+    /// Cacheable (instead of `cacheable_by_default`).
+    pub cacheable: Option<bool>,
+
+    /// Encodable (instead of `encodable_by_default`).
+    pub encodable: Option<bool>,
+
+    /// Minimum cacheable body size.
+    pub min_body_size: Option<usize>,
+
+    /// Maximum cacheable body size.
+    pub max_body_size: Option<usize>,
+
+    /// Application-provided data version of the content.
+    ///
+    /// Used to derive a deterministic validator instead of a synthetic `Last-Modified`. See
+    /// [SyntheticLastModified].
+    pub data_version: Option<u64>,
+}
+
+impl RoutePolicy {
+    /// Set cache duration.
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.duration = Some(duration);
+        self
+    }
+
+    /// Set cacheable.
+    pub fn cacheable(mut self, cacheable: bool) -> Self {
+        self.cacheable = Some(cacheable);
+        self
+    }
+
+    /// Set encodable.
+    pub fn encodable(mut self, encodable: bool) -> Self {
+        self.encodable = Some(encodable);
+        self
+    }
+
+    /// Set minimum cacheable body size.
+    pub fn min_body_size(mut self, min_body_size: usize) -> Self {
+        self.min_body_size = Some(min_body_size);
+        self
+    }
+
+    /// Set maximum cacheable body size.
+    pub fn max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = Some(max_body_size);
+        self
+    }
+
+    /// Set data version.
+    pub fn data_version(mut self, data_version: u64) -> Self {
+        self.data_version = Some(data_version);
+        self
+    }
+}
+
+//
+// RouteOverrides
+//
+
+/// The settings of a response's [RoutePolicy], falling back to the configurations.
+///
+/// Small and [Copy], so that a route policy applies to its response without cloning the shared
+/// configurations.
+#[derive(Clone, Copy, Debug)]
+pub struct RouteOverrides {
+    /// Cacheable by default.
+    pub cacheable_by_default: bool,
+
+    /// Minimum cacheable body size.
+    pub min_body_size: usize,
+
+    /// Maximum cacheable body size.
+    pub max_body_size: usize,
+
+    /// Encodable by default.
+    pub encodable_by_default: bool,
+}
+
+impl RouteOverrides {
+    /// Constructor.
+    pub fn new(
+        policy: Option<&RoutePolicy>,
+        caching: &CachingConfiguration,
+        encoding: &EncodingConfiguration,
+    ) -> Self {
+        let mut overrides = Self {
+            cacheable_by_default: caching.cacheable_by_default,
+            min_body_size: caching.min_body_size,
+            max_body_size: caching.max_body_size,
+            encodable_by_default: encoding.encodable_by_default,
+        };
+
+        let Some(policy) = policy else {
+            return overrides;
+        };
+
+        if let Some(cacheable) = policy.cacheable {
+            overrides.cacheable_by_default = cacheable;
+        }
+
+        if let Some(min_body_size) = policy.min_body_size {
+            overrides.min_body_size = min_body_size;
+        }
+
+        if let Some(max_body_size) = policy.max_body_size {
+            overrides.max_body_size = max_body_size;
+        }
+
+        if overrides.min_body_size > overrides.max_body_size {
+            tracing::warn!(
+                "route policy min_body_size {} > max_body_size {}",
+                overrides.min_body_size,
+                overrides.max_body_size
+            );
+            overrides.min_body_size = overrides.max_body_size;
+        }
+
+        if let Some(encodable) = policy.encodable {
+            overrides.encodable_by_default = encodable;
+        }
+
+        overrides
+    }
+}
@@ -0,0 +1,443 @@
+use super::{
+    super::{cache::*, key::*, response::*},
+    admin::*,
+};
+
+use {
+    http::header::*,
+    kutil::std::collections::*,
+    std::{
+        collections::VecDeque,
+        fmt,
+        sync::{atomic::*, *},
+        time::*,
+    },
+};
+
+/// Default for [PurgeJobs::retained].
+pub const DEFAULT_RETAINED_PURGE_JOBS: usize = 64;
+
+//
+// PurgePacing
+//
+
+/// Pacing of a [PurgeJobs] purge.
+#[derive(Clone, Copy, Debug)]
+pub struct PurgePacing {
+    /// Maximum number of keys to process per tick.
+    pub max_keys_per_tick: usize,
+
+    /// Pause between ticks.
+    pub tick_interval: Duration,
+
+    /// Maximum total duration ([None] for unbounded).
+    ///
+    /// A purge that takes longer is stopped (see [PurgeJobState::TimedOut]).
+    pub max_total_duration: Option<Duration>,
+}
+
+impl PurgePacing {
+    /// Constructor.
+    pub fn new(max_keys_per_tick: usize, tick_interval: Duration) -> Self {
+        Self {
+            max_keys_per_tick: max_keys_per_tick.max(1),
+            tick_interval,
+            max_total_duration: None,
+        }
+    }
+
+    /// Set maximum total duration.
+    pub fn with_max_total_duration(mut self, max_total_duration: Duration) -> Self {
+        self.max_total_duration = Some(max_total_duration);
+        self
+    }
+}
+
+impl Default for PurgePacing {
+    /// 1,000 keys per 10 milliseconds, unbounded.
+    fn default() -> Self {
+        Self::new(1_000, Duration::from_millis(10))
+    }
+}
+
+//
+// PurgeJobs
+//
+
+/// Paced bulk invalidation.
+///
+/// Invalidating many entries at once (e.g. banning a large path prefix) can spike the serving
+/// latency of requests on the same runtime. Instead, a purge job invalidates the matching keys
+/// in bounded batches (ticks), pausing between them, in a background task.
+///
+/// Correctness does not depend on the pacing: when a job starts, it puts up a purge fence for
+/// its [BanExpression], so that matching entries created before the fence are misses even before
+/// they are physically invalidated. The fence is taken down when the job completes. If the job is
+/// cancelled or times out, the fence stays up (matching entries stay hidden) until
+/// [lifted](Self::lift_fence).
+///
+/// Note that entries stored concurrently by requests that started before the fence (i.e. created
+/// before it but not yet in the key snapshot) can reappear after the fence is taken down. Use a
+/// [FencedCache](super::super::FencedCache) to guard against that.
+///
+/// Jobs require a cache that supports [keys](Cache::keys) and a Tokio runtime.
+///
+/// Requires the `paced-purge` feature.
+///
+/// Cloning is cheap and clones share state.
+#[derive(Clone)]
+pub struct PurgeJobs {
+    /// Default pacing.
+    pub pacing: PurgePacing,
+
+    /// Maximum number of finished jobs retained for status.
+    pub retained: usize,
+
+    state: Arc<PurgeJobsState>,
+}
+
+impl PurgeJobs {
+    /// Constructor.
+    pub fn new(pacing: PurgePacing) -> Self {
+        Self {
+            pacing,
+            retained: DEFAULT_RETAINED_PURGE_JOBS,
+            state: Default::default(),
+        }
+    }
+
+    /// Start a purge job.
+    ///
+    /// Returns the job ID, or [None] if the cache doesn't support [keys](Cache::keys).
+    pub fn start<CacheT, CacheKeyT>(
+        &self,
+        cache: CacheT,
+        expression: BanExpression,
+        tag_header: HeaderName,
+        pacing: Option<PurgePacing>,
+    ) -> Option<u64>
+    where
+        CacheT: Cache<CacheKeyT>,
+        CacheKeyT: CacheKey,
+    {
+        let pacing = pacing.unwrap_or(self.pacing);
+        let keys = cache.keys()?;
+
+        let id = self.state.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let job = Arc::new(PurgeJob {
+            id,
+            expression: expression.clone(),
+            total: keys.len(),
+            started: SystemTime::now(),
+            start: Instant::now(),
+            scanned: Default::default(),
+            invalidated: Default::default(),
+            state: Mutex::new(PurgeJobState::Running),
+            finished: Mutex::new(None),
+            cancelled: Default::default(),
+            lift_fence: Default::default(),
+        });
+
+        // The fence must be up before we return
+        self.state.fences.write().expect("lock").push(PurgeFence {
+            job_id: id,
+            expression,
+            tag_header: tag_header.clone(),
+            as_of: job.started,
+        });
+        self.state.fence_count.fetch_add(1, Ordering::Release);
+
+        self.state.jobs.lock().expect("lock").insert(id, job.clone());
+
+        tracing::info!("purge job {} started: {} ({} keys)", id, job.expression, keys.len());
+
+        let purge_jobs = self.clone();
+        ::tokio::spawn(async move {
+            let state = purge_jobs.run(&job, &cache, keys, &tag_header, pacing).await;
+            purge_jobs.finish(&job, state);
+        });
+
+        Some(id)
+    }
+
+    /// Whether a purge fence hides an entry.
+    pub fn fenced<CacheKeyT>(&self, key: &CacheKeyT, cached_response: &CachedResponse) -> bool
+    where
+        CacheKeyT: CacheKey,
+    {
+        if self.state.fence_count.load(Ordering::Acquire) == 0 {
+            return false;
+        }
+
+        self.state.fences.read().expect("lock").iter().any(|fence| {
+            cached_response.created <= fence.as_of
+                && fence.expression.matches(key, cached_response, &fence.tag_header)
+        })
+    }
+
+    /// Cancel a job.
+    ///
+    /// It stops before its next tick. If `lift_fence` is true then its purge fence is taken down
+    /// when it stops. Returns false if there is no such running job.
+    pub fn cancel(&self, id: u64, lift_fence: bool) -> bool {
+        match self.state.jobs.lock().expect("lock").get(&id) {
+            Some(job) if *job.state.lock().expect("lock") == PurgeJobState::Running => {
+                if lift_fence {
+                    job.lift_fence.store(true, Ordering::Relaxed);
+                }
+                job.cancelled.store(true, Ordering::Relaxed);
+                true
+            }
+
+            _ => false,
+        }
+    }
+
+    /// Take down the purge fence of a cancelled or timed out job.
+    ///
+    /// Returns false if there is no such fence (or if the job is still running, in which case
+    /// use [cancel](Self::cancel)).
+    pub fn lift_fence(&self, id: u64) -> bool {
+        let running = self
+            .status(id)
+            .is_some_and(|status| status.state == PurgeJobState::Running);
+        !running && self.remove_fence(id)
+    }
+
+    /// Status of a job.
+    pub fn status(&self, id: u64) -> Option<PurgeJobStatus> {
+        self.state
+            .jobs
+            .lock()
+            .expect("lock")
+            .get(&id)
+            .map(|job| job.status())
+    }
+
+    /// Status of all retained jobs, by ID.
+    pub fn statuses(&self) -> Vec<PurgeJobStatus> {
+        let mut statuses: Vec<_> =
+            self.state.jobs.lock().expect("lock").values().map(|job| job.status()).collect();
+        statuses.sort_by_key(|status| status.id);
+        statuses
+    }
+
+    async fn run<CacheT, CacheKeyT>(
+        &self,
+        job: &PurgeJob,
+        cache: &CacheT,
+        keys: Vec<CacheKeyT>,
+        tag_header: &HeaderName,
+        pacing: PurgePacing,
+    ) -> PurgeJobState
+    where
+        CacheT: Cache<CacheKeyT>,
+        CacheKeyT: CacheKey,
+    {
+        for batch in keys.chunks(pacing.max_keys_per_tick) {
+            if job.cancelled.load(Ordering::Relaxed) {
+                return PurgeJobState::Cancelled;
+            }
+
+            if let Some(max_total_duration) = pacing.max_total_duration
+                && job.start.elapsed() > max_total_duration
+            {
+                return PurgeJobState::TimedOut;
+            }
+
+            for key in batch {
+                job.scanned.fetch_add(1, Ordering::Relaxed);
+
+                let matches = match &job.expression {
+                    BanExpression::PathPrefix(prefix) => {
+                        key.path().is_some_and(|path| path.starts_with(prefix.as_str()))
+                    }
+
+                    BanExpression::Tag(_) => cache.get(key).await.is_some_and(|cached_response| {
+                        job.expression.matches(key, &cached_response, tag_header)
+                    }),
+                };
+
+                if matches {
+                    cache.invalidate(key).await;
+                    job.invalidated.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+
+            ::tokio::time::sleep(pacing.tick_interval).await;
+        }
+
+        PurgeJobState::Completed
+    }
+
+    fn finish(&self, job: &PurgeJob, state: PurgeJobState) {
+        *job.state.lock().expect("lock") = state;
+        *job.finished.lock().expect("lock") = Some(job.start.elapsed());
+
+        if state == PurgeJobState::Completed || job.lift_fence.load(Ordering::Relaxed) {
+            self.remove_fence(job.id);
+        }
+
+        let status = job.status();
+        tracing::info!("purge job {}: {}", job.id, status);
+
+        let mut finished = self.state.finished.lock().expect("lock");
+        finished.push_back(job.id);
+        while finished.len() > self.retained {
+            if let Some(id) = finished.pop_front() {
+                self.state.jobs.lock().expect("lock").remove(&id);
+            }
+        }
+    }
+
+    fn remove_fence(&self, id: u64) -> bool {
+        let mut fences = self.state.fences.write().expect("lock");
+        let count = fences.len();
+        fences.retain(|fence| fence.job_id != id);
+        let removed = count - fences.len();
+        self.state.fence_count.fetch_sub(removed, Ordering::Release);
+        removed != 0
+    }
+}
+
+impl Default for PurgeJobs {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl fmt::Debug for PurgeJobs {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("PurgeJobs")
+            .field("pacing", &self.pacing)
+            .field("retained", &self.retained)
+            .field("fences", &self.state.fence_count.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+//
+// PurgeJobState
+//
+
+/// State of a [PurgeJobs] job.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PurgeJobState {
+    /// Running.
+    Running,
+
+    /// Completed. The purge fence is down.
+    Completed,
+
+    /// Cancelled. The purge fence is still up.
+    Cancelled,
+
+    /// Stopped after the maximum total duration. The purge fence is still up.
+    TimedOut,
+}
+
+impl fmt::Display for PurgeJobState {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(
+            match self {
+                Self::Running => "running",
+                Self::Completed => "completed",
+                Self::Cancelled => "cancelled",
+                Self::TimedOut => "timed out",
+            },
+            formatter,
+        )
+    }
+}
+
+//
+// PurgeJobStatus
+//
+
+/// Status of a [PurgeJobs] job.
+#[derive(Clone, Debug)]
+pub struct PurgeJobStatus {
+    /// ID.
+    pub id: u64,
+
+    /// Expression.
+    pub expression: BanExpression,
+
+    /// State.
+    pub state: PurgeJobState,
+
+    /// Number of keys in the snapshot.
+    pub total: usize,
+
+    /// Number of keys scanned so far.
+    pub scanned: u64,
+
+    /// Number of entries invalidated so far.
+    pub invalidated: u64,
+
+    /// When the job started.
+    pub started: SystemTime,
+
+    /// Time elapsed (until now if running, until it finished otherwise).
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for PurgeJobStatus {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            formatter,
+            "{}, scanned {}/{}, invalidated {}",
+            self.state, self.scanned, self.total, self.invalidated
+        )
+    }
+}
+
+#[derive(Default)]
+struct PurgeJobsState {
+    next_id: AtomicU64,
+    jobs: Mutex<FastHashMap<u64, Arc<PurgeJob>>>,
+    finished: Mutex<VecDeque<u64>>,
+    fences: RwLock<Vec<PurgeFence>>,
+    fence_count: AtomicUsize,
+}
+
+struct PurgeJob {
+    id: u64,
+    expression: BanExpression,
+    total: usize,
+    started: SystemTime,
+    start: Instant,
+    scanned: AtomicU64,
+    invalidated: AtomicU64,
+    state: Mutex<PurgeJobState>,
+    finished: Mutex<Option<Duration>>,
+    cancelled: AtomicBool,
+    lift_fence: AtomicBool,
+}
+
+impl PurgeJob {
+    fn status(&self) -> PurgeJobStatus {
+        PurgeJobStatus {
+            id: self.id,
+            expression: self.expression.clone(),
+            state: *self.state.lock().expect("lock"),
+            total: self.total,
+            scanned: self.scanned.load(Ordering::Relaxed),
+            invalidated: self.invalidated.load(Ordering::Relaxed),
+            started: self.started,
+            elapsed: self
+                .finished
+                .lock()
+                .expect("lock")
+                .unwrap_or_else(|| self.start.elapsed()),
+        }
+    }
+}
+
+struct PurgeFence {
+    job_id: u64,
+    expression: BanExpression,
+    tag_header: HeaderName,
+    as_of: SystemTime,
+}
@@ -0,0 +1,478 @@
+use super::{
+    super::{coding::*, key::*, response::*},
+    hooks::*,
+};
+
+use {
+    http::{header::*, *},
+    kutil::{http::*, std::collections::*},
+    std::{
+        fmt,
+        hash::*,
+        result::Result,
+        sync::{atomic::*, *},
+        time::*,
+    },
+};
+
+/// Default maximum body prefix passed to body validators (see [ServeValidation]).
+pub const DEFAULT_SERVE_VALIDATION_BODY_PREFIX: usize = 512;
+
+//
+// ServeValidator
+//
+
+/// Validator for [ServeValidation].
+#[derive(Clone)]
+pub struct ServeValidator {
+    /// Whether the validator needs a prefix of the body.
+    pub needs_body: bool,
+
+    /// Validate (hook).
+    pub validate: ServeValidationHook,
+}
+
+impl ServeValidator {
+    /// Constructor.
+    pub fn new(
+        needs_body: bool,
+        validate: impl Fn(ServeValidationHookContext) -> Result<(), String> + 'static + Send + Sync,
+    ) -> Self {
+        Self {
+            needs_body,
+            validate: Arc::new(Box::new(validate)),
+        }
+    }
+
+    /// Rejects entries with a `Content-Length` that differs from the length of the body.
+    pub fn content_length() -> Self {
+        Self::new(false, |context| match (context.headers.content_length(), context.body_length) {
+            (Some(content_length), Some(body_length)) if content_length != body_length => Err(
+                format!("Content-Length is {} but body is {}", content_length, body_length),
+            ),
+
+            _ => Ok(()),
+        })
+    }
+
+    /// Rejects entries with a body that is sniffed as one of these types while `Content-Type`
+    /// declares another family: HTML under `image/*`, or an image under `text/html`.
+    pub fn content_type_sniff(types: &[SniffedType]) -> Self {
+        let types = types.to_vec();
+        Self::new(true, move |context| {
+            let (Some(prefix), Some(content_type)) =
+                (context.body_prefix, context.headers.string_value(CONTENT_TYPE))
+            else {
+                return Ok(());
+            };
+
+            let Some(sniffed_type) = SniffedType::sniff(prefix) else {
+                return Ok(());
+            };
+
+            let content_type = content_type.trim().to_ascii_lowercase();
+            let mismatch = if sniffed_type == SniffedType::Html {
+                content_type.starts_with("image/")
+            } else {
+                content_type.starts_with("text/html")
+            };
+
+            if mismatch && types.contains(&sniffed_type) {
+                Err(format!("{} body under Content-Type {}", sniffed_type, content_type))
+            } else {
+                Ok(())
+            }
+        })
+    }
+
+    /// Rejects entries with a body that is not valid UTF-8 under `charset=utf-8`.
+    ///
+    /// A multi-byte sequence cut off by the end of the prefix is not an error.
+    pub fn utf8() -> Self {
+        Self::new(true, |context| {
+            let (Some(prefix), Some(content_type)) =
+                (context.body_prefix, context.headers.string_value(CONTENT_TYPE))
+            else {
+                return Ok(());
+            };
+
+            let content_type = content_type.to_ascii_lowercase().replace([' ', '"'], "");
+            if !content_type.contains("charset=utf-8") {
+                return Ok(());
+            }
+
+            match std::str::from_utf8(prefix) {
+                Err(error) if error.error_len().is_some() => Err(format!(
+                    "invalid UTF-8 at byte {} under charset=utf-8",
+                    error.valid_up_to()
+                )),
+
+                _ => Ok(()),
+            }
+        })
+    }
+}
+
+impl fmt::Debug for ServeValidator {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("ServeValidator")
+            .field("needs_body", &self.needs_body)
+            .finish()
+    }
+}
+
+//
+// SniffedType
+//
+
+/// Body type recognized by its magic bytes (see [ServeValidator::content_type_sniff]).
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum SniffedType {
+    /// HTML.
+    Html,
+
+    /// PNG.
+    Png,
+
+    /// JPEG.
+    Jpeg,
+
+    /// GIF.
+    Gif,
+
+    /// WebP.
+    WebP,
+}
+
+impl SniffedType {
+    /// All types.
+    pub const ALL: [Self; 5] = [Self::Html, Self::Png, Self::Jpeg, Self::Gif, Self::WebP];
+
+    /// Sniff a body prefix.
+    pub fn sniff(prefix: &[u8]) -> Option<Self> {
+        if prefix.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(Self::Png)
+        } else if prefix.starts_with(b"\xff\xd8\xff") {
+            Some(Self::Jpeg)
+        } else if prefix.starts_with(b"GIF87a") || prefix.starts_with(b"GIF89a") {
+            Some(Self::Gif)
+        } else if prefix.len() >= 12 && prefix.starts_with(b"RIFF") && &prefix[8..12] == b"WEBP" {
+            Some(Self::WebP)
+        } else {
+            let text = prefix.trim_ascii_start();
+            let start = &text[..text.len().min(14)];
+            (start.eq_ignore_ascii_case(b"<!doctype html")
+                || start.get(..5).is_some_and(|start| start.eq_ignore_ascii_case(b"<html")))
+            .then_some(Self::Html)
+        }
+    }
+}
+
+impl fmt::Display for SniffedType {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(
+            match self {
+                Self::Html => "HTML",
+                Self::Png => "PNG",
+                Self::Jpeg => "JPEG",
+                Self::Gif => "GIF",
+                Self::WebP => "WebP",
+            },
+            formatter,
+        )
+    }
+}
+
+//
+// ServeValidation
+//
+
+/// Serve-time sanity check of cache entries, with automatic quarantine.
+///
+/// Before a hit is served its entry is checked by the validators. An entry that any of them
+/// rejects is quarantined: it is invalidated and the request is handled as a miss, so that the
+/// fresh upstream response replaces it. The rejection is counted, logged (with the key rendered
+/// for logs), and reported to the quarantine hook, if configured.
+///
+/// A key that is rejected `threshold` consecutive times (e.g. because the validator always
+/// rejects what the upstream produces) is cooled down: for the cooldown period its requests go to
+/// the upstream and are served uncached, so that a broken validator can't double the work of
+/// every request.
+///
+/// Validators get a prefix of the body only if one of them [needs it](ServeValidator::needs_body),
+/// and only if the entry has an [Identity](kutil::transcoding::Encoding::Identity)
+/// representation. Otherwise checking is limited to the headers.
+///
+/// Cloning is cheap and clones share state.
+#[derive(Clone)]
+pub struct ServeValidation {
+    /// Validators.
+    pub validators: Vec<ServeValidator>,
+
+    /// Maximum body prefix passed to validators.
+    pub body_prefix: usize,
+
+    /// Consecutive rejections of a key before it is cooled down.
+    pub threshold: u32,
+
+    /// Cooldown period.
+    pub cooldown: Duration,
+
+    /// Maximum number of tracked keys.
+    pub capacity: usize,
+
+    /// Quarantine (hook).
+    pub on_quarantine: Option<QuarantineHook>,
+
+    state: Arc<QuarantineState>,
+}
+
+impl ServeValidation {
+    /// Constructor.
+    pub fn new(validators: Vec<ServeValidator>) -> Self {
+        Self {
+            validators,
+            body_prefix: DEFAULT_SERVE_VALIDATION_BODY_PREFIX,
+            threshold: 3,
+            cooldown: Duration::from_secs(60),
+            capacity: 10 * 1024,
+            on_quarantine: None,
+            state: Arc::new(QuarantineState {
+                hasher: Default::default(),
+                keys: Default::default(),
+                tracked: AtomicUsize::new(0),
+                quarantined: AtomicU64::new(0),
+                cooled_down: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Set maximum body prefix passed to validators.
+    pub fn body_prefix(mut self, body_prefix: usize) -> Self {
+        self.body_prefix = body_prefix;
+        self
+    }
+
+    /// Set consecutive rejections of a key before it is cooled down, and the cooldown period.
+    pub fn cooldown(mut self, threshold: u32, cooldown: Duration) -> Self {
+        self.threshold = threshold;
+        self.cooldown = cooldown;
+        self
+    }
+
+    /// Set quarantine hook.
+    pub fn on_quarantine(
+        mut self,
+        on_quarantine: impl Fn(QuarantineEvent) + 'static + Send + Sync,
+    ) -> Self {
+        self.on_quarantine = Some(Arc::new(Box::new(on_quarantine)));
+        self
+    }
+
+    /// Whether a key is cooling down.
+    pub fn cooling_down(&self, key: &impl Hash) -> bool {
+        // Avoid the lock in the common case
+        if self.state.tracked.load(Ordering::Relaxed) == 0 {
+            return false;
+        }
+
+        let hash = self.state.hasher.hash_one(key);
+        let mut keys = self.state.keys.lock().expect("lock");
+        match keys.get(&hash).and_then(|rejections| rejections.cooldown_until) {
+            Some(cooldown_until) if Instant::now() < cooldown_until => true,
+
+            Some(_) => {
+                tracing::debug!("cooldown over");
+                keys.remove(&hash);
+                self.state.tracked.store(keys.len(), Ordering::Relaxed);
+                false
+            }
+
+            None => false,
+        }
+    }
+
+    /// Check an entry that is about to be served.
+    pub fn check<CacheKeyT>(
+        &self,
+        uri: &Uri,
+        key: &CacheKeyT,
+        cached_response: &CachedResponse,
+        key_log_policy: &KeyLogPolicy,
+    ) -> ServeCheck
+    where
+        CacheKeyT: CacheKey,
+    {
+        let representations = &cached_response.body.representations;
+        let body_prefix = if self.validators.iter().any(|validator| validator.needs_body) {
+            representations
+                .get(&CodingId::IDENTITY)
+                .map(|bytes| &bytes[..bytes.len().min(self.body_prefix)])
+        } else {
+            None
+        };
+
+        let context = ServeValidationHookContext::new(
+            uri,
+            cached_response.headers(),
+            representations
+                .get(&cached_response.original_coding)
+                .map(|bytes| bytes.len()),
+            body_prefix,
+        );
+
+        let rejection = self
+            .validators
+            .iter()
+            .find_map(|validator| (validator.validate)(context.clone()).err());
+
+        let Some(reason) = rejection else {
+            self.accept(key);
+            return ServeCheck::Valid;
+        };
+
+        let cool_down = self.reject(key);
+
+        let key = key.display_for_logs(key_log_policy).to_string();
+        tracing::warn!("quarantined ({}): {}", reason, key);
+        self.state.quarantined.fetch_add(1, Ordering::Relaxed);
+
+        let check = if cool_down {
+            self.state.cooled_down.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!("cooling down for {:?}: {}", self.cooldown, key);
+            ServeCheck::CoolingDown
+        } else {
+            ServeCheck::Rejected
+        };
+
+        if let Some(on_quarantine) = &self.on_quarantine {
+            on_quarantine(QuarantineEvent { key, reason });
+        }
+
+        check
+    }
+
+    /// Statistics.
+    pub fn stats(&self) -> QuarantineStats {
+        QuarantineStats {
+            tracked: self.state.tracked.load(Ordering::Relaxed),
+            quarantined: self.state.quarantined.load(Ordering::Relaxed),
+            cooled_down: self.state.cooled_down.load(Ordering::Relaxed),
+        }
+    }
+
+    // Reset the consecutive rejections of a key.
+    fn accept(&self, key: &impl Hash) {
+        if self.state.tracked.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+
+        let hash = self.state.hasher.hash_one(key);
+        let mut keys = self.state.keys.lock().expect("lock");
+        keys.remove(&hash);
+        self.state.tracked.store(keys.len(), Ordering::Relaxed);
+    }
+
+    // Count a rejection of a key. Returns true if it should cool down.
+    fn reject(&self, key: &impl Hash) -> bool {
+        let hash = self.state.hasher.hash_one(key);
+        let mut keys = self.state.keys.lock().expect("lock");
+
+        if !keys.contains_key(&hash) && keys.len() >= self.capacity {
+            let now = Instant::now();
+            keys.retain(|_hash, rejections| {
+                rejections.cooldown_until.is_some_and(|cooldown_until| now < cooldown_until)
+            });
+            if keys.len() >= self.capacity {
+                return false;
+            }
+        }
+
+        let rejections = keys.entry(hash).or_default();
+        rejections.consecutive = rejections.consecutive.saturating_add(1);
+
+        let cool_down = rejections.consecutive >= self.threshold;
+        if cool_down {
+            rejections.cooldown_until = Some(Instant::now() + self.cooldown);
+        }
+
+        self.state.tracked.store(keys.len(), Ordering::Relaxed);
+        cool_down
+    }
+}
+
+impl fmt::Debug for ServeValidation {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("ServeValidation")
+            .field("validators", &self.validators)
+            .field("body_prefix", &self.body_prefix)
+            .field("threshold", &self.threshold)
+            .field("cooldown", &self.cooldown)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+//
+// ServeCheck
+//
+
+/// Outcome of [ServeValidation::check].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ServeCheck {
+    /// Serve the entry.
+    Valid,
+
+    /// Quarantine the entry and handle the request as a miss.
+    Rejected,
+
+    /// Quarantine the entry and serve the upstream response uncached.
+    CoolingDown,
+}
+
+//
+// QuarantineEvent
+//
+
+/// Quarantine event (see [ServeValidation]).
+#[derive(Clone, Debug)]
+pub struct QuarantineEvent {
+    /// Key (rendered for logs).
+    pub key: String,
+
+    /// Rejection reason.
+    pub reason: String,
+}
+
+//
+// QuarantineStats
+//
+
+/// [ServeValidation] statistics.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QuarantineStats {
+    /// Number of tracked keys.
+    pub tracked: usize,
+
+    /// Quarantined entries.
+    pub quarantined: u64,
+
+    /// Number of times a key was cooled down.
+    pub cooled_down: u64,
+}
+
+struct QuarantineState {
+    hasher: RandomState,
+    keys: Mutex<FastHashMap<u64, Rejections>>,
+    tracked: AtomicUsize,
+    quarantined: AtomicU64,
+    cooled_down: AtomicU64,
+}
+
+#[derive(Default)]
+struct Rejections {
+    consecutive: u32,
+    cooldown_until: Option<Instant>,
+}
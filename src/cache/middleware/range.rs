@@ -0,0 +1,119 @@
+use {
+    http::{header::*, *},
+    kutil::std::immutable::*,
+    std::ops::Range,
+};
+
+//
+// RangeRequest
+//
+
+/// How to answer a `Range` request from a cached representation of a known size.
+///
+/// Only single byte ranges are served. Multiple ranges, other units, syntactically invalid
+/// ranges, and ranges whose `If-Range` doesn't match the entry are answered with the full
+/// representation, which is permitted by HTTP.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RangeRequest {
+    /// Serve the full representation.
+    Full,
+
+    /// Serve `206 Partial Content` for this range of bytes.
+    Partial(Range<usize>),
+
+    /// Serve `416 Range Not Satisfiable`.
+    Unsatisfiable,
+}
+
+impl RangeRequest {
+    /// Evaluate the request headers against a representation.
+    ///
+    /// `total` is the size of the representation and `entry_headers` are its stored headers
+    /// (for `If-Range`).
+    pub fn evaluate(request_headers: &HeaderMap, total: usize, entry_headers: &HeaderMap) -> Self {
+        let Some(range) = request_headers.get(RANGE).and_then(|range| range.to_str().ok()) else {
+            return Self::Full;
+        };
+
+        if let Some(if_range) = request_headers.get(IF_RANGE)
+            && !if_range_matches(if_range, entry_headers)
+        {
+            return Self::Full;
+        }
+
+        Self::parse(range, total)
+    }
+
+    /// Parse a `Range` header value, e.g. `bytes=0-1023`, `bytes=1024-`, or `bytes=-512`.
+    pub fn parse(range: &str, total: usize) -> Self {
+        let Some(range) = range.trim().strip_prefix("bytes=") else {
+            return Self::Full;
+        };
+
+        if range.contains(',') {
+            return Self::Full;
+        }
+
+        let Some((start, end)) = range.split_once('-') else {
+            return Self::Full;
+        };
+        let (start, end) = (start.trim(), end.trim());
+
+        if start.is_empty() {
+            // Suffix range: the last bytes
+            return match end.parse::<usize>() {
+                Ok(0) => Self::Unsatisfiable,
+                Ok(_) if total == 0 => Self::Unsatisfiable,
+                Ok(suffix) => Self::Partial(total.saturating_sub(suffix)..total),
+                Err(_) => Self::Full,
+            };
+        }
+
+        let Ok(start) = start.parse::<usize>() else {
+            return Self::Full;
+        };
+
+        let end = if end.is_empty() {
+            None
+        } else {
+            match end.parse::<usize>() {
+                Ok(end) if end >= start => Some(end),
+                _ => return Self::Full,
+            }
+        };
+
+        if start >= total {
+            return Self::Unsatisfiable;
+        }
+
+        // The last position is inclusive and may be beyond the end
+        let end = end.map(|end| end.saturating_add(1)).unwrap_or(total).min(total);
+        Self::Partial(start..end)
+    }
+}
+
+/// `416 Range Not Satisfiable` response for a representation of a known size.
+pub fn range_not_satisfiable_response<BodyT>(total: usize) -> Response<BodyT>
+where
+    BodyT: From<ImmutableBytes>,
+{
+    let mut response = Response::new(ImmutableBytes::default().into());
+    *response.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+    let headers = response.headers_mut();
+    if let Ok(content_range) = HeaderValue::try_from(format!("bytes */{}", total)) {
+        headers.insert(CONTENT_RANGE, content_range);
+    }
+    headers.insert(CONTENT_LENGTH, HeaderValue::from_static("0"));
+    response
+}
+
+// Whether `If-Range` matches the entry: a strong ETag or the exact Last-Modified date.
+fn if_range_matches(if_range: &HeaderValue, entry_headers: &HeaderMap) -> bool {
+    if if_range.as_bytes().starts_with(b"\"") {
+        entry_headers
+            .get(ETAG)
+            .is_some_and(|etag| etag == if_range && !etag.as_bytes().starts_with(b"W/"))
+    } else {
+        entry_headers.get(LAST_MODIFIED) == Some(if_range)
+    }
+}
@@ -1,8 +1,15 @@
-use super::{super::key::*, configuration::*, hooks::*};
+use super::{
+    super::{coding::*, key::*},
+    accept_encoding::*,
+    configuration::*,
+    forwarded::*,
+    hooks::*,
+    target::*,
+};
 
 use {
-    http::*,
-    kutil::{http::*, transcoding::*},
+    http::{header::*, *},
+    kutil::http::*,
 };
 
 //
@@ -17,16 +24,61 @@ pub trait CacheableEncodableRequest<RequestBodyT> {
         configuration: &MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>,
     ) -> bool;
 
+    /// Negotiate language if [LanguageNegotiation](super::language::LanguageNegotiation) is
+    /// configured.
+    fn negotiate_language<CacheT, CacheKeyT>(
+        &self,
+        configuration: &MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+    ) -> Option<Language>;
+
     /// May call `cache_key` hook.
+    ///
+    /// Resolves the [RequestOrigin] and then calls
+    /// [cache_key_for_origin](Self::cache_key_for_origin).
     fn cache_key_with_hook<CacheT, CacheKeyT>(
         &self,
+        language: Option<&Language>,
+        configuration: &MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+    ) -> CacheKeyT
+    where
+        CacheKeyT: CacheKey;
+
+    /// May call `cache_key` hook.
+    ///
+    /// The key is created from the URI selected by [KeyUriSource](super::key_uri::KeyUriSource).
+    /// The negotiated language, if provided, and the origin, if host partitioning is enabled, are
+    /// set before calling the hook.
+    ///
+    /// For an absolute-form target (see [RequestTargetForm]) the key is created from its
+    /// origin-form, and the origin is always set from its scheme and authority.
+    fn cache_key_for_origin<CacheT, CacheKeyT>(
+        &self,
+        language: Option<&Language>,
+        origin: &RequestOrigin,
         configuration: &MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>,
     ) -> CacheKeyT
     where
         CacheKeyT: CacheKey;
 
+    /// Negotiates via the configured [EncodingNegotiator](super::negotiation::EncodingNegotiator),
+    /// after normalizing `Accept-Encoding` (see [AcceptEncoding]).
+    ///
+    /// Always [Identity](kutil::transcoding::Encoding::Identity) if `assume_inner_compression`.
+    ///
     /// May call `encodable_by_request` hook.
-    fn select_encoding(&self, configuration: &MiddlewareEncodingConfiguration) -> Encoding;
+    fn select_encoding(&self, configuration: &MiddlewareEncodingConfiguration) -> CodingId;
+
+    /// The coding in which to store a new entry: the most preferred enabled built-in coding.
+    ///
+    /// Unlike [select_encoding](Self::select_encoding), it does not depend on the request's
+    /// `Accept-Encoding`, so that what we store is decided by our preferences rather than by the
+    /// quirks of whichever client happened to trigger the miss (e.g. one that sends only `*` or
+    /// only unknown codings). That client is served its own negotiated coding from the entry.
+    ///
+    /// Always [Identity](kutil::transcoding::Encoding::Identity) if `assume_inner_compression`.
+    ///
+    /// May call `encodable_by_request` hook.
+    fn store_encoding(&self, configuration: &MiddlewareEncodingConfiguration) -> CodingId;
 }
 
 impl<RequestBodyT> CacheableEncodableRequest<RequestBodyT> for Request<RequestBodyT> {
@@ -34,12 +86,15 @@ impl<RequestBodyT> CacheableEncodableRequest<RequestBodyT> for Request<RequestBo
         &self,
         configuration: &MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>,
     ) -> bool {
-        let mut skip_cache = if !configuration.cache.is_none() {
+        let mut skip_cache = if configuration.cache.is_some() {
             let method = self.method();
-            if method.is_idempotent() {
+            // Non-idempotent requests would otherwise share an entry per URI
+            if configuration.methods.allows(method)
+                && (method.is_idempotent() || configuration.body_key.is_some())
+            {
                 false
             } else {
-                tracing::debug!("skip (non-idempotent {})", method);
+                tracing::debug!("skip (method {})", method);
                 true
             }
         } else {
@@ -47,6 +102,36 @@ impl<RequestBodyT> CacheableEncodableRequest<RequestBodyT> for Request<RequestBo
             true
         };
 
+        if !skip_cache {
+            match RequestTargetForm::of(self) {
+                RequestTargetForm::Origin => {}
+                RequestTargetForm::Absolute if configuration.cache_absolute_form => {}
+                form => {
+                    tracing::debug!("skip ({})", form);
+                    skip_cache = true;
+                }
+            }
+        }
+
+        if !skip_cache
+            && let Some(name) = configuration
+                .bypass_headers
+                .iter()
+                .find(|name| self.headers().contains_key(*name))
+        {
+            tracing::debug!("skip (header {})", name);
+            skip_cache = true;
+        }
+
+        // Even if partitioned by it
+        if !skip_cache
+            && configuration.strict_privacy
+            && self.headers().contains_key(AUTHORIZATION)
+        {
+            tracing::debug!("skip (strict privacy)");
+            skip_cache = true;
+        }
+
         if !skip_cache
             && let Some(cacheable) = &configuration.cacheable_by_request
             && !cacheable(CacheableHookContext::new(self.uri(), self.headers()))
@@ -55,55 +140,193 @@ impl<RequestBodyT> CacheableEncodableRequest<RequestBodyT> for Request<RequestBo
             skip_cache = true;
         }
 
+        // Axum adds MatchedPath only for requests that a route matched, not for fallbacks
+        #[cfg(feature = "axum")]
+        if !skip_cache
+            && configuration.never_cache_unmatched
+            && self
+                .extensions()
+                .get::<::axum::extract::MatchedPath>()
+                .is_none()
+        {
+            tracing::debug!("skip (unmatched)");
+            skip_cache = true;
+        }
+
         skip_cache
     }
 
+    fn negotiate_language<CacheT, CacheKeyT>(
+        &self,
+        configuration: &MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+    ) -> Option<Language> {
+        configuration
+            .language_negotiation
+            .as_ref()
+            .map(|language_negotiation| language_negotiation.negotiate(self.headers()).clone())
+    }
+
     fn cache_key_with_hook<CacheT, CacheKeyT>(
         &self,
+        language: Option<&Language>,
         configuration: &MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>,
     ) -> CacheKeyT
     where
         CacheKeyT: CacheKey,
     {
-        let mut cache_key = self.cache_key();
+        let origin = RequestOrigin::resolve(self, configuration.trusted_forwarded.as_ref());
+        self.cache_key_for_origin(language, &origin, configuration)
+    }
+
+    fn cache_key_for_origin<CacheT, CacheKeyT>(
+        &self,
+        language: Option<&Language>,
+        origin: &RequestOrigin,
+        configuration: &MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+    ) -> CacheKeyT
+    where
+        CacheKeyT: CacheKey,
+    {
+        let (target_uri, target_origin);
+        let mut uri = configuration.key_uri_source.uri(self);
+        let mut origin = origin;
+        let mut partition = configuration.partition_by_host;
+
+        // An absolute-form target's authority is part of the key, so that it can never collide
+        // with keys for other hosts
+        if RequestTargetForm::of(self) == RequestTargetForm::Absolute {
+            target_uri = origin_form(uri);
+            target_origin = RequestOrigin::from_uri(uri);
+            uri = &target_uri;
+            origin = &target_origin;
+            partition = true;
+        }
+
+        let mut cache_key = if self.method() == Method::HEAD {
+            CacheKeyT::for_head_lookup(uri, self.headers())
+        } else {
+            CacheKeyT::for_request(self.method(), uri, self.headers())
+        };
+
+        if partition {
+            cache_key.set_origin(
+                origin.scheme.as_ref(),
+                origin.host.as_ref().map(AsRef::<str>::as_ref),
+                origin.port,
+            );
+        }
+
+        if let Some(language) = language {
+            cache_key.set_negotiated_language(language.clone());
+        }
+
+        for name in &configuration.partition_headers {
+            let digest = header_digest(self.headers(), name, &configuration.header_digest_key);
+            cache_key.set_header_digest(name, digest);
+        }
 
         if let Some(cache_key_hook) = &configuration.cache_key {
-            cache_key_hook(CacheKeyHookContext::new(&mut cache_key, self));
+            cache_key_hook(CacheKeyHookContext::new(&mut cache_key, self, origin));
         }
 
         cache_key
     }
 
-    fn select_encoding(&self, configuration: &MiddlewareEncodingConfiguration) -> Encoding {
-        let encoding = match &configuration.enabled_encodings_by_preference {
-            Some(enabled_encodings) => {
-                if !enabled_encodings.is_empty() {
-                    self.headers()
-                        .accept_encoding()
-                        .best(enabled_encodings)
-                        .cloned()
-                        .unwrap_or_default()
-                        .into()
-                } else {
-                    return Encoding::Identity;
+    fn select_encoding(&self, configuration: &MiddlewareEncodingConfiguration) -> CodingId {
+        if configuration.assume_inner_compression {
+            return CodingId::IDENTITY;
+        }
+
+        let Some(enabled_encodings) = &configuration.enabled_encodings_by_preference else {
+            return CodingId::IDENTITY;
+        };
+
+        let custom_codings = &configuration.enabled_custom_codings_by_preference;
+        if enabled_encodings.is_empty() && custom_codings.is_empty() {
+            return CodingId::IDENTITY;
+        }
+
+        let accept_encoding = match AcceptEncoding::from_headers(
+            self.headers(),
+            configuration.max_accept_encoding_members,
+        ) {
+            Some(accept_encoding) => {
+                configuration
+                    .accept_encoding_anomalies
+                    .record(&accept_encoding.anomalies);
+
+                if accept_encoding.identity_only {
+                    return CodingId::IDENTITY;
                 }
+
+                Some(accept_encoding.to_header_value())
             }
 
-            None => return Encoding::Identity,
+            None => None,
         };
 
-        if encoding != Encoding::Identity
-            && let Some(encodable) = &configuration.encodable_by_request
-            && !encodable(EncodableHookContext::new(
-                &encoding,
-                self.uri(),
-                self.headers(),
-            ))
-        {
-            tracing::debug!("not encoding to {} (encodable_by_request=false)", encoding);
-            return Encoding::Identity;
+        let coding = configuration
+            .negotiator
+            .negotiate(
+                accept_encoding.as_deref().as_slice(),
+                custom_codings,
+                enabled_encodings,
+            )
+            .unwrap_or(CodingId::IDENTITY);
+
+        encodable_coding(self, coding, configuration)
+    }
+
+    fn store_encoding(&self, configuration: &MiddlewareEncodingConfiguration) -> CodingId {
+        if configuration.assume_inner_compression {
+            return CodingId::IDENTITY;
         }
 
-        encoding
+        let Some(most_preferred) = configuration
+            .enabled_encodings_by_preference
+            .as_ref()
+            .and_then(|enabled_encodings| enabled_encodings.first())
+        else {
+            return CodingId::IDENTITY;
+        };
+
+        encodable_coding(self, CodingId::Builtin((*most_preferred).into()), configuration)
+    }
+}
+
+// Keyed digest of all the values of a header, in order; None if there are none.
+fn header_digest(headers: &HeaderMap, name: &HeaderName, key: &[u8; 16]) -> Option<u64> {
+    let mut values = headers.get_all(name).iter().peekable();
+    values.peek()?;
+
+    // Length-prefixed, so that different splits of the same bytes differ
+    let mut bytes = Vec::default();
+    for value in values {
+        bytes.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(value.as_bytes());
+    }
+
+    Some(keyed_hash(key, &bytes))
+}
+
+// The coding if it is available and encodable for the request, otherwise Identity.
+fn encodable_coding<RequestBodyT>(
+    request: &Request<RequestBodyT>,
+    coding: CodingId,
+    configuration: &MiddlewareEncodingConfiguration,
+) -> CodingId {
+    if !coding.is_available() {
+        tracing::warn!("not encoding to {} (feature not enabled)", coding);
+        return CodingId::IDENTITY;
     }
+
+    if !coding.is_identity()
+        && let Some(encodable) = &configuration.encodable_by_request
+        && !encodable(EncodableHookContext::new(&coding, request.uri(), request.headers()))
+    {
+        tracing::debug!("not encoding to {} (encodable_by_request=false)", coding);
+        return CodingId::IDENTITY;
+    }
+
+    coding
 }
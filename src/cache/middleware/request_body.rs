@@ -0,0 +1,123 @@
+use {
+    http::*,
+    http_body::*,
+    kutil::{
+        http::*,
+        std::{error::*, future::*, immutable::*},
+    },
+    std::{result::Result, sync::*},
+};
+
+// Reads a request body of a declared size and puts it back.
+type BufferFn<RequestBodyT> = Arc<
+    Box<
+        dyn Fn(Request<RequestBodyT>, usize) -> CapturedFuture<BufferedRequest<RequestBodyT>>
+            + Send
+            + Sync,
+    >,
+>;
+
+type BufferedRequest<RequestBodyT> = Result<(Request<RequestBodyT>, ImmutableBytes), String>;
+
+//
+// RequestBodyBuffer
+//
+
+/// Buffers request bodies for inspection and then puts them back into the request.
+///
+/// Type-erased, so that the service doesn't have to require its request body type to be a
+/// [Body]. Request trailers are discarded.
+///
+/// Only bodies with a declared `Content-Length` are buffered, so that a body is never read
+/// unless it can be put back. A body that is longer than declared can't be put back.
+pub struct RequestBodyBuffer<RequestBodyT> {
+    buffer: BufferFn<RequestBodyT>,
+}
+
+impl<RequestBodyT> RequestBodyBuffer<RequestBodyT> {
+    /// Constructor.
+    pub fn new() -> Self
+    where
+        RequestBodyT: 'static + Body + From<ImmutableBytes> + Send + Unpin,
+        RequestBodyT::Error: Into<CapturedError>,
+    {
+        let buffer: BufferFn<RequestBodyT> =
+            Arc::new(Box::new(|request: Request<RequestBodyT>, declared_size| {
+                capture_async! {
+                    let (parts, body) = request.into_parts();
+                    let bytes = body
+                        .read_into_bytes_or_pieces(Some(declared_size), 0, declared_size)
+                        .await;
+                    match bytes {
+                        Ok((bytes, _trailers)) => {
+                            Ok((Request::from_parts(parts, bytes.clone().into()), bytes))
+                        }
+
+                        Err(error) => Err(error.error.to_string()),
+                    }
+                }
+            }));
+
+        Self { buffer }
+    }
+
+    /// Buffer the request body if its declared size is at most `max_body_size`.
+    pub async fn buffer(
+        &self,
+        request: Request<RequestBodyT>,
+        max_body_size: usize,
+    ) -> BufferedRequestBody<RequestBodyT> {
+        let declared_size = match request.headers().content_length() {
+            Some(declared_size) if declared_size <= max_body_size => declared_size,
+
+            Some(declared_size) => {
+                tracing::debug!("request body too large: {}", declared_size);
+                return BufferedRequestBody::NotBuffered(request);
+            }
+
+            None => {
+                tracing::debug!("request body size not declared");
+                return BufferedRequestBody::NotBuffered(request);
+            }
+        };
+
+        match (self.buffer)(request, declared_size).await {
+            Ok((request, body)) => BufferedRequestBody::Buffered(request, body),
+            Err(error) => BufferedRequestBody::Failed(error),
+        }
+    }
+}
+
+impl<RequestBodyT> Default for RequestBodyBuffer<RequestBodyT>
+where
+    RequestBodyT: 'static + Body + From<ImmutableBytes> + Send + Unpin,
+    RequestBodyT::Error: Into<CapturedError>,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<RequestBodyT> Clone for RequestBodyBuffer<RequestBodyT> {
+    fn clone(&self) -> Self {
+        Self {
+            buffer: self.buffer.clone(),
+        }
+    }
+}
+
+//
+// BufferedRequestBody
+//
+
+/// Outcome of [RequestBodyBuffer::buffer].
+pub enum BufferedRequestBody<RequestBodyT> {
+    /// The request has the buffered body.
+    Buffered(Request<RequestBodyT>, ImmutableBytes),
+
+    /// The body was not read, because its size is not declared or is too large.
+    NotBuffered(Request<RequestBodyT>),
+
+    /// The body could not be read, and the request is lost.
+    Failed(String),
+}
@@ -0,0 +1,167 @@
+use {
+    kutil::std::collections::*,
+    std::{
+        fmt, future, mem,
+        sync::*,
+        task::{Poll, Waker},
+    },
+};
+
+//
+// ResourceLimiter
+//
+
+/// Limits concurrent upstream calls per resource.
+///
+/// A resource is a grouping of cache keys (e.g. the language variants of a page), identified by
+/// an opaque string. When many variants of one resource miss at once, e.g. after it was
+/// invalidated, only a limited number of them call the upstream concurrently while the rest are
+/// queued. Other resources are not affected.
+///
+/// Per-resource state is created on demand and removed when the resource is idle, so memory use
+/// is bounded by the number of resources with calls in flight. Permits are released when dropped,
+/// and queued calls that are dropped (e.g. because the client disconnected) never hold up the
+/// queue.
+///
+/// Cloning is cheap and clones share state.
+#[derive(Clone)]
+pub struct ResourceLimiter {
+    state: Arc<ResourceLimiterState>,
+}
+
+impl ResourceLimiter {
+    /// Constructor.
+    pub fn new(max_concurrent: usize) -> Self {
+        Self {
+            state: Arc::new(ResourceLimiterState {
+                max_concurrent: max_concurrent.max(1),
+                resources: Default::default(),
+            }),
+        }
+    }
+
+    /// Maximum concurrent calls per resource.
+    pub fn max_concurrent(&self) -> usize {
+        self.state.max_concurrent
+    }
+
+    /// Acquire a permit if one is available now.
+    pub fn try_acquire(&self, resource_id: &Arc<str>) -> Option<ResourcePermit> {
+        let mut resources = self.state.resources.lock().expect("lock");
+        let slots = resources.entry(resource_id.clone()).or_default();
+        if slots.in_flight < self.state.max_concurrent {
+            slots.in_flight += 1;
+            Some(self.permit(resource_id))
+        } else {
+            None
+        }
+    }
+
+    /// Acquire a permit, waiting for one to become available.
+    pub async fn acquire(&self, resource_id: Arc<str>) -> ResourcePermit {
+        future::poll_fn(|context| {
+            let mut resources = self.state.resources.lock().expect("lock");
+            let slots = resources.entry(resource_id.clone()).or_default();
+            if slots.in_flight < self.state.max_concurrent {
+                slots.in_flight += 1;
+                Poll::Ready(self.permit(&resource_id))
+            } else {
+                if !slots.waiters.iter().any(|waiter| waiter.will_wake(context.waker())) {
+                    slots.waiters.push(context.waker().clone());
+                }
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Number of resources with calls in flight or queued.
+    pub fn active_resources(&self) -> usize {
+        self.state.resources.lock().expect("lock").len()
+    }
+
+    fn permit(&self, resource_id: &Arc<str>) -> ResourcePermit {
+        ResourcePermit {
+            state: self.state.clone(),
+            resource_id: resource_id.clone(),
+        }
+    }
+}
+
+impl fmt::Debug for ResourceLimiter {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("ResourceLimiter")
+            .field("max_concurrent", &self.state.max_concurrent)
+            .field("active_resources", &self.active_resources())
+            .finish()
+    }
+}
+
+//
+// ResourcePermit
+//
+
+/// [ResourceLimiter] permit.
+///
+/// Released when dropped.
+pub struct ResourcePermit {
+    state: Arc<ResourceLimiterState>,
+    resource_id: Arc<str>,
+}
+
+impl ResourcePermit {
+    /// Resource ID.
+    pub fn resource_id(&self) -> &str {
+        &self.resource_id
+    }
+}
+
+impl Drop for ResourcePermit {
+    fn drop(&mut self) {
+        let waiters = {
+            let mut resources = self.state.resources.lock().expect("lock");
+            match resources.get_mut(&self.resource_id) {
+                Some(slots) => {
+                    slots.in_flight = slots.in_flight.saturating_sub(1);
+
+                    // Waking all of them (rather than one) means that a waiter that is no longer
+                    // around can't swallow the wake
+                    let waiters = mem::take(&mut slots.waiters);
+
+                    if slots.in_flight == 0 {
+                        resources.remove(&self.resource_id);
+                    }
+
+                    waiters
+                }
+
+                None => Default::default(),
+            }
+        };
+
+        for waiter in waiters {
+            waiter.wake();
+        }
+    }
+}
+
+impl fmt::Debug for ResourcePermit {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("ResourcePermit")
+            .field("resource_id", &self.resource_id)
+            .finish()
+    }
+}
+
+struct ResourceLimiterState {
+    max_concurrent: usize,
+    resources: Mutex<FastHashMap<Arc<str>, ResourceSlots>>,
+}
+
+#[derive(Default)]
+struct ResourceSlots {
+    in_flight: usize,
+    waiters: Vec<Waker>,
+}
@@ -1,13 +1,14 @@
-use super::super::super::{cache::*, configuration::*, key::*, response::*};
+use super::super::super::{coding::*, configuration::*, response::*};
 
 use {
     http::*,
     http_body::*,
     kutil::{
-        http::transcoding::*,
+        http::{transcoding::*, *},
         std::{error::*, immutable::*},
-        transcoding::*,
+        transcoding::reader::*,
     },
+    std::sync::*,
 };
 
 //
@@ -17,67 +18,109 @@ use {
 /// To transcoding response.
 #[allow(async_fn_in_trait)]
 pub trait ToTranscodingResponse {
-    /// To a [Response] with a [TranscodingBody].
+    /// To a [Response] with a [TranscodingBody], together with the entry to store.
     ///
-    /// Will update the cache if we are modified.
+    /// Nothing is written to the cache. Instead, the entry to store is returned, so that the
+    /// caller can commit all of a request's changes with a single write (see
+    /// [PendingStore](super::super::store::PendingStore)):
     ///
-    /// If we encounter an error will return a response with [StatusCode::INTERNAL_SERVER_ERROR].
-    async fn to_transcoding_response<ResponseBodyT, CacheT, CacheKeyT>(
+    /// * If `is_new` then it is this entry, including any representation created for the
+    ///   response.
+    /// * Otherwise it is a clone with the representation created for the response, if one was,
+    ///   to be merged into the cached entry.
+    ///
+    /// If we encounter an error will return a response with [StatusCode::INTERNAL_SERVER_ERROR]
+    /// and nothing to store.
+    ///
+    /// `trailers` are emitted after the body. They are never stored.
+    async fn to_transcoding_response<ResponseBodyT>(
         self,
-        encoding: &Encoding,
+        coding: &CodingId,
         is_new: bool,
-        cache: CacheT,
-        key: CacheKeyT,
+        trailers: Vec<HeaderMap>,
         configuration: &EncodingConfiguration,
-    ) -> Response<TranscodingBody<ResponseBodyT>>
+    ) -> (
+        Response<TranscodingBody<ResponseBodyT>>,
+        Option<CachedResponseRef>,
+    )
     where
         ResponseBodyT: 'static + Body + From<ImmutableBytes> + Send + Unpin,
         ResponseBodyT::Data: From<ImmutableBytes> + Send,
-        ResponseBodyT::Error: Into<CapturedError>,
-        CacheT: Cache<CacheKeyT>,
-        CacheKeyT: CacheKey;
+        ResponseBodyT::Error: Into<CapturedError>;
 }
 
 impl ToTranscodingResponse for CachedResponseRef {
-    /// To a [Response] with a [TranscodingBody].
+    /// To a [Response] with a [TranscodingBody], together with the entry to store.
+    ///
+    /// Nothing is written to the cache. Instead, the entry to store is returned, so that the
+    /// caller can commit all of a request's changes with a single write (see
+    /// [PendingStore](super::super::store::PendingStore)):
+    ///
+    /// * If `is_new` then it is this entry, including any representation created for the
+    ///   response.
+    /// * Otherwise it is a clone with the representation created for the response, if one was,
+    ///   to be merged into the cached entry.
     ///
-    /// Will update the cache if we are modified.
+    /// If we encounter an error will return a response with [StatusCode::INTERNAL_SERVER_ERROR]
+    /// and nothing to store.
     ///
-    /// If we encounter an error will return a response with [StatusCode::INTERNAL_SERVER_ERROR].
-    async fn to_transcoding_response<ResponseBodyT, CacheT, CacheKeyT>(
+    /// `trailers` are emitted after the body. They are never stored.
+    async fn to_transcoding_response<ResponseBodyT>(
         self,
-        encoding: &Encoding,
+        coding: &CodingId,
         is_new: bool,
-        cache: CacheT,
-        key: CacheKeyT,
+        trailers: Vec<HeaderMap>,
         configuration: &EncodingConfiguration,
-    ) -> Response<TranscodingBody<ResponseBodyT>>
+    ) -> (
+        Response<TranscodingBody<ResponseBodyT>>,
+        Option<CachedResponseRef>,
+    )
     where
         ResponseBodyT: 'static + Body + From<ImmutableBytes> + Send + Unpin,
         ResponseBodyT::Data: From<ImmutableBytes>,
         ResponseBodyT::Error: Into<CapturedError>,
-        CacheT: Cache<CacheKeyT>,
-        CacheKeyT: CacheKey,
     {
-        match self.to_response(&encoding, configuration).await {
+        let response = if trailers.is_empty() {
+            self.to_response(coding, configuration).await
+        } else {
+            self.to_response::<ResponseBodyT>(coding, configuration)
+                .await
+                .map(|(response, modified)| {
+                    (
+                        response.map(|body| passthrough_with_trailers(body, trailers)),
+                        modified,
+                    )
+                })
+        };
+
+        match response {
             Ok((response, modified)) => {
+                let modified = modified.map(Arc::new);
                 if is_new {
-                    cache.put(key, self).await;
-                } else if let Some(modified) = modified {
-                    // A new CachedResponse should already contain our encoding
-                    // and thus never cause modification!
-                    assert!(!is_new);
-
-                    cache.put(key, modified.into()).await;
+                    (response, Some(modified.unwrap_or(self)))
+                } else {
+                    (response, modified)
                 }
-
-                response
             }
 
             Err(error) => {
-                tracing::error!("could not create response from cache: {} {}", key, error);
-                error_transcoding_response()
+                tracing::error!("could not create response from cache: {}", error);
+                (error_transcoding_response(), None)
             }
         }
     }
 }
+
+/// Passthrough [TranscodingBody] that emits trailers after the body.
+pub fn passthrough_with_trailers<BodyT>(
+    body: BodyT,
+    trailers: Vec<HeaderMap>,
+) -> TranscodingBody<BodyT>
+where
+    BodyT: Body,
+    BodyT::Error: Into<CapturedError>,
+{
+    let mut reader = body.into_reader();
+    reader.trailers = trailers;
+    TranscodingBody::new(reader.into_passthrough_reader())
+}
@@ -1,8 +1,14 @@
-use super::super::{configuration::*, hooks::*};
+use super::super::{
+    super::{cache_control::*, coding::*},
+    configuration::*,
+    hooks::*,
+    policy::*,
+    vary::*,
+};
 
 use {
     http::{header::*, *},
-    kutil::{http::*, transcoding::*},
+    kutil::http::*,
 };
 
 //
@@ -15,27 +21,35 @@ pub trait UpstreamResponse<ResponseBodyT> {
     ///
     /// Also returns the value of `Content-Length` if available.
     ///
+    /// If we respect `Cache-Control` then we also skip responses that a shared cache must not
+    /// store, and responses with a `Vary` on request headers that are not accounted for by the
+    /// cache key.
+    ///
     /// If the response passes all our checks then we turn to the hook to give it one last chance
     /// to skip the cache.
+    ///
+    /// The cacheable default and body size limits are those of the `overrides`.
     fn should_skip_cache<RequestBodyT, CacheT, CacheKeyT>(
         &self,
         uri: &Uri,
         configuration: &MiddlewareCachingConfiguration<CacheT, CacheKeyT, RequestBodyT>,
+        overrides: &RouteOverrides,
     ) -> (bool, Option<usize>);
 
     /// Validate encoding.
     ///
-    /// Checks `content_length`, if provided, against `min_body_size`. And gives the hook one last
-    /// chance to skip encoding.
+    /// Never encodes a response with `Cache-Control: no-transform` (if we respect it). Checks
+    /// `content_length`, if provided, against `min_body_size`. And gives the hook one last chance
+    /// to skip encoding.
     ///
     /// Will return true if we are forcing a skip.
     fn validate_encoding(
         &self,
         uri: &Uri,
-        encoding: Encoding,
+        coding: CodingId,
         content_length: Option<usize>,
         configuration: &MiddlewareEncodingConfiguration,
-    ) -> (Encoding, bool);
+    ) -> (CodingId, bool);
 }
 
 impl<ResponseBodyT> UpstreamResponse<ResponseBodyT> for Response<ResponseBodyT> {
@@ -43,14 +57,15 @@ impl<ResponseBodyT> UpstreamResponse<ResponseBodyT> for Response<ResponseBodyT>
         &self,
         uri: &Uri,
         configuration: &MiddlewareCachingConfiguration<CacheT, CacheKeyT, RequestBodyT>,
+        overrides: &RouteOverrides,
     ) -> (bool, Option<usize>) {
         let headers = self.headers();
         let status = self.status();
 
-        let mut skip_cache = if !headers.xx_cache(configuration.inner.cacheable_by_default) {
+        let mut skip_cache = if !headers.xx_cache(overrides.cacheable_by_default) {
             tracing::debug!("skip ({}=false)", XX_CACHE);
             (true, None)
-        } else if !status.is_success() {
+        } else if !configuration.inner.is_cacheable_status(status) {
             tracing::debug!("skip (status={})", status.as_u16());
             (true, None)
         } else if headers.contains_key(CONTENT_RANGE) {
@@ -59,10 +74,11 @@ impl<ResponseBodyT> UpstreamResponse<ResponseBodyT> for Response<ResponseBodyT>
         } else {
             match headers.content_length() {
                 Some(content_length) => {
-                    if content_length < configuration.inner.min_body_size {
+                    // Negative responses (e.g. redirects) often have empty bodies
+                    if content_length < overrides.min_body_size && status.is_success() {
                         tracing::debug!("skip (Content-Length too small)");
                         (true, Some(content_length))
-                    } else if content_length > configuration.inner.max_body_size {
+                    } else if content_length > overrides.max_body_size {
                         tracing::debug!("skip (Content-Length too big)");
                         (true, Some(content_length))
                     } else {
@@ -74,6 +90,29 @@ impl<ResponseBodyT> UpstreamResponse<ResponseBodyT> for Response<ResponseBodyT>
             }
         };
 
+        if !skip_cache.0 && configuration.vary_keys.is_some() && vary_names(headers, &[]).is_none() {
+            tracing::debug!("skip ({}: *)", VARY);
+            skip_cache.0 = true;
+        }
+
+        if !skip_cache.0 && configuration.inner.respect_cache_control {
+            // We key by negotiated encoding and language, and with vary keys also by the varied
+            // request headers, but not by other request headers
+            let keyed = VaryKeys::keyed(configuration.language_negotiation.is_some());
+
+            let cache_control = CacheControl::from_headers(headers);
+            if !cache_control.is_storable() {
+                tracing::debug!("skip ({})", CACHE_CONTROL);
+                skip_cache.0 = true;
+            } else if cache_control.is_stale_on_arrival() && headers.xx_cache_duration().is_none() {
+                tracing::debug!("skip ({} max-age=0)", CACHE_CONTROL);
+                skip_cache.0 = true;
+            } else if configuration.vary_keys.is_none() && !vary_is_keyed(headers, keyed) {
+                tracing::debug!("skip ({})", VARY);
+                skip_cache.0 = true;
+            }
+        }
+
         if !skip_cache.0
             && let Some(cacheable) = &configuration.cacheable_by_response
             && !cacheable(CacheableHookContext::new(uri, headers))
@@ -88,37 +127,36 @@ impl<ResponseBodyT> UpstreamResponse<ResponseBodyT> for Response<ResponseBodyT>
     fn validate_encoding(
         &self,
         uri: &Uri,
-        encoding: Encoding,
+        coding: CodingId,
         content_length: Option<usize>,
         configuration: &MiddlewareEncodingConfiguration,
-    ) -> (Encoding, bool) {
-        if encoding == Encoding::Identity {
-            (encoding, false)
+    ) -> (CodingId, bool) {
+        if coding.is_identity() {
+            (coding, false)
+        } else if configuration.inner.no_transform(self.headers()) {
+            // Not a skip: the entry itself will never be encoded
+            tracing::debug!("not encoding to {} (no-transform)", coding);
+            (CodingId::IDENTITY, false)
         } else {
             if let Some(content_length) = content_length {
                 let min_body_size = configuration.inner.min_body_size;
-                if min_body_size != 0 {
-                    if content_length < min_body_size {
-                        tracing::debug!("not encoding to {} (too small)", encoding);
-                        return (Encoding::Identity, true);
-                    }
+                if min_body_size != 0 && content_length < min_body_size {
+                    tracing::debug!("not encoding to {} (too small)", coding);
+                    return (CodingId::IDENTITY, true);
                 }
             }
 
             match &configuration.encodable_by_response {
                 Some(encodable) => {
-                    if encodable(EncodableHookContext::new(&encoding, uri, self.headers())) {
-                        (encoding, false)
+                    if encodable(EncodableHookContext::new(&coding, uri, self.headers())) {
+                        (coding, false)
                     } else {
-                        tracing::debug!(
-                            "not encoding to {} (encodable_by_response=false)",
-                            encoding
-                        );
-                        (Encoding::Identity, true)
+                        tracing::debug!("not encoding to {} (encodable_by_response=false)", coding);
+                        (CodingId::IDENTITY, true)
                     }
                 }
 
-                None => (encoding, false),
+                None => (coding, false),
             }
         }
     }
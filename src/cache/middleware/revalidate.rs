@@ -0,0 +1,169 @@
+use super::super::response::*;
+
+use {
+    kutil::std::collections::*,
+    std::{
+        fmt,
+        hash::*,
+        sync::{atomic::*, *},
+        time::*,
+    },
+};
+
+//
+// StaleWhileRevalidate
+//
+
+/// Serves expired entries while refreshing them in the background.
+///
+/// An entry that expired less than `window` ago is served immediately (with an `Age` beyond its
+/// freshness), and a background request to the upstream refreshes it through the normal miss
+/// path: conditionally if the entry has validators, storing the new entry or re-arming the old
+/// one. There is at most one refresh per key at a time. If the refresh fails then the stale entry
+/// is kept (and served until the window is over) and the failure is logged.
+///
+/// The cache must retain expired entries for at least the window. For Moka that means a grace
+/// period (`for_http_response_with_grace`) of at least the window, so that its TTL is the cache
+/// duration plus the window.
+///
+/// Background refreshes are spawned on the current Tokio runtime. Without one, stale entries are
+/// served but not refreshed.
+///
+/// Requires the `stale-while-revalidate` feature.
+///
+/// Cloning is cheap and clones share state.
+#[derive(Clone)]
+pub struct StaleWhileRevalidate {
+    /// How long after expiring an entry may be served.
+    pub window: Duration,
+
+    state: Arc<StaleWhileRevalidateState>,
+}
+
+impl StaleWhileRevalidate {
+    /// Constructor.
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            state: Default::default(),
+        }
+    }
+
+    /// Whether an entry is expired but may still be served.
+    pub fn usable(&self, cached_response: &CachedResponse, now: SystemTime) -> bool {
+        match cached_response.duration {
+            Some(duration) if !cached_response.validators_only => {
+                let age = now.duration_since(cached_response.created).unwrap_or_default();
+                age >= duration && age < duration.saturating_add(self.window)
+            }
+
+            _ => false,
+        }
+    }
+
+    /// Begin a refresh, unless one is already in flight for the key.
+    pub fn try_begin(&self, key: &impl Hash) -> Option<RevalidationGuard> {
+        self.state.served.fetch_add(1, Ordering::Relaxed);
+
+        let hash = self.state.hasher.hash_one(key);
+        if self.state.in_flight.lock().expect("lock").insert(hash) {
+            self.state.refreshes.fetch_add(1, Ordering::Relaxed);
+            Some(RevalidationGuard {
+                state: self.state.clone(),
+                hash,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Statistics.
+    pub fn stats(&self) -> StaleWhileRevalidateStats {
+        StaleWhileRevalidateStats {
+            served: self.state.served.load(Ordering::Relaxed),
+            refreshes: self.state.refreshes.load(Ordering::Relaxed),
+            failed: self.state.failed.load(Ordering::Relaxed),
+            in_flight: self.state.in_flight.lock().expect("lock").len(),
+        }
+    }
+}
+
+impl fmt::Debug for StaleWhileRevalidate {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("StaleWhileRevalidate")
+            .field("window", &self.window)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+//
+// RevalidationGuard
+//
+
+/// Background refresh of a [StaleWhileRevalidate] entry.
+///
+/// Another refresh for the key can begin once dropped.
+pub struct RevalidationGuard {
+    state: Arc<StaleWhileRevalidateState>,
+    hash: u64,
+}
+
+impl RevalidationGuard {
+    /// Record a failed refresh.
+    pub fn failed(&self) {
+        self.state.failed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for RevalidationGuard {
+    fn drop(&mut self) {
+        self.state.in_flight.lock().expect("lock").remove(&self.hash);
+    }
+}
+
+impl fmt::Debug for RevalidationGuard {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.debug_struct("RevalidationGuard").finish()
+    }
+}
+
+//
+// Revalidation
+//
+
+/// Request extension marking a [StaleWhileRevalidate] background refresh.
+///
+/// Such requests never get stale entries.
+#[derive(Clone, Copy, Debug)]
+pub struct Revalidation;
+
+//
+// StaleWhileRevalidateStats
+//
+
+/// [StaleWhileRevalidate] statistics.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StaleWhileRevalidateStats {
+    /// Stale entries served.
+    pub served: u64,
+
+    /// Background refreshes begun.
+    pub refreshes: u64,
+
+    /// Background refreshes that failed.
+    pub failed: u64,
+
+    /// Background refreshes in flight.
+    pub in_flight: usize,
+}
+
+#[derive(Default)]
+struct StaleWhileRevalidateState {
+    hasher: RandomState,
+    in_flight: Mutex<FastHashSet<u64>>,
+    served: AtomicU64,
+    refreshes: AtomicU64,
+    failed: AtomicU64,
+}
@@ -0,0 +1,331 @@
+use super::{super::hooks::*, immutable::*};
+
+use {
+    duration_str::*,
+    std::{
+        fmt,
+        sync::{atomic::*, *},
+        time::*,
+    },
+};
+
+/// Hook to receive [SloReport] for [EmitEvent].
+pub type SloHook = Arc<Box<dyn Fn(&SloReport) + Send + Sync>>;
+
+//
+// SloAction
+//
+
+/// Action for [HitRateSlo].
+pub trait SloAction
+where
+    Self: Send + Sync,
+{
+    /// Called when a window completes below target while not already in violation.
+    fn violated(&self, report: &SloReport);
+
+    /// Called when a window completes at or above target while in violation.
+    ///
+    /// The default implementation does nothing.
+    fn recovered(&self, _report: &SloReport) {}
+}
+
+//
+// EmitEvent
+//
+
+/// [SloAction] that emits a structured tracing event and optionally calls a hook.
+#[derive(Clone, Default)]
+pub struct EmitEvent {
+    /// Hook.
+    pub hook: Option<SloHook>,
+}
+
+impl EmitEvent {
+    /// Constructor.
+    pub fn new(hook: impl Fn(&SloReport) + 'static + Send + Sync) -> Self {
+        Self {
+            hook: Some(Arc::new(Box::new(hook))),
+        }
+    }
+}
+
+impl SloAction for EmitEvent {
+    fn violated(&self, report: &SloReport) {
+        tracing::warn!(
+            scope = %report.scope,
+            hit_rate = report.hit_rate,
+            target = report.target,
+            samples = report.samples,
+            "hit rate SLO violated: {}",
+            report
+        );
+
+        if let Some(hook) = &self.hook {
+            hook(report);
+        }
+    }
+
+    fn recovered(&self, report: &SloReport) {
+        tracing::info!(
+            scope = %report.scope,
+            hit_rate = report.hit_rate,
+            target = report.target,
+            samples = report.samples,
+            "hit rate SLO recovered: {}",
+            report
+        );
+
+        if let Some(hook) = &self.hook {
+            hook(report);
+        }
+    }
+}
+
+//
+// SloConfig
+//
+
+/// Configuration for [HitRateSlo].
+#[derive(Clone)]
+pub struct SloConfig {
+    /// Target hit rate (between 0.0 and 1.0).
+    pub target: f64,
+
+    /// Evaluation window.
+    pub window: Duration,
+
+    /// Windows with fewer requests (hits plus misses) than this are not evaluated, so that
+    /// trickle traffic won't cause false alarms.
+    pub min_samples: u64,
+
+    /// Windows that complete within this time after startup are not evaluated (the cache is
+    /// still warming up).
+    pub grace_after_start: Duration,
+
+    /// Action.
+    pub action: Arc<dyn SloAction>,
+
+    /// Clock (hook) for windows and the grace period, e.g. for tests. The system time if not
+    /// provided.
+    pub clock: Option<ClockHook>,
+}
+
+impl SloConfig {
+    /// Current time according to the clock hook, or the system time if not provided.
+    pub fn now(&self) -> SystemTime {
+        match &self.clock {
+            Some(clock) => clock(),
+            None => SystemTime::now(),
+        }
+    }
+}
+
+impl Default for SloConfig {
+    fn default() -> Self {
+        Self {
+            target: 0.8,
+            window: Duration::from_secs(60 * 10),
+            min_samples: 100,
+            grace_after_start: Duration::from_secs(60 * 10),
+            action: Arc::new(EmitEvent::default()),
+            clock: None,
+        }
+    }
+}
+
+impl fmt::Debug for SloConfig {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("SloConfig")
+            .field("target", &self.target)
+            .field("window", &self.window)
+            .field("min_samples", &self.min_samples)
+            .field("grace_after_start", &self.grace_after_start)
+            .finish()
+    }
+}
+
+//
+// HitRateSlo
+//
+
+/// Hit rate service level objective for the paths of a scope.
+///
+/// Hits and misses are counted per window. Evaluation happens only when a window completes, so
+/// the per-request overhead is an atomic increment. Only requests that looked up the cache are
+/// counted. A window that is not evaluated (because of the grace period or too few samples) is
+/// merged into the next one.
+///
+/// Both violations and recoveries are reported to the [SloAction], once per transition.
+///
+/// Cloning is cheap and clones share state.
+#[derive(Clone)]
+pub struct HitRateSlo {
+    /// Scope (for reports).
+    pub scope: Arc<str>,
+
+    /// Matcher.
+    pub matcher: PathMatcher,
+
+    /// Configuration.
+    pub configuration: SloConfig,
+
+    state: Arc<SloState>,
+}
+
+impl HitRateSlo {
+    /// Constructor.
+    pub fn new(scope: impl Into<Arc<str>>, matcher: PathMatcher, configuration: SloConfig) -> Self {
+        let now = configuration.now();
+        Self {
+            scope: scope.into(),
+            matcher,
+            configuration,
+            state: Arc::new(SloState {
+                started: now,
+                window_start: Mutex::new(now),
+                hits: Default::default(),
+                misses: Default::default(),
+                violated: Default::default(),
+                violations: Default::default(),
+            }),
+        }
+    }
+
+    /// Record a hit or a miss for a path.
+    pub fn record(&self, path: &str, hit: bool) {
+        if !self.matcher.matches(path) {
+            return;
+        }
+
+        if hit {
+            self.state.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.state.misses.fetch_add(1, Ordering::Relaxed);
+        }
+
+        // If another request is evaluating then we can skip
+        if let Ok(mut window_start) = self.state.window_start.try_lock() {
+            let now = self.configuration.now();
+            if elapsed(*window_start, now) >= self.configuration.window {
+                *window_start = now;
+                self.evaluate(now);
+            }
+        }
+    }
+
+    /// Whether we are currently in violation.
+    pub fn is_violated(&self) -> bool {
+        self.state.violated.load(Ordering::Relaxed)
+    }
+
+    /// Number of violations so far.
+    pub fn violations(&self) -> u64 {
+        self.state.violations.load(Ordering::Relaxed)
+    }
+
+    fn evaluate(&self, now: SystemTime) {
+        let hits = self.state.hits.load(Ordering::Relaxed);
+        let misses = self.state.misses.load(Ordering::Relaxed);
+        let samples = hits + misses;
+
+        // Keep counting into the next window
+        if elapsed(self.state.started, now) < self.configuration.grace_after_start
+            || samples < self.configuration.min_samples.max(1)
+        {
+            return;
+        }
+
+        // Requests recorded since we loaded belong to the next window
+        self.state.hits.fetch_sub(hits, Ordering::Relaxed);
+        self.state.misses.fetch_sub(misses, Ordering::Relaxed);
+
+        let report = SloReport {
+            scope: self.scope.clone(),
+            hit_rate: hits as f64 / samples as f64,
+            target: self.configuration.target,
+            samples,
+            window: self.configuration.window,
+            violated: false,
+        };
+
+        if report.hit_rate < report.target {
+            if !self.state.violated.swap(true, Ordering::Relaxed) {
+                self.state.violations.fetch_add(1, Ordering::Relaxed);
+                self.configuration.action.violated(&SloReport {
+                    violated: true,
+                    ..report
+                });
+            }
+        } else if self.state.violated.swap(false, Ordering::Relaxed) {
+            self.configuration.action.recovered(&report);
+        }
+    }
+}
+
+impl fmt::Debug for HitRateSlo {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("HitRateSlo")
+            .field("scope", &self.scope)
+            .field("matcher", &self.matcher)
+            .field("configuration", &self.configuration)
+            .field("violated", &self.is_violated())
+            .finish()
+    }
+}
+
+//
+// SloReport
+//
+
+/// [HitRateSlo] violation or recovery.
+#[derive(Clone, Debug)]
+pub struct SloReport {
+    /// Scope.
+    pub scope: Arc<str>,
+
+    /// Hit rate in the window.
+    pub hit_rate: f64,
+
+    /// Target hit rate.
+    pub target: f64,
+
+    /// Requests in the window.
+    pub samples: u64,
+
+    /// Window.
+    pub window: Duration,
+
+    /// True for a violation, false for a recovery.
+    pub violated: bool,
+}
+
+impl fmt::Display for SloReport {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            formatter,
+            "{} hit rate {:.1}% (target {:.1}%) over {} requests in {}",
+            self.scope,
+            self.hit_rate * 100.,
+            self.target * 100.,
+            self.samples,
+            self.window.human_format()
+        )
+    }
+}
+
+struct SloState {
+    started: SystemTime,
+    window_start: Mutex<SystemTime>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    violated: AtomicBool,
+    violations: AtomicU64,
+}
+
+// Zero if the clock went backwards.
+fn elapsed(since: SystemTime, now: SystemTime) -> Duration {
+    now.duration_since(since).unwrap_or_default()
+}
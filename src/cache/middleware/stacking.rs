@@ -0,0 +1,206 @@
+use super::{super::validators::*, trail::*};
+
+use {
+    http::*,
+    kutil::http::*,
+    std::{fmt, sync::*},
+};
+
+/// Separator between a control header name and the label of the layer that it targets, e.g.
+/// `XX-Cache--micro`.
+pub const XX_SCOPE_SEPARATOR: &str = "--";
+
+/// Label for control headers that target all stacked layers, e.g. `XX-Cache--*`.
+pub const XX_SCOPE_ALL: &str = "*";
+
+/// Control headers that can be scoped to a layer.
+pub static SCOPED_CONTROL_HEADERS: [HeaderName; 4] =
+    [XX_CACHE, XX_CACHE_DURATION, XX_ENCODE, XX_NO_SYNTHETIC_VALIDATORS];
+
+//
+// StackingRole
+//
+
+/// Role of a [CachingLayer](super::super::super::CachingLayer) in a stack of layers.
+///
+/// Two layers can be stacked intentionally, e.g. an outer micro-cache (a TTL of a second or two,
+/// absorbing stampedes) over an inner full-featured cache (long TTLs, encodings, a shared tier).
+/// Exactly one of them should encode and give entries synthetic validators, and the inner one
+/// should forward control headers to the outer one. Give each layer a
+/// [label](super::super::super::CachingLayer::label) in order to target control headers at it.
+///
+/// Control headers suffixed with [XX_SCOPE_SEPARATOR] and a label (e.g.
+/// `XX-Cache-Duration--micro`) apply only to the layer with that label. They take precedence over
+/// unsuffixed ones, which apply to all layers. A forwarding layer passes both the suffixed headers
+/// of other layers and its unsuffixed ones (as `XX-Cache--*` etc.) to the layers above it,
+/// including on hits, because they are stored with the entry. A layer that doesn't forward
+/// removes them all, so they never reach the client.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum StackingRole {
+    /// Not stacked.
+    #[default]
+    Standalone,
+
+    /// Outer layer of a stack.
+    ///
+    /// Does not encode: misses are stored and served in the coding in which the inner layer
+    /// produced them (hits for clients that don't accept that coding are still transcoded). Gives
+    /// no synthetic validators.
+    MicroCache,
+
+    /// Inner layer of a stack.
+    ///
+    /// Encodes and gives synthetic validators, and forwards control headers.
+    FullCache,
+
+    /// Custom division of labor.
+    Custom {
+        /// Whether to encode misses.
+        encoding: bool,
+
+        /// Whether to give entries synthetic validators.
+        synthetic_validators: bool,
+
+        /// Whether to forward control headers.
+        forward_control_headers: bool,
+    },
+}
+
+impl StackingRole {
+    /// Whether to encode misses.
+    pub fn encodes(&self) -> bool {
+        match self {
+            Self::Standalone | Self::FullCache => true,
+            Self::MicroCache => false,
+            Self::Custom { encoding, .. } => *encoding,
+        }
+    }
+
+    /// Whether to give entries synthetic validators.
+    pub fn synthetic_validators(&self) -> bool {
+        match self {
+            Self::Standalone | Self::FullCache => true,
+            Self::MicroCache => false,
+            Self::Custom {
+                synthetic_validators,
+                ..
+            } => *synthetic_validators,
+        }
+    }
+
+    /// Whether to forward control headers.
+    pub fn forwards_control_headers(&self) -> bool {
+        match self {
+            Self::Standalone | Self::MicroCache => false,
+            Self::FullCache => true,
+            Self::Custom {
+                forward_control_headers,
+                ..
+            } => *forward_control_headers,
+        }
+    }
+
+    /// Resolve the control headers for a layer.
+    ///
+    /// Afterwards the unsuffixed control headers are the ones that apply to the layer. See
+    /// [StackingRole].
+    pub fn resolve_control_headers(&self, headers: &mut HeaderMap, label: Option<&str>) {
+        let forward = self.forwards_control_headers();
+
+        // Most responses have no scoped control headers
+        if !forward && !headers.keys().any(is_scoped_control_header) {
+            return;
+        }
+
+        for name in &SCOPED_CONTROL_HEADERS {
+            let unscoped = headers.get(name).cloned();
+            let for_us = label
+                .and_then(|label| scoped_control_header(name, label))
+                .and_then(|scoped| headers.remove(scoped));
+            let for_all =
+                scoped_control_header(name, XX_SCOPE_ALL).and_then(|scoped| headers.get(scoped));
+
+            if let Some(value) = for_us.or_else(|| unscoped.clone()).or_else(|| for_all.cloned()) {
+                headers.insert(name, value);
+            }
+
+            if forward
+                && let Some(unscoped) = unscoped
+                && let Some(scoped) = scoped_control_header(name, XX_SCOPE_ALL)
+            {
+                headers.insert(scoped, unscoped);
+            }
+        }
+
+        if !forward {
+            let scoped: Vec<_> = headers
+                .keys()
+                .filter(|name| is_scoped_control_header(name))
+                .cloned()
+                .collect();
+            for name in scoped {
+                headers.remove(name);
+            }
+        }
+    }
+}
+
+/// Name of a control header scoped to a label, e.g. `XX-Cache--micro`.
+///
+/// Returns [None] if the label is not valid in a header name.
+pub fn scoped_control_header(name: &HeaderName, label: &str) -> Option<HeaderName> {
+    HeaderName::try_from(format!("{}{}{}", name, XX_SCOPE_SEPARATOR, label)).ok()
+}
+
+/// Whether a header name is a control header scoped to a label.
+pub fn is_scoped_control_header(name: &HeaderName) -> bool {
+    name.as_str()
+        .split_once(XX_SCOPE_SEPARATOR)
+        .is_some_and(|(name, _)| SCOPED_CONTROL_HEADERS.iter().any(|scoped| scoped == name))
+}
+
+//
+// LayerOutcome
+//
+
+/// Outcome of a labeled layer for a request.
+#[derive(Clone, Debug)]
+pub struct LayerOutcome {
+    /// Label.
+    pub label: Arc<str>,
+
+    /// Decisions.
+    pub decisions: Decisions,
+}
+
+impl LayerOutcome {
+    /// Whether the response was served from the layer's cache.
+    pub fn is_hit(&self) -> bool {
+        self.decisions
+            .iter()
+            .any(|decision| decision.starts_with("hit"))
+    }
+}
+
+impl fmt::Display for LayerOutcome {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{}: {}", self.label, self.decisions)
+    }
+}
+
+//
+// LayerOutcomes
+//
+
+/// Response extension with the outcomes of the
+/// [labeled](super::super::super::CachingLayer::label) layers that handled the request, innermost
+/// first.
+#[derive(Clone, Debug, Default)]
+pub struct LayerOutcomes(pub Vec<LayerOutcome>);
+
+impl LayerOutcomes {
+    /// The outcome of a layer.
+    pub fn get(&self, label: &str) -> Option<&LayerOutcome> {
+        self.0.iter().find(|outcome| *outcome.label == *label)
+    }
+}
@@ -0,0 +1,135 @@
+use super::super::{cache::*, key::*, self_test::*};
+
+use std::{
+    fmt,
+    sync::{atomic::*, *},
+};
+
+const UNVERIFIED: u8 = 0;
+const PASSED: u8 = 1;
+const FAILED: u8 = 2;
+
+//
+// CacheVerification
+//
+
+/// Verification of the cache on first use.
+///
+/// The first request runs [Cache::self_test] and the outcome is remembered, so checking it on the
+/// hot path afterwards is a single atomic load. You can also run it explicitly (e.g. at startup)
+/// via [verify](Self::verify).
+///
+/// Cloning is cheap and clones share state.
+#[derive(Clone, Debug)]
+pub struct CacheVerification {
+    /// Policy.
+    pub policy: CacheVerificationPolicy,
+
+    state: Arc<AtomicU8>,
+}
+
+impl CacheVerification {
+    /// Constructor.
+    pub fn new(policy: CacheVerificationPolicy) -> Self {
+        Self {
+            policy,
+            state: Default::default(),
+        }
+    }
+
+    /// Run the self-test now and remember the outcome.
+    pub async fn verify<CacheT, CacheKeyT>(
+        &self,
+        cache: &CacheT,
+    ) -> Result<SelfTestReport, SelfTestError>
+    where
+        CacheT: Cache<CacheKeyT>,
+        CacheKeyT: CacheKey,
+    {
+        let result = cache.self_test().await;
+
+        match &result {
+            Ok(report) => {
+                tracing::info!("cache self-test passed: {}", report);
+                self.state.store(PASSED, Ordering::Release);
+            }
+
+            Err(error) => {
+                tracing::error!("cache self-test failed ({}): {}", self.policy, error);
+                self.state.store(FAILED, Ordering::Release);
+            }
+        }
+
+        result
+    }
+
+    /// The verdict, running the self-test if it hasn't been run yet.
+    ///
+    /// Concurrent first requests might each run the self-test. That's harmless because entries
+    /// are unique per run.
+    pub async fn verdict<CacheT, CacheKeyT>(&self, cache: &CacheT) -> CacheVerdict
+    where
+        CacheT: Cache<CacheKeyT>,
+        CacheKeyT: CacheKey,
+    {
+        let passed = match self.state.load(Ordering::Acquire) {
+            UNVERIFIED => self.verify(cache).await.is_ok(),
+            state => state == PASSED,
+        };
+
+        if passed {
+            return CacheVerdict::Use;
+        }
+
+        match self.policy {
+            CacheVerificationPolicy::WarnOnly => CacheVerdict::Use,
+            CacheVerificationPolicy::RefuseToServe => CacheVerdict::Refuse,
+            CacheVerificationPolicy::Degrade => CacheVerdict::Bypass,
+        }
+    }
+}
+
+//
+// CacheVerificationPolicy
+//
+
+/// What to do if [CacheVerification] fails.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum CacheVerificationPolicy {
+    /// Log an error but keep using the cache.
+    #[default]
+    WarnOnly,
+
+    /// Fail requests with 500 (Internal Server Error).
+    RefuseToServe,
+
+    /// Bypass the cache (and encoding of stored entries) and serve straight from upstream.
+    Degrade,
+}
+
+impl fmt::Display for CacheVerificationPolicy {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::WarnOnly => write!(formatter, "warn only"),
+            Self::RefuseToServe => write!(formatter, "refusing to serve"),
+            Self::Degrade => write!(formatter, "degrading"),
+        }
+    }
+}
+
+//
+// CacheVerdict
+//
+
+/// Outcome of [CacheVerification::verdict].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CacheVerdict {
+    /// Use the cache.
+    Use,
+
+    /// Bypass the cache.
+    Bypass,
+
+    /// Refuse to serve.
+    Refuse,
+}
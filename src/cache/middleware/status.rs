@@ -0,0 +1,87 @@
+use super::trail::*;
+
+use {http::*, std::fmt};
+
+//
+// CacheStatus
+//
+
+/// Response extension with whether the response came from the cache.
+///
+/// It is inserted into all responses, including error and not-modified responses, so that outer
+/// layers and handlers (e.g. a tracing layer) can tell hits from misses. See also
+/// [expose_cache_status_header](super::super::super::CachingLayer::expose_cache_status_header).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CacheStatus {
+    /// Served from the cache.
+    Hit,
+
+    /// Served from the cache as a 304 (Not Modified).
+    HitNotModified,
+
+    /// Looked up but not served from the cache.
+    Miss,
+
+    /// The cache was not used.
+    Bypass {
+        /// Reason, e.g. "learned" or "quarantine".
+        reason: &'static str,
+    },
+}
+
+impl CacheStatus {
+    /// Status for a decision trail.
+    pub fn from_trail(trail: &DecisionTrail) -> Self {
+        if let Some(hit) = trail.decisions.iter().find(|decision| decision.starts_with("hit")) {
+            return if hit.contains("not modified") {
+                Self::HitNotModified
+            } else {
+                Self::Hit
+            };
+        }
+
+        if trail.looked_up || trail.decisions.iter().any(|decision| decision.starts_with("miss")) {
+            return Self::Miss;
+        }
+
+        // The first decision explains why we didn't look up, e.g. "skip (learned)"
+        let reason = match trail.decisions.iter().next() {
+            Some(decision) => match decision.split_once(" (") {
+                Some((_, reason)) => reason.trim_end_matches(')'),
+                None => decision,
+            },
+            None => "uncacheable",
+        };
+
+        Self::Bypass { reason }
+    }
+
+    /// Whether the response was served from the cache.
+    pub fn is_hit(&self) -> bool {
+        matches!(self, Self::Hit | Self::HitNotModified)
+    }
+
+    /// As a string: `HIT`, `MISS`, or `BYPASS`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Hit | Self::HitNotModified => "HIT",
+            Self::Miss => "MISS",
+            Self::Bypass { .. } => "BYPASS",
+        }
+    }
+
+    /// As a header value: `HIT`, `MISS`, or `BYPASS`.
+    pub fn header_value(&self) -> HeaderValue {
+        HeaderValue::from_static(self.as_str())
+    }
+}
+
+impl fmt::Display for CacheStatus {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::HitNotModified => write!(formatter, "HIT (not modified)"),
+            Self::Bypass { reason } => write!(formatter, "BYPASS ({})", reason),
+            _ => formatter.write_str(self.as_str()),
+        }
+    }
+}
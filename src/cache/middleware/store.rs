@@ -0,0 +1,358 @@
+use super::{
+    super::{cache::*, coding::*, configuration::*, key::*, response::*, weight::*},
+    hooks::*,
+};
+
+use std::{any::*, fmt, panic::*, sync::*, time::*};
+
+//
+// StorePathway
+//
+
+/// How a [StoreEvent] came about.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StorePathway {
+    /// A new entry stored for a miss.
+    Miss,
+
+    /// A new entry stored for a miss, with a body transformed before storing.
+    Transform,
+
+    /// An existing entry that gained a representation when reencoded for a hit.
+    Reencode,
+
+    /// An expired entry re-armed by a conditional refresh (`304 Not Modified`).
+    Refresh,
+
+    /// A validators-only entry stored for an oversized response.
+    ValidatorsOnly,
+
+    /// A new entry assembled from upstream ranges (requires the `range-assembly` feature).
+    Assembly,
+}
+
+impl fmt::Display for StorePathway {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(
+            match self {
+                Self::Miss => "miss",
+                Self::Transform => "transform",
+                Self::Reencode => "reencode",
+                Self::Refresh => "refresh",
+                Self::ValidatorsOnly => "validators only",
+                Self::Assembly => "assembly",
+            },
+            formatter,
+        )
+    }
+}
+
+//
+// StoreEvent
+//
+
+/// Context for [StoreHook].
+///
+/// Emitted once per successful store by the middleware. Stores that are rejected (e.g. because
+/// the key was invalidated meanwhile) emit nothing. Stores made directly via the cache, e.g. by a
+/// [ReencodeJob](super::super::reencode::ReencodeJob), are not reported.
+#[derive(Debug)]
+pub struct StoreEvent<'this, CacheKeyT> {
+    /// Cache key. Its [Display](fmt::Display) is the same as is used for logging.
+    pub key: &'this CacheKeyT,
+
+    /// Stored entry.
+    ///
+    /// For [Reencode](StorePathway::Reencode) this is the entry as we merged it, which could
+    /// differ from the cache's if it was modified concurrently.
+    pub cached_response: &'this CachedResponse,
+
+    /// Pathway.
+    pub pathway: StorePathway,
+}
+
+impl<'this, CacheKeyT> StoreEvent<'this, CacheKeyT>
+where
+    CacheKeyT: CacheKey,
+{
+    /// Constructor.
+    pub fn new(
+        key: &'this CacheKeyT,
+        cached_response: &'this CachedResponse,
+        pathway: StorePathway,
+    ) -> Self {
+        Self {
+            key,
+            cached_response,
+            pathway,
+        }
+    }
+
+    /// Resolved cache duration.
+    pub fn duration(&self) -> Option<Duration> {
+        self.cached_response.duration
+    }
+
+    /// Weight (including the key).
+    pub fn weight(&self) -> usize {
+        self.key.cache_weight() + self.cached_response.cache_weight()
+    }
+
+    /// Stored encodings.
+    pub fn encodings(&self) -> Vec<CodingId> {
+        self.cached_response
+            .body
+            .representations
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    /// Creation time.
+    pub fn created(&self) -> SystemTime {
+        self.cached_response.created
+    }
+
+    /// Whether the entry has validators only.
+    pub fn validators_only(&self) -> bool {
+        self.cached_response.validators_only
+    }
+}
+
+//
+// PendingStore
+//
+
+/// Cache write accumulated while handling a request.
+///
+/// A request commits at most one, after its response has been constructed from the same
+/// in-memory entry. Thus a remote cache sees one write per logical event, and concurrent readers
+/// never see intermediate states. (Background work, such as a
+/// [ReencodeJob](super::super::reencode::ReencodeJob), writes separately.)
+#[derive(Debug)]
+pub enum PendingStore<CacheKeyT> {
+    /// Store an entry.
+    Put {
+        /// Cache key.
+        key: CacheKeyT,
+
+        /// Fence captured before reading.
+        fence: Fence,
+
+        /// Entry.
+        cached_response: CachedResponseRef,
+
+        /// Pathway.
+        pathway: StorePathway,
+    },
+
+    /// Merge new representations into the cached entry.
+    Merge {
+        /// Cache key.
+        key: CacheKeyT,
+
+        /// Entry with the new representations.
+        cached_response: CachedResponseRef,
+    },
+}
+
+impl<CacheKeyT> PendingStore<CacheKeyT>
+where
+    CacheKeyT: CacheKey,
+{
+    /// Pathway.
+    pub fn pathway(&self) -> StorePathway {
+        match self {
+            Self::Put { pathway, .. } => *pathway,
+            Self::Merge { .. } => StorePathway::Reencode,
+        }
+    }
+
+    /// Cache key.
+    pub fn key(&self) -> &CacheKeyT {
+        match self {
+            Self::Put { key, .. } | Self::Merge { key, .. } => key,
+        }
+    }
+
+    /// Entry.
+    pub fn cached_response(&self) -> &CachedResponseRef {
+        match self {
+            Self::Put {
+                cached_response, ..
+            }
+            | Self::Merge {
+                cached_response, ..
+            } => cached_response,
+        }
+    }
+
+    /// Commit.
+    ///
+    /// A put is rejected if the key was invalidated since the fence was captured. A merge is
+    /// skipped if the entry is no longer cached, and otherwise keeps representations added
+    /// concurrently, pruning disabled ones and ones beyond the maximum if so configured.
+    ///
+    /// `notifier` is notified of successful stores and merges.
+    pub async fn commit<CacheT>(
+        self,
+        cache: &CacheT,
+        configuration: &EncodingConfiguration,
+        notifier: &mut StoreNotifier<'_, CacheKeyT>,
+    ) where
+        CacheT: Cache<CacheKeyT>,
+    {
+        match self {
+            Self::Put {
+                key,
+                fence,
+                cached_response,
+                pathway,
+            } => {
+                if cache
+                    .put_fenced(key.clone(), cached_response.clone(), fence)
+                    .await
+                    == PutOutcome::Stored
+                {
+                    notifier.notify(&key, &cached_response, pathway);
+                }
+            }
+
+            Self::Merge {
+                key,
+                cached_response,
+            } => {
+                let mut merged = None;
+                if cache
+                    .update(key.clone(), |current| {
+                        // The requested coding is the one that the current entry doesn't have
+                        let requested = cached_response
+                            .body
+                            .representations
+                            .keys()
+                            .find(|coding| !current.body.representations.contains_key(coding))
+                            .cloned();
+
+                        let replacement = current.with_representations_from(&cached_response).map(
+                            |mut replacement| {
+                                replacement.prune_representations(configuration);
+                                if let Some(requested) = &requested {
+                                    replacement
+                                        .body
+                                        .limit_representations(requested, configuration);
+                                }
+                                Arc::new(replacement)
+                            },
+                        );
+                        merged = replacement.clone();
+                        replacement
+                    })
+                    .await
+                    && let Some(merged) = merged
+                {
+                    notifier.notify(&key, &merged, StorePathway::Reencode);
+                }
+            }
+        }
+    }
+}
+
+//
+// StoreNotifier
+//
+
+/// Calls the [StoreHook], if configured, and accounts for its latency.
+///
+/// The hook is called synchronously, on the request's task, so it should be cheap (e.g. sending
+/// to a channel). Panics in the hook are caught and logged, and do not fail the request.
+pub struct StoreNotifier<'this, CacheKeyT> {
+    /// Hook.
+    pub hook: Option<&'this StoreHook<CacheKeyT>>,
+
+    /// Pathway for new entries.
+    pub pathway: StorePathway,
+
+    /// How keys are rendered in logs.
+    pub key_log_policy: &'this KeyLogPolicy,
+
+    /// Time spent in the hook.
+    pub elapsed: Duration,
+
+    /// Whether anything was stored.
+    pub stored: bool,
+}
+
+impl<'this, CacheKeyT> StoreNotifier<'this, CacheKeyT>
+where
+    CacheKeyT: CacheKey,
+{
+    /// Constructor.
+    pub fn new(
+        hook: Option<&'this StoreHook<CacheKeyT>>,
+        pathway: StorePathway,
+        key_log_policy: &'this KeyLogPolicy,
+    ) -> Self {
+        Self {
+            hook,
+            pathway,
+            key_log_policy,
+            elapsed: Default::default(),
+            stored: false,
+        }
+    }
+
+    /// Notify.
+    pub fn notify(
+        &mut self,
+        key: &CacheKeyT,
+        cached_response: &CachedResponse,
+        pathway: StorePathway,
+    ) {
+        self.stored = true;
+
+        if let Some(hook) = self.hook {
+            let start = Instant::now();
+
+            if let Err(panic) = catch_unwind(AssertUnwindSafe(|| {
+                hook(StoreEvent::new(key, cached_response, pathway))
+            })) {
+                tracing::error!(
+                    "store hook panicked: {} ({}): {}",
+                    key.display_for_logs(self.key_log_policy),
+                    pathway,
+                    panic_message(&*panic)
+                );
+            }
+
+            self.elapsed += start.elapsed();
+        }
+    }
+
+    /// Notify for a new entry.
+    pub fn notify_new(&mut self, key: &CacheKeyT, cached_response: &CachedResponse) {
+        self.notify(key, cached_response, self.pathway);
+    }
+}
+
+impl<'this, CacheKeyT> fmt::Debug for StoreNotifier<'this, CacheKeyT> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("StoreNotifier")
+            .field("hook", &self.hook.is_some())
+            .field("pathway", &self.pathway)
+            .field("elapsed", &self.elapsed)
+            .field("stored", &self.stored)
+            .finish()
+    }
+}
+
+// Panic payloads are usually strings.
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown"
+    }
+}
@@ -0,0 +1,73 @@
+use {
+    http::{request::*, uri::*, *},
+    std::fmt,
+};
+
+//
+// RequestTargetForm
+//
+
+/// Form of a request target.
+///
+/// See [IETF RFC 9112 section 3.2](https://datatracker.ietf.org/doc/html/rfc9112#section-3.2).
+///
+/// Only origin-form targets (e.g. `/path?query`) are cached by default. Note that HTTP/2 and
+/// HTTP/3 requests always carry the scheme and authority (as pseudo-headers), so for them an
+/// absolute URI is considered origin-form.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum RequestTargetForm {
+    /// Path and optional query, e.g. `/path?query`.
+    Origin,
+
+    /// Absolute URI, e.g. `http://example.com/path`, as sent to proxies.
+    Absolute,
+
+    /// Authority only, e.g. `example.com:443`, as sent with `CONNECT`.
+    Authority,
+
+    /// `*`, as sent with server-wide `OPTIONS`.
+    Asterisk,
+}
+
+impl RequestTargetForm {
+    /// The form of a request's target.
+    pub fn of<RequestBodyT>(request: &Request<RequestBodyT>) -> Self {
+        let uri = request.uri();
+        if uri.scheme().is_none() {
+            if uri.path() == "*" {
+                Self::Asterisk
+            } else if uri.authority().is_some() || uri.path_and_query().is_none() {
+                Self::Authority
+            } else {
+                Self::Origin
+            }
+        } else if request.version() < Version::HTTP_2 {
+            Self::Absolute
+        } else {
+            Self::Origin
+        }
+    }
+}
+
+impl fmt::Display for RequestTargetForm {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(
+            match self {
+                Self::Origin => "origin-form",
+                Self::Absolute => "absolute-form",
+                Self::Authority => "authority-form",
+                Self::Asterisk => "asterisk-form",
+            },
+            formatter,
+        )
+    }
+}
+
+/// A URI reduced to origin-form (path and query).
+///
+/// Without a path and query it is `/`.
+pub fn origin_form(uri: &Uri) -> Uri {
+    uri.path_and_query()
+        .map(|path_and_query| Uri::from(path_and_query.clone()))
+        .unwrap_or_else(|| Uri::from_static("/"))
+}
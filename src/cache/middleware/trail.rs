@@ -0,0 +1,170 @@
+use {
+    duration_str::*,
+    http::*,
+    std::{fmt, time::*},
+};
+
+//
+// DecisionTrail
+//
+
+/// Summary of the caching decisions and timing breakdown for a single request.
+///
+/// Used for logging slow requests. Recording is just a few [Instant] deltas, so the overhead is
+/// negligible.
+#[derive(Clone, Debug, Default)]
+pub struct DecisionTrail {
+    /// Decisions in the order in which they were made.
+    pub decisions: Decisions,
+
+    /// Whether the cache was looked up.
+    pub looked_up: bool,
+
+    /// Time spent looking up the cache.
+    pub lookup: Duration,
+
+    /// Time spent queued for the resource's upstream concurrency limit.
+    pub queued: Duration,
+
+    /// Time spent waiting for the upstream response.
+    pub upstream: Duration,
+
+    /// Time spent reading the upstream body (including its initial encoding) when storing.
+    pub body_read: Duration,
+
+    /// Time spent creating the response from the cache entry (including reencoding).
+    pub transcode: Duration,
+
+    /// Time spent writing to the cache (excluding the store hook).
+    pub store: Duration,
+
+    /// Time spent processing headers of responses that are not created from the cache entry
+    /// (e.g. pass-through responses), and adding headers to served responses.
+    pub headers_processing: Duration,
+
+    /// Time spent in the store hook.
+    pub store_hook: Duration,
+}
+
+impl DecisionTrail {
+    /// Record a decision.
+    pub fn decide(&mut self, decision: &'static str) {
+        self.decisions.push(decision);
+    }
+
+    /// Whether the response was served from the cache.
+    pub fn is_hit(&self) -> bool {
+        self.decisions
+            .iter()
+            .any(|decision| decision.starts_with("hit"))
+    }
+
+    /// The phase that took the most time.
+    pub fn dominant_phase(&self) -> &'static str {
+        [
+            ("lookup", self.lookup),
+            ("queued", self.queued),
+            ("upstream", self.upstream),
+            ("body_read", self.body_read),
+            ("transcode", self.transcode),
+            ("store", self.store),
+            ("headers_processing", self.headers_processing),
+            ("store_hook", self.store_hook),
+        ]
+        .into_iter()
+        .max_by_key(|(_, duration)| *duration)
+        .map(|(phase, _)| phase)
+        .unwrap_or_default()
+    }
+
+    /// Log at info level if `elapsed` is over the threshold.
+    pub fn log_if_slow(&self, uri: &Uri, elapsed: Duration, threshold: Duration) {
+        if elapsed > threshold {
+            tracing::info!(
+                uri = %uri,
+                elapsed = %elapsed.human_format(),
+                dominant = self.dominant_phase(),
+                "slow: {}",
+                self
+            );
+        }
+    }
+}
+
+impl fmt::Display for DecisionTrail {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            formatter,
+            "{} (lookup={}, queued={}, upstream={}, body_read={}, transcode={}, \
+             store={}, headers_processing={}, store_hook={})",
+            self.decisions,
+            self.lookup.human_format(),
+            self.queued.human_format(),
+            self.upstream.human_format(),
+            self.body_read.human_format(),
+            self.transcode.human_format(),
+            self.store.human_format(),
+            self.headers_processing.human_format(),
+            self.store_hook.human_format()
+        )
+    }
+}
+
+//
+// Decisions
+//
+
+/// Decisions of a [DecisionTrail].
+///
+/// The first few are stored inline, so that recording them does not allocate. A request rarely
+/// makes more than that.
+#[derive(Clone, Debug, Default)]
+pub struct Decisions {
+    inline: [&'static str; INLINE_DECISIONS],
+    inline_len: usize,
+    overflow: Vec<&'static str>,
+}
+
+impl Decisions {
+    /// Add a decision.
+    pub fn push(&mut self, decision: &'static str) {
+        if self.inline_len < INLINE_DECISIONS {
+            self.inline[self.inline_len] = decision;
+            self.inline_len += 1;
+        } else {
+            self.overflow.push(decision);
+        }
+    }
+
+    /// Number of decisions.
+    pub fn len(&self) -> usize {
+        self.inline_len + self.overflow.len()
+    }
+
+    /// Whether there are no decisions.
+    pub fn is_empty(&self) -> bool {
+        self.inline_len == 0
+    }
+
+    /// Iterate the decisions in the order in which they were made.
+    pub fn iter(&self) -> impl Iterator<Item = &'static str> {
+        self.inline[..self.inline_len]
+            .iter()
+            .chain(self.overflow.iter())
+            .copied()
+    }
+}
+
+impl fmt::Display for Decisions {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        for (index, decision) in self.iter().enumerate() {
+            if index != 0 {
+                formatter.write_str(", ")?;
+            }
+            formatter.write_str(decision)?;
+        }
+        Ok(())
+    }
+}
+
+const INLINE_DECISIONS: usize = 8;
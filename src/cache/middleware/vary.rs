@@ -0,0 +1,128 @@
+use super::super::{cache_control::*, key::*};
+
+use {
+    http::header::*,
+    kutil::std::collections::*,
+    std::{fmt, hash::*, sync::*},
+};
+
+/// Default maximum number of tracked keys for [VaryKeys].
+pub const DEFAULT_VARY_KEYS_CAPACITY: usize = 10 * 1024;
+
+//
+// VaryKeys
+//
+
+/// Cache keys that account for the request headers named by the response `Vary` header.
+///
+/// Without it, content negotiation for headers other than `Accept-Encoding` (and
+/// `Accept-Language` if we negotiate languages) has to be handled in the cache key hook. With
+/// it, when a response varies on a request header then the header's value becomes part of the
+/// key (see [CacheKey::set_varied_header]), so that e.g. an English page won't be served to a
+/// client that accepts only Chinese. Each combination of values gets its own entry.
+///
+/// Because we only learn of `Vary` from responses, the varied header names are remembered per
+/// key (by hash, in a bounded table). Lookups for a key with remembered names use the variant
+/// key. A hit on an entry with names that we didn't use (e.g. because the table was full, or the
+/// upstream started varying) is treated as a miss, and its response is stored under the variant
+/// key.
+///
+/// Header values are used as is, so clients that express the same preference differently get
+/// separate entries. Responses with `Vary: *` are not cached.
+///
+/// Cloning is cheap and clones share state.
+#[derive(Clone)]
+pub struct VaryKeys {
+    /// Maximum number of tracked keys.
+    pub capacity: usize,
+
+    state: Arc<VaryKeysState>,
+}
+
+impl VaryKeys {
+    /// Constructor.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Default::default(),
+        }
+    }
+
+    /// The remembered varied header names for a key.
+    pub fn names(&self, key: &impl Hash) -> Option<Arc<[HeaderName]>> {
+        let hash = self.state.hasher.hash_one(key);
+        self.state.names.lock().expect("lock").get(&hash).cloned()
+    }
+
+    /// Remember the varied header names for a key.
+    ///
+    /// Empty names are forgotten.
+    pub fn remember(&self, key: &impl Hash, names: Arc<[HeaderName]>) {
+        let hash = self.state.hasher.hash_one(key);
+        let mut keys = self.state.names.lock().expect("lock");
+
+        if names.is_empty() {
+            keys.remove(&hash);
+        } else if keys.contains_key(&hash) || keys.len() < self.capacity {
+            tracing::debug!("vary: {:?}", names);
+            keys.insert(hash, names);
+        }
+    }
+
+    /// The varied header names of a response, excluding those in `keyed`.
+    ///
+    /// Returns [None] for `Vary: *`.
+    pub fn response_names(
+        headers: &HeaderMap,
+        keyed: &[HeaderName],
+    ) -> Option<Arc<[HeaderName]>> {
+        vary_names(headers, keyed).map(|names| names.into())
+    }
+
+    /// The variant of a key for the request headers.
+    pub fn variant<CacheKeyT>(
+        key: &CacheKeyT,
+        names: &[HeaderName],
+        headers: &HeaderMap,
+    ) -> CacheKeyT
+    where
+        CacheKeyT: CacheKey,
+    {
+        let mut variant = key.clone();
+        for name in names {
+            variant.set_varied_header(name, headers.get(name));
+        }
+        variant
+    }
+
+    /// Request headers that are already accounted for by the key.
+    pub fn keyed(language_negotiation: bool) -> &'static [HeaderName] {
+        if language_negotiation {
+            &[ACCEPT_ENCODING, ACCEPT_LANGUAGE]
+        } else {
+            &[ACCEPT_ENCODING]
+        }
+    }
+}
+
+impl Default for VaryKeys {
+    fn default() -> Self {
+        Self::new(DEFAULT_VARY_KEYS_CAPACITY)
+    }
+}
+
+impl fmt::Debug for VaryKeys {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("VaryKeys")
+            .field("capacity", &self.capacity)
+            .field("tracked", &self.state.names.lock().expect("lock").len())
+            .finish()
+    }
+}
+
+#[derive(Default)]
+struct VaryKeysState {
+    hasher: RandomState,
+    names: Mutex<FastHashMap<u64, Arc<[HeaderName]>>>,
+}
@@ -0,0 +1,85 @@
+use {http::*, std::fmt};
+
+//
+// WarmOutcome
+//
+
+/// Outcome of warming the cache with a request.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum WarmOutcome {
+    /// The response was stored.
+    Stored,
+
+    /// There already was an entry.
+    Cached,
+
+    /// The response was not stored.
+    Skipped {
+        /// Reason, e.g. "uncacheable".
+        reason: &'static str,
+    },
+
+    /// The inner service failed.
+    Error(String),
+}
+
+impl fmt::Display for WarmOutcome {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Stored => formatter.write_str("stored"),
+            Self::Cached => formatter.write_str("cached"),
+            Self::Skipped { reason } => write!(formatter, "skipped ({})", reason),
+            Self::Error(error) => write!(formatter, "error: {}", error),
+        }
+    }
+}
+
+//
+// WarmReport
+//
+
+/// Report of [warm](super::super::super::CachingService::warm).
+#[derive(Clone, Debug, Default)]
+pub struct WarmReport {
+    /// Outcomes in request order.
+    pub outcomes: Vec<(Uri, WarmOutcome)>,
+}
+
+impl WarmReport {
+    /// Number of stored responses.
+    pub fn stored(&self) -> usize {
+        self.count(|outcome| *outcome == WarmOutcome::Stored)
+    }
+
+    /// Number of requests that had entries.
+    pub fn cached(&self) -> usize {
+        self.count(|outcome| *outcome == WarmOutcome::Cached)
+    }
+
+    /// Number of skipped responses.
+    pub fn skipped(&self) -> usize {
+        self.count(|outcome| matches!(outcome, WarmOutcome::Skipped { .. }))
+    }
+
+    /// Number of errors.
+    pub fn errors(&self) -> usize {
+        self.count(|outcome| matches!(outcome, WarmOutcome::Error(_)))
+    }
+
+    fn count(&self, predicate: impl Fn(&WarmOutcome) -> bool) -> usize {
+        self.outcomes.iter().filter(|(_, outcome)| predicate(outcome)).count()
+    }
+}
+
+impl fmt::Display for WarmReport {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            formatter,
+            "stored: {}, cached: {}, skipped: {}, errors: {}",
+            self.stored(),
+            self.cached(),
+            self.skipped(),
+            self.errors()
+        )
+    }
+}
@@ -0,0 +1,306 @@
+use super::super::{cache::*, key::*, response::*, weight::*};
+
+use std::{
+    fmt,
+    hash::*,
+    sync::{atomic::*, *},
+};
+
+/// Default maximum number of keys in the [WeightAudit] reservoir.
+pub const DEFAULT_WEIGHT_AUDIT_RESERVOIR: usize = 1024;
+
+/// Default estimate-to-actual ratio beyond which [WeightAudit] flags an entry (in either
+/// direction).
+pub const DEFAULT_WEIGHT_DIVERGENCE: f64 = 2.0;
+
+/// Factor within which the declared weights of realistic entries are expected to be of their
+/// actual sizes.
+///
+/// Declared weights include in-memory overhead (e.g. of header map entries) that the serialized
+/// form doesn't have, so they are expected to be larger, especially for entries with many headers
+/// and small bodies.
+pub const WEIGHT_ESTIMATE_FACTOR: f64 = 4.0;
+
+//
+// WeightAudit
+//
+
+/// Audit of [CacheWeight] estimates.
+///
+/// Capacity limits (e.g. Moka's `max_capacity`) are only as good as the declared weights of
+/// entries. This audit compares the declared [cache_weight](CacheWeight::cache_weight) of sampled
+/// entries with their actual [serialized](CachedResponse::serialize) size, as a proxy for their
+/// storage cost, and flags entries where the two diverge.
+///
+/// Sampling never scans the cache. The middleware feeds stored keys into a bounded reservoir (a
+/// uniform sample of all stored keys), and [sample](Self::sample) gets just a few of them, so it
+/// is cheap enough to run periodically in production. Keys of entries that have since been
+/// evicted are counted as missing.
+///
+/// Cloning is cheap and clones share state.
+#[derive(Debug)]
+pub struct WeightAudit<CacheKeyT = CommonCacheKey> {
+    /// Estimate-to-actual ratio beyond which an entry is flagged (in either direction).
+    pub divergence: f64,
+
+    state: Arc<WeightAuditState<CacheKeyT>>,
+}
+
+impl<CacheKeyT> WeightAudit<CacheKeyT> {
+    /// Constructor.
+    pub fn new() -> Self {
+        Self::new_with_capacity(DEFAULT_WEIGHT_AUDIT_RESERVOIR)
+    }
+
+    /// Constructor.
+    ///
+    /// `capacity` is the maximum number of keys in the reservoir.
+    pub fn new_with_capacity(capacity: usize) -> Self {
+        Self {
+            divergence: DEFAULT_WEIGHT_DIVERGENCE,
+            state: Arc::new(WeightAuditState {
+                reservoir: Mutex::new(Reservoir {
+                    keys: Vec::with_capacity(capacity),
+                    capacity,
+                }),
+                seen: Default::default(),
+                last_report: Default::default(),
+                hasher: Default::default(),
+            }),
+        }
+    }
+
+    /// Set divergence.
+    pub fn with_divergence(mut self, divergence: f64) -> Self {
+        self.divergence = divergence.max(1.0);
+        self
+    }
+
+    /// The report of the last [sample](Self::sample), if there was one.
+    pub fn last_report(&self) -> Option<WeightAuditReport> {
+        self.state.last_report.lock().expect("lock").clone()
+    }
+}
+
+impl<CacheKeyT> WeightAudit<CacheKeyT>
+where
+    CacheKeyT: CacheKey,
+{
+    /// Record a stored key.
+    pub fn record(&self, key: &CacheKeyT) {
+        // Algorithm R: the nth key replaces a random key with probability capacity/n
+        let seen = self.state.seen.fetch_add(1, Ordering::Relaxed);
+        let mut reservoir = self.state.reservoir.lock().expect("lock");
+        if reservoir.keys.len() < reservoir.capacity {
+            reservoir.keys.push(key.clone());
+        } else {
+            let index = (self.state.hasher.hash_one(seen) % (seen + 1)) as usize;
+            if index < reservoir.keys.len() {
+                reservoir.keys[index] = key.clone();
+            }
+        }
+    }
+
+    /// Sample up to `n` entries and audit them.
+    ///
+    /// The report is also kept as the [last_report](Self::last_report).
+    pub async fn sample<CacheT>(&self, cache: &CacheT, n: usize) -> WeightAuditReport
+    where
+        CacheT: Cache<CacheKeyT>,
+    {
+        let keys: Vec<_> = {
+            let reservoir = self.state.reservoir.lock().expect("lock");
+            let len = reservoir.keys.len();
+            let start = match len {
+                0 => 0,
+                _ => (self.state.hasher.hash_one(self.state.seen.load(Ordering::Relaxed))
+                    % len as u64) as usize,
+            };
+
+            // A random window of the reservoir is itself a random sample
+            reservoir.keys.iter().cycle().skip(start).take(n.min(len)).cloned().collect()
+        };
+
+        let mut samples = Vec::with_capacity(keys.len());
+        let mut missing = 0;
+        for key in keys {
+            match cache.get(&key).await {
+                Some(cached_response) => {
+                    samples.push(WeightSample::new(key.to_string(), &cached_response))
+                }
+                None => missing += 1,
+            }
+        }
+
+        let report = WeightAuditReport::new(samples, missing, self.divergence);
+        tracing::debug!("weight audit: {}", report);
+        *self.state.last_report.lock().expect("lock") = Some(report.clone());
+        report
+    }
+}
+
+impl<CacheKeyT> Clone for WeightAudit<CacheKeyT> {
+    fn clone(&self) -> Self {
+        Self {
+            divergence: self.divergence,
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<CacheKeyT> Default for WeightAudit<CacheKeyT> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+//
+// WeightSample
+//
+
+/// Audited entry.
+#[derive(Clone, Debug)]
+pub struct WeightSample {
+    /// Key.
+    pub key: String,
+
+    /// Declared [cache_weight](CacheWeight::cache_weight).
+    pub declared: usize,
+
+    /// [Serialized](CachedResponse::serialize) size.
+    pub actual: usize,
+}
+
+impl WeightSample {
+    /// Constructor.
+    pub fn new(key: String, cached_response: &CachedResponse) -> Self {
+        Self {
+            key,
+            declared: cached_response.cache_weight(),
+            actual: cached_response.serialize().len(),
+        }
+    }
+
+    /// Estimate-to-actual ratio.
+    pub fn ratio(&self) -> f64 {
+        self.declared as f64 / self.actual.max(1) as f64
+    }
+
+    /// Whether the ratio is beyond the divergence (in either direction).
+    pub fn diverges(&self, divergence: f64) -> bool {
+        let ratio = self.ratio();
+        ratio > divergence || ratio * divergence < 1.0
+    }
+}
+
+impl fmt::Display for WeightSample {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            formatter,
+            "{}: declared {} bytes, actual {} bytes ({:.2})",
+            self.key,
+            self.declared,
+            self.actual,
+            self.ratio()
+        )
+    }
+}
+
+//
+// WeightAuditReport
+//
+
+/// [WeightAudit] report.
+///
+/// Ratios are of the declared weight to the actual size, so a ratio below 1 means that the
+/// weigher undercounts.
+#[derive(Clone, Debug, Default)]
+pub struct WeightAuditReport {
+    /// Number of audited entries.
+    pub sampled: usize,
+
+    /// Number of sampled keys that were no longer in the cache.
+    pub missing: usize,
+
+    /// Minimum ratio.
+    pub min_ratio: f64,
+
+    /// Median ratio.
+    pub median_ratio: f64,
+
+    /// Maximum ratio.
+    pub max_ratio: f64,
+
+    /// Ratio of the sums, i.e. of the declared weight of all audited entries to their actual
+    /// size.
+    pub total_ratio: f64,
+
+    /// Entries with ratios beyond the divergence.
+    pub divergent: Vec<WeightSample>,
+}
+
+impl WeightAuditReport {
+    /// Constructor.
+    pub fn new(samples: Vec<WeightSample>, missing: usize, divergence: f64) -> Self {
+        if samples.is_empty() {
+            return Self {
+                missing,
+                ..Default::default()
+            };
+        }
+
+        let mut ratios: Vec<_> = samples.iter().map(WeightSample::ratio).collect();
+        ratios.sort_by(f64::total_cmp);
+
+        let middle = ratios.len() / 2;
+        let median_ratio = if ratios.len() % 2 == 0 {
+            (ratios[middle - 1] + ratios[middle]) / 2.0
+        } else {
+            ratios[middle]
+        };
+
+        let declared: usize = samples.iter().map(|sample| sample.declared).sum();
+        let actual: usize = samples.iter().map(|sample| sample.actual).sum();
+
+        Self {
+            sampled: samples.len(),
+            missing,
+            min_ratio: ratios[0],
+            median_ratio,
+            max_ratio: ratios[ratios.len() - 1],
+            total_ratio: declared as f64 / actual.max(1) as f64,
+            divergent: samples.into_iter().filter(|sample| sample.diverges(divergence)).collect(),
+        }
+    }
+}
+
+impl fmt::Display for WeightAuditReport {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            formatter,
+            "sampled: {}, missing: {}, ratio min/median/max: {:.2}/{:.2}/{:.2}, total: {:.2}, \
+             divergent: {}",
+            self.sampled,
+            self.missing,
+            self.min_ratio,
+            self.median_ratio,
+            self.max_ratio,
+            self.total_ratio,
+            self.divergent.len()
+        )
+    }
+}
+
+#[derive(Debug)]
+struct WeightAuditState<CacheKeyT> {
+    reservoir: Mutex<Reservoir<CacheKeyT>>,
+    seen: AtomicU64,
+    last_report: Mutex<Option<WeightAuditReport>>,
+    hasher: RandomState,
+}
+
+#[derive(Debug)]
+struct Reservoir<CacheKeyT> {
+    keys: Vec<CacheKeyT>,
+    capacity: usize,
+}
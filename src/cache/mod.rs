@@ -1,10 +1,29 @@
 mod body;
 mod cache;
+mod cache_control;
+mod coding;
 mod configuration;
+mod dependency;
+mod fenced;
+mod heuristic;
 mod hooks;
+mod invalidation;
+mod jitter;
+mod join;
 mod key;
+mod key_migration;
+mod length;
+mod preload;
+mod reencode;
 mod response;
+mod self_test;
+mod serialized;
+mod skew;
+mod split;
 mod tiered;
+mod tiered_policy;
+mod validators;
+mod verification;
 mod weight;
 
 /// Cache axum utilities.
@@ -18,4 +37,4 @@ pub mod implementation;
 pub mod middleware;
 
 #[allow(unused_imports)]
-pub use {body::*, cache::*, configuration::*, hooks::*, key::*, response::*, tiered::*, weight::*};
+pub use {body::*, cache::*, cache_control::*, coding::*, configuration::*, dependency::*, fenced::*, heuristic::*, hooks::*, invalidation::*, jitter::*, key::*, key_migration::*, length::*, preload::*, reencode::*, response::*, self_test::*, serialized::*, skew::*, split::*, tiered::*, tiered_policy::*, validators::*, verification::*, weight::*};
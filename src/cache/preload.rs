@@ -0,0 +1,135 @@
+use super::key::*;
+
+use {
+    duration_str::*,
+    std::{fmt, time::*},
+};
+
+//
+// PreloadConfig
+//
+
+/// Configuration for [TieredCache::preload_first_tier](super::TieredCache::preload_first_tier).
+#[derive(Clone, Debug)]
+pub struct PreloadConfig<CacheKeyT = CommonCacheKey> {
+    /// Maximum total weight of the loaded entries (including their keys).
+    ///
+    /// Entries that would exceed it are skipped, but smaller entries after them may still fit.
+    pub byte_budget: usize,
+
+    /// Which keys to load.
+    pub key_source: PreloadKeySource<CacheKeyT>,
+
+    /// Maximum number of entries fetched concurrently from the next cache.
+    pub concurrency: usize,
+
+    /// Optional deadline, measured from the start.
+    ///
+    /// It is checked between batches (of [concurrency](Self::concurrency) entries), so it can be
+    /// exceeded by the time it takes to fetch one batch.
+    pub deadline: Option<Duration>,
+}
+
+impl<CacheKeyT> PreloadConfig<CacheKeyT> {
+    /// Constructor.
+    pub fn new(byte_budget: usize, key_source: PreloadKeySource<CacheKeyT>) -> Self {
+        Self {
+            byte_budget,
+            key_source,
+            concurrency: 8,
+            deadline: None,
+        }
+    }
+
+    /// Set concurrency.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    /// Set deadline.
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+}
+
+//
+// PreloadKeySource
+//
+
+/// Which keys [TieredCache::preload_first_tier](super::TieredCache::preload_first_tier) loads.
+#[derive(Clone, Debug)]
+pub enum PreloadKeySource<CacheKeyT = CommonCacheKey> {
+    /// These keys, in order of priority.
+    ///
+    /// Usually the manifest exported by the previous process on shutdown via
+    /// [TieredCache::export_manifest](super::TieredCache::export_manifest).
+    Manifest(Vec<CacheKeyT>),
+
+    /// All keys of the next cache, from the most to the least [hit](super::CachedResponse::hits).
+    ///
+    /// Requires a next cache that supports [keys](super::Cache::keys). Hits are only counted with
+    /// entry stats enabled, and next caches that serialize entries must persist them in order for
+    /// this to be useful.
+    Popular,
+
+    /// All keys of the next cache, from the most to the least recently
+    /// [created](super::CachedResponse::created).
+    ///
+    /// Requires a next cache that supports [keys](super::Cache::keys).
+    RecentlyWritten,
+}
+
+//
+// PreloadReport
+//
+
+/// What [TieredCache::preload_first_tier](super::TieredCache::preload_first_tier) did.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PreloadReport {
+    /// Entries loaded into the first cache.
+    pub loaded: u64,
+
+    /// Total weight of the loaded entries (including their keys).
+    pub bytes: u64,
+
+    /// Entries already in the first cache.
+    pub present: u64,
+
+    /// Entries not found in the next cache.
+    pub missing: u64,
+
+    /// Expired entries.
+    pub expired: u64,
+
+    /// Entries skipped because they would exceed the byte budget.
+    pub over_budget: u64,
+
+    /// Whether the deadline was reached before all keys were visited.
+    pub deadline_reached: bool,
+
+    /// Duration.
+    pub duration: Duration,
+}
+
+impl fmt::Display for PreloadReport {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            formatter,
+            "loaded {} ({} bytes), present {}, missing {}, expired {}, over budget {}{} in {}",
+            self.loaded,
+            self.bytes,
+            self.present,
+            self.missing,
+            self.expired,
+            self.over_budget,
+            if self.deadline_reached {
+                ", deadline reached"
+            } else {
+                ""
+            },
+            self.duration.human_format()
+        )
+    }
+}
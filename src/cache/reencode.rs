@@ -0,0 +1,303 @@
+use super::{cache::*, coding::*, configuration::*, join::*, key::*, response::*, weight::*};
+
+use {
+    kutil::http::*,
+    std::{collections::*, fmt, sync::*, time::*},
+};
+
+//
+// ReencodeFilter
+//
+
+/// Which entries a [ReencodeJob] reencodes.
+///
+/// Entries that already have the coding, that have validators only, that are marked as not
+/// encodable (via `XX-Encode`), or that are [no_transform](CachedResponse::no_transform) are always
+/// skipped.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReencodeFilter {
+    /// Minimum weight of the entry (including its key), e.g. to do only the heaviest entries.
+    pub min_weight: usize,
+
+    /// Minimum time remaining until the entry expires. Entries without a duration always pass.
+    ///
+    /// There's little point in reencoding entries that are about to expire.
+    pub min_remaining: Duration,
+}
+
+impl ReencodeFilter {
+    /// Whether an entry passes the filter.
+    pub fn matches<CacheKeyT>(
+        &self,
+        key: &CacheKeyT,
+        cached_response: &CachedResponse,
+        coding: &CodingId,
+        configuration: &EncodingConfiguration,
+    ) -> bool
+    where
+        CacheKeyT: CacheKey,
+    {
+        if cached_response.validators_only
+            || cached_response.no_transform
+            || cached_response.body.representations.contains_key(coding)
+            || !cached_response
+                .headers()
+                .xx_encode(configuration.encodable_by_default)
+        {
+            return false;
+        }
+
+        if let Some(duration) = cached_response.duration {
+            let elapsed = SystemTime::now()
+                .duration_since(cached_response.created)
+                .unwrap_or_default();
+            if duration.saturating_sub(elapsed) < self.min_remaining {
+                return false;
+            }
+        }
+
+        key.cache_weight() + cached_response.cache_weight() >= self.min_weight
+    }
+}
+
+//
+// ReencodeJob
+//
+
+/// Background job that adds a representation in a coding to existing cache entries.
+///
+/// Useful after enabling a new encoding: instead of reencoding lazily during live traffic (one
+/// hit at a time) we can do it for the matching entries during a quiet period.
+///
+/// Entries are updated via [Cache::update], merging with the current entry, so that concurrent
+/// modifications (e.g. reencodes by requests) are not lost. Updates don't affect expiry if the
+/// cache implementation honors the entry's creation time (as Moka's `CachedResponseExpiry` does).
+///
+/// Requires a cache that supports [keys](Cache::keys). The keys are snapshotted on the first
+/// [run](Self::run) and then consumed as a cursor, so a job that ran out of budget can be resumed
+/// by calling [run](Self::run) again.
+pub struct ReencodeJob<CacheT, CacheKeyT = CommonCacheKey> {
+    /// Cache.
+    pub cache: CacheT,
+
+    /// Coding to add.
+    pub coding: CodingId,
+
+    /// Filter.
+    pub filter: ReencodeFilter,
+
+    /// Maximum number of entries reencoded concurrently.
+    pub concurrency: usize,
+
+    /// Encoding configuration.
+    pub configuration: EncodingConfiguration,
+
+    /// How keys are rendered in logs.
+    pub key_log_policy: KeyLogPolicy,
+
+    cursor: Option<VecDeque<CacheKeyT>>,
+    progress: ReencodeProgress,
+}
+
+impl<CacheT, CacheKeyT> ReencodeJob<CacheT, CacheKeyT>
+where
+    CacheT: Cache<CacheKeyT>,
+    CacheKeyT: CacheKey,
+{
+    /// Constructor.
+    pub fn new(cache: CacheT, coding: CodingId, configuration: EncodingConfiguration) -> Self {
+        Self {
+            cache,
+            coding,
+            filter: Default::default(),
+            concurrency: 4,
+            configuration,
+            key_log_policy: Default::default(),
+            cursor: None,
+            progress: Default::default(),
+        }
+    }
+
+    /// Set filter.
+    pub fn with_filter(mut self, filter: ReencodeFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Set key log policy.
+    pub fn with_key_log_policy(mut self, key_log_policy: KeyLogPolicy) -> Self {
+        self.key_log_policy = key_log_policy;
+        self
+    }
+
+    /// Set concurrency.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    /// Whether all entries have been visited.
+    pub fn is_done(&self) -> bool {
+        self.cursor.as_ref().is_some_and(|cursor| cursor.is_empty())
+    }
+
+    /// Progress so far.
+    pub fn progress(&self) -> ReencodeProgress {
+        self.progress
+    }
+
+    /// Run until done or until the budget is spent.
+    ///
+    /// The budget is checked between batches (of [concurrency](Self::concurrency) entries), so it
+    /// can be exceeded by the time it takes to reencode one batch.
+    ///
+    /// Returns the progress so far (accumulated over all runs).
+    pub async fn run(&mut self, budget: Duration) -> ReencodeProgress {
+        let start = Instant::now();
+
+        let cursor = self.cursor.get_or_insert_with(|| match self.cache.keys() {
+            Some(keys) => keys.into(),
+
+            None => {
+                tracing::warn!("cache does not support key enumeration; nothing to reencode");
+                Default::default()
+            }
+        });
+
+        while !cursor.is_empty() && start.elapsed() < budget {
+            let batch: Vec<_> = cursor
+                .drain(..self.concurrency.max(1).min(cursor.len()))
+                .map(|key| {
+                    reencode(
+                        &self.cache,
+                        key,
+                        &self.coding,
+                        &self.filter,
+                        &self.configuration,
+                        &self.key_log_policy,
+                    )
+                })
+                .collect();
+
+            for outcome in join_all(batch).await {
+                self.progress.visited += 1;
+                match outcome {
+                    ReencodeOutcome::Reencoded => self.progress.reencoded += 1,
+                    ReencodeOutcome::Skipped => self.progress.skipped += 1,
+                    ReencodeOutcome::Failed => self.progress.failed += 1,
+                }
+            }
+        }
+
+        self.progress.remaining = cursor.len() as u64;
+
+        tracing::info!("reencoding to {}: {}", self.coding, self.progress);
+        self.progress
+    }
+}
+
+impl<CacheT, CacheKeyT> fmt::Debug for ReencodeJob<CacheT, CacheKeyT> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("ReencodeJob")
+            .field("coding", &self.coding)
+            .field("filter", &self.filter)
+            .field("concurrency", &self.concurrency)
+            .field("progress", &self.progress)
+            .finish()
+    }
+}
+
+//
+// ReencodeProgress
+//
+
+/// [ReencodeJob] progress.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReencodeProgress {
+    /// Entries visited.
+    pub visited: u64,
+
+    /// Entries that gained the representation.
+    pub reencoded: u64,
+
+    /// Entries skipped (gone, filtered out, or modified meanwhile).
+    pub skipped: u64,
+
+    /// Entries that failed to reencode.
+    pub failed: u64,
+
+    /// Entries yet to be visited.
+    pub remaining: u64,
+}
+
+impl fmt::Display for ReencodeProgress {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            formatter,
+            "visited {}, reencoded {}, skipped {}, failed {}, remaining {}",
+            self.visited, self.reencoded, self.skipped, self.failed, self.remaining
+        )
+    }
+}
+
+enum ReencodeOutcome {
+    Reencoded,
+    Skipped,
+    Failed,
+}
+
+async fn reencode<CacheT, CacheKeyT>(
+    cache: &CacheT,
+    key: CacheKeyT,
+    coding: &CodingId,
+    filter: &ReencodeFilter,
+    configuration: &EncodingConfiguration,
+    key_log_policy: &KeyLogPolicy,
+) -> ReencodeOutcome
+where
+    CacheT: Cache<CacheKeyT>,
+    CacheKeyT: CacheKey,
+{
+    let Some(cached_response) = cache.get(&key).await else {
+        return ReencodeOutcome::Skipped;
+    };
+
+    if !filter.matches(&key, &cached_response, coding, configuration) {
+        return ReencodeOutcome::Skipped;
+    }
+
+    match cached_response
+        .with_additional_encoding(coding, configuration)
+        .await
+    {
+        Ok(reencoded) => {
+            // Merge rather than replace, in case the entry was modified while we were encoding
+            if cache
+                .update(key, move |current| {
+                    current
+                        .with_representations_from(&reencoded)
+                        .map(|mut replacement| {
+                            replacement.prune_representations(configuration);
+                            Arc::new(replacement)
+                        })
+                })
+                .await
+            {
+                ReencodeOutcome::Reencoded
+            } else {
+                ReencodeOutcome::Skipped
+            }
+        }
+
+        Err(error) => {
+            tracing::error!(
+                "could not reencode to {}: {} {}",
+                coding,
+                key.display_for_logs(key_log_policy),
+                error
+            );
+            ReencodeOutcome::Failed
+        }
+    }
+}
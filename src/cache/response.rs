@@ -1,16 +1,26 @@
-use super::{body::*, configuration::*, hooks::*, weight::*};
+use super::{
+    body::*, cache_control::*, coding::*, configuration::*, dependency::*, heuristic::*, hooks::*,
+    length::*, middleware::*, validators::*, weight::*,
+};
 
 use {
     core::any::*,
     duration_str::*,
     http::{header::*, response::*, *},
     http_body::*,
+    httpdate::*,
     kutil::{
         http::*,
-        std::{error::*, immutable::*},
-        transcoding::*,
+        std::{collections::*, error::*, immutable::*},
+    },
+    std::{
+        io,
+        mem::*,
+        ops::Range,
+        result::Result,
+        sync::{atomic::*, *},
+        time::*,
     },
-    std::{io, mem::*, result::Result, sync::*, time::*},
 };
 
 /// Common reference type for [CachedResponse].
@@ -33,6 +43,39 @@ pub struct CachedResponse {
 
     /// Optional duration.
     pub duration: Option<Duration>,
+
+    /// When the entry was created.
+    pub created: SystemTime,
+
+    /// Upstream `Age` captured when the entry was created.
+    pub upstream_age: Duration,
+
+    /// The coding in which the upstream produced the body.
+    ///
+    /// Serving another coding is a transformation. See [OnTheFlyValidatorPolicy].
+    pub original_coding: CodingId,
+
+    /// Whether this entry holds only validators and metadata, without a body.
+    ///
+    /// Such entries are used exclusively to answer conditional requests and `HEAD` requests. They
+    /// are never served as a response with a body.
+    pub validators_only: bool,
+
+    /// Whether the upstream response had `Cache-Control: no-transform` (and we respect it).
+    ///
+    /// Such entries have only the [original coding](Self::original_coding) and are always served
+    /// in it, regardless of the requested coding.
+    pub no_transform: bool,
+
+    /// Dependency tokens recorded while handling the request that created this entry.
+    ///
+    /// See [DependencyCache].
+    pub dependencies: Arc<[Arc<str>]>,
+
+    /// Number of hits.
+    ///
+    /// Shared by clones, including refreshed ones, because they are the same entry.
+    pub hits: Arc<AtomicU64>,
 }
 
 impl CachedResponse {
@@ -47,95 +90,250 @@ impl CachedResponse {
     /// incomplete bodies!), together with [ResponsePieces], which can be used by the caller to
     /// reconstruct the original response.
     ///
-    /// `preferred_encoding` is the encoding in which we *want* to store the body. If the response's
+    /// `preferred_coding` is the coding in which we *want* to store the body. If the response's
     /// encoding is different from what we want then it will be reencoded, unless the `XX-Encode`
-    /// header is "false", in which case it's as if `preferred_encoding` were
-    /// [Identity](Encoding::Identity).
+    /// header is "false", in which case it's as if `preferred_coding` were
+    /// [Identity](CodingId::IDENTITY).
     ///
-    /// If an [Identity](Encoding::Identity) is created during this reencoding then it will also be
+    /// If an [Identity](CodingId::IDENTITY) is created during this reencoding then it will also be
     /// stored if `keep_identity_encoding` is true.
     ///
+    /// If the response has `Cache-Control: no-transform` (and `respect_no_transform` is true), or
+    /// if `never_transform` is true, then the body is stored as is, ignoring `preferred_coding`.
+    /// See [no_transform](Self::no_transform).
+    ///
     /// If the response doesn't already have a `Last-Modified` header, we will set it to the
     /// current time.
+    ///
+    /// The upstream `Age` header, if provided, is captured and removed. See
+    /// [apply_age_accounting](Self::apply_age_accounting).
+    ///
+    /// If `declared_body_size` is provided and the body is shorter or longer, the error is a
+    /// [LengthMismatch] and nothing is stored.
+    ///
+    /// Trailers are discarded. See [new_for_with_trailers](Self::new_for_with_trailers).
     pub async fn new_for<BodyT>(
         uri: &Uri,
         response: Response<BodyT>,
         declared_body_size: Option<usize>,
-        mut preferred_encoding: Encoding,
+        preferred_coding: CodingId,
         skip_encoding: bool,
         caching_configuration: &CachingConfiguration,
         encoding_configuration: &EncodingConfiguration,
     ) -> Result<Self, ErrorWithResponsePieces<ReadBodyError, BodyT>>
     where
-        BodyT: Body + Unpin,
+        BodyT: Body + From<ImmutableBytes> + Unpin,
         BodyT::Error: Into<CapturedError>,
     {
-        let (mut parts, body) = response.into_parts();
+        Self::new_for_with_trailers(
+            uri,
+            response,
+            declared_body_size,
+            preferred_coding,
+            skip_encoding,
+            caching_configuration,
+            encoding_configuration,
+        )
+        .await
+        .map(|(cached_response, _trailers)| cached_response)
+    }
 
-        let bytes = match body
+    /// Like [new_for](Self::new_for) but also returns the upstream trailers.
+    ///
+    /// The trailers are *not* part of the entry. They are intended for the response to the
+    /// request that triggered the store.
+    pub async fn new_for_with_trailers<BodyT>(
+        uri: &Uri,
+        response: Response<BodyT>,
+        declared_body_size: Option<usize>,
+        preferred_coding: CodingId,
+        skip_encoding: bool,
+        caching_configuration: &CachingConfiguration,
+        encoding_configuration: &EncodingConfiguration,
+    ) -> Result<(Self, Vec<HeaderMap>), ErrorWithResponsePieces<ReadBodyError, BodyT>>
+    where
+        BodyT: Body + From<ImmutableBytes> + Unpin,
+        BodyT::Error: Into<CapturedError>,
+    {
+        let (parts, body) = response.into_parts();
+
+        let overrides = RouteOverrides::new(
+            parts.extensions.get::<RoutePolicy>(),
+            caching_configuration,
+            encoding_configuration,
+        );
+
+        // Negative responses (e.g. redirects) often have empty bodies
+        let min_body_size = if parts.status.is_success() {
+            overrides.min_body_size
+        } else {
+            0
+        };
+
+        let (bytes, trailers) = match body
             .read_into_bytes_or_pieces(
                 declared_body_size,
-                caching_configuration.min_body_size,
-                caching_configuration.max_body_size,
+                min_body_size,
+                overrides.max_body_size,
             )
             .await
         {
-            Ok((bytes, _trailers)) => bytes,
-            Err(error) => {
+            Ok(bytes_and_trailers) => bytes_and_trailers,
+            Err(mut error) => {
+                // We read at most the declared size, so a longer body is a mismatch
+                if let Some(declared_body_size) = declared_body_size
+                    && let Some(pieces) = &error.pieces
+                    && pieces.first_bytes.len() > declared_body_size
+                {
+                    error.error =
+                        LengthMismatch::new(declared_body_size, pieces.first_bytes.len()).into();
+                }
+
                 return Err(ErrorWithResponsePieces::new_from_body(error, parts));
             }
         };
 
-        if preferred_encoding != Encoding::Identity {
+        // A shorter body is a mismatch, too (e.g. an aborted upstream read); storing it would
+        // serve a truncated entry until it expires
+        if let Some(declared_body_size) = declared_body_size
+            && bytes.len() != declared_body_size
+        {
+            let error = LengthMismatch::new(declared_body_size, bytes.len());
+            let pieces = BodyPieces::new(ImmutableBytes::default().into(), bytes);
+            return Err(ErrorWithResponsePieces::new_from_body(
+                ErrorWithBodyPieces::new(error.into(), Some(pieces)),
+                parts,
+            ));
+        }
+
+        let cached_response = Self::from_bytes(
+            uri,
+            parts,
+            bytes,
+            preferred_coding,
+            skip_encoding,
+            caching_configuration,
+            encoding_configuration,
+        )
+        .await
+        // This is not *exactly* a ReadBodyError, but rather an encoding error for the read body
+        .map_err(|error| ErrorWithResponsePieces::from(ReadBodyError::from(error)))?;
+
+        Ok((cached_response, trailers))
+    }
+
+    /// Constructor from response parts and a body that we already have, e.g. a pre-rendered page.
+    ///
+    /// The entry is created exactly as [new_for](Self::new_for) would create it for a response
+    /// with these parts and body, including removing transport headers, adding a synthetic
+    /// `Last-Modified`, and encoding to `preferred_coding`. However, the body size limits of the
+    /// caching configuration are not checked, as the body is not read from anywhere.
+    ///
+    /// The bytes are in the coding of the `Content-Encoding` header (Identity if there is none).
+    pub async fn from_bytes(
+        uri: &Uri,
+        mut parts: Parts,
+        bytes: ImmutableBytes,
+        mut preferred_coding: CodingId,
+        skip_encoding: bool,
+        caching_configuration: &CachingConfiguration,
+        encoding_configuration: &EncodingConfiguration,
+    ) -> io::Result<Self> {
+        let original_coding = CodingId::Builtin(parts.headers.content_encoding().into());
+
+        let policy = parts.extensions.remove::<RoutePolicy>();
+        let overrides =
+            RouteOverrides::new(policy.as_ref(), caching_configuration, encoding_configuration);
+
+        let no_transform = encoding_configuration.no_transform(&parts.headers);
+        if no_transform {
+            if preferred_coding != original_coding {
+                tracing::debug!("not encoding to {} (no-transform)", preferred_coding);
+            }
+            preferred_coding = original_coding.clone();
+        } else if !preferred_coding.is_identity() {
             if !parts
                 .headers
-                .xx_encode(encoding_configuration.encodable_by_default)
+                .xx_encode(overrides.encodable_by_default)
             {
                 tracing::debug!(
                     "not encoding to {} ({}=false)",
-                    preferred_encoding,
+                    preferred_coding,
                     XX_ENCODE
                 );
-                preferred_encoding = Encoding::Identity;
-            } else if bytes.len() < encoding_configuration.min_body_size {
-                tracing::debug!("not encoding to {} (too small)", preferred_encoding);
-                preferred_encoding = Encoding::Identity;
+                preferred_coding = CodingId::IDENTITY;
+            } else if bytes.is_empty() || bytes.len() < encoding_configuration.min_body_size {
+                tracing::debug!("not encoding to {} (too small)", preferred_coding);
+                preferred_coding = CodingId::IDENTITY;
             }
         }
 
         let body = CachedBody::new_with(
             bytes,
             parts.headers.content_encoding().into(),
-            preferred_encoding,
+            preferred_coding,
             encoding_configuration,
         )
-        .await
-        // This is not *exactly* a ReadBodyError, but rather an encoding error for the read body
-        .map_err(|error| ErrorWithResponsePieces::from(ReadBodyError::from(error)))?;
+        .await?;
 
-        // Extract `XX-Cache-Duration` or call hook
-        let duration = match parts.headers.xx_cache_duration() {
-            Some(duration) => Some(duration),
-            None => caching_configuration
-                .cache_duration
-                .as_ref()
-                .and_then(|duration| duration(CacheDurationHookContext::new(uri, &parts.headers))),
-        };
+        let policy_duration = policy.as_ref().and_then(|policy| policy.duration);
+        let data_version = policy.and_then(|policy| policy.data_version);
+        let duration = Self::duration_for(
+            uri,
+            parts.status,
+            &parts.headers,
+            policy_duration,
+            caching_configuration,
+        );
 
-        if let Some(duration) = duration {
-            tracing::debug!("duration: {}", duration.human_format());
-        }
+        let created = caching_configuration.now();
+        let upstream_age = upstream_age(&parts.headers);
 
-        // Make sure we have a `Last-Modified`
-        if !parts.headers.contains_key(LAST_MODIFIED) {
-            parts.headers.set_into_header_value(LAST_MODIFIED, now());
+        // Make sure we have a validator (unless opted out); negative entries are never validated
+        if parts.status.is_success()
+            && !parts.headers.contains_key(LAST_MODIFIED)
+            && !parts.headers.bool_value(XX_NO_SYNTHETIC_VALIDATORS, false)
+            && caching_configuration
+                .synthetic_last_modified
+                .applies(duration)
+        {
+            match data_version {
+                // Deterministic, so that all replicas agree
+                Some(data_version) => {
+                    if !parts.headers.contains_key(ETAG)
+                        && let Ok(etag) =
+                            HeaderValue::try_from(format!("W/\"v{}\"", data_version))
+                    {
+                        parts.headers.insert(ETAG, etag);
+                    }
+                }
+
+                None => {
+                    parts.headers.set_into_header_value(
+                        LAST_MODIFIED,
+                        HttpDate::from(whole_seconds(created)),
+                    );
+                }
+            }
         }
 
-        parts.headers.remove(XX_CACHE);
-        parts.headers.remove(XX_CACHE_DURATION);
-        parts.headers.remove(CONTENT_ENCODING);
-        parts.headers.remove(CONTENT_LENGTH);
-        parts.headers.remove(CONTENT_DIGEST);
+        let dependencies = cache_tags(&parts.headers);
+
+        // `Accept-Ranges` is re-added when serving (see `CachingLayer::serve_ranges`)
+        remove_headers(
+            &mut parts.headers,
+            &[
+                AGE,
+                XX_CACHE,
+                XX_CACHE_DURATION,
+                XX_CACHE_TAGS,
+                XX_NO_SYNTHETIC_VALIDATORS,
+                CONTENT_ENCODING,
+                CONTENT_LENGTH,
+                CONTENT_DIGEST,
+                ACCEPT_RANGES,
+            ],
+        );
 
         // Note that we are keeping the `XX-Encode` header in the cache
         // (but will remove it in `to_response`)
@@ -144,24 +342,262 @@ impl CachedResponse {
             parts.headers.set_bool_value(XX_ENCODE, true);
         }
 
-        // TODO: can we support ranges? if so, we should not remove this header
-        // https://developer.mozilla.org/en-US/docs/Web/HTTP/Reference/Headers/Accept-Ranges
-        parts.headers.remove(ACCEPT_RANGES);
-
         Ok(Self {
             parts,
             body,
             duration,
+            created,
+            upstream_age,
+            original_coding,
+            validators_only: false,
+            no_transform,
+            dependencies,
+            hits: Default::default(),
         })
     }
 
+    /// Constructor for an entry that holds only validators and metadata, without a body.
+    ///
+    /// Intended for responses that are too big to cache. Such entries can still be used to answer
+    /// conditional requests and `HEAD` requests without calling the upstream.
+    ///
+    /// Returns [None] if the response has neither `ETag` nor `Last-Modified`, in which case there
+    /// is nothing worth caching, or if its status is not success (2xx), in which case validators
+    /// are meaningless.
+    pub fn new_validators_only(
+        uri: &Uri,
+        status: StatusCode,
+        headers: &HeaderMap,
+        content_length: Option<usize>,
+        caching_configuration: &CachingConfiguration,
+    ) -> Option<Self> {
+        const METADATA_HEADERS: &[HeaderName] = &[
+            ETAG,
+            LAST_MODIFIED,
+            CONTENT_TYPE,
+            CONTENT_ENCODING,
+            CONTENT_LANGUAGE,
+            CONTENT_LENGTH,
+        ];
+
+        if !status.is_success()
+            || (!headers.contains_key(ETAG) && !headers.contains_key(LAST_MODIFIED))
+        {
+            return None;
+        }
+
+        let mut metadata_headers = HeaderMap::with_capacity(METADATA_HEADERS.len());
+        for name in METADATA_HEADERS {
+            for value in headers.get_all(name) {
+                metadata_headers.append(name, value.clone());
+            }
+        }
+
+        if let Some(content_length) = content_length {
+            metadata_headers.set_value(CONTENT_LENGTH, content_length);
+        }
+
+        let (mut parts, _) = Response::new(()).into_parts();
+        parts.status = status;
+        parts.headers = metadata_headers;
+
+        Some(Self {
+            parts,
+            body: Default::default(),
+            duration: Self::duration_for(uri, status, headers, None, caching_configuration),
+            created: caching_configuration.now(),
+            upstream_age: upstream_age(headers),
+            original_coding: CodingId::Builtin(headers.content_encoding().into()),
+            validators_only: true,
+            no_transform: false,
+            dependencies: Default::default(),
+            hits: Default::default(),
+        })
+    }
+
+    // Extract `XX-Cache-Duration`, use the route policy, call hook, use `Cache-Control` (if we
+    // respect it), use the negative duration (if not success), or use heuristic freshness.
+    fn duration_for(
+        uri: &Uri,
+        status: StatusCode,
+        headers: &HeaderMap,
+        policy_duration: Option<Duration>,
+        caching_configuration: &CachingConfiguration,
+    ) -> Option<Duration> {
+        let (duration, source) = if let Some(duration) = headers.xx_cache_duration() {
+            (Some(duration), DurationSource::Explicit)
+        } else if let Some(duration) = policy_duration {
+            (Some(duration), DurationSource::Policy)
+        } else if let Some(duration) = caching_configuration
+            .cache_duration
+            .as_ref()
+            .and_then(|duration| duration(CacheDurationHookContext::new(uri, headers)))
+        {
+            (Some(duration), DurationSource::Hook)
+        } else if caching_configuration.respect_cache_control
+            && let Some(duration) = CacheControl::from_headers(headers)
+                .freshness(headers, caching_configuration.now())
+        {
+            (Some(duration), DurationSource::CacheControl)
+        } else if !status.is_success()
+            && let Some(duration) = caching_configuration.negative_duration
+        {
+            (Some(duration), DurationSource::Negative)
+        } else if let Some(duration) = caching_configuration
+            .heuristic_freshness
+            .as_ref()
+            .and_then(|heuristic| heuristic.duration(headers, caching_configuration.now()))
+        {
+            (Some(duration), DurationSource::Heuristic)
+        } else {
+            (None, DurationSource::Default)
+        };
+
+        let duration = match (duration, &caching_configuration.ttl_jitter) {
+            (Some(duration), Some(ttl_jitter)) => {
+                Some(ttl_jitter.jitter(duration, caching_configuration.now()))
+            }
+            _ => duration,
+        };
+
+        match duration {
+            Some(duration) => tracing::debug!("duration: {} ({})", duration.human_format(), source),
+            None => tracing::debug!("duration: {}", source),
+        }
+
+        duration
+    }
+
+    /// Clone refreshed by a `304 Not Modified` upstream response.
+    ///
+    /// The stored body is kept as is. Stored headers are updated by the ones in the 304 response
+    /// as per
+    /// [IETF RFC 9111 section 4.3.4](https://datatracker.ietf.org/doc/html/rfc9111#section-4.3.4),
+    /// except for those that describe the body, which we manage ourselves. The duration is reset
+    /// according to the 304 response (keeping the previous one if it has none, e.g. because the
+    /// `XX-Cache-Duration` was sent only with the full response) and the entry is considered
+    /// created now.
+    pub fn refreshed(
+        &self,
+        uri: &Uri,
+        headers: &HeaderMap,
+        policy_duration: Option<Duration>,
+        caching_configuration: &CachingConfiguration,
+    ) -> Self {
+        let unupdatable_headers =
+            [CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_DIGEST, CONTENT_RANGE, AGE];
+
+        let mut parts = self.parts.clone();
+        for name in headers.keys() {
+            if unupdatable_headers.contains(name) {
+                continue;
+            }
+
+            parts.headers.remove(name);
+            for value in headers.get_all(name) {
+                parts.headers.append(name, value.clone());
+            }
+        }
+
+        parts.headers.remove(XX_CACHE);
+        parts.headers.remove(XX_CACHE_DURATION);
+        parts.headers.remove(XX_NO_SYNTHETIC_VALIDATORS);
+
+        Self {
+            parts,
+            body: self.body.clone(),
+            duration: Self::duration_for(
+                uri,
+                self.parts.status,
+                headers,
+                policy_duration,
+                caching_configuration,
+            )
+            .or(self.duration),
+            created: caching_configuration.now(),
+            upstream_age: upstream_age(headers),
+            original_coding: self.original_coding.clone(),
+            validators_only: self.validators_only,
+            no_transform: self.no_transform,
+            dependencies: self.dependencies.clone(),
+            hits: self.hits.clone(),
+        }
+    }
+
     /// Clone with new body.
     pub fn clone_with_body(&self, body: CachedBody) -> Self {
         Self {
             parts: self.parts.clone(),
             body,
-            duration: self.duration.clone(),
+            duration: self.duration,
+            created: self.created,
+            upstream_age: self.upstream_age,
+            original_coding: self.original_coding.clone(),
+            validators_only: self.validators_only,
+            no_transform: self.no_transform,
+            dependencies: self.dependencies.clone(),
+            hits: self.hits.clone(),
+        }
+    }
+
+    /// Clone with an additional representation in the specified coding.
+    ///
+    /// Uses the same reencoding as [to_response](Self::to_response). Returns an unmodified clone
+    /// if we already have the coding, if the encoding fails verification, or if the entry is
+    /// [no_transform](Self::no_transform).
+    pub async fn with_additional_encoding(
+        &self,
+        coding: &CodingId,
+        configuration: &EncodingConfiguration,
+    ) -> io::Result<Self> {
+        if self.validators_only {
+            return Err(io::Error::other("entry has validators only"));
+        }
+
+        if self.no_transform {
+            return Ok(self.clone());
+        }
+
+        let (_, _, modified) = self.body.get(coding, configuration).await?;
+        Ok(match modified {
+            Some(body) => self.clone_with_body(body),
+            None => self.clone(),
+        })
+    }
+
+    /// Clone with the representations of another version of this entry that we don't have.
+    ///
+    /// Returns [None] if nothing would be added or if the other is not a version of this entry
+    /// (i.e. it was created at a different time or its content differs).
+    ///
+    /// Useful for merging concurrent reencodes via [Cache::update](super::cache::Cache::update).
+    pub fn with_representations_from(&self, other: &Self) -> Option<Self> {
+        if self.created != other.created || self.content_differs(other) {
+            return None;
         }
+
+        let mut body = self.body.clone();
+        body.add_representations_from(&other.body)
+            .then(|| self.clone_with_body(body))
+    }
+
+    /// Prune representations in codings that are not [enabled](EncodingConfiguration::is_enabled),
+    /// if `prune_disabled_representations` is true.
+    ///
+    /// [no_transform](Self::no_transform) entries are never pruned, and neither is the last
+    /// representation. Returns the number of representations removed.
+    pub fn prune_representations(&mut self, configuration: &EncodingConfiguration) -> usize {
+        if !configuration.prune_disabled_representations || self.no_transform {
+            return 0;
+        }
+
+        let pruned = self
+            .body
+            .retain_representations(|coding| configuration.is_enabled(coding));
+        if pruned != 0 {
+            tracing::debug!("pruned {} disabled representations", pruned);
+        }
+        pruned
     }
 
     /// Headers.
@@ -171,51 +607,359 @@ impl CachedResponse {
 
     /// Create a [Response].
     ///
-    /// If we don't have the specified encoding then we will reencode from another encoding,
-    /// storing the result so that we won't have to encode it again.
+    /// If we don't have the specified coding then we will reencode from another coding, storing
+    /// the result so that we won't have to encode it again.
     ///
-    /// If an [Identity](Encoding::Identity) is created during this reencoding then it will also be
+    /// If an [Identity](CodingId::IDENTITY) is created during this reencoding then it will also be
     /// stored if `keep_identity_encoding` is true.
     ///
-    /// If the stored `XX-Encode` header is "false" then will ignore the specified encoding and
-    /// return an [Identity](Encoding::Identity) response.
+    /// If the stored `XX-Encode` header is "false", or if the specified coding is not
+    /// [enabled](EncodingConfiguration::is_enabled), then will ignore the specified coding and
+    /// return an [Identity](CodingId::IDENTITY) response. Stored representations in codings that
+    /// are no longer enabled are thus never served. If the entry is
+    /// [no_transform](Self::no_transform) then will ignore the specified coding and return the
+    /// original coding.
     ///
     /// Returns a modified clone if reencoding caused a new encoding to be stored. Note that
     /// cloning should be cheap due to our use of [ImmutableBytes] in the body.
     pub async fn to_response<BodyT>(
         &self,
-        mut encoding: &Encoding,
+        coding: &CodingId,
         configuration: &EncodingConfiguration,
     ) -> io::Result<(Response<BodyT>, Option<Self>)>
     where
         BodyT: Body + From<ImmutableBytes>,
     {
-        if (*encoding != Encoding::Identity)
-            && !self.headers().xx_encode(configuration.encodable_by_default)
+        if self.validators_only {
+            // We must never serve an empty body in place of the real one
+            return Err(io::Error::other("entry has validators only"));
+        }
+
+        let coding = self.serving_coding(coding, configuration);
+
+        // Note that the coding we get might be different (if it failed verification)
+        let (bytes, coding, modified) = match self.body.representations.get(coding) {
+            // Fast path for hits, skipping the reencoding machinery
+            Some(bytes) => (bytes.clone(), coding.clone(), None),
+            None => self.body.get(coding, configuration).await?,
+        };
+
+        Ok((
+            self.response_for(bytes, &coding, configuration),
+            modified.map(|body| self.clone_with_body(body)),
+        ))
+    }
+
+    /// Create a [Response] from a stored representation, without any encoding or decoding.
+    ///
+    /// Prefers the coding that [to_response](Self::to_response) would serve, falling back to
+    /// [Identity](CodingId::IDENTITY). Returns [None] if neither is stored (or if the entry has
+    /// validators only).
+    pub fn to_stored_response<BodyT>(
+        &self,
+        coding: &CodingId,
+        configuration: &EncodingConfiguration,
+    ) -> Option<Response<BodyT>>
+    where
+        BodyT: Body + From<ImmutableBytes>,
+    {
+        if self.validators_only {
+            return None;
+        }
+
+        let coding = self.serving_coding(coding, configuration);
+        let (bytes, coding) = match self.body.representations.get(coding) {
+            Some(bytes) => (bytes, coding),
+            None => (
+                self.body.representations.get(&CodingId::IDENTITY)?,
+                &CodingId::IDENTITY,
+            ),
+        };
+
+        Some(self.response_for(bytes.clone(), coding, configuration))
+    }
+
+    /// The [Identity](CodingId::IDENTITY) representation.
+    ///
+    /// If we don't have it then we will decode it from another coding, in which case it will
+    /// also be stored if `keep_identity_encoding` is true. Returns a modified clone if it was.
+    pub async fn identity(
+        &self,
+        configuration: &EncodingConfiguration,
+    ) -> io::Result<(ImmutableBytes, Option<Self>)> {
+        if self.validators_only {
+            return Err(io::Error::other("entry has validators only"));
+        }
+
+        match self.body.representations.get(&CodingId::IDENTITY) {
+            Some(bytes) => Ok((bytes.clone(), None)),
+            None => {
+                let (bytes, _coding, modified) =
+                    self.body.get(&CodingId::IDENTITY, configuration).await?;
+                Ok((bytes, modified.map(|body| self.clone_with_body(body))))
+            }
+        }
+    }
+
+    /// Create a `206 Partial Content` [Response] for a range of the
+    /// [identity](Self::identity) representation.
+    ///
+    /// The range must be within the representation (see [RangeRequest]). The bytes are copied.
+    pub fn to_range_response<BodyT>(
+        &self,
+        identity: &ImmutableBytes,
+        range: Range<usize>,
+        configuration: &EncodingConfiguration,
+    ) -> Response<BodyT>
+    where
+        BodyT: Body + From<ImmutableBytes>,
+    {
+        let total = identity.len();
+        let bytes = ImmutableBytes::from(identity[range.clone()].to_vec());
+        let mut response = self.response_for(bytes, &CodingId::IDENTITY, configuration);
+
+        *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+        let headers = response.headers_mut();
+        if let Ok(content_range) =
+            HeaderValue::try_from(format!("bytes {}-{}/{}", range.start, range.end - 1, total))
         {
-            tracing::debug!("not encoding to {} ({}=false)", encoding, XX_ENCODE);
-            encoding = &Encoding::Identity;
+            headers.insert(CONTENT_RANGE, content_range);
         }
+        headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
 
-        let (bytes, modified) = self.body.get(encoding, configuration).await?;
+        response
+    }
 
+    // The coding to serve for a requested coding.
+    fn serving_coding<'this>(
+        &'this self,
+        coding: &'this CodingId,
+        configuration: &EncodingConfiguration,
+    ) -> &'this CodingId {
+        if self.no_transform {
+            if *coding != self.original_coding {
+                tracing::debug!("not encoding to {} (no-transform)", coding);
+            }
+            &self.original_coding
+        } else if !coding.is_identity()
+            && !self.headers().xx_encode(configuration.encodable_by_default)
+        {
+            tracing::debug!("not encoding to {} ({}=false)", coding, XX_ENCODE);
+            &CodingId::IDENTITY
+        } else if !configuration.is_enabled(coding) {
+            tracing::debug!("not encoding to {} (disabled)", coding);
+            &CodingId::IDENTITY
+        } else {
+            coding
+        }
+    }
+
+    // Response for a representation.
+    fn response_for<BodyT>(
+        &self,
+        bytes: ImmutableBytes,
+        coding: &CodingId,
+        configuration: &EncodingConfiguration,
+    ) -> Response<BodyT>
+    where
+        BodyT: Body + From<ImmutableBytes>,
+    {
         let mut parts = self.parts.clone();
 
         parts.headers.remove(XX_ENCODE);
 
-        if *encoding != Encoding::Identity {
+        if *coding != self.original_coding {
+            configuration
+                .on_the_fly_validators
+                .apply(&mut parts.headers, coding);
+        }
+
+        if !coding.is_identity() {
             // No need to specify Identity as it's the default
-            parts
-                .headers
-                .set_into_header_value(CONTENT_ENCODING, encoding.clone());
+            parts.headers.set_into_header_value(CONTENT_ENCODING, coding.clone());
         }
 
         parts.headers.set_value(CONTENT_LENGTH, bytes.len());
 
-        Ok((
-            Response::from_parts(parts, bytes.into()),
-            modified.map(|body| self.clone_with_body(body)),
-        ))
+        Response::from_parts(parts, bytes.into())
+    }
+
+    /// Clone with the body transformed by the hook.
+    ///
+    /// Returns [None] if the hook keeps the original body, if it fails, or if the transformed body
+    /// is not within the cacheable size limits (of the overrides).
+    ///
+    /// The transformed body is stored as [Identity](CodingId::IDENTITY) only. A strong `ETag` is
+    /// weakened because the bytes changed.
+    pub async fn transformed(
+        &self,
+        uri: &Uri,
+        transform: &TransformHook,
+        overrides: &RouteOverrides,
+        encoding_configuration: &EncodingConfiguration,
+    ) -> Option<Self> {
+        if self.validators_only {
+            return None;
+        }
+
+        let (bytes, _, _) = match self
+            .body
+            .get(&CodingId::IDENTITY, encoding_configuration)
+            .await
+        {
+            Ok(identity) => identity,
+            Err(error) => {
+                tracing::error!("could not decode for transform: {}", error);
+                return None;
+            }
+        };
+
+        let transformed = match transform(TransformHookContext::new(uri, self.headers(), &bytes)) {
+            Ok(transformed) => transformed?,
+            Err(error) => {
+                tracing::error!("transform failed, storing the original: {}", error);
+                return None;
+            }
+        };
+
+        let size = transformed.bytes.len();
+        if size < overrides.min_body_size || size > overrides.max_body_size {
+            tracing::debug!("not storing transformed body (size {})", size);
+            return None;
+        }
+
+        let mut parts = self.parts.clone();
+        for name in transformed.headers.keys() {
+            parts.headers.remove(name);
+            for value in transformed.headers.get_all(name) {
+                parts.headers.append(name, value.clone());
+            }
+        }
+
+        if let Some(etag) = parts.headers.get(ETAG)
+            && !etag.as_bytes().starts_with(b"W/")
+            && let Ok(weak) = HeaderValue::try_from([b"W/", etag.as_bytes()].concat())
+        {
+            parts.headers.insert(ETAG, weak);
+        }
+
+        let mut representations = FastHashMap::default();
+        representations.insert(CodingId::IDENTITY, transformed.bytes);
+
+        Some(Self {
+            parts,
+            body: CachedBody::new(representations),
+            duration: self.duration,
+            created: self.created,
+            upstream_age: self.upstream_age,
+            original_coding: CodingId::IDENTITY,
+            validators_only: false,
+            no_transform: self.no_transform,
+            dependencies: self.dependencies.clone(),
+            hits: Default::default(),
+        })
+    }
+
+    /// Whether the content differs from that of another entry.
+    ///
+    /// Compares a representation in a coding that both have, falling back to comparing `ETag`.
+    /// If neither is possible we assume they are the same.
+    pub fn content_differs(&self, other: &Self) -> bool {
+        for (coding, bytes) in &self.body.representations {
+            if let Some(other_bytes) = other.body.representations.get(coding) {
+                return bytes != other_bytes;
+            }
+        }
+
+        match (self.headers().get(ETAG), other.headers().get(ETAG)) {
+            (Some(etag), Some(other_etag)) => etag != other_etag,
+            _ => false,
+        }
+    }
+
+    /// Whether the duration has elapsed.
+    ///
+    /// Caches may retain expired entries for a grace period, in which case they can still be
+    /// refreshed via a conditional upstream request.
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        match self.duration {
+            Some(duration) => now.duration_since(self.created).unwrap_or_default() >= duration,
+            None => false,
+        }
+    }
+
+    /// Whether the status is not success (2xx), e.g. a cached 404 (Not Found).
+    ///
+    /// Conditional requests are not evaluated for negative entries, as per
+    /// [RFC 9110 section 13.2.1](https://datatracker.ietf.org/doc/html/rfc9110#section-13.2.1).
+    pub fn is_negative(&self) -> bool {
+        !self.parts.status.is_success()
+    }
+
+    /// Add `If-None-Match` and `If-Modified-Since` request headers from our validators.
+    ///
+    /// Request headers that are already present are never overridden.
+    pub fn add_conditional_headers(&self, headers: &mut HeaderMap) {
+        if !headers.contains_key(IF_NONE_MATCH)
+            && let Some(etag) = self.headers().get(ETAG)
+        {
+            headers.insert(IF_NONE_MATCH, etag.clone());
+        }
+
+        if !headers.contains_key(IF_MODIFIED_SINCE)
+            && let Some(last_modified) = self.headers().get(LAST_MODIFIED)
+        {
+            headers.insert(IF_MODIFIED_SINCE, last_modified.clone());
+        }
+    }
+
+    /// Age: the time held in our cache plus the captured upstream `Age`.
+    pub fn age(&self, now: SystemTime) -> Duration {
+        self.upstream_age + self.held(now)
+    }
+
+    /// The time held in our cache, since [created](Self::created).
+    pub fn held(&self, now: SystemTime) -> Duration {
+        now.duration_since(self.created).unwrap_or_default()
+    }
+
+    /// Apply age accounting to the headers of a response served from this entry.
+    ///
+    /// Stored values are not changed, so the accounting strategy can be changed at any time.
+    pub fn apply_age_accounting(
+        &self,
+        headers: &mut HeaderMap,
+        age_accounting: AgeAccounting,
+        now: SystemTime,
+    ) {
+        match age_accounting {
+            AgeAccounting::RefreshDate => {
+                headers.set_into_header_value(DATE, HttpDate::from(now));
+                headers.remove(AGE);
+            }
+
+            AgeAccounting::EmitAge => {
+                headers.set_value(AGE, self.age(now).as_secs());
+            }
+
+            AgeAccounting::Both => {
+                headers.set_into_header_value(DATE, HttpDate::from(now));
+                headers.set_value(AGE, self.age(now).as_secs());
+            }
+        }
+    }
+
+    /// Create a `HEAD` [Response] from the stored headers.
+    ///
+    /// Works for both complete and validators-only entries. The `Content-Length` will be the
+    /// recorded one, if available.
+    pub fn to_head_response<BodyT>(&self) -> Response<BodyT>
+    where
+        BodyT: Body + From<ImmutableBytes>,
+    {
+        let mut parts = self.parts.clone();
+        parts.headers.remove(XX_ENCODE);
+        Response::from_parts(parts, ImmutableBytes::default().into())
     }
 }
 
@@ -233,8 +977,80 @@ impl CacheWeight for CachedResponse {
         }
         size += parts.extensions.len() * EXTENSION_ENTRY_SIZE;
 
+        for token in self.dependencies.iter() {
+            size += size_of::<Arc<str>>() + token.len();
+        }
+
         size += self.body.cache_weight();
 
         size
     }
 }
+
+// Truncate to whole seconds (the resolution of HTTP dates).
+fn whole_seconds(time: SystemTime) -> SystemTime {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs()),
+        Err(_) => time,
+    }
+}
+
+// Tags in the `XX-Cache-Tags` header, within the default dependency limits.
+fn cache_tags(headers: &HeaderMap) -> Arc<[Arc<str>]> {
+    let mut tags: Vec<Arc<str>> = Vec::default();
+
+    for tag in headers
+        .get_all(XX_CACHE_TAGS)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+    {
+        if tags.len() >= DEFAULT_MAX_DEPENDENCIES {
+            tracing::debug!("cache tag ignored (too many): {}", tag);
+            break;
+        }
+
+        if !tag.is_empty()
+            && tag.len() <= DEFAULT_MAX_DEPENDENCY_LENGTH
+            && !tags.iter().any(|existing| **existing == *tag)
+        {
+            tags.push(tag.into());
+        }
+    }
+
+    tags.into()
+}
+
+// Remove headers in a single pass that rebuilds the map once.
+//
+// If none of the headers are present (the common case) then we skip the pass entirely.
+fn remove_headers(headers: &mut HeaderMap, names: &[HeaderName]) {
+    if !names.iter().any(|name| headers.contains_key(name)) {
+        return;
+    }
+
+    let mut rebuilt = HeaderMap::with_capacity(headers.len());
+    let mut current_name: Option<HeaderName> = None;
+
+    // Note that drain yields the name only for the first value of each header
+    for (name, value) in headers.drain() {
+        if let Some(name) = name {
+            current_name = (!names.contains(&name)).then_some(name);
+        }
+
+        if let Some(name) = &current_name {
+            rebuilt.append(name, value);
+        }
+    }
+
+    *headers = rebuilt;
+}
+
+// Upstream `Age` header value.
+fn upstream_age(headers: &HeaderMap) -> Duration {
+    headers
+        .parse_value::<u64>(AGE)
+        .map(Duration::from_secs)
+        .unwrap_or_default()
+}
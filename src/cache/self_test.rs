@@ -0,0 +1,166 @@
+use super::{cache::*, coding::*, key::*, response::*};
+
+use {
+    http::{header::*, *},
+    std::{error::Error, fmt, result::Result, sync::*, time::*},
+};
+
+//
+// SelfTestReport
+//
+
+/// Report of a successful [Cache::self_test].
+#[derive(Clone, Debug, Default)]
+pub struct SelfTestReport {
+    /// Put latency.
+    pub put: Duration,
+
+    /// Get latency.
+    pub get: Duration,
+
+    /// Invalidate latency.
+    pub invalidate: Duration,
+
+    /// Reports of tiers, if the cache has them.
+    pub tiers: Vec<(String, SelfTestReport)>,
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            formatter,
+            "put {:?}, get {:?}, invalidate {:?}",
+            self.put, self.get, self.invalidate
+        )?;
+
+        for (name, tier) in &self.tiers {
+            write!(formatter, "; {}: ({})", name, tier)?;
+        }
+
+        Ok(())
+    }
+}
+
+//
+// SelfTestError
+//
+
+/// [Cache::self_test] error.
+#[derive(Clone, Debug)]
+pub struct SelfTestError {
+    /// The operation that failed.
+    pub operation: &'static str,
+
+    /// Message.
+    pub message: String,
+
+    /// The tier that failed, if the cache has tiers (e.g. "first.next").
+    pub tier: Option<String>,
+}
+
+impl SelfTestError {
+    /// Constructor.
+    pub fn new(operation: &'static str, message: impl ToString) -> Self {
+        Self {
+            operation,
+            message: message.to_string(),
+            tier: None,
+        }
+    }
+
+    /// Attribute to a tier.
+    pub fn in_tier(mut self, tier: &str) -> Self {
+        self.tier = Some(match self.tier {
+            Some(inner_tier) => format!("{}.{}", tier, inner_tier),
+            None => tier.into(),
+        });
+        self
+    }
+}
+
+impl fmt::Display for SelfTestError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(tier) = &self.tier {
+            write!(formatter, "{}: ", tier)?;
+        }
+        write!(formatter, "{}: {}", self.operation, self.message)
+    }
+}
+
+impl Error for SelfTestError {}
+
+/// Self-test via a put/get/invalidate round trip of a sentinel entry.
+///
+/// This is the default implementation of [Cache::self_test].
+///
+/// The sentinel has a unique key (under the `/.self-test/` path), no body, and a short duration,
+/// so that it would not linger even if the invalidation were to fail.
+pub async fn round_trip_self_test<CacheT, CacheKeyT>(
+    cache: &CacheT,
+) -> Result<SelfTestReport, SelfTestError>
+where
+    CacheT: Cache<CacheKeyT>,
+    CacheKeyT: CacheKey,
+{
+    let now = SystemTime::now();
+    let sentinel = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string();
+
+    let uri = Uri::try_from(format!("/.self-test/{}", sentinel))
+        .map_err(|error| SelfTestError::new("key", error))?;
+    let key = CacheKeyT::for_request(&Method::GET, &uri, &HeaderMap::default());
+
+    let (mut parts, _) = Response::new(()).into_parts();
+    let etag = HeaderValue::try_from(format!("\"{}\"", sentinel))
+        .map_err(|error| SelfTestError::new("key", error))?;
+    parts.headers.insert(ETAG, etag.clone());
+
+    let cached_response = CachedResponse {
+        parts,
+        body: Default::default(),
+        duration: Some(Duration::from_secs(10)),
+        created: now,
+        upstream_age: Duration::ZERO,
+        original_coding: CodingId::IDENTITY,
+        validators_only: false,
+        no_transform: false,
+        dependencies: Default::default(),
+        hits: Default::default(),
+    };
+
+    let start = Instant::now();
+    cache.put(key.clone(), Arc::new(cached_response)).await;
+    let put = start.elapsed();
+
+    let start = Instant::now();
+    let cached_response = cache.get(&key).await;
+    let get = start.elapsed();
+
+    match cached_response {
+        Some(cached_response) => {
+            if cached_response.headers().get(ETAG) != Some(&etag) {
+                return Err(SelfTestError::new("get", "got a different entry"));
+            }
+        }
+
+        None => return Err(SelfTestError::new("get", "entry not found after put")),
+    }
+
+    let start = Instant::now();
+    cache.invalidate(&key).await;
+    let invalidate = start.elapsed();
+
+    if cache.get(&key).await.is_some() {
+        return Err(SelfTestError::new("invalidate", "entry found after invalidate"));
+    }
+
+    Ok(SelfTestReport {
+        put,
+        get,
+        invalidate,
+        tiers: Default::default(),
+    })
+}
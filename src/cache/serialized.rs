@@ -0,0 +1,334 @@
+use super::{body::*, coding::*, response::*};
+
+use {
+    http::{header::*, *},
+    kutil::{
+        std::{collections::*, immutable::*},
+        transcoding::*,
+    },
+    std::{error::Error, fmt, result::Result, sync::*, time::*},
+};
+
+/// Version of the serialized entry format.
+///
+/// It is the first byte of every [serialized](CachedResponse::serialize) entry. Any change to
+/// the format must increment it. Entries with another version fail to deserialize (and are thus
+/// treated as misses by caches that store them).
+pub const SERIALIZED_ENTRY_VERSION: u8 = 2;
+
+const FLAG_VALIDATORS_ONLY: u8 = 1;
+const FLAG_NO_TRANSFORM: u8 = 2;
+const FLAG_DURATION: u8 = 4;
+
+const CODING_CUSTOM: u8 = 255;
+
+impl CachedResponse {
+    /// Serialize for caches that store bytes.
+    ///
+    /// Response extensions and [hits](Self::hits) are not serialized.
+    ///
+    /// The format (all integers big-endian, lengths as `u32`):
+    ///
+    /// 1. [SERIALIZED_ENTRY_VERSION]
+    /// 2. flags: validators only (1), no transform (2), has duration (4)
+    /// 3. status (`u16`) and HTTP version (`u8`)
+    /// 4. duration in milliseconds (`u64`), if it has one
+    /// 5. creation time as seconds (`u64`) and nanoseconds (`u32`) since the Unix epoch
+    /// 6. upstream age in milliseconds (`u64`)
+    /// 7. original coding
+    /// 8. headers: count, then a length-prefixed name and value for each
+    /// 9. representations: count, then a coding, [digest](CachedBody::digest) (`u64`), and
+    ///    length-prefixed bytes for each
+    /// 10. dependencies: count, then each length-prefixed
+    ///
+    /// A coding is a byte: 0 for identity, 1 for Brotli, 2 for Deflate, 3 for GZip, 4 for
+    /// Zstandard, or 255 followed by the length-prefixed token of a custom coding.
+    pub fn serialize(&self) -> Vec<u8> {
+        let body_size: usize = self.body.representations.values().map(|bytes| bytes.len()).sum();
+        let mut bytes = Vec::with_capacity(body_size + 256);
+
+        bytes.push(SERIALIZED_ENTRY_VERSION);
+
+        let mut flags = 0;
+        if self.validators_only {
+            flags |= FLAG_VALIDATORS_ONLY;
+        }
+        if self.no_transform {
+            flags |= FLAG_NO_TRANSFORM;
+        }
+        if self.duration.is_some() {
+            flags |= FLAG_DURATION;
+        }
+        bytes.push(flags);
+
+        bytes.extend_from_slice(&self.parts.status.as_u16().to_be_bytes());
+        bytes.push(version_to_byte(self.parts.version));
+
+        if let Some(duration) = self.duration {
+            write_millis(&mut bytes, duration);
+        }
+
+        let created = self.created.duration_since(UNIX_EPOCH).unwrap_or_default();
+        bytes.extend_from_slice(&created.as_secs().to_be_bytes());
+        bytes.extend_from_slice(&created.subsec_nanos().to_be_bytes());
+
+        write_millis(&mut bytes, self.upstream_age);
+
+        write_coding(&mut bytes, &self.original_coding);
+
+        write_length(&mut bytes, self.parts.headers.len());
+        for (name, value) in &self.parts.headers {
+            write_length_prefixed(&mut bytes, name.as_str().as_bytes());
+            write_length_prefixed(&mut bytes, value.as_bytes());
+        }
+
+        write_length(&mut bytes, self.body.representations.len());
+        for (coding, representation) in &self.body.representations {
+            write_coding(&mut bytes, coding);
+            let digest = self.body.digest(coding).expect("representation");
+            bytes.extend_from_slice(&digest.to_be_bytes());
+            write_length_prefixed(&mut bytes, representation);
+        }
+
+        write_length(&mut bytes, self.dependencies.len());
+        for dependency in self.dependencies.iter() {
+            write_length_prefixed(&mut bytes, dependency.as_bytes());
+        }
+
+        bytes
+    }
+
+    /// Deserialize an entry [serialized](Self::serialize) by this or another process.
+    ///
+    /// `custom_codings` are the tokens of the custom codings that we know. Representations in
+    /// other custom codings are dropped (it's an error if the original coding is unknown).
+    ///
+    /// Digests are not verified. That's safe because they are only used to find candidates for
+    /// sharing, which are then compared byte by byte.
+    pub fn deserialize(
+        bytes: &[u8],
+        custom_codings: &[&'static str],
+    ) -> Result<Self, DeserializeEntryError> {
+        let mut reader = Reader { bytes };
+
+        let version = reader.u8()?;
+        if version != SERIALIZED_ENTRY_VERSION {
+            return Err(DeserializeEntryError::new(format!("unsupported version: {}", version)));
+        }
+
+        let flags = reader.u8()?;
+
+        let (mut parts, _) = Response::new(()).into_parts();
+        parts.status = StatusCode::from_u16(reader.u16()?)
+            .map_err(|_| DeserializeEntryError::new("invalid status"))?;
+        parts.version = version_from_byte(reader.u8()?)?;
+
+        let duration = if flags & FLAG_DURATION != 0 {
+            Some(reader.millis()?)
+        } else {
+            None
+        };
+
+        let created = UNIX_EPOCH + Duration::new(reader.u64()?, reader.u32()?);
+        let upstream_age = reader.millis()?;
+
+        let original_coding = reader
+            .coding(custom_codings)?
+            .ok_or_else(|| DeserializeEntryError::new("unknown original coding"))?;
+
+        for _ in 0..reader.length()? {
+            let name = HeaderName::from_bytes(reader.length_prefixed()?)
+                .map_err(|_| DeserializeEntryError::new("invalid header name"))?;
+            let value = HeaderValue::from_bytes(reader.length_prefixed()?)
+                .map_err(|_| DeserializeEntryError::new("invalid header value"))?;
+            parts.headers.append(name, value);
+        }
+
+        let mut representations = FastHashMap::default();
+        let mut digests = FastHashMap::default();
+        for _ in 0..reader.length()? {
+            let coding = reader.coding(custom_codings)?;
+            let digest = reader.u64()?;
+            let representation = reader.length_prefixed()?;
+            if let Some(coding) = coding {
+                let representation = ImmutableBytes::from(representation.to_vec());
+                representations.insert(coding.clone(), representation);
+                digests.insert(coding, digest);
+            }
+        }
+
+        let mut dependencies = Vec::default();
+        for _ in 0..reader.length()? {
+            let dependency = std::str::from_utf8(reader.length_prefixed()?)
+                .map_err(|_| DeserializeEntryError::new("invalid dependency"))?;
+            dependencies.push(Arc::from(dependency));
+        }
+
+        if !reader.bytes.is_empty() {
+            return Err(DeserializeEntryError::new("trailing bytes"));
+        }
+
+        Ok(Self {
+            parts,
+            body: CachedBody::new_with_digests(representations, digests),
+            duration,
+            created,
+            upstream_age,
+            original_coding,
+            validators_only: flags & FLAG_VALIDATORS_ONLY != 0,
+            no_transform: flags & FLAG_NO_TRANSFORM != 0,
+            dependencies: dependencies.into(),
+            hits: Default::default(),
+        })
+    }
+}
+
+//
+// DeserializeEntryError
+//
+
+/// [CachedResponse::deserialize] error.
+#[derive(Clone, Debug)]
+pub struct DeserializeEntryError {
+    /// Message.
+    pub message: String,
+}
+
+impl DeserializeEntryError {
+    /// Constructor.
+    pub fn new(message: impl ToString) -> Self {
+        Self {
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for DeserializeEntryError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "deserialize entry: {}", self.message)
+    }
+}
+
+impl Error for DeserializeEntryError {}
+
+struct Reader<'this> {
+    bytes: &'this [u8],
+}
+
+impl<'this> Reader<'this> {
+    fn take(&mut self, length: usize) -> Result<&'this [u8], DeserializeEntryError> {
+        if self.bytes.len() < length {
+            return Err(DeserializeEntryError::new("truncated"));
+        }
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, DeserializeEntryError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, DeserializeEntryError> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().expect("2 bytes")))
+    }
+
+    fn u32(&mut self) -> Result<u32, DeserializeEntryError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().expect("4 bytes")))
+    }
+
+    fn u64(&mut self) -> Result<u64, DeserializeEntryError> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().expect("8 bytes")))
+    }
+
+    fn millis(&mut self) -> Result<Duration, DeserializeEntryError> {
+        Ok(Duration::from_millis(self.u64()?))
+    }
+
+    fn length(&mut self) -> Result<usize, DeserializeEntryError> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn length_prefixed(&mut self) -> Result<&'this [u8], DeserializeEntryError> {
+        let length = self.length()?;
+        self.take(length)
+    }
+
+    // [None] for unknown custom codings.
+    fn coding(
+        &mut self,
+        custom_codings: &[&'static str],
+    ) -> Result<Option<CodingId>, DeserializeEntryError> {
+        Ok(Some(match self.u8()? {
+            0 => CodingId::IDENTITY,
+            1 => Encoding::Brotli.into(),
+            2 => Encoding::Deflate.into(),
+            3 => Encoding::GZip.into(),
+            4 => Encoding::Zstandard.into(),
+
+            CODING_CUSTOM => {
+                let name = self.length_prefixed()?;
+                match custom_codings.iter().find(|custom_coding| custom_coding.as_bytes() == name) {
+                    Some(custom_coding) => CodingId::Custom(custom_coding),
+                    None => return Ok(None),
+                }
+            }
+
+            coding => {
+                return Err(DeserializeEntryError::new(format!("invalid coding: {}", coding)));
+            }
+        }))
+    }
+}
+
+fn write_coding(bytes: &mut Vec<u8>, coding: &CodingId) {
+    match coding {
+        CodingId::Builtin(encoding) => bytes.push(match encoding {
+            Encoding::Identity => 0,
+            Encoding::Brotli => 1,
+            Encoding::Deflate => 2,
+            Encoding::GZip => 3,
+            Encoding::Zstandard => 4,
+        }),
+
+        CodingId::Custom(name) => {
+            bytes.push(CODING_CUSTOM);
+            write_length_prefixed(bytes, name.as_bytes());
+        }
+    }
+}
+
+fn write_millis(bytes: &mut Vec<u8>, duration: Duration) {
+    let millis: u64 = duration.as_millis().try_into().unwrap_or(u64::MAX);
+    bytes.extend_from_slice(&millis.to_be_bytes());
+}
+
+fn write_length(bytes: &mut Vec<u8>, length: usize) {
+    bytes.extend_from_slice(&(length as u32).to_be_bytes());
+}
+
+fn write_length_prefixed(bytes: &mut Vec<u8>, value: &[u8]) {
+    write_length(bytes, value.len());
+    bytes.extend_from_slice(value);
+}
+
+fn version_to_byte(version: Version) -> u8 {
+    match version {
+        Version::HTTP_09 => 9,
+        Version::HTTP_10 => 10,
+        Version::HTTP_2 => 20,
+        Version::HTTP_3 => 30,
+        _ => 11,
+    }
+}
+
+fn version_from_byte(byte: u8) -> Result<Version, DeserializeEntryError> {
+    Ok(match byte {
+        9 => Version::HTTP_09,
+        10 => Version::HTTP_10,
+        11 => Version::HTTP_11,
+        20 => Version::HTTP_2,
+        30 => Version::HTTP_3,
+        _ => return Err(DeserializeEntryError::new("invalid HTTP version")),
+    })
+}
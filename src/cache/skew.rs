@@ -0,0 +1,105 @@
+use super::{cache::*, coding::*, configuration::*, key::*};
+
+use {
+    kutil::std::collections::*,
+    std::fmt,
+};
+
+//
+// RepresentationSkew
+//
+
+/// How far the stored representations are from the enabled codings.
+///
+/// Changing the enabled codings leaves existing entries behind. Representations in codings that
+/// were disabled are never served, but they take up room until their entries expire or are
+/// pruned (see `prune_disabled_representations`). Codings that were enabled are added lazily on
+/// hits, or in advance by a [ReencodeJob](super::reencode::ReencodeJob).
+///
+/// Entries that have validators only or that are
+/// [no_transform](super::CachedResponse::no_transform) are not counted, because neither applies to
+/// them.
+#[derive(Clone, Debug, Default)]
+pub struct RepresentationSkew {
+    /// Entries scanned.
+    pub entries: u64,
+
+    /// Entries with at least one representation in a disabled coding.
+    pub skewed_entries: u64,
+
+    /// Number of entries with a representation in each disabled coding.
+    pub disabled: FastHashMap<CodingId, u64>,
+
+    /// Number of entries without a representation in each enabled coding.
+    pub missing: FastHashMap<CodingId, u64>,
+}
+
+impl RepresentationSkew {
+    /// Scan the cache.
+    ///
+    /// Requires a cache that supports [keys](Cache::keys), otherwise returns [None]. Note that
+    /// reading the entries might affect the cache's eviction order.
+    pub async fn scan<CacheT, CacheKeyT>(
+        cache: &CacheT,
+        configuration: &EncodingConfiguration,
+    ) -> Option<Self>
+    where
+        CacheT: Cache<CacheKeyT>,
+        CacheKeyT: CacheKey,
+    {
+        let keys = cache.keys()?;
+        let mut skew = Self::default();
+
+        for key in keys {
+            let Some(cached_response) = cache.get(&key).await else {
+                continue;
+            };
+
+            if cached_response.validators_only || cached_response.no_transform {
+                continue;
+            }
+
+            skew.entries += 1;
+
+            let representations = &cached_response.body.representations;
+
+            let mut skewed = false;
+            for coding in representations.keys() {
+                if !configuration.is_enabled(coding) {
+                    *skew.disabled.entry(coding.clone()).or_default() += 1;
+                    skewed = true;
+                }
+            }
+
+            if skewed {
+                skew.skewed_entries += 1;
+            }
+
+            if let Some(enabled_codings) = &configuration.enabled_codings {
+                for coding in enabled_codings.iter() {
+                    if !representations.contains_key(coding) {
+                        *skew.missing.entry(coding.clone()).or_default() += 1;
+                    }
+                }
+            }
+        }
+
+        Some(skew)
+    }
+}
+
+impl fmt::Display for RepresentationSkew {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{} of {} entries skewed", self.skewed_entries, self.entries)?;
+
+        for (coding, count) in &self.disabled {
+            write!(formatter, ", {} disabled: {}", coding, count)?;
+        }
+
+        for (coding, count) in &self.missing {
+            write!(formatter, ", {} missing: {}", coding, count)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,274 @@
+use super::{cache::*, invalidation::*, key::*, response::*, self_test::*};
+
+use {
+    kutil::std::collections::*,
+    std::{sync::*, time::*},
+};
+
+/// Default maximum number of recent writes tracked by [SplitCache].
+pub const DEFAULT_RECENT_WRITES: usize = 4096;
+
+//
+// SplitCache
+//
+
+/// [Cache] with separate handles for reading and writing, e.g. a read replica and a primary.
+///
+/// [get](Cache::get) and the introspection functions ([keys](Cache::keys),
+/// [entry_count](Cache::entry_count), and [weighted_size](Cache::weighted_size)) go to the read
+/// cache. Everything else goes to the write cache.
+///
+/// Replicas lag behind their primary, so an entry we just stored might not be readable yet, which
+/// would cause duplicate upstream work and duplicate puts. With
+/// [read_your_writes](Self::read_your_writes), keys written through this wrapper within the
+/// window are read from the write cache instead. Invalidations within the window are remembered
+/// too (unless disabled via [remember_invalidations](Self::remember_invalidations)), so that an
+/// invalidated entry isn't resurrected by a stale replica.
+///
+/// The number of tracked keys is bounded. When the bound is reached, expired keys are forgotten,
+/// and if that's not enough then new keys are not tracked. Note that
+/// [invalidate_all](Cache::invalidate_all) forgets all tracked keys.
+///
+/// The self-test only tests the write cache, as the read cache might not accept puts.
+#[derive(Clone, Debug)]
+pub struct SplitCache<ReadCacheT, WriteCacheT, CacheKeyT = CommonCacheKey> {
+    /// Read cache.
+    pub read: ReadCacheT,
+
+    /// Write cache.
+    pub write: WriteCacheT,
+
+    /// Window for reading recently written keys from the write cache.
+    pub read_your_writes: Option<Duration>,
+
+    /// Whether to remember invalidations within the window.
+    pub remember_invalidations: bool,
+
+    /// How keys are rendered in logs.
+    pub key_log_policy: KeyLogPolicy,
+
+    recent: Arc<RecentWrites<CacheKeyT>>,
+}
+
+impl<ReadCacheT, WriteCacheT, CacheKeyT> SplitCache<ReadCacheT, WriteCacheT, CacheKeyT> {
+    /// Constructor.
+    pub fn new(read: ReadCacheT, write: WriteCacheT) -> Self {
+        Self::new_with_capacity(read, write, DEFAULT_RECENT_WRITES)
+    }
+
+    /// Constructor.
+    ///
+    /// `capacity` is the maximum number of recent writes to track.
+    pub fn new_with_capacity(read: ReadCacheT, write: WriteCacheT, capacity: usize) -> Self {
+        Self {
+            read,
+            write,
+            read_your_writes: None,
+            remember_invalidations: true,
+            key_log_policy: Default::default(),
+            recent: Arc::new(RecentWrites {
+                writes: Mutex::new(FastHashMap::default()),
+                capacity,
+            }),
+        }
+    }
+
+    /// Set window for reading recently written keys from the write cache.
+    pub fn read_your_writes(mut self, read_your_writes: Duration) -> Self {
+        self.read_your_writes = Some(read_your_writes);
+        self
+    }
+
+    /// Set whether to remember invalidations within the window.
+    pub fn remember_invalidations(mut self, remember_invalidations: bool) -> Self {
+        self.remember_invalidations = remember_invalidations;
+        self
+    }
+
+    /// Set key log policy.
+    pub fn with_key_log_policy(mut self, key_log_policy: KeyLogPolicy) -> Self {
+        self.key_log_policy = key_log_policy;
+        self
+    }
+}
+
+impl<ReadCacheT, WriteCacheT, CacheKeyT> SplitCache<ReadCacheT, WriteCacheT, CacheKeyT>
+where
+    CacheKeyT: CacheKey,
+{
+    fn record(&self, key: &CacheKeyT, invalidated: bool) {
+        if let Some(window) = self.read_your_writes
+            && !self.recent.record(key, invalidated, window)
+        {
+            tracing::debug!(
+                "recent write not tracked (too many): {}",
+                key.display_for_logs(&self.key_log_policy)
+            );
+        }
+    }
+}
+
+impl<ReadCacheT, WriteCacheT, CacheKeyT> Cache<CacheKeyT>
+    for SplitCache<ReadCacheT, WriteCacheT, CacheKeyT>
+where
+    ReadCacheT: Cache<CacheKeyT>,
+    WriteCacheT: Cache<CacheKeyT>,
+    CacheKeyT: CacheKey,
+{
+    async fn get(&self, key: &CacheKeyT) -> Option<CachedResponseRef> {
+        let recent = self
+            .read_your_writes
+            .and_then(|window| self.recent.get(key, window));
+
+        match recent {
+            Some(RecentWrite::Put) => {
+                tracing::debug!(
+                    "reading recent write: {}",
+                    key.display_for_logs(&self.key_log_policy)
+                );
+                self.write.get(key).await
+            }
+
+            Some(RecentWrite::Invalidated) => {
+                tracing::debug!(
+                    "recently invalidated: {}",
+                    key.display_for_logs(&self.key_log_policy)
+                );
+                None
+            }
+
+            None => self.read.get(key).await,
+        }
+    }
+
+    async fn put(&self, key: CacheKeyT, cached_response: CachedResponseRef) {
+        self.write.put(key.clone(), cached_response).await;
+        self.record(&key, false);
+    }
+
+    fn fence(&self, key: &CacheKeyT) -> Fence {
+        self.write.fence(key)
+    }
+
+    async fn put_fenced(
+        &self,
+        key: CacheKeyT,
+        cached_response: CachedResponseRef,
+        fence: Fence,
+    ) -> PutOutcome {
+        let outcome = self.write.put_fenced(key.clone(), cached_response, fence).await;
+        if outcome == PutOutcome::Stored {
+            self.record(&key, false);
+        }
+        outcome
+    }
+
+    async fn update<UpdateT>(&self, key: CacheKeyT, update: UpdateT) -> bool
+    where
+        UpdateT: FnOnce(CachedResponseRef) -> Option<CachedResponseRef> + Send,
+    {
+        let updated = self.write.update(key.clone(), update).await;
+        if updated {
+            self.record(&key, false);
+        }
+        updated
+    }
+
+    async fn invalidate(&self, key: &CacheKeyT) {
+        self.write.invalidate(key).await;
+        if self.remember_invalidations {
+            self.record(key, true);
+        } else {
+            self.recent.forget(key);
+        }
+    }
+
+    async fn invalidate_all(&self) {
+        self.write.invalidate_all().await;
+        self.recent.clear();
+    }
+
+    async fn invalidate_all_with_outcome(&self) -> InvalidationOutcome {
+        let outcome = self.write.invalidate_all_with_outcome().await;
+        self.recent.clear();
+        outcome
+    }
+
+    async fn self_test(&self) -> Result<SelfTestReport, SelfTestError> {
+        self.write.self_test().await.map_err(|error| error.in_tier("write"))
+    }
+
+    fn entry_count(&self) -> Option<u64> {
+        self.read.entry_count()
+    }
+
+    fn weighted_size(&self) -> Option<u64> {
+        self.read.weighted_size()
+    }
+
+    fn keys(&self) -> Option<Vec<CacheKeyT>> {
+        self.read.keys()
+    }
+}
+
+//
+// RecentWrites
+//
+
+#[derive(Debug)]
+struct RecentWrites<CacheKeyT> {
+    writes: Mutex<FastHashMap<CacheKeyT, (RecentWrite, Instant)>>,
+    capacity: usize,
+}
+
+impl<CacheKeyT> RecentWrites<CacheKeyT>
+where
+    CacheKeyT: CacheKey,
+{
+    fn get(&self, key: &CacheKeyT, window: Duration) -> Option<RecentWrite> {
+        let mut writes = self.writes.lock().expect("lock");
+        let (recent_write, written) = writes.get(key)?;
+
+        if written.elapsed() < window {
+            Some(*recent_write)
+        } else {
+            writes.remove(key);
+            None
+        }
+    }
+
+    // Returns false if not tracked.
+    fn record(&self, key: &CacheKeyT, invalidated: bool, window: Duration) -> bool {
+        let mut writes = self.writes.lock().expect("lock");
+
+        if !writes.contains_key(key) && writes.len() >= self.capacity {
+            writes.retain(|_key, (_recent_write, written)| written.elapsed() < window);
+            if writes.len() >= self.capacity {
+                return false;
+            }
+        }
+
+        let recent_write = if invalidated {
+            RecentWrite::Invalidated
+        } else {
+            RecentWrite::Put
+        };
+
+        writes.insert(key.clone(), (recent_write, Instant::now()));
+        true
+    }
+
+    fn forget(&self, key: &CacheKeyT) {
+        self.writes.lock().expect("lock").remove(key);
+    }
+
+    fn clear(&self) {
+        self.writes.lock().expect("lock").clear();
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum RecentWrite {
+    Put,
+    Invalidated,
+}
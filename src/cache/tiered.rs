@@ -1,4 +1,13 @@
-use super::{cache::*, key::*, response::*};
+use super::{
+    cache::*, invalidation::*, join::*, key::*, preload::*, response::*, self_test::*,
+    tiered_policy::*, weight::*,
+};
+
+use std::{
+    cmp::Reverse,
+    sync::{atomic::*, *},
+    time::*,
+};
 
 //
 // TieredCache
@@ -9,6 +18,19 @@ use super::{cache::*, key::*, response::*};
 /// The assumption is that the first cache is faster than the next.
 ///
 /// For more tiers you can chain this type.
+///
+/// [update](Cache::update) and [keys](Cache::keys) apply to the first cache, with successful
+/// updates copied to the next cache (regardless of the write policy).
+///
+/// When putting an entry that replaces one in the first cache, representations that are
+/// identical to the replaced entry's share its bytes, so that we don't keep duplicate copies
+/// (e.g. when an expired entry is fetched again with unchanged content). This only happens if
+/// the first cache can answer [get_if_present](Cache::get_if_present), which unlike
+/// [get](Cache::get) doesn't count as an access (e.g. it doesn't for Moka). The next cache is
+/// always written in full.
+///
+/// By default a hit in the next cache is not promoted into the first cache, and puts go to both
+/// caches. See [TieredCachePolicy].
 #[derive(Clone, Debug)]
 pub struct TieredCache<FirstCacheT, NextCacheT> {
     /// First cache.
@@ -16,12 +38,214 @@ pub struct TieredCache<FirstCacheT, NextCacheT> {
 
     /// Next cache.
     pub next: NextCacheT,
+
+    /// How keys are rendered in logs.
+    pub key_log_policy: KeyLogPolicy,
+
+    /// Promotion and write policy.
+    pub policy: TieredCachePolicy,
 }
 
 impl<FirstCacheT, NextCacheT> TieredCache<FirstCacheT, NextCacheT> {
     /// Constructor.
     pub fn new(first: FirstCacheT, next: NextCacheT) -> Self {
-        Self { first, next }
+        Self {
+            first,
+            next,
+            key_log_policy: Default::default(),
+            policy: Default::default(),
+        }
+    }
+
+    /// Set policy.
+    pub fn with_policy(mut self, policy: TieredCachePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Set key log policy.
+    pub fn with_key_log_policy(mut self, key_log_policy: KeyLogPolicy) -> Self {
+        self.key_log_policy = key_log_policy;
+        self
+    }
+}
+
+impl<FirstCacheT, NextCacheT> TieredCache<FirstCacheT, NextCacheT> {
+    /// Load entries from the next cache into the first cache.
+    ///
+    /// Call it on boot, before serving (or concurrently with a readiness probe), so that the
+    /// first requests after a deploy don't all pay for a round trip to the next cache.
+    ///
+    /// Entries that are already in the first cache, expired entries, and entries that would
+    /// exceed the byte budget are skipped. Loaded entries keep their creation time, so with a
+    /// cache implementation that honors it (as Moka's `CachedResponseExpiry` does) they expire
+    /// when they would have in the next cache.
+    pub async fn preload_first_tier<CacheKeyT>(
+        &self,
+        configuration: PreloadConfig<CacheKeyT>,
+    ) -> PreloadReport
+    where
+        CacheKeyT: CacheKey,
+        FirstCacheT: Cache<CacheKeyT>,
+        NextCacheT: Cache<CacheKeyT>,
+    {
+        let start = Instant::now();
+        let mut report = PreloadReport::default();
+        let mut budget = configuration.byte_budget;
+        let by_popularity = matches!(configuration.key_source, PreloadKeySource::Popular);
+
+        let (keys, in_order) = match configuration.key_source {
+            PreloadKeySource::Manifest(keys) => (keys, true),
+
+            PreloadKeySource::Popular | PreloadKeySource::RecentlyWritten => {
+                let keys = self.next.keys().unwrap_or_else(|| {
+                    tracing::warn!(
+                        "next cache does not support key enumeration; nothing to preload"
+                    );
+                    Default::default()
+                });
+                (keys, false)
+            }
+        };
+
+        // Entries to be sorted before loading (not needed for manifests, which are in order)
+        let mut candidates = Vec::default();
+
+        let mut keys = keys.into_iter().peekable();
+        while keys.peek().is_some() && budget != 0 {
+            if let Some(deadline) = configuration.deadline
+                && start.elapsed() >= deadline
+            {
+                report.deadline_reached = true;
+                break;
+            }
+
+            let batch: Vec<_> = keys
+                .by_ref()
+                .take(configuration.concurrency.max(1))
+                .map(|key| async move {
+                    if self.first.get(&key).await.is_some() {
+                        return (key, None, true);
+                    }
+
+                    let cached_response = self.next.get(&key).await;
+                    (key, cached_response, false)
+                })
+                .collect();
+
+            for (key, cached_response, present) in join_all(batch).await {
+                match cached_response {
+                    Some(cached_response) => {
+                        if in_order {
+                            self.preload(key, cached_response, &mut budget, &mut report).await;
+                        } else {
+                            candidates.push((key, cached_response));
+                        }
+                    }
+
+                    None => {
+                        if present {
+                            report.present += 1;
+                        } else {
+                            report.missing += 1;
+                        }
+                    }
+                }
+            }
+        }
+
+        if !candidates.is_empty() {
+            if by_popularity {
+                candidates.sort_by_key(|(_key, cached_response)| {
+                    Reverse(cached_response.hits.load(Ordering::Relaxed))
+                });
+            } else {
+                candidates
+                    .sort_by_key(|(_key, cached_response)| Reverse(cached_response.created));
+            }
+
+            for (key, cached_response) in candidates {
+                if budget == 0 {
+                    break;
+                }
+                self.preload(key, cached_response, &mut budget, &mut report).await;
+            }
+        }
+
+        report.duration = start.elapsed();
+        tracing::info!("preloaded first tier: {}", report);
+        report
+    }
+
+    /// The keys of the first cache, from the most to the least [hit](CachedResponse::hits), up
+    /// to a maximum.
+    ///
+    /// Call it on graceful shutdown and persist the keys, so that the next process can preload
+    /// them via [PreloadKeySource::Manifest].
+    ///
+    /// Returns [None] if the first cache doesn't support [keys](Cache::keys).
+    pub async fn export_manifest<CacheKeyT>(&self, max: usize) -> Option<Vec<CacheKeyT>>
+    where
+        CacheKeyT: CacheKey,
+        FirstCacheT: Cache<CacheKeyT>,
+    {
+        let mut entries = Vec::default();
+        for key in self.first.keys()? {
+            if let Some(cached_response) = self.first.get(&key).await {
+                entries.push((cached_response.hits.load(Ordering::Relaxed), key));
+            }
+        }
+
+        entries.sort_by_key(|(hits, _key)| Reverse(*hits));
+        Some(entries.into_iter().take(max).map(|(_hits, key)| key).collect())
+    }
+
+    // Load an entry into the first cache if it is fresh and fits within the budget.
+    async fn preload<CacheKeyT>(
+        &self,
+        key: CacheKeyT,
+        cached_response: CachedResponseRef,
+        budget: &mut usize,
+        report: &mut PreloadReport,
+    ) where
+        CacheKeyT: CacheKey,
+        FirstCacheT: Cache<CacheKeyT>,
+    {
+        if cached_response.is_expired(SystemTime::now()) {
+            report.expired += 1;
+            return;
+        }
+
+        let weight = Self::weight(&key, &cached_response);
+        if weight > *budget {
+            report.over_budget += 1;
+            return;
+        }
+
+        tracing::debug!("preloading: {}", key.display_for_logs(&self.key_log_policy));
+        self.first.put(key, cached_response).await;
+
+        *budget -= weight;
+        report.loaded += 1;
+        report.bytes += weight as u64;
+    }
+
+    // Whether a hit in the next cache should be written into the first cache.
+    fn promotes<CacheKeyT>(&self, key: &CacheKeyT, cached_response: &CachedResponse) -> bool
+    where
+        CacheKeyT: CacheKey,
+    {
+        self.policy.promote
+            && !cached_response.is_expired(SystemTime::now())
+            && self.policy.write_for(Self::weight(key, cached_response), cached_response).to_first()
+    }
+
+    // Weight of an entry, including its key.
+    fn weight<CacheKeyT>(key: &CacheKeyT, cached_response: &CachedResponse) -> usize
+    where
+        CacheKeyT: CacheKey,
+    {
+        key.cache_weight() + cached_response.cache_weight()
     }
 }
 
@@ -32,15 +256,77 @@ where
     NextCacheT: Cache<CacheKeyT>,
 {
     async fn get(&self, key: &CacheKeyT) -> Option<CachedResponseRef> {
-        match self.first.get(key).await {
-            Some(cached_response) => Some(cached_response),
-            None => self.next.get(key).await,
+        if let Some(cached_response) = self.first.get(key).await {
+            return Some(cached_response);
+        }
+
+        let cached_response = self.next.get(key).await?;
+
+        if self.promotes(key, &cached_response) {
+            tracing::debug!("promoting: {}", key.display_for_logs(&self.key_log_policy));
+            self.first.put(key.clone(), cached_response.clone()).await;
+        }
+
+        Some(cached_response)
+    }
+
+    fn get_if_present(&self, key: &CacheKeyT) -> Option<Option<CachedResponseRef>> {
+        // Only a hit in the first tier is authoritative
+        match self.first.get_if_present(key) {
+            Some(Some(cached_response)) => Some(Some(cached_response)),
+            _ => None,
         }
     }
 
     async fn put(&self, key: CacheKeyT, cached_response: CachedResponseRef) {
+        let write = self.policy.write_for(Self::weight(&key, &cached_response), &cached_response);
+
+        if !write.to_first() {
+            self.first.invalidate(&key).await;
+            return self.next.put(key, cached_response).await;
+        }
+
+        // Not get, which would count as a hit
+        let cached_response = match self.first.get_if_present(&key) {
+            Some(Some(existing)) if !Arc::ptr_eq(&existing, &cached_response) => {
+                let mut body = cached_response.body.clone();
+                if body.share_representations_with(&existing.body) {
+                    tracing::debug!(
+                        "sharing representations with replaced entry: {}",
+                        key.display_for_logs(&self.key_log_policy)
+                    );
+                    Arc::new(cached_response.clone_with_body(body))
+                } else {
+                    cached_response
+                }
+            }
+
+            _ => cached_response,
+        };
+
         self.first.put(key.clone(), cached_response.clone()).await;
-        self.next.put(key, cached_response).await
+
+        if write.to_next() {
+            self.next.put(key, cached_response).await
+        } else {
+            self.next.invalidate(&key).await
+        }
+    }
+
+    async fn update<UpdateT>(&self, key: CacheKeyT, update: UpdateT) -> bool
+    where
+        UpdateT: FnOnce(CachedResponseRef) -> Option<CachedResponseRef> + Send,
+    {
+        // The first cache decides; the next one just follows
+        if !self.first.update(key.clone(), update).await {
+            return false;
+        }
+
+        if let Some(cached_response) = self.first.get(&key).await {
+            self.next.put(key, cached_response).await;
+        }
+
+        true
     }
 
     async fn invalidate(&self, key: &CacheKeyT) {
@@ -52,4 +338,40 @@ where
         self.first.invalidate_all().await;
         self.next.invalidate_all().await
     }
+
+    async fn invalidate_all_with_outcome(&self) -> InvalidationOutcome {
+        // A failing tier must not prevent invalidating the other
+        let first = self.first.invalidate_all_with_outcome().await;
+        let next = self.next.invalidate_all_with_outcome().await;
+        InvalidationOutcome::tiered(vec![("first".into(), first), ("next".into(), next)])
+    }
+
+    async fn invalidate_matching<PredicateT>(&self, predicate: PredicateT) -> InvalidationOutcome
+    where
+        PredicateT: 'static + Fn(&CacheKeyT) -> bool + Send + Sync,
+    {
+        let predicate = Arc::new(predicate);
+        let next_predicate = predicate.clone();
+
+        // A failing tier must not prevent invalidating the other
+        let first = self.first.invalidate_matching(move |key: &CacheKeyT| predicate(key)).await;
+        let next = self.next.invalidate_matching(move |key: &CacheKeyT| next_predicate(key)).await;
+        InvalidationOutcome::tiered(vec![("first".into(), first), ("next".into(), next)])
+    }
+
+    fn keys(&self) -> Option<Vec<CacheKeyT>> {
+        self.first.keys()
+    }
+
+    async fn self_test(&self) -> Result<SelfTestReport, SelfTestError> {
+        let first = self.first.self_test().await.map_err(|error| error.in_tier("first"))?;
+        let next = self.next.self_test().await.map_err(|error| error.in_tier("next"))?;
+
+        Ok(SelfTestReport {
+            put: first.put + next.put,
+            get: first.get + next.get,
+            invalidate: first.invalidate + next.invalidate,
+            tiers: vec![("first".into(), first), ("next".into(), next)],
+        })
+    }
 }
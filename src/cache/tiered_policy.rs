@@ -0,0 +1,117 @@
+use super::response::*;
+
+use std::{fmt, sync::*};
+
+/// Tier hook for [TieredCachePolicy].
+///
+/// Receives the weight of the entry (including its key) and the entry. Returns [None] to use
+/// the policy's [write](TieredCachePolicy::write).
+pub type TieredWriteHook =
+    Arc<Box<dyn Fn(usize, &CachedResponse) -> Option<TieredWrite> + Send + Sync>>;
+
+//
+// TieredCachePolicy
+//
+
+/// Policy for a [TieredCache](super::TieredCache).
+///
+/// The default preserves the behavior of plain tiers: no promotion, and puts go to both tiers.
+#[derive(Clone, Default)]
+pub struct TieredCachePolicy {
+    /// Whether a hit in the next cache is written into the first cache.
+    ///
+    /// Expired entries and entries that the policy wouldn't write to the first cache are not
+    /// promoted.
+    pub promote: bool,
+
+    /// Which tiers receive puts.
+    pub write: TieredWrite,
+
+    /// Optional per-entry override of [write](Self::write).
+    pub write_hook: Option<TieredWriteHook>,
+}
+
+impl TieredCachePolicy {
+    /// Set promotion.
+    pub fn with_promotion(mut self, promote: bool) -> Self {
+        self.promote = promote;
+        self
+    }
+
+    /// Set write.
+    pub fn with_write(mut self, write: TieredWrite) -> Self {
+        self.write = write;
+        self
+    }
+
+    /// Set write hook.
+    pub fn with_write_hook(
+        mut self,
+        write_hook: impl Fn(usize, &CachedResponse) -> Option<TieredWrite> + 'static + Send + Sync,
+    ) -> Self {
+        self.write_hook = Some(Arc::new(Box::new(write_hook)));
+        self
+    }
+
+    /// Write entries that weigh more than a maximum (including their key) only to the next
+    /// cache, e.g. to keep large bodies out of a small memory tier.
+    ///
+    /// Replaces the write hook.
+    pub fn with_first_tier_max_weight(self, max_weight: usize) -> Self {
+        self.with_write_hook(move |weight, _cached_response| {
+            (weight > max_weight).then_some(TieredWrite::NextOnly)
+        })
+    }
+
+    /// Which tiers receive an entry.
+    pub fn write_for(&self, weight: usize, cached_response: &CachedResponse) -> TieredWrite {
+        self.write_hook
+            .as_ref()
+            .and_then(|write_hook| write_hook(weight, cached_response))
+            .unwrap_or(self.write)
+    }
+}
+
+impl fmt::Debug for TieredCachePolicy {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("TieredCachePolicy")
+            .field("promote", &self.promote)
+            .field("write", &self.write)
+            .field("write_hook", &self.write_hook.is_some())
+            .finish()
+    }
+}
+
+//
+// TieredWrite
+//
+
+/// Which tiers of a [TieredCache](super::TieredCache) receive a put.
+///
+/// When only one tier is written, the key is invalidated in the other, so that it can't serve
+/// an older version.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum TieredWrite {
+    /// Both caches (write-through).
+    #[default]
+    Both,
+
+    /// Only the first cache.
+    FirstOnly,
+
+    /// Only the next cache.
+    NextOnly,
+}
+
+impl TieredWrite {
+    /// Whether the first cache is written.
+    pub fn to_first(self) -> bool {
+        !matches!(self, Self::NextOnly)
+    }
+
+    /// Whether the next cache is written.
+    pub fn to_next(self) -> bool {
+        !matches!(self, Self::FirstOnly)
+    }
+}
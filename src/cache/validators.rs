@@ -0,0 +1,188 @@
+use super::coding::*;
+
+use {
+    http::header::*,
+    kutil::http::*,
+};
+
+/// `Repr-Digest` header name.
+///
+/// See [IETF RFC 9530](https://datatracker.ietf.org/doc/html/rfc9530).
+pub const REPR_DIGEST: HeaderName = HeaderName::from_static("repr-digest");
+
+/// Legacy `Digest` header name.
+///
+/// See [IETF RFC 3230](https://datatracker.ietf.org/doc/html/rfc3230).
+pub const DIGEST: HeaderName = HeaderName::from_static("digest");
+
+/// `XX-No-Synthetic-Validators` header name.
+///
+/// A response with this set to "true" will not be given synthetic validators when stored. See
+/// [SyntheticLastModified](super::configuration::SyntheticLastModified).
+pub const XX_NO_SYNTHETIC_VALIDATORS: HeaderName =
+    HeaderName::from_static("xx-no-synthetic-validators");
+
+/// Response headers that a `304 Not Modified` must have if the `200 OK` would have had them, plus
+/// `Last-Modified`, which lets clients update their stored metadata.
+///
+/// Content headers (e.g. `Content-Length` and `Content-Encoding`) are never included.
+///
+/// See
+/// [IETF RFC 9110 section 15.4.5](https://datatracker.ietf.org/doc/html/rfc9110#section-15.4.5).
+pub const NOT_MODIFIED_HEADERS: &[HeaderName] =
+    &[CACHE_CONTROL, CONTENT_LOCATION, DATE, ETAG, EXPIRES, LAST_MODIFIED, VARY];
+
+/// Whether a cached response is modified according to the conditional request headers.
+///
+/// `If-None-Match` takes precedence: when present, `If-Modified-Since` is ignored. It matches if
+/// it is `*` or if any of its tags matches the `ETag` by weak comparison (i.e. ignoring the `W/`
+/// prefix). See
+/// [IETF RFC 9110 section 13.1.2](https://datatracker.ietf.org/doc/html/rfc9110#section-13.1.2).
+///
+/// If there is not enough information we assume that it is modified.
+pub fn modified_weak(request_headers: &HeaderMap, response_headers: &HeaderMap) -> bool {
+    if !request_headers.contains_key(IF_NONE_MATCH) {
+        return modified(request_headers, response_headers);
+    }
+
+    let etag = response_headers
+        .string_value(ETAG)
+        .map(|etag| opaque_tag(etag.trim()));
+
+    let matches = request_headers.string_values(IF_NONE_MATCH).iter().any(|value| {
+        value.split(',').map(|tag| tag.trim()).any(|tag| {
+            tag == "*" || etag.is_some_and(|etag| !etag.is_empty() && opaque_tag(tag) == etag)
+        })
+    });
+
+    if matches {
+        tracing::debug!("not modified ({})", IF_NONE_MATCH);
+    }
+
+    !matches
+}
+
+//
+// OnTheFlyValidatorPolicy
+//
+
+/// What to do with the `ETag` of a response when we serve it in a coding other than the one in
+/// which it was produced, whether by on-the-fly encoding or from a cached entry in a non-original
+/// coding.
+///
+/// The upstream `ETag` describes the original representation. Without this, downstream caches
+/// would associate it with every coding we serve, which can cause confusing revalidation
+/// mismatches.
+///
+/// Regardless of the policy, `Content-Digest` (and the legacy `Digest`) are removed from
+/// transformed responses because they describe the bytes of the content. `Repr-Digest` is kept:
+/// per [IETF RFC 9530](https://datatracker.ietf.org/doc/html/rfc9530) it describes the selected
+/// representation independently of how its content is transferred.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum OnTheFlyValidatorPolicy {
+    /// Convert a strong `ETag` to a weak one.
+    Weaken,
+
+    /// Suffix the `ETag` with the coding, e.g. `"abc"` becomes `"abc-br"`, so that each
+    /// representation has a distinct validator.
+    ///
+    /// Suffixed tags in `If-None-Match` are recognized when checking our cached entries.
+    #[default]
+    Suffix,
+
+    /// Remove the `ETag`.
+    Strip,
+}
+
+impl OnTheFlyValidatorPolicy {
+    /// Apply to the headers of a transformed response.
+    pub fn apply(&self, headers: &mut HeaderMap, coding: &CodingId) {
+        headers.remove(CONTENT_DIGEST);
+        headers.remove(DIGEST);
+
+        let Some(etag) = headers.string_value(ETAG).map(String::from) else {
+            return;
+        };
+
+        let etag = match self {
+            Self::Weaken => {
+                if etag.starts_with("W/") {
+                    return;
+                }
+                format!("W/{}", etag)
+            }
+
+            Self::Suffix => match etag.strip_suffix('"') {
+                Some(etag) => format!("{}-{}\"", etag, coding),
+                None => return,
+            },
+
+            Self::Strip => {
+                headers.remove(ETAG);
+                return;
+            }
+        };
+
+        match HeaderValue::from_str(&etag) {
+            Ok(etag) => {
+                headers.insert(ETAG, etag);
+            }
+
+            Err(_) => {
+                headers.remove(ETAG);
+            }
+        }
+    }
+
+    /// Remove coding suffixes from the tags in `If-None-Match` request headers.
+    ///
+    /// Only relevant for [Suffix](Self::Suffix).
+    pub fn unsuffix_if_none_match(
+        &self,
+        headers: &mut HeaderMap,
+        custom: &[&'static str],
+        builtin: &[EncodingHeaderValue],
+    ) {
+        if *self != Self::Suffix || !headers.contains_key(IF_NONE_MATCH) {
+            return;
+        }
+
+        let values: Vec<_> = headers
+            .string_values(IF_NONE_MATCH)
+            .into_iter()
+            .map(String::from)
+            .collect();
+        headers.remove(IF_NONE_MATCH);
+
+        for value in values {
+            let value: Vec<_> = value
+                .split(",")
+                .map(|tag| {
+                    let tag = tag.trim();
+                    let codings = custom.iter().map(|name| CodingId::Custom(name)).chain(
+                        builtin
+                            .iter()
+                            .map(|encoding| CodingId::Builtin((*encoding).into())),
+                    );
+
+                    for coding in codings {
+                        if let Some(tag) = tag.strip_suffix(&format!("-{}\"", coding)) {
+                            return format!("{}\"", tag);
+                        }
+                    }
+
+                    tag.into()
+                })
+                .collect();
+
+            if let Ok(value) = HeaderValue::from_str(&value.join(", ")) {
+                headers.append(IF_NONE_MATCH, value);
+            }
+        }
+    }
+}
+
+// Entity tag without the weakness indicator.
+fn opaque_tag(tag: &str) -> &str {
+    tag.strip_prefix("W/").unwrap_or(tag)
+}
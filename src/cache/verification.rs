@@ -0,0 +1,145 @@
+use super::coding::*;
+
+use {
+    kutil::std::immutable::*,
+    std::{
+        collections::*,
+        io,
+        sync::{atomic::*, *},
+    },
+};
+
+/// Default maximum number of quarantined transcodes tracked by [TranscodeVerification].
+pub const DEFAULT_TRANSCODE_QUARANTINE: usize = 64;
+
+//
+// TranscodeVerification
+//
+
+/// Transcode verification.
+///
+/// Before a newly encoded representation is stored it is decoded back and compared with its
+/// source. A representation that fails verification is never stored or served.
+///
+/// Failed (coding, input size) pairs are quarantined so that the same doomed encode isn't retried
+/// on every request. The quarantine is bounded: the oldest entries are forgotten first.
+///
+/// Sampling is deterministic: with a rate of 0.25 exactly one of every four encodes is verified.
+///
+/// Cloning is cheap and clones share state.
+#[derive(Clone, Debug)]
+pub struct TranscodeVerification {
+    /// Fraction of encodes to verify (0.0 to 1.0).
+    pub rate: f64,
+
+    state: Arc<VerificationState>,
+}
+
+impl TranscodeVerification {
+    /// Constructor.
+    pub fn new(rate: f64) -> Self {
+        Self::new_with_capacity(rate, DEFAULT_TRANSCODE_QUARANTINE)
+    }
+
+    /// Constructor.
+    ///
+    /// `capacity` is the maximum number of quarantined transcodes to track.
+    pub fn new_with_capacity(rate: f64, capacity: usize) -> Self {
+        Self {
+            rate: rate.clamp(0.0, 1.0),
+            state: Arc::new(VerificationState {
+                count: AtomicU64::new(0),
+                failures: AtomicU64::new(0),
+                quarantine: Mutex::new(VecDeque::with_capacity(capacity)),
+                capacity,
+            }),
+        }
+    }
+
+    /// Number of verification failures so far.
+    pub fn failures(&self) -> u64 {
+        self.state.failures.load(Ordering::Relaxed)
+    }
+
+    /// Whether a (coding, input size) pair is quarantined.
+    pub fn is_quarantined(&self, coding: &CodingId, size: usize) -> bool {
+        self.state
+            .quarantine
+            .lock()
+            .expect("lock")
+            .iter()
+            .any(|(quarantined_coding, quarantined_size)| {
+                (quarantined_coding == coding) && (*quarantined_size == size)
+            })
+    }
+
+    /// Encode from [Identity](kutil::transcoding::Encoding::Identity) with verification.
+    ///
+    /// Returns [None] if the pair is quarantined or if verification failed, in which case the
+    /// caller should fall back to the source bytes.
+    pub async fn encode(
+        &self,
+        coding: &CodingId,
+        identity_bytes: &ImmutableBytes,
+        transcoders: &TranscoderRegistry,
+    ) -> io::Result<Option<ImmutableBytes>> {
+        let size = identity_bytes.len();
+
+        if self.is_quarantined(coding, size) {
+            tracing::debug!("not encoding to {} (quarantined for size {})", coding, size);
+            return Ok(None);
+        }
+
+        let encoded_bytes = coding.encode(identity_bytes, transcoders).await?;
+
+        if !self.sample() {
+            return Ok(Some(encoded_bytes));
+        }
+
+        let verified = match coding.decode(&encoded_bytes, transcoders).await {
+            Ok(decoded_bytes) => decoded_bytes == *identity_bytes,
+            Err(_) => false,
+        };
+
+        if verified {
+            Ok(Some(encoded_bytes))
+        } else {
+            tracing::error!("encoding to {} failed verification for size {}", coding, size);
+            self.state.failures.fetch_add(1, Ordering::Relaxed);
+            self.quarantine(coding.clone(), size);
+            Ok(None)
+        }
+    }
+
+    // Whether to verify the current encode.
+    fn sample(&self) -> bool {
+        if self.rate >= 1.0 {
+            return true;
+        }
+
+        let count = self.state.count.fetch_add(1, Ordering::Relaxed) as f64;
+        ((count + 1.0) * self.rate).floor() > (count * self.rate).floor()
+    }
+
+    fn quarantine(&self, coding: CodingId, size: usize) {
+        let mut quarantine = self.state.quarantine.lock().expect("lock");
+
+        if quarantine.len() >= self.state.capacity {
+            quarantine.pop_front();
+        }
+
+        quarantine.push_back((coding, size));
+    }
+}
+
+//
+// VerificationState
+//
+
+#[derive(Debug)]
+struct VerificationState {
+    count: AtomicU64,
+    failures: AtomicU64,
+    quarantine: Mutex<VecDeque<(CodingId, usize)>>,
+    capacity: usize,
+}
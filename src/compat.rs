@@ -0,0 +1,81 @@
+use super::cache::{middleware::*, *};
+
+use {
+    http::{request::*, *},
+    kutil::transcoding::*,
+    std::sync::*,
+};
+
+// Removal policy
+// ==============
+//
+// Shims in this module live for exactly one major version after the change they adapt. They must
+// be thin adapters onto the new APIs (no independent behavior) and must carry a `#[deprecated]`
+// note pointing at the replacement.
+//
+// Builder names, `Cache::put`, and `CachingLayer::keep_identity_encoding(bool)` have not changed,
+// so they need no shims. The compat tests pin them (along with the examples of the previous
+// version), so that changing them requires adding shims here.
+//
+// Struct fields can't be adapted: hooks that read `EncodableHookContext::encoding` must migrate
+// to `EncodableHookContext::coding`.
+
+/// Create a cache key for a request, calling the `cache_key` hook if configured.
+///
+/// Adapts the previous signature of
+/// [CacheableEncodableRequest::cache_key_with_hook], which did not accept a negotiated language.
+#[deprecated(note = "use `CacheableEncodableRequest::cache_key_with_hook` with a negotiated language")]
+pub fn cache_key_with_hook<RequestBodyT, CacheT, CacheKeyT>(
+    request: &Request<RequestBodyT>,
+    configuration: &MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+) -> CacheKeyT
+where
+    CacheKeyT: CacheKey,
+{
+    let language = request.negotiate_language(configuration);
+    request.cache_key_with_hook(language.as_ref(), configuration)
+}
+
+/// Select the encoding for a request.
+///
+/// Adapts the previous signature of [CacheableEncodableRequest::select_encoding], which returned
+/// a built-in [Encoding]. Custom codings are [Identity](Encoding::Identity).
+#[deprecated(note = "use `CacheableEncodableRequest::select_encoding`, which returns a `CodingId`")]
+pub fn select_encoding<RequestBodyT>(
+    request: &Request<RequestBodyT>,
+    configuration: &MiddlewareEncodingConfiguration,
+) -> Encoding {
+    request.select_encoding(configuration).builtin_or_identity()
+}
+
+/// Create an [EncodableHookContext] for a built-in [Encoding].
+///
+/// Adapts the previous signature of [EncodableHookContext::new].
+#[deprecated(note = "use `EncodableHookContext::new` with a `CodingId`")]
+pub fn encodable_hook_context<'this>(
+    encoding: &Encoding,
+    uri: &'this Uri,
+    headers: &'this HeaderMap,
+) -> EncodableHookContext<'this> {
+    let coding = match encoding {
+        Encoding::Identity => &CodingId::IDENTITY,
+        Encoding::Brotli => &CodingId::Builtin(Encoding::Brotli),
+        Encoding::Deflate => &CodingId::Builtin(Encoding::Deflate),
+        Encoding::GZip => &CodingId::Builtin(Encoding::GZip),
+        Encoding::Zstandard => &CodingId::Builtin(Encoding::Zstandard),
+    };
+    EncodableHookContext::new(coding, uri, headers)
+}
+
+/// Create a [CacheKeyHookContext] without an origin.
+///
+/// Adapts the previous signature of [CacheKeyHookContext::new]. The origin is unresolved (the
+/// default [RequestOrigin]).
+#[deprecated(note = "use `CacheKeyHookContext::new` with a `RequestOrigin`")]
+pub fn cache_key_hook_context<'this, CacheKeyT, RequestBodyT>(
+    cache_key: &'this mut CacheKeyT,
+    request: &'this Request<RequestBodyT>,
+) -> CacheKeyHookContext<'this, CacheKeyT, RequestBodyT> {
+    static UNRESOLVED_ORIGIN: LazyLock<RequestOrigin> = LazyLock::new(Default::default);
+    CacheKeyHookContext::new(cache_key, request, &UNRESOLVED_ORIGIN)
+}
@@ -0,0 +1,521 @@
+use super::cache::*;
+
+use {
+    http::{header::*, *},
+    std::{fmt, sync::*, time::*},
+};
+
+//
+// Capabilities
+//
+
+/// Optional [Cache] capabilities to test in [run_conformance].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Capabilities {
+    /// Honors [CachedResponse::duration].
+    pub expiry: bool,
+
+    /// Supports [fence](Cache::fence) and [put_fenced](Cache::put_fenced).
+    pub fencing: bool,
+
+    /// Supports [entry_count](Cache::entry_count) and [weighted_size](Cache::weighted_size).
+    pub sizes: bool,
+
+    /// Supports [invalidate_matching](Cache::invalidate_matching).
+    pub matching: bool,
+}
+
+impl Capabilities {
+    /// All capabilities.
+    pub fn all() -> Self {
+        Self {
+            expiry: true,
+            fencing: true,
+            sizes: true,
+            matching: true,
+        }
+    }
+}
+
+//
+// ConformanceFailure
+//
+
+/// Conformance failure.
+#[derive(Clone, Debug)]
+pub struct ConformanceFailure {
+    /// Scenario name.
+    pub scenario: &'static str,
+
+    /// Message.
+    pub message: String,
+}
+
+impl fmt::Display for ConformanceFailure {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{}: {}", self.scenario, self.message)
+    }
+}
+
+/// Run the [Cache] conformance suite and panic if there are failures.
+///
+/// `factory` must return a new, empty cache every time it is called. Each scenario gets its own.
+///
+/// Intended to be called from a test:
+///
+/// ```no_run
+/// # use tower_http_response_cache::{cache::implementation::lru::*, conformance::*};
+/// # type MyCache = SimpleLruCache;
+/// #[tokio::test]
+/// async fn conformance() {
+///     run_conformance(|| MyCache::new(1024 * 1024, None), Capabilities::default()).await;
+/// }
+/// ```
+pub async fn run_conformance<CacheT>(factory: impl Fn() -> CacheT, capabilities: Capabilities)
+where
+    CacheT: Cache<CommonCacheKey>,
+{
+    let failures = check_conformance(factory, capabilities).await;
+    if !failures.is_empty() {
+        let failures: Vec<_> = failures.iter().map(|failure| failure.to_string()).collect();
+        panic!("cache conformance failures:\n{}", failures.join("\n"));
+    }
+}
+
+/// Run the [Cache] conformance suite and return the failures.
+///
+/// See [run_conformance].
+pub async fn check_conformance<CacheT>(
+    factory: impl Fn() -> CacheT,
+    capabilities: Capabilities,
+) -> Vec<ConformanceFailure>
+where
+    CacheT: Cache<CommonCacheKey>,
+{
+    let mut failures = Vec::default();
+
+    round_trip(factory(), &mut failures).await;
+    overwrite(factory(), &mut failures).await;
+    invalidate(factory(), &mut failures).await;
+    invalidate_all(factory(), &mut failures).await;
+    clone_shares_state(factory(), &mut failures).await;
+    concurrent(factory(), &mut failures).await;
+    edge_durations(factory(), &mut failures).await;
+
+    if capabilities.expiry {
+        expiry(factory(), &mut failures).await;
+    }
+
+    if capabilities.fencing {
+        fencing(factory(), &mut failures).await;
+    }
+
+    if capabilities.sizes {
+        sizes(factory(), &mut failures).await;
+    }
+
+    if capabilities.matching {
+        invalidate_matching(factory(), &mut failures).await;
+    }
+
+    failures
+}
+
+/// Check the re-keying behavior of [KeyMigratingCache] over caches from `factory`.
+///
+/// `factory` must return a new, empty cache every time it is called, and the cache must support
+/// fencing. The legacy key form used here is the key without its
+/// [partition](CommonCacheKey::partition).
+pub async fn check_key_migration<CacheT>(factory: impl Fn() -> CacheT) -> Vec<ConformanceFailure>
+where
+    CacheT: Cache<CommonCacheKey>,
+{
+    let mut failures = Vec::default();
+
+    let migrating = |cache| {
+        KeyMigratingCache::new(cache, Duration::from_secs(60), |key: &CommonCacheKey| {
+            let mut key = key.clone();
+            key.partition = None;
+            Some(key)
+        })
+    };
+
+    rekey(migrating(factory()), &mut failures).await;
+    rekey_invalidate(migrating(factory()), &mut failures).await;
+
+    let cache = migrating(factory()).with_until(SystemTime::now() - Duration::from_secs(1));
+    rekey_after_transition(cache, &mut failures).await;
+
+    failures
+}
+
+// Scenarios
+
+async fn round_trip<CacheT>(cache: CacheT, failures: &mut Vec<ConformanceFailure>)
+where
+    CacheT: Cache<CommonCacheKey>,
+{
+    const SCENARIO: &str = "round trip";
+
+    if cache.get(&key("/a")).await.is_some() {
+        fail(failures, SCENARIO, "new cache is not empty");
+    }
+
+    cache.put(key("/a"), entry("a1", None)).await;
+    expect_version(&cache, "/a", Some("a1"), failures, SCENARIO).await;
+    expect_version(&cache, "/b", None, failures, SCENARIO).await;
+}
+
+async fn overwrite<CacheT>(cache: CacheT, failures: &mut Vec<ConformanceFailure>)
+where
+    CacheT: Cache<CommonCacheKey>,
+{
+    const SCENARIO: &str = "overwrite";
+
+    cache.put(key("/a"), entry("a1", None)).await;
+    cache.put(key("/a"), entry("a2", None)).await;
+    expect_version(&cache, "/a", Some("a2"), failures, SCENARIO).await;
+}
+
+async fn invalidate<CacheT>(cache: CacheT, failures: &mut Vec<ConformanceFailure>)
+where
+    CacheT: Cache<CommonCacheKey>,
+{
+    const SCENARIO: &str = "invalidate";
+
+    cache.put(key("/a"), entry("a1", None)).await;
+    cache.put(key("/b"), entry("b1", None)).await;
+    cache.invalidate(&key("/a")).await;
+    expect_version(&cache, "/a", None, failures, SCENARIO).await;
+    expect_version(&cache, "/b", Some("b1"), failures, SCENARIO).await;
+
+    // Invalidating a missing key is not an error
+    cache.invalidate(&key("/c")).await;
+}
+
+async fn invalidate_all<CacheT>(cache: CacheT, failures: &mut Vec<ConformanceFailure>)
+where
+    CacheT: Cache<CommonCacheKey>,
+{
+    const SCENARIO: &str = "invalidate all";
+
+    cache.put(key("/a"), entry("a1", None)).await;
+    cache.put(key("/b"), entry("b1", None)).await;
+    cache.invalidate_all().await;
+    expect_version(&cache, "/a", None, failures, SCENARIO).await;
+    expect_version(&cache, "/b", None, failures, SCENARIO).await;
+
+    // The cache must still be usable
+    cache.put(key("/a"), entry("a2", None)).await;
+    expect_version(&cache, "/a", Some("a2"), failures, SCENARIO).await;
+}
+
+async fn clone_shares_state<CacheT>(cache: CacheT, failures: &mut Vec<ConformanceFailure>)
+where
+    CacheT: Cache<CommonCacheKey>,
+{
+    const SCENARIO: &str = "clone shares state";
+
+    let clone = cache.clone();
+    clone.put(key("/a"), entry("a1", None)).await;
+    expect_version(&cache, "/a", Some("a1"), failures, SCENARIO).await;
+
+    cache.invalidate(&key("/a")).await;
+    expect_version(&clone, "/a", None, failures, SCENARIO).await;
+}
+
+// Allowed outcomes: after concurrent puts of different versions and an invalidation, the entry is
+// either missing or one of the versions that were put (never anything else)
+async fn concurrent<CacheT>(cache: CacheT, failures: &mut Vec<ConformanceFailure>)
+where
+    CacheT: Cache<CommonCacheKey>,
+{
+    const SCENARIO: &str = "concurrent";
+
+    cache.put(key("/a"), entry("a0", None)).await;
+
+    let a = key("/a");
+    tokio::join!(
+        cache.put(key("/a"), entry("a1", None)),
+        cache.invalidate(&a),
+        cache.put(key("/a"), entry("a2", None)),
+        cache.get(&a),
+    );
+
+    match version(cache.get(&key("/a")).await) {
+        None => {}
+        Some(version) if (version == "a1") || (version == "a2") => {}
+        Some(version) => fail(failures, SCENARIO, format!("unexpected version: {}", version)),
+    }
+
+    // After the dust settles the last operation wins
+    cache.put(key("/a"), entry("a3", None)).await;
+    expect_version(&cache, "/a", Some("a3"), failures, SCENARIO).await;
+}
+
+async fn edge_durations<CacheT>(cache: CacheT, failures: &mut Vec<ConformanceFailure>)
+where
+    CacheT: Cache<CommonCacheKey>,
+{
+    const SCENARIO: &str = "edge durations";
+
+    // Must not panic; a zero-duration entry may or may not be retrievable immediately
+    cache.put(key("/zero"), entry("z1", Some(Duration::ZERO))).await;
+    match version(cache.get(&key("/zero")).await) {
+        None => {}
+        Some(version) if version == "z1" => {}
+        Some(version) => fail(failures, SCENARIO, format!("unexpected version: {}", version)),
+    }
+
+    // About a hundred years
+    cache
+        .put(key("/huge"), entry("h1", Some(Duration::from_secs(100 * 365 * 24 * 60 * 60))))
+        .await;
+    expect_version(&cache, "/huge", Some("h1"), failures, SCENARIO).await;
+
+    // Must not overflow
+    cache.put(key("/max"), entry("m1", Some(Duration::MAX))).await;
+    expect_version(&cache, "/max", Some("m1"), failures, SCENARIO).await;
+}
+
+async fn expiry<CacheT>(cache: CacheT, failures: &mut Vec<ConformanceFailure>)
+where
+    CacheT: Cache<CommonCacheKey>,
+{
+    const SCENARIO: &str = "expiry";
+
+    cache.put(key("/short"), entry("s1", Some(Duration::from_millis(100)))).await;
+    cache.put(key("/long"), entry("l1", Some(Duration::from_secs(60)))).await;
+    expect_version(&cache, "/short", Some("s1"), failures, SCENARIO).await;
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    expect_version(&cache, "/short", None, failures, SCENARIO).await;
+    expect_version(&cache, "/long", Some("l1"), failures, SCENARIO).await;
+}
+
+async fn fencing<CacheT>(cache: CacheT, failures: &mut Vec<ConformanceFailure>)
+where
+    CacheT: Cache<CommonCacheKey>,
+{
+    const SCENARIO: &str = "fencing";
+
+    let fence = cache.fence(&key("/a"));
+    if cache.put_fenced(key("/a"), entry("a1", None), fence).await != PutOutcome::Stored {
+        fail(failures, SCENARIO, "fresh fence rejected");
+    }
+    expect_version(&cache, "/a", Some("a1"), failures, SCENARIO).await;
+
+    let fence = cache.fence(&key("/a"));
+    cache.invalidate(&key("/a")).await;
+    if cache.put_fenced(key("/a"), entry("a2", None), fence).await != PutOutcome::RejectedStale {
+        fail(failures, SCENARIO, "stale fence not rejected after invalidate");
+    }
+    expect_version(&cache, "/a", None, failures, SCENARIO).await;
+
+    let fence = cache.fence(&key("/b"));
+    cache.invalidate_all().await;
+    if cache.put_fenced(key("/b"), entry("b1", None), fence).await != PutOutcome::RejectedStale {
+        fail(failures, SCENARIO, "stale fence not rejected after invalidate all");
+    }
+    expect_version(&cache, "/b", None, failures, SCENARIO).await;
+}
+
+async fn sizes<CacheT>(cache: CacheT, failures: &mut Vec<ConformanceFailure>)
+where
+    CacheT: Cache<CommonCacheKey>,
+{
+    const SCENARIO: &str = "sizes";
+
+    cache.put(key("/a"), entry("a1", None)).await;
+
+    // Counts might be eventually consistent, so we can only check that they are provided
+    if cache.entry_count().is_none() {
+        fail(failures, SCENARIO, "entry_count not provided");
+    }
+
+    if cache.weighted_size().is_none() {
+        fail(failures, SCENARIO, "weighted_size not provided");
+    }
+}
+
+async fn rekey<CacheT>(cache: KeyMigratingCache<CacheT>, failures: &mut Vec<ConformanceFailure>)
+where
+    CacheT: Cache<CommonCacheKey>,
+{
+    const SCENARIO: &str = "rekey";
+
+    cache.inner.put(key("/a"), entry("a1", None)).await;
+
+    let got = version(cache.get(&partitioned_key("/a")).await);
+    if got.as_deref() != Some("a1") {
+        fail(failures, SCENARIO, format!("fallback: expected \"a1\", got {:?}", got));
+    }
+
+    // Re-stored under the new key, legacy entry kept
+    let got = version(cache.inner.get(&partitioned_key("/a")).await);
+    if got.as_deref() != Some("a1") {
+        fail(failures, SCENARIO, format!("re-stored: expected \"a1\", got {:?}", got));
+    }
+    expect_version(&cache.inner, "/a", Some("a1"), failures, SCENARIO).await;
+
+    // No second fallback
+    cache.get(&partitioned_key("/a")).await;
+    let stats = cache.stats();
+    if stats.fallback_hits != 1 {
+        fail(failures, SCENARIO, format!("expected 1 fallback hit: {}", stats));
+    }
+
+    cache.get(&partitioned_key("/missing")).await;
+    let stats = cache.stats();
+    if stats.fallback_misses != 1 {
+        fail(failures, SCENARIO, format!("expected 1 fallback miss: {}", stats));
+    }
+}
+
+async fn rekey_invalidate<CacheT>(
+    cache: KeyMigratingCache<CacheT>,
+    failures: &mut Vec<ConformanceFailure>,
+) where
+    CacheT: Cache<CommonCacheKey>,
+{
+    const SCENARIO: &str = "rekey invalidate";
+
+    // Invalidating the new key must also invalidate the legacy form
+    cache.inner.put(key("/a"), entry("a1", None)).await;
+    cache.invalidate(&partitioned_key("/a")).await;
+    expect_version(&cache.inner, "/a", None, failures, SCENARIO).await;
+    if cache.get(&partitioned_key("/a")).await.is_some() {
+        fail(failures, SCENARIO, "fallback hit after invalidate");
+    }
+
+    // An invalidation between the fallback read and the re-store must win
+    cache.inner.put(key("/b"), entry("b1", None)).await;
+    let fence = cache.fence(&partitioned_key("/b"));
+    cache.invalidate(&partitioned_key("/b")).await;
+    let outcome = cache.put_fenced(partitioned_key("/b"), entry("b1", None), fence).await;
+    if outcome != PutOutcome::RejectedStale {
+        fail(failures, SCENARIO, "stale re-store not rejected");
+    }
+    if cache.get(&partitioned_key("/b")).await.is_some() {
+        fail(failures, SCENARIO, "hit after invalidate");
+    }
+}
+
+async fn rekey_after_transition<CacheT>(
+    cache: KeyMigratingCache<CacheT>,
+    failures: &mut Vec<ConformanceFailure>,
+) where
+    CacheT: Cache<CommonCacheKey>,
+{
+    const SCENARIO: &str = "rekey after transition";
+
+    cache.inner.put(key("/a"), entry("a1", None)).await;
+    if cache.get(&partitioned_key("/a")).await.is_some() {
+        fail(failures, SCENARIO, "fallback hit after the transition window");
+    }
+}
+
+async fn invalidate_matching<CacheT>(cache: CacheT, failures: &mut Vec<ConformanceFailure>)
+where
+    CacheT: Cache<CommonCacheKey>,
+{
+    const SCENARIO: &str = "invalidate matching";
+
+    cache.put(tagged_key("/a", &["product:123"]), entry("a1", None)).await;
+    cache.put(tagged_key("/b", &["product:123", "product:456"]), entry("b1", None)).await;
+    cache.put(key("/c"), entry("c1", None)).await;
+
+    let outcome = cache
+        .invalidate_matching(|key: &CommonCacheKey| key.has_extension(b"product:123"))
+        .await;
+    if !outcome.is_success() {
+        fail(failures, SCENARIO, format!("failed: {}", outcome));
+    }
+
+    if cache.get(&tagged_key("/a", &["product:123"])).await.is_some() {
+        fail(failures, SCENARIO, "/a: not invalidated");
+    }
+    if cache.get(&tagged_key("/b", &["product:123", "product:456"])).await.is_some() {
+        fail(failures, SCENARIO, "/b: not invalidated");
+    }
+    expect_version(&cache, "/c", Some("c1"), failures, SCENARIO).await;
+}
+
+// Utils
+
+fn key(path: &str) -> CommonCacheKey {
+    let uri = Uri::try_from(path).expect("URI");
+    CommonCacheKey::for_request(&Method::GET, &uri, &HeaderMap::default())
+}
+
+fn tagged_key(path: &str, tags: &[&str]) -> CommonCacheKey {
+    let mut key = key(path);
+    key.extensions = Some(
+        tags.iter()
+            .map(|tag| (tag.as_bytes().to_vec().into(), Vec::default().into()))
+            .collect(),
+    );
+    key
+}
+
+fn partitioned_key(path: &str) -> CommonCacheKey {
+    let mut key = key(path);
+    key.partition = Some("partition".into());
+    key
+}
+
+// We use the ETag to identify the entry's version
+fn entry(version: &'static str, duration: Option<Duration>) -> CachedResponseRef {
+    let (mut parts, _) = Response::new(()).into_parts();
+    parts.headers.insert(ETAG, HeaderValue::from_static(version));
+
+    Arc::new(CachedResponse {
+        parts,
+        body: Default::default(),
+        duration,
+        created: SystemTime::now(),
+        upstream_age: Duration::ZERO,
+        original_coding: CodingId::IDENTITY,
+        validators_only: false,
+        no_transform: false,
+        dependencies: Default::default(),
+        hits: Default::default(),
+    })
+}
+
+fn version(cached_response: Option<CachedResponseRef>) -> Option<String> {
+    cached_response.and_then(|cached_response| {
+        cached_response
+            .headers()
+            .get(ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(String::from)
+    })
+}
+
+async fn expect_version<CacheT>(
+    cache: &CacheT,
+    path: &str,
+    expected: Option<&str>,
+    failures: &mut Vec<ConformanceFailure>,
+    scenario: &'static str,
+) where
+    CacheT: Cache<CommonCacheKey>,
+{
+    let version = version(cache.get(&key(path)).await);
+    if version.as_deref() != expected {
+        fail(
+            failures,
+            scenario,
+            format!("{}: expected {:?}, got {:?}", path, expected, version),
+        );
+    }
+}
+
+fn fail(failures: &mut Vec<ConformanceFailure>, scenario: &'static str, message: impl ToString) {
+    failures.push(ConformanceFailure {
+        scenario,
+        message: message.to_string(),
+    });
+}
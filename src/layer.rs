@@ -4,7 +4,12 @@ use super::{
 };
 
 use {
-    kutil::http::*,
+    http::{HeaderName, Method, Request, StatusCode, Uri},
+    http_body::*,
+    kutil::{
+        http::*,
+        std::{error::*, immutable::*},
+    },
     std::{marker::*, sync::*, time::*},
     tower::*,
 };
@@ -30,6 +35,40 @@ use {
 /// [From]\<[ImmutableBytes](kutil::std::immutable::ImmutableBytes)\>. (This is the case with
 /// [axum](https://github.com/tokio-rs/axum).)
 ///
+/// Without axum, e.g. with plain [hyper](https://hyper.rs), you can use `Full<Bytes>` from
+/// [http-body-util](https://docs.rs/http-body-util) as the response body:
+///
+/// ```
+/// use {
+///     http::*,
+///     http_body_util::*,
+///     kutil::std::immutable::*,
+///     std::convert::*,
+///     tower::*,
+///     tower_http_response_cache::{
+///         cache::{implementation::moka::*, *},
+///         *,
+///     },
+/// };
+///
+/// let cache = moka::future::Cache::<CommonCacheKey, _, _>::builder()
+///     .for_http_response()
+///     .max_capacity(1024 * 1024)
+///     .build();
+///
+/// let layer = DefaultCachingLayer::<Full<Bytes>>::default()
+///     .cache(MokaCacheImplementation::new(cache));
+///
+/// let service = ServiceBuilder::new()
+///     .layer(layer)
+///     .service(service_fn(|_request: Request<Full<Bytes>>| async {
+///         Ok::<_, Infallible>(Response::new(Full::new(Bytes::from_static(b"Hello, world!\n"))))
+///     }));
+/// # drop(service);
+/// ```
+///
+/// See also the `hyper_plain` and `tower_only` examples.
+///
 /// Usage notes
 /// ===========
 ///
@@ -39,6 +78,8 @@ use {
 ///
 ///    * Set `XX-Cache` to "false" to skip caching.
 ///    * Set `XX-Encode` to "false" to skip encoding.
+///    * Set `XX-Cache-Tags` to comma-separated tags in order to purge the entry by tag with a
+///      [DependencyCache].
 ///
 ///    However, you can also configure for "opt-in", *requiring* these headers to be set to "true"
 ///    in order to enable the features. See [cacheable_by_default](Self::cacheable_by_default) and
@@ -54,8 +95,10 @@ use {
 /// 3. You can explicitly set the cache duration for a response via a `XX-Cache-Duration` header.
 ///    Its string value is parsed using [duration-str](https://github.com/baoyachi/duration-str).
 ///    You can also provide a [cache_duration](Self::cache_duration) hook (the
-///    `XX-Cache-Duration` header will override it). The actual effect of the duration depends on
-///    the cache implementation.
+///    `XX-Cache-Duration` header will override it). As a last resort you can enable
+///    [heuristic_freshness](Self::heuristic_freshness), which derives the duration from
+///    `Last-Modified`. To desynchronize expirations, see [ttl_jitter](Self::ttl_jitter). The
+///    actual effect of the duration depends on the cache implementation.
 ///
 ///    ([Here](https://docs.rs/moka/latest/moka/policy/trait.Expiry.html#method.expire_after_create)
 ///    is the logic used for the Moka implementation.)
@@ -68,12 +111,32 @@ use {
 ///    cache key accordingly, so that different content will be cached separately. [CommonCacheKey]
 ///    reserves fields for media type and languages, just for this purpose.
 ///
+///    For `Accept-Language` specifically, [negotiate_languages](Self::negotiate_languages) handles
+///    negotiation for you, including fallbacks from specific to general languages (e.g. `de-AT` to
+///    `de`) and to a default language.
+///
 ///    If this impossible or too cumbersome, the alternative to content negotiation is to make
 ///    content selection the client's responsibility by including the content type in the URL, in
 ///    the path itself or as a query parameter. Web browsers often rely on JavaScript to automate
 ///    this for users by switching to the appropriate URL, for example adding "/en" to the path to
 ///    select English.
 ///
+/// 5. `Cache-Control: no-transform` is respected, on both responses and requests (see
+///    [respect_no_transform](Self::respect_no_transform)). Such responses are still cached, but
+///    are always served exactly as the upstream encoded them, and are never encoded on the fly.
+///
+///    Note that this takes precedence over `Accept-Encoding`: a client that does not accept the
+///    upstream's coding gets it anyway, which is the same as what we do for pass-through
+///    responses in codings that we won't reencode. If the upstream's coding might not be
+///    acceptable to some clients then it should not use `no-transform` with pre-encoded bodies.
+///
+/// 6. The layer can also cache the responses of an outbound HTTP client, e.g. Hyper's legacy
+///    `Client` or any other Tower service from [Request](http::Request) to
+///    [Response](http::Response). See [for_client](Self::for_client). Client response bodies
+///    usually can't be created from bytes, so wrap them in [ClientBody]. The same caches (and
+///    the same invalidation tooling) can be shared between server and client layers, as long as
+///    their keys don't collide.
+///
 /// General advice
 /// ==============
 ///
@@ -107,30 +170,48 @@ use {
 ///       degradation (as well as outright failure) for busy, resource-heavy servers. You might
 ///       want to initialize your cache with popular entries before opening your server to
 ///       requests. If your cache is distributed it might also mean syncing the cache first.
+///       Use [CachingService::warm] to run synthetic requests through the service, or
+///       [CachedResponse::from_bytes] to create entries from data you already have.
 ///
 ///    2. Invalidating cache entries manually can be critical for ensuring that clients don't
 ///       see out-of-date data, especially when your cache durations are long. For example, when
 ///       certain data is deleted from your database you can make sure to invalidate all cache
-///       entries that depend on that data. To simplify this, you can the data IDs to your cache
-///       keys. When invalidating, you can then enumerate all existing keys that contain the
-///       relevant ID. [CommonCacheKey] reserves an `extensions` fields just for this purpose.
+///       entries that depend on that data. To simplify this, you can add the data IDs to your
+///       cache keys. [CommonCacheKey] reserves an `extensions` field just for this purpose. When
+///       invalidating, you can then use [invalidate_matching](Cache::invalidate_matching) with
+///       [has_extension](CommonCacheKey::has_extension) to drop all entries tagged with the
+///       relevant ID.
+///
+///       Note that a request that started before the invalidation might store its (stale)
+///       response after it. To guard against this, wrap your cache in a [FencedCache].
 ///
 /// Request handling
 /// ================
 ///
 /// Here we'll go over the complete processing flow in detail:
 ///
+/// 0. If the request is an authorized cache administration request (see
+///    [admin_methods](Self::admin_methods)) then invalidate accordingly and respond. END.
+///
 /// 1. A request arrives. Check if it is cacheable (for now). Reasons it won't be cacheable:
 ///
 ///    * Caching is disabled for this layer
-///    * The request is non-idempotent (e.g. POST)
+///    * The request method is not cacheable (by default those that are non-idempotent, e.g.
+///      POST; see [methods](Self::methods)). Non-idempotent methods are never cacheable unless
+///      requests are keyed by their body (see [cache_key_with_body](Self::cache_key_with_body)).
+///    * The request target is not in origin-form (see
+///      [cache_absolute_form](Self::cache_absolute_form))
+///    * The request has one of the [bypass_cache_on_headers](Self::bypass_cache_on_headers), or
+///      `Authorization` with [strict_privacy](Self::strict_privacy)
 ///    * If we pass the checks above then we give the
 ///      [cacheable_by_request](Self::cacheable_by_request) hook a chance to skip caching.
 ///      If it returns false then we are non-cacheable.
 ///
 ///    If the response is non-cacheable then go to "Non-cached request handling" below.
 ///
-/// 2. Check if we have a cached response.
+/// 2. Check if we have a cached response. `HEAD` requests are looked up under the keys of `GET`
+///    requests (see [CacheKey::for_head_lookup]). A hit is served with the headers that a `GET`
+///    would get and without a body. A miss is passed through to the upstream and not stored.
 ///
 /// 3. If we do, then:
 ///
@@ -157,17 +238,27 @@ use {
 ///       `keep_identity_encoding` is true then we will store the decoded data in the cache so that
 ///       we can skip this step in the future (the trade-off is taking up more room in the cache).
 ///
-///    6. Encode the body and store it in the cache.
+///    6. Encode the body and add it to the cache entry (see step 5).
 ///
 ///    7. Go up to step 3.2.2.
 ///
 /// 4. If we don't have a cached response:
 ///
-///    1. Get the upstream response and check if it is cacheable. Reasons it won't be cacheable:
+///    1. If we have an expired cache entry that is still retained (e.g. via
+///       `for_http_response_with_grace` for Moka) and the request has no conditional headers of
+///       its own, add `If-None-Match` and `If-Modified-Since` from the entry's validators to the
+///       upstream request. If the upstream response is 304 (Not Modified) then refresh the entry
+///       with its headers and duration and go to step 3.1. We thus skip all body transfer and
+///       encoding.
 ///
-///       * Its status code is not "success" (200 to 299)
+///    2. Get the upstream response and check if it is cacheable. Reasons it won't be cacheable:
+///
+///       * Its status code is not "success" (200 to 299) or one of the
+///         [cacheable_status_codes](Self::cacheable_status_codes)
 ///       * Its `XX-Cache` header is "false"
-///       * It has a `Content-Range` header (we don't cache partial responses)
+///       * It has a `Content-Range` header (we don't cache partial responses, though they can
+///         be assembled into a full entry, see `assemble_ranges` in the `range-assembly`
+///         feature)
 ///       * It has a `Content-Length` header that is lower than our configured minimum or higher
 ///         than our configured maximum
 ///       * If we pass all the checks above then we give the
@@ -176,33 +267,43 @@ use {
 ///
 ///       If the upstream response is non-cacheable then go to "Non-cached request handling" below.
 ///
-///    2. Otherwise select the best encoding according to our configured preferences and the
-///       priorities specified in the request's `Accept-Encoding`. If the upstream response has
-///       `XX-Encode` header as "false" or has `Content-Length` smaller than our configured
-///       minimum, then use Identity encoding.
+///    3. Otherwise select the encoding to store: our most preferred one, regardless of the
+///       request's `Accept-Encoding`, so that the stored form does not depend on which client
+///       happened to ask first. (The request is served its own negotiated encoding from the
+///       entry.) If the upstream response has `XX-Encode` header as "false" or has
+///       `Content-Length` smaller than our configured minimum, then use Identity encoding.
 ///
-///    3. If the selected encoding is not Identity then we give the
+///    4. If the selected encoding is not Identity then we give the
 ///       [encodable_by_response](Self::encodable_by_response) hook one last chance to skip
 ///       encoding. If it returns false we set the encoding to Identity and add the `XX-Encode`
 ///       header as "true" for use by step 3.1 above.
 ///
-///    4. Read the upstream response body into a buffer. If there is no `Content-Length` header
+///    5. Read the upstream response body into a buffer. If there is no `Content-Length` header
 ///       then make sure to read no more than our configured maximum size.
 ///
-///    5. If there's still more data left or the data that was read is less than our configured
-///       minimum size then it means the upstream response is non-cacheable, so:
+///    6. If there's still more data left, or the data that was read is less than our configured
+///       minimum size, or its size doesn't match the `Content-Length` header (a truncated or
+///       overlong body), then it means the upstream response is non-cacheable, so:
 ///
 ///       1. Push the data that we read back into the front of the upstream response body.
 ///
 ///       2. Go to "Non-cached request handling" step 4 below.
 ///
-///    6. Otherwise store the read bytes in the cache, encoding them if necessary. We know the
+///    7. Otherwise store the read bytes in the cache, encoding them if necessary. We know the
 ///       size, so we can check if it's smaller than the configured minimum for encoding, in
 ///       which case we use Identity encoding. We also make sure to set the cached `Last-Modified`
 ///       header to the current time if the header wasn't already set. Go up to step 3.2.
 ///
-///       Note that upstream response trailers are discarded and *not* stored in the cache. (We
-///       make the assumption that trailers are only relevant to "real" responses.)
+///       Note that upstream response trailers are *not* stored in the cache. (We make the
+///       assumption that trailers are only relevant to "real" responses.) They are, however,
+///       emitted after the body of this response, and passed to the
+///       [on_trailers](Self::on_trailers) hook. Subsequent hits will have no trailers.
+///       Responses that are not stored keep their trailers, even when encoded on the fly.
+///
+/// 5. Finally, whatever the steps above would store in the cache (a new or refreshed entry, or a
+///    new representation for an existing entry) is written with a single write, after the
+///    response has been constructed from the same in-memory entry. A remote cache thus sees one
+///    write per request, and concurrent readers never see intermediate states.
 ///
 /// ### Non-cached request handling
 ///
@@ -246,6 +347,7 @@ where
 {
     caching: MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>,
     encoding: MiddlewareEncodingConfiguration,
+    diagnosed: Arc<Once>,
 }
 
 impl<RequestBodyT, CacheT, CacheKeyT> CachingLayer<RequestBodyT, CacheT, CacheKeyT>
@@ -253,6 +355,308 @@ where
     CacheT: Cache<CacheKeyT>,
     CacheKeyT: CacheKey,
 {
+    /// Constructor for caching the responses of an outbound HTTP client.
+    ///
+    /// The upstream is not ours, so its standard headers are the primary policy source:
+    ///
+    /// * [respect_cache_control](Self::respect_cache_control) is true
+    /// * [xx_headers](Self::xx_headers) is false
+    /// * encoding is disabled and [never_transform](Self::never_transform) is true, because the
+    ///   client negotiates its own encoding (bodies are stored and served as received)
+    /// * [methods](Self::methods) is [MethodPolicy::GetOnly]
+    /// * [partition_by_host](Self::partition_by_host) and
+    ///   [cache_absolute_form](Self::cache_absolute_form) are true, because requests are to
+    ///   absolute URIs
+    /// * [synthetic_last_modified](Self::synthetic_last_modified) is
+    ///   [SyntheticLastModified::Never], because only the upstream's validators are meaningful to
+    ///   it
+    ///
+    /// Revalidation is outbound: an expired entry that is still retained (e.g. via
+    /// `for_http_response_with_grace` for Moka) gets its validators sent upstream, and a
+    /// `304 Not Modified` refreshes it.
+    ///
+    /// Because encoding is not negotiated, the client should send the same `Accept-Encoding` for
+    /// all requests. Responses with a `Vary` on any other request header are not cached.
+    pub fn for_client() -> Self {
+        Self::default()
+            .respect_cache_control(true)
+            .xx_headers(false)
+            .disable_encoding()
+            .never_transform(true)
+            .methods(MethodPolicy::GetOnly)
+            .partition_by_host(true)
+            .cache_absolute_form(true)
+            .synthetic_last_modified(SyntheticLastModified::Never)
+    }
+
+    /// Load-adaptive degradation.
+    ///
+    /// Under load we progressively shed optional work on misses: first eager encoding (storing
+    /// and serving Identity only), then storing altogether. Existing hits are always served. See
+    /// [LoadShedPolicy].
+    ///
+    /// Keep a clone of the policy in order to adjust its thresholds at runtime.
+    ///
+    /// [None] by default.
+    pub fn load_shed(mut self, load_shed: LoadShedPolicy) -> Self {
+        self.caching.load_shed = Some(load_shed);
+        self
+    }
+
+    /// Admission policy for storing misses, e.g. "cache on second request".
+    ///
+    /// Misses that are not admitted are passed through without being stored. Keep a clone of the
+    /// policy in order to call [record_eviction](AdmissionPolicy::record_eviction) from your
+    /// cache's eviction listener and to read its [stats](AdmissionPolicy::stats).
+    ///
+    /// [None] by default.
+    pub fn admission(mut self, admission: AdmissionPolicy) -> Self {
+        self.caching.admission = Some(admission);
+        self
+    }
+
+    /// Bound the total bytes buffered by all in-flight stores (including their encoded copies).
+    ///
+    /// Misses that don't fit are stored without encoding, or passed through without caching.
+    /// Use [buffer_budget](Self::buffer_budget) to access its stats. See [BufferBudget].
+    ///
+    /// Unbounded by default.
+    pub fn max_inflight_buffer_bytes(mut self, max_inflight_buffer_bytes: usize) -> Self {
+        self.caching.buffer_budget = Some(BufferBudget::new(max_inflight_buffer_bytes));
+        self
+    }
+
+    /// Budget of bytes buffered by in-flight stores, if bounded.
+    ///
+    /// All services created by this layer share it.
+    pub fn buffer_budget(&self) -> Option<BufferBudget> {
+        self.caching.buffer_budget.clone()
+    }
+
+    /// Coalesce concurrent misses for the same cache key, so that only one of them calls the
+    /// upstream while the others wait for it to store the entry. Use
+    /// [request_coalescing](Self::request_coalescing) to access its stats. See
+    /// [RequestCoalescing].
+    ///
+    /// The default is false.
+    pub fn coalesce_requests(mut self, coalesce_requests: bool) -> Self {
+        self.caching.coalescing = coalesce_requests.then(Default::default);
+        self
+    }
+
+    /// Coalescing of concurrent misses, if enabled.
+    ///
+    /// All services created by this layer share it.
+    pub fn request_coalescing(&self) -> Option<RequestCoalescing> {
+        self.caching.coalescing.clone()
+    }
+
+    /// Serve entries that expired less than `window` ago immediately, while refreshing them in
+    /// the background (at most one refresh per key at a time). If the refresh fails then the
+    /// stale entry is kept. Use [stale_while_revalidate_state](Self::stale_while_revalidate_state)
+    /// to access its stats. See [StaleWhileRevalidate].
+    ///
+    /// The cache must retain expired entries for at least the window (e.g. via
+    /// `for_http_response_with_grace` for Moka, with a grace period of at least the window).
+    ///
+    /// Requires the `stale-while-revalidate` feature and a Tokio runtime.
+    ///
+    /// [None] by default.
+    #[cfg(feature = "stale-while-revalidate")]
+    pub fn stale_while_revalidate(mut self, window: Duration) -> Self {
+        self.caching.stale_while_revalidate = Some(StaleWhileRevalidate::new(window));
+        self
+    }
+
+    /// Serving of expired entries while refreshing them, if enabled.
+    ///
+    /// All services created by this layer share it.
+    #[cfg(feature = "stale-while-revalidate")]
+    pub fn stale_while_revalidate_state(&self) -> Option<StaleWhileRevalidate> {
+        self.caching.stale_while_revalidate.clone()
+    }
+
+    /// Learned bypass for keys that keep producing uncacheable responses.
+    ///
+    /// Requests for such keys go directly to the upstream, skipping the cache lookup, except for
+    /// a sample that goes through the full path in order to notice when they become cacheable.
+    /// Keep a clone in order to read its [stats](LearnedBypass::stats). See [LearnedBypass].
+    ///
+    /// [None] by default.
+    pub fn learned_bypass(mut self, learned_bypass: LearnedBypass) -> Self {
+        self.caching.learned_bypass = Some(learned_bypass);
+        self
+    }
+
+    /// Record dependency tokens for cache misses, so that entries can be purged by token via a
+    /// [DependencyCache].
+    ///
+    /// Handlers record tokens via the [CacheDependencies] request extension. Tokens recorded with
+    /// a TTL also bound the duration of the entry (see [CacheDependencies::record_with_ttl]).
+    ///
+    /// Disabled by default. The default limits are [DEFAULT_MAX_DEPENDENCIES] and
+    /// [DEFAULT_MAX_DEPENDENCY_LENGTH].
+    pub fn record_dependencies(mut self, max_count: usize, max_length: usize) -> Self {
+        self.caching.dependencies = Some(CacheDependencies::new(max_count, max_length));
+        self
+    }
+
+    /// Invalidate the cached entries for a URI when a write to it fails its precondition, e.g.
+    /// with `412 Precondition Failed`, so that clients doing optimistic concurrency get the
+    /// current representation (and its `ETag`) when they read it again. See
+    /// [ConflictInvalidation].
+    ///
+    /// [None] by default.
+    pub fn conflict_invalidation(mut self, conflict_invalidation: ConflictInvalidation) -> Self {
+        self.caching.conflict_invalidation = Some(conflict_invalidation);
+        self
+    }
+
+    /// Validate cache entries before serving them, quarantining those that fail (e.g. a stored
+    /// error page under an image type, or a truncated body).
+    ///
+    /// Keep a clone in order to read its [stats](ServeValidation::stats). See [ServeValidation].
+    ///
+    /// [None] by default.
+    pub fn validate_on_serve(mut self, serve_validation: ServeValidation) -> Self {
+        self.caching.serve_validation = Some(serve_validation);
+        self
+    }
+
+    /// Cache generations, for instant rollback of cached content.
+    ///
+    /// Keep a clone in order to bump the generation (e.g. on deploy) and to switch which
+    /// generation is served. See [CacheGenerations].
+    ///
+    /// Requires a [CacheKey] implementation that supports
+    /// [set_generation](CacheKey::set_generation), such as [CommonCacheKey].
+    ///
+    /// [None] by default.
+    pub fn generations(mut self, generations: CacheGenerations) -> Self {
+        self.caching.generations = Some(generations);
+        self
+    }
+
+    /// Handle for operational cache bypass.
+    ///
+    /// Keep it (or make it available to an admin handler) in order to engage a time-boxed bypass
+    /// during incidents. All services created by this layer share it.
+    pub fn cache_override(&self) -> CacheOverride {
+        self.caching.cache_override.clone()
+    }
+
+    /// Handle for runtime control, e.g. for disabling caching or changing the enabled encodings
+    /// without rebuilding the service.
+    ///
+    /// Keep it (or make it available to an admin handler). All services created by this layer
+    /// share it. See [CachingControl].
+    pub fn control(&self) -> CachingControl {
+        self.caching.control.clone()
+    }
+
+    /// Verify the cache by running its [self-test](Cache::self_test) on first use.
+    ///
+    /// Cache errors otherwise silently degrade to misses, so a misconfigured backend could go
+    /// unnoticed. The policy decides what happens if verification fails. See [CacheVerification].
+    ///
+    /// Call [verify](Self::verify) to run it explicitly before serving traffic.
+    ///
+    /// [None] by default.
+    pub fn verify_cache_on_first_use(mut self, policy: CacheVerificationPolicy) -> Self {
+        self.caching.cache_verification = Some(CacheVerification::new(policy));
+        self
+    }
+
+    /// Invalidate the cached entries for a URI.
+    ///
+    /// The keys are created by the middleware's own pipeline, including the
+    /// [cache_key](Self::cache_key) hook, exactly as for a `GET` (and `HEAD`, if cacheable)
+    /// request to the URI without headers, for all known variants (see [uri_cache_keys]). An
+    /// absolute URI provides the origin. Use [invalidate_request](Self::invalidate_request) if
+    /// keys depend on other request headers.
+    ///
+    /// Returns the number of invalidated entries.
+    pub async fn invalidate_uri(&self, uri: &Uri) -> usize
+    where
+        RequestBodyT: Default,
+    {
+        let mut request = Request::new(RequestBodyT::default());
+        *request.uri_mut() = uri.clone();
+        self.invalidate_request(&mut request).await
+    }
+
+    /// Invalidate the cached entries for a request's URI.
+    ///
+    /// Like [invalidate_uri](Self::invalidate_uri), but the request's headers (e.g. `Host`) are
+    /// available to the pipeline. The request's method is ignored.
+    ///
+    /// Returns the number of invalidated entries.
+    pub async fn invalidate_request(&self, request: &mut Request<RequestBodyT>) -> usize {
+        invalidate_uri_cache_keys(request, &self.caching).await
+    }
+
+    /// Run the cache self-test now.
+    ///
+    /// If [verify_cache_on_first_use](Self::verify_cache_on_first_use) is configured then the
+    /// outcome is shared with all services created by this layer and the first request will not
+    /// run it again.
+    pub async fn verify(&self) -> Result<SelfTestReport, SelfTestError> {
+        self.caching.verify_cache().await
+    }
+
+    /// Track per-entry statistics, e.g. for capacity planning. See [EntryStats].
+    ///
+    /// Use [entry_stats](Self::entry_stats) to access the report.
+    ///
+    /// [None] by default.
+    pub fn track_entry_stats(mut self, configuration: TopKConfig) -> Self {
+        self.caching.entry_stats = Some(EntryStats::new(configuration));
+        self
+    }
+
+    /// Per-entry statistics, if tracked.
+    ///
+    /// All services created by this layer share them.
+    pub fn entry_stats(&self) -> Option<EntryStats> {
+        self.caching.entry_stats.clone()
+    }
+
+    /// Observe the body sizes of cacheable responses, e.g. to choose
+    /// [max_cacheable_body_size](Self::max_cacheable_body_size). See [BodySizeObserver].
+    ///
+    /// Use [body_sizes](Self::body_sizes) to access the histogram.
+    ///
+    /// Disabled by default.
+    pub fn observe_body_sizes(mut self) -> Self {
+        self.caching.body_sizes = Some(Default::default());
+        self
+    }
+
+    /// Body size observer, if enabled.
+    ///
+    /// All services created by this layer share it.
+    pub fn body_sizes(&self) -> Option<BodySizeObserver> {
+        self.caching.body_sizes.clone()
+    }
+
+    /// Audit the declared weights of stored entries against their actual sizes. See
+    /// [WeightAudit].
+    ///
+    /// Use [weight_audit](Self::weight_audit) to sample.
+    ///
+    /// [None] by default.
+    pub fn audit_weights(mut self, weight_audit: WeightAudit<CacheKeyT>) -> Self {
+        self.caching.weight_audit = Some(weight_audit);
+        self
+    }
+
+    /// Weight audit, if enabled.
+    ///
+    /// All services created by this layer share it.
+    pub fn weight_audit(&self) -> Option<WeightAudit<CacheKeyT>> {
+        self.caching.weight_audit.clone()
+    }
+
     /// Enable cache.
     ///
     /// Not enabled by default.
@@ -277,6 +681,19 @@ where
         self
     }
 
+    /// Whether to cache only the validators and metadata of responses that are too big to cache.
+    ///
+    /// Such entries have no body. They are used to answer conditional requests (`If-None-Match`
+    /// and `If-Modified-Since`) with 304 (Not Modified) and `HEAD` requests without calling the
+    /// upstream. Otherwise they are treated as a miss and are refreshed by the pass-through
+    /// response. Only responses with `ETag` or `Last-Modified` are stored.
+    ///
+    /// The default is false.
+    pub fn cache_validators_for_oversized(mut self, cache_validators_for_oversized: bool) -> Self {
+        self.caching.inner.cache_validators_for_oversized = cache_validators_for_oversized;
+        self
+    }
+
     /// If a response does not specify the `XX-Cache` response header then this we will assume its
     /// value is this.
     ///
@@ -329,138 +746,1111 @@ where
         self
     }
 
-    /// Provide a hook to get a response's cache duration.
+    /// Provide a hook to update a request's cache key from its body, e.g. with a digest of a
+    /// GraphQL query. It is called after the [cache_key](Self::cache_key) hook.
     ///
-    /// Will only be called if an `XX-Cache-Duration` response header is *not* provided. In other
-    /// words, `XX-Cache-Duration` will always override this value.
+    /// Bodies of up to `max_body_size` are buffered for the hook and then passed on. See
+    /// [BodyKey] for which requests are keyed by their body and for the edge cases. To cache
+    /// `POST` requests also see [cacheable_methods](Self::cacheable_methods).
     ///
-    /// Note that the headers are *response* headers.
+    /// [None] by default.
+    pub fn cache_key_with_body(
+        mut self,
+        max_body_size: usize,
+        cache_key: impl Fn(CacheKeyBodyHookContext<CacheKeyT, RequestBodyT>)
+        + 'static
+        + Send
+        + Sync,
+    ) -> Self
+    where
+        RequestBodyT: 'static + Body + From<ImmutableBytes> + Send + Unpin,
+        RequestBodyT::Error: Into<CapturedError>,
+    {
+        self.caching.body_key = Some(BodyKey::new(max_body_size, Arc::new(Box::new(cache_key))));
+        self
+    }
+
+    /// Provide a hook to receive the upstream trailers of a response that is being stored, e.g.
+    /// for logging or metrics.
+    ///
+    /// Trailers are never stored. The request that triggered the store still receives them after
+    /// the body, but subsequent hits have no trailers.
     ///
     /// [None] by default.
-    pub fn cache_duration(
+    pub fn on_trailers(
         mut self,
-        cache_duration: impl Fn(CacheDurationHookContext) -> Option<Duration> + 'static + Send + Sync,
+        on_trailers: impl Fn(TrailersHookContext) + 'static + Send + Sync,
     ) -> Self {
-        self.caching.inner.cache_duration = Some(Arc::new(Box::new(cache_duration)));
+        self.caching.on_trailers = Some(Arc::new(Box::new(on_trailers)));
         self
     }
 
-    /// Enable encodings in order from most preferred to least.
+    /// Provide a hook to receive an event for each entry stored by the middleware: new entries
+    /// for misses, reencoded representations merged into existing entries, refreshed entries, and
+    /// validators-only entries. See [StorePathway].
     ///
-    /// Will be negotiated with the client's preferences (in its `Accept-Encoding` header) to
-    /// select the best.
+    /// Combined with your cache implementation's eviction events this gives a complete lifecycle
+    /// stream. Stores are reported only here, never by the cache implementation, so there are no
+    /// duplicates.
     ///
-    /// There is no need to specify [Identity](kutil::transcoding::Encoding::Identity) as it is
-    /// always enabled.
+    /// The hook is called synchronously, after the store, so keep it cheap (e.g. send to a
+    /// channel and do the work elsewhere). Its latency is recorded in the
+    /// [DecisionTrail::store_hook] timing. Panics are caught and logged.
     ///
-    /// The default is [ENCODINGS_BY_PREFERENCE].
-    pub fn enable_encodings(
+    /// [None] by default.
+    pub fn on_store(
         mut self,
-        enabled_encodings_by_preference: Vec<EncodingHeaderValue>,
+        on_store: impl Fn(StoreEvent<CacheKeyT>) + 'static + Send + Sync,
     ) -> Self {
-        self.encoding.enabled_encodings_by_preference = Some(enabled_encodings_by_preference);
+        self.caching.on_store = Some(Arc::new(Box::new(on_store)));
         self
     }
 
-    /// Disables encoding.
+    /// Provide a hook to receive typed cache events, e.g. for counting hits, misses, and stores
+    /// in your metrics system. See [CacheEventKind].
     ///
-    /// The default is [ENCODINGS_BY_PREFERENCE].
-    pub fn disable_encoding(mut self) -> Self {
-        self.encoding.enabled_encodings_by_preference = None;
+    /// Unlike [on_store](Self::on_store), this reports every request's outcome, but it does not
+    /// give access to stored entries.
+    ///
+    /// The hook is called synchronously, on the request's task, so keep it cheap (e.g. increment
+    /// counters). When not provided the events are not even constructed.
+    ///
+    /// [None] by default.
+    pub fn on_cache_event(
+        mut self,
+        on_cache_event: impl Fn(CacheEvent<CacheKeyT>) + 'static + Send + Sync,
+    ) -> Self {
+        self.caching.on_cache_event = Some(Arc::new(Box::new(on_cache_event)));
         self
     }
 
-    /// Minimum size in bytes of response bodies to encode.
+    /// Key cache entries by the values of the request headers that the response `Vary` header
+    /// names, so that upstreams that negotiate content (e.g. by `Accept` or `Accept-Language`)
+    /// get an entry per variant without a [cache_key](Self::cache_key) hook. Responses with
+    /// `Vary: *` are not cached. See [VaryKeys].
     ///
-    /// Note that non-cached responses without `Content-Length` cannot be checked against this
-    /// value.
+    /// Requires a [CacheKey] implementation that supports
+    /// [set_varied_header](CacheKey::set_varied_header), such as [CommonCacheKey].
     ///
-    /// The default is 0.
-    pub fn min_encodable_body_size(mut self, min_encodable_body_size: usize) -> Self {
-        self.encoding.inner.min_body_size = min_encodable_body_size;
+    /// [None] by default.
+    pub fn vary_keys(mut self, vary_keys: VaryKeys) -> Self {
+        self.caching.vary_keys = Some(vary_keys);
         self
     }
 
-    /// If a response does not specify the `XX-Encode` response header then this we will assume its
-    /// value is this.
+    /// Enable `Accept-Language` content negotiation for the supported languages. The first
+    /// language is the default.
     ///
-    /// The default is true.
-    pub fn encodable_by_default(mut self, encodable_by_default: bool) -> Self {
-        self.encoding.inner.encodable_by_default = encodable_by_default;
+    /// The negotiated language is always one of the supported languages (see
+    /// [LanguageNegotiation]). It is set in the cache key *before* the [cache_key](Self::cache_key)
+    /// hook is called, so that variants are cached separately while the key space remains bounded.
+    /// The raw `Accept-Language` header never enters the cache key.
+    ///
+    /// Responses will have `Content-Language` set to the negotiated language and
+    /// `Accept-Language` will be added to `Vary`.
+    ///
+    /// `supported_languages` must not be empty!
+    ///
+    /// [None] by default.
+    pub fn negotiate_languages(mut self, supported_languages: Vec<Language>) -> Self {
+        self.caching.language_negotiation =
+            Some(Arc::new(LanguageNegotiation::new(supported_languages)));
         self
     }
 
-    /// Provide a hook to test whether a request is encodable.
+    /// Provide a hook to get a response's cache duration.
     ///
-    /// Will only be called after all internal conditions are met, giving you one last chance to
-    /// prevent encoding.
+    /// Will only be called if an `XX-Cache-Duration` response header is *not* provided. In other
+    /// words, `XX-Cache-Duration` will always override this value.
     ///
-    /// Note that the headers are *request* headers. This hook is called before we have the
-    /// upstream response.
+    /// Note that the headers are *response* headers.
     ///
     /// [None] by default.
-    pub fn encodable_by_request(
+    pub fn cache_duration(
         mut self,
-        encodable_by_request: impl Fn(EncodableHookContext) -> bool + 'static + Send + Sync,
+        cache_duration: impl Fn(CacheDurationHookContext) -> Option<Duration> + 'static + Send + Sync,
     ) -> Self {
-        self.encoding.encodable_by_request = Some(Arc::new(Box::new(encodable_by_request)));
+        self.caching.inner.cache_duration = Some(Arc::new(Box::new(cache_duration)));
         self
     }
 
-    /// Provide a hook to test whether a response is encodable.
-    ///
-    /// Will only be called after all internal conditions are met, giving you one last chance to
-    /// prevent encoding.
+    /// Status codes other than success (2xx) whose responses are cacheable, e.g. 404 (Not Found),
+    /// 410 (Gone), and the permanent redirects 301 (Moved Permanently) and 308 (Permanent
+    /// Redirect).
     ///
-    /// Note that the headers are *response* headers. This hook is called *after* we get the
-    /// upstream response but *before* we read its body.
+    /// 304 (Not Modified), informational (1xx), and server error (5xx) status codes are never
+    /// cacheable and are ignored. Conditional requests are not evaluated for such negative
+    /// entries, so they get no synthetic `Last-Modified`, and `min_body_size` does not apply to
+    /// them (redirect bodies are often empty). See also
+    /// [negative_cache_duration](Self::negative_cache_duration).
     ///
-    /// [None] by default.
-    pub fn encodable_by_response(
+    /// The default is none.
+    pub fn cacheable_status_codes(
         mut self,
-        encodable_by_response: impl Fn(EncodableHookContext) -> bool + 'static + Send + Sync,
+        cacheable_status_codes: impl IntoIterator<Item = StatusCode>,
     ) -> Self {
-        self.encoding.encodable_by_response = Some(Arc::new(Box::new(encodable_by_response)));
+        self.caching.inner.cacheable_status_codes = cacheable_status_codes.into_iter().collect();
         self
     }
 
-    /// Whether to keep an [Identity](kutil::transcoding::Encoding::Identity) in the cache if it is
-    /// created during reencoding.
+    /// Cache duration for negative entries (see
+    /// [cacheable_status_codes](Self::cacheable_status_codes)), usually shorter than for others.
     ///
-    /// Keeping it optimizes for compute with the trade-off of taking up more room in the cache.
+    /// It applies if there is no `XX-Cache-Duration` header, route policy duration,
+    /// [cache_duration](Self::cache_duration) hook result, or `Cache-Control` duration (if we
+    /// [respect](Self::respect_cache_control) it).
     ///
-    /// The default is true.
-    pub fn keep_identity_encoding(mut self, keep_identity_encoding: bool) -> Self {
-        self.encoding.inner.keep_identity_encoding = keep_identity_encoding;
+    /// Also makes 404 (Not Found) and 410 (Gone) cacheable, in addition to any other
+    /// [cacheable_status_codes](Self::cacheable_status_codes) set before.
+    ///
+    /// [None] by default.
+    pub fn negative_cache_duration(mut self, negative_cache_duration: Duration) -> Self {
+        let inner = &mut self.caching.inner;
+        inner.negative_duration = Some(negative_cache_duration);
+
+        let mut cacheable_status_codes = inner.cacheable_status_codes.to_vec();
+        for status in [StatusCode::NOT_FOUND, StatusCode::GONE] {
+            if !cacheable_status_codes.contains(&status) {
+                cacheable_status_codes.push(status);
+            }
+        }
+        inner.cacheable_status_codes = cacheable_status_codes.into();
+
         self
     }
-}
 
-impl<RequestBodyT, CacheT, CacheKeyT> Default for CachingLayer<RequestBodyT, CacheT, CacheKeyT>
-where
-    CacheT: Cache<CacheKeyT>,
-    CacheKeyT: CacheKey,
-{
-    fn default() -> Self {
-        Self {
-            caching: Default::default(),
-            encoding: Default::default(),
-        }
+    /// Immutable asset profile for matching paths, e.g. fingerprinted assets.
+    ///
+    /// Accepts a [PathMatcher] or an [ImmutablePaths] (to configure its `max_age`).
+    ///
+    /// [None] by default.
+    pub fn immutable_paths(mut self, immutable_paths: impl Into<ImmutablePaths>) -> Self {
+        self.caching.immutable_paths = Some(immutable_paths.into());
+        self
     }
-}
 
-impl<RequestBodyT, CacheT, CacheKeyT> Clone for CachingLayer<RequestBodyT, CacheT, CacheKeyT>
-where
-    CacheT: Cache<CacheKeyT>,
-    CacheKeyT: CacheKey,
-{
-    fn clone(&self) -> Self {
-        Self {
-            caching: self.caching.clone(),
-            encoding: self.encoding.clone(),
-        }
+    /// Assemble upstream `206 Partial Content` responses for matching paths into full entries.
+    ///
+    /// Ranges of objects up to `max_object_size` are passed through to the client and collected
+    /// until they cover the whole object, which is then stored as a normal entry. See
+    /// [RangeAssembly] (and [range_assembly](Self::range_assembly) to configure its TTL and
+    /// memory bound).
+    ///
+    /// Requires the `range-assembly` feature.
+    ///
+    /// [None] by default.
+    #[cfg(feature = "range-assembly")]
+    pub fn assemble_ranges(self, matcher: PathMatcher, max_object_size: usize) -> Self {
+        self.range_assembly(RangeAssembly::new(matcher, max_object_size))
     }
-}
+
+    /// Assemble upstream `206 Partial Content` responses into full entries.
+    ///
+    /// All services created by this layer share it.
+    ///
+    /// Requires the `range-assembly` feature.
+    ///
+    /// [None] by default.
+    #[cfg(feature = "range-assembly")]
+    pub fn range_assembly(mut self, range_assembly: RangeAssembly<CacheKeyT>) -> Self {
+        self.caching.range_assembly = Some(range_assembly);
+        self
+    }
+
+    /// Deduplicate non-idempotent requests (e.g. `POST`) that carry an `Idempotency-Key` header:
+    /// retries receive the first execution's response instead of executing again. See
+    /// [IdempotencyConfig].
+    ///
+    /// This is independent of response caching, and works even without a cache. Request bodies
+    /// are buffered in order to fingerprint the payload.
+    ///
+    /// All services created by this layer share it.
+    ///
+    /// Requires the `idempotency` feature.
+    ///
+    /// [None] by default.
+    #[cfg(feature = "idempotency")]
+    pub fn idempotency(mut self, idempotency: IdempotencyConfig) -> Self
+    where
+        RequestBodyT: 'static + Body + From<ImmutableBytes> + Send + Unpin,
+        RequestBodyT::Error: Into<CapturedError>,
+    {
+        self.caching.idempotency = Some(IdempotentRequests::new(idempotency));
+        self
+    }
+
+    /// Record the [PipelineStage] sequence of every request, e.g. in order to assert it against
+    /// golden expectations in tests. See [PipelineProbe].
+    ///
+    /// Requires the `test-util` feature.
+    ///
+    /// [None] by default.
+    #[cfg(feature = "test-util")]
+    pub fn pipeline_probe(mut self, pipeline_probe: PipelineProbe) -> Self {
+        self.caching.pipeline_probe = Some(pipeline_probe);
+        self
+    }
+
+    /// Hit rate service level objective for matching paths.
+    ///
+    /// The configured [SloAction] is notified when a window completes below target, and again
+    /// when the hit rate recovers. See [HitRateSlo].
+    ///
+    /// Can be called multiple times to add scopes.
+    pub fn hit_rate_slo(
+        mut self,
+        scope: impl Into<Arc<str>>,
+        matcher: PathMatcher,
+        configuration: SloConfig,
+    ) -> Self {
+        self.caching
+            .hit_rate_slos
+            .push(HitRateSlo::new(scope, matcher, configuration));
+        self
+    }
+
+    /// Handle cache-busting query parameters (e.g. `?v=<build>`) for matching paths.
+    ///
+    /// [KeyAndExpireOld](BustParamMode::KeyAndExpireOld) parameters stay in the cache key, but
+    /// when a newer value is first seen the entry for the previous value is invalidated, so that
+    /// old builds don't linger until they expire. [Strip](BustParamMode::Strip) parameters are
+    /// removed from the cache key so that random values can't pollute the cache.
+    ///
+    /// Requires a [CacheKey] implementation that supports
+    /// [set_query_parameter](CacheKey::set_query_parameter), such as [CommonCacheKey].
+    ///
+    /// Can be called multiple times to add policies.
+    pub fn bust_param_policy(mut self, bust_param_policy: BustParamPolicy) -> Self {
+        self.caching.bust_params.push(bust_param_policy);
+        self
+    }
+
+    /// Trust forwarded headers (`Forwarded` and/or `X-Forwarded-Proto` and `X-Forwarded-Host`)
+    /// from trusted proxies.
+    ///
+    /// Needed when behind a proxy (e.g. a TLS-terminating load balancer), so that the effective
+    /// scheme and host (see [RequestOrigin]) are the ones the client used. The headers are ignored
+    /// if the peer is not trusted, so that clients can't forge them. See [TrustPolicy].
+    ///
+    /// [None] by default.
+    pub fn trusted_forwarded(mut self, trust_policy: TrustPolicy) -> Self {
+        self.caching.trusted_forwarded = Some(trust_policy);
+        self
+    }
+
+    /// Partition cache keys by the effective origin (scheme, host, and port), e.g. for serving
+    /// multiple hosts.
+    ///
+    /// Requires a [CacheKey] implementation that supports [set_origin](CacheKey::set_origin),
+    /// such as [CommonCacheKey]. When behind a proxy you likely also want
+    /// [trusted_forwarded](Self::trusted_forwarded).
+    ///
+    /// The default is false.
+    pub fn partition_by_host(mut self, partition_by_host: bool) -> Self {
+        self.caching.partition_by_host = partition_by_host;
+        self
+    }
+
+    /// Partition cache keys for matching paths by an identifier derived from the request (e.g.
+    /// the authenticated tenant), so that activity in one partition can't be observed from
+    /// another via timing. See [PartitionPolicy].
+    ///
+    /// Composes with [partition_by_host](Self::partition_by_host). Requires a [CacheKey]
+    /// implementation that supports [set_partition](CacheKey::set_partition), such as
+    /// [CommonCacheKey].
+    ///
+    /// [None] by default.
+    pub fn partition_by(mut self, partition_policy: PartitionPolicy) -> Self {
+        self.caching.partition = Some(partition_policy);
+        self
+    }
+
+    /// Skip the cache for requests with any of these headers, e.g. `Authorization` or `Cookie`,
+    /// so that responses generated for one client are never served to another.
+    ///
+    /// Empty by default.
+    pub fn bypass_cache_on_headers(mut self, names: &[HeaderName]) -> Self {
+        self.caching.bypass_headers = names.into();
+        self
+    }
+
+    /// Partition cache keys by the values of these request headers, e.g. `Cookie`, so that
+    /// requests with different values (or without the header) get separate entries.
+    ///
+    /// The keys get a keyed digest of the values (see [keyed_hash]) rather than the values
+    /// themselves, so that credentials don't end up in logs or in shared cache storage, and can't
+    /// be guessed from the keys without the [header_digest_key](Self::header_digest_key).
+    ///
+    /// Applied before the [cache_key](Self::cache_key) hook (see
+    /// [set_header_digest](CacheKey::set_header_digest)). Note that [PURGE](Self::admin_methods)
+    /// only invalidates the entries for its own header values.
+    ///
+    /// Empty by default.
+    pub fn partition_cache_by_headers(mut self, names: &[HeaderName]) -> Self {
+        self.caching.partition_headers = names.into();
+        self
+    }
+
+    /// Key for the digests of [partition_cache_by_headers](Self::partition_cache_by_headers).
+    ///
+    /// Processes that share a cache must use the same key, and it should be kept secret.
+    ///
+    /// The default is random, i.e. different for every layer, so that partitioned entries are not
+    /// shared between processes (or between restarts, for persistent caches).
+    pub fn header_digest_key(mut self, header_digest_key: [u8; 16]) -> Self {
+        self.caching.header_digest_key = header_digest_key;
+        self
+    }
+
+    /// Skip the cache for requests with `Authorization`.
+    ///
+    /// This applies even if keys are partitioned by it (see
+    /// [partition_cache_by_headers](Self::partition_cache_by_headers)).
+    ///
+    /// The default is false.
+    pub fn strict_privacy(mut self, strict_privacy: bool) -> Self {
+        self.caching.strict_privacy = strict_privacy;
+        self
+    }
+
+    /// Which URI to use for cache keys.
+    ///
+    /// The default, [KeyUriSource::Original], makes keys consistent regardless of whether the
+    /// layer is attached to an outer or a nested axum router. The recommended attachment point is
+    /// the outer router.
+    pub fn key_uri_source(mut self, key_uri_source: KeyUriSource) -> Self {
+        self.caching.key_uri_source = key_uri_source;
+        self
+    }
+
+    /// Whether to skip caching for requests that no route matched, i.e. those handled by a
+    /// fallback (typically 404s for probed paths, which would otherwise fill the cache with junk
+    /// entries).
+    ///
+    /// Requires the `axum` feature. It relies on axum's `MatchedPath` request extension, so the
+    /// layer must be attached via `Router::layer` (rather than wrapping the router from the
+    /// outside).
+    ///
+    /// The default is false.
+    pub fn never_cache_unmatched(mut self, never_cache_unmatched: bool) -> Self {
+        self.caching.never_cache_unmatched = never_cache_unmatched;
+        self
+    }
+
+    /// Whether to cache requests with absolute-form targets (e.g. `GET http://example.com/path`),
+    /// as sent to proxies. Enable for reverse-proxy deployments that must accept them.
+    ///
+    /// Their keys are created from the origin-form (path and query), with the origin always set
+    /// from the target's scheme and authority (as with
+    /// [partition_by_host](Self::partition_by_host)), so that they can never collide with keys
+    /// for other hosts.
+    ///
+    /// Asterisk-form (`OPTIONS *`) and authority-form (`CONNECT`) targets are never cached. See
+    /// [RequestTargetForm].
+    ///
+    /// The default is false.
+    pub fn cache_absolute_form(mut self, cache_absolute_form: bool) -> Self {
+        self.caching.cache_absolute_form = cache_absolute_form;
+        self
+    }
+
+    /// Which request methods are cacheable.
+    ///
+    /// The default is [MethodPolicy::Idempotent].
+    pub fn methods(mut self, methods: MethodPolicy) -> Self {
+        self.caching.methods = methods;
+        self
+    }
+
+    /// Cacheable request methods ([MethodPolicy::Listed]), e.g. `POST` for a read-only API.
+    ///
+    /// Non-idempotent methods are only cached if requests are keyed by their body (see
+    /// [cache_key_with_body](Self::cache_key_with_body)).
+    pub fn cacheable_methods(mut self, methods: &[Method]) -> Self {
+        self.caching.methods = MethodPolicy::Listed(methods.into());
+        self
+    }
+
+    /// Whether to process our `XX-Cache`, `XX-Cache-Duration`, `XX-Cache-Tags`, `XX-Encode`, and
+    /// `XX-No-Synthetic-Validators` response headers.
+    ///
+    /// If false they are removed from upstream responses and have no effect, which is what you
+    /// want if you don't control the upstream.
+    ///
+    /// The default is true.
+    pub fn xx_headers(mut self, xx_headers: bool) -> Self {
+        self.caching.xx_headers = xx_headers;
+        self
+    }
+
+    /// Instance label, for stacking several layers intentionally. See [StackingRole].
+    ///
+    /// Control headers suffixed with `--` and the label (e.g. `XX-Cache--micro`) apply only to
+    /// this layer, and the [LayerOutcomes] response extension includes this layer's outcome. The
+    /// label must be valid in a header name (lowercase letters, digits, and `-` are safe).
+    ///
+    /// [None] by default.
+    pub fn label(mut self, label: impl Into<Arc<str>>) -> Self {
+        self.caching.label = Some(label.into());
+        self
+    }
+
+    /// Role in a stack of layers, which determines the division of labor between them. See
+    /// [StackingRole].
+    ///
+    /// A role that doesn't give synthetic validators sets
+    /// [synthetic_last_modified](Self::synthetic_last_modified) to [SyntheticLastModified::Never].
+    ///
+    /// Example of an outer micro-cache over an inner full cache:
+    ///
+    /// ```no_run
+    /// # use {
+    /// #     http::*,
+    /// #     http_body_util::*,
+    /// #     kutil::std::immutable::*,
+    /// #     std::convert::*,
+    /// #     tower::*,
+    /// #     tower_http_response_cache::{
+    /// #         cache::{implementation::moka::*, middleware::*, *},
+    /// #         *,
+    /// #     },
+    /// # };
+    /// # let new_cache = || {
+    /// #     let cache = moka::future::Cache::builder().for_http_response().build();
+    /// #     MokaCacheImplementation::new(cache)
+    /// # };
+    /// # let (micro_cache, full_cache) = (new_cache(), new_cache());
+    /// # let upstream = service_fn(|_request: Request<()>| async {
+    /// #     Ok::<_, Infallible>(Response::new(Full::<Bytes>::default()))
+    /// # });
+    /// let service = ServiceBuilder::new()
+    ///     .layer(
+    ///         DefaultCachingLayer::default()
+    ///             .cache(micro_cache)
+    ///             .label("micro")
+    ///             .role(StackingRole::MicroCache),
+    ///     )
+    ///     .layer(
+    ///         DefaultCachingLayer::default()
+    ///             .cache(full_cache)
+    ///             .label("full")
+    ///             .role(StackingRole::FullCache),
+    ///     )
+    ///     .service(upstream);
+    /// # let _: &CachingService<CachingService<_, (), _>, (), _> = &service;
+    /// ```
+    ///
+    /// The default is [StackingRole::Standalone].
+    pub fn role(mut self, role: StackingRole) -> Self {
+        if !role.synthetic_validators() {
+            self.caching.inner.synthetic_last_modified = SyntheticLastModified::Never;
+        }
+        self.caching.role = role;
+        self
+    }
+
+    /// Whether to serve byte ranges of cached entries.
+    ///
+    /// If true, cache hits for `GET` requests with a single byte range are answered with
+    /// `206 Partial Content` (or `416 Range Not Satisfiable`) from the entry's
+    /// [Identity](kutil::transcoding::Encoding::Identity) representation, which is decoded if
+    /// necessary. Ranged responses are never encoded, regardless of `Accept-Encoding`. Full
+    /// responses from the cache then have `Accept-Ranges: bytes`. See [RangeRequest].
+    ///
+    /// Entries of negative responses and [no_transform](CachedResponse::no_transform) entries
+    /// that are not stored in Identity are always served in full.
+    ///
+    /// The default is true.
+    pub fn serve_ranges(mut self, serve_ranges: bool) -> Self {
+        self.caching.serve_ranges = serve_ranges;
+        self
+    }
+
+    /// Write the [CacheStatus] of responses (`HIT`, `MISS`, or `BYPASS`) to a response header,
+    /// e.g. `X-Cache`.
+    ///
+    /// The status is always available as a response extension. This is a convenience for when
+    /// you want clients to see it, too.
+    ///
+    /// [None] by default.
+    pub fn expose_cache_status_header(mut self, header_name: HeaderName) -> Self {
+        self.caching.cache_status_header = Some(header_name);
+        self
+    }
+
+    /// Write the time (in whole seconds) that responses served from the cache were held in it to
+    /// a response header, e.g. `X-Cache-Age`.
+    ///
+    /// Unlike `Age` (see [age_accounting](Self::age_accounting)) it doesn't include the upstream
+    /// `Age`, and it is written regardless of age accounting. Responses that were not served from
+    /// the cache don't get it.
+    ///
+    /// [None] by default.
+    pub fn expose_cache_age_header(mut self, header_name: HeaderName) -> Self {
+        self.caching.cache_age_header = Some(header_name);
+        self
+    }
+
+    /// Handle Varnish-style cache administration methods (`PURGE` and `BAN`) in-band.
+    ///
+    /// Authorized requests are handled by the middleware and never reach the inner service.
+    /// Unauthorized ones are passed to the inner service untouched. See [AdminMethodConfig].
+    ///
+    /// [None] by default.
+    pub fn admin_methods(mut self, admin_methods: AdminMethodConfig) -> Self {
+        self.caching.admin_methods = Some(admin_methods);
+        self
+    }
+
+    /// Run `BAN` [admin methods](Self::admin_methods) as paced background jobs.
+    ///
+    /// Matching entries are hidden immediately, behind a purge fence, and invalidated in bounded
+    /// batches so that huge purges don't spike serving latency. `BAN` responds with `202
+    /// Accepted` and the job ID. Use [purge_jobs](Self::purge_jobs) to check on or cancel jobs.
+    /// See [PurgeJobs].
+    ///
+    /// Requires the `paced-purge` feature and a Tokio runtime.
+    ///
+    /// [None] by default.
+    #[cfg(feature = "paced-purge")]
+    pub fn paced_purges(mut self, pacing: PurgePacing) -> Self {
+        self.caching.purge_jobs = Some(PurgeJobs::new(pacing));
+        self
+    }
+
+    /// Paced bulk invalidation, if enabled.
+    ///
+    /// All services created by this layer share it.
+    #[cfg(feature = "paced-purge")]
+    pub fn purge_jobs(&self) -> Option<PurgeJobs> {
+        self.caching.purge_jobs.clone()
+    }
+
+    /// Provide a hook to map requests to resource IDs, grouping the cache keys of one resource
+    /// (e.g. its language variants).
+    ///
+    /// Used only for concurrency control (see
+    /// [max_concurrent_per_resource](Self::max_concurrent_per_resource)). Requests for which the
+    /// hook returns [None] are not grouped.
+    ///
+    /// [None] by default.
+    pub fn resource_id(
+        mut self,
+        resource_id: impl Fn(ResourceIdHookContext<CacheKeyT>) -> Option<String>
+        + 'static
+        + Send
+        + Sync,
+    ) -> Self {
+        self.caching.resource_id = Some(Arc::new(Box::new(resource_id)));
+        self
+    }
+
+    /// Limit concurrent upstream calls (for misses and refreshes) per resource.
+    ///
+    /// Calls beyond the limit are queued until a slot frees. This avoids a "thundering herd" of
+    /// upstream calls for the variants of a resource after it is invalidated or expires. Requires
+    /// a [resource_id](Self::resource_id) hook. See [ResourceLimiter].
+    ///
+    /// Unlimited by default.
+    pub fn max_concurrent_per_resource(mut self, max_concurrent: usize) -> Self {
+        self.caching.resource_limiter = Some(ResourceLimiter::new(max_concurrent));
+        self
+    }
+
+    /// Provide a hook to transform a response body before storing it, e.g. to minify it.
+    ///
+    /// The hook receives the [Identity](kutil::transcoding::Encoding::Identity) body. Errors are
+    /// logged and the original body is stored instead. The transformed body must be within the
+    /// cacheable size limits in order to be stored.
+    ///
+    /// [None] by default.
+    pub fn transform_before_store(
+        mut self,
+        transform: impl Fn(TransformHookContext) -> TransformResult + 'static + Send + Sync,
+    ) -> Self {
+        self.caching.inner.transform_before_store = Some(Arc::new(Box::new(transform)));
+        self
+    }
+
+    /// What the request that triggered a [transform](Self::transform_before_store) receives.
+    ///
+    /// The default is [OriginalFirst](TransformPolicy::OriginalFirst).
+    pub fn transform_policy(mut self, transform_policy: TransformPolicy) -> Self {
+        self.caching.inner.transform_policy = transform_policy;
+        self
+    }
+
+    /// Heuristic freshness for responses with no other duration.
+    ///
+    /// This is the lowest-priority duration source: it applies only if there is no
+    /// `XX-Cache-Duration` header, route policy duration, or [cache_duration](Self::cache_duration)
+    /// hook result. It is also skipped if the response has an explicit expiration. See
+    /// [HeuristicConfig].
+    ///
+    /// [None] by default.
+    pub fn heuristic_freshness(mut self, heuristic_freshness: HeuristicConfig) -> Self {
+        self.caching.inner.heuristic_freshness = Some(heuristic_freshness);
+        self
+    }
+
+    /// Random jitter for cache durations, so that entries stored in a burst don't all expire at
+    /// the same time. See [JitterConfig].
+    ///
+    /// It's applied to durations from all sources, when an entry is stored and when it's
+    /// refreshed.
+    ///
+    /// [None] by default.
+    pub fn ttl_jitter(mut self, ttl_jitter: JitterConfig) -> Self {
+        self.caching.inner.ttl_jitter = Some(ttl_jitter);
+        self
+    }
+
+    /// Whether to respect standard response headers as a shared cache would:
+    ///
+    /// * Responses with `Cache-Control` `no-store`, `no-cache`, or `private` are not cached.
+    /// * Responses with `Vary` on request headers that are not accounted for by the cache key
+    ///   (anything other than `Accept-Encoding`, and `Accept-Language` if we
+    ///   [negotiate languages](Self::negotiate_languages)) are not cached.
+    /// * The cache duration is derived from `s-maxage`, `max-age`, or `Expires`, minus `Age`.
+    ///   The `XX-Cache-Duration` header, route policy duration, and
+    ///   [cache_duration](Self::cache_duration) hook take precedence.
+    /// * Responses with `max-age=0` (or `s-maxage=0`) are not cached, unless they have the
+    ///   `XX-Cache-Duration` header.
+    ///
+    /// Unknown directives and invalid values are ignored, so a malformed `Cache-Control` falls
+    /// back to the default behavior.
+    ///
+    /// The default is false.
+    pub fn respect_cache_control(mut self, respect_cache_control: bool) -> Self {
+        self.caching.inner.respect_cache_control = respect_cache_control;
+        self
+    }
+
+    /// Log a summary of the caching decisions at info level for requests that take longer than
+    /// this to handle.
+    ///
+    /// The measured duration is from the start of handling until the response is constructed. It
+    /// does not include streaming the body downstream. The summary includes a timing breakdown
+    /// (cache lookup, upstream, body read, and transcode) and names the dominant phase.
+    ///
+    /// Faster requests are logged as usual.
+    ///
+    /// [None] by default.
+    pub fn log_slow_over(mut self, log_slow_over: Duration) -> Self {
+        self.caching.log_slow_over = Some(log_slow_over);
+        self
+    }
+
+    /// Limit the time that the caching layer may add to each request.
+    ///
+    /// Time spent awaiting the inner service is not counted. If the budget is exhausted then we
+    /// fail open: the upstream response is served as is (uncached and not encoded), or, for hits,
+    /// a stored representation that needs no further work. Exhaustion is never an error. See
+    /// [OverheadBudget].
+    ///
+    /// Abandoning a slow cache or transcoder midway requires the `overhead-budget` feature.
+    /// Without it the budget is only checked between operations.
+    ///
+    /// Use [overhead_budget](Self::overhead_budget) to access the exhaustion counts.
+    ///
+    /// [None] by default.
+    pub fn max_overhead(mut self, max_overhead: Duration) -> Self {
+        self.caching.overhead_budget = Some(OverheadBudget::new(max_overhead));
+        self
+    }
+
+    /// Overhead budget, if configured.
+    ///
+    /// All services created by this layer share it.
+    pub fn overhead_budget(&self) -> Option<OverheadBudget> {
+        self.caching.overhead_budget.clone()
+    }
+
+    /// How to account for the time an entry was held in the cache when serving it.
+    ///
+    /// This matters if there are caches downstream (e.g. a CDN), which would otherwise consider
+    /// entries served from our cache to be brand new.
+    ///
+    /// The default is [AgeAccounting::EmitAge].
+    pub fn age_accounting(mut self, age_accounting: AgeAccounting) -> Self {
+        self.caching.inner.age_accounting = age_accounting;
+        self
+    }
+
+    /// When to give stored entries a synthetic `Last-Modified` if the upstream response doesn't
+    /// have one.
+    ///
+    /// Synthetic validators are only accurate to the second, which can cause spurious
+    /// `304 Not Modified` responses for content that changes faster than that. They can also be
+    /// disabled per response with the `XX-No-Synthetic-Validators` header.
+    ///
+    /// The default is [SyntheticLastModified::Always].
+    pub fn synthetic_last_modified(
+        mut self,
+        synthetic_last_modified: SyntheticLastModified,
+    ) -> Self {
+        self.caching.inner.synthetic_last_modified = synthetic_last_modified;
+        self
+    }
+
+    /// Provide a clock hook.
+    ///
+    /// Used for entry creation times, synthetic `Last-Modified`, and age accounting. Mostly useful
+    /// for testing.
+    ///
+    /// [None] by default, meaning that the system time is used.
+    pub fn clock(mut self, clock: impl Fn() -> SystemTime + 'static + Send + Sync) -> Self {
+        self.caching.inner.clock = Some(Arc::new(Box::new(clock)));
+        self
+    }
+
+    /// How cache keys are rendered in logs and error messages.
+    ///
+    /// Keys can include sensitive or very long query strings. Use [KeyLogPolicy::redacted] to
+    /// redact query values and cap lengths. Note that cache implementations and
+    /// [ReencodeJob] have their own settings.
+    ///
+    /// The default renders keys in full.
+    pub fn key_log_policy(mut self, key_log_policy: KeyLogPolicy) -> Self {
+        self.caching.inner.key_log_policy = key_log_policy;
+        self
+    }
+
+    /// Assume that a compression middleware (e.g. tower-http's `CompressionLayer`) handles
+    /// encoding, disabling this layer's encoding entirely.
+    ///
+    /// Supported orderings:
+    ///
+    /// * Compression middleware *above* (wrapping) this layer: we store and serve
+    ///   [Identity](kutil::transcoding::Encoding::Identity) and it encodes our responses.
+    /// * Compression middleware *below* this layer: we decode its encoded bodies when storing
+    ///   them, so that the cache holds and serves
+    ///   [Identity](kutil::transcoding::Encoding::Identity). Responses that we don't cache pass
+    ///   through as encoded by it.
+    ///
+    /// Either way, bodies are never encoded twice. Without this setting, we warn (once per route)
+    /// if an upstream response is already encoded. See [COMPRESSION_CONFLICT_HELP].
+    ///
+    /// The default is false.
+    pub fn assume_inner_compression(mut self, assume_inner_compression: bool) -> Self {
+        self.encoding.assume_inner_compression = assume_inner_compression;
+        self
+    }
+
+    /// Enable encodings in order from most preferred to least.
+    ///
+    /// Will be negotiated with the client's preferences (in its `Accept-Encoding` header) to
+    /// select the best.
+    ///
+    /// There is no need to specify [Identity](kutil::transcoding::Encoding::Identity) as it is
+    /// always enabled.
+    ///
+    /// Encodings whose crate feature is not enabled will be served as
+    /// [Identity](kutil::transcoding::Encoding::Identity) (with a warning in the logs).
+    ///
+    /// The default is [ENCODINGS_BY_PREFERENCE].
+    pub fn enable_encodings(
+        mut self,
+        enabled_encodings_by_preference: Vec<EncodingHeaderValue>,
+    ) -> Self {
+        self.encoding.enabled_encodings_by_preference =
+            Some(enabled_encodings_by_preference.into());
+        self
+    }
+
+    /// Enable a custom coding, e.g. a dictionary-based or experimental coding.
+    ///
+    /// Custom codings are preferred over the built-in encodings, in the order in which they are
+    /// enabled. The default negotiator selects a custom coding only if the client explicitly
+    /// accepts it.
+    ///
+    /// Custom codings are only used for cached responses. Non-cached responses cannot be
+    /// streamed in a custom coding and will be sent as
+    /// [Identity](kutil::transcoding::Encoding::Identity) instead.
+    pub fn enable_custom_coding(
+        mut self,
+        name: &'static str,
+        transcoder: impl Transcoder + 'static,
+    ) -> Self {
+        self.encoding.inner.transcoders.register(name, Arc::new(transcoder));
+        let mut custom_codings = self.encoding.enabled_custom_codings_by_preference.to_vec();
+        custom_codings.push(name);
+        self.encoding.enabled_custom_codings_by_preference = custom_codings.into();
+        self
+    }
+
+    /// Provide an `Accept-Encoding` negotiator.
+    ///
+    /// The default is [CommonEncodingNegotiator].
+    pub fn negotiator(mut self, negotiator: impl EncodingNegotiator + 'static) -> Self {
+        self.encoding.negotiator = Arc::new(negotiator);
+        self
+    }
+
+    /// Maximum number of request `Accept-Encoding` members. Beyond it we will not encode.
+    ///
+    /// The negotiator gets normalized `Accept-Encoding` values. See [AcceptEncoding].
+    ///
+    /// The default is [DEFAULT_MAX_ACCEPT_ENCODING_MEMBERS].
+    pub fn max_accept_encoding_members(mut self, max_accept_encoding_members: usize) -> Self {
+        self.encoding.max_accept_encoding_members = max_accept_encoding_members;
+        self
+    }
+
+    /// Counter for requests with malformed `Accept-Encoding`.
+    ///
+    /// Keep a clone in order to read its counts. By default a new counter is used.
+    pub fn accept_encoding_anomalies(
+        mut self,
+        accept_encoding_anomalies: AcceptEncodingAnomalyCounter,
+    ) -> Self {
+        self.encoding.accept_encoding_anomalies = accept_encoding_anomalies;
+        self
+    }
+
+    /// Disables encoding.
+    ///
+    /// The default is [ENCODINGS_BY_PREFERENCE].
+    pub fn disable_encoding(mut self) -> Self {
+        self.encoding.enabled_encodings_by_preference = None;
+        self
+    }
+
+    /// Minimum size in bytes of response bodies to encode.
+    ///
+    /// Note that non-cached responses without `Content-Length` cannot be checked against this
+    /// value.
+    ///
+    /// The default is 0.
+    pub fn min_encodable_body_size(mut self, min_encodable_body_size: usize) -> Self {
+        self.encoding.inner.min_body_size = min_encodable_body_size;
+        self
+    }
+
+    /// If a response does not specify the `XX-Encode` response header then this we will assume its
+    /// value is this.
+    ///
+    /// The default is true.
+    pub fn encodable_by_default(mut self, encodable_by_default: bool) -> Self {
+        self.encoding.inner.encodable_by_default = encodable_by_default;
+        self
+    }
+
+    /// Provide a hook to test whether a request is encodable.
+    ///
+    /// Will only be called after all internal conditions are met, giving you one last chance to
+    /// prevent encoding.
+    ///
+    /// Note that the headers are *request* headers. This hook is called before we have the
+    /// upstream response.
+    ///
+    /// [None] by default.
+    pub fn encodable_by_request(
+        mut self,
+        encodable_by_request: impl Fn(EncodableHookContext) -> bool + 'static + Send + Sync,
+    ) -> Self {
+        self.encoding.encodable_by_request = Some(Arc::new(Box::new(encodable_by_request)));
+        self
+    }
+
+    /// Provide a hook to test whether a response is encodable.
+    ///
+    /// Will only be called after all internal conditions are met, giving you one last chance to
+    /// prevent encoding.
+    ///
+    /// Note that the headers are *response* headers. This hook is called *after* we get the
+    /// upstream response but *before* we read its body.
+    ///
+    /// [None] by default.
+    pub fn encodable_by_response(
+        mut self,
+        encodable_by_response: impl Fn(EncodableHookContext) -> bool + 'static + Send + Sync,
+    ) -> Self {
+        self.encoding.encodable_by_response = Some(Arc::new(Box::new(encodable_by_response)));
+        self
+    }
+
+    /// Whether to keep an [Identity](kutil::transcoding::Encoding::Identity) in the cache if it is
+    /// created during reencoding.
+    ///
+    /// Keeping it optimizes for compute with the trade-off of taking up more room in the cache.
+    ///
+    /// The default is true.
+    pub fn keep_identity_encoding(mut self, keep_identity_encoding: bool) -> Self {
+        self.encoding.inner.keep_identity_encoding = keep_identity_encoding;
+        self
+    }
+
+    /// Maximum number of representations (codings) cached per entry, including Identity.
+    ///
+    /// When serving a new coding would exceed it, the least useful representations are dropped:
+    /// the just-requested coding is kept, then Identity, then the smallest of the others. The
+    /// entry's [weight](CacheWeight) shrinks accordingly when it is re-stored. Must be at least 1.
+    ///
+    /// [None] (unlimited) by default.
+    pub fn max_cached_encodings(mut self, max_cached_encodings: usize) -> Self {
+        self.encoding.inner.max_cached_encodings = Some(max_cached_encodings.max(1));
+        self
+    }
+
+    /// What to do with the `ETag` of responses that we transform by serving them in a coding
+    /// other than the original, whether encoding on the fly or from the cache.
+    ///
+    /// Digest headers are always removed from transformed responses.
+    ///
+    /// The default is [Suffix](OnTheFlyValidatorPolicy::Suffix).
+    pub fn on_the_fly_validator_policy(mut self, policy: OnTheFlyValidatorPolicy) -> Self {
+        self.encoding.inner.on_the_fly_validators = policy;
+        self
+    }
+
+    /// Whether to respect `Cache-Control: no-transform` by never changing the content coding of
+    /// responses that have it, or of responses to requests that have it.
+    ///
+    /// Applies regardless of any other `Cache-Control` handling. Disabling it violates
+    /// [IETF RFC 9111](https://datatracker.ietf.org/doc/html/rfc9111#section-5.2.2.6), so do so
+    /// only if no client depends on it.
+    ///
+    /// The default is true.
+    pub fn respect_no_transform(mut self, respect_no_transform: bool) -> Self {
+        self.encoding.inner.respect_no_transform = respect_no_transform;
+        self
+    }
+
+    /// Whether to treat all responses as if they had `Cache-Control: no-transform`, i.e. to store
+    /// and serve them exactly as the upstream encoded them.
+    ///
+    /// The default is false.
+    pub fn never_transform(mut self, never_transform: bool) -> Self {
+        self.encoding.inner.never_transform = never_transform;
+        self
+    }
+
+    /// Whether to prune representations in codings that are no longer enabled when an entry is
+    /// modified (i.e. reencoded or refreshed).
+    ///
+    /// Such representations are never served either way, so this only reclaims room in the
+    /// cache. See [RepresentationSkew] for measuring how many entries have them.
+    ///
+    /// The default is false.
+    pub fn prune_disabled_representations(mut self, prune_disabled_representations: bool) -> Self {
+        self.encoding.inner.prune_disabled_representations = prune_disabled_representations;
+        self
+    }
+
+    /// Whether to verify every newly encoded representation by decoding it back and comparing it
+    /// with its source before storing it.
+    ///
+    /// Representations that fail verification are never stored or served. We fall back to the
+    /// source instead and quarantine the (coding, input size) pair. See [TranscodeVerification].
+    ///
+    /// This costs a decode per encode. See also
+    /// [verify_transcodes_sampled](Self::verify_transcodes_sampled).
+    ///
+    /// The default is false.
+    pub fn verify_transcodes(mut self, verify_transcodes: bool) -> Self {
+        self.encoding.inner.verification =
+            verify_transcodes.then(|| TranscodeVerification::new(1.0));
+        self
+    }
+
+    /// Like [verify_transcodes](Self::verify_transcodes) but verifies only a fraction of encodes.
+    ///
+    /// `rate` is between 0.0 and 1.0.
+    pub fn verify_transcodes_sampled(mut self, rate: f64) -> Self {
+        self.encoding.inner.verification = Some(TranscodeVerification::new(rate));
+        self
+    }
+
+    /// Suppress a configuration warning by its code, for deployments that know better.
+    ///
+    /// Errors cannot be suppressed. See [diagnostics](Self::diagnostics).
+    pub fn allow(mut self, diagnostic_code: &'static str) -> Self {
+        self.caching.allowed_diagnostics.push(diagnostic_code);
+        self
+    }
+
+    /// Describe the effective caching policy for API consumers. See [PolicyDescription].
+    pub fn describe_policy(&self) -> PolicyDescription {
+        self.policy_describer().describe()
+    }
+
+    /// Live source of [PolicyDescription], e.g. as state for an API documentation handler.
+    ///
+    /// Runtime state shared by all services created by this layer, such as the
+    /// [cache_override](Self::cache_override), is described on every call. See
+    /// [PolicyDescriber].
+    pub fn policy_describer(&self) -> PolicyDescriber {
+        PolicyDescriber::new(&self.caching, &self.encoding)
+    }
+
+    /// Check the configuration for conflicting, dead, or suspicious settings.
+    ///
+    /// Suppressed warnings (see [allow](Self::allow)) are not included. See
+    /// [configuration_rules].
+    pub fn diagnostics(&self) -> Vec<ConfigurationDiagnostic> {
+        check_configuration(&self.caching, &self.encoding, &self.caching.allowed_diagnostics)
+    }
+
+    /// Build the service, failing if the configuration has errors.
+    ///
+    /// The error lists all diagnostics. Warnings are logged (once per layer and its clones).
+    pub fn build_checked<InnerServiceT>(
+        &self,
+        inner_service: InnerServiceT,
+    ) -> Result<CachingService<InnerServiceT, RequestBodyT, CacheT, CacheKeyT>, ConfigurationError>
+    {
+        let diagnostics = self.diagnostics();
+
+        if diagnostics
+            .iter()
+            .any(|diagnostic| diagnostic.severity == DiagnosticSeverity::Error)
+        {
+            return Err(ConfigurationError { diagnostics });
+        }
+
+        self.diagnosed.call_once(|| {
+            for diagnostic in &diagnostics {
+                tracing::warn!("{}", diagnostic);
+            }
+        });
+
+        Ok(CachingService::new(inner_service, self.caching.clone(), self.encoding.clone()))
+    }
+}
+
+/// [CachingLayer] with [CommonCacheKey] and the Moka cache implementation.
+#[cfg(feature = "moka")]
+pub type DefaultCachingLayer<RequestBodyT> =
+    CachingLayer<RequestBodyT, implementation::moka::MokaCacheImplementation, CommonCacheKey>;
+
+impl<RequestBodyT, CacheT, CacheKeyT> Default for CachingLayer<RequestBodyT, CacheT, CacheKeyT>
+where
+    CacheT: Cache<CacheKeyT>,
+    CacheKeyT: CacheKey,
+{
+    fn default() -> Self {
+        Self {
+            caching: Default::default(),
+            encoding: Default::default(),
+            diagnosed: Arc::new(Once::new()),
+        }
+    }
+}
+
+impl<RequestBodyT, CacheT, CacheKeyT> Clone for CachingLayer<RequestBodyT, CacheT, CacheKeyT>
+where
+    CacheT: Cache<CacheKeyT>,
+    CacheKeyT: CacheKey,
+{
+    fn clone(&self) -> Self {
+        Self {
+            caching: self.caching.clone(),
+            encoding: self.encoding.clone(),
+            diagnosed: self.diagnosed.clone(),
+        }
+    }
+}
 
 impl<InnerServiceT, RequestBodyT, CacheT, CacheKeyT> Layer<InnerServiceT>
     for CachingLayer<RequestBodyT, CacheT, CacheKeyT>
@@ -470,7 +1860,11 @@ where
 {
     type Service = CachingService<InnerServiceT, RequestBodyT, CacheT, CacheKeyT>;
 
+    /// Panics if the configuration has errors. See [build_checked](CachingLayer::build_checked).
     fn layer(&self, inner_service: InnerServiceT) -> Self::Service {
-        CachingService::new(inner_service, self.caching.clone(), self.encoding.clone())
+        match self.build_checked(inner_service) {
+            Ok(service) => service,
+            Err(error) => panic!("{}", error),
+        }
     }
 }
@@ -1,12 +1,36 @@
 // https://stackoverflow.com/a/61417700
 #![cfg_attr(docsrs, feature(doc_cfg))]
 #![warn(missing_docs)]
+#![allow(clippy::module_inception, clippy::too_many_arguments)]
 #![doc = include_str!("../README.md")]
 
+#[cfg(feature = "middleware")]
 mod layer;
+#[cfg(feature = "middleware")]
 mod service;
 
 /// Cache.
 pub mod cache;
 
+/// Compatibility shims for the previous API version.
+///
+/// Enabled by the `compat-0x` feature. Shims are deprecated adapters onto the current API and are
+/// kept for one major version, allowing you to upgrade the dependency first and then migrate call
+/// sites incrementally.
+#[cfg(feature = "compat-0x")]
+pub mod compat;
+
+/// Cache key agreement between processes and platforms.
+///
+/// Enabled by the `test-util` feature.
+#[cfg(feature = "test-util")]
+pub mod agreement;
+
+/// [Cache](cache::Cache) conformance suite for implementors.
+///
+/// Enabled by the `test-util` feature.
+#[cfg(feature = "test-util")]
+pub mod conformance;
+
+#[cfg(feature = "middleware")]
 pub use {layer::*, service::*};
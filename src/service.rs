@@ -1,13 +1,15 @@
 use super::cache::{middleware::*, *};
 
 use {
-    http::{request::*, response::*},
+    http::{header::*, request::*, response::*, HeaderMap, Method, StatusCode, Uri},
     http_body::*,
     kutil::{
         http::{transcoding::*, *},
         std::{error::*, future::*, immutable::*},
     },
-    std::{convert::*, mem, result::Result, sync::*, task::*},
+    std::{
+        convert::*, fmt, future, mem, ops::ControlFlow, result::Result, sync::*, task::*, time::*,
+    },
     tower::*,
 };
 
@@ -19,14 +21,52 @@ use {
 ///
 /// You will often be using [CachingLayer](super::CachingLayer) rather than this service directly,
 /// thus this service's functionality is documented there.
+///
+/// Driving the service directly, without an HTTP server:
+///
+/// ```
+/// use {
+///     http::*,
+///     http_body_util::*,
+///     kutil::std::immutable::*,
+///     std::convert::*,
+///     tower::*,
+///     tower_http_response_cache::{
+///         cache::{implementation::moka::*, *},
+///         *,
+///     },
+/// };
+///
+/// # tokio::runtime::Runtime::new().expect("runtime").block_on(async {
+/// let cache = moka::future::Cache::<CommonCacheKey, _, _>::builder()
+///     .for_http_response()
+///     .max_capacity(1024 * 1024)
+///     .build();
+///
+/// let mut service = DefaultCachingLayer::<()>::default()
+///     .cache(MokaCacheImplementation::new(cache))
+///     .layer(service_fn(|_request: Request<()>| async {
+///         Ok::<_, Infallible>(Response::new(Full::new(Bytes::from_static(b"Hello, world!\n"))))
+///     }));
+///
+/// for _ in 0..2 {
+///     let response = service
+///         .oneshot_ready(Request::get("/").body(()).expect("request"))
+///         .await
+///         .expect("response");
+///     let body = response.into_body().collect().await.expect("body").to_bytes();
+///     assert_eq!(body, "Hello, world!\n");
+/// }
+/// # });
+/// ```
 pub struct CachingService<InnerServiceT, RequestBodyT, CacheT, CacheKeyT = CommonCacheKey>
 where
     CacheT: Cache<CacheKeyT>,
     CacheKeyT: CacheKey,
 {
     inner_service: InnerServiceT,
-    caching: MiddlewareCachingConfiguration<RequestBodyT, CacheT, CacheKeyT>,
-    encoding: MiddlewareEncodingConfiguration,
+    configuration: Arc<MiddlewareConfiguration<RequestBodyT, CacheT, CacheKeyT>>,
+    controlled: ControlledConfiguration<RequestBodyT, CacheT, CacheKeyT>,
 }
 
 impl<InnerServiceT, RequestBodyT, CacheT, CacheKeyT>
@@ -42,29 +82,1940 @@ where
         encoding: MiddlewareEncodingConfiguration,
     ) -> Self {
         assert!(caching.inner.min_body_size <= caching.inner.max_body_size);
+        let controlled =
+            ControlledConfiguration::new(MiddlewareConfiguration::new(caching, encoding));
         Self {
             inner_service,
-            caching: caching.clone(),
-            encoding: encoding.clone(),
+            configuration: controlled.built.clone(),
+            controlled,
+        }
+    }
+
+    /// Run the cache self-test now.
+    ///
+    /// If [verify_cache_on_first_use](super::CachingLayer::verify_cache_on_first_use) is
+    /// configured then the outcome is remembered and the first request will not run it again.
+    pub async fn verify(&self) -> Result<SelfTestReport, SelfTestError> {
+        self.configuration.caching.verify_cache().await
+    }
+
+    /// Scan the cache for entries with representations that don't match the enabled codings.
+    ///
+    /// Returns [None] if there is no cache or if it doesn't support key enumeration.
+    pub async fn representation_skew(&self) -> Option<RepresentationSkew> {
+        let cache = self.configuration.caching.cache.as_ref()?;
+        RepresentationSkew::scan(cache, &self.configuration.encoding.inner).await
+    }
+
+    // Clone while keeping `inner_service`.
+    //
+    // See: https://docs.rs/tower/latest/tower/trait.Service.html#be-careful-when-cloning-inner-services
+    fn clone_and_keep_inner_service(&mut self) -> Self
+    where
+        InnerServiceT: Clone,
+    {
+        let mut clone = self.clone();
+        clone.inner_service = mem::replace(&mut self.inner_service, clone.inner_service);
+        clone
+    }
+
+    // Handle request.
+    async fn handle<ResponseBodyT>(
+        mut self,
+        mut request: Request<RequestBodyT>,
+    ) -> Result<Response<TranscodingBody<ResponseBodyT>>, InnerServiceT::Error>
+    where
+        Self: Send,
+        InnerServiceT: 'static
+            + Service<Request<RequestBodyT>, Response = Response<ResponseBodyT>>
+            + Clone
+            + Send,
+        InnerServiceT::Future: Send,
+        RequestBodyT: 'static + Send,
+        ResponseBodyT: 'static + Body + From<ImmutableBytes> + Send + Unpin,
+        ResponseBodyT::Data: From<ImmutableBytes> + Send,
+        ResponseBodyT::Error: Into<CapturedError>,
+    {
+        // Runtime overrides
+        self.configuration = self.controlled.current();
+
+        if let Some(admin_methods) = &self.configuration.caching.admin_methods
+            && let Some(verb) = admin_methods.verb(&request)
+        {
+            let mut response =
+                Self::handle_admin(&self.configuration, admin_methods, verb, request).await;
+            let status = CacheStatus::Bypass { reason: "admin" };
+            Self::insert_cache_status(&self.configuration, &mut response, status);
+            return Ok(response);
+        }
+
+        let start = Instant::now();
+
+        // `handle_with_context` consumes us; one refcount for everything we need after it
+        let configuration = self.configuration.clone();
+
+        // The request stage runs its hooks while constructing the context
+        #[cfg(feature = "test-util")]
+        if let Some(probe) = &self.configuration.caching.pipeline_probe {
+            probe.record(PipelineStage::Request, None);
+        }
+
+        let mut context = RequestCacheContext::new(
+            &request,
+            &self.configuration.caching,
+            &self.configuration.encoding,
+        );
+
+        if let Some(body_key) = &configuration.caching.body_key
+            && let Some(cache_key) = &mut context.cache_key
+            && body_key.applies(&request)
+        {
+            request = match body_key.apply(request, cache_key).await {
+                BodyKeyOutcome::Keyed(request) => request,
+
+                BodyKeyOutcome::Skip(request) => {
+                    tracing::debug!("skip (request body)");
+                    context.trail.decide("skip (request body)");
+                    context.skip_cache = true;
+                    context.cache_key = None;
+                    request
+                }
+
+                BodyKeyOutcome::Failed(error) => {
+                    tracing::debug!("could not read request body: {}", error);
+                    let mut response =
+                        admin_response(StatusCode::BAD_REQUEST, format!("{}\n", error));
+                    let status = CacheStatus::Bypass {
+                        reason: "request body",
+                    };
+                    Self::insert_cache_status(&configuration, &mut response, status);
+                    return Ok(response);
+                }
+            };
+        }
+
+        // The keys must be created before the request is consumed
+        let conflict_keys = match &configuration.caching.conflict_invalidation {
+            Some(conflict_invalidation)
+                if configuration.caching.cache.is_some()
+                    && conflict_invalidation.applies(request.method()) =>
+            {
+                uri_cache_keys(&mut request, &configuration.caching)
+            }
+
+            _ => Vec::default(),
+        };
+
+        // Boxed for the same reason as the stages (see `handle_with_context`)
+        #[cfg(feature = "idempotency")]
+        let response = match &configuration.caching.idempotency {
+            Some(idempotency)
+                if idempotency.config.applies(request.method(), request.uri().path()) =>
+            {
+                Box::pin(self.handle_idempotent(idempotency, request, &mut context)).await
+            }
+
+            _ => Box::pin(self.handle_with_context(request, &mut context)).await,
+        };
+
+        #[cfg(not(feature = "idempotency"))]
+        let response = Box::pin(self.handle_with_context(request, &mut context)).await;
+
+        // Upstream errors have nothing to learn from or store (and needn't be held across awaits)
+        let response =
+            response.inspect_err(|_| Self::record_stage(&configuration, &mut context, start))?;
+
+        // HEAD hits are served from GET entries, with the same headers but without the body
+        let response = if context.method == Method::HEAD && context.trail.is_hit() {
+            let (parts, _body) = response.into_parts();
+            Response::from_parts(parts, ImmutableBytes::default().into())
+        } else {
+            response
+        };
+
+        // The remaining stages, in declared order
+        Self::feedback_stage(
+            &configuration,
+            &conflict_keys,
+            response.status(),
+            response.headers(),
+            &mut context,
+        )
+        .await;
+        Self::store_stage(&configuration, &mut context).await;
+        let response = Self::serve_stage(&configuration, response, &mut context);
+        Self::record_stage(&configuration, &mut context, start);
+
+        Ok(response)
+    }
+
+    // Feedback stage: learn from the response.
+    async fn feedback_stage(
+        configuration: &MiddlewareConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+        conflict_keys: &[CacheKeyT],
+        status: StatusCode,
+        headers: &HeaderMap,
+        context: &mut RequestCacheContext<CacheKeyT>,
+    ) {
+        context.enter(PipelineStage::Feedback);
+
+        if !conflict_keys.is_empty()
+            && let Some(conflict_invalidation) = &configuration.caching.conflict_invalidation
+            && let Some(cache) = &configuration.caching.cache
+        {
+            conflict_invalidation.invalidate(cache, conflict_keys, status, headers).await;
+        }
+
+        if let Some(learned_bypass) = &configuration.caching.learned_bypass
+            && let Some(cache_key) = &context.cache_key
+        {
+            if context.uncacheable {
+                learned_bypass.learn(cache_key);
+            } else if context.pending_store.is_some() || context.trail.is_hit() {
+                learned_bypass.forget(cache_key);
+            }
+        }
+    }
+
+    // Store stage: one write per request, once the response has been constructed.
+    async fn store_stage(
+        configuration: &MiddlewareConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+        context: &mut RequestCacheContext<CacheKeyT>,
+    ) {
+        context.enter(PipelineStage::Store);
+
+        if let Some(pending_store) = context.pending_store.take()
+            && let Some(cache) = &configuration.caching.cache
+        {
+            // Reencodes are reported when they happen
+            let store_event = match &configuration.caching.on_cache_event {
+                Some(on_cache_event) if matches!(pending_store, PendingStore::Put { .. }) => {
+                    let body = &pending_store.cached_response().body;
+                    let kind = CacheEventKind::Store {
+                        encodings: body.representations.keys().cloned().collect(),
+                        size: body.representations.values().map(|bytes| bytes.len()).sum(),
+                        constraint: context.duration_constraint.clone(),
+                    };
+                    Some((on_cache_event, pending_store.key().clone(), kind))
+                }
+
+                _ => None,
+            };
+
+            // Merges don't add entries
+            let audit_key = match &configuration.caching.weight_audit {
+                Some(weight_audit) if matches!(pending_store, PendingStore::Put { .. }) => {
+                    Some((weight_audit, pending_store.key().clone()))
+                }
+
+                _ => None,
+            };
+
+            let mut notifier = StoreNotifier::new(
+                configuration.caching.on_store.as_ref(),
+                pending_store.pathway(),
+                &configuration.caching.inner.key_log_policy,
+            );
+            let store_start = Instant::now();
+            if context
+                .within_budget(pending_store.commit(
+                    cache,
+                    &configuration.encoding.inner,
+                    &mut notifier,
+                ))
+                .await
+                .is_none()
+            {
+                context.exhaust_budget(BudgetPhase::Store);
+            }
+            context.trail.store = store_start.elapsed().saturating_sub(notifier.elapsed);
+            context.trail.store_hook += notifier.elapsed;
+
+            if notifier.stored && let Some((on_cache_event, key, kind)) = store_event {
+                on_cache_event(CacheEvent::new(&context.uri, Some(&key), kind));
+            }
+
+            if notifier.stored && let Some((weight_audit, key)) = audit_key {
+                weight_audit.record(&key);
+            }
+        }
+
+        // Coalesced followers can now find the entry (or miss again if there is none)
+        context.coalescing_guard = None;
+    }
+
+    // Serve stage: serve-time transformations of the response.
+    fn serve_stage<BodyT>(
+        configuration: &MiddlewareConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+        mut response: Response<BodyT>,
+        context: &mut RequestCacheContext<CacheKeyT>,
+    ) -> Response<BodyT> {
+        context.enter(PipelineStage::Serve);
+        let headers_start = Instant::now();
+
+        if let Some(language) = &context.language {
+            LanguageNegotiation::set_response_headers(language, response.headers_mut());
+        }
+
+        if context.immutable
+            && let Some(immutable_paths) = &configuration.caching.immutable_paths
+            && (response.status().is_success() || response.status() == StatusCode::NOT_MODIFIED)
+        {
+            response
+                .headers_mut()
+                .insert(CACHE_CONTROL, immutable_paths.cache_control());
+        }
+
+        if let Some(constraint) = &context.duration_constraint {
+            response.extensions_mut().insert(constraint.clone());
+        }
+
+        let inner_outcomes = context.inner_outcomes.take();
+        if let Some(label) = &configuration.caching.label {
+            let mut outcomes = response
+                .extensions_mut()
+                .remove::<LayerOutcomes>()
+                .or(inner_outcomes)
+                .unwrap_or_default();
+            outcomes.0.push(LayerOutcome {
+                label: label.clone(),
+                decisions: context.trail.decisions.clone(),
+            });
+            response.extensions_mut().insert(outcomes);
+        } else if let Some(inner_outcomes) = inner_outcomes {
+            response.extensions_mut().insert(inner_outcomes);
+        }
+
+        if let Some(phase) = context.budget_exhausted_phase() {
+            response.extensions_mut().insert(OverheadExceeded { phase });
+        }
+
+        let status = CacheStatus::from_trail(&context.trail);
+        Self::insert_cache_status(configuration, &mut response, status);
+
+        context.trail.headers_processing += headers_start.elapsed();
+        response
+    }
+
+    // Insert the cache status extension (and header).
+    fn insert_cache_status<BodyT>(
+        configuration: &MiddlewareConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+        response: &mut Response<BodyT>,
+        status: CacheStatus,
+    ) {
+        if let Some(cache_status_header) = &configuration.caching.cache_status_header {
+            response
+                .headers_mut()
+                .insert(cache_status_header, status.header_value());
+        }
+
+        response.extensions_mut().insert(status);
+    }
+
+    // Record stage.
+    fn record_stage(
+        configuration: &MiddlewareConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+        context: &mut RequestCacheContext<CacheKeyT>,
+        start: Instant,
+    ) {
+        context.enter(PipelineStage::Record);
+
+        if let Some(log_slow_over) = configuration.caching.log_slow_over {
+            context
+                .trail
+                .log_if_slow(&context.uri, start.elapsed(), log_slow_over);
+        }
+
+        if let Some(load_shed) = &configuration.caching.load_shed {
+            load_shed.record_handle(start.elapsed());
+        }
+
+        if context.trail.looked_up {
+            let hit = context.trail.is_hit();
+            for hit_rate_slo in &configuration.caching.hit_rate_slos {
+                hit_rate_slo.record(context.uri.path(), hit);
+            }
+        }
+    }
+
+    // Handle a cache administration request.
+    async fn handle_admin<ResponseBodyT>(
+        configuration: &MiddlewareConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+        admin_methods: &AdminMethodConfig,
+        verb: AdminVerb,
+        mut request: Request<RequestBodyT>,
+    ) -> Response<TranscodingBody<ResponseBodyT>>
+    where
+        ResponseBodyT: 'static + Body + From<ImmutableBytes> + Send + Unpin,
+        ResponseBodyT::Data: From<ImmutableBytes> + Send,
+        ResponseBodyT::Error: Into<CapturedError>,
+    {
+        let Some(cache) = &configuration.caching.cache else {
+            return admin_response(StatusCode::SERVICE_UNAVAILABLE, "no cache\n".into());
+        };
+
+        let peer = peer_address(&request);
+        let (target, invalidated, status) = match verb {
+            AdminVerb::Purge => {
+                let target = request.uri().to_string();
+                let invalidated = admin_methods
+                    .purge(&mut request, &configuration.caching)
+                    .await;
+                let status = if invalidated == 0 {
+                    StatusCode::NOT_FOUND
+                } else {
+                    StatusCode::OK
+                };
+                (target, invalidated, status)
+            }
+
+            AdminVerb::Ban => {
+                let Some(ban_expression) = admin_methods.ban_expression(request.headers()) else {
+                    return admin_response(StatusCode::BAD_REQUEST, "no ban expression\n".into());
+                };
+
+                #[cfg(feature = "paced-purge")]
+                if let Some(purge_jobs) = &configuration.caching.purge_jobs {
+                    let target = ban_expression.to_string();
+                    let Some(id) = purge_jobs.start(
+                        cache.clone(),
+                        ban_expression,
+                        admin_methods.tag_header.clone(),
+                        None,
+                    ) else {
+                        return admin_response(
+                            StatusCode::NOT_IMPLEMENTED,
+                            "cache does not support key enumeration\n".into(),
+                        );
+                    };
+
+                    // Invalidation is ongoing, so nothing is counted yet
+                    admin_methods.audit(AdminAuditEvent {
+                        verb,
+                        peer,
+                        target,
+                        invalidated: 0,
+                    });
+
+                    return admin_response(StatusCode::ACCEPTED, format!("purge job {}\n", id));
+                }
+
+                let Some(invalidated) = ban_expression
+                    .invalidate(cache, &admin_methods.tag_header)
+                    .await
+                else {
+                    return admin_response(
+                        StatusCode::NOT_IMPLEMENTED,
+                        "cache does not support key enumeration\n".into(),
+                    );
+                };
+
+                (ban_expression.to_string(), invalidated, StatusCode::OK)
+            }
+        };
+
+        admin_methods.audit(AdminAuditEvent {
+            verb,
+            peer,
+            target,
+            invalidated,
+        });
+
+        admin_response(status, format!("invalidated {}\n", invalidated))
+    }
+
+    // Handle a request subject to idempotency deduplication.
+    #[cfg(feature = "idempotency")]
+    async fn handle_idempotent<ResponseBodyT>(
+        mut self,
+        idempotency: &IdempotentRequests<RequestBodyT>,
+        request: Request<RequestBodyT>,
+        context: &mut RequestCacheContext<CacheKeyT>,
+    ) -> Result<Response<TranscodingBody<ResponseBodyT>>, InnerServiceT::Error>
+    where
+        Self: Send,
+        InnerServiceT: 'static
+            + Service<Request<RequestBodyT>, Response = Response<ResponseBodyT>>
+            + Clone
+            + Send,
+        InnerServiceT::Future: Send,
+        RequestBodyT: 'static + Send,
+        ResponseBodyT: 'static + Body + From<ImmutableBytes> + Send + Unpin,
+        ResponseBodyT::Data: From<ImmutableBytes> + Send,
+        ResponseBodyT::Error: Into<CapturedError>,
+    {
+        let (request, idempotency_begin) = match idempotency.begin(request).await {
+            Ok(begun) => begun,
+
+            Err(error) => {
+                tracing::debug!("could not read request body: {}", error);
+                context.trail.decide("idempotency (request body)");
+                return Ok(admin_response(StatusCode::BAD_REQUEST, format!("{}\n", error)));
+            }
+        };
+
+        let idempotency_guard = match idempotency_begin {
+            Idempotency::Execute(idempotency_guard) => idempotency_guard,
+
+            Idempotency::Replay(idempotent_response) => {
+                tracing::debug!("idempotency (replay)");
+                context.trail.decide("idempotency (replay)");
+                return Ok(idempotent_response.to_response(true));
+            }
+
+            Idempotency::Mismatch => {
+                tracing::debug!("idempotency (mismatch)");
+                context.trail.decide("idempotency (mismatch)");
+                return Ok(admin_response(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "Idempotency-Key was used with a different payload\n".into(),
+                ));
+            }
+
+            Idempotency::Conflict => {
+                context.trail.decide("idempotency (conflict)");
+                return Ok(admin_response(
+                    StatusCode::CONFLICT,
+                    "request with this Idempotency-Key is still being processed\n".into(),
+                ));
+            }
+
+            Idempotency::MissingKey => {
+                context.trail.decide("idempotency (missing key)");
+                return Ok(admin_response(
+                    StatusCode::BAD_REQUEST,
+                    "missing or invalid Idempotency-Key\n".into(),
+                ));
+            }
+
+            Idempotency::Bypass => {
+                context.trail.decide("idempotency (bypass)");
+                return self.upstream_as_is(request, context).await;
+            }
+        };
+
+        tracing::debug!("idempotency (execute)");
+        context.trail.decide("idempotency (execute)");
+
+        context.enter(PipelineStage::Upstream);
+        let upstream_start = Instant::now();
+        let upstream_response = self.inner_service.call(request).await;
+        context.trail.upstream = upstream_start.elapsed();
+        let mut upstream_response = upstream_response?;
+        context.enter(PipelineStage::Response);
+        self.strip_xx_headers(upstream_response.headers_mut());
+        upstream_response.extensions_mut().remove::<RoutePolicy>();
+
+        let (parts, body) = upstream_response.into_parts();
+
+        let body_read_start = Instant::now();
+        let bytes = body
+            .read_into_bytes_or_pieces(
+                parts.headers.content_length(),
+                0,
+                idempotency.config.max_body,
+            )
+            .await;
+        context.trail.body_read = body_read_start.elapsed();
+
+        match bytes {
+            Ok((bytes, trailers)) => {
+                idempotency_guard.complete(IdempotentResponse::new(
+                    parts.status,
+                    parts.headers.clone(),
+                    bytes.clone(),
+                ));
+
+                // Kept as received, but the first client also gets the trailers
+                Ok(Response::from_parts(parts, ResponseBodyT::from(bytes))
+                    .map(|body| passthrough_with_trailers(body, trailers)))
+            }
+
+            // Not kept; dropping the guard lets the next duplicate execute
+            Err(error) => {
+                let error = ErrorWithResponsePieces::new_from_body(error, parts);
+                match error.pieces {
+                    Some(pieces) => {
+                        tracing::debug!("idempotency (not kept: {})", error.error);
+                        Ok(self.as_is(
+                            pieces.response,
+                            Some(pieces.first_bytes),
+                            &mut context.trail,
+                        ))
+                    }
+
+                    None => {
+                        tracing::error!("could not read response: {}", error.error);
+                        context.trail.decide("error");
+                        Ok(error_transcoding_response())
+                    }
+                }
+            }
+        }
+    }
+
+    // Handle request with its context.
+    //
+    // Each stage either continues to the next or ends the request early. The stages are boxed, as
+    // otherwise (especially in debug builds) polling stacked layers can overflow the stack.
+    async fn handle_with_context<ResponseBodyT>(
+        mut self,
+        request: Request<RequestBodyT>,
+        context: &mut RequestCacheContext<CacheKeyT>,
+    ) -> Result<Response<TranscodingBody<ResponseBodyT>>, InnerServiceT::Error>
+    where
+        Self: Send,
+        InnerServiceT: 'static
+            + Service<Request<RequestBodyT>, Response = Response<ResponseBodyT>>
+            + Clone
+            + Send,
+        InnerServiceT::Future: Send,
+        RequestBodyT: 'static + Send,
+        ResponseBodyT: 'static + Body + From<ImmutableBytes> + Send + Unpin,
+        ResponseBodyT::Data: From<ImmutableBytes> + Send,
+        ResponseBodyT::Error: Into<CapturedError>,
+    {
+        let (request, lookup) = match Box::pin(self.lookup_stage(request, context)).await {
+            ControlFlow::Continue(lookup) => lookup,
+            ControlFlow::Break(end) => return self.end_early(end, context).await,
+        };
+
+        let (request, miss) =
+            match Box::pin(self.conditional_stage(request, lookup, context)).await {
+                ControlFlow::Continue(miss) => miss,
+                ControlFlow::Break(end) => return self.end_early(end, context).await,
+            };
+
+        Box::pin(self.upstream_stage(request, miss, context)).await
+    }
+
+    // End a request before the upstream stage.
+    async fn end_early<ResponseBodyT>(
+        &mut self,
+        end: StageEnd<RequestBodyT, ResponseBodyT>,
+        context: &mut RequestCacheContext<CacheKeyT>,
+    ) -> Result<Response<TranscodingBody<ResponseBodyT>>, InnerServiceT::Error>
+    where
+        InnerServiceT: Service<Request<RequestBodyT>, Response = Response<ResponseBodyT>>,
+        ResponseBodyT: 'static + Body + From<ImmutableBytes> + Send + Unpin,
+        ResponseBodyT::Data: From<ImmutableBytes> + Send,
+        ResponseBodyT::Error: Into<CapturedError>,
+    {
+        match end {
+            StageEnd::Skip(request) => self.upstream_skipping_cache(request, context).await,
+            StageEnd::AsIs(request) => self.upstream_as_is(request, context).await,
+            StageEnd::Respond(response) => Ok(*response),
+        }
+    }
+
+    // Lookup stage: decide whether to use the cache and, if so, look up the entry.
+    async fn lookup_stage<ResponseBodyT>(
+        &mut self,
+        mut request: Request<RequestBodyT>,
+        context: &mut RequestCacheContext<CacheKeyT>,
+    ) -> Staged<RequestBodyT, ResponseBodyT, Lookup<CacheT, CacheKeyT>>
+    where
+        ResponseBodyT: Body + From<ImmutableBytes>,
+        ResponseBodyT::Error: Into<CapturedError>,
+    {
+        context.enter(PipelineStage::Lookup);
+
+        // Keys that keep producing uncacheable responses go directly to the upstream
+        let learned_bypass =
+            match (&self.configuration.caching.learned_bypass, &context.cache_key) {
+                (Some(learned_bypass), Some(cache_key)) => learned_bypass.bypass(cache_key),
+                _ => false,
+            };
+
+        let disabled = !self.configuration.caching.control.is_enabled();
+
+        let mut degraded = false;
+        if !context.skip_cache
+            && !disabled
+            && !learned_bypass
+            && let Some(cache_verification) = &self.configuration.caching.cache_verification
+            && let Some(cache) = &self.configuration.caching.cache
+        {
+            let verdict = context
+                .within_budget(cache_verification.verdict(cache))
+                .await;
+            match verdict {
+                Some(CacheVerdict::Use) => {}
+                Some(CacheVerdict::Bypass) => degraded = true,
+                Some(CacheVerdict::Refuse) => {
+                    context.trail.decide("error (cache verification)");
+                    let response = Box::new(error_transcoding_response());
+                    return ControlFlow::Break(StageEnd::Respond(response));
+                }
+                None => {
+                    context.exhaust_budget(BudgetPhase::Lookup);
+                    return ControlFlow::Break(StageEnd::AsIs(request));
+                }
+            }
+        }
+
+        let bypass = self.configuration.caching.cache_override.mode();
+
+        if degraded
+            || disabled
+            || learned_bypass
+            || bypass == Some(BypassMode::All)
+            || context.skip_cache
+        {
+            let (decision, reason) = if disabled {
+                ("skip (disabled)", "disabled")
+            } else if degraded {
+                ("skip (degraded)", "degraded")
+            } else if learned_bypass {
+                ("skip (learned)", "learned")
+            } else if bypass.is_some() {
+                ("skip (bypass)", "bypass")
+            } else {
+                ("skip", "request")
+            };
+            context.trail.decide(decision);
+            self.cache_event(
+                &context.uri,
+                context.cache_key.as_ref(),
+                CacheEventKind::SkipRequest { reason },
+            );
+            return ControlFlow::Break(StageEnd::Skip(request));
+        }
+
+        let cache = self.configuration.caching.cache.clone().expect("has cache");
+        let mut cache_key = context.cache_key.clone().expect("has cache key");
+
+        // Responses that vary on request headers are stored under variant keys
+        let vary = self.configuration.caching.vary_keys.as_ref().map(|vary_keys| {
+            let names = vary_keys.names(&cache_key).unwrap_or_default();
+            let base_key = cache_key.clone();
+            if !names.is_empty() {
+                cache_key = VaryKeys::variant(&base_key, &names, request.headers());
+            }
+            (vary_keys.clone(), base_key, names, request.headers().clone())
+        });
+
+        // Keys with entries that keep failing serve-time validation go directly to the upstream
+        if let Some(serve_validation) = &self.configuration.caching.serve_validation
+            && serve_validation.cooling_down(&cache_key)
+        {
+            tracing::debug!("skip (quarantine)");
+            context.trail.decide("skip (quarantine)");
+            self.cache_event(
+                &context.uri,
+                Some(&cache_key),
+                CacheEventKind::SkipRequest {
+                    reason: "quarantine",
+                },
+            );
+            return ControlFlow::Break(StageEnd::AsIs(request));
+        }
+
+        // Expire entries superseded by a newer cache-busting value
+        let stale_cache_keys = mem::take(&mut context.bust_invalidations);
+        if !stale_cache_keys.is_empty()
+            && context
+                .within_budget(async {
+                    for stale_cache_key in &stale_cache_keys {
+                        cache.invalidate(stale_cache_key).await;
+                    }
+                })
+                .await
+                .is_none()
+        {
+            context.exhaust_budget(BudgetPhase::Lookup);
+            return ControlFlow::Break(StageEnd::AsIs(request));
+        }
+
+        // Recognize our own suffixed validators
+        let encoding_configuration = &self.configuration.encoding;
+        if let Some(enabled_encodings) = &encoding_configuration.enabled_encodings_by_preference {
+            encoding_configuration
+                .inner
+                .on_the_fly_validators
+                .unsuffix_if_none_match(
+                    request.headers_mut(),
+                    &encoding_configuration.enabled_custom_codings_by_preference,
+                    enabled_encodings,
+                );
+        }
+
+        // Capture the fence before reading so that we won't resurrect invalidated entries
+        let fence = cache.fence(&cache_key);
+
+        let (cached_response, previous_generation_key) = if bypass == Some(BypassMode::Reads) {
+            tracing::debug!("miss (bypass)");
+            context.trail.decide("miss (bypass)");
+            (None, None)
+        } else {
+            let lookup_start = Instant::now();
+            let lookup = context
+                .within_budget(Self::lookup(&self.configuration, &cache, &cache_key))
+                .await;
+            context.trail.lookup = lookup_start.elapsed();
+            let Some((mut cached_response, mut previous_generation_key)) = lookup else {
+                context.exhaust_budget(BudgetPhase::Lookup);
+                return ControlFlow::Break(StageEnd::AsIs(request));
+            };
+            context.trail.looked_up = true;
+
+            // Concurrent misses wait for one of them to store the entry, then look up again
+            // (HEAD misses don't store)
+            if let Some(coalescing) = &self.configuration.caching.coalescing
+                && context.method != Method::HEAD
+                && cached_response.as_ref().is_none_or(|cached_response| {
+                    cached_response.is_expired(self.configuration.caching.inner.now())
+                        && !self.serves_stale(&request, cached_response)
+                })
+            {
+                match coalescing.lead_or_follow(&cache_key).await {
+                    Some(coalescing_guard) => context.coalescing_guard = Some(coalescing_guard),
+
+                    None => {
+                        tracing::debug!("coalesced");
+                        context.trail.decide("coalesced");
+                        let lookup = context
+                            .within_budget(Self::lookup(&self.configuration, &cache, &cache_key))
+                            .await;
+                        let Some(lookup) = lookup else {
+                            context.exhaust_budget(BudgetPhase::Lookup);
+                            return ControlFlow::Break(StageEnd::AsIs(request));
+                        };
+                        (cached_response, previous_generation_key) = lookup;
+                    }
+                }
+            }
+
+            if previous_generation_key.is_some() {
+                tracing::debug!("previous generation");
+                context.trail.decide("previous generation");
+            }
+            (cached_response, previous_generation_key)
+        };
+
+        ControlFlow::Continue((
+            request,
+            Lookup {
+                cache,
+                cache_key,
+                fence,
+                vary,
+                cached_response,
+                previous_generation_key,
+            },
+        ))
+    }
+
+    // Conditional stage: evaluate the entry, and serve it if it is a hit.
+    async fn conditional_stage<ResponseBodyT>(
+        &mut self,
+        request: Request<RequestBodyT>,
+        lookup: Lookup<CacheT, CacheKeyT>,
+        context: &mut RequestCacheContext<CacheKeyT>,
+    ) -> Staged<RequestBodyT, ResponseBodyT, Miss<CacheT, CacheKeyT>>
+    where
+        Self: Send,
+        InnerServiceT: 'static
+            + Service<Request<RequestBodyT>, Response = Response<ResponseBodyT>>
+            + Clone
+            + Send,
+        InnerServiceT::Future: Send,
+        RequestBodyT: 'static + Send,
+        ResponseBodyT: 'static + Body + From<ImmutableBytes> + Send + Unpin,
+        ResponseBodyT::Data: From<ImmutableBytes> + Send,
+        ResponseBodyT::Error: Into<CapturedError>,
+    {
+        context.enter(PipelineStage::Conditional);
+
+        let Lookup {
+            cache,
+            cache_key,
+            mut fence,
+            vary,
+            cached_response,
+            previous_generation_key,
+        } = lookup;
+
+        // Expired entries within the stale window are served while we refresh them
+        #[cfg(feature = "stale-while-revalidate")]
+        if let Some(stale_while_revalidate) =
+            self.configuration.caching.stale_while_revalidate.clone()
+            && let Some(cached_response) = &cached_response
+            && self.serves_stale(&request, cached_response)
+        {
+            let cached_response = cached_response.clone();
+
+            let mut response = if cached_response.is_negative()
+                || modified_weak(request.headers(), cached_response.headers())
+            {
+                tracing::debug!("hit (stale)");
+                context.trail.decide("hit (stale)");
+                self.cache_event(&context.uri, Some(&cache_key), CacheEventKind::Hit);
+
+                let coding = if context.no_transform {
+                    cached_response.original_coding.clone()
+                } else {
+                    context.coding.clone()
+                };
+
+                // Reencodings are not merged, because the refresh will replace the entry
+                let (response, _modified) = cached_response
+                    .clone()
+                    .to_transcoding_response(
+                        &coding,
+                        false,
+                        Default::default(),
+                        &self.configuration.encoding.inner,
+                    )
+                    .await;
+
+                let bytes = response.headers().content_length().unwrap_or_default();
+                self.record_hit(Some(&cache_key), &cached_response, bytes);
+                response
+            } else {
+                tracing::debug!("hit (stale, not modified)");
+                context.trail.decide("hit (stale, not modified)");
+                self.cache_event(&context.uri, Some(&cache_key), CacheEventKind::HitNotModified);
+                self.record_hit(Some(&cache_key), &cached_response, 0);
+                self.not_modified(&cached_response, context)
+            };
+            self.account_age(&cached_response, &mut response);
+
+            // A HEAD response has no body to store
+            if context.method != Method::HEAD
+                && let Some(revalidation_guard) = stale_while_revalidate.try_begin(&cache_key)
+            {
+                self.clone().revalidate(request, revalidation_guard);
+            }
+
+            return ControlFlow::Break(StageEnd::Respond(Box::new(response)));
+        }
+
+        // Expired entries might still be retained (for a grace period) so that we can refresh them
+        let (cached_response, expired) = match cached_response {
+            Some(cached_response)
+                if cached_response.is_expired(self.configuration.caching.inner.now()) =>
+            {
+                tracing::debug!("miss (expired)");
+                context.trail.decide("miss (expired)");
+                (None, Some(cached_response))
+            }
+
+            cached_response => (cached_response, None),
+        };
+
+        // Immutable content cannot have changed, so any validator will do
+        if context.immutable
+            && let Some(cached_response) = &cached_response
+            && !cached_response.is_negative()
+            && (request.headers().contains_key(IF_NONE_MATCH)
+                || request.headers().contains_key(IF_MODIFIED_SINCE))
+        {
+            tracing::debug!("hit (immutable, not modified)");
+            context.trail.decide("hit (immutable, not modified)");
+            self.cache_event(&context.uri, Some(&cache_key), CacheEventKind::HitNotModified);
+            let mut response = self.not_modified(cached_response, context);
+            self.account_age(cached_response, &mut response);
+            return ControlFlow::Break(StageEnd::Respond(Box::new(response)));
+        }
+
+        let cached_response = match cached_response {
+            Some(cached_response) if cached_response.validators_only => {
+                if !modified_weak(request.headers(), cached_response.headers()) {
+                    tracing::debug!("hit (validators only, not modified)");
+                    context.trail.decide("hit (validators only, not modified)");
+                    self.cache_event(
+                        &context.uri,
+                        Some(&cache_key),
+                        CacheEventKind::HitNotModified,
+                    );
+                    let mut response = self.not_modified(&cached_response, context);
+                    self.account_age(&cached_response, &mut response);
+                    return ControlFlow::Break(StageEnd::Respond(Box::new(response)));
+                } else if context.method == Method::HEAD {
+                    tracing::debug!("hit (validators only, HEAD)");
+                    context.trail.decide("hit (validators only, HEAD)");
+                    self.cache_event(&context.uri, Some(&cache_key), CacheEventKind::Hit);
+                    let mut response = cached_response.to_head_response();
+                    self.account_age(&cached_response, &mut response);
+                    return ControlFlow::Break(StageEnd::Respond(Box::new(response)));
+                } else {
+                    // Never serve an empty body; treat as a miss
+                    tracing::debug!("miss (validators only)");
+                    context.trail.decide("miss (validators only)");
+                    None
+                }
+            }
+
+            cached_response => cached_response,
+        };
+
+        // Quarantine entries that fail serve-time validation
+        let cached_response = match (cached_response, &self.configuration.caching.serve_validation)
+        {
+            (Some(cached_response), Some(serve_validation)) => {
+                let stored_key = previous_generation_key.as_ref().unwrap_or(&cache_key);
+                let check = serve_validation.check(
+                    &context.uri,
+                    stored_key,
+                    &cached_response,
+                    &self.configuration.caching.inner.key_log_policy,
+                );
+
+                match check {
+                    ServeCheck::Valid => Some(cached_response),
+
+                    check => {
+                        cache.invalidate(stored_key).await;
+
+                        if check == ServeCheck::CoolingDown {
+                            context.trail.decide("skip (quarantine)");
+                            self.cache_event(
+                                &context.uri,
+                                Some(&cache_key),
+                                CacheEventKind::SkipRequest {
+                                    reason: "quarantine",
+                                },
+                            );
+                            return ControlFlow::Break(StageEnd::AsIs(request));
+                        }
+
+                        // Our own invalidation must not refuse the replacement
+                        fence = cache.fence(&cache_key);
+                        tracing::debug!("miss (quarantine)");
+                        context.trail.decide("miss (quarantine)");
+                        None
+                    }
+                }
+            }
+
+            (cached_response, _) => cached_response,
+        };
+
+        // An entry that varies on headers we didn't key by might be for another variant
+        let cached_response = match (cached_response, &vary) {
+            (Some(cached_response), Some((vary_keys, base_key, names, _))) => {
+                let keyed =
+                    VaryKeys::keyed(self.configuration.caching.language_negotiation.is_some());
+                match VaryKeys::response_names(cached_response.headers(), keyed) {
+                    Some(response_names) if response_names != *names => {
+                        vary_keys.remember(base_key, response_names);
+                        tracing::debug!("miss (vary)");
+                        context.trail.decide("miss (vary)");
+                        None
+                    }
+
+                    _ => Some(cached_response),
+                }
+            }
+
+            (cached_response, _) => cached_response,
+        };
+
+        if let Some(cached_response) = cached_response {
+            // Modifications of a previous generation's entry belong to it
+            let cache_key = previous_generation_key.unwrap_or(cache_key);
+            let response = self
+                .hit_response(
+                    request.method(),
+                    request.headers(),
+                    cache_key,
+                    cached_response,
+                    context,
+                )
+                .await;
+            return match response {
+                Some(response) => ControlFlow::Break(StageEnd::Respond(Box::new(response))),
+                None => ControlFlow::Break(StageEnd::AsIs(request)),
+            };
+        }
+
+        // HEAD requests are looked up under GET keys, so their responses must not be stored
+        if context.method == Method::HEAD {
+            tracing::debug!("miss (HEAD)");
+            context.trail.decide("miss (HEAD)");
+            self.cache_event(&context.uri, Some(&cache_key), CacheEventKind::Miss);
+            return ControlFlow::Break(StageEnd::AsIs(request));
+        }
+
+        self.cache_event(&context.uri, Some(&cache_key), CacheEventKind::Miss);
+
+        let store_encoding = request.store_encoding(&self.configuration.encoding);
+
+        // For the immutable safety valve
+        let previous = if context.immutable { expired.clone() } else { None };
+
+        ControlFlow::Continue((
+            request,
+            Miss {
+                cache,
+                cache_key,
+                fence,
+                vary,
+                store_encoding,
+                expired,
+                previous,
+            },
+        ))
+    }
+
+    // Serve a hit from the entry.
+    //
+    // Returns [None] if the overhead budget is exhausted and there is no stored representation
+    // to fall back to.
+    async fn hit_response<ResponseBodyT>(
+        &mut self,
+        method: &Method,
+        headers: &HeaderMap,
+        cache_key: CacheKeyT,
+        cached_response: CachedResponseRef,
+        context: &mut RequestCacheContext<CacheKeyT>,
+    ) -> Option<Response<TranscodingBody<ResponseBodyT>>>
+    where
+        ResponseBodyT: 'static + Body + From<ImmutableBytes> + Send + Unpin,
+        ResponseBodyT::Data: From<ImmutableBytes> + Send,
+        ResponseBodyT::Error: Into<CapturedError>,
+    {
+        if !cached_response.is_negative() && !modified_weak(headers, cached_response.headers()) {
+            tracing::debug!("hit (not modified)");
+            context.trail.decide("hit (not modified)");
+            self.cache_event(&context.uri, Some(&cache_key), CacheEventKind::HitNotModified);
+
+            self.record_hit(Some(&cache_key), &cached_response, 0);
+
+            let mut response = self.not_modified(&cached_response, context);
+            self.account_age(&cached_response, &mut response);
+            return Some(response);
+        }
+
+        tracing::debug!("hit");
+        context.trail.decide("hit");
+        self.cache_event(&context.uri, Some(&cache_key), CacheEventKind::Hit);
+
+        // Needed only for entry stats
+        let hit_key = self
+            .configuration
+            .caching
+            .entry_stats
+            .is_some()
+            .then(|| cache_key.clone());
+
+        if let Some((mut response, modified)) =
+            Self::range_response(&self.configuration, method, headers, &cached_response, context)
+                .await
+        {
+            if let Some(modified) = modified {
+                context.stage(PendingStore::Merge {
+                    key: cache_key,
+                    cached_response: Arc::new(modified),
+                });
+            }
+
+            let bytes = response.headers().content_length().unwrap_or_default();
+            self.record_hit(hit_key.as_ref(), &cached_response, bytes);
+
+            self.account_age(&cached_response, &mut response);
+            return Some(response);
+        }
+
+        let coding = if context.no_transform {
+            cached_response.original_coding.clone()
+        } else {
+            context.coding.clone()
+        };
+
+        let transcode_start = Instant::now();
+        let response = context
+            .within_budget(cached_response.clone().to_transcoding_response(
+                &coding,
+                false,
+                Default::default(),
+                &self.configuration.encoding.inner,
+            ))
+            .await;
+        context.trail.transcode = transcode_start.elapsed();
+
+        let mut response = match response {
+            Some((response, modified)) => {
+                if let Some(modified) = modified {
+                    if self.configuration.caching.on_cache_event.is_some()
+                        && let Some(from) = cached_response.body.reencoding_source(&coding)
+                    {
+                        for to in modified.body.representations.keys() {
+                            if !cached_response.body.representations.contains_key(to) {
+                                self.cache_event(
+                                    &context.uri,
+                                    Some(&cache_key),
+                                    CacheEventKind::ReencodeInCache {
+                                        from: from.clone(),
+                                        to: to.clone(),
+                                    },
+                                );
+                            }
+                        }
+                    }
+
+                    context.stage(PendingStore::Merge {
+                        key: cache_key,
+                        cached_response: modified,
+                    });
+                }
+                response
+            }
+
+            None => {
+                context.exhaust_budget(BudgetPhase::Hit);
+                cached_response.to_stored_response(&coding, &self.configuration.encoding.inner)?
+            }
+        };
+
+        let bytes = response.headers().content_length().unwrap_or_default();
+        self.record_hit(hit_key.as_ref(), &cached_response, bytes);
+
+        if self.configuration.caching.serve_ranges
+            && response.status() == StatusCode::OK
+            && !cached_response.is_negative()
+        {
+            response
+                .headers_mut()
+                .insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        }
+
+        self.account_age(&cached_response, &mut response);
+        Some(response)
+    }
+
+    // Upstream stage: call the upstream for a miss, conditionally if we are refreshing an expired
+    // entry.
+    async fn upstream_stage<ResponseBodyT>(
+        &mut self,
+        mut request: Request<RequestBodyT>,
+        miss: Miss<CacheT, CacheKeyT>,
+        context: &mut RequestCacheContext<CacheKeyT>,
+    ) -> Result<Response<TranscodingBody<ResponseBodyT>>, InnerServiceT::Error>
+    where
+        InnerServiceT: Service<Request<RequestBodyT>, Response = Response<ResponseBodyT>>,
+        ResponseBodyT: 'static + Body + From<ImmutableBytes> + Send + Unpin,
+        ResponseBodyT::Data: From<ImmutableBytes> + Send,
+        ResponseBodyT::Error: Into<CapturedError>,
+    {
+        // We can only make sense of a 304 if the conditional headers are our own
+        let refreshing = miss.expired.clone().filter(|expired| {
+            !expired.validators_only
+                && !expired.is_negative()
+                && !request.headers().contains_key(IF_NONE_MATCH)
+                && !request.headers().contains_key(IF_MODIFIED_SINCE)
+        });
+
+        if let Some(expired) = &refreshing {
+            expired.add_conditional_headers(request.headers_mut());
+        }
+
+        context.enter(PipelineStage::Upstream);
+
+        // Handlers record into the extension; we keep a clone
+        let dependencies = self.configuration.caching.dependencies.as_ref().map(|dependencies| {
+            let dependencies = dependencies.fresh();
+            request.extensions_mut().insert(dependencies.clone());
+            dependencies
+        });
+
+        // Held until we are done with the upstream response (including storing it)
+        let _resource_permit = Self::acquire_resource_permit(&self.configuration, context).await;
+
+        let upstream_start = Instant::now();
+        let upstream_response = self.inner_service.call(request).await;
+        context.trail.upstream = upstream_start.elapsed();
+        let mut upstream_response = upstream_response?;
+        context.enter(PipelineStage::Response);
+        self.strip_xx_headers(upstream_response.headers_mut());
+
+        // Not to be stored with the entry
+        context.inner_outcomes = upstream_response.extensions_mut().remove::<LayerOutcomes>();
+
+        if let Some(expired) = refreshing
+            && upstream_response.status() == StatusCode::NOT_MODIFIED
+        {
+            return Ok(self
+                .refresh_response(upstream_response, expired, miss, dependencies, context)
+                .await);
+        }
+
+        Ok(self
+            .response_stage(upstream_response, miss, dependencies, context)
+            .await)
+    }
+
+    // Refresh an expired entry with the upstream's 304 for our own conditional request.
+    async fn refresh_response<ResponseBodyT>(
+        &mut self,
+        mut upstream_response: Response<ResponseBodyT>,
+        expired: CachedResponseRef,
+        miss: Miss<CacheT, CacheKeyT>,
+        dependencies: Option<CacheDependencies>,
+        context: &mut RequestCacheContext<CacheKeyT>,
+    ) -> Response<TranscodingBody<ResponseBodyT>>
+    where
+        ResponseBodyT: 'static + Body + From<ImmutableBytes> + Send + Unpin,
+        ResponseBodyT::Data: From<ImmutableBytes> + Send,
+        ResponseBodyT::Error: Into<CapturedError>,
+    {
+        tracing::debug!("refresh (not modified)");
+        context.trail.decide("refresh (not modified)");
+
+        let policy_duration = upstream_response
+            .extensions_mut()
+            .remove::<RoutePolicy>()
+            .and_then(|policy| policy.duration);
+        let mut refreshed = expired.refreshed(
+            &context.uri,
+            upstream_response.headers(),
+            policy_duration,
+            &self.configuration.caching.inner,
+        );
+        refreshed.prune_representations(&self.configuration.encoding.inner);
+        if let Some(dependencies) = &dependencies {
+            let tokens = dependencies.tokens();
+            // The handler might have answered the conditional request without data
+            if !tokens.is_empty() {
+                refreshed.dependencies = tokens;
+            }
+
+            if let Some(constraint) = dependencies.constraint(refreshed.duration) {
+                tracing::debug!("duration bounded by {}", constraint);
+                context.trail.decide("dependency bound");
+                refreshed.duration = Some(constraint.ttl);
+                context.duration_constraint = Some(constraint);
+            }
+        }
+        let cached_response = Arc::new(refreshed);
+
+        let encoding = if context.no_transform {
+            cached_response.original_coding.clone()
+        } else {
+            context.coding.clone()
+        };
+
+        // The refreshed entry is new, including any representation we create for it
+        let transcode_start = Instant::now();
+        let response = context
+            .within_budget(cached_response.clone().to_transcoding_response(
+                &encoding,
+                true,
+                Default::default(),
+                &self.configuration.encoding.inner,
+            ))
+            .await;
+        context.trail.transcode = transcode_start.elapsed();
+
+        // The 304 was for our own conditional request, so we must serve the entry
+        let mut response = match response {
+            Some((response, refreshed)) => {
+                if let Some(refreshed) = refreshed {
+                    context.stage(PendingStore::Put {
+                        key: miss.cache_key,
+                        fence: miss.fence,
+                        cached_response: refreshed,
+                        pathway: StorePathway::Refresh,
+                    });
+                }
+                response
+            }
+
+            None => {
+                context.exhaust_budget(BudgetPhase::Store);
+                Self::entry_response(
+                    &self.configuration,
+                    &cached_response,
+                    &encoding,
+                    Default::default(),
+                )
+                .await
+            }
+        };
+
+        self.account_age(&cached_response, &mut response);
+        response
+    }
+
+    // Response stage: decide whether to store the upstream response.
+    async fn response_stage<ResponseBodyT>(
+        &mut self,
+        mut upstream_response: Response<ResponseBodyT>,
+        miss: Miss<CacheT, CacheKeyT>,
+        dependencies: Option<CacheDependencies>,
+        context: &mut RequestCacheContext<CacheKeyT>,
+    ) -> Response<TranscodingBody<ResponseBodyT>>
+    where
+        ResponseBodyT: 'static + Body + From<ImmutableBytes> + Send + Unpin,
+        ResponseBodyT::Data: From<ImmutableBytes> + Send,
+        ResponseBodyT::Error: Into<CapturedError>,
+    {
+        let uri = context.uri.clone();
+
+        // Route policy overrides our configuration for this response
+        let overrides = self.route_overrides(upstream_response.extensions().get::<RoutePolicy>());
+
+        self.check_compression_conflict(&uri, upstream_response.headers());
+
+        if context.budget_exhausted() {
+            context.exhaust_budget(BudgetPhase::Miss);
+            self.cache_event(
+                &uri,
+                Some(&miss.cache_key),
+                CacheEventKind::SkipResponse {
+                    reason: "overhead budget",
+                },
+            );
+            return self.as_is(upstream_response, None, &mut context.trail);
+        }
+
+        // Existing hits are never shed, only the work of storing misses
+        let load_shed_level = match &self.configuration.caching.load_shed {
+            Some(load_shed) => load_shed.evaluate(),
+            None => LoadShedLevel::Normal,
+        };
+
+        #[cfg(feature = "range-assembly")]
+        if load_shed_level == LoadShedLevel::Normal
+            && upstream_response.status() == StatusCode::PARTIAL_CONTENT
+            && let Some(range_assembly) = &self.configuration.caching.range_assembly
+            && let Some(content_range) =
+                range_assembly.assemblable(uri.path(), upstream_response.headers())
+        {
+            return self
+                .assemble_range(
+                    content_range,
+                    upstream_response,
+                    miss.cache_key,
+                    miss.fence,
+                    &uri,
+                    context,
+                )
+                .await;
+        }
+
+        let (skip_caching, content_length) =
+            upstream_response.should_skip_cache(&uri, &self.configuration.caching, &overrides);
+
+        // Store under the variant for the response's Vary
+        let (cache_key, fence) = match &miss.vary {
+            Some((vary_keys, base_key, names, request_headers)) if !skip_caching => {
+                let keyed =
+                    VaryKeys::keyed(self.configuration.caching.language_negotiation.is_some());
+                match VaryKeys::response_names(upstream_response.headers(), keyed) {
+                    Some(response_names) if response_names != *names => {
+                        vary_keys.remember(base_key, response_names.clone());
+                        let cache_key =
+                            VaryKeys::variant(base_key, &response_names, request_headers);
+                        let fence = miss.cache.fence(&cache_key);
+                        (cache_key, fence)
+                    }
+
+                    _ => (miss.cache_key, miss.fence),
+                }
+            }
+
+            _ => (miss.cache_key, miss.fence),
+        };
+
+        // We serve the request's coding, but store in ours
+        let (mut encoding, _) = upstream_response.validate_encoding(
+            &uri,
+            context.coding.clone(),
+            content_length,
+            &self.configuration.encoding,
+        );
+        let (mut store_encoding, skip_encoding) = upstream_response.validate_encoding(
+            &uri,
+            miss.store_encoding,
+            content_length,
+            &self.configuration.encoding,
+        );
+
+        // The request forbids changing the upstream's coding, or another layer does the encoding
+        if context.no_transform || !self.configuration.caching.role.encodes() {
+            encoding = CodingId::Builtin(upstream_response.headers().content_encoding().into());
+            store_encoding = encoding.clone();
+        }
+
+        let shed_store = !skip_caching && (load_shed_level == LoadShedLevel::SkipStore);
+        let not_admitted = !skip_caching
+            && !shed_store
+            && self
+                .configuration
+                .caching
+                .admission
+                .as_ref()
+                .is_some_and(|admission| !admission.admit(&cache_key));
+
+        // Reserve the worst case before buffering
+        let mut buffer_reservation = None;
+        let mut budget_allows_encoding = true;
+        let over_budget = if !skip_caching
+            && !shed_store
+            && !not_admitted
+            && let Some(buffer_budget) = &self.configuration.caching.buffer_budget
+        {
+            let body_size = content_length.unwrap_or(overrides.max_body_size);
+            let encode = !store_encoding.is_identity() && !context.no_transform;
+            match buffer_budget.reserve_store(body_size, encode) {
+                Some((reservation, allows_encoding)) => {
+                    buffer_reservation = Some(reservation);
+                    budget_allows_encoding = allows_encoding;
+                    false
+                }
+
+                None => true,
+            }
+        } else {
+            false
+        };
+
+        if skip_caching || shed_store || not_admitted || over_budget {
+            let reason = if shed_store {
+                tracing::debug!("skip (load)");
+                context.trail.decide("skip (load)");
+                "load"
+            } else if over_budget {
+                tracing::debug!("skip (buffer budget)");
+                context.trail.decide("skip (buffer budget)");
+                "buffer budget"
+            } else if not_admitted {
+                tracing::debug!("skip (admission)");
+                context.trail.decide("skip (admission)");
+                "admission"
+            } else {
+                context.trail.decide("skip (upstream)");
+                context.uncacheable = true;
+                "upstream"
+            };
+            self.cache_event(&uri, Some(&cache_key), CacheEventKind::SkipResponse { reason });
+
+            upstream_response.extensions_mut().remove::<RoutePolicy>();
+
+            if skip_caching
+                && let Some(content_length) = content_length
+                && content_length > overrides.max_body_size
+            {
+                if let Some(body_sizes) = &self.configuration.caching.body_sizes
+                    && (!self.configuration.caching.inner.respect_cache_control
+                        || CacheControl::from_headers(upstream_response.headers()).is_storable())
+                {
+                    body_sizes.record_rejected(content_length);
+                }
+
+                if let Some(pending_store) = self.validators_only_store(
+                    cache_key,
+                    fence,
+                    &uri,
+                    upstream_response.status(),
+                    upstream_response.headers(),
+                    Some(content_length),
+                ) {
+                    context.stage(pending_store);
+                }
+            }
+
+            return self.with_transcoding_body(
+                upstream_response,
+                None,
+                &encoding,
+                &overrides,
+                &mut context.trail,
+            );
+        }
+
+        tracing::debug!("miss");
+        context.trail.decide("miss");
+
+        // Not encoding for storing means not encoding for serving either
+        if load_shed_level == LoadShedLevel::SkipEncoding
+            && !store_encoding.is_identity()
+            && !context.no_transform
+        {
+            tracing::debug!("not encoding to {} (load)", store_encoding);
+            context.trail.decide("identity (load)");
+            store_encoding = CodingId::IDENTITY;
+            encoding = CodingId::IDENTITY;
+        }
+
+        if !budget_allows_encoding && !store_encoding.is_identity() {
+            tracing::debug!("not encoding to {} (buffer budget)", store_encoding);
+            context.trail.decide("identity (buffer budget)");
+            store_encoding = CodingId::IDENTITY;
+            encoding = CodingId::IDENTITY;
+        }
+
+        let body_read_start = Instant::now();
+        let cached_response = CachedResponse::new_for_with_trailers(
+            &uri,
+            upstream_response,
+            content_length,
+            store_encoding.clone(),
+            skip_encoding,
+            &self.configuration.caching.inner,
+            &self.configuration.encoding.inner,
+        )
+        .await;
+        context.trail.body_read = body_read_start.elapsed();
+
+        match cached_response {
+            Ok((mut cached_response, trailers)) => {
+                tracing::debug!("store ({})", store_encoding);
+                context.trail.decide("store");
+
+                // The body is read, and encoded if it will be
+                if let Some(buffer_reservation) = &mut buffer_reservation {
+                    let weight = cached_response.body.cache_weight();
+                    buffer_reservation.shrink_to(weight);
+                }
+
+                if let Some(body_sizes) = &self.configuration.caching.body_sizes {
+                    body_sizes.record_stored(&cached_response);
+                }
+
+                if let Some(dependencies) = &dependencies {
+                    // Tags from the `XX-Cache-Tags` header are tokens, too
+                    for tag in cached_response.dependencies.iter() {
+                        dependencies.record(tag);
+                    }
+                    cached_response.dependencies = dependencies.tokens();
+                }
+
+                if let Some(on_trailers) = &self.configuration.caching.on_trailers {
+                    for trailers in &trailers {
+                        on_trailers(TrailersHookContext::new(&uri, trailers));
+                    }
+                }
+
+                if let Some(immutable_paths) = &self.configuration.caching.immutable_paths
+                    && context.immutable
+                {
+                    if let Some(previous) = &miss.previous
+                        && cached_response.content_differs(previous)
+                    {
+                        immutable_paths.demote(uri.path());
+                        context.immutable = false;
+                    } else {
+                        cached_response.duration = Some(immutable_paths.max_age);
+                    }
+                }
+
+                // The shortest-lived dependency bounds the whole entry
+                if let Some(dependencies) = &dependencies
+                    && let Some(constraint) = dependencies.constraint(cached_response.duration)
+                {
+                    tracing::debug!("duration bounded by {}", constraint);
+                    context.trail.decide("dependency bound");
+                    cached_response.duration = Some(constraint.ttl);
+                    context.duration_constraint = Some(constraint);
+                }
+
+                self.stored_response(
+                    cached_response,
+                    trailers,
+                    (cache_key, fence),
+                    &encoding,
+                    &overrides,
+                    context,
+                )
+                .await
+            }
+
+            Err(error) => match error.pieces {
+                Some(mut pieces) => {
+                    tracing::debug!("skip ({})", error.error);
+                    let reason = if LengthMismatch::of(&error.error).is_some() {
+                        context.trail.decide("skip (length mismatch)");
+                        "length mismatch"
+                    } else {
+                        context.trail.decide("skip (body)");
+                        "body"
+                    };
+                    context.uncacheable = true;
+                    self.cache_event(
+                        &uri,
+                        Some(&cache_key),
+                        CacheEventKind::SkipResponse { reason },
+                    );
+                    pieces.response.extensions_mut().remove::<RoutePolicy>();
+
+                    if pieces.first_bytes.len() > overrides.max_body_size {
+                        // We stopped reading, so we know only that it's bigger
+                        if let Some(body_sizes) = &self.configuration.caching.body_sizes {
+                            body_sizes.record_rejected(pieces.first_bytes.len());
+                        }
+
+                        if let Some(pending_store) = self.validators_only_store(
+                            cache_key,
+                            fence,
+                            &uri,
+                            pieces.response.status(),
+                            pieces.response.headers(),
+                            None,
+                        ) {
+                            context.stage(pending_store);
+                        }
+                    }
+
+                    self.with_transcoding_body(
+                        pieces.response,
+                        Some(pieces.first_bytes),
+                        &encoding,
+                        &overrides,
+                        &mut context.trail,
+                    )
+                }
+
+                None => {
+                    tracing::error!(
+                        "could not create cache entry: {} {}",
+                        self.key_for_logs(&cache_key),
+                        error
+                    );
+                    context.trail.decide("error");
+                    error_transcoding_response()
+                }
+            },
+        }
+    }
+
+    // Stage the new entry for the store stage and serve it.
+    async fn stored_response<ResponseBodyT>(
+        &mut self,
+        mut cached_response: CachedResponse,
+        trailers: Vec<HeaderMap>,
+        (cache_key, fence): (CacheKeyT, Fence),
+        encoding: &CodingId,
+        overrides: &RouteOverrides,
+        context: &mut RequestCacheContext<CacheKeyT>,
+    ) -> Response<TranscodingBody<ResponseBodyT>>
+    where
+        ResponseBodyT: 'static + Body + From<ImmutableBytes> + Send + Unpin,
+        ResponseBodyT::Data: From<ImmutableBytes> + Send,
+        ResponseBodyT::Error: Into<CapturedError>,
+    {
+        // Storing is optional work
+        if context.budget_exhausted() {
+            context.exhaust_budget(BudgetPhase::Store);
+            let mut response =
+                Self::entry_response(&self.configuration, &cached_response, encoding, trailers)
+                    .await;
+            self.account_age(&cached_response, &mut response);
+            return response;
+        }
+
+        // The original to serve if we store a transformed body
+        let mut original = None;
+        let mut pathway = StorePathway::Miss;
+        let caching_configuration = &self.configuration.caching.inner;
+        if let Some(transform) = &caching_configuration.transform_before_store
+            && let Some(transformed) = context
+                .within_budget(cached_response.transformed(
+                    &context.uri,
+                    transform,
+                    overrides,
+                    &self.configuration.encoding.inner,
+                ))
+                .await
+                .flatten()
+        {
+            context.trail.decide("transform");
+            pathway = StorePathway::Transform;
+            if caching_configuration.transform_policy == TransformPolicy::OriginalFirst {
+                original = Some(cached_response);
+            }
+            cached_response = transformed;
+        }
+
+        let cached_response = Arc::new(cached_response);
+
+        let transcode_start = Instant::now();
+        let mut response = match original {
+            Some(original) => {
+                context.stage(PendingStore::Put {
+                    key: cache_key.clone(),
+                    fence,
+                    cached_response: cached_response.clone(),
+                    pathway,
+                });
+
+                match original
+                    .to_response::<ResponseBodyT>(encoding, &self.configuration.encoding.inner)
+                    .await
+                {
+                    Ok((response, _)) => {
+                        response.map(|body| passthrough_with_trailers(body, trailers))
+                    }
+
+                    Err(error) => {
+                        tracing::error!(
+                            "could not create response: {} {}",
+                            self.key_for_logs(&cache_key),
+                            error
+                        );
+                        error_transcoding_response()
+                    }
+                }
+            }
+
+            None => {
+                let response = context
+                    .within_budget(cached_response.clone().to_transcoding_response(
+                        encoding,
+                        true,
+                        trailers.clone(),
+                        &self.configuration.encoding.inner,
+                    ))
+                    .await;
+
+                match response {
+                    Some((response, stored)) => {
+                        // Including any representation created for it
+                        if let Some(stored) = stored {
+                            context.stage(PendingStore::Put {
+                                key: cache_key,
+                                fence,
+                                cached_response: stored,
+                                pathway,
+                            });
+                        }
+                        response
+                    }
+
+                    None => {
+                        context.exhaust_budget(BudgetPhase::Store);
+                        Self::entry_response(
+                            &self.configuration,
+                            &cached_response,
+                            encoding,
+                            trailers,
+                        )
+                        .await
+                    }
+                }
+            }
+        };
+        context.trail.transcode = transcode_start.elapsed();
+
+        self.account_age(&cached_response, &mut response);
+        response
+    }
+
+    // Call the upstream for a request that skips the cache.
+    async fn upstream_skipping_cache<ResponseBodyT>(
+        &mut self,
+        request: Request<RequestBodyT>,
+        context: &mut RequestCacheContext<CacheKeyT>,
+    ) -> Result<Response<TranscodingBody<ResponseBodyT>>, InnerServiceT::Error>
+    where
+        InnerServiceT: Service<Request<RequestBodyT>, Response = Response<ResponseBodyT>>,
+        ResponseBodyT: 'static + Body + From<ImmutableBytes> + Send + Unpin,
+        ResponseBodyT::Data: From<ImmutableBytes> + Send,
+        ResponseBodyT::Error: Into<CapturedError>,
+    {
+        context.enter(PipelineStage::Upstream);
+        let upstream_start = Instant::now();
+        let upstream_response = self.inner_service.call(request).await;
+        context.trail.upstream = upstream_start.elapsed();
+
+        context.enter(PipelineStage::Response);
+        let uri = &context.uri;
+        upstream_response.map(|mut upstream_response| {
+            self.strip_xx_headers(upstream_response.headers_mut());
+            self.check_compression_conflict(uri, upstream_response.headers());
+
+            let policy = upstream_response.extensions_mut().remove::<RoutePolicy>();
+            let overrides = self.route_overrides(policy.as_ref());
+
+            let (encoding, _skip_encoding) = if context.no_transform {
+                // Identity means pass-through
+                (CodingId::IDENTITY, false)
+            } else {
+                upstream_response.validate_encoding(
+                    uri,
+                    context.coding.clone(),
+                    context.content_length,
+                    &self.configuration.encoding,
+                )
+            };
+            self.with_transcoding_body(
+                upstream_response,
+                None,
+                &encoding,
+                &overrides,
+                &mut context.trail,
+            )
+        })
+    }
+
+    // Route policy overrides for a response.
+    fn route_overrides(&self, policy: Option<&RoutePolicy>) -> RouteOverrides {
+        RouteOverrides::new(
+            policy,
+            &self.configuration.caching.inner,
+            &self.configuration.encoding.inner,
+        )
+    }
+
+    // Emit a cache event, if we have a hook.
+    fn cache_event(&self, uri: &Uri, key: Option<&CacheKeyT>, kind: CacheEventKind) {
+        if let Some(on_cache_event) = &self.configuration.caching.on_cache_event {
+            on_cache_event(CacheEvent::new(uri, key, kind));
         }
     }
 
-    // Clone while keeping `inner_service`.
+    // Wrap an upstream response in a transcoding body.
     //
-    // See: https://docs.rs/tower/latest/tower/trait.Service.html#be-careful-when-cloning-inner-services
-    fn clone_and_keep_inner_service(&mut self) -> Self
+    // If this transforms the response then we apply the on-the-fly validator policy.
+    //
+    // Upstream trailers are kept: the transcoding body emits them after the encoder has finished
+    // (see the transcoding_trailers test).
+    //
+    // Time spent is recorded as headers processing.
+    fn with_transcoding_body<ResponseBodyT>(
+        &self,
+        upstream_response: Response<ResponseBodyT>,
+        first_bytes: Option<ImmutableBytes>,
+        coding: &CodingId,
+        overrides: &RouteOverrides,
+        trail: &mut DecisionTrail,
+    ) -> Response<TranscodingBody<ResponseBodyT>>
     where
-        InnerServiceT: Clone,
+        ResponseBodyT: 'static + Body + From<ImmutableBytes> + Send + Unpin,
+        ResponseBodyT::Data: From<ImmutableBytes> + Send,
+        ResponseBodyT::Error: Into<CapturedError>,
     {
-        let mut clone = self.clone();
-        clone.inner_service = mem::replace(&mut self.inner_service, clone.inner_service);
-        clone
+        let headers_start = Instant::now();
+        let original_encoding = upstream_response.headers().get(CONTENT_ENCODING).cloned();
+
+        let mut response = upstream_response.with_transcoding_body_with_first_bytes(
+            first_bytes,
+            &coding.builtin_or_identity(),
+            overrides.encodable_by_default,
+        );
+
+        if response.headers().get(CONTENT_ENCODING) != original_encoding.as_ref() {
+            let coding = CodingId::Builtin(response.headers().content_encoding().into());
+            self.configuration
+                .encoding
+                .inner
+                .on_the_fly_validators
+                .apply(response.headers_mut(), &coding);
+        }
+
+        trail.headers_processing += headers_start.elapsed();
+        response
     }
 
-    // Handle request.
-    async fn handle<ResponseBodyT>(
-        mut self,
+    // Call the upstream and serve its response as is.
+    //
+    // The fallback for when the overhead budget is exhausted before we have a response.
+    async fn upstream_as_is<ResponseBodyT>(
+        &mut self,
         request: Request<RequestBodyT>,
+        context: &mut RequestCacheContext<CacheKeyT>,
     ) -> Result<Response<TranscodingBody<ResponseBodyT>>, InnerServiceT::Error>
     where
         InnerServiceT: Service<Request<RequestBodyT>, Response = Response<ResponseBodyT>>,
@@ -72,125 +2023,535 @@ where
         ResponseBodyT::Data: From<ImmutableBytes> + Send,
         ResponseBodyT::Error: Into<CapturedError>,
     {
-        if request.should_skip_cache(&self.caching) {
-            // Capture request data before moving the request to the inner service
-            let uri = request.uri().clone();
-            let encoding = request.select_encoding(&self.encoding);
-            let content_length = request.headers().content_length();
+        context.enter(PipelineStage::Upstream);
+        let upstream_start = Instant::now();
+        let upstream_response = self.inner_service.call(request).await;
+        context.trail.upstream += upstream_start.elapsed();
+        context.enter(PipelineStage::Response);
 
-            return self
-                .inner_service
-                .call(request)
+        upstream_response
+            .map(|upstream_response| self.as_is(upstream_response, None, &mut context.trail))
+    }
+
+    // Serve an upstream response as is (uncached and not encoded).
+    fn as_is<ResponseBodyT>(
+        &self,
+        mut upstream_response: Response<ResponseBodyT>,
+        first_bytes: Option<ImmutableBytes>,
+        trail: &mut DecisionTrail,
+    ) -> Response<TranscodingBody<ResponseBodyT>>
+    where
+        ResponseBodyT: 'static + Body + From<ImmutableBytes> + Send + Unpin,
+        ResponseBodyT::Data: From<ImmutableBytes> + Send,
+        ResponseBodyT::Error: Into<CapturedError>,
+    {
+        self.strip_xx_headers(upstream_response.headers_mut());
+        upstream_response.extensions_mut().remove::<RoutePolicy>();
+
+        // Identity means pass-through
+        self.with_transcoding_body(
+            upstream_response,
+            first_bytes,
+            &CodingId::IDENTITY,
+            &self.route_overrides(None),
+            trail,
+        )
+    }
+
+    // Serve an entry without storing anything.
+    //
+    // The fallback for when the overhead budget is exhausted after we have an entry. Prefers a
+    // stored representation, but will encode if it must, as the upstream response is gone.
+    async fn entry_response<ResponseBodyT>(
+        configuration: &MiddlewareConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+        cached_response: &CachedResponse,
+        coding: &CodingId,
+        trailers: Vec<HeaderMap>,
+    ) -> Response<TranscodingBody<ResponseBodyT>>
+    where
+        ResponseBodyT: 'static + Body + From<ImmutableBytes> + Send + Unpin,
+        ResponseBodyT::Data: From<ImmutableBytes> + Send,
+        ResponseBodyT::Error: Into<CapturedError>,
+    {
+        let configuration = &configuration.encoding.inner;
+
+        let stored = cached_response.to_stored_response::<ResponseBodyT>(coding, configuration);
+        let response = match stored {
+            Some(response) => Ok(response),
+            None => cached_response
+                .to_response::<ResponseBodyT>(coding, configuration)
                 .await
-                .map(|upstream_response| {
-                    let (encoding, _skip_encoding) = upstream_response.validate_encoding(
-                        &uri,
-                        encoding,
-                        content_length,
-                        &self.encoding,
-                    );
-                    upstream_response
-                        .with_transcoding_body(&encoding, self.encoding.inner.encodable_by_default)
-                });
+                .map(|(response, _)| response),
+        };
+
+        match response {
+            Ok(response) => response.map(|body| passthrough_with_trailers(body, trailers)),
+            Err(error) => {
+                tracing::error!("could not create response: {}", error);
+                error_transcoding_response()
+            }
         }
+    }
 
-        let cache = self.caching.cache.clone().expect("has cache");
-        let cache_key = request.cache_key_with_hook(&self.caching);
+    // Serve a byte range of an entry's Identity representation.
+    //
+    // Returns [None] if the request should be answered with the full representation.
+    async fn range_response<ResponseBodyT>(
+        configuration: &MiddlewareConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+        method: &Method,
+        headers: &HeaderMap,
+        cached_response: &CachedResponse,
+        context: &mut RequestCacheContext<CacheKeyT>,
+    ) -> Option<(Response<TranscodingBody<ResponseBodyT>>, Option<CachedResponse>)>
+    where
+        ResponseBodyT: 'static + Body + From<ImmutableBytes> + Send + Unpin,
+        ResponseBodyT::Data: From<ImmutableBytes> + Send,
+        ResponseBodyT::Error: Into<CapturedError>,
+    {
+        if !configuration.caching.serve_ranges
+            || method != Method::GET
+            || !headers.contains_key(RANGE)
+            || cached_response.is_negative()
+            || cached_response.parts.status != StatusCode::OK
+            || (cached_response.no_transform && !cached_response.original_coding.is_identity())
+        {
+            return None;
+        }
 
-        match cache.get(&cache_key).await {
-            Some(cached_response) => Ok({
-                if modified(request.headers(), cached_response.headers()) {
-                    tracing::debug!("hit");
+        let configuration = &configuration.encoding.inner;
+        let (identity, modified) =
+            match context.within_budget(cached_response.identity(configuration)).await {
+                Some(Ok(identity)) => identity,
 
-                    cached_response
-                        .to_transcoding_response(
-                            &request.select_encoding(&self.encoding),
-                            false,
-                            cache,
-                            cache_key,
-                            &self.encoding.inner,
-                        )
-                        .await
-                } else {
-                    tracing::debug!("hit (not modified)");
+                Some(Err(error)) => {
+                    tracing::error!("could not get identity for range: {}", error);
+                    return None;
+                }
 
-                    not_modified_transcoding_response()
+                None => {
+                    context.exhaust_budget(BudgetPhase::Hit);
+                    return None;
                 }
-            }),
+            };
 
-            None => {
-                // Capture request data before moving the request to the inner service
-                let uri = request.uri().clone();
-                let encoding = request.select_encoding(&self.encoding);
+        let total = identity.len();
+        let response = match RangeRequest::evaluate(
+            headers,
+            total,
+            cached_response.headers(),
+        ) {
+            RangeRequest::Full => return None,
 
-                let upstream_response = self.inner_service.call(request).await?;
+            RangeRequest::Partial(range) => {
+                tracing::debug!("range: {:?}", range);
+                context.trail.decide("range");
+                cached_response.to_range_response(&identity, range, configuration)
+            }
 
-                Ok({
-                    let (skip_caching, content_length) =
-                        upstream_response.should_skip_cache(&uri, &self.caching);
-                    let (encoding, skip_encoding) = upstream_response.validate_encoding(
-                        &uri,
-                        encoding.clone(),
-                        content_length,
-                        &self.encoding,
-                    );
+            RangeRequest::Unsatisfiable => {
+                tracing::debug!("range (unsatisfiable)");
+                context.trail.decide("range (unsatisfiable)");
+                range_not_satisfiable_response(total)
+            }
+        };
 
-                    if skip_caching {
-                        upstream_response.with_transcoding_body(
-                            &encoding,
-                            self.encoding.inner.encodable_by_default,
-                        )
-                    } else {
-                        tracing::debug!("miss");
+        Some((response.map(|body| passthrough_with_trailers(body, Default::default())), modified))
+    }
 
-                        match CachedResponse::new_for(
-                            &uri,
-                            upstream_response,
-                            content_length,
-                            encoding.clone(),
-                            skip_encoding,
-                            &self.caching.inner,
-                            &self.encoding.inner,
+    // Pass an upstream range through, contributing it to its assembly.
+    //
+    // If it completes the assembly then the assembled entry is staged for storing.
+    #[cfg(feature = "range-assembly")]
+    async fn assemble_range<ResponseBodyT>(
+        &mut self,
+        content_range: ContentRange,
+        upstream_response: Response<ResponseBodyT>,
+        cache_key: CacheKeyT,
+        fence: Fence,
+        uri: &Uri,
+        context: &mut RequestCacheContext<CacheKeyT>,
+    ) -> Response<TranscodingBody<ResponseBodyT>>
+    where
+        ResponseBodyT: 'static + Body + From<ImmutableBytes> + Send + Unpin,
+        ResponseBodyT::Data: From<ImmutableBytes> + Send,
+        ResponseBodyT::Error: Into<CapturedError>,
+    {
+        let range_assembly = self
+            .configuration
+            .caching
+            .range_assembly
+            .as_ref()
+            .expect("has range assembly");
+        let (parts, body) = upstream_response.into_parts();
+
+        let body_read_start = Instant::now();
+        let bytes = body
+            .read_into_bytes_or_pieces(None, 0, content_range.size())
+            .await;
+        context.trail.body_read = body_read_start.elapsed();
+
+        // Trailers are not passed through (they are unusual for ranges)
+        let bytes = match bytes {
+            Ok((bytes, _trailers)) => bytes,
+
+            Err(error) => {
+                let error = ErrorWithResponsePieces::new_from_body(error, parts);
+                return match error.pieces {
+                    Some(pieces) => {
+                        tracing::debug!("skip ({})", error.error);
+                        context.trail.decide("skip (range)");
+                        self.as_is(
+                            pieces.response,
+                            Some(pieces.first_bytes),
+                            &mut context.trail,
                         )
-                        .await
-                        {
-                            Ok(cached_response) => {
-                                tracing::debug!("store ({})", encoding);
-                                Arc::new(cached_response)
-                                    .to_transcoding_response(
-                                        &encoding,
-                                        true,
-                                        cache,
-                                        cache_key,
-                                        &self.encoding.inner,
-                                    )
-                                    .await
-                            }
+                    }
 
-                            Err(error) => match error.pieces {
-                                Some(pieces) => {
-                                    tracing::debug!("skip ({})", error.error);
-                                    pieces.response.with_transcoding_body_with_first_bytes(
-                                        Some(pieces.first_bytes),
-                                        &encoding,
-                                        self.encoding.inner.encodable_by_default,
-                                    )
-                                }
-
-                                None => {
-                                    tracing::error!(
-                                        "could not create cache entry: {} {}",
-                                        cache_key,
-                                        error
-                                    );
-                                    error_transcoding_response()
-                                }
-                            },
-                        }
+                    None => {
+                        tracing::error!("could not read range: {}", error.error);
+                        context.trail.decide("error");
+                        error_transcoding_response()
                     }
-                })
+                };
+            }
+        };
+
+        tracing::debug!("assemble");
+        context.trail.decide("assemble");
+
+        if let Some((headers, assembled)) =
+            range_assembly.contribute(&cache_key, &content_range, &parts.headers, &bytes)
+        {
+            let mut promoted = Response::new(ResponseBodyT::from(assembled));
+            *promoted.headers_mut() = headers;
+            promoted
+                .headers_mut()
+                .set_value(CONTENT_LENGTH, content_range.total);
+            if let Some(policy) = parts.extensions.get::<RoutePolicy>() {
+                promoted.extensions_mut().insert(policy.clone());
+            }
+
+            let overrides = self.route_overrides(promoted.extensions().get::<RoutePolicy>());
+            let (skip_caching, content_length) =
+                promoted.should_skip_cache(uri, &self.configuration.caching, &overrides);
+            if skip_caching {
+                context.trail.decide("skip (assembled)");
+            } else {
+                let (encoding, skip_encoding) = promoted.validate_encoding(
+                    uri,
+                    context.coding.clone(),
+                    content_length,
+                    &self.configuration.encoding,
+                );
+
+                let cached_response = context
+                    .within_budget(CachedResponse::new_for(
+                        uri,
+                        promoted,
+                        content_length,
+                        encoding,
+                        skip_encoding,
+                        &self.configuration.caching.inner,
+                        &self.configuration.encoding.inner,
+                    ))
+                    .await;
+
+                match cached_response {
+                    Some(Ok(cached_response)) => {
+                        tracing::debug!("store (assembled)");
+                        context.trail.decide("store (assembled)");
+                        context.stage(PendingStore::Put {
+                            key: cache_key,
+                            fence,
+                            cached_response: Arc::new(cached_response),
+                            pathway: StorePathway::Assembly,
+                        });
+                    }
+
+                    Some(Err(error)) => {
+                        tracing::error!(
+                            "could not create cache entry: {} {}",
+                            self.key_for_logs(&cache_key),
+                            error
+                        );
+                    }
+
+                    None => context.exhaust_budget(BudgetPhase::Store),
+                }
+            }
+        }
+
+        self.as_is(
+            Response::from_parts(parts, bytes.into()),
+            None,
+            &mut context.trail,
+        )
+    }
+
+    // Permit for the per-resource upstream concurrency limit, if configured.
+    async fn acquire_resource_permit(
+        configuration: &MiddlewareConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+        context: &mut RequestCacheContext<CacheKeyT>,
+    ) -> Option<ResourcePermit> {
+        let resource_limiter = configuration.caching.resource_limiter.as_ref()?;
+        let resource_id = context.resource_id.clone()?;
+
+        if let Some(permit) = resource_limiter.try_acquire(&resource_id) {
+            return Some(permit);
+        }
+
+        tracing::debug!("queued (resource): {}", resource_id);
+        context.trail.decide("queued (resource)");
+        let queued_start = Instant::now();
+        let permit = context
+            .within_budget(resource_limiter.acquire(resource_id))
+            .await;
+        context.trail.queued = queued_start.elapsed();
+
+        // Fail open: call the upstream without a permit
+        if permit.is_none() {
+            context.exhaust_budget(BudgetPhase::Queue);
+        }
+
+        permit
+    }
+
+    // Look up, consulting the generations switch (at most two lookups).
+    //
+    // Also returns the previous generation's key if the entry is from there.
+    async fn lookup(
+        configuration: &MiddlewareConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+        cache: &CacheT,
+        cache_key: &CacheKeyT,
+    ) -> (Option<CachedResponseRef>, Option<CacheKeyT>) {
+        let previous = configuration
+            .caching
+            .generations
+            .as_ref()
+            .and_then(|generations| {
+                let mode = generations.mode();
+                if mode == ServeGeneration::Current {
+                    return None;
+                }
+
+                let mut previous_key = cache_key.clone();
+                previous_key.set_generation(generations.previous()?);
+                Some((mode, previous_key))
+            });
+
+        match previous {
+            None => (Self::get_entry(configuration, cache, cache_key).await, None),
+
+            Some((ServeGeneration::ForcePrevious(_), previous_key)) => {
+                match Self::get_entry(configuration, cache, &previous_key).await {
+                    Some(cached_response) => (Some(cached_response), Some(previous_key)),
+                    None => (Self::get_entry(configuration, cache, cache_key).await, None),
+                }
+            }
+
+            Some((_, previous_key)) => match Self::get_entry(configuration, cache, cache_key).await
+            {
+                Some(cached_response) => (Some(cached_response), None),
+                None => {
+                    let cached_response =
+                        Self::get_entry(configuration, cache, &previous_key).await;
+                    let previous_key = cached_response.is_some().then_some(previous_key);
+                    (cached_response, previous_key)
+                }
+            },
+        }
+    }
+
+    // Get an entry, unless it is hidden by a purge fence.
+    //
+    // Caches that can answer immediately are not awaited.
+    async fn get_entry(
+        configuration: &MiddlewareConfiguration<RequestBodyT, CacheT, CacheKeyT>,
+        cache: &CacheT,
+        cache_key: &CacheKeyT,
+    ) -> Option<CachedResponseRef> {
+        let cached_response = match cache.get_if_present(cache_key) {
+            Some(cached_response) => cached_response?,
+            None => cache.get(cache_key).await?,
+        };
+
+        #[cfg(feature = "paced-purge")]
+        if let Some(purge_jobs) = &configuration.caching.purge_jobs
+            && purge_jobs.fenced(cache_key, &cached_response)
+        {
+            return None;
+        }
+
+        #[cfg(not(feature = "paced-purge"))]
+        let _ = configuration;
+
+        Some(cached_response)
+    }
+
+    // Count a hit for an entry.
+    fn record_hit(
+        &self,
+        cache_key: Option<&CacheKeyT>,
+        cached_response: &CachedResponse,
+        bytes: usize,
+    ) {
+        if let Some(body_sizes) = &self.configuration.caching.body_sizes {
+            body_sizes.record_hit(cached_response);
+        }
+
+        match (&self.configuration.caching.entry_stats, cache_key) {
+            (Some(entry_stats), Some(cache_key)) => {
+                entry_stats.record_hit(cache_key, cached_response, bytes)
+            }
+
+            // Admission history needs the count even without entry stats
+            _ => {
+                if self.configuration.caching.admission.is_some() {
+                    cached_response.hits.fetch_add(1, atomic::Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    // Whether to serve an expired entry while refreshing it in the background.
+    #[allow(unused_variables)]
+    fn serves_stale(
+        &self,
+        request: &Request<RequestBodyT>,
+        cached_response: &CachedResponse,
+    ) -> bool {
+        #[cfg(feature = "stale-while-revalidate")]
+        if let Some(stale_while_revalidate) = &self.configuration.caching.stale_while_revalidate {
+            // Our own refreshes must reach the upstream
+            return request.extensions().get::<Revalidation>().is_none()
+                && stale_while_revalidate
+                    .usable(cached_response, self.configuration.caching.inner.now())
+                && ::tokio::runtime::Handle::try_current().is_ok();
+        }
+
+        false
+    }
+
+    // Warn if the upstream response suggests a compression middleware below us.
+    fn check_compression_conflict(&self, uri: &Uri, headers: &HeaderMap) {
+        let encoding = &self.configuration.encoding;
+        if !encoding.assume_inner_compression
+            && encoding
+                .enabled_encodings_by_preference
+                .as_ref()
+                .is_some_and(|enabled_encodings| !enabled_encodings.is_empty())
+        {
+            encoding.compression_conflicts.check(uri, headers);
+        }
+    }
+
+    // Cache key as configured for logs.
+    fn key_for_logs<'this>(&'this self, cache_key: &'this CacheKeyT) -> impl fmt::Display + 'this {
+        cache_key.display_for_logs(&self.configuration.caching.inner.key_log_policy)
+    }
+
+    // Remove our `XX-` headers from an upstream response if we are not processing them.
+    fn strip_xx_headers(&self, headers: &mut HeaderMap) {
+        let caching = &self.configuration.caching;
+        caching.role.resolve_control_headers(headers, caching.label.as_deref());
+
+        if !caching.xx_headers {
+            for name in [
+                XX_CACHE,
+                XX_CACHE_DURATION,
+                XX_CACHE_TAGS,
+                XX_ENCODE,
+                XX_NO_SYNTHETIC_VALIDATORS,
+            ] {
+                headers.remove(name);
+            }
+        }
+    }
+
+    // Apply age accounting to a response served from a cache entry.
+    fn account_age<BodyT>(
+        &self,
+        cached_response: &CachedResponse,
+        response: &mut Response<BodyT>,
+    ) {
+        if !response.status().is_server_error() {
+            let now = self.configuration.caching.inner.now();
+            cached_response.apply_age_accounting(
+                response.headers_mut(),
+                self.configuration.caching.inner.age_accounting,
+                now,
+            );
+
+            if let Some(cache_age_header) = &self.configuration.caching.cache_age_header {
+                response
+                    .headers_mut()
+                    .set_value(cache_age_header.clone(), cached_response.held(now).as_secs());
+            }
+        }
+    }
+
+    // 304 for an entry, with the headers that the 200 would have had (including the validator for
+    // the coding that we would have served). Date and Age are then handled by age accounting, as
+    // for the 200.
+    fn not_modified<ResponseBodyT>(
+        &self,
+        cached_response: &CachedResponse,
+        context: &RequestCacheContext<CacheKeyT>,
+    ) -> Response<TranscodingBody<ResponseBodyT>>
+    where
+        ResponseBodyT: Body + From<ImmutableBytes>,
+        ResponseBodyT::Error: Into<CapturedError>,
+    {
+        let mut response = not_modified_transcoding_response();
+
+        let headers = response.headers_mut();
+        for name in NOT_MODIFIED_HEADERS {
+            for value in cached_response.headers().get_all(name) {
+                headers.append(name, value.clone());
+            }
+        }
+
+        if !context.no_transform && context.coding != cached_response.original_coding {
+            let encoding_configuration = &self.configuration.encoding.inner;
+            if !encoding_configuration.never_transform {
+                encoding_configuration
+                    .on_the_fly_validators
+                    .apply(headers, &context.coding);
             }
         }
+
+        response
+    }
+
+    // Validators-only entry to store for an oversized response, if configured.
+    fn validators_only_store(
+        &self,
+        cache_key: CacheKeyT,
+        fence: Fence,
+        uri: &Uri,
+        status: StatusCode,
+        headers: &HeaderMap,
+        content_length: Option<usize>,
+    ) -> Option<PendingStore<CacheKeyT>> {
+        if !self.configuration.caching.inner.cache_validators_for_oversized {
+            return None;
+        }
+
+        let cached_response = CachedResponse::new_validators_only(
+            uri,
+            status,
+            headers,
+            content_length,
+            &self.configuration.caching.inner,
+        )?;
+
+        tracing::debug!("store (validators only)");
+        Some(PendingStore::Put {
+            key: cache_key,
+            fence,
+            cached_response: Arc::new(cached_response),
+            pathway: StorePathway::ValidatorsOnly,
+        })
     }
 }
 
@@ -204,8 +2565,8 @@ where
     fn clone(&self) -> Self {
         Self {
             inner_service: self.inner_service.clone(),
-            caching: self.caching.clone(),
-            encoding: self.encoding.clone(),
+            configuration: self.configuration.clone(),
+            controlled: self.controlled.clone(),
         }
     }
 }
@@ -249,3 +2610,239 @@ where
         capture_async! { cloned_self.handle(request).await }
     }
 }
+
+impl<InnerServiceT, RequestBodyT, ResponseBodyT, ErrorT, CacheT, CacheKeyT>
+    CachingService<InnerServiceT, RequestBodyT, CacheT, CacheKeyT>
+where
+    Self: Service<
+            Request<RequestBodyT>,
+            Response = Response<TranscodingBody<ResponseBodyT>>,
+            Error = ErrorT,
+        >,
+    ResponseBodyT: Body,
+    ResponseBodyT::Error: Into<CapturedError>,
+    CacheT: Cache<CacheKeyT>,
+    CacheKeyT: CacheKey,
+{
+    /// Wait until the service is ready and then call it.
+    ///
+    /// Unlike Tower's `ServiceExt::oneshot` this does not consume the service, so it can be
+    /// called repeatedly, e.g. in tests or when embedding the service outside of an HTTP server.
+    pub async fn oneshot_ready(
+        &mut self,
+        request: Request<RequestBodyT>,
+    ) -> Result<Response<TranscodingBody<ResponseBodyT>>, ErrorT> {
+        future::poll_fn(|context| self.poll_ready(context)).await?;
+        self.call(request).await
+    }
+
+    /// Warm the cache by calling the service with synthetic requests, one at a time.
+    ///
+    /// Each request goes through the whole service, so its response is stored exactly as it
+    /// would be for a client's request (and only if it is cacheable). Requests that already have
+    /// entries don't refresh them. Spawn it if you want it to run in the background, e.g. at
+    /// startup.
+    ///
+    /// To insert entries from data that you already have, see [CachedResponse::from_bytes].
+    pub async fn warm<RequestsT>(&mut self, requests: RequestsT) -> WarmReport
+    where
+        RequestsT: IntoIterator<Item = Request<RequestBodyT>>,
+        ErrorT: fmt::Display,
+    {
+        let mut report = WarmReport::default();
+
+        for request in requests {
+            let uri = request.uri().clone();
+            let headers = request.headers().clone();
+            let cache_key = RequestCacheContext::new(
+                &request,
+                &self.configuration.caching,
+                &self.configuration.encoding,
+            )
+            .cache_key;
+
+            let outcome = match self.oneshot_ready(request).await {
+                Ok(response) => match response.extensions().get::<CacheStatus>() {
+                    Some(CacheStatus::Hit | CacheStatus::HitNotModified) => WarmOutcome::Cached,
+                    Some(CacheStatus::Bypass { reason }) => WarmOutcome::Skipped { reason },
+                    _ if self.has_entry(cache_key, &headers).await => WarmOutcome::Stored,
+                    _ => WarmOutcome::Skipped {
+                        reason: "uncacheable",
+                    },
+                },
+
+                Err(error) => WarmOutcome::Error(error.to_string()),
+            };
+
+            tracing::debug!("warm: {}: {}", uri, outcome);
+            report.outcomes.push((uri, outcome));
+        }
+
+        tracing::info!("warmed: {}", report);
+        report
+    }
+
+    // Whether there is an entry for a key (or its variant for the request headers).
+    async fn has_entry(&self, cache_key: Option<CacheKeyT>, headers: &HeaderMap) -> bool {
+        let (Some(cache), Some(mut cache_key)) = (&self.configuration.caching.cache, cache_key)
+        else {
+            return false;
+        };
+
+        if let Some(vary_keys) = &self.configuration.caching.vary_keys {
+            let names = vary_keys.names(&cache_key).unwrap_or_default();
+            if !names.is_empty() {
+                cache_key = VaryKeys::variant(&cache_key, &names, headers);
+            }
+        }
+
+        cache.get(&cache_key).await.is_some()
+    }
+}
+
+#[cfg(feature = "stale-while-revalidate")]
+impl<InnerServiceT, RequestBodyT, ResponseBodyT, ErrorT, CacheT, CacheKeyT>
+    CachingService<InnerServiceT, RequestBodyT, CacheT, CacheKeyT>
+where
+    Self: 'static
+        + Service<
+            Request<RequestBodyT>,
+            Response = Response<TranscodingBody<ResponseBodyT>>,
+            Error = ErrorT,
+            Future: Send,
+        >
+        + Send,
+    RequestBodyT: 'static + Send,
+    ResponseBodyT: 'static + Body + Send,
+    ResponseBodyT::Error: Into<CapturedError>,
+    CacheT: Cache<CacheKeyT>,
+    CacheKeyT: CacheKey,
+{
+    // Refresh a stale entry in the background.
+    //
+    // The request goes through the whole service again, so the refresh is just a miss (a
+    // conditional one if the entry has validators).
+    fn revalidate(
+        mut self,
+        mut request: Request<RequestBodyT>,
+        revalidation_guard: RevalidationGuard,
+    ) {
+        // We can only make sense of a 304 if the conditional headers are our own
+        let headers = request.headers_mut();
+        headers.remove(IF_NONE_MATCH);
+        headers.remove(IF_MODIFIED_SINCE);
+        request.extensions_mut().insert(Revalidation);
+
+        let uri = request.uri().clone();
+        ::tokio::spawn(async move {
+            tracing::debug!("revalidating: {}", uri);
+            match self.oneshot_ready(request).await {
+                Ok(response) if !response.status().is_server_error() => {}
+
+                Ok(response) => {
+                    revalidation_guard.failed();
+                    tracing::warn!(
+                        "revalidation failed, keeping stale entry: {} ({})",
+                        uri,
+                        response.status()
+                    );
+                }
+
+                Err(_) => {
+                    revalidation_guard.failed();
+                    tracing::warn!("revalidation failed, keeping stale entry: {}", uri);
+                }
+            }
+        });
+    }
+}
+
+// Variant keying of a request: the tracker, the base key, the names we keyed by, and the request
+// headers.
+type Variant<CacheKeyT> = (VaryKeys, CacheKeyT, Arc<[HeaderName]>, HeaderMap);
+
+// Outcome of a stage of `handle_with_context`: continue with the request, or end it early.
+type Staged<RequestBodyT, ResponseBodyT, NextT> =
+    ControlFlow<StageEnd<RequestBodyT, ResponseBodyT>, (Request<RequestBodyT>, NextT)>;
+
+//
+// StageEnd
+//
+
+// How a stage ends a request early.
+enum StageEnd<RequestBodyT, ResponseBodyT>
+where
+    ResponseBodyT: Body,
+    ResponseBodyT::Error: Into<CapturedError>,
+{
+    // Skip the cache and call the upstream.
+    Skip(Request<RequestBodyT>),
+
+    // Call the upstream and serve its response as is.
+    AsIs(Request<RequestBodyT>),
+
+    // Serve this response (boxed, as it is much larger than a request).
+    Respond(Box<Response<TranscodingBody<ResponseBodyT>>>),
+}
+
+//
+// Lookup
+//
+
+// What the lookup stage found.
+struct Lookup<CacheT, CacheKeyT> {
+    cache: CacheT,
+    cache_key: CacheKeyT,
+    fence: Fence,
+    vary: Option<Variant<CacheKeyT>>,
+    cached_response: Option<CachedResponseRef>,
+    previous_generation_key: Option<CacheKeyT>,
+}
+
+//
+// Miss
+//
+
+// What the conditional stage knows about a miss.
+struct Miss<CacheT, CacheKeyT> {
+    cache: CacheT,
+    cache_key: CacheKeyT,
+    fence: Fence,
+    vary: Option<Variant<CacheKeyT>>,
+    store_encoding: CodingId,
+
+    // Retained expired entry, which we can refresh
+    expired: Option<CachedResponseRef>,
+
+    // Previous entry, for the immutable safety valve
+    previous: Option<CachedResponseRef>,
+}
+
+// Plain text response for a cache administration request.
+fn admin_response<ResponseBodyT>(
+    status: StatusCode,
+    message: String,
+) -> Response<TranscodingBody<ResponseBodyT>>
+where
+    ResponseBodyT: Body + From<ImmutableBytes>,
+    ResponseBodyT::Error: Into<CapturedError>,
+{
+    let bytes = ImmutableBytes::from(message.into_bytes());
+    let content_length = bytes.len();
+
+    let mut response = Response::new(ResponseBodyT::from(bytes));
+    *response.status_mut() = status;
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+    headers.set_value(CONTENT_LENGTH, content_length);
+    response.map(|body| passthrough_with_trailers(body, Default::default()))
+}
+
+/// [CachingService] with [CommonCacheKey] and the Moka cache implementation.
+#[cfg(feature = "moka")]
+pub type DefaultCachingService<InnerServiceT, RequestBodyT> = CachingService<
+    InnerServiceT,
+    RequestBodyT,
+    implementation::moka::MokaCacheImplementation,
+    CommonCacheKey,
+>;
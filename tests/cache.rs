@@ -0,0 +1,1277 @@
+mod common;
+
+use {
+    common::*,
+    http::{header::*, *},
+    http_body::*,
+    kutil::{
+        http::{CONTENT_DIGEST, XX_CACHE, XX_CACHE_DURATION, XX_ENCODE, transcoding::*},
+        std::immutable::*,
+        transcoding::{transcode::*, *},
+    },
+    std::{array, future::*, pin::*, sync::*, time::*},
+    tower_http_response_cache::cache::{implementation::lru::*, middleware::*, *},
+};
+
+#[cfg(feature = "moka")]
+use {moka::Expiry, tower_http_response_cache::cache::implementation::moka::*};
+
+#[cfg(feature = "file")]
+use tower_http_response_cache::cache::implementation::file::*;
+
+// SipHash-2-4 reference vectors (key 00..0f, messages 00..(n-1))
+#[test]
+fn keyed_hash_reference() {
+    let key: [u8; 16] = array::from_fn(|index| index as u8);
+    let message = |length| (0..length).collect::<Vec<u8>>();
+    assert_eq!(keyed_hash(&key, &message(0)), 0x726fdb47dd0e0e31);
+    assert_eq!(keyed_hash(&key, &message(8)), 0x93f5f5799a932462);
+    assert_eq!(keyed_hash(&key, &message(15)), 0xa129ca6149be45e5);
+}
+
+// Canonical forms are pinned to exact bytes, are independent of the order in which query
+// parameters and extensions were given, and don't include absent fields
+#[test]
+fn canonical_key_form() {
+    let hex = |key: &CommonCacheKey| {
+        key.canonical_bytes().iter().map(|byte| format!("{:02x}", byte)).collect::<String>()
+    };
+
+    assert_eq!(hex(&key("/")), "01010000000347455402000000012f");
+    assert_eq!(
+        hex(&key("/s?b=2&a=1")),
+        "01010000000347455402000000022f73030000000a0000000161000000013103\
+         0000000a00000001620000000132"
+    );
+    assert_eq!(key("/s?b=2&a=1").canonical_bytes(), key("/s?a=1&b=2").canonical_bytes());
+    assert_eq!(key("/s?q=b&q=a").canonical_bytes(), key("/s?q=a&q=b").canonical_bytes());
+    assert_ne!(key("/s?q=a").canonical_bytes(), key("/s?q=A").canonical_bytes());
+    assert_ne!(key("/index.html").canonical_bytes(), key("/Index.HTML").canonical_bytes());
+
+    let with_extensions = |entries: &[(&str, &str)]| {
+        let mut key = key("/");
+        for (name, value) in entries {
+            key.extensions.get_or_insert_default().insert(
+                ImmutableBytes::from(name.as_bytes().to_vec()),
+                ImmutableBytes::from(value.as_bytes().to_vec()),
+            );
+        }
+        key
+    };
+    let forward = with_extensions(&[("a", "1"), ("b", "2")]);
+    let backward = with_extensions(&[("b", "2"), ("a", "1")]);
+    assert_eq!(forward.canonical_bytes(), backward.canonical_bytes());
+    assert_eq!(forward.canonical_digest(), backward.canonical_digest());
+    assert_ne!(forward.canonical_bytes(), key("/").canonical_bytes());
+    assert_eq!(with_extensions(&[]).canonical_bytes(), key("/").canonical_bytes());
+
+    // The pinned fixtures shipped for key type implementors
+    #[cfg(feature = "test-util")]
+    tower_http_response_cache::agreement::check_canonical_key_fixtures().expect("fixtures");
+}
+
+// The agreement digest is reproducible, covers every request in the corpus, and changes when
+// the keys do
+#[cfg(feature = "test-util")]
+#[test]
+fn key_agreement() {
+    use tower_http_response_cache::agreement::*;
+
+    let corpus = synthetic_key_corpus();
+    let agreement = key_agreement_test::<CommonCacheKey>(&corpus);
+    assert_eq!(agreement.version, CANONICAL_KEY_FORM_VERSION);
+    assert_eq!(agreement.key_digests.len(), corpus.len());
+    assert_eq!(agreement.to_string(), key_agreement_test::<CommonCacheKey>(&corpus).to_string());
+    assert!(agreement.to_string().starts_with(&format!("v{}:", CANONICAL_KEY_FORM_VERSION)));
+
+    // Reordered query parameters agree
+    let digest = |target: &str| {
+        let request = Request::get(target).body(()).expect("Request::get");
+        key_agreement_test::<CommonCacheKey>(&[request]).key_digests[0]
+    };
+    assert_eq!(digest("/search?q=cache&lang=en"), digest("/search?lang=en&q=cache"));
+
+    // A hook that changes keys changes the digest
+    let hooked = key_agreement_test_with(&corpus, |request| {
+        let mut key =
+            CommonCacheKey::for_request(request.method(), request.uri(), request.headers());
+        key.partition = Some("tenant".into());
+        key
+    });
+    assert_eq!(hooked.key_digests.len(), corpus.len());
+    assert_ne!(hooked.digest, agreement.digest);
+}
+
+// Unused optional key fields keep the previous canonical form, while a deployment that starts
+// using one falls back to its legacy entries once per key, until the transition window ends
+#[tokio::test]
+async fn key_migration() {
+    let hex = |key: &CommonCacheKey| {
+        key.canonical_bytes().iter().map(|byte| format!("{:02x}", byte)).collect::<String>()
+    };
+    let generational = |path, generation| {
+        let mut key = key(path);
+        key.generation = Some(generation);
+        key
+    };
+
+    // Tagged fields are appended, never interleaved
+    let mut partitioned = key("/");
+    partitioned.partition = Some("p".into());
+    assert_eq!(hex(&key("/")), "01010000000347455402000000012f");
+    assert_eq!(hex(&partitioned), "01010000000347455402000000012f0b0000000170");
+    assert_eq!(
+        hex(&generational("/", 7)),
+        "01010000000347455402000000012f0a000000080000000000000007"
+    );
+
+    let migrating = |until| {
+        KeyMigratingCache::new(
+            FencedCache::new(SimpleLruCache::new(1024 * 1024, None)),
+            Duration::ZERO,
+            |key: &CommonCacheKey| {
+                let mut key = key.clone();
+                key.generation = None;
+                Some(key)
+            },
+        )
+        .with_until(until)
+    };
+
+    let cache = migrating(SystemTime::now() + Duration::from_secs(60));
+    cache.inner.put(key("/a"), entry("a1", None)).await;
+    cache.inner.put(key("/b"), entry("b1", None)).await;
+
+    // Falls back once, then hits the re-stored entry; the legacy entry stays for old processes
+    assert!(cache.get_if_present(&generational("/a", 1)).is_none(), "needs the async get");
+    assert_eq!(version(cache.get(&generational("/a", 1)).await).as_deref(), Some("a1"));
+    assert_eq!(version(cache.get(&generational("/a", 1)).await).as_deref(), Some("a1"));
+    assert_eq!(version(cache.inner.get(&generational("/a", 1)).await).as_deref(), Some("a1"));
+    assert_version(&cache.inner, "/a", Some("a1")).await;
+
+    // A newer entry under the new key is not shadowed by the legacy one
+    cache.put(generational("/a", 1), entry("a2", None)).await;
+    assert_eq!(version(cache.get(&generational("/a", 1)).await).as_deref(), Some("a2"));
+
+    // Invalidating during the transition also drops the legacy form, so nothing falls back
+    cache.invalidate(&generational("/b", 1)).await;
+    assert_version(&cache.inner, "/b", None).await;
+    assert!(cache.get(&generational("/b", 1)).await.is_none());
+
+    // A fallback read that races an invalidation is served as a miss and not re-stored
+    cache.inner.put(key("/c"), entry("c1", None)).await;
+    let fence = cache.fence(&generational("/c", 1));
+    cache.invalidate(&generational("/c", 1)).await;
+    let outcome = cache.put_fenced(generational("/c", 1), entry("c1", None), fence).await;
+    assert_eq!(outcome, PutOutcome::RejectedStale);
+    assert!(cache.get(&generational("/c", 1)).await.is_none());
+
+    let stats = cache.stats();
+    assert_eq!(stats.fallback_hits, 1, "{}", stats);
+    assert_eq!(stats.fallback_misses, 2, "{}", stats);
+
+    // Keys without the new field have no distinct legacy form
+    assert!(cache.get(&key("/missing")).await.is_none());
+    assert_eq!(cache.stats().fallback_misses, 2);
+
+    // After the window, legacy entries are ignored
+    let cache = migrating(SystemTime::now() - Duration::from_secs(1));
+    cache.inner.put(key("/a"), entry("a1", None)).await;
+    assert!(!cache.in_transition());
+    assert!(cache.get(&generational("/a", 1)).await.is_none());
+    assert!(matches!(cache.get_if_present(&generational("/a", 1)), Some(None)));
+    assert_eq!(cache.stats().fallback_hits, 0);
+}
+
+// The declared cache weight of a synthetic corpus of entries must be within
+// WEIGHT_ESTIMATE_FACTOR of their actual (serialized) size
+//
+// The corpus varies the number of headers, representations, and dependency tokens, and the body
+// sizes. A new CachedResponse field that is serialized but not accounted for in its weight should
+// fail this test (once the corpus exercises it).
+#[test]
+fn weight_estimates() {
+    for header_count in [0, 8, 64] {
+        for coding_count in [1, 3] {
+            for dependency_count in [0, 16] {
+                for body_size in [1024, 64 * 1024] {
+                    let cached_response =
+                        synthetic_entry(header_count, coding_count, dependency_count, body_size);
+
+                    let sample = WeightSample::new(
+                        format!(
+                            "headers={} codings={} dependencies={} body={}",
+                            header_count, coding_count, dependency_count, body_size
+                        ),
+                        &cached_response,
+                    );
+
+                    assert!(!sample.diverges(WEIGHT_ESTIMATE_FACTOR), "{}", sample);
+                }
+            }
+        }
+    }
+}
+
+// Audit ratios are of declared weights to actual sizes, entries beyond the divergence are flagged
+// in both directions (and at exactly the divergence are not), and an empty sample only counts
+// the missing keys
+#[test]
+fn weight_audit_report() {
+    let sample = |key: &str, declared, actual| WeightSample {
+        key: key.into(),
+        declared,
+        actual,
+    };
+
+    let samples = vec![
+        sample("exact", 100, 100),
+        sample("over", 300, 100),
+        sample("under", 10, 100),
+        sample("double", 200, 100),
+        sample("half", 50, 100),
+    ];
+    let report = WeightAuditReport::new(samples, 2, 2.0);
+    assert_eq!((report.sampled, report.missing), (5, 2));
+    assert_eq!((report.min_ratio, report.median_ratio, report.max_ratio), (0.1, 1.0, 3.0));
+    assert_eq!(report.total_ratio, 660.0 / 500.0);
+    let divergent: Vec<_> = report.divergent.iter().map(|sample| sample.key.as_str()).collect();
+    assert_eq!(divergent, ["over", "under"]);
+
+    // Even count: the median is between the middle two
+    let samples = vec![sample("a", 100, 100), sample("b", 300, 100)];
+    assert_eq!(WeightAuditReport::new(samples, 0, 4.0).median_ratio, 2.0);
+
+    // Empty entries don't divide by zero
+    assert_eq!(sample("empty", 64, 0).ratio(), 64.0);
+
+    let report = WeightAuditReport::new(Vec::default(), 3, 2.0);
+    assert_eq!((report.sampled, report.missing), (0, 3));
+    assert!(report.divergent.is_empty());
+}
+
+// A range that ends at the largest position is clamped to the representation
+#[test]
+fn range_end_overflow() {
+    let range = format!("bytes=10-{}", usize::MAX);
+    assert_eq!(RangeRequest::parse(&range, 100), RangeRequest::Partial(10..100));
+}
+
+// A key with long query values renders within the caps, with sensitive values redacted and a
+// stable hash suffix, distinct long keys render distinctly, and the default renders in full
+#[test]
+fn key_log_policy() {
+    let policy = KeyLogPolicy::default()
+        .with_sensitive_query_param("Q")
+        .with_max_component_length(64)
+        .with_max_length(128);
+    let secret = "hunter2".repeat(1024);
+    let long_key =
+        |suffix| key(&format!("/search?q={}&filter={}{}", secret, "f".repeat(1024), suffix));
+
+    let rendered = long_key("1").display_for_logs(&policy).to_string();
+    assert!(rendered.len() <= 128, "capped: {}", rendered.len());
+    assert!(!rendered.contains("hunter2"), "redacted: {}", rendered);
+    assert!(rendered.contains("…#"), "hash suffix: {}", rendered);
+    assert_eq!(long_key("1").display_for_logs(&policy).to_string(), rendered, "stable");
+    assert_ne!(long_key("2").display_for_logs(&policy).to_string(), rendered, "distinct");
+
+    let short_key = key("/search?q=hunter2");
+    let rendered = short_key.display_for_logs(&KeyLogPolicy::redacted()).to_string();
+    assert!(rendered.contains(&format!("q={}", REDACTED)), "redacted preset: {}", rendered);
+    assert!(!rendered.contains("hunter2"), "redacted preset: {}", rendered);
+
+    let rendered = short_key.display_for_logs(&KeyLogPolicy::default()).to_string();
+    assert_eq!(rendered, short_key.to_string(), "default");
+    assert!(rendered.contains("q=hunter2"), "default: {}", rendered);
+}
+
+// Jitter spreads a duration uniformly within its fraction, capped at the maximum offset, never
+// goes below the minimum duration, and leaves shorter durations untouched
+#[test]
+fn ttl_jitter() {
+    let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let secs = Duration::from_secs;
+    let millis = Duration::from_millis;
+
+    // (jitter, duration, expected range)
+    let cases = [
+        (JitterConfig::new(0.1, secs(300)), secs(600), secs(540)..=secs(660)),
+        (JitterConfig::new(1.0, secs(10)), secs(600), secs(590)..=secs(610)),
+        (JitterConfig::new(1.0, secs(300)).min_duration(secs(5)), secs(6), secs(5)..=secs(12)),
+        (JitterConfig::new(1.0, secs(300)), millis(500), millis(500)..=millis(500)),
+    ];
+
+    for (jitter, duration, expected_range) in cases {
+        let jittered: Vec<_> = (0..1000).map(|_| jitter.jitter(duration, now)).collect();
+        for jittered in &jittered {
+            assert!(expected_range.contains(jittered), "{:?}: {:?}", duration, jittered);
+        }
+
+        // Spread across the range
+        let (start, end) = (*expected_range.start(), *expected_range.end());
+        if start != end {
+            let quarter = (end - start) / 4;
+            assert!(jittered.iter().any(|jittered| *jittered < start + quarter), "{:?}", duration);
+            assert!(jittered.iter().any(|jittered| *jittered > end - quarter), "{:?}", duration);
+        }
+    }
+}
+
+// max_cached_encodings keeps Identity and the most recently requested coding when other codings
+// are requested in turn
+#[test]
+fn max_cached_encodings() {
+    let mut configuration = MiddlewareEncodingConfiguration::default().inner;
+    configuration.max_cached_encodings = Some(2);
+
+    let identity = ImmutableBytes::from(vec![0; 4096]);
+    let mut body = CachedBody::new([(CodingId::IDENTITY, identity)].into_iter().collect());
+
+    let codings = [
+        Encoding::GZip,
+        Encoding::Brotli,
+        Encoding::Deflate,
+        Encoding::Zstandard,
+    ];
+    for (index, coding) in codings.into_iter().enumerate() {
+        let coding = CodingId::from(coding);
+
+        // As if reencoded for a request
+        let bytes = ImmutableBytes::from(vec![index as u8; 1024 + index]);
+        body.representations.insert(coding.clone(), bytes);
+        body.limit_representations(&coding, &configuration);
+
+        let codings: Vec<_> = body
+            .representations
+            .keys()
+            .map(ToString::to_string)
+            .collect();
+        assert!(
+            body.representations.len() == 2
+                && body.representations.contains_key(&CodingId::IDENTITY)
+                && body.representations.contains_key(&coding),
+            "after {}: [{}]",
+            coding,
+            codings.join(", ")
+        );
+    }
+}
+
+// An upstream body that is encoded on the fly (as for responses that are not stored) keeps its
+// trailers, and the encoded data is complete when they are emitted
+#[tokio::test]
+async fn transcoding_trailers() {
+    let mut trailers = HeaderMap::default();
+    trailers.insert("grpc-status", HeaderValue::from_static("0"));
+
+    let upstream = FramesBody(
+        [
+            Frame::data(ImmutableBytes::from(b"hello, ".to_vec())),
+            Frame::data(ImmutableBytes::from(b"world".to_vec())),
+            Frame::trailers(trailers.clone()),
+        ]
+        .into(),
+    );
+
+    let response =
+        Response::new(upstream).with_transcoding_body_with_first_bytes(None, &Encoding::GZip, true);
+
+    assert_eq!(
+        response.headers().get(CONTENT_ENCODING),
+        Some(&HeaderValue::from_static("gzip"))
+    );
+
+    let mut body = pin!(response.into_body());
+    let mut data = Vec::default();
+    let mut received_trailers = Vec::default();
+    while let Some(frame) = poll_fn(|context| body.as_mut().poll_frame(context)).await {
+        match frame.expect("frame").into_data() {
+            Ok(bytes) => {
+                assert!(received_trailers.is_empty(), "data after trailers");
+                data.extend_from_slice(&bytes);
+            }
+
+            Err(frame) => {
+                if let Ok(trailers) = frame.into_trailers() {
+                    received_trailers.push(trailers);
+                }
+            }
+        }
+    }
+
+    let decoded = ImmutableBytes::from(data)
+        .decode(&Encoding::GZip)
+        .await
+        .expect("decode");
+    assert_eq!(decoded.as_ref(), b"hello, world");
+    assert_eq!(received_trailers, vec![trailers]);
+}
+
+// A body that is shorter or longer than its declared Content-Length is not made into an entry,
+// and its pieces are returned for passing through
+#[tokio::test]
+async fn length_mismatch() {
+    let caching = MiddlewareCachingConfiguration::<(), (), CommonCacheKey>::default().inner;
+    let encoding = MiddlewareEncodingConfiguration::default().inner;
+    let uri = Uri::from_static("/length-mismatch");
+
+    // Under-delivers and over-delivers relative to the declared 1000 bytes
+    for (declared, size) in [(1000, 400), (1000, 1600)] {
+        let body = FramesBody(
+            [
+                Frame::data(ImmutableBytes::from(vec![b'a'; size / 2])),
+                Frame::data(ImmutableBytes::from(vec![b'b'; size - size / 2])),
+            ]
+            .into(),
+        );
+
+        let mut response = Response::new(body);
+        response
+            .headers_mut()
+            .insert(CONTENT_LENGTH, HeaderValue::from(declared));
+
+        let error = match CachedResponse::new_for(
+            &uri,
+            response,
+            Some(declared),
+            CodingId::IDENTITY,
+            true,
+            &caching,
+            &encoding,
+        )
+        .await
+        {
+            Ok(_) => panic!("{} of {}: stored", size, declared),
+            Err(error) => error,
+        };
+
+        assert!(
+            LengthMismatch::of(&error.error).is_some(),
+            "{} of {}: {}",
+            size,
+            declared,
+            error.error
+        );
+        assert!(
+            error.pieces.is_some(),
+            "{} of {}: no pieces",
+            size,
+            declared
+        );
+    }
+}
+
+// Storing removes exactly the transport and control headers, keeping all values of the rest in
+// order, for responses with few and many headers, with and without control headers
+#[tokio::test]
+async fn store_header_removal() {
+    let caching = MiddlewareCachingConfiguration::<(), (), CommonCacheKey>::default().inner;
+    let encoding = MiddlewareEncodingConfiguration::default().inner;
+    let uri = Uri::from_static("/headers");
+
+    let control = [
+        (AGE, "60"),
+        (XX_CACHE, "true"),
+        (XX_CACHE_DURATION, "5m"),
+        (XX_CACHE_TAGS, "a, b"),
+        (XX_NO_SYNTHETIC_VALIDATORS, "true"),
+        (CONTENT_LENGTH, "5"),
+        (CONTENT_DIGEST, "sha-256=:AAAA:"),
+        (ACCEPT_RANGES, "bytes"),
+    ];
+
+    for header_count in [0, 5, 40] {
+        for with_control in [false, true] {
+            let mut headers = HeaderMap::new();
+            headers.insert(ETAG, HeaderValue::from_static("\"v1\""));
+            headers.insert(
+                LAST_MODIFIED,
+                HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+            );
+            for index in 0..header_count {
+                let name = HeaderName::try_from(format!("x-header-{}", index)).expect("name");
+                headers.insert(name, HeaderValue::from(index));
+            }
+            if header_count > 0 {
+                headers.append(SET_COOKIE, HeaderValue::from_static("a=1"));
+                headers.append(SET_COOKIE, HeaderValue::from_static("b=2"));
+            }
+            if with_control {
+                for (name, value) in &control {
+                    headers.insert(name, HeaderValue::from_static(value));
+                }
+            }
+
+            // One remove per header, as before single-pass removal
+            let mut expected = headers.clone();
+            for (name, _) in &control {
+                expected.remove(name);
+            }
+            expected.insert(XX_ENCODE, HeaderValue::from_static("true"));
+
+            let body = FramesBody::from(ImmutableBytes::from(b"hello".to_vec()));
+            let mut response = Response::new(body);
+            *response.headers_mut() = headers;
+            let cached_response = match CachedResponse::new_for(
+                &uri,
+                response,
+                None,
+                CodingId::IDENTITY,
+                true,
+                &caching,
+                &encoding,
+            )
+            .await
+            {
+                Ok(cached_response) => cached_response,
+                Err(error) => panic!("{} headers: {}", header_count, error.error),
+            };
+
+            // Note that map equality compares all values of each header in order
+            let stored = cached_response.parts.headers;
+            assert_eq!(stored, expected, "{} headers, control={}", header_count, with_control);
+        }
+    }
+}
+
+// SimpleLruCache evicts the least recently used entries, where gets and puts count as uses
+#[tokio::test]
+async fn lru_eviction_order() {
+    // Our entries all weigh the same
+    let unbounded = SimpleLruCache::new(usize::MAX, None);
+    unbounded.put(key("/a"), entry("v1", None)).await;
+    let weight = unbounded.weight();
+
+    let cache = SimpleLruCache::new(3 * weight, None);
+    for path in ["/a", "/b", "/c"] {
+        cache.put(key(path), entry("v1", None)).await;
+    }
+    assert_eq!(cache.keys_by_recency(), [key("/a"), key("/b"), key("/c")]);
+
+    // Getting /a makes /b the least recently used
+    assert_version(&cache, "/a", Some("v1")).await;
+    cache.put(key("/d"), entry("v1", None)).await;
+    assert_eq!(cache.keys_by_recency(), [key("/c"), key("/a"), key("/d")]);
+
+    // Putting /c again makes /a the least recently used
+    cache.put(key("/c"), entry("v2", None)).await;
+    cache.put(key("/e"), entry("v1", None)).await;
+    assert_eq!(cache.keys_by_recency(), [key("/d"), key("/c"), key("/e")]);
+    assert_eq!(cache.weight(), 3 * weight);
+
+    // A miss is not a use
+    assert_version(&cache, "/a", None).await;
+    assert_eq!(cache.keys_by_recency(), [key("/d"), key("/c"), key("/e")]);
+}
+
+// A heavier replacement evicts as many entries as it needs, and an entry heavier than the maximum
+// is not stored (and removes the entry it would have replaced)
+#[tokio::test]
+async fn lru_eviction_by_weight() {
+    let light = || entry("v1", None);
+    let heavy = || Arc::new(synthetic_entry(0, 1, 0, 4 * 1024));
+
+    let unbounded = SimpleLruCache::new(usize::MAX, None);
+    unbounded.put(key("/a"), light()).await;
+    let light_weight = unbounded.weight();
+    unbounded.put(key("/a"), heavy()).await;
+    let heavy_weight = unbounded.weight();
+
+    let cache = SimpleLruCache::new(heavy_weight + light_weight, None);
+    for path in ["/a", "/b", "/c"] {
+        cache.put(key(path), light()).await;
+    }
+
+    cache.put(key("/c"), heavy()).await;
+    assert_eq!(cache.keys_by_recency(), [key("/b"), key("/c")]);
+    assert_eq!(cache.weight(), heavy_weight + light_weight);
+
+    let cache = SimpleLruCache::new(heavy_weight - 1, None);
+    cache.put(key("/a"), light()).await;
+    cache.put(key("/a"), heavy()).await;
+    assert!(cache.is_empty(), "too heavy: stored");
+    assert_eq!(cache.weight(), 0);
+}
+
+// Expired entries are removed when gotten, unless within the grace period, and durations that
+// overflow never expire
+#[tokio::test]
+async fn lru_expiry() {
+    let cache = SimpleLruCache::new(1024 * 1024, None);
+    cache.put(key("/expired"), entry("v1", Some(Duration::ZERO))).await;
+    assert_eq!(cache.len(), 1);
+    assert_version(&cache, "/expired", None).await;
+    assert!(cache.is_empty(), "expired: not removed");
+
+    let cache = SimpleLruCache::new(1024 * 1024, None).with_grace(Duration::from_secs(60));
+    cache.put(key("/grace"), entry("v1", Some(Duration::ZERO))).await;
+    assert_version(&cache, "/grace", Some("v1")).await;
+
+    let cache =
+        SimpleLruCache::new(1024 * 1024, Some(Duration::MAX)).with_grace(Duration::from_secs(60));
+    cache.put(key("/max"), entry("v1", Some(Duration::MAX))).await;
+    cache.put(key("/default"), entry("v1", None)).await;
+    assert_version(&cache, "/max", Some("v1")).await;
+    assert_version(&cache, "/default", Some("v1")).await;
+}
+
+// Only caches that never wait answer synchronously: the LRU always does (expiring and touching
+// as get does), wrappers delegate, tiers answer only for first-tier hits, and Moka never does
+#[tokio::test]
+async fn get_if_present() {
+    let present = |answer: Option<Option<CachedResponseRef>>| answer.map(version);
+
+    let cache = SimpleLruCache::new(1024 * 1024, None);
+    cache.put(key("/a"), entry("v1", None)).await;
+    cache.put(key("/b"), entry("v1", None)).await;
+    cache.put(key("/expired"), entry("v1", Some(Duration::ZERO))).await;
+    assert_eq!(present(cache.get_if_present(&key("/a"))), Some(Some("v1".into())));
+    assert_eq!(present(cache.get_if_present(&key("/missing"))), Some(None));
+    assert_eq!(present(cache.get_if_present(&key("/expired"))), Some(None));
+    assert_eq!(cache.keys_by_recency(), [key("/b"), key("/a")], "expired removed, /a touched");
+
+    // Wrappers
+    let fenced = FencedCache::new(cache.clone());
+    assert_eq!(present(fenced.get_if_present(&key("/b"))), Some(Some("v1".into())));
+    let dependency = DependencyCache::new(cache.clone());
+    assert_eq!(present(dependency.get_if_present(&key("/missing"))), Some(None));
+    assert_eq!(present(MockCache::default().get_if_present(&key("/a"))), None, "default");
+
+    // Tiers: a miss in the first tier may be a hit in the next
+    let lru = || SimpleLruCache::new(1024 * 1024, None);
+    let cache = TieredCache::new(lru(), lru());
+    cache.put(key("/both"), entry("v1", None)).await;
+    cache.next.put(key("/next"), entry("v1", None)).await;
+    assert_eq!(present(cache.get_if_present(&key("/both"))), Some(Some("v1".into())));
+    assert_eq!(present(cache.get_if_present(&key("/next"))), None);
+    assert_eq!(present(cache.get_if_present(&key("/missing"))), None);
+    assert_version(&cache, "/next", Some("v1")).await;
+
+    #[cfg(feature = "moka")]
+    {
+        let cache = MokaCacheImplementation::new(
+            moka::future::Cache::builder().max_capacity(1024 * 1024).build(),
+        );
+        cache.put(key("/a"), entry("v1", None)).await;
+        assert_eq!(present(cache.get_if_present(&key("/a"))), None);
+        assert_version(&cache, "/a", Some("v1")).await;
+    }
+}
+
+// Corrupted and truncated files are misses and are removed
+#[cfg(feature = "file")]
+#[tokio::test]
+async fn file_corruption() {
+    let directory = temporary_directory("corruption");
+    let cache = FileCacheImplementation::open(&directory, 1024 * 1024).await.expect("open");
+
+    cache.put(key("/a"), Arc::new(synthetic_entry(4, 2, 0, 1024))).await;
+    let path = directory.join(cache.file_name(&key("/a")));
+    let bytes = std::fs::read(&path).expect("read");
+
+    // The file starts with the 8-byte expiry and the 4-byte key length
+    let corruptions = [
+        ("empty", Vec::default()),
+        ("expiry only", bytes[..8].to_vec()),
+        ("truncated key", bytes[..14].to_vec()),
+        ("truncated entry", bytes[..bytes.len() / 2].to_vec()),
+        ("last byte missing", bytes[..bytes.len() - 1].to_vec()),
+        ("garbage", vec![0xff; bytes.len()]),
+    ];
+
+    for (name, corrupted) in corruptions {
+        std::fs::write(&path, corrupted).expect("write");
+        assert!(cache.get(&key("/a")).await.is_none(), "{}: hit", name);
+        assert!(!path.exists(), "{}: not removed", name);
+    }
+
+    // Still usable
+    cache.put(key("/a"), entry("v1", None)).await;
+    assert_version(&cache, "/a", Some("v1")).await;
+}
+
+// Invalidating by tag leaves exactly the untagged entry, whether the cache matches its keys
+// itself (Moka), delegates to its tiers, or falls back to enumerating them
+#[tokio::test]
+async fn invalidate_by_tag() {
+    fn tagged(path: &str, tags: &[&str]) -> CommonCacheKey {
+        let mut key = key(path);
+        for tag in tags {
+            key.extensions.get_or_insert_default().insert(
+                ImmutableBytes::from(tag.as_bytes().to_vec()),
+                ImmutableBytes::from(b"1".to_vec()),
+            );
+        }
+        key
+    }
+
+    assert!(tagged("/", &["product:123"]).has_extension(b"product:123"));
+    assert!(!tagged("/", &["product:1234"]).has_extension(b"product:123"), "exact");
+    assert!(!tagged("/", &["product:123"]).has_extension(b"1"), "names only");
+    assert!(!key("/").has_extension(b"product:123"));
+
+    async fn check<CacheT>(name: &str, cache: CacheT)
+    where
+        CacheT: Cache<CommonCacheKey>,
+    {
+        let keys = [
+            tagged("/a", &["product:123"]),
+            tagged("/b", &["category:7", "product:123"]),
+            tagged("/c", &["category:7"]),
+            key("/d"),
+        ];
+        for key in &keys {
+            cache.put(key.clone(), entry("v1", None)).await;
+        }
+
+        let outcome = cache.invalidate_matching(|key| key.has_extension(b"product:123")).await;
+        assert!(outcome.is_success(), "{}: {:?}", name, outcome);
+        assert!(outcome.count.is_none_or(|count| count == 2), "{}: {:?}", name, outcome);
+
+        let mut present = Vec::default();
+        for key in &keys {
+            present.push(cache.get(key).await.is_some());
+        }
+        assert_eq!(present, [false, false, true, true], "{}", name);
+
+        // Invalidating by the other tag leaves exactly the untagged entry
+        cache.invalidate_matching(|key| key.has_extension(b"category:7")).await;
+        assert!(cache.get(&keys[2]).await.is_none(), "{}", name);
+        assert_version(&cache, "/d", Some("v1")).await;
+    }
+
+    let lru = || SimpleLruCache::new(1024 * 1024, None);
+    check("lru", lru()).await;
+    check("mock", MockCache::default()).await;
+    check("tiered", TieredCache::new(lru(), MockCache::default())).await;
+
+    // The tiers are invalidated too, not just the combination
+    let cache = TieredCache::new(lru(), lru());
+    cache.put(tagged("/a", &["product:123"]), entry("v1", None)).await;
+    let product = |key: &CommonCacheKey| key.has_extension(b"product:123");
+    let outcome = cache.invalidate_matching(product).await;
+    assert_eq!(outcome.tiers.len(), 2);
+    assert!(cache.next.get(&tagged("/a", &["product:123"])).await.is_none());
+
+    #[cfg(feature = "moka")]
+    {
+        // With invalidation closures, and without them (falling back to iterating)
+        let builder = || moka::future::Cache::builder().max_capacity(1024 * 1024);
+        let cache = MokaCacheImplementation::new(builder().for_http_response().build());
+        check("moka", cache).await;
+        check("moka (no closures)", MokaCacheImplementation::new(builder().build())).await;
+    }
+}
+
+// TieredCachePolicy promotion and writes with two SimpleLruCache tiers
+#[tokio::test]
+async fn tiered_policies() {
+    let tiered = |policy| {
+        let tier = || SimpleLruCache::new(1024 * 1024, None);
+        TieredCache::new(tier(), tier()).with_policy(policy)
+    };
+
+    // Default: both tiers are written, and next hits are not promoted
+    let cache = tiered(TieredCachePolicy::default());
+    cache.put(key("/default"), entry("v1", None)).await;
+    assert_version(&cache.first, "/default", Some("v1")).await;
+    assert_version(&cache.next, "/default", Some("v1")).await;
+    cache.first.invalidate(&key("/default")).await;
+    assert_version(&cache, "/default", Some("v1")).await;
+    assert_version(&cache.first, "/default", None).await;
+
+    // Promotion
+    let cache = tiered(TieredCachePolicy::default().with_promotion(true));
+    cache.next.put(key("/promote"), entry("v1", None)).await;
+    assert_version(&cache, "/promote", Some("v1")).await;
+    assert_version(&cache.first, "/promote", Some("v1")).await;
+
+    // Expired entries are not promoted
+    cache.next.put(key("/expired"), entry("v1", Some(Duration::ZERO))).await;
+    cache.get(&key("/expired")).await;
+    assert_version(&cache.first, "/expired", None).await;
+
+    // First only: the older version in the next tier is invalidated
+    let cache = tiered(TieredCachePolicy::default().with_write(TieredWrite::FirstOnly));
+    cache.next.put(key("/first"), entry("v1", None)).await;
+    cache.put(key("/first"), entry("v2", None)).await;
+    assert_version(&cache.first, "/first", Some("v2")).await;
+    assert_version(&cache.next, "/first", None).await;
+
+    // Next only: the older version in the first tier is invalidated
+    let cache = tiered(TieredCachePolicy::default().with_write(TieredWrite::NextOnly));
+    cache.first.put(key("/next"), entry("v1", None)).await;
+    cache.put(key("/next"), entry("v2", None)).await;
+    assert_version(&cache.first, "/next", None).await;
+    assert_version(&cache.next, "/next", Some("v2")).await;
+
+    // Weight threshold: heavy entries are neither written to nor promoted into the first tier
+    let heavy = || Arc::new(synthetic_entry(0, 1, 0, 64 * 1024));
+    let cache = tiered(
+        TieredCachePolicy::default().with_promotion(true).with_first_tier_max_weight(16 * 1024),
+    );
+    cache.put(key("/light"), entry("v1", None)).await;
+    cache.put(key("/heavy"), heavy()).await;
+    assert_version(&cache.first, "/light", Some("v1")).await;
+    assert_version(&cache.first, "/heavy", None).await;
+    assert_version(&cache.next, "/heavy", Some("synthetic")).await;
+    assert_version(&cache, "/heavy", Some("synthetic")).await;
+    assert_version(&cache.first, "/heavy", None).await;
+}
+
+// SplitCache reads from the replica and writes to the primary, and within the read-your-writes
+// window recent puts are read from the primary and recent invalidations are not resurrected by
+// the lagging replica
+#[tokio::test]
+async fn split_read_your_writes() {
+    // The replica only catches up with the primary when we replicate
+    let split = |window: Option<Duration>| {
+        let cache = SplitCache::new(MockCache::default(), MockCache::default());
+        match window {
+            Some(window) => cache.read_your_writes(window),
+            None => cache,
+        }
+    };
+    let replicate = async |cache: &SplitCache<MockCache, MockCache>| {
+        cache.read.invalidate_all().await;
+        for key in cache.write.keys().expect("keys") {
+            let cached_response = cache.write.get(&key).await.expect("get");
+            cache.read.put(key, cached_response).await;
+        }
+    };
+
+    // Without the window a just-stored key misses until replicated
+    let cache = split(None);
+    cache.put(key("/a"), entry("v1", None)).await;
+    assert_version(&cache, "/a", None).await;
+    replicate(&cache).await;
+    assert_version(&cache, "/a", Some("v1")).await;
+
+    // With the window it hits via the primary
+    let window = Duration::from_millis(50);
+    let cache = split(Some(window));
+    cache.put(key("/a"), entry("v1", None)).await;
+    assert_version(&cache, "/a", Some("v1")).await;
+    assert_version(&cache.read, "/a", None).await;
+
+    // Once the window expires we read from the replica again
+    tokio::time::sleep(window + Duration::from_millis(20)).await;
+    assert_version(&cache, "/a", None).await;
+    replicate(&cache).await;
+    assert_version(&cache, "/a", Some("v1")).await;
+
+    // A stale replica does not undo an invalidation within the window
+    cache.invalidate(&key("/a")).await;
+    assert_version(&cache.read, "/a", Some("v1")).await;
+    assert_version(&cache, "/a", None).await;
+    tokio::time::sleep(window + Duration::from_millis(20)).await;
+    assert_version(&cache, "/a", Some("v1")).await;
+    replicate(&cache).await;
+    assert_version(&cache, "/a", None).await;
+
+    // Unless invalidations are not remembered
+    let cache = split(Some(window)).remember_invalidations(false);
+    cache.put(key("/b"), entry("v1", None)).await;
+    replicate(&cache).await;
+    cache.invalidate(&key("/b")).await;
+    assert_version(&cache, "/b", Some("v1")).await;
+}
+
+// Preloading the first tier respects the byte budget and the deadline, loaded entries are served
+// by the first tier, and a manifest exported by one process is preloaded by the next
+#[tokio::test]
+async fn tiered_preload() {
+    let tiered = |next: &MockCache| {
+        TieredCache::new(SimpleLruCache::new(1024 * 1024, None), next.clone())
+    };
+    let weight = |path| key(path).cache_weight() + entry("v1", None).cache_weight();
+
+    let next = MockCache::default();
+    let cache = tiered(&next);
+    next.put(key("/heavy"), Arc::new(synthetic_entry(0, 1, 0, 64 * 1024))).await;
+    next.put(key("/expired"), entry("v1", Some(Duration::ZERO))).await;
+    cache.first.put(key("/present"), entry("v1", None)).await;
+    for path in ["/a", "/b", "/c"] {
+        next.put(key(path), entry("v1", None)).await;
+    }
+
+    // Room for two of the light entries, in manifest order, after skipping the heavy one
+    let manifest = ["/heavy", "/expired", "/missing", "/present", "/a", "/b", "/c"];
+    let configuration = PreloadConfig::new(
+        weight("/a") * 2 + weight("/a") / 2,
+        PreloadKeySource::Manifest(manifest.into_iter().map(key).collect()),
+    )
+    .with_concurrency(2);
+    let report = cache.preload_first_tier(configuration).await;
+    assert_eq!((report.loaded, report.bytes), (2, weight("/a") as u64 * 2));
+    assert_eq!((report.present, report.missing, report.expired), (1, 1, 1));
+    assert_eq!(report.over_budget, 2, "/heavy and /c");
+    assert!(!report.deadline_reached);
+
+    // Loaded entries are hits without the next tier
+    next.invalidate_all().await;
+    assert_version(&cache, "/a", Some("v1")).await;
+    assert_version(&cache, "/b", Some("v1")).await;
+    assert_version(&cache, "/c", None).await;
+
+    // Nothing is loaded after the deadline
+    let next = MockCache::default();
+    next.put(key("/a"), entry("v1", None)).await;
+    let key_source = PreloadKeySource::Manifest(vec![key("/a")]);
+    let configuration = PreloadConfig::new(1024 * 1024, key_source).with_deadline(Duration::ZERO);
+    let report = tiered(&next).preload_first_tier(configuration).await;
+    assert_eq!(report.loaded, 0);
+    assert!(report.deadline_reached);
+
+    // Popular and recently written keys come first
+    let next = MockCache::default();
+    for (path, hits) in [("/older", 5), ("/newer", 1)] {
+        let cached_response = entry("v1", None);
+        cached_response.hits.store(hits, atomic::Ordering::Relaxed);
+        next.put(key(path), cached_response).await;
+    }
+    for (key_source, expected) in
+        [(PreloadKeySource::Popular, "/older"), (PreloadKeySource::RecentlyWritten, "/newer")]
+    {
+        let cache = tiered(&next);
+        let configuration = PreloadConfig::new(weight("/older"), key_source);
+        let report = cache.preload_first_tier(configuration).await;
+        assert_eq!(report.loaded, 1, "{}", expected);
+        assert_eq!(cache.first.keys().expect("keys"), [key(expected)]);
+    }
+
+    // The first process exports its most hit keys on shutdown...
+    let next = MockCache::default();
+    let cache = tiered(&next);
+    for (path, hits) in [("/a", 1), ("/b", 3), ("/c", 2)] {
+        cache.put(key(path), entry("v1", None)).await;
+        let cached_response = cache.first.get(&key(path)).await.expect("get");
+        cached_response.hits.fetch_add(hits, atomic::Ordering::Relaxed);
+    }
+    let manifest = cache.export_manifest(2).await.expect("export_manifest");
+    assert_eq!(manifest, [key("/b"), key("/c")]);
+
+    // ...and the next process preloads them on boot
+    let cache = tiered(&next);
+    let report = cache
+        .preload_first_tier(PreloadConfig::new(1024 * 1024, PreloadKeySource::Manifest(manifest)))
+        .await;
+    assert_eq!(report.loaded, 2);
+    next.invalidate_all().await;
+    assert_version(&cache, "/a", None).await;
+    assert_version(&cache, "/b", Some("v1")).await;
+    assert_version(&cache, "/c", Some("v1")).await;
+}
+
+// Representation digests are stable and survive serialization, and a put that replaces an entry
+// in the first tier shares its identical representations
+#[tokio::test]
+async fn representation_digests() {
+    let cached_response = synthetic_entry(0, 3, 0, 1024);
+    let serialized = cached_response.serialize();
+    let deserialized = CachedResponse::deserialize(&serialized, &[]).expect("deserialize");
+
+    for (coding, bytes) in &cached_response.body.representations {
+        let digest = cached_response.body.digest(coding);
+        assert_eq!(digest, Some(stable_hash(bytes)), "{}", coding);
+        assert_eq!(deserialized.body.digest(coding), digest, "{}", coding);
+    }
+
+    let cache = TieredCache::new(
+        SimpleLruCache::new(1024 * 1024, None),
+        SimpleLruCache::new(1024 * 1024, None),
+    );
+    cache.put(key("/shared"), Arc::new(cached_response)).await;
+    let existing = cache.first.get(&key("/shared")).await.expect("existing");
+
+    // Same bytes in other allocations
+    cache.put(key("/shared"), Arc::new(deserialized)).await;
+    let replaced = cache.first.get(&key("/shared")).await.expect("replaced");
+
+    for (coding, bytes) in &replaced.body.representations {
+        let existing = existing.body.representations.get(coding).expect("coding");
+        assert_eq!(bytes.as_ptr(), existing.as_ptr(), "{}", coding);
+    }
+}
+
+// A reencode job adds the coding to matching entries without disturbing their expiry or other
+// representations, merges with a concurrent reencode, and resumes from where its budget ran out
+#[cfg(all(feature = "brotli", feature = "zstd"))]
+#[tokio::test]
+async fn reencode_job() {
+    let encoding = MiddlewareEncodingConfiguration::default().inner;
+    let zstd = CodingId::from(Encoding::Zstandard);
+    let brotli = CodingId::from(Encoding::Brotli);
+    let hour = Some(Duration::from_secs(60 * 60));
+
+    // (path, duration, no-transform, expected to be reencoded)
+    let entries = [
+        ("/a", hour, false, true),
+        ("/b", hour, false, true),
+        ("/c", None, false, true),
+        ("/expiring", Some(Duration::from_secs(60)), false, false),
+        ("/no-transform", hour, true, false),
+    ];
+
+    let cache = InterferingCache::default();
+    for (path, duration, no_transform, _) in entries {
+        let mut cached_response = synthetic_entry(0, 2, 0, 4096);
+        cached_response.duration = duration;
+        cached_response.no_transform = no_transform;
+        cache.0.put(key(path), Arc::new(cached_response)).await;
+    }
+
+    // A request reencodes /b to Brotli while the job is reencoding it
+    *cache.1.lock().expect("lock") = Some(key("/b"));
+
+    let mut job = ReencodeJob::new(cache.clone(), zstd.clone(), encoding.clone())
+        .with_filter(ReencodeFilter {
+            min_weight: 0,
+            min_remaining: Duration::from_secs(10 * 60),
+        })
+        .with_concurrency(2);
+
+    // Interrupted before visiting anything
+    let progress = job.run(Duration::ZERO).await;
+    assert_eq!((progress.visited, progress.remaining), (0, 5), "interrupted");
+    assert!(!job.is_done(), "interrupted");
+
+    // Not in the snapshot of keys
+    cache.0.put(key("/late"), Arc::new(synthetic_entry(0, 2, 0, 4096))).await;
+
+    let mut originals = Vec::default();
+    for (path, ..) in &entries {
+        originals.push(cache.0.get(&key(path)).await.expect(path));
+    }
+
+    let progress = job.run(Duration::from_secs(60)).await;
+    assert_eq!(
+        (progress.visited, progress.reencoded, progress.skipped, progress.failed),
+        (5, 3, 2, 0),
+        "resumed: {}",
+        progress
+    );
+    assert_eq!(progress.remaining, 0, "resumed");
+    assert!(job.is_done(), "resumed");
+
+    for ((path, _, _, reencoded), original) in entries.iter().zip(originals) {
+        let cached_response = cache.0.get(&key(path)).await.expect(path);
+        let representations = &cached_response.body.representations;
+
+        assert_eq!(cached_response.created, original.created, "{}: created", path);
+        assert_eq!(cached_response.duration, original.duration, "{}: duration", path);
+        for (coding, bytes) in &original.body.representations {
+            assert_eq!(representations.get(coding), Some(bytes), "{}: {}", path, coding);
+        }
+
+        assert_eq!(representations.contains_key(&zstd), *reencoded, "{}: zstd", path);
+        if let Some(bytes) = representations.get(&zstd) {
+            let decoded = bytes.decode(&Encoding::Zstandard).await.expect("decode");
+            let identity = representations.get(&CodingId::IDENTITY).expect("identity");
+            assert_eq!(&decoded, identity, "{}: zstd", path);
+        }
+
+        assert_eq!(representations.contains_key(&brotli), *path == "/b", "{}: brotli", path);
+    }
+
+    let late = cache.0.get(&key("/late")).await.expect("late");
+    assert!(!late.body.representations.contains_key(&zstd), "late");
+
+    let progress = job.run(Duration::from_secs(60)).await;
+    assert_eq!(progress.visited, 5, "done");
+}
+
+// Reencodes an entry to Brotli (as a request would) on the first get of its key, after which the
+// getter has a stale entry
+#[derive(Clone, Default)]
+struct InterferingCache(MockCache, Arc<Mutex<Option<CommonCacheKey>>>);
+
+impl Cache for InterferingCache {
+    async fn get(&self, key: &CommonCacheKey) -> Option<CachedResponseRef> {
+        let cached_response = self.0.get(key).await?;
+
+        let interfere = {
+            let mut interfering_key = self.1.lock().expect("lock");
+            interfering_key.take_if(|interfering_key| *interfering_key == *key).is_some()
+        };
+        if interfere {
+            let encoding = MiddlewareEncodingConfiguration::default().inner;
+            let reencoded = cached_response
+                .with_additional_encoding(&Encoding::Brotli.into(), &encoding)
+                .await
+                .expect("reencode");
+            self.0.put(key.clone(), Arc::new(reencoded)).await;
+        }
+
+        Some(cached_response)
+    }
+
+    async fn put(&self, key: CommonCacheKey, cached_response: CachedResponseRef) {
+        self.0.put(key, cached_response).await
+    }
+
+    async fn invalidate(&self, key: &CommonCacheKey) {
+        self.0.invalidate(key).await
+    }
+
+    async fn invalidate_all(&self) {
+        self.0.invalidate_all().await
+    }
+
+    fn keys(&self) -> Option<Vec<CommonCacheKey>> {
+        self.0.keys()
+    }
+}
+
+// Housekeeping applies pending evictions, after which a tiny cache is within its capacity and our
+// accessors agree with Moka's
+#[cfg(feature = "moka")]
+#[tokio::test]
+async fn moka_housekeep() {
+    let inner = moka::future::Cache::builder().max_capacity(2).build();
+    let cache = MokaCacheImplementation::new(inner);
+
+    for index in 0..8 {
+        cache.put(key(&format!("/{}", index)), entry("v1", None)).await;
+    }
+    cache.housekeep().await;
+
+    assert!(cache.len() <= 2, "entries: {}", cache.len());
+    assert_eq!(cache.len(), cache.inner.entry_count());
+    assert_eq!(cache.weight(), cache.inner.weighted_size());
+    assert_eq!(Cache::entry_count(&cache), Some(cache.len()));
+    assert_eq!(Cache::weighted_size(&cache), Some(cache.weight()));
+}
+
+// The housekeeper drains the pending tasks of a burst of inserts during idle time (so that no
+// later call has to), runs early when the insert threshold is reached, and stops on shutdown
+#[cfg(feature = "housekeeping")]
+#[tokio::test]
+async fn moka_housekeeper() {
+    // (name, configuration, expected triggered runs)
+    let scenarios = [
+        (
+            "interval",
+            HousekeepingConfig {
+                interval: Duration::from_millis(50),
+                insert_threshold: None,
+            },
+            0..=0,
+        ),
+        (
+            "threshold",
+            HousekeepingConfig {
+                interval: Duration::from_secs(60 * 60),
+                insert_threshold: Some(50),
+            },
+            1..=2,
+        ),
+    ];
+
+    for (name, configuration, expected_triggered_runs) in scenarios {
+        let inner = moka::future::Cache::builder().max_capacity(8).build();
+        let cache = MokaCacheImplementation::new_with_housekeeping(inner, Some(configuration));
+        let housekeeper = cache.housekeeper.clone().expect("housekeeper");
+
+        for index in 0..100 {
+            cache.put(key(&format!("/{}", index)), entry("v1", None)).await;
+        }
+
+        // Idle
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // Note that entry_count doesn't run pending tasks itself
+        assert!(cache.inner.entry_count() <= 8, "{}: entries: {}", name, cache.inner.entry_count());
+
+        let stats = housekeeper.stats();
+        assert!(stats.runs >= 1, "{}: runs", name);
+        assert!(
+            expected_triggered_runs.contains(&stats.triggered_runs),
+            "{}: triggered runs: {}",
+            name,
+            stats.triggered_runs
+        );
+        assert!(stats.max <= stats.total, "{}: durations", name);
+
+        tokio::time::timeout(Duration::from_millis(100), housekeeper.shutdown())
+            .await
+            .unwrap_or_else(|_| panic!("{}: shutdown", name));
+
+        let runs = housekeeper.stats().runs;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(housekeeper.stats().runs, runs, "{}: runs after shutdown", name);
+    }
+}
+
+// Durations near the maximum don't overflow with the grace period
+#[cfg(feature = "moka")]
+#[test]
+fn moka_expiry_overflow() {
+    let expiry = CachedResponseExpiry::new(Duration::from_secs(60));
+    let remaining = expiry.expire_after_create(
+        &key("/max"),
+        &entry("v1", Some(Duration::MAX)),
+        Instant::now(),
+    );
+    assert!(remaining.is_some_and(|remaining| remaining > Duration::from_secs(60)));
+}
+
+// The top keys of a Zipfian workload are its head, with counts within the SpaceSaving error
+// bounds, and the number of tracked keys stays fixed as the number of keys grows 100x
+#[test]
+fn entry_stats_top_keys() {
+    const K: usize = 20;
+    const ROUNDS: usize = 800;
+
+    let configuration = TopKConfig {
+        k: K,
+        decay: Duration::from_secs(60 * 60),
+    };
+
+    // Key i (from 1) is hit every i rounds, so its frequency is proportional to 1/i
+    let entry_stats = EntryStats::new(configuration);
+    let entries: Vec<_> = (0..1000).map(|_| entry("zipf", None)).collect();
+    for round in 0..ROUNDS {
+        for (index, cached_response) in entries.iter().enumerate() {
+            if round % (index + 1) == 0 {
+                entry_stats.record_hit(&format!("/zipf/{}", index + 1), cached_response, 10);
+            }
+        }
+    }
+
+    // Only every ENTRY_STATS_SAMPLE_EVERY-th hit is recorded
+    let recorded = |rank: usize| {
+        let hits = ROUNDS.div_ceil(rank) as u64;
+        hits - hits % ENTRY_STATS_SAMPLE_EVERY
+    };
+    let total: u64 = (1..=entries.len()).map(recorded).sum();
+
+    let top_keys = entry_stats.top_keys(K);
+    for rank in (1..=entries.len()).filter(|rank| recorded(*rank) > total / K as u64) {
+        let key = format!("/zipf/{}", rank);
+        let key_stats = top_keys.iter().find(|key_stats| key_stats.key == key);
+        let key_stats = key_stats.unwrap_or_else(|| panic!("{}: not in top keys", key));
+        assert!(
+            key_stats.hits - key_stats.error <= recorded(rank) && recorded(rank) <= key_stats.hits,
+            "{}: {} hits (error {}), recorded {}",
+            key,
+            key_stats.hits,
+            key_stats.error,
+            recorded(rank)
+        );
+    }
+    assert_eq!(top_keys[0].key, "/zipf/1", "hottest");
+    assert_eq!(top_keys[0].bytes_served, recorded(1) * 10, "hottest: bytes served");
+
+    for key_count in [1_000, 100_000] {
+        let entry_stats = EntryStats::new(configuration);
+        let cached_response = entry("many", None);
+        for index in 0..key_count {
+            for _ in 0..ENTRY_STATS_SAMPLE_EVERY {
+                entry_stats.record_hit(&index, &cached_response, 10);
+            }
+        }
+        assert_eq!(entry_stats.top_keys(usize::MAX).len(), K, "{} keys", key_count);
+    }
+}
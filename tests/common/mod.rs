@@ -0,0 +1,241 @@
+use {
+    http::{header::*, *},
+    http_body::*,
+    http_body_util::*,
+    kutil::{
+        std::{collections::*, immutable::*},
+        transcoding::*,
+    },
+    std::{
+        collections::VecDeque,
+        env, fmt, fs,
+        future::*,
+        io,
+        path::PathBuf,
+        pin::*,
+        process,
+        result::Result,
+        sync::*,
+        task::*,
+        time::*,
+    },
+    tower_http_response_cache::cache::*,
+};
+
+/// Key for a `GET` of a path.
+#[allow(unused)]
+pub fn key(path: &str) -> CommonCacheKey {
+    let uri = Uri::try_from(path).expect("URI");
+    CommonCacheKey::for_request(&Method::GET, &uri, &HeaderMap::default())
+}
+
+/// Entry identified by its ETag.
+#[allow(unused)]
+pub fn entry(version: &'static str, duration: Option<Duration>) -> CachedResponseRef {
+    let (mut parts, _) = Response::new(()).into_parts();
+    parts.headers.insert(ETAG, HeaderValue::from_static(version));
+
+    Arc::new(CachedResponse {
+        parts,
+        body: Default::default(),
+        duration,
+        created: SystemTime::now(),
+        upstream_age: Duration::ZERO,
+        original_coding: CodingId::IDENTITY,
+        validators_only: false,
+        no_transform: false,
+        dependencies: Default::default(),
+        hits: Default::default(),
+    })
+}
+
+/// Entry with the "synthetic" version and generated headers, representations, and dependencies.
+#[allow(unused)]
+pub fn synthetic_entry(
+    header_count: usize,
+    coding_count: usize,
+    dependency_count: usize,
+    body_size: usize,
+) -> CachedResponse {
+    let mut cached_response = Arc::unwrap_or_clone(entry("synthetic", None));
+
+    for index in 0..header_count {
+        let name = HeaderName::try_from(format!("x-synthetic-{}", index)).expect("header name");
+        let value = HeaderValue::try_from("v".repeat(index % 32 + 1)).expect("header value");
+        cached_response.parts.headers.insert(name, value);
+    }
+
+    let codings = [Encoding::Identity, Encoding::GZip, Encoding::Brotli];
+    let representations: FastHashMap<_, _> = codings
+        .into_iter()
+        .take(coding_count)
+        .enumerate()
+        .map(|(index, coding)| {
+            // Encoded representations are smaller
+            let bytes: Vec<_> = (0..body_size / (index + 1)).map(|byte| byte as u8).collect();
+            (CodingId::from(coding), ImmutableBytes::from(bytes))
+        })
+        .collect();
+    cached_response.body = CachedBody::new(representations);
+
+    cached_response.dependencies = (0..dependency_count)
+        .map(|index| Arc::from(format!("synthetic:{}", index)))
+        .collect();
+
+    cached_response
+}
+
+/// Version (ETag) of an entry.
+#[allow(unused)]
+pub fn version(cached_response: Option<CachedResponseRef>) -> Option<String> {
+    cached_response.and_then(|cached_response| {
+        cached_response.headers().get(ETAG).and_then(|etag| etag.to_str().ok()).map(String::from)
+    })
+}
+
+/// Assert the version of the entry for a path.
+#[allow(unused)]
+pub async fn assert_version<CacheT>(cache: &CacheT, path: &str, expected: Option<&str>)
+where
+    CacheT: Cache<CommonCacheKey>,
+{
+    assert_eq!(version(cache.get(&key(path)).await).as_deref(), expected, "{}", path);
+}
+
+/// Read a body to the end.
+#[allow(unused)]
+pub async fn body_bytes<BodyT>(body: BodyT) -> Vec<u8>
+where
+    BodyT: Body,
+    BodyT::Error: fmt::Debug,
+{
+    body.collect().await.expect("collect").to_bytes().to_vec()
+}
+
+/// Path of a directory under the system's temporary directory, unique to this process and name.
+///
+/// The directory is removed if it exists, but not created.
+#[allow(unused)]
+pub fn temporary_directory(name: &str) -> PathBuf {
+    let directory = env::temp_dir().join(format!(
+        "tower-http-response-cache-{}-{}",
+        process::id(),
+        name
+    ));
+    let _ = fs::remove_dir_all(&directory);
+    directory
+}
+
+//
+// FramesBody
+//
+
+/// Body of fixed frames.
+#[allow(unused)]
+pub struct FramesBody(pub VecDeque<Frame<ImmutableBytes>>);
+
+impl Body for FramesBody {
+    type Data = ImmutableBytes;
+    type Error = io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        _context: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Poll::Ready(self.0.pop_front().map(Ok))
+    }
+}
+
+impl From<ImmutableBytes> for FramesBody {
+    fn from(bytes: ImmutableBytes) -> Self {
+        Self([Frame::data(bytes)].into())
+    }
+}
+
+//
+// ValidatedUpstream
+//
+
+/// Upstream that always responds with the same body and validators.
+#[allow(unused)]
+#[derive(Clone)]
+pub struct ValidatedUpstream;
+
+#[allow(unused)]
+impl ValidatedUpstream {
+    /// ETag.
+    pub fn etag() -> HeaderValue {
+        HeaderValue::from_static("\"v1\"")
+    }
+}
+
+impl tower::Service<Request<()>> for ValidatedUpstream {
+    type Response = Response<FramesBody>;
+    type Error = io::Error;
+    type Future = Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _context: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _request: Request<()>) -> Self::Future {
+        let mut response = Response::new(FramesBody::from(ImmutableBytes::from(b"hello".to_vec())));
+        let headers = response.headers_mut();
+        headers.insert(ETAG, Self::etag());
+        headers.insert(
+            LAST_MODIFIED,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        ready(Ok(response))
+    }
+}
+
+//
+// MockCache
+//
+
+/// Cache that keeps its entries in a hash map and never expires them.
+#[allow(unused)]
+#[derive(Clone, Default)]
+pub struct MockCache {
+    entries: Arc<Mutex<FastHashMap<CommonCacheKey, CachedResponseRef>>>,
+}
+
+impl Cache for MockCache {
+    async fn get(&self, key: &CommonCacheKey) -> Option<CachedResponseRef> {
+        self.entries.lock().expect("lock").get(key).cloned()
+    }
+
+    async fn put(&self, key: CommonCacheKey, cached_response: CachedResponseRef) {
+        self.entries
+            .lock()
+            .expect("lock")
+            .insert(key, cached_response);
+    }
+
+    async fn invalidate(&self, key: &CommonCacheKey) {
+        self.entries.lock().expect("lock").remove(key);
+    }
+
+    async fn invalidate_all(&self) {
+        self.entries.lock().expect("lock").clear();
+    }
+
+    fn entry_count(&self) -> Option<u64> {
+        Some(self.entries.lock().expect("lock").len() as u64)
+    }
+
+    fn weighted_size(&self) -> Option<u64> {
+        let entries = self.entries.lock().expect("lock");
+        Some(
+            entries
+                .values()
+                .map(|cached_response| cached_response.cache_weight() as u64)
+                .sum(),
+        )
+    }
+
+    fn keys(&self) -> Option<Vec<CommonCacheKey>> {
+        Some(self.entries.lock().expect("lock").keys().cloned().collect())
+    }
+}
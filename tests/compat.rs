@@ -0,0 +1,7 @@
+// Code written against the previous version (including its examples) compiles with the compat
+// shims, with deprecation warnings at most
+#[test]
+fn compat() {
+    let cases = trybuild::TestCases::new();
+    cases.pass("tests/compat/*.rs");
+}
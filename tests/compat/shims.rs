@@ -0,0 +1,57 @@
+// Code written against the previous version's signatures
+
+// Only deprecation warnings are allowed
+#![deny(warnings)]
+#![allow(deprecated)]
+
+use {
+    http::*,
+    kutil::transcoding::*,
+    std::sync::*,
+    tokio::*,
+    tower_http_response_cache::{
+        cache::{implementation::lru::*, middleware::*, *},
+        compat::*,
+        *,
+    },
+};
+
+#[main]
+async fn main() {
+    let request = Request::get("/compat").body(()).expect("Request::get");
+    let uri = request.uri().clone();
+    let headers = HeaderMap::default();
+
+    let caching = MiddlewareCachingConfiguration::<(), (), CommonCacheKey>::default();
+    let mut cache_key: CommonCacheKey = cache_key_with_hook(&request, &caching);
+
+    let encoding: Encoding = select_encoding(&request, &MiddlewareEncodingConfiguration::default());
+    assert_eq!(encoding, Encoding::Identity);
+
+    let context = encodable_hook_context(&Encoding::GZip, &uri, &headers);
+    assert_eq!(context.coding, &CodingId::from(Encoding::GZip));
+
+    let context = cache_key_hook_context(&mut cache_key, &request);
+    assert_eq!(context.origin, &RequestOrigin::default());
+
+    let cache = SimpleLruCache::new(1024 * 1024, None);
+    let (parts, _) = Response::new(()).into_parts();
+    let cached_response = CachedResponse {
+        parts,
+        body: Default::default(),
+        duration: None,
+        created: std::time::SystemTime::now(),
+        upstream_age: Default::default(),
+        original_coding: CodingId::IDENTITY,
+        validators_only: false,
+        no_transform: false,
+        dependencies: Default::default(),
+        hits: Default::default(),
+    };
+    cache.put(cache_key.clone(), Arc::new(cached_response)).await;
+    assert!(cache.get(&cache_key).await.is_some());
+
+    let _layer = CachingLayer::<(), SimpleLruCache>::default()
+        .cache(cache)
+        .keep_identity_encoding(false);
+}
@@ -0,0 +1,121 @@
+mod common;
+
+use {
+    common::*,
+    std::{sync::*, time::*},
+    tower_http_response_cache::{
+        cache::{implementation::{byte_store::*, lru::*, moka::*}, *},
+        conformance::*,
+    },
+};
+
+#[cfg(feature = "file")]
+use tower_http_response_cache::cache::implementation::file::*;
+
+// The conformance suite against the bundled implementations, proving the suite itself
+
+fn moka() -> MokaCacheImplementation {
+    MokaCacheImplementation::new(
+        moka::future::Cache::<CommonCacheKey, _, _>::builder()
+            .for_http_response()
+            .max_capacity(1024 * 1024)
+            .time_to_live(Duration::from_secs(60))
+            .build(),
+    )
+}
+
+#[tokio::test]
+async fn moka_conformance() {
+    let capabilities = Capabilities {
+        expiry: true,
+        sizes: true,
+        matching: true,
+        ..Default::default()
+    };
+    run_conformance(moka, capabilities).await;
+}
+
+#[tokio::test]
+async fn fenced_moka_conformance() {
+    run_conformance(|| FencedCache::new(moka()), Capabilities::all()).await;
+}
+
+#[tokio::test]
+async fn tiered_moka_conformance() {
+    let capabilities = Capabilities {
+        expiry: true,
+        matching: true,
+        ..Default::default()
+    };
+    run_conformance(|| TieredCache::new(moka(), moka()), capabilities).await;
+}
+
+#[tokio::test]
+async fn lru_conformance() {
+    let capabilities = Capabilities {
+        expiry: true,
+        sizes: true,
+        matching: true,
+        ..Default::default()
+    };
+    run_conformance(|| SimpleLruCache::new(1024 * 1024, None), capabilities).await;
+}
+
+#[tokio::test]
+async fn byte_store_conformance() {
+    let capabilities = Capabilities {
+        expiry: true,
+        ..Default::default()
+    };
+    run_conformance(|| ByteStoreCache::new(HashMapByteStore::default()), capabilities).await;
+}
+
+#[tokio::test]
+async fn memoized_byte_store_conformance() {
+    let capabilities = Capabilities {
+        expiry: true,
+        ..Default::default()
+    };
+    run_conformance(
+        || ByteStoreCache::new(HashMapByteStore::default()).with_memo(1024 * 1024, None),
+        capabilities,
+    )
+    .await;
+}
+
+// Opening is asynchronous, so we open a directory for each scenario in advance
+#[cfg(feature = "file")]
+#[tokio::test]
+async fn file_conformance() {
+    let mut caches = Vec::default();
+    for index in 0..16 {
+        let directory = temporary_directory(&format!("conformance-{}", index));
+        let cache = FileCacheImplementation::open(directory, 1024 * 1024).await.expect("open");
+        caches.push(cache);
+    }
+    let caches = Mutex::new(caches);
+
+    let capabilities = Capabilities {
+        expiry: true,
+        sizes: true,
+        ..Default::default()
+    };
+    run_conformance(|| caches.lock().expect("lock").pop().expect("cache"), capabilities).await;
+}
+
+#[tokio::test]
+async fn mock_conformance() {
+    let capabilities = Capabilities {
+        sizes: true,
+        matching: true,
+        ..Default::default()
+    };
+    run_conformance(MockCache::default, capabilities).await;
+}
+
+#[tokio::test]
+async fn fenced_moka_key_migration() {
+    let failures = check_key_migration(|| FencedCache::new(moka())).await;
+    let failures: Vec<_> = failures.iter().map(ToString::to_string).collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
@@ -0,0 +1,86 @@
+// The examples are compiled as modules here (so their main functions are not run), and we test
+// the services they construct
+//
+// (Each example has its own `mod utils`)
+#![allow(clippy::duplicate_mod)]
+
+#[allow(dead_code)]
+#[path = "../examples/basic.rs"]
+mod basic;
+
+#[allow(dead_code)]
+#[path = "../examples/hyper_plain.rs"]
+mod hyper_plain;
+
+#[allow(dead_code)]
+#[path = "../examples/tower_only.rs"]
+mod tower_only;
+
+use {
+    http::{header::*, *},
+    http_body_util::BodyExt,
+    std::sync::{atomic::*, *},
+    tower::*,
+    tower_http_response_cache::cache::middleware::*,
+};
+
+// The basic example's router misses, then hits
+#[tokio::test]
+async fn basic_router() {
+    let router = basic::router();
+
+    for expected_status in [CacheStatus::Miss, CacheStatus::Hit] {
+        let request = Request::get("/").body(axum::body::Body::empty()).expect("Request::get");
+        let response = router.clone().oneshot(request).await.expect("oneshot");
+        assert_eq!(response.extensions().get(), Some(&expected_status));
+        let body = response.into_body().collect().await.expect("collect").to_bytes();
+        assert_eq!(body, "Hello, world!\n");
+    }
+}
+
+// The hyper_plain example's service misses, then hits, and never caches "/nevercache"
+#[tokio::test]
+async fn hyper_plain_service() {
+    let mut service = hyper_plain::service::<()>();
+
+    for (path, expected_status) in [
+        ("/long", CacheStatus::Miss),
+        ("/long", CacheStatus::Hit),
+        ("/nevercache", CacheStatus::Miss),
+        ("/nevercache", CacheStatus::Miss),
+    ] {
+        let request = Request::get(path).body(()).expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+        assert_eq!(response.extensions().get(), Some(&expected_status), "{}", path);
+        response.into_body().collect().await.expect("collect");
+    }
+}
+
+// The tower_only example's service misses, then hits (also when encoding from the cached entry),
+// calling upstream just once
+#[tokio::test]
+async fn tower_only_service() {
+    let upstream_calls = Arc::new(AtomicUsize::default());
+    let mut service = tower_only::service(upstream_calls.clone());
+
+    for (accept_encoding, expected_status, expected_encoding) in
+        [("", "MISS", None), ("", "HIT", None), ("gzip", "HIT", Some("gzip"))]
+    {
+        let request = Request::get("/")
+            .header(ACCEPT_ENCODING, accept_encoding)
+            .body(())
+            .expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+        let headers = response.headers();
+        assert_eq!(headers.get("x-cache").expect("X-Cache"), expected_status, "{:?}", accept_encoding);
+        assert_eq!(
+            headers.get(CONTENT_ENCODING).map(|value| value.to_str().expect("to_str")),
+            expected_encoding,
+            "{:?}",
+            accept_encoding
+        );
+        response.into_body().collect().await.expect("collect");
+    }
+
+    assert_eq!(upstream_calls.load(Ordering::Relaxed), 1, "upstream calls");
+}
@@ -0,0 +1,5630 @@
+mod common;
+
+use {
+    common::*,
+    http::{header::*, uri::Scheme, *},
+    http_body::*,
+    http_body_util::BodyExt,
+    kutil::{
+        http::{EncodingHeaderValue, HeaderValues, Language, XX_CACHE, XX_CACHE_DURATION},
+        std::immutable::*,
+        transcoding::{Encoding, transcode::*},
+    },
+    std::{fmt, future::*, io, mem, net::SocketAddr, pin::*, sync::*, task, thread, time::*, vec},
+    tower::*,
+    tower_http_response_cache::{
+        cache::{implementation::lru::*, middleware::*, *},
+        *,
+    },
+};
+
+// A 304 Not Modified served from an entry has the Last-Modified and ETag that the 200 OK had, and
+// no content headers
+#[tokio::test]
+async fn not_modified_headers() {
+    let layer =
+        CachingLayer::<(), SimpleLruCache>::default().cache(SimpleLruCache::new(1024 * 1024, None));
+    let mut service = layer.layer(ValidatedUpstream);
+
+    let request = || {
+        Request::get("/not-modified")
+            .body(())
+            .expect("Request::get")
+    };
+
+    let ok = service.oneshot_ready(request()).await.expect("200");
+
+    let mut conditional = request();
+    conditional
+        .headers_mut()
+        .insert(IF_NONE_MATCH, ValidatedUpstream::etag());
+    let not_modified = service.oneshot_ready(conditional).await.expect("304");
+
+    assert_eq!(not_modified.status(), StatusCode::NOT_MODIFIED);
+
+    for name in [LAST_MODIFIED, ETAG] {
+        let expected = ok.headers().get(&name);
+        assert!(expected.is_some(), "{}", name);
+        assert_eq!(not_modified.headers().get(&name), expected, "{}", name);
+    }
+
+    for name in [CONTENT_LENGTH, CONTENT_ENCODING] {
+        assert!(!not_modified.headers().contains_key(&name), "has {}", name);
+    }
+}
+
+// If-None-Match matches by weak comparison, with any tag or *, and takes precedence over
+// If-Modified-Since, and its 304 echoes the ETag
+#[tokio::test]
+async fn if_none_match() {
+    let layer =
+        CachingLayer::<(), SimpleLruCache>::default().cache(SimpleLruCache::new(1024 * 1024, None));
+    let mut service = layer.layer(ValidatedUpstream);
+
+    let request = Request::get("/conditional").body(()).expect("Request::get");
+    service.oneshot_ready(request).await.expect("oneshot_ready");
+
+    // Last-Modified is Wed, 21 Oct 2015 07:28:00 GMT
+    let later = Some("Thu, 22 Oct 2015 07:28:00 GMT");
+    let earlier = Some("Tue, 20 Oct 2015 07:28:00 GMT");
+
+    // (If-None-Match, If-Modified-Since, status)
+    let cases = [
+        (Some("\"v1\""), None, StatusCode::NOT_MODIFIED),
+        (Some("W/\"v1\""), None, StatusCode::NOT_MODIFIED),
+        (Some("\"v0\", W/\"v1\""), None, StatusCode::NOT_MODIFIED),
+        (Some("*"), None, StatusCode::NOT_MODIFIED),
+        (Some("\"v2\""), None, StatusCode::OK),
+        (Some("\"v2\""), later, StatusCode::OK),
+        (Some("\"v1\""), earlier, StatusCode::NOT_MODIFIED),
+        (None, later, StatusCode::NOT_MODIFIED),
+        (None, earlier, StatusCode::OK),
+    ];
+
+    for (if_none_match, if_modified_since, expected) in cases {
+        let mut request = Request::get("/conditional");
+        if let Some(if_none_match) = if_none_match {
+            request = request.header(IF_NONE_MATCH, if_none_match);
+        }
+        if let Some(if_modified_since) = if_modified_since {
+            request = request.header(IF_MODIFIED_SINCE, if_modified_since);
+        }
+        let request = request.body(()).expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+
+        assert_eq!(response.status(), expected, "{:?} {:?}", if_none_match, if_modified_since);
+        let status = response.extensions().get::<CacheStatus>().map(CacheStatus::as_str);
+        assert_eq!(status, Some("HIT"), "{:?} {:?}", if_none_match, if_modified_since);
+        assert_eq!(response.headers().get(ETAG), Some(&ValidatedUpstream::etag()));
+    }
+}
+
+// Each coding served from an entry gets its own suffixed ETag, which revalidates, and keeps the
+// Repr-Digest
+#[tokio::test]
+async fn representation_validators() {
+    let upstream = service_fn(|_request: Request<()>| async move {
+        let mut response = Response::new(FramesBody::from(ImmutableBytes::from(b"hello".to_vec())));
+        let headers = response.headers_mut();
+        headers.insert(ETAG, ValidatedUpstream::etag());
+        headers.insert(REPR_DIGEST, HeaderValue::from_static("sha-256=:abc=:"));
+        headers.insert("content-digest", HeaderValue::from_static("sha-256=:abc=:"));
+        Ok::<_, io::Error>(response)
+    });
+    let mut service = CachingLayer::<(), SimpleLruCache>::default()
+        .cache(SimpleLruCache::new(1024 * 1024, None))
+        .layer(upstream);
+
+    let request = |encoding: &'static str| {
+        Request::get("/representations")
+            .header(ACCEPT_ENCODING, encoding)
+            .body(())
+            .expect("Request::get")
+    };
+
+    let response = service.oneshot_ready(request("identity")).await.expect("identity");
+    assert_eq!(response.headers().get(ETAG), Some(&ValidatedUpstream::etag()));
+
+    for (encoding, etag) in [("gzip", "\"v1-gzip\""), ("br", "\"v1-br\"")] {
+        let response = service.oneshot_ready(request(encoding)).await.expect(encoding);
+        let headers = response.headers();
+        assert_eq!(response.extensions().get::<CacheStatus>(), Some(&CacheStatus::Hit));
+        assert_eq!(headers.get(CONTENT_ENCODING), Some(&HeaderValue::from_static(encoding)));
+        assert_eq!(headers.get(ETAG), Some(&HeaderValue::from_static(etag)), "{}", encoding);
+        assert!(headers.contains_key(REPR_DIGEST), "{}: no Repr-Digest", encoding);
+        assert!(!headers.contains_key("content-digest"), "{}: Content-Digest", encoding);
+
+        let mut conditional = request(encoding);
+        conditional.headers_mut().insert(IF_NONE_MATCH, HeaderValue::from_static(etag));
+        let response = service.oneshot_ready(conditional).await.expect(encoding);
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED, "{}", encoding);
+        assert_eq!(response.headers().get(ETAG), Some(&HeaderValue::from_static(etag)));
+    }
+}
+
+// Encoding on the fly (without a cache) transforms the ETag per the policy, drops the
+// Content-Digest, and keeps the Repr-Digest
+#[tokio::test]
+async fn on_the_fly_validators() {
+    let upstream = service_fn(|_request: Request<()>| async move {
+        let mut response = Response::new(FramesBody::from(ImmutableBytes::from(b"hello".to_vec())));
+        let headers = response.headers_mut();
+        headers.insert(ETAG, ValidatedUpstream::etag());
+        headers.insert(REPR_DIGEST, HeaderValue::from_static("sha-256=:abc=:"));
+        headers.insert("content-digest", HeaderValue::from_static("sha-256=:abc=:"));
+        Ok::<_, io::Error>(response)
+    });
+
+    let policies = [
+        (OnTheFlyValidatorPolicy::Suffix, Some("\"v1-gzip\"")),
+        (OnTheFlyValidatorPolicy::Weaken, Some("W/\"v1\"")),
+        (OnTheFlyValidatorPolicy::Strip, None),
+    ];
+
+    for (policy, expected_etag) in policies {
+        let mut service = CachingLayer::<(), SimpleLruCache>::default()
+            .on_the_fly_validator_policy(policy)
+            .layer(upstream);
+
+        let request = Request::get("/on-the-fly")
+            .header(ACCEPT_ENCODING, "gzip")
+            .body(())
+            .expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+        let headers = response.headers();
+
+        assert_eq!(headers.get(CONTENT_ENCODING), Some(&HeaderValue::from_static("gzip")));
+        assert_eq!(
+            headers.get(ETAG),
+            expected_etag.map(HeaderValue::from_static).as_ref(),
+            "{:?}",
+            policy
+        );
+        assert!(headers.contains_key(REPR_DIGEST), "{:?}: no Repr-Digest", policy);
+        assert!(!headers.contains_key("content-digest"), "{:?}: Content-Digest", policy);
+    }
+}
+
+// Trivial custom coding
+struct Rot13;
+
+impl Rot13 {
+    fn rotate(bytes: &[u8]) -> Vec<u8> {
+        bytes
+            .iter()
+            .map(|byte| match byte {
+                b'a'..=b'z' => (byte - b'a' + 13) % 26 + b'a',
+                b'A'..=b'Z' => (byte - b'A' + 13) % 26 + b'A',
+                _ => *byte,
+            })
+            .collect()
+    }
+}
+
+impl Transcoder for Rot13 {
+    fn encode(&self, identity_bytes: &ImmutableBytes) -> io::Result<ImmutableBytes> {
+        Ok(Self::rotate(identity_bytes.as_ref()).into())
+    }
+
+    fn decode(&self, bytes: &ImmutableBytes) -> io::Result<ImmutableBytes> {
+        Ok(Self::rotate(bytes.as_ref()).into())
+    }
+}
+
+// A custom coding is stored, served from hits, and reencoded to and from the built-in codings
+#[tokio::test]
+async fn custom_coding() {
+    let cache = SimpleLruCache::new(1024 * 1024, None);
+    let mut service = CachingLayer::<(), SimpleLruCache>::default()
+        .cache(cache.clone())
+        .enable_custom_coding("rot13", Rot13)
+        .layer(ValidatedUpstream);
+
+    let request = |encoding: &'static str| {
+        Request::get("/custom-coding")
+            .header(ACCEPT_ENCODING, encoding)
+            .body(())
+            .expect("Request::get")
+    };
+
+    let steps = [
+        ("rot13", "MISS", Some("rot13")),
+        ("rot13", "HIT", Some("rot13")),
+        ("identity", "HIT", None),
+        ("gzip", "HIT", Some("gzip")),
+        ("rot13", "HIT", Some("rot13")),
+    ];
+
+    let mut transcoders = TranscoderRegistry::default();
+    transcoders.register("rot13", Arc::new(Rot13));
+
+    for (encoding, expected_status, expected_coding) in steps {
+        let response = service.oneshot_ready(request(encoding)).await.expect(encoding);
+        let status = response.extensions().get::<CacheStatus>().map(CacheStatus::as_str);
+        assert_eq!(status, Some(expected_status), "{}", encoding);
+
+        let coding = response.headers().get(CONTENT_ENCODING).map(|coding| {
+            coding.to_str().expect("Content-Encoding").to_string()
+        });
+        assert_eq!(coding.as_deref(), expected_coding, "{}", encoding);
+
+        let coding = match expected_coding {
+            Some("rot13") => CodingId::Custom("rot13"),
+            Some(_) => Encoding::GZip.into(),
+            None => CodingId::IDENTITY,
+        };
+        let body = ImmutableBytes::from(body_bytes(response.into_body()).await);
+        let body = coding.decode(&body, &transcoders).await.expect("decode");
+        assert_eq!(body.as_ref(), b"hello", "{}", encoding);
+    }
+
+    assert_eq!(cache.len(), 1, "entries");
+}
+
+// Custom coding whose encoder corrupts the bytes, counting its calls
+struct Corrupting(Arc<atomic::AtomicUsize>);
+
+impl Transcoder for Corrupting {
+    fn encode(&self, identity_bytes: &ImmutableBytes) -> io::Result<ImmutableBytes> {
+        self.0.fetch_add(1, atomic::Ordering::SeqCst);
+        Ok(identity_bytes.as_ref().iter().rev().copied().collect::<Vec<_>>().into())
+    }
+
+    fn decode(&self, bytes: &ImmutableBytes) -> io::Result<ImmutableBytes> {
+        Ok(bytes.clone())
+    }
+}
+
+// With transcode verification, a corrupt representation is never served (we fall back to the
+// source) and the failed encode is quarantined, while without it the corruption reaches the client
+#[tokio::test]
+async fn transcode_verification() {
+    for verify_transcodes in [true, false] {
+        let encodes = Arc::new(atomic::AtomicUsize::default());
+        let mut service = CachingLayer::<(), SimpleLruCache>::default()
+            .cache(SimpleLruCache::new(1024 * 1024, None))
+            .enable_custom_coding("corrupt", Corrupting(encodes.clone()))
+            .verify_transcodes(verify_transcodes)
+            .layer(ValidatedUpstream);
+
+        for _ in 0..3 {
+            let request = Request::get("/verification")
+                .header(ACCEPT_ENCODING, "corrupt")
+                .body(())
+                .expect("Request::get");
+            let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+            let coding = response.headers().get(CONTENT_ENCODING).cloned();
+            let body = body_bytes(response.into_body()).await;
+
+            if verify_transcodes {
+                assert_eq!(coding, None, "verified: Content-Encoding");
+                assert_eq!(body, b"hello", "verified: body");
+            } else {
+                assert_eq!(coding, Some(HeaderValue::from_static("corrupt")), "unverified");
+                assert_ne!(body, b"hello", "unverified: body");
+            }
+        }
+
+        assert_eq!(
+            encodes.load(atomic::Ordering::SeqCst),
+            1,
+            "verify_transcodes={}: encodes",
+            verify_transcodes
+        );
+    }
+}
+
+// A no-transform response is stored as the upstream encoded it and served byte-exact to every
+// client, even one that does not accept its coding, and is never encoded; a no-transform request
+// likewise gets the upstream's coding; unless we are told not to respect the directive
+#[tokio::test]
+async fn no_transform() {
+    let gzip = ImmutableBytes::from(b"hello".to_vec()).encode(&Encoding::GZip).await.expect("gzip");
+    let upstream = {
+        let gzip = gzip.clone();
+        service_fn(move |_request: Request<()>| {
+            let mut response = Response::new(FramesBody::from(gzip.clone()));
+            let headers = response.headers_mut();
+            headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+            headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=60, no-transform"));
+            ready(Ok::<_, io::Error>(response))
+        })
+    };
+
+    for respect_no_transform in [true, false] {
+        let encodes = Arc::new(atomic::AtomicUsize::default());
+        let cache = MockCache::default();
+        let mut service = CachingLayer::<(), MockCache>::default()
+            .cache(cache.clone())
+            .enable_custom_coding("corrupt", Corrupting(encodes.clone()))
+            .respect_no_transform(respect_no_transform)
+            .layer(upstream.clone());
+
+        for encoding in ["identity", "gzip", "corrupt", "identity"] {
+            let request = Request::get("/no-transform")
+                .header(ACCEPT_ENCODING, encoding)
+                .body(())
+                .expect("Request::get");
+            let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+            let coding = response.headers().get(CONTENT_ENCODING).cloned();
+            let body = body_bytes(response.into_body()).await;
+
+            if respect_no_transform {
+                assert_eq!(coding, Some(HeaderValue::from_static("gzip")), "{}", encoding);
+                assert_eq!(body, gzip.as_ref(), "{}: body", encoding);
+            } else if encoding == "identity" {
+                assert_eq!(coding, None, "not respected: {}", encoding);
+                assert_eq!(body, b"hello", "not respected: {}: body", encoding);
+            }
+        }
+
+        if respect_no_transform {
+            let cached_response = cache.get(&key("/no-transform")).await.expect("stored");
+            assert!(cached_response.no_transform, "flag");
+            let codings: Vec<_> = cached_response.body.representations.keys().cloned().collect();
+            assert_eq!(codings, [CodingId::from(Encoding::GZip)], "representations");
+            assert_eq!(encodes.load(atomic::Ordering::SeqCst), 0, "encodes");
+        }
+    }
+
+    // Request
+    let mut service = CachingLayer::<(), SimpleLruCache>::default()
+        .cache(SimpleLruCache::new(1024 * 1024, None))
+        .layer(ValidatedUpstream);
+
+    let request = Request::get("/no-transform-request")
+        .header(ACCEPT_ENCODING, "gzip")
+        .header(CACHE_CONTROL, "no-transform")
+        .body(())
+        .expect("Request::get");
+    let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+    assert_eq!(response.headers().get(CONTENT_ENCODING), None, "request: Content-Encoding");
+    assert_eq!(body_bytes(response.into_body()).await, b"hello", "request: body");
+}
+
+// After a rolling change disables GZip, a stored GZip representation is never served, counts as
+// skew, and is pruned when the entry is next modified (if pruning is on)
+#[cfg(all(feature = "gzip", feature = "brotli"))]
+#[tokio::test]
+async fn disabled_representations() {
+    let gzip = CodingId::from(Encoding::GZip);
+    let brotli = CodingId::from(Encoding::Brotli);
+
+    for prune in [true, false] {
+        let cache = MockCache::default();
+        let layer = |enabled_encodings| {
+            CachingLayer::<(), MockCache>::default()
+                .cache(cache.clone())
+                .enable_encodings(enabled_encodings)
+                .prune_disabled_representations(prune)
+                .layer(ValidatedUpstream)
+        };
+        let mut before = layer(vec![EncodingHeaderValue::GZip]);
+        let mut after = layer(vec![EncodingHeaderValue::Brotli]);
+
+        let request = |encoding| {
+            Request::get("/disabled")
+                .header(ACCEPT_ENCODING, encoding)
+                .body(())
+                .expect("Request::get")
+        };
+        let has = async |coding| {
+            let cached_response = cache.get(&key("/disabled")).await.expect("stored");
+            cached_response.body.representations.contains_key(coding)
+        };
+
+        before.oneshot_ready(request("gzip")).await.expect("before");
+        assert!(has(&gzip).await, "prune={}: stored", prune);
+
+        let skew = after.representation_skew().await.expect("skew");
+        assert_eq!((skew.entries, skew.skewed_entries), (1, 1), "prune={}: {}", prune, skew);
+        assert_eq!(skew.disabled.get(&gzip), Some(&1), "prune={}: {}", prune, skew);
+        assert_eq!(skew.missing.get(&brotli), Some(&1), "prune={}: {}", prune, skew);
+
+        let response = after.oneshot_ready(request("gzip")).await.expect("after");
+        assert_eq!(response.extensions().get::<CacheStatus>(), Some(&CacheStatus::Hit));
+        assert_eq!(response.headers().get(CONTENT_ENCODING), None, "prune={}", prune);
+        assert_eq!(body_bytes(response.into_body()).await, b"hello", "prune={}", prune);
+
+        // Modified by reencoding
+        let response = after.oneshot_ready(request("br")).await.expect("after: br");
+        assert_eq!(response.headers().get(CONTENT_ENCODING), Some(&HeaderValue::from_static("br")));
+        assert!(has(&brotli).await, "prune={}: reencoded", prune);
+        assert_eq!(has(&gzip).await, !prune, "prune={}: pruned", prune);
+
+        let skew = after.representation_skew().await.expect("skew");
+        assert_eq!(skew.skewed_entries, if prune { 0 } else { 1 }, "prune={}: {}", prune, skew);
+        assert_eq!(skew.missing.get(&brotli), None, "prune={}: {}", prune, skew);
+    }
+}
+
+// A corpus of real-world Accept-Encoding values pins their normalization, the anomaly classes
+// counted for them, and the coding served, which is the same for repeated requests
+#[cfg(feature = "gzip")]
+#[tokio::test]
+async fn accept_encoding_corpus() {
+    use AcceptEncodingAnomaly::*;
+
+    // (Accept-Encoding, expected normalized value, expected anomalies, expected GZip)
+    let corpus: [(&str, &str, &[AcceptEncodingAnomaly], bool); 27] = [
+        ("gzip", "gzip", &[], true),
+        ("GZIP", "gzip", &[], true),
+        ("gzip, deflate", "gzip, deflate", &[], true),
+        ("gzip, deflate, br", "gzip, deflate, br", &[], true),
+        ("gzip,deflate,sdch", "gzip, deflate, sdch", &[], true),
+        ("compress, gzip", "compress, gzip", &[], true),
+        ("br", "br", &[], false),
+        ("identity", "identity", &[], false),
+        ("", "", &[Empty], false),
+        ("   ", "", &[Empty], false),
+        ("gzip;q=", "", &[InvalidWeight], false),
+        ("gzip;q=2", "", &[InvalidWeight], false),
+        ("gzip;q=abc", "", &[InvalidWeight], false),
+        ("gzip;q=.5", "", &[InvalidWeight], false),
+        ("gzip;q=0.1234", "", &[InvalidWeight], false),
+        ("gzip;q=, br", "br", &[InvalidWeight], false),
+        (";q=1", "", &[EmptyMember], false),
+        ("gzip, , br", "gzip, br", &[EmptyMember], true),
+        ("gzip deflate", "", &[InvalidCoding], false),
+        ("gzip, gzip", "gzip", &[Duplicate], true),
+        ("gzip;q=0.5, GZip;q=1", "gzip", &[Duplicate], true),
+        ("gzip;q=1, gzip;q=0.2", "gzip", &[Duplicate], true),
+        ("gzip;q=0", "gzip;q=0.000", &[], false),
+        ("gzip;q=1.000", "gzip", &[], true),
+        ("gzip ; q=0.5", "gzip;q=0.500", &[], true),
+        ("gzip;level=9", "gzip", &[], true),
+        ("gzip, deflate, br, zstd, compress", "", &[TooManyMembers], false),
+    ];
+
+    let anomalies = AcceptEncodingAnomalyCounter::default();
+    let mut service = CachingLayer::<(), SimpleLruCache>::default()
+        .cache(SimpleLruCache::new(1024 * 1024, None))
+        .enable_encodings(vec![EncodingHeaderValue::GZip])
+        .max_accept_encoding_members(4)
+        .accept_encoding_anomalies(anomalies.clone())
+        .layer(ValidatedUpstream);
+
+    for (value, expected_normalized, expected_anomalies, expected_gzip) in corpus {
+        let mut headers = HeaderMap::default();
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(value));
+        let accept_encoding = AcceptEncoding::from_headers(&headers, 4).expect("Accept-Encoding");
+        assert_eq!(accept_encoding.to_header_value(), expected_normalized, "{:?}", value);
+        assert_eq!(accept_encoding.anomalies, expected_anomalies, "{:?}: anomalies", value);
+        assert_eq!(
+            accept_encoding.identity_only,
+            expected_normalized.is_empty(),
+            "{:?}: identity only",
+            value
+        );
+
+        for _ in 0..2 {
+            let request = Request::get("/corpus")
+                .header(ACCEPT_ENCODING, value)
+                .body(())
+                .expect("Request::get");
+            let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+            let coding = response.headers().get(CONTENT_ENCODING);
+            assert_eq!(coding.is_some_and(|coding| coding == "gzip"), expected_gzip, "{:?}", value);
+        }
+    }
+
+    for anomaly in AcceptEncodingAnomaly::ALL {
+        let expected = corpus.iter().filter(|(_, _, anomalies, _)| anomalies.contains(&anomaly));
+        assert_eq!(anomalies.count(anomaly), 2 * expected.count() as u64, "{}", anomaly);
+    }
+}
+
+// Always selects GZip
+struct GZipNegotiator;
+
+impl EncodingNegotiator for GZipNegotiator {
+    fn negotiate(
+        &self,
+        _accept_encoding: &[&str],
+        _custom: &[&'static str],
+        _builtin: &[EncodingHeaderValue],
+    ) -> Option<CodingId> {
+        Some(Encoding::GZip.into())
+    }
+}
+
+// A custom negotiator's choice is served, with its Content-Encoding, on both a miss and a hit
+#[tokio::test]
+async fn custom_negotiator() {
+    let mut service = CachingLayer::<(), SimpleLruCache>::default()
+        .cache(SimpleLruCache::new(1024 * 1024, None))
+        .negotiator(GZipNegotiator)
+        .layer(ValidatedUpstream);
+
+    for expected_status in ["MISS", "HIT"] {
+        let request = Request::get("/negotiator")
+            .header(ACCEPT_ENCODING, "br")
+            .body(())
+            .expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect(expected_status);
+        let status = response.extensions().get::<CacheStatus>().map(CacheStatus::as_str);
+        assert_eq!(status, Some(expected_status));
+        assert_eq!(
+            response.headers().get(CONTENT_ENCODING),
+            Some(&HeaderValue::from_static("gzip")),
+            "{}",
+            expected_status
+        );
+    }
+}
+
+// In a GZip-only build, Brotli requests are served Identity (even if Brotli is explicitly enabled,
+// in which case it wins the negotiation but degrades), while GZip is still negotiated
+#[cfg(all(feature = "gzip", not(feature = "brotli")))]
+#[tokio::test]
+async fn gzip_only_build() {
+    let brotli_enabled = || vec![EncodingHeaderValue::Brotli, EncodingHeaderValue::GZip];
+
+    // (enabled encodings, Accept-Encoding, expected Content-Encoding)
+    let cases = [
+        (None, "br", None),
+        (None, "br, gzip;q=0.5", Some("gzip")),
+        (Some(brotli_enabled()), "br", None),
+        (Some(brotli_enabled()), "br, gzip;q=0.5", None),
+        (Some(brotli_enabled()), "gzip", Some("gzip")),
+    ];
+
+    for (enabled_encodings, accept_encoding, expected_coding) in cases {
+        let mut layer = CachingLayer::<(), SimpleLruCache>::default()
+            .cache(SimpleLruCache::new(1024 * 1024, None));
+        if let Some(enabled_encodings) = enabled_encodings.clone() {
+            layer = layer.enable_encodings(enabled_encodings);
+        }
+        let mut service = layer.layer(ValidatedUpstream);
+
+        let request = Request::get("/gzip-only")
+            .header(ACCEPT_ENCODING, accept_encoding)
+            .body(())
+            .expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect(accept_encoding);
+        assert_eq!(
+            response.headers().get(CONTENT_ENCODING),
+            expected_coding.map(HeaderValue::from_static).as_ref(),
+            "{:?}: {}",
+            enabled_encodings,
+            accept_encoding
+        );
+
+        let body = body_bytes(response.into_body()).await;
+        if expected_coding.is_none() {
+            assert_eq!(body, b"hello", "{:?}: {}", enabled_encodings, accept_encoding);
+        }
+    }
+}
+
+// The first client gets the original or the transformed body according to the policy, subsequent
+// clients get the stored transformed body with a weakened ETag, and transforms that fail or exceed
+// the size limits store the original
+#[tokio::test]
+async fn transform_before_store() {
+    let hello = || HeaderValue::from_static("\"v1\"");
+    let transformed = || HeaderValue::from_static("W/\"v1\"");
+
+    // (path, policy, expected first body, expected second body, expected second ETag)
+    let cases = [
+        ("/upper", TransformPolicy::OriginalFirst, "hello", "HELLO", transformed()),
+        ("/upper", TransformPolicy::TransformedToo, "HELLO", "HELLO", transformed()),
+        ("/failing", TransformPolicy::TransformedToo, "hello", "hello", hello()),
+        ("/large", TransformPolicy::TransformedToo, "hello", "hello", hello()),
+    ];
+
+    for (path, policy, expected_first, expected_second, expected_etag) in cases {
+        let cache = SimpleLruCache::new(1024 * 1024, None);
+        let mut service = CachingLayer::<(), SimpleLruCache>::default()
+            .cache(cache.clone())
+            .max_cacheable_body_size(8)
+            .transform_before_store(|context| match context.uri.path() {
+                "/failing" => Err("failing".into()),
+                "/large" => Ok(Some(TransformedBody::new(vec![b'x'; 16].into()))),
+                _ => Ok(Some(TransformedBody::new(
+                    context.bytes.as_ref().to_ascii_uppercase().into(),
+                ))),
+            })
+            .transform_policy(policy)
+            .layer(ValidatedUpstream);
+
+        for (second, expected_body) in [(false, expected_first), (true, expected_second)] {
+            let request = Request::get(path).body(()).expect("Request::get");
+            let response = service.oneshot_ready(request).await.expect(path);
+            if second {
+                let etag = response.headers().get(ETAG);
+                assert_eq!(etag, Some(&expected_etag), "{} {:?}: ETag", path, policy);
+            }
+            let body = body_bytes(response.into_body()).await;
+            assert_eq!(body, expected_body.as_bytes(), "{} {:?}", path, policy);
+        }
+
+        let cached_response = cache.get(&key(path)).await.expect(path);
+        assert_eq!(
+            cached_response.headers().get(ETAG),
+            Some(&expected_etag),
+            "{} {:?}: stored ETag",
+            path,
+            policy
+        );
+    }
+}
+
+// Upstream trailers are delivered to the client whose request stored the response and passed to
+// the hook, but are not stored, so hits have none
+#[tokio::test]
+async fn storing_trailers() {
+    let mut trailers = HeaderMap::default();
+    trailers.insert("server-timing", HeaderValue::from_static("render;dur=12"));
+    trailers.insert("x-render-stats", HeaderValue::from_static("queries=3"));
+
+    let upstream = {
+        let trailers = trailers.clone();
+        service_fn(move |_request: Request<()>| {
+            let body = FramesBody(
+                [
+                    Frame::data(ImmutableBytes::from(b"hello".to_vec())),
+                    Frame::trailers(trailers.clone()),
+                ]
+                .into(),
+            );
+            ready(Ok::<_, io::Error>(Response::new(body)))
+        })
+    };
+
+    let hooked = Arc::new(Mutex::new(Vec::default()));
+    let mut service = {
+        let hooked = hooked.clone();
+        CachingLayer::<(), MockCache>::default()
+            .cache(MockCache::default())
+            .on_trailers(move |context| {
+                let hooked_trailers = (context.uri.path().to_string(), context.trailers.clone());
+                hooked.lock().expect("lock").push(hooked_trailers);
+            })
+            .layer(upstream)
+    };
+
+    for (expected_status, expected_trailers) in [("MISS", Some(&trailers)), ("HIT", None)] {
+        let request = Request::get("/trailers").body(()).expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect(expected_status);
+        let status = response.extensions().get::<CacheStatus>().map(CacheStatus::as_str);
+        assert_eq!(status, Some(expected_status));
+
+        let collected = response.into_body().collect().await.expect("collect");
+        assert_eq!(collected.trailers(), expected_trailers, "{}", expected_status);
+        assert_eq!(collected.to_bytes().as_ref(), b"hello", "{}", expected_status);
+    }
+
+    let hooked = hooked.lock().expect("lock");
+    assert_eq!(*hooked, [("/trailers".to_string(), trailers)], "hooked");
+}
+
+// HEAD requests are answered from entries cached by GET, with the same headers and no body, and
+// HEAD misses are not stored
+#[tokio::test]
+async fn head_from_get() {
+    let cache = SimpleLruCache::new(1024 * 1024, None);
+    let layer = CachingLayer::<(), SimpleLruCache>::default().cache(cache.clone());
+    let mut service = layer.layer(ValidatedUpstream);
+
+    let request = |method| {
+        Request::builder().method(method).uri("/head").body(()).expect("Request::builder")
+    };
+
+    let status = |response: &Response<_>| response.extensions().get::<CacheStatus>().copied();
+
+    // Miss
+    let response = service.oneshot_ready(request(Method::HEAD)).await.expect("HEAD miss");
+    assert!(
+        !status(&response).is_some_and(|status| status.is_hit()),
+        "HEAD on empty cache: hit"
+    );
+    assert!(cache.is_empty(), "HEAD miss: stored");
+
+    let get = service.oneshot_ready(request(Method::GET)).await.expect("GET");
+
+    // Hit
+    let head = service.oneshot_ready(request(Method::HEAD)).await.expect("HEAD hit");
+    assert_eq!(status(&head), Some(CacheStatus::Hit));
+
+    for name in [CONTENT_LENGTH, ETAG, LAST_MODIFIED] {
+        let expected = get.headers().get(&name);
+        assert!(expected.is_some(), "{}", name);
+        assert_eq!(head.headers().get(&name), expected, "{}", name);
+    }
+
+    let mut body = pin!(head.into_body());
+    let mut size = 0;
+    while let Some(Ok(frame)) = poll_fn(|context| body.as_mut().poll_frame(context)).await {
+        size += frame.data_ref().map(|bytes| bytes.len()).unwrap_or_default();
+    }
+    assert_eq!(size, 0, "HEAD hit: body bytes");
+
+    // Conditional hit
+    let mut conditional = request(Method::HEAD);
+    conditional.headers_mut().insert(IF_NONE_MATCH, ValidatedUpstream::etag());
+    let response = service.oneshot_ready(conditional).await.expect("conditional");
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+}
+
+// Complementary upstream ranges are assembled into an entry that serves full requests, while a
+// validator change mid-assembly abandons it and the bound on in-flight assemblies refuses more
+#[cfg(feature = "range-assembly")]
+#[tokio::test]
+async fn range_assembly() {
+    let version = Arc::new(Mutex::new(("\"v1\"", "0123456789")));
+    let full_calls = Arc::new(atomic::AtomicUsize::default());
+    let upstream = {
+        let (version, full_calls) = (version.clone(), full_calls.clone());
+        service_fn(move |request: Request<()>| {
+            let (etag, content) = *version.lock().expect("lock");
+            let range = request.headers().get(RANGE).map(|range| {
+                let range = range.to_str().expect("Range").trim_start_matches("bytes=");
+                let (start, end) = range.split_once('-').expect("range");
+                (start.parse::<usize>().expect("start"), end.parse::<usize>().expect("end"))
+            });
+
+            let mut response = match range {
+                Some((start, end)) => {
+                    let body = content.as_bytes()[start..=end].to_vec();
+                    let mut response = Response::new(FramesBody::from(ImmutableBytes::from(body)));
+                    *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+                    let content_range = format!("bytes {}-{}/{}", start, end, content.len());
+                    let content_range = HeaderValue::try_from(content_range).expect("range");
+                    response.headers_mut().insert(CONTENT_RANGE, content_range);
+                    response
+                }
+
+                None => {
+                    full_calls.fetch_add(1, atomic::Ordering::SeqCst);
+                    let body = content.as_bytes().to_vec();
+                    Response::new(FramesBody::from(ImmutableBytes::from(body)))
+                }
+            };
+            response.headers_mut().insert(ETAG, HeaderValue::from_static(etag));
+            ready(Ok::<_, io::Error>(response))
+        })
+    };
+
+    // Room for one object in flight
+    let range_assembly =
+        RangeAssembly::new(PathMatcher::Prefix("/objects/".into()), 100).max_assembling_size(15);
+    let mut service = CachingLayer::<(), MockCache>::default()
+        .cache(MockCache::default())
+        .range_assembly(range_assembly.clone())
+        .layer(upstream);
+
+    let mut get = async |path: &str, range: Option<&str>| {
+        let mut request = Request::get(path);
+        if let Some(range) = range {
+            request = request.header(RANGE, range);
+        }
+        let response = service
+            .oneshot_ready(request.body(()).expect("Request::get"))
+            .await
+            .expect("oneshot_ready");
+        let status = response.extensions().get::<CacheStatus>().map(CacheStatus::as_str);
+        let content_length = response.headers().get(CONTENT_LENGTH).cloned();
+        (status, content_length, body_bytes(response.into_body()).await)
+    };
+
+    // Assembled
+    assert_eq!(get("/objects/a", Some("bytes=0-4")).await.2, b"01234");
+    assert_eq!(range_assembly.stats().in_flight, 1, "first range");
+    assert_eq!(get("/objects/a", Some("bytes=5-9")).await.2, b"56789");
+    assert_eq!(range_assembly.stats().completed, 1, "second range");
+
+    let (status, content_length, body) = get("/objects/a", None).await;
+    assert_eq!(status, Some("HIT"), "assembled");
+    assert_eq!(content_length, Some(HeaderValue::from(10)), "assembled: Content-Length");
+    assert_eq!(body, b"0123456789", "assembled");
+    assert_eq!(full_calls.load(atomic::Ordering::SeqCst), 0, "assembled: full calls");
+
+    // Validator change
+    get("/objects/b", Some("bytes=0-4")).await;
+    *version.lock().expect("lock") = ("\"v2\"", "abcdefghij");
+    assert_eq!(get("/objects/b", Some("bytes=5-9")).await.2, b"fghij");
+    let stats = range_assembly.stats();
+    assert_eq!((stats.abandoned, stats.in_flight), (1, 0), "validator change");
+
+    let (status, _, body) = get("/objects/b", None).await;
+    assert_eq!(status, Some("MISS"), "abandoned");
+    assert_eq!(body, b"abcdefghij", "abandoned");
+
+    // Memory bound
+    get("/objects/c", Some("bytes=0-4")).await;
+    get("/objects/d", Some("bytes=0-4")).await;
+    let stats = range_assembly.stats();
+    assert_eq!((stats.in_flight, stats.assembling_size, stats.refused), (1, 10, 1), "bound");
+}
+
+// Validators of an oversized response answer matching conditional requests and HEAD requests
+// without calling the upstream, while other requests pass through and refresh them
+#[tokio::test]
+async fn oversized_validators() {
+    let cache = SimpleLruCache::new(1024 * 1024, None);
+    let calls = Arc::new(atomic::AtomicUsize::default());
+    let etag = Arc::new(Mutex::new("\"v1\""));
+    let upstream = {
+        let (calls, etag) = (calls.clone(), etag.clone());
+        service_fn(move |_request: Request<()>| {
+            calls.fetch_add(1, atomic::Ordering::SeqCst);
+            let etag = *etag.lock().expect("lock");
+            async move {
+                let body = ImmutableBytes::from(b"hello, world".to_vec());
+                let mut response = Response::new(FramesBody::from(body));
+                let headers = response.headers_mut();
+                headers.insert(ETAG, HeaderValue::from_static(etag));
+                headers.insert(CONTENT_LENGTH, HeaderValue::from_static("12"));
+                Ok::<_, io::Error>(response)
+            }
+        })
+    };
+    let mut service = CachingLayer::<(), SimpleLruCache>::default()
+        .cache(cache.clone())
+        .max_cacheable_body_size(4)
+        .cache_validators_for_oversized(true)
+        .layer(upstream);
+
+    let request = |method, if_none_match: Option<&'static str>| {
+        let mut request = Request::builder().method(method).uri("/export");
+        if let Some(if_none_match) = if_none_match {
+            request = request.header(IF_NONE_MATCH, if_none_match);
+        }
+        request.body(()).expect("Request::builder")
+    };
+
+    let calls = || calls.load(atomic::Ordering::SeqCst);
+
+    let response = service.oneshot_ready(request(Method::GET, None)).await.expect("oversized");
+    assert_eq!(body_bytes(response.into_body()).await, b"hello, world");
+    let cached_response = cache.get(&key("/export")).await.expect("validators");
+    assert!(cached_response.validators_only, "not validators only");
+    assert_eq!(calls(), 1);
+
+    let response = service
+        .oneshot_ready(request(Method::GET, Some("\"v1\"")))
+        .await
+        .expect("matching");
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(calls(), 1, "matching: upstream called");
+
+    let response = service.oneshot_ready(request(Method::HEAD, None)).await.expect("HEAD");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers().get(CONTENT_LENGTH), Some(&HeaderValue::from_static("12")));
+    assert!(body_bytes(response.into_body()).await.is_empty(), "HEAD: body");
+    assert_eq!(calls(), 1, "HEAD: upstream called");
+
+    *etag.lock().expect("lock") = "\"v2\"";
+    let response = service
+        .oneshot_ready(request(Method::GET, Some("\"v0\"")))
+        .await
+        .expect("not matching");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_bytes(response.into_body()).await, b"hello, world");
+    assert_eq!(calls(), 2, "not matching: upstream not called");
+    assert_version(&cache, "/export", Some("\"v2\"")).await;
+
+    // Never an empty body
+    let response = service.oneshot_ready(request(Method::GET, None)).await.expect("GET");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_bytes(response.into_body()).await, b"hello, world");
+    assert_eq!(calls(), 3, "GET: upstream not called");
+}
+
+// An expired entry is refreshed by a 304 from the upstream without rendering its body again,
+// keeping its representations and taking the 304's headers, while changed content replaces it
+#[tokio::test]
+async fn conditional_refresh() {
+    let version = Arc::new(Mutex::new("\"v1\""));
+    let renders = Arc::new(atomic::AtomicUsize::default());
+    let upstream = {
+        let (version, renders) = (version.clone(), renders.clone());
+        service_fn(move |request: Request<()>| {
+            let version = *version.lock().expect("lock");
+            let renders = renders.clone();
+            async move {
+                let not_modified = request
+                    .headers()
+                    .get(IF_NONE_MATCH)
+                    .is_some_and(|if_none_match| if_none_match == version);
+                let mut response = if not_modified {
+                    let mut response = Response::new(FramesBody(Default::default()));
+                    *response.status_mut() = StatusCode::NOT_MODIFIED;
+                    response.headers_mut().insert("x-note", HeaderValue::from_static("refreshed"));
+                    response
+                } else {
+                    renders.fetch_add(1, atomic::Ordering::SeqCst);
+                    let body = format!("hello {}", version);
+                    Response::new(FramesBody::from(ImmutableBytes::from(body.into_bytes())))
+                };
+                response.headers_mut().insert(ETAG, HeaderValue::from_static(version));
+                Ok::<_, io::Error>(response)
+            }
+        })
+    };
+
+    let cache = MockCache::default();
+    let now = Arc::new(Mutex::new(SystemTime::now()));
+    let mut service = {
+        let now = now.clone();
+        CachingLayer::<(), MockCache>::default()
+            .cache(cache.clone())
+            .clock(move || *now.lock().expect("lock"))
+            .cache_duration(|_context| Some(Duration::from_secs(60)))
+            .layer(upstream)
+    };
+
+    let mut get = async |encoding: &'static str| {
+        let request = Request::get("/refresh")
+            .header(ACCEPT_ENCODING, encoding)
+            .body(())
+            .expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+        let note = response.headers().get("x-note").cloned();
+        (response.extensions().get::<CacheStatus>().copied(), note)
+    };
+    let expire = || *now.lock().expect("lock") += Duration::from_secs(120);
+    let has_gzip = async || {
+        let cached_response = cache.get(&key("/refresh")).await.expect("stored");
+        cached_response.body.representations.contains_key(&CodingId::from(Encoding::GZip))
+    };
+
+    get("identity").await;
+    assert_eq!(get("gzip").await.0, Some(CacheStatus::Hit));
+    assert!(has_gzip().await, "gzip representation");
+
+    // Unchanged
+    expire();
+    let (status, note) = get("identity").await;
+    assert_ne!(status, Some(CacheStatus::Hit), "refresh");
+    assert_eq!(note, Some(HeaderValue::from_static("refreshed")), "refresh");
+    assert_eq!(renders.load(atomic::Ordering::SeqCst), 1, "refresh: renders");
+    assert!(has_gzip().await, "refresh: gzip representation");
+
+    let (status, note) = get("gzip").await;
+    assert_eq!(status, Some(CacheStatus::Hit), "after refresh");
+    assert_eq!(note, Some(HeaderValue::from_static("refreshed")), "after refresh");
+
+    // Changed
+    *version.lock().expect("lock") = "\"v2\"";
+    expire();
+    let (_, note) = get("identity").await;
+    assert_eq!(note, None, "changed");
+    assert_eq!(renders.load(atomic::Ordering::SeqCst), 2, "changed: renders");
+    assert_version(&cache, "/refresh", Some("\"v2\"")).await;
+    assert!(!has_gzip().await, "changed: gzip representation");
+}
+
+// An expired entry within the window is served at once to concurrent requests while a single
+// background refresh replaces it; a failed refresh keeps the stale entry, an entry beyond the
+// window is a plain miss, and an unbounded window doesn't overflow
+#[cfg(feature = "stale-while-revalidate")]
+#[tokio::test]
+async fn stale_while_revalidate() {
+    let calls = Arc::new(atomic::AtomicUsize::default());
+    let failing = Arc::new(atomic::AtomicBool::default());
+    let upstream = service_fn({
+        let calls = calls.clone();
+        let failing = failing.clone();
+        move |_request: Request<()>| {
+            let call = calls.fetch_add(1, atomic::Ordering::SeqCst) + 1;
+            let failing = failing.load(atomic::Ordering::SeqCst);
+            async move {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                let body = format!("version {}", call);
+                let mut response =
+                    Response::new(FramesBody::from(ImmutableBytes::from(body.into_bytes())));
+                if failing {
+                    *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                }
+                Ok::<_, io::Error>(response)
+            }
+        }
+    });
+
+    let cache = MockCache::default();
+    let now = Arc::new(Mutex::new(SystemTime::now()));
+    let layer_with = |window| {
+        let now = now.clone();
+        CachingLayer::<(), MockCache>::default()
+            .cache(cache.clone())
+            .clock(move || *now.lock().expect("lock"))
+            .cache_duration(|_context| Some(Duration::from_secs(60)))
+            .stale_while_revalidate(window)
+    };
+    let layer = layer_with(Duration::from_secs(60));
+    let state = layer.stale_while_revalidate_state().expect("stale_while_revalidate_state");
+    let service = layer.layer(upstream.clone());
+
+    let get = |service: &CachingService<_, (), MockCache>| {
+        let mut service = service.clone();
+        async move {
+            let request = Request::get("/stale").body(()).expect("Request::get");
+            let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+            let status = response.extensions().get::<CacheStatus>().map(CacheStatus::as_str);
+            (status, String::from_utf8(decoded_body(response).await).expect("UTF-8"))
+        }
+    };
+    let advance = |secs| *now.lock().expect("lock") += Duration::from_secs(secs);
+    let settle = async || tokio::time::sleep(Duration::from_millis(100)).await;
+    let upstream_calls = || calls.load(atomic::Ordering::SeqCst);
+
+    assert_eq!(get(&service).await, (Some("MISS"), "version 1".into()));
+
+    // Stale, served at once, refreshed once
+    advance(90);
+    let start = Instant::now();
+    let (first, second, third) = tokio::join!(get(&service), get(&service), get(&service));
+    assert!(start.elapsed() < Duration::from_millis(20), "{:?}", start.elapsed());
+    for (_, body) in [first, second, third] {
+        assert_eq!(body, "version 1");
+    }
+    settle().await;
+    assert_eq!(upstream_calls(), 2);
+    let stats = state.stats();
+    assert_eq!((stats.served, stats.refreshes, stats.in_flight), (3, 1, 0));
+    assert_eq!(get(&service).await, (Some("HIT"), "version 2".into()));
+
+    // Failed refresh
+    failing.store(true, atomic::Ordering::SeqCst);
+    advance(90);
+    assert_eq!(get(&service).await.1, "version 2");
+    settle().await;
+    assert_eq!(upstream_calls(), 3);
+    assert_eq!(state.stats().failed, 1);
+    assert_eq!(get(&service).await.1, "version 2", "kept");
+    settle().await;
+    assert_eq!(upstream_calls(), 4);
+    assert_eq!(state.stats().failed, 2);
+    failing.store(false, atomic::Ordering::SeqCst);
+
+    // Beyond the window
+    advance(200);
+    assert_eq!(get(&service).await, (Some("MISS"), "version 5".into()));
+
+    // Unbounded window
+    let service = layer_with(Duration::MAX).layer(upstream);
+    advance(60 * 60 * 24 * 365);
+    assert_eq!(get(&service).await.1, "version 5");
+    settle().await;
+    assert_eq!(get(&service).await.1, "version 6");
+}
+
+// A miss, a hit, and a conditional hit emit exactly their events in order, each with the URI and
+// key of its request; reencoding a hit and skipping a request are reported too
+#[tokio::test]
+async fn cache_events() {
+    let cache = MockCache::default();
+    let events = Arc::new(Mutex::new(Vec::default()));
+    let mut service = CachingLayer::<(), MockCache>::default()
+        .cache(cache.clone())
+        .bypass_cache_on_headers(&[COOKIE])
+        .on_cache_event({
+            let events = events.clone();
+            move |event: CacheEvent<CommonCacheKey>| {
+                let path = event
+                    .key
+                    .and_then(|key| key.path.as_ref())
+                    .map(|path| AsRef::<str>::as_ref(path).to_string());
+                events.lock().expect("lock").push((event.uri.to_string(), path, event.kind));
+            }
+        })
+        .layer(ValidatedUpstream);
+    let take_events = || {
+        let events = mem::take(&mut *events.lock().expect("lock"));
+        for (uri, path, kind) in &events {
+            assert_eq!(uri, "/events", "{}", kind);
+            assert!(path.as_ref().is_none_or(|path| path == "/events"), "{}", kind);
+        }
+        events.into_iter().map(|(_, _, kind)| kind).collect::<Vec<_>>()
+    };
+
+    let request = |encoding, header: Option<(HeaderName, HeaderValue)>| {
+        let mut request = Request::get("/events")
+            .header(ACCEPT_ENCODING, encoding)
+            .body(())
+            .expect("Request::get");
+        if let Some((name, value)) = header {
+            request.headers_mut().insert(name, value);
+        }
+        request
+    };
+
+    let response = service.oneshot_ready(request("identity", None)).await.expect("miss");
+    assert_eq!(decoded_body(response).await, b"hello");
+    let cached_response = cache.get(&key("/events")).await.expect("stored");
+    let representations = &cached_response.body.representations;
+    assert_eq!(
+        take_events(),
+        [
+            CacheEventKind::Miss,
+            CacheEventKind::Store {
+                encodings: representations.keys().cloned().collect(),
+                size: representations.values().map(|bytes| bytes.len()).sum(),
+                constraint: None,
+            },
+        ]
+    );
+
+    let response = service.oneshot_ready(request("identity", None)).await.expect("hit");
+    assert_eq!(decoded_body(response).await, b"hello");
+    assert_eq!(take_events(), [CacheEventKind::Hit]);
+
+    let conditional = Some((IF_NONE_MATCH, ValidatedUpstream::etag()));
+    let response = service.oneshot_ready(request("identity", conditional)).await.expect("304");
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(take_events(), [CacheEventKind::HitNotModified]);
+
+    // The miss stored Brotli, so GZip is new
+    #[cfg(all(feature = "gzip", feature = "brotli"))]
+    {
+        let response = service.oneshot_ready(request("gzip", None)).await.expect("reencode");
+        assert_eq!(decoded_body(response).await, b"hello");
+        let events = take_events();
+        assert_eq!(events.len(), 2, "{:?}", events);
+        assert_eq!(events[0], CacheEventKind::Hit);
+        assert!(
+            matches!(&events[1], CacheEventKind::ReencodeInCache { to, .. }
+                if *to == CodingId::from(Encoding::GZip)),
+            "{:?}",
+            events
+        );
+    }
+
+    let cookie = Some((COOKIE, HeaderValue::from_static("a=1")));
+    let response = service.oneshot_ready(request("identity", cookie)).await.expect("skip");
+    assert_eq!(decoded_body(response).await, b"hello");
+    assert_eq!(take_events(), [CacheEventKind::SkipRequest { reason: "request" }]);
+}
+
+// Each store the middleware makes is reported once, attributed to its pathway (a miss, a
+// reencode for a hit, a conditional refresh), while hits report nothing and a panicking hook
+// neither fails the request nor prevents the store
+#[tokio::test]
+async fn store_events() {
+    let upstream = service_fn(|request: Request<()>| async move {
+        let mut response = match request.headers().get(IF_NONE_MATCH) {
+            Some(if_none_match) if if_none_match == ValidatedUpstream::etag() => {
+                let mut response = Response::new(FramesBody(Default::default()));
+                *response.status_mut() = StatusCode::NOT_MODIFIED;
+                response
+            }
+            _ => Response::new(FramesBody::from(ImmutableBytes::from(b"hello".to_vec()))),
+        };
+        response.headers_mut().insert(ETAG, ValidatedUpstream::etag());
+        Ok::<_, io::Error>(response)
+    });
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let now = Arc::new(Mutex::new(SystemTime::now()));
+    let mut service = {
+        let (events, now) = (events.clone(), now.clone());
+        CachingLayer::<(), MockCache>::default()
+            .cache(MockCache::default())
+            .clock(move || *now.lock().expect("lock"))
+            .cache_duration(|_context| Some(Duration::from_secs(60)))
+            .on_store(move |event| {
+                assert!(event.weight() > 0, "weight");
+                assert_eq!(event.duration(), Some(Duration::from_secs(60)), "duration");
+                events.lock().expect("lock").push((
+                    event.key.to_string(),
+                    event.pathway,
+                    event.encodings(),
+                ));
+            })
+            .layer(upstream)
+    };
+
+    let mut get = async |encoding: &'static str| {
+        let request = Request::get("/stored")
+            .header(ACCEPT_ENCODING, encoding)
+            .body(())
+            .expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+        assert_eq!(response.status(), StatusCode::OK, "{}", encoding);
+        mem::take(&mut *events.lock().expect("lock"))
+    };
+    let identity = CodingId::from(Encoding::Identity);
+    let gzip = CodingId::from(Encoding::GZip);
+
+    let stored = get("identity").await;
+    assert_eq!(stored.len(), 1, "miss: {:?}", stored);
+    assert_eq!(stored[0].0, key("/stored").to_string(), "miss: key");
+    assert_eq!(stored[0].1, StorePathway::Miss, "miss: pathway");
+    assert!(stored[0].2.contains(&identity), "miss: encodings");
+
+    let stored = get("identity").await;
+    assert!(stored.is_empty(), "hit: {:?}", stored);
+
+    let stored = get("gzip").await;
+    assert_eq!(stored.len(), 1, "reencode: {:?}", stored);
+    assert_eq!(stored[0].1, StorePathway::Reencode, "reencode: pathway");
+    assert!(stored[0].2.contains(&gzip), "reencode: encodings");
+
+    let stored = get("gzip").await;
+    assert!(stored.is_empty(), "reencoded hit: {:?}", stored);
+
+    *now.lock().expect("lock") += Duration::from_secs(120);
+    let stored = get("identity").await;
+    assert_eq!(stored.len(), 1, "refresh: {:?}", stored);
+    assert_eq!(stored[0].1, StorePathway::Refresh, "refresh: pathway");
+
+    // Panicking hook
+    let mut service = CachingLayer::<(), MockCache>::default()
+        .cache(MockCache::default())
+        .on_store(|_event| panic!("store hook"))
+        .layer(upstream);
+
+    for expected in [CacheStatus::Miss, CacheStatus::Hit] {
+        let request = Request::get("/stored").body(()).expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+        assert_eq!(response.extensions().get::<CacheStatus>(), Some(&expected), "panic");
+        assert_eq!(body_bytes(response.into_body()).await, b"hello", "panic");
+    }
+}
+
+// In client mode the partner's standard headers drive caching: its max-age gives the duration,
+// expired entries are revalidated outbound, private and Vary'd responses are not stored, and its
+// XX headers are ignored, while invalidating the shared cache affects the client
+#[tokio::test]
+async fn client_mode() {
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let partner = {
+        let calls = calls.clone();
+        service_fn(move |request: Request<()>| {
+            let conditional =
+                request.headers().get(IF_NONE_MATCH) == Some(&ValidatedUpstream::etag());
+            calls.lock().expect("lock").push((request.uri().path().to_string(), conditional));
+
+            let mut response = if conditional {
+                let mut response = Response::new(FramesBody(Default::default()));
+                *response.status_mut() = StatusCode::NOT_MODIFIED;
+                response
+            } else {
+                Response::new(FramesBody::from(ImmutableBytes::from(b"hello".to_vec())))
+            };
+
+            let headers = response.headers_mut();
+            headers.insert(ETAG, ValidatedUpstream::etag());
+            headers.insert(XX_CACHE, HeaderValue::from_static("false"));
+            match request.uri().path() {
+                "/private" => {
+                    headers.insert(CACHE_CONTROL, HeaderValue::from_static("private, max-age=60"));
+                }
+
+                path => {
+                    headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=60"));
+                    if path == "/vary" {
+                        headers.insert(VARY, HeaderValue::from_static("Accept-Language"));
+                    }
+                }
+            }
+
+            ready(Ok::<_, io::Error>(response))
+        })
+    };
+
+    let cache = MockCache::default();
+    let now = Arc::new(Mutex::new(SystemTime::now()));
+    let mut client = {
+        let now = now.clone();
+        CachingLayer::<(), MockCache>::for_client()
+            .cache(cache.clone())
+            .clock(move || *now.lock().expect("lock"))
+            .layer(partner)
+    };
+
+    let mut get = async |path: &str| {
+        let uri = format!("http://partner.example{}", path);
+        let request = Request::get(uri).body(()).expect("Request::get");
+        let response = client.oneshot_ready(request).await.expect("oneshot_ready");
+        assert_eq!(response.headers().get(XX_CACHE), None, "{}: XX-Cache", path);
+        let status = response.extensions().get::<CacheStatus>().map(CacheStatus::as_str);
+        let calls = mem::take(&mut *calls.lock().expect("lock"));
+        (status, calls.into_iter().map(|(_, conditional)| conditional).collect::<Vec<_>>())
+    };
+
+    // (path, expected status, expected calls to the partner: whether conditional)
+    let steps = [
+        ("/public", "MISS", vec![false]),
+        ("/public", "HIT", vec![]),
+        ("/private", "MISS", vec![false]),
+        ("/private", "MISS", vec![false]),
+        ("/vary", "MISS", vec![false]),
+        ("/vary", "MISS", vec![false]),
+    ];
+
+    for (path, expected_status, expected_calls) in steps {
+        let (status, calls) = get(path).await;
+        assert_eq!(status, Some(expected_status), "{}", path);
+        assert_eq!(calls, expected_calls, "{}: calls", path);
+    }
+    assert_eq!(cache.keys().expect("keys").len(), 1, "entries");
+
+    // Revalidated outbound
+    *now.lock().expect("lock") += Duration::from_secs(120);
+    let (status, calls) = get("/public").await;
+    assert_ne!(status, Some("HIT"), "revalidate");
+    assert_eq!(calls, [true], "revalidate: calls");
+    assert_eq!(get("/public").await, (Some("HIT"), vec![]), "revalidated");
+
+    // Invalidated via the shared cache
+    for key in cache.keys().expect("keys") {
+        cache.invalidate(&key).await;
+    }
+    assert_eq!(get("/public").await, (Some("MISS"), vec![false]), "invalidated");
+}
+
+// With respect_cache_control, no-store, private, and max-age=0 skip the cache and s-maxage or
+// max-age is the duration, with XX-Cache-Duration and then the hook taking precedence, while a
+// malformed Cache-Control falls back to the default, as do all responses without it
+#[tokio::test]
+async fn cache_control_signals() {
+    // Responds with the Cache-Control and XX-Cache-Duration requested via test headers
+    let upstream = service_fn(|request: Request<()>| async move {
+        let mut response = Response::new(FramesBody::from(ImmutableBytes::from(b"hello".to_vec())));
+        let names = [("x-cache-control", CACHE_CONTROL), ("x-cache-duration", XX_CACHE_DURATION)];
+        for (test_name, name) in names {
+            if let Some(value) = request.headers().get(test_name) {
+                response.headers_mut().insert(name, value.clone());
+            }
+        }
+        Ok::<_, io::Error>(response)
+    });
+
+    let minutes = |minutes: u64| Some(Some(Duration::from_secs(minutes * 60)));
+
+    // (respect Cache-Control, path, Cache-Control, XX-Cache-Duration, stored duration)
+    let cases = [
+        (true, "/no-store", "no-store", None, None),
+        (true, "/private", "private, max-age=60", None, None),
+        (true, "/max-age", "max-age=60", None, minutes(1)),
+        (true, "/s-maxage", "s-maxage=120, max-age=60", None, minutes(2)),
+        (true, "/max-age-0", "max-age=0", None, None),
+        (true, "/s-maxage-0", "s-maxage=0, max-age=60", None, None),
+        (true, "/explicit", "max-age=0", Some("5m"), minutes(5)),
+        (true, "/hook", "max-age=60", None, minutes(10)),
+        (true, "/hook-explicit", "max-age=60", Some("5m"), minutes(5)),
+        (true, "/malformed", "max-age=banana, no-stroe", None, Some(None)),
+        (false, "/no-store", "no-store", None, Some(None)),
+        (false, "/max-age-0", "max-age=0", None, Some(None)),
+        (false, "/max-age", "max-age=60", None, Some(None)),
+    ];
+
+    for respect_cache_control in [true, false] {
+        let cache = MockCache::default();
+        let mut service = CachingLayer::<(), MockCache>::default()
+            .cache(cache.clone())
+            .respect_cache_control(respect_cache_control)
+            .allow("W003")
+            .cache_duration(|context| {
+                context.uri.path().starts_with("/hook").then(|| Duration::from_secs(600))
+            })
+            .layer(upstream);
+
+        for (_, path, cache_control, duration, expected) in
+            cases.iter().filter(|(respect, ..)| *respect == respect_cache_control)
+        {
+            let mut request = Request::get(*path).header("x-cache-control", *cache_control);
+            if let Some(duration) = duration {
+                request = request.header("x-cache-duration", *duration);
+            }
+            let request = request.body(()).expect("Request::get");
+            service.oneshot_ready(request).await.expect("oneshot_ready");
+
+            let keys = cache.keys().expect("keys");
+            let stored = match keys.iter().find(|key| key.path() == Some(*path)) {
+                Some(key) => cache.get(key).await.map(|cached_response| cached_response.duration),
+                None => None,
+            };
+            assert_eq!(stored, *expected, "{}: {}", respect_cache_control, path);
+        }
+    }
+}
+
+// With vary keys, each value of a request header named by Vary gets its own entry (including a
+// missing header), and responses with Vary: * are not cached
+#[tokio::test]
+async fn vary_keys() {
+    // Responds in the requested language
+    let upstream = service_fn(|request: Request<()>| async move {
+        let language = request.headers().get(ACCEPT_LANGUAGE).and_then(|value| value.to_str().ok());
+        let body = ImmutableBytes::from(language.unwrap_or("default").as_bytes().to_vec());
+        let mut response = Response::new(FramesBody::from(body));
+        let vary = if request.uri().path() == "/star" { "*" } else { "Accept-Language" };
+        response.headers_mut().insert(VARY, HeaderValue::from_static(vary));
+        Ok::<_, io::Error>(response)
+    });
+    let cache = MockCache::default();
+    let mut service = CachingLayer::<(), MockCache>::default()
+        .cache(cache.clone())
+        .vary_keys(VaryKeys::default())
+        .layer(upstream);
+
+    // (path, Accept-Language, cache status, body)
+    let requests = [
+        ("/page", Some("en"), "MISS", "en"),
+        ("/page", Some("en"), "HIT", "en"),
+        ("/page", Some("zh"), "MISS", "zh"),
+        ("/page", Some("zh"), "HIT", "zh"),
+        ("/page", Some("en"), "HIT", "en"),
+        ("/page", None, "MISS", "default"),
+        ("/page", None, "HIT", "default"),
+        ("/page", Some(""), "MISS", ""),
+        ("/star", Some("en"), "MISS", "en"),
+        ("/star", Some("en"), "MISS", "en"),
+    ];
+
+    for (path, language, expected_status, expected_body) in requests {
+        let mut request = Request::get(path);
+        if let Some(language) = language {
+            request = request.header(ACCEPT_LANGUAGE, language);
+        }
+        let request = request.body(()).expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+        let status = response.extensions().get::<CacheStatus>().map(CacheStatus::as_str);
+        assert_eq!(status, Some(expected_status), "{} {:?}", path, language);
+        let body = body_bytes(response.into_body()).await;
+        assert_eq!(body, expected_body.as_bytes(), "{} {:?}", path, language);
+    }
+
+    assert_eq!(cache.entry_count(), Some(4), "en, zh, missing, and empty");
+}
+
+// Opted-in negative responses are stored for the negative duration (unless the upstream says
+// otherwise) and answered without the upstream even for conditional requests, empty redirects
+// are stored as is, and 304 and 5xx are never stored
+#[tokio::test]
+async fn negative_caching() {
+    let calls = Arc::new(Mutex::new(Vec::default()));
+    let upstream = service_fn({
+        let calls = calls.clone();
+        move |request: Request<()>| {
+            let path = request.uri().path().to_string();
+            calls.lock().expect("lock").push(path.clone());
+            let (status, body): (_, &[u8]) = match path.as_str() {
+                "/moved" => (StatusCode::MOVED_PERMANENTLY, b""),
+                "/gone" => (StatusCode::GONE, b"gone"),
+                "/unavailable" => (StatusCode::SERVICE_UNAVAILABLE, b"unavailable"),
+                "/not-modified" => (StatusCode::NOT_MODIFIED, b""),
+                _ => (StatusCode::NOT_FOUND, b"not found"),
+            };
+            let mut response = Response::new(FramesBody::from(ImmutableBytes::from(body.to_vec())));
+            *response.status_mut() = status;
+            let headers = response.headers_mut();
+            match path.as_str() {
+                "/moved" => {
+                    headers.insert(LOCATION, HeaderValue::from_static("/elsewhere"));
+                }
+
+                "/overridden" => {
+                    headers.insert(XX_CACHE_DURATION, HeaderValue::from_static("1m"));
+                }
+
+                _ => {}
+            }
+            ready(Ok::<_, io::Error>(response))
+        }
+    });
+
+    let cache = MockCache::default();
+    let now = Arc::new(Mutex::new(SystemTime::now()));
+    let mut service = {
+        let now = now.clone();
+        CachingLayer::<(), MockCache>::default()
+            .cache(cache.clone())
+            .clock(move || *now.lock().expect("lock"))
+            .cacheable_status_codes([
+                StatusCode::MOVED_PERMANENTLY,
+                StatusCode::NOT_MODIFIED,
+                StatusCode::SERVICE_UNAVAILABLE,
+            ])
+            .negative_cache_duration(Duration::from_secs(10))
+            .layer(upstream)
+    };
+
+    let mut get = async |path: &str, if_none_match: bool| {
+        let mut request = Request::get(path).header(ACCEPT_ENCODING, "gzip");
+        if if_none_match {
+            request = request.header(IF_NONE_MATCH, "*");
+        }
+        let request = request.body(()).expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+        (response.status(), response.headers().clone(), decoded_body(response).await)
+    };
+    let calls_to = |path: &str| {
+        calls.lock().expect("lock").iter().filter(|call| *call == path).count()
+    };
+    let duration = async |path: &str| cache.get(&key(path)).await.expect("stored").duration;
+
+    // (path, expected status, expected body, stored)
+    let cases: [(_, _, &[u8], _); 6] = [
+        ("/missing", StatusCode::NOT_FOUND, b"not found", true),
+        ("/gone", StatusCode::GONE, b"gone", true),
+        ("/moved", StatusCode::MOVED_PERMANENTLY, b"", true),
+        ("/overridden", StatusCode::NOT_FOUND, b"not found", true),
+        ("/unavailable", StatusCode::SERVICE_UNAVAILABLE, b"unavailable", false),
+        ("/not-modified", StatusCode::NOT_MODIFIED, b"", false),
+    ];
+
+    for (path, expected_status, expected_body, stored) in cases {
+        for if_none_match in [false, true] {
+            let (status, headers, body) = get(path, if_none_match).await;
+            assert_eq!(status, expected_status, "{}", path);
+            assert_eq!(body, expected_body, "{}", path);
+            if path == "/moved" {
+                assert_eq!(headers.get(LOCATION), Some(&HeaderValue::from_static("/elsewhere")));
+            }
+        }
+        assert_eq!(calls_to(path), if stored { 1 } else { 2 }, "{}: calls", path);
+    }
+
+    assert_eq!(duration("/missing").await, Some(Duration::from_secs(10)));
+    assert_eq!(duration("/overridden").await, Some(Duration::from_secs(60)));
+
+    *now.lock().expect("lock") += Duration::from_secs(11);
+    assert_eq!(get("/missing", false).await.0, StatusCode::NOT_FOUND);
+    assert_eq!(calls_to("/missing"), 2, "expired");
+}
+
+// Immutable paths answer any conditional request with 304 and are served with an immutable
+// Cache-Control, until a refresh reveals different content, which demotes the path
+#[tokio::test]
+async fn immutable_paths() {
+    let content = Arc::new(Mutex::new("hello"));
+    let upstream = {
+        let content = content.clone();
+        service_fn(move |_request: Request<()>| {
+            let content = *content.lock().expect("lock");
+            async move {
+                let body = ImmutableBytes::from(content.as_bytes().to_vec());
+                let mut response = Response::new(FramesBody::from(body));
+                response.headers_mut().insert(ETAG, ValidatedUpstream::etag());
+                Ok::<_, io::Error>(response)
+            }
+        })
+    };
+
+    let now = Arc::new(Mutex::new(SystemTime::now()));
+    let mut service = {
+        let now = now.clone();
+        CachingLayer::<(), MockCache>::default()
+            .cache(MockCache::default())
+            .clock(move || *now.lock().expect("lock"))
+            .immutable_paths(
+                ImmutablePaths::new(PathMatcher::Prefix("/assets/".into()))
+                    .max_age(Duration::from_secs(60)),
+            )
+            .layer(upstream)
+    };
+
+    let mut get = async |path: &'static str, validator: Option<(HeaderName, &'static str)>| {
+        let mut request = Request::get(path);
+        if let Some((name, value)) = validator {
+            request = request.header(name, value);
+        }
+        let request = request.body(()).expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect(path);
+        (response.status(), response.headers().get(CACHE_CONTROL).cloned())
+    };
+
+    let immutable = Some(HeaderValue::from_static("public, max-age=60, immutable"));
+    let other_etag = || Some((IF_NONE_MATCH, "\"other\""));
+    let ancient = || Some((IF_MODIFIED_SINCE, "Thu, 01 Jan 1970 00:00:00 GMT"));
+
+    assert_eq!(get("/assets/app.js", None).await, (StatusCode::OK, immutable.clone()));
+    for validator in [other_etag(), ancient()] {
+        let (status, cache_control) = get("/assets/app.js", validator.clone()).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED, "{:?}", validator);
+        assert_eq!(cache_control, immutable, "{:?}", validator);
+    }
+
+    // Other paths have normal semantics
+    get("/other.js", None).await;
+    assert_eq!(get("/other.js", other_etag()).await, (StatusCode::OK, None));
+
+    // Refreshed with different content
+    *content.lock().expect("lock") = "HELLO";
+    *now.lock().expect("lock") += Duration::from_secs(120);
+    assert_eq!(get("/assets/app.js", None).await, (StatusCode::OK, None), "demoted");
+    assert_eq!(get("/assets/app.js", other_etag()).await, (StatusCode::OK, None), "demoted");
+}
+
+// Host partitioning keys by the forwarded scheme and host only if the peer is a trusted proxy,
+// taking the first forwarded element, so that forged headers can't influence the key
+#[tokio::test]
+async fn trusted_forwarded() {
+    let trusted = Some(SocketAddr::from(([127, 0, 0, 1], 4711)));
+    let untrusted = Some(SocketAddr::from(([203, 0, 113, 5], 4711)));
+    let forwarded = |value| vec![(FORWARDED, value)];
+    let x_forwarded = |proto, host| vec![(X_FORWARDED_PROTO, proto), (X_FORWARDED_HOST, host)];
+
+    // (name, peer, headers, expected scheme, expected host)
+    let cases = [
+        (
+            "Forwarded",
+            trusted,
+            forwarded("for=192.0.2.43;proto=https;host=\"Shop.Example\", host=evil.example"),
+            Some(Scheme::HTTPS),
+            "shop.example",
+        ),
+        (
+            "X-Forwarded",
+            trusted,
+            x_forwarded("https", "tenant.example, evil.example"),
+            Some(Scheme::HTTPS),
+            "tenant.example",
+        ),
+        ("untrusted", untrusted, forwarded("proto=https;host=evil.example"), None, "internal"),
+        ("no peer", None, x_forwarded("https", "evil.example"), None, "internal"),
+    ];
+
+    for (name, peer, headers, expected_scheme, expected_host) in cases {
+        let cache = MockCache::default();
+        let mut service = CachingLayer::<(), MockCache>::default()
+            .cache(cache.clone())
+            .partition_by_host(true)
+            .trusted_forwarded(TrustPolicy::trusting_peers(|address| address.is_loopback()))
+            .layer(ValidatedUpstream);
+
+        let mut request = Request::get("/page")
+            .header(HOST, "internal")
+            .body(())
+            .expect("Request::get");
+        for (header_name, value) in headers {
+            request.headers_mut().insert(header_name, HeaderValue::from_static(value));
+        }
+        if let Some(peer) = peer {
+            request.extensions_mut().insert(peer);
+        }
+        service.oneshot_ready(request).await.expect(name);
+
+        let keys = cache.keys().expect("keys");
+        assert_eq!(keys.len(), 1, "{}", name);
+        assert_eq!(keys[0].scheme, expected_scheme, "{}", name);
+        assert_eq!(keys[0].host.as_deref(), Some(expected_host), "{}", name);
+    }
+}
+
+// Forwarded header values are parsed into elements of lowercase names and unquoted values, with
+// separators and escapes inside quotes kept as is
+#[test]
+fn forwarded_parsing() {
+    let elements = parse_forwarded(
+        "for=\"[2001:db8:cafe::17]:4711\";Proto=https, for=192.0.2.43;host=\"a.example;b,c\" ,\
+         by=\"quoted \\\"name\\\"\"",
+    );
+
+    let expected = [
+        vec![("for", "[2001:db8:cafe::17]:4711"), ("proto", "https")],
+        vec![("for", "192.0.2.43"), ("host", "a.example;b,c")],
+        vec![("by", "quoted \"name\"")],
+    ];
+
+    assert_eq!(elements.len(), expected.len(), "{:?}", elements);
+    for (element, expected) in elements.iter().zip(expected) {
+        let element: Vec<_> =
+            element.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect();
+        assert_eq!(element, expected);
+    }
+}
+
+// Fuzz-derived request targets that are not in origin-form are never cached, with their form as
+// the skip reason, while their keys are still well formed
+#[tokio::test]
+async fn request_target_forms() {
+    let log = Arc::new(Mutex::new(Vec::<u8>::new()));
+    let subscriber = {
+        let log = log.clone();
+        tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || CapturedLog(log.clone()))
+            .finish()
+    };
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let cache = MockCache::default();
+    let mut service = CachingLayer::<(), MockCache>::default()
+        .cache(cache.clone())
+        .layer(ValidatedUpstream);
+
+    // (method, request target, expected skip reason)
+    let cases = [
+        (Method::OPTIONS, "*", "skip (asterisk-form)"),
+        (Method::GET, "http://evil.example/x", "skip (absolute-form)"),
+        (Method::GET, "evil.example:443", "skip (authority-form)"),
+        (Method::CONNECT, "evil.example:443", "skip (method CONNECT)"),
+    ];
+
+    for (method, target, expected_reason) in cases {
+        let uri = Uri::try_from(target).expect("URI");
+        let key = CommonCacheKey::for_request(&method, &uri, &HeaderMap::default());
+        let path = key.path.as_ref().map(AsRef::<str>::as_ref);
+        assert!(path.is_some_and(|path| !path.is_empty()), "{}: path", target);
+
+        for _ in 0..2 {
+            let request = Request::builder().method(method.clone()).uri(uri.clone());
+            let request = request.body(()).expect("Request::builder");
+            let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+            let status = response.extensions().get::<CacheStatus>().map(CacheStatus::as_str);
+            assert_eq!(status, Some("BYPASS"), "{} {}", method, target);
+        }
+
+        let logged = String::from_utf8(mem::take(&mut *log.lock().expect("lock"))).expect("UTF-8");
+        assert!(logged.contains(expected_reason), "{} {}: {}", method, target, logged);
+    }
+    assert_eq!(cache.keys().expect("keys").len(), 0, "entries");
+}
+
+// With cache_absolute_form, an absolute-form target is keyed by its scheme and authority with an
+// origin-form path, so it never collides with another host or with an origin-form target
+#[tokio::test]
+async fn cache_absolute_form() {
+    let cache = MockCache::default();
+    let mut service = CachingLayer::<(), MockCache>::default()
+        .cache(cache.clone())
+        .cache_absolute_form(true)
+        .layer(ValidatedUpstream);
+
+    let mut status = async |target: &'static str| {
+        let request = Request::get(target)
+            .header(HOST, "example.com:8080")
+            .body(())
+            .expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+        response.extensions().get::<CacheStatus>().map(CacheStatus::as_str)
+    };
+
+    assert_eq!(status("http://Example.com:8080/x?y=1").await, Some("MISS"));
+    assert_eq!(status("http://example.com:8080/x?y=1").await, Some("HIT"));
+    assert_eq!(status("http://other.example:8080/x?y=1").await, Some("MISS"), "other host");
+    assert_eq!(status("/x?y=1").await, Some("MISS"), "origin-form");
+
+    let keys = cache.keys().expect("keys");
+    let key = keys
+        .iter()
+        .find(|key| key.host.as_ref().map(AsRef::<str>::as_ref) == Some("example.com"))
+        .expect("absolute-form key");
+    assert_eq!(key.scheme, Some(uri::Scheme::HTTP), "scheme");
+    assert_eq!(key.port, Some(8080), "port");
+    assert_eq!(key.path.as_ref().map(AsRef::<str>::as_ref), Some("/x"), "path");
+}
+
+// Client mode caches absolute-form targets without having to enable it separately
+#[tokio::test]
+async fn client_mode_absolute_form() {
+    let mut client = CachingLayer::<(), MockCache>::for_client()
+        .cache(MockCache::default())
+        .layer(ValidatedUpstream);
+
+    for expected_status in ["MISS", "HIT"] {
+        let request = Request::get("http://partner.example/x").body(()).expect("Request::get");
+        let response = client.oneshot_ready(request).await.expect("oneshot_ready");
+        let status = response.extensions().get::<CacheStatus>().map(CacheStatus::as_str);
+        assert_eq!(status, Some(expected_status));
+    }
+}
+
+// A newer build version invalidates the entry for the previous version of the same path on first
+// sight, stripped cache-busting parameters don't make for new entries, and other parameters are
+// untouched
+#[tokio::test]
+async fn bust_params() {
+    let cache = MockCache::default();
+    let mut service = CachingLayer::<(), MockCache>::default()
+        .cache(cache.clone())
+        .bust_param_policy(
+            BustParamPolicy::new(PathMatcher::Prefix("/assets/".into()))
+                .parameter("v", BustParamMode::KeyAndExpireOld)
+                .numeric(true),
+        )
+        .bust_param_policy(
+            BustParamPolicy::new(PathMatcher::Prefix("/api/".into()))
+                .parameter("cachebust", BustParamMode::Strip),
+        )
+        .layer(ValidatedUpstream);
+
+    // Steps: path, expected status, paths expected to be stored, paths expected to be gone
+    let steps = [
+        ("/assets/app.js?v=9", "MISS", vec!["/assets/app.js?v=9"], vec![]),
+        ("/assets/app.js?v=9", "HIT", vec![], vec![]),
+        ("/assets/lib.js?v=9", "MISS", vec!["/assets/lib.js?v=9"], vec![]),
+        (
+            // Numerically newer (but lexicographically older)
+            "/assets/app.js?v=10",
+            "MISS",
+            vec!["/assets/app.js?v=10", "/assets/lib.js?v=9"],
+            vec!["/assets/app.js?v=9"],
+        ),
+        // Older values don't invalidate newer ones
+        ("/assets/app.js?v=9", "MISS", vec!["/assets/app.js?v=10"], vec![]),
+        ("/api/data?cachebust=1&page=1", "MISS", vec!["/api/data?page=1"], vec![]),
+        ("/api/data?cachebust=2&page=1", "HIT", vec![], vec![]),
+        ("/api/data?page=1", "HIT", vec![], vec![]),
+        ("/api/data?cachebust=3&page=2", "MISS", vec!["/api/data?page=2"], vec![]),
+    ];
+
+    for (path, expected_status, stored, gone) in steps {
+        let request = Request::get(path).body(()).expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect(path);
+        let status = response.extensions().get::<CacheStatus>().map(CacheStatus::as_str);
+        assert_eq!(status, Some(expected_status), "{}", path);
+
+        for stored in stored {
+            assert!(cache.get(&key(stored)).await.is_some(), "{}: {} stored", path, stored);
+        }
+        for gone in gone {
+            assert!(cache.get(&key(gone)).await.is_none(), "{}: {} gone", path, gone);
+        }
+    }
+}
+
+// The same URI with and without Cookie either skips the cache or gets separate entries, depending
+// on whether the layer bypasses or partitions by it, and Authorization skips the cache with strict
+// privacy (even if partitioned by it)
+#[tokio::test]
+async fn header_privacy() {
+    let request = |header: Option<(HeaderName, &'static str)>| {
+        let mut request = Request::get("/private").body(()).expect("Request::get");
+        if let Some((name, value)) = header {
+            request.headers_mut().insert(name, HeaderValue::from_static(value));
+        }
+        request
+    };
+
+    let cookie = |value| Some((COOKIE, value));
+
+    // Steps: request header, expected status, expected number of entries
+    let layer = CachingLayer::<(), SimpleLruCache>::default;
+    let scenarios = [
+        (
+            "bypass",
+            layer().bypass_cache_on_headers(&[COOKIE]),
+            vec![
+                (None, "MISS", 1),
+                (cookie("a=1"), "BYPASS", 1),
+                (cookie("a=1"), "BYPASS", 1),
+                (None, "HIT", 1),
+            ],
+        ),
+        (
+            "partition",
+            layer().partition_cache_by_headers(&[COOKIE]),
+            vec![
+                (None, "MISS", 1),
+                (cookie("a=1"), "MISS", 2),
+                (cookie("a=1"), "HIT", 2),
+                (cookie("a=2"), "MISS", 3),
+                (None, "HIT", 3),
+            ],
+        ),
+        (
+            "strict privacy",
+            layer().strict_privacy(true),
+            vec![(None, "MISS", 1), (Some((AUTHORIZATION, "Bearer a")), "BYPASS", 1)],
+        ),
+        (
+            "strict privacy despite partition",
+            layer().partition_cache_by_headers(&[AUTHORIZATION]).strict_privacy(true),
+            vec![(None, "MISS", 1), (Some((AUTHORIZATION, "Bearer a")), "BYPASS", 1)],
+        ),
+    ];
+
+    for (name, layer, steps) in scenarios {
+        let cache = SimpleLruCache::new(1024 * 1024, None);
+        let mut service = layer.cache(cache.clone()).layer(ValidatedUpstream);
+
+        for (index, (header, expected_status, expected_len)) in steps.into_iter().enumerate() {
+            let response = service.oneshot_ready(request(header)).await.expect("oneshot_ready");
+            let status = response.extensions().get::<CacheStatus>().map(CacheStatus::as_str);
+            assert_eq!(status, Some(expected_status), "{} {}: status", name, index);
+            assert_eq!(cache.len(), expected_len, "{} {}: entries", name, index);
+        }
+    }
+}
+
+// Activity in one partition never changes the hits and misses observed from another for the
+// partitioned paths, while other paths share entries, and requests without a partition are
+// bypassed or share their own bucket, never another partition's
+#[tokio::test]
+async fn partition_probes() {
+    let layer = |unpartitioned| {
+        let cache = SimpleLruCache::new(1024 * 1024, None);
+        let partition_policy =
+            PartitionPolicy::new(PathMatcher::Prefix("/presence/".into()), |context| {
+                let tenant = context.headers.get("x-tenant")?;
+                tenant.to_str().ok().map(String::from)
+            })
+            .unpartitioned(unpartitioned);
+        let layer = CachingLayer::<(), SimpleLruCache>::default()
+            .cache(cache.clone())
+            .partition_by(partition_policy);
+        (cache, layer.layer(ValidatedUpstream))
+    };
+
+    let probe = async |service: &mut CachingService<ValidatedUpstream, (), SimpleLruCache>,
+                       path: &'static str,
+                       tenant: Option<&'static str>| {
+        let mut request = Request::get(path);
+        if let Some(tenant) = tenant {
+            request = request.header("x-tenant", tenant);
+        }
+        let request = request.body(()).expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+        response.extensions().get::<CacheStatus>().map(CacheStatus::as_str)
+    };
+
+    let (cache, mut service) = layer(UnpartitionedPolicy::Bypass);
+
+    // B observes the same outcomes whether or not A is active
+    for (path, busy) in [("/presence/quiet", false), ("/presence/busy", true)] {
+        let mut observed = Vec::default();
+        for _ in 0..3 {
+            if busy {
+                probe(&mut service, path, Some("a")).await;
+            }
+            observed.push(probe(&mut service, path, Some("b")).await);
+        }
+        assert_eq!(observed, [Some("MISS"), Some("HIT"), Some("HIT")], "{}", path);
+    }
+
+    // Unpartitioned paths are shared
+    assert_eq!(probe(&mut service, "/public", Some("a")).await, Some("MISS"));
+    assert_eq!(probe(&mut service, "/public", Some("b")).await, Some("HIT"));
+    assert_eq!(probe(&mut service, "/public", None).await, Some("HIT"));
+
+    // Requests without a partition are bypassed
+    assert_eq!(probe(&mut service, "/presence/busy", None).await, Some("BYPASS"));
+    assert_eq!(probe(&mut service, "/presence/busy", Some("")).await, Some("BYPASS"));
+
+    // The partition is part of the key
+    let mut partitions: Vec<_> = cache
+        .keys()
+        .expect("keys")
+        .into_iter()
+        .map(|key| {
+            let partition = key.partition.as_ref().map(|partition| {
+                AsRef::<str>::as_ref(partition).to_string()
+            });
+            (key.path().map(String::from), partition)
+        })
+        .collect();
+    partitions.sort();
+    let expected = [
+        ("/presence/busy", Some("a")),
+        ("/presence/busy", Some("b")),
+        ("/presence/quiet", Some("b")),
+        ("/public", None),
+    ]
+    .map(|(path, partition)| (Some(path.into()), partition.map(String::from)));
+    assert_eq!(partitions, expected);
+
+    // Or share a dedicated bucket
+    let (_cache, mut service) = layer(UnpartitionedPolicy::Bucket);
+    assert_eq!(probe(&mut service, "/presence/bucket", None).await, Some("MISS"));
+    assert_eq!(probe(&mut service, "/presence/bucket", Some("")).await, Some("HIT"));
+    assert_eq!(probe(&mut service, "/presence/bucket", Some("a")).await, Some("MISS"));
+    assert_eq!(probe(&mut service, "/presence/bucket", None).await, Some("HIT"));
+}
+
+// Partitioning digests depend on the digest key
+#[tokio::test]
+async fn header_digest_key() {
+    let cache = SimpleLruCache::new(1024 * 1024, None);
+    let layer = |key| {
+        CachingLayer::<(), SimpleLruCache>::default()
+            .cache(cache.clone())
+            .partition_cache_by_headers(&[COOKIE])
+            .header_digest_key(key)
+    };
+
+    let request = || Request::get("/private").header(COOKIE, "a=1").body(()).expect("Request::get");
+
+    let status = |response: Response<_>| {
+        response.extensions().get::<CacheStatus>().map(CacheStatus::as_str)
+    };
+
+    let mut service = layer([1; 16]).layer(ValidatedUpstream);
+    let response = service.oneshot_ready(request()).await.expect("store");
+    assert_eq!(status(response), Some("MISS"));
+    let response = service.oneshot_ready(request()).await.expect("same key");
+    assert_eq!(status(response), Some("HIT"));
+
+    let mut service = layer([2; 16]).layer(ValidatedUpstream);
+    let response = service.oneshot_ready(request()).await.expect("other key");
+    assert_eq!(status(response), Some("MISS"));
+}
+
+// A hit has Age and the cache age header computed from the entry's creation time, while for the
+// response that stored the entry they are zero or missing
+#[tokio::test]
+async fn age_headers() {
+    let x_cache_age = HeaderName::from_static("x-cache-age");
+
+    let now = Arc::new(Mutex::new(SystemTime::now()));
+    let clock = now.clone();
+    let layer = CachingLayer::<(), SimpleLruCache>::default()
+        .cache(SimpleLruCache::new(1024 * 1024, None))
+        .clock(move || *clock.lock().expect("lock"))
+        .expose_cache_age_header(x_cache_age.clone());
+    let mut service = layer.layer(ValidatedUpstream);
+
+    let request = || Request::get("/age").body(()).expect("Request::get");
+
+    let age = |response: &Response<_>, name: &HeaderName| {
+        response.headers().get(name).and_then(|age| age.to_str().ok()?.parse::<u64>().ok())
+    };
+
+    let response = service.oneshot_ready(request()).await.expect("store");
+    for name in [&AGE, &x_cache_age] {
+        assert!(age(&response, name).unwrap_or_default() == 0, "store: {}", name);
+    }
+
+    *now.lock().expect("lock") += Duration::from_secs(2);
+
+    let response = service.oneshot_ready(request()).await.expect("hit");
+    assert_eq!(response.extensions().get::<CacheStatus>(), Some(&CacheStatus::Hit));
+    for name in [&AGE, &x_cache_age] {
+        assert!(age(&response, name).is_some_and(|age| age >= 2), "hit: {}", name);
+    }
+}
+
+// Each age accounting strategy serves the same stored entry with its own Date and Age, so that a
+// downstream cache that emits Age computes the remaining freshness from the time held here plus
+// the upstream Age
+#[tokio::test]
+async fn age_accounting() {
+    let created = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let upstream = service_fn(move |_request: Request<()>| async move {
+        let mut response = Response::new(FramesBody::from(ImmutableBytes::from(b"hello".to_vec())));
+        let headers = response.headers_mut();
+        let date = HeaderValue::from_str(&httpdate::fmt_http_date(created)).expect("Date");
+        headers.insert(DATE, date);
+        headers.insert(AGE, HeaderValue::from_static("60"));
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=900"));
+        Ok::<_, io::Error>(response)
+    });
+
+    let cache = SimpleLruCache::new(1024 * 1024, None);
+    let now = Arc::new(Mutex::new(created));
+    let service = |age_accounting| {
+        let now = now.clone();
+        CachingLayer::<(), SimpleLruCache>::default()
+            .cache(cache.clone())
+            .clock(move || *now.lock().expect("lock"))
+            .age_accounting(age_accounting)
+            .layer(upstream)
+    };
+
+    let request = || Request::get("/age-accounting").body(()).expect("Request::get");
+    service(AgeAccounting::EmitAge).oneshot_ready(request()).await.expect("store");
+
+    let served = created + Duration::from_secs(540);
+    *now.lock().expect("lock") = served;
+
+    // (strategy, expected Date, expected Age)
+    let strategies = [
+        (AgeAccounting::EmitAge, created, Some(600)),
+        (AgeAccounting::RefreshDate, served, None),
+        (AgeAccounting::Both, served, Some(600)),
+    ];
+
+    for (age_accounting, expected_date, expected_age) in strategies {
+        let response = service(age_accounting).oneshot_ready(request()).await.expect("hit");
+        assert_eq!(response.extensions().get::<CacheStatus>(), Some(&CacheStatus::Hit));
+
+        let headers = response.headers();
+        let date = headers
+            .get(DATE)
+            .and_then(|date| httpdate::parse_http_date(date.to_str().ok()?).ok())
+            .expect("Date");
+        let age = headers.get(AGE).and_then(|age| age.to_str().ok()?.parse::<u64>().ok());
+        assert_eq!(date, expected_date, "{:?}: Date", age_accounting);
+        assert_eq!(age, expected_age, "{:?}: Age", age_accounting);
+
+        // Downstream current age (RFC 9111 §4.2.3), received now
+        if expected_age.is_some() {
+            let apparent_age = served.duration_since(date).unwrap_or_default().as_secs();
+            let current_age = apparent_age.max(age.unwrap_or_default());
+            assert_eq!(900 - current_age, 300, "{:?}: remaining freshness", age_accounting);
+        }
+    }
+
+    // The stored entry is unchanged
+    let cached_response = cache.get(&key("/age-accounting")).await.expect("stored");
+    assert_eq!(cached_response.created, created);
+    assert_eq!(cached_response.upstream_age, Duration::from_secs(60));
+}
+
+// Entries stored in the same instant with the same duration get jittered durations spread across
+// the configured window
+#[tokio::test]
+async fn ttl_jitter_burst() {
+    let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let cache = MockCache::default();
+    let mut service = CachingLayer::<(), MockCache>::default()
+        .cache(cache.clone())
+        .clock(move || now)
+        .cache_duration(|_context| Some(Duration::from_secs(600)))
+        .ttl_jitter(JitterConfig::new(0.1, Duration::from_secs(300)))
+        .layer(ValidatedUpstream);
+
+    let mut durations = Vec::new();
+    for index in 0..100 {
+        let path = format!("/jitter/{}", index);
+        let request = Request::get(&path).body(()).expect("Request::get");
+        service.oneshot_ready(request).await.expect("oneshot_ready");
+        let cached_response = cache.get(&key(&path)).await.expect("stored");
+        durations.push(cached_response.duration.expect("duration").as_secs_f64());
+    }
+
+    let min = durations.iter().copied().fold(f64::MAX, f64::min);
+    let max = durations.iter().copied().fold(f64::MIN, f64::max);
+    let mean = durations.iter().sum::<f64>() / durations.len() as f64;
+    assert!(min >= 540.0 && max <= 660.0, "window: {}..{}", min, max);
+    assert!(min < 560.0 && max > 640.0, "spread: {}..{}", min, max);
+    assert!((mean - 600.0).abs() < 15.0, "mean: {}", mean);
+}
+
+// Heuristic freshness is a fraction of the time since Last-Modified, clamped, not applicable to a
+// future Last-Modified, and overridden by an explicit duration
+#[tokio::test]
+async fn heuristic_freshness() {
+    const DAY: u64 = 60 * 60 * 24;
+
+    let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let upstream = service_fn(move |request: Request<()>| async move {
+        let mut response = Response::new(FramesBody::from(ImmutableBytes::from(b"hello".to_vec())));
+        let headers = response.headers_mut();
+        let last_modified = match request.uri().path() {
+            "/recent" => now - Duration::from_secs(5),
+            "/future" => now + Duration::from_secs(DAY),
+            _ => now - Duration::from_secs(10 * DAY),
+        };
+        let last_modified = HeaderValue::from_str(&httpdate::fmt_http_date(last_modified));
+        headers.insert(LAST_MODIFIED, last_modified.expect("Last-Modified"));
+        if request.uri().path() == "/explicit" {
+            headers.insert("xx-cache-duration", HeaderValue::from_static("5m"));
+        }
+        Ok::<_, io::Error>(response)
+    });
+
+    let cache = MockCache::default();
+    let mut service = CachingLayer::<(), MockCache>::default()
+        .cache(cache.clone())
+        .clock(move || now)
+        .heuristic_freshness(HeuristicConfig {
+            fraction: 0.1,
+            min: Duration::from_secs(60),
+            max: Duration::from_secs(DAY),
+            require_last_modified: true,
+        })
+        .layer(upstream);
+
+    let paths = [
+        ("/ancient", Some(DAY)),
+        ("/recent", Some(60)),
+        ("/future", None),
+        ("/explicit", Some(5 * 60)),
+    ];
+
+    for (path, expected_duration) in paths {
+        let request = Request::get(path).body(()).expect("Request::get");
+        service.oneshot_ready(request).await.expect(path);
+
+        let cached_response = cache.get(&key(path)).await.expect(path);
+        assert_eq!(
+            cached_response.duration,
+            expected_duration.map(Duration::from_secs),
+            "{}",
+            path
+        );
+    }
+}
+
+// Synthetic Last-Modified (whole seconds of the clock) is given according to the mode and the
+// per-response opt-out, so that micro-cached entries need not get spurious 304s, and a data version
+// gives a validator that replicas with different clocks agree on
+#[tokio::test]
+async fn synthetic_last_modified() {
+    let now = SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_600);
+    let upstream = service_fn(|request: Request<()>| async move {
+        let mut response = Response::new(FramesBody::from(ImmutableBytes::from(b"live".to_vec())));
+        let path = request.uri().path();
+        let duration = if path == "/feed" { "2s" } else { "1m" };
+        let headers = response.headers_mut();
+        headers.insert("xx-cache-duration", HeaderValue::from_static(duration));
+        if path == "/opted-out" {
+            headers.insert(XX_NO_SYNTHETIC_VALIDATORS, HeaderValue::from_static("true"));
+        }
+        if path == "/versioned" {
+            response.extensions_mut().insert(RoutePolicy::default().data_version(7));
+        }
+        Ok::<_, io::Error>(response)
+    });
+
+    let layer = |now: SystemTime, mode| {
+        CachingLayer::<(), MockCache>::default()
+            .cache(MockCache::default())
+            .clock(move || now)
+            .synthetic_last_modified(mode)
+            .layer(upstream)
+    };
+
+    let whole_seconds = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let last_modified = httpdate::fmt_http_date(whole_seconds);
+    let synthetic = Some((LAST_MODIFIED, last_modified.as_str()));
+    let versioned = Some((ETAG, "W/\"v7\""));
+
+    // Modes and expected validators for /feed (2 seconds), /page, /opted-out, and /versioned
+    let modes = [
+        (
+            SyntheticLastModified::Always,
+            [synthetic.clone(), synthetic.clone(), None, versioned.clone()],
+        ),
+        (SyntheticLastModified::Never, [None, None, None, None]),
+        (
+            SyntheticLastModified::OnlyIfDurationAtLeast(Duration::from_secs(10)),
+            [None, synthetic, None, versioned],
+        ),
+    ];
+
+    for (mode, expected_validators) in modes {
+        let mut service = layer(now, mode);
+        let paths = ["/feed", "/page", "/opted-out", "/versioned"];
+
+        for (path, expected_validator) in paths.into_iter().zip(expected_validators) {
+            let request = Request::get(path).body(()).expect("Request::get");
+            let response = service.oneshot_ready(request).await.expect(path);
+            let headers = response.headers();
+            assert!(!headers.contains_key(XX_NO_SYNTHETIC_VALIDATORS), "{:?} {}", mode, path);
+            for name in [LAST_MODIFIED, ETAG] {
+                let value = headers.get(&name).map(|value| value.to_str().expect("value"));
+                let expected = expected_validator
+                    .as_ref()
+                    .filter(|(expected_name, _)| *expected_name == name)
+                    .map(|(_, expected)| *expected);
+                assert_eq!(value, expected, "{:?} {}: {}", mode, path, name);
+            }
+
+            // Entries without validators are served in full
+            let request = match path {
+                "/versioned" => Request::get(path).header(IF_NONE_MATCH, "W/\"v7\""),
+                _ => Request::get(path).header(IF_MODIFIED_SINCE, last_modified.as_str()),
+            };
+            let request = request.body(()).expect("Request::get");
+            let response = service.oneshot_ready(request).await.expect(path);
+            let expected_status = match expected_validator {
+                Some(_) => StatusCode::NOT_MODIFIED,
+                None => StatusCode::OK,
+            };
+            assert_eq!(response.status(), expected_status, "{:?} {}: conditional", mode, path);
+        }
+    }
+
+    // Replicas with different clocks
+    for offset in [Duration::ZERO, Duration::from_millis(3_700)] {
+        let mut service = layer(now + offset, SyntheticLastModified::Always);
+        let request = Request::get("/versioned").body(()).expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect("versioned");
+        let etag = response.headers().get(ETAG);
+        assert_eq!(etag, Some(&HeaderValue::from_static("W/\"v7\"")), "{:?}", offset);
+    }
+}
+
+// Negotiated languages fall back from specific to general and then to the default, so the entries
+// are bounded by the supported languages, and the response names the language it was served in
+#[tokio::test]
+async fn language_negotiation() {
+    let cache = SimpleLruCache::new(1024 * 1024, None);
+    let supported = ["en", "de", "fr"].into_iter().map(Language::from).collect();
+    let mut service = CachingLayer::<(), SimpleLruCache>::default()
+        .cache(cache.clone())
+        .negotiate_languages(supported)
+        .layer(ValidatedUpstream);
+
+    // Steps: Accept-Language, expected status, expected Content-Language, expected entries
+    let steps = [
+        (Some("de-AT"), "MISS", "de", 1),
+        (Some("de-CH, en;q=0.5"), "HIT", "de", 1),
+        (Some("xx-klingon"), "MISS", "en", 2),
+        (None, "HIT", "en", 2),
+        (Some("xx-klingon-bot, ;;q=banana, *"), "HIT", "en", 2),
+        (Some("de;q=0, fr;q=0.5"), "MISS", "fr", 3),
+        (Some("de-AT;q=0"), "HIT", "en", 3),
+    ];
+
+    for (accept_language, expected_status, expected_language, expected_len) in steps {
+        let mut request = Request::get("/language");
+        if let Some(accept_language) = accept_language {
+            request = request.header(ACCEPT_LANGUAGE, accept_language);
+        }
+        let request = request.body(()).expect("Request::get");
+
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+        let headers = response.headers();
+        let status = response.extensions().get::<CacheStatus>().map(CacheStatus::as_str);
+        assert_eq!(status, Some(expected_status), "{:?}: status", accept_language);
+        assert_eq!(
+            headers.get(CONTENT_LANGUAGE),
+            Some(&HeaderValue::from_static(expected_language)),
+            "{:?}",
+            accept_language
+        );
+        assert!(
+            headers.get_all(VARY).iter().any(|vary| vary == "Accept-Language"),
+            "{:?}: Vary",
+            accept_language
+        );
+        assert_eq!(cache.len(), expected_len, "{:?}: entries", accept_language);
+    }
+}
+
+// A route policy applies to its own response only
+#[tokio::test]
+async fn route_policy() {
+    let cache = SimpleLruCache::new(1024 * 1024, None);
+    let upstream = service_fn(|request: Request<()>| async move {
+        let mut response = Response::new(FramesBody::from(ImmutableBytes::from(b"hello".to_vec())));
+        if request.uri().path() == "/uncacheable" {
+            response.extensions_mut().insert(RoutePolicy::default().cacheable(false));
+        }
+        Ok::<_, io::Error>(response)
+    });
+    let mut service =
+        CachingLayer::<(), SimpleLruCache>::default().cache(cache.clone()).layer(upstream);
+
+    let request = |path| Request::get(path).body(()).expect("Request::get");
+
+    service.oneshot_ready(request("/uncacheable")).await.expect("uncacheable");
+    assert!(cache.is_empty(), "uncacheable: stored");
+
+    service.oneshot_ready(request("/cacheable")).await.expect("cacheable");
+    assert_eq!(cache.len(), 1, "cacheable: entries");
+}
+
+// A policy attached to an axum route overrides the layer's configuration for that route only,
+// wherever the route is mounted, and is neither stored nor served
+#[cfg(feature = "axum")]
+#[tokio::test]
+async fn axum_route_policy() {
+    use ::axum::{Router, body::Body, routing::get};
+    use tower_http_response_cache::cache::axum::CachedRoute;
+
+    let cache = SimpleLruCache::new(1024 * 1024, None);
+    let large = || get(("hello, world",));
+    let router = Router::new()
+        .route("/default", large())
+        .route("/policy", large().cache(RoutePolicy::default().max_body_size(1024)));
+    let mut service = CachingLayer::<Body, SimpleLruCache>::default()
+        .cache(cache.clone())
+        .max_cacheable_body_size(4)
+        .layer(router);
+
+    let mut status = async |path: &'static str| {
+        let request = Request::get(path).body(Body::empty()).expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect(path);
+        assert!(response.extensions().get::<RoutePolicy>().is_none(), "{}: served", path);
+        response.extensions().get::<CacheStatus>().map(CacheStatus::as_str)
+    };
+
+    assert_eq!(status("/default").await, Some("MISS"));
+    assert_eq!(status("/default").await, Some("MISS"));
+    assert_eq!(status("/policy").await, Some("MISS"));
+    assert_eq!(status("/policy").await, Some("HIT"));
+    assert_eq!(cache.len(), 1, "entries");
+
+    let cached_response = cache.get(&key("/policy")).await.expect("stored");
+    assert!(cached_response.parts.extensions.get::<RoutePolicy>().is_none(), "stored");
+
+    // The same handler, mounted elsewhere, keeps its policy
+    let router = Router::new().route(
+        "/renamed",
+        large().cache(RoutePolicy::default().max_body_size(1024)),
+    );
+    let mut service = CachingLayer::<Body, SimpleLruCache>::default()
+        .cache(cache.clone())
+        .max_cacheable_body_size(4)
+        .layer(router);
+    for expected_status in ["MISS", "HIT"] {
+        let request = Request::get("/renamed").body(Body::empty()).expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect("renamed");
+        let status = response.extensions().get::<CacheStatus>().map(CacheStatus::as_str);
+        assert_eq!(status, Some(expected_status), "renamed");
+    }
+}
+
+// Layers attached to the outer router and to a nested router key the same request identically,
+// and a flood of requests for unmatched paths creates no entries
+#[cfg(feature = "axum")]
+#[tokio::test]
+async fn axum_nesting() {
+    use ::axum::{Router, body::Body, routing::get};
+
+    let layer = |cache: &SimpleLruCache| {
+        CachingLayer::<Body, SimpleLruCache>::default()
+            .cache(cache.clone())
+            .never_cache_unmatched(true)
+    };
+    let items = || Router::new().route("/items", get(("items",)));
+
+    let outer_cache = SimpleLruCache::new(1024 * 1024, None);
+    let outer = Router::new()
+        .nest("/api/v1", items())
+        .layer(layer(&outer_cache));
+
+    let nested_cache = SimpleLruCache::new(1024 * 1024, None);
+    let nested = Router::new().nest("/api/v1", items().layer(layer(&nested_cache)));
+
+    for (name, router, cache) in [("outer", outer, outer_cache), ("nested", nested, nested_cache)] {
+        let request = Request::get("/api/v1/items").body(Body::empty()).expect("Request::get");
+        let response = router.clone().oneshot(request).await.expect(name);
+        assert_eq!(response.status(), StatusCode::OK, "{}", name);
+
+        assert!(cache.get(&key("/api/v1/items")).await.is_some(), "{}: key", name);
+        assert_eq!(cache.len(), 1, "{}: entries", name);
+
+        for index in 0..20 {
+            let path = format!("/api/v1/probe-{}", index);
+            let request = Request::get(&path).body(Body::empty()).expect("Request::get");
+            let response = router.clone().oneshot(request).await.expect(&path);
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}: {}", name, path);
+        }
+        assert_eq!(cache.len(), 1, "{}: entries after flood", name);
+    }
+}
+
+// Every path carries the cache status extension, and the exposed header agrees with it: misses,
+// hits, conditional hits, bypassed requests, admin requests, and responses whose body failed
+#[tokio::test]
+async fn cache_status_header() {
+    let x_cache = HeaderName::from_static("x-cache");
+    let admin_methods = AdminMethodConfig::trusting_peers(|ip| ip.is_loopback());
+    let mut service = CachingLayer::<(), MockCache>::default()
+        .cache(MockCache::default())
+        .bypass_cache_on_headers(&[COOKIE])
+        .admin_methods(admin_methods)
+        .expose_cache_status_header(x_cache.clone())
+        .layer(ValidatedUpstream);
+
+    // (method, header, expected status code, expected header, expected extension)
+    type Case = (&'static str, Option<(HeaderName, HeaderValue)>, StatusCode, &'static str);
+    type Check = fn(&CacheStatus) -> bool;
+    let cases: [(Case, Check); 6] = [
+        (("GET", None, StatusCode::OK, "MISS"), |status| *status == CacheStatus::Miss),
+        (("GET", None, StatusCode::OK, "HIT"), |status| *status == CacheStatus::Hit),
+        (
+            (
+                "GET",
+                Some((IF_NONE_MATCH, ValidatedUpstream::etag())),
+                StatusCode::NOT_MODIFIED,
+                "HIT",
+            ),
+            |status| *status == CacheStatus::HitNotModified,
+        ),
+        (
+            ("GET", Some((COOKIE, HeaderValue::from_static("a=1"))), StatusCode::OK, "BYPASS"),
+            |status| matches!(status, CacheStatus::Bypass { .. }),
+        ),
+        (("PURGE", None, StatusCode::OK, "BYPASS"), |status| {
+            *status == CacheStatus::Bypass { reason: "admin" }
+        }),
+        (("GET", None, StatusCode::OK, "MISS"), |status| *status == CacheStatus::Miss),
+    ];
+
+    for ((method, header, expected_code, expected_header), expected_status) in cases {
+        let mut request =
+            Request::builder().method(method).uri("/status").body(()).expect("Request::builder");
+        request.extensions_mut().insert(SocketAddr::from(([127, 0, 0, 1], 4000)));
+        if let Some((name, value)) = header {
+            request.headers_mut().insert(name, value);
+        }
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+        assert_eq!(response.status(), expected_code, "{} {}", method, expected_header);
+
+        let status = response.extensions().get::<CacheStatus>().copied().expect("extension");
+        assert!(expected_status(&status), "{} {}: {}", method, expected_header, status);
+        let header = response.headers().get(&x_cache).expect("header");
+        assert_eq!(header, expected_header, "{}", status);
+    }
+
+    // The response is an error, but it was still looked up
+    let failing = service_fn(|_request: Request<()>| async {
+        Ok::<_, io::Error>(Response::new(FailingBody))
+    });
+    let service = CachingLayer::<(), MockCache>::default()
+        .cache(MockCache::default())
+        .expose_cache_status_header(x_cache.clone())
+        .layer(failing);
+    let request = Request::get("/failing").body(()).expect("Request::get");
+    let response = service.oneshot(request).await.expect("oneshot");
+    assert_eq!(response.extensions().get::<CacheStatus>(), Some(&CacheStatus::Miss));
+    assert_eq!(response.headers().get(&x_cache).expect("header"), "MISS");
+}
+
+// Body that fails on its first frame
+struct FailingBody;
+
+impl Body for FailingBody {
+    type Data = ImmutableBytes;
+    type Error = io::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        _context: &mut task::Context<'_>,
+    ) -> task::Poll<Option<std::result::Result<Frame<Self::Data>, Self::Error>>> {
+        task::Poll::Ready(Some(Err(io::Error::other("failing"))))
+    }
+}
+
+impl From<ImmutableBytes> for FailingBody {
+    fn from(_bytes: ImmutableBytes) -> Self {
+        Self
+    }
+}
+
+// Dependency tokens recorded by the handler are stored with the entry, which is then purged by
+// any of them (and only by them), the per-entry token limits are enforced, and the extension is
+// never sent to the client
+#[tokio::test]
+async fn dependency_tokens() {
+    let cache = DependencyCache::new(SimpleLruCache::new(1024 * 1024, None));
+    let upstream = service_fn(|request: Request<()>| async move {
+        let dependencies = request.extensions().get::<CacheDependencies>().expect("extension");
+        match request.uri().path() {
+            "/products/42" => {
+                assert!(dependencies.record("product:42"));
+                assert!(dependencies.record("category:7"));
+                assert!(dependencies.record("product:42"), "duplicate");
+            }
+
+            "/products/43" => {
+                assert!(dependencies.record("product:43"));
+                assert!(dependencies.record("category:7"));
+            }
+
+            "/many" => {
+                assert!(!dependencies.record(""), "empty");
+                assert!(!dependencies.record(&"x".repeat(17)), "too long");
+                assert!(dependencies.record("first"));
+                assert!(dependencies.record("second"));
+                assert!(!dependencies.record("third"), "too many");
+            }
+
+            _ => {}
+        }
+        let body = ImmutableBytes::from(b"hello".to_vec());
+        Ok::<_, io::Error>(Response::new(FramesBody::from(body)))
+    });
+    let mut service = CachingLayer::<(), DependencyCache<SimpleLruCache>>::default()
+        .cache(cache.clone())
+        .record_dependencies(2, 16)
+        .layer(upstream);
+
+    let mut status = async |path: &'static str| {
+        let request = Request::get(path).body(()).expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+        assert!(response.extensions().get::<CacheDependencies>().is_none(), "{}: leaked", path);
+        response.extensions().get::<CacheStatus>().map(CacheStatus::as_str)
+    };
+
+    for path in ["/products/42", "/products/43", "/about", "/many"] {
+        assert_eq!(status(path).await, Some("MISS"), "{}", path);
+        assert_eq!(status(path).await, Some("HIT"), "{}", path);
+    }
+
+    // product:42, category:7, product:43, first, second
+    assert_eq!(cache.token_count(), 5);
+
+    // Either token purges the entry, leaving the others
+    assert_eq!(cache.purge_dependency("product:42").await, 1);
+    assert_eq!(status("/products/42").await, Some("MISS"));
+    assert_eq!(status("/products/43").await, Some("HIT"));
+    assert_eq!(status("/about").await, Some("HIT"));
+
+    assert_eq!(cache.purge_dependency("category:7").await, 2);
+    assert_eq!(status("/products/42").await, Some("MISS"));
+    assert_eq!(status("/products/43").await, Some("MISS"));
+    assert_eq!(status("/about").await, Some("HIT"));
+
+    // Ignored tokens were not stored
+    assert_eq!(cache.purge_dependency("third").await, 0);
+    assert_eq!(status("/many").await, Some("HIT"));
+    assert_eq!(cache.purge_dependency("second").await, 1);
+    assert_eq!(status("/many").await, Some("MISS"));
+}
+
+// Dependency TTLs recorded anywhere during the request bound the entry's duration, the shortest
+// one is attributed in the response and the store event, a shorter explicit duration still wins,
+// and requests recording no bounds keep their duration
+#[tokio::test]
+async fn dependency_ttls() {
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    // Knows nothing about the page that embeds it
+    fn render_ads(dependencies: &CacheDependencies) {
+        dependencies.record_with_ttl("ads:1", Duration::from_secs(5 * 60));
+    }
+
+    let upstream = service_fn(|request: Request<()>| async move {
+        let dependencies = request.extensions().get::<CacheDependencies>().expect("extension");
+        let path = request.uri().path();
+        if path != "/plain" {
+            dependencies.record_with_ttl("pricing:42", HOUR);
+            dependencies.record_with_ttl("reviews:42", 7 * 24 * HOUR);
+        }
+        if path == "/with-ads" {
+            render_ads(dependencies);
+        }
+
+        let mut response = Response::new(FramesBody::from(ImmutableBytes::from(b"page".to_vec())));
+        let duration = match path {
+            "/explicit-short" => Some("1m"),
+            "/explicit-long" => Some("1d"),
+            _ => None,
+        };
+        if let Some(duration) = duration {
+            response.headers_mut().insert(XX_CACHE_DURATION, HeaderValue::from_static(duration));
+        }
+        Ok::<_, io::Error>(response)
+    });
+
+    let cache = MockCache::default();
+    let constraints = Arc::new(Mutex::new(Vec::default()));
+    let mut service = CachingLayer::<(), MockCache>::default()
+        .cache(cache.clone())
+        .record_dependencies(8, 32)
+        .on_cache_event({
+            let constraints = constraints.clone();
+            move |event: CacheEvent<CommonCacheKey>| {
+                if let CacheEventKind::Store { constraint, .. } = event.kind {
+                    constraints.lock().expect("lock").push(constraint);
+                }
+            }
+        })
+        .layer(upstream);
+
+    // (path, expected duration, expected constraining token)
+    let cases = [
+        ("/page", Some(HOUR), Some("pricing:42")),
+        ("/with-ads", Some(Duration::from_secs(5 * 60)), Some("ads:1")),
+        ("/explicit-short", Some(Duration::from_secs(60)), None),
+        ("/explicit-long", Some(HOUR), Some("pricing:42")),
+        ("/plain", None, None),
+    ];
+
+    for (path, expected_duration, expected_token) in cases {
+        let request = Request::get(path).body(()).expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+        let status = response.extensions().get::<CacheStatus>().map(CacheStatus::as_str);
+        assert_eq!(status, Some("MISS"), "{}", path);
+        let token = |bound: &DependencyBound| bound.token.to_string();
+        let attributed = response.extensions().get::<DependencyBound>().map(token);
+        assert_eq!(attributed.as_deref(), expected_token, "{}: extension", path);
+        assert_eq!(decoded_body(response).await, b"page", "{}", path);
+
+        let stored = cache.get(&key(path)).await.expect("stored");
+        assert_eq!(stored.duration, expected_duration, "{}: duration", path);
+
+        let constraint = constraints.lock().expect("lock").pop().expect("store event");
+        assert_eq!(constraint.as_ref().map(token).as_deref(), expected_token, "{}: event", path);
+        if let Some(constraint) = constraint {
+            assert_eq!(Some(constraint.ttl), expected_duration, "{}: event", path);
+        }
+    }
+}
+
+// POSTs keyed by their body are cached per body and the upstream still gets the whole body;
+// bodies that are undeclared or over the limit skip the cache but are passed on, a body longer
+// than declared is a bad request, and listing POST without a body key never caches it
+#[tokio::test]
+async fn request_body_keys() {
+    const LONG: &str = "{ everything { and more } } { everything { and more } }";
+
+    let calls = Arc::new(atomic::AtomicUsize::default());
+    let upstream = service_fn({
+        let calls = calls.clone();
+        move |request: Request<FramesBody>| {
+            calls.fetch_add(1, atomic::Ordering::SeqCst);
+            async move {
+                let query = body_bytes(request.into_body()).await;
+                let body = format!("result for {}", String::from_utf8_lossy(&query));
+                Ok::<_, io::Error>(Response::new(FramesBody::from(ImmutableBytes::from(
+                    body.into_bytes(),
+                ))))
+            }
+        }
+    });
+
+    let layer = || {
+        CachingLayer::<FramesBody, MockCache>::default()
+            .cache(MockCache::default())
+            .cacheable_methods(&[Method::GET, Method::POST])
+    };
+    let keyed = layer().cache_key_with_body(32, |context| {
+        context.cache_key.extensions.get_or_insert_default().insert(
+            ImmutableBytes::from(b"query".to_vec()),
+            context.body.clone(),
+        );
+    });
+    let mut service = keyed.layer(upstream.clone());
+
+    // (method, body frames, declared length, expected status code, expected cache status,
+    // expected calls)
+    let cases: [(Method, &[&str], _, _, _, _); 8] = [
+        (Method::POST, &["{ a }"], Some(5), StatusCode::OK, "MISS", 1),
+        (Method::POST, &["{ a }"], Some(5), StatusCode::OK, "HIT", 1),
+        (Method::POST, &["{ b }"], Some(5), StatusCode::OK, "MISS", 2),
+        (Method::POST, &["{ a", " }"], None, StatusCode::OK, "BYPASS", 3),
+        (Method::POST, &[LONG], Some(LONG.len()), StatusCode::OK, "BYPASS", 4),
+        (Method::POST, &["{ a }"], Some(3), StatusCode::BAD_REQUEST, "BYPASS", 4),
+        (Method::GET, &[], None, StatusCode::OK, "MISS", 5),
+        (Method::GET, &[], None, StatusCode::OK, "HIT", 5),
+    ];
+
+    for (method, frames, declared, expected_code, expected_status, expected_calls) in cases {
+        let frames_body = frames.iter().map(|frame| frame.as_bytes().to_vec());
+        let body = FramesBody(frames_body.map(|bytes| Frame::data(bytes.into())).collect());
+        let mut request =
+            Request::builder().method(method.clone()).uri("/graphql").body(body).expect("request");
+        if let Some(declared) = declared {
+            request.headers_mut().insert(CONTENT_LENGTH, declared.into());
+        }
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+        let description = format!("{} {:?}", method, frames);
+        assert_eq!(response.status(), expected_code, "{}", description);
+        let status = response.extensions().get::<CacheStatus>().map(CacheStatus::as_str);
+        assert_eq!(status, Some(expected_status), "{}", description);
+        assert_eq!(calls.load(atomic::Ordering::SeqCst), expected_calls, "{}", description);
+
+        if expected_code == StatusCode::OK {
+            let expected_body = format!("result for {}", frames.concat());
+            assert_eq!(decoded_body(response).await, expected_body.as_bytes(), "{}", description);
+        }
+    }
+
+    // Without a body key
+    calls.store(0, atomic::Ordering::SeqCst);
+    let mut service = layer().layer(upstream);
+    for _ in 0..2 {
+        let request = Request::post("/graphql")
+            .header(CONTENT_LENGTH, 5)
+            .body(FramesBody::from(ImmutableBytes::from(b"{ a }".to_vec())))
+            .expect("Request::post");
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+        let status = response.extensions().get::<CacheStatus>().map(CacheStatus::as_str);
+        assert_eq!(status, Some("BYPASS"));
+        assert_eq!(decoded_body(response).await, b"result for { a }");
+    }
+    assert_eq!(calls.load(atomic::Ordering::SeqCst), 2);
+}
+
+// Each configuration rule fires for its conflicting settings and not otherwise, all diagnostics
+// are listed together, and warnings (but not errors) can be suppressed
+#[test]
+fn configuration_diagnostics() {
+    type Layer = CachingLayer<(), SimpleLruCache>;
+    let layer = || Layer::default().cache(SimpleLruCache::new(1024 * 1024, None));
+    let codes = |layer: &Layer| {
+        layer.diagnostics().iter().map(|diagnostic| diagnostic.code).collect::<Vec<_>>()
+    };
+
+    // (configure, expected codes)
+    type Configure = fn(Layer) -> Layer;
+    let cases: [(Configure, &[&str]); 16] = [
+        (|layer| layer, &[]),
+        (|layer| layer.min_cacheable_body_size(10).max_cacheable_body_size(5), &["E001"]),
+        (|layer| layer.min_cacheable_body_size(5).max_cacheable_body_size(10), &[]),
+        (|layer| layer.disable_encoding().min_encodable_body_size(100), &["W001"]),
+        (|layer| layer.min_encodable_body_size(100), &[]),
+        (|layer| layer.disable_encoding().keep_identity_encoding(false), &["W002"]),
+        (|layer| layer.enable_encodings(vec![]).keep_identity_encoding(false), &["W002"]),
+        (|layer| layer.keep_identity_encoding(false), &[]),
+        (
+            |layer| {
+                layer
+                    .cache_duration(|_context| Some(Duration::from_secs(60)))
+                    .respect_cache_control(true)
+            },
+            &["W003"],
+        ),
+        (|layer| layer.cache_duration(|_context| Some(Duration::from_secs(60))), &[]),
+        (|_layer| Layer::default().record_dependencies(2, 16), &["W004"]),
+        (|_layer| Layer::default(), &[]),
+        (|layer| layer.cacheable_methods(&[Method::GET, Method::POST]), &["W005"]),
+        (|layer| layer.cacheable_methods(&[Method::GET, Method::HEAD]), &[]),
+        (
+            |layer| layer.strict_privacy(true).partition_cache_by_headers(&[AUTHORIZATION]),
+            &["W006"],
+        ),
+        (|layer| layer.partition_cache_by_headers(&[AUTHORIZATION]), &[]),
+    ];
+
+    for (index, (configure, expected)) in cases.into_iter().enumerate() {
+        assert_eq!(codes(&configure(layer())), expected, "case {}", index);
+    }
+
+    // All diagnostics are listed, in rule order, and errors fail the build
+    let conflicting = || {
+        layer()
+            .min_cacheable_body_size(10)
+            .max_cacheable_body_size(5)
+            .disable_encoding()
+            .min_encodable_body_size(100)
+            .cache_duration(|_context| Some(Duration::from_secs(60)))
+            .respect_cache_control(true)
+    };
+    assert_eq!(codes(&conflicting()), ["E001", "W001", "W003"]);
+    let error = match conflicting().build_checked(ValidatedUpstream) {
+        Ok(_) => panic!("built"),
+        Err(error) => error,
+    };
+    assert_eq!(error.diagnostics.len(), 3);
+    let message = error.to_string();
+    for code in ["E001", "W001", "W003"] {
+        assert!(message.contains(code), "{}: {}", code, message);
+    }
+
+    // Warnings are suppressed by their codes, errors are not
+    let allowed = conflicting().allow("W001").allow("W003").allow("E001");
+    assert_eq!(codes(&allowed), ["E001"]);
+
+    // Warnings alone do not fail the build
+    let warned = layer().allow("W001").disable_encoding().min_encodable_body_size(100);
+    assert!(codes(&warned).is_empty());
+    assert!(warned.build_checked(ValidatedUpstream).is_ok());
+    let warned = layer().cacheable_methods(&[Method::POST]);
+    assert_eq!(codes(&warned), ["W005"]);
+    assert!(warned.build_checked(ValidatedUpstream).is_ok());
+}
+
+// The policy description reflects the configuration in consumer terms, leaves out internal
+// details unless opted in, and describes runtime overrides and registered routes live
+#[test]
+fn describe_policy() {
+    let tenant = HeaderName::from_static("x-tenant");
+    let layer = CachingLayer::<(), SimpleLruCache>::default()
+        .cache(SimpleLruCache::new(1024 * 1024, None))
+        .methods(MethodPolicy::GetOnly)
+        .respect_cache_control(true)
+        .cache_duration(|_context| None)
+        .allow("W003")
+        .strict_privacy(true)
+        .partition_cache_by_headers(std::slice::from_ref(&tenant))
+        .max_cacheable_body_size(64 * 1024)
+        .enable_encodings(vec![EncodingHeaderValue::Brotli, EncodingHeaderValue::GZip]);
+
+    let description = layer.describe_policy();
+    assert_eq!(description.methods, [Method::GET]);
+    assert_eq!(
+        description.duration_sources,
+        ["upstream", "route", "application", "Cache-Control", "default"]
+    );
+    assert_eq!((description.min_body_size, description.max_body_size), (0, 64 * 1024));
+    assert_eq!(description.key_headers, [ACCEPT_ENCODING, tenant]);
+    for condition in ["request Authorization", "response Cache-Control max-age=0"] {
+        assert!(description.bypass_conditions.contains(&condition), "{}", condition);
+    }
+    assert_eq!(description.encodings, ["br", "gzip"]);
+    assert_eq!(description.operational_override, None);
+
+    let markdown = description.to_markdown();
+    assert!(markdown.contains("| Cached methods | GET |\n"), "{}", markdown);
+    assert!(markdown.contains("| Key headers | accept-encoding, x-tenant |\n"), "{}", markdown);
+
+    // Internal details are left out
+    assert!(description.control_headers.is_empty());
+    for internal in ["XX-", "xx-", "hook", "Lru"] {
+        assert!(!markdown.contains(internal), "{}: {}", internal, markdown);
+    }
+
+    // Unless opted in
+    let describer = layer.policy_describer().control_headers(true);
+    assert!(describer.describe().control_headers.contains(&XX_CACHE_DURATION));
+
+    // Live: an engaged override and a registered route are described on the next call
+    layer.cache_override().bypass_reads(Duration::from_secs(60));
+    describer.route("/products/{id}", &RoutePolicy::default().duration(Duration::from_secs(300)));
+    let description = describer.describe();
+    let operational_override = description.operational_override.as_deref().unwrap_or_default();
+    assert!(operational_override.starts_with("bypass reads for "), "{}", operational_override);
+    let markdown = description.to_markdown();
+    assert!(markdown.contains("| /products/{id} | default | 300s | default |"), "{}", markdown);
+
+    layer.cache_override().resume();
+    assert_eq!(describer.describe().operational_override, None);
+}
+
+// Entries are purged by the tags declared in their XX-Cache-Tags, which are not served
+#[tokio::test]
+async fn purge_by_tag() {
+    let cache = DependencyCache::new(SimpleLruCache::new(1024 * 1024, None));
+    let upstream = service_fn(|request: Request<()>| async move {
+        let mut response = Response::new(FramesBody::from(ImmutableBytes::from(b"hello".to_vec())));
+        let tags = match request.uri().path() {
+            "/products/42" => Some("product-42, category-7"),
+            "/products/43" => Some("product-43, category-7"),
+            _ => None,
+        };
+        if let Some(tags) = tags {
+            response.headers_mut().insert(XX_CACHE_TAGS, HeaderValue::from_static(tags));
+        }
+        Ok::<_, io::Error>(response)
+    });
+    let mut service = CachingLayer::<(), DependencyCache<SimpleLruCache>>::default()
+        .cache(cache.clone())
+        .layer(upstream);
+
+    let mut status = async |path: &'static str| {
+        let request = Request::get(path).body(()).expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+        assert!(!response.headers().contains_key(XX_CACHE_TAGS), "{}: tags served", path);
+        response.extensions().get::<CacheStatus>().map(CacheStatus::as_str)
+    };
+
+    for path in ["/products/42", "/products/43", "/about"] {
+        assert_eq!(status(path).await, Some("MISS"), "{}", path);
+        assert_eq!(status(path).await, Some("HIT"), "{}", path);
+    }
+
+    assert_eq!(cache.purge_dependency("product-42").await, 1);
+    assert_eq!(status("/products/42").await, Some("MISS"));
+    assert_eq!(status("/products/43").await, Some("HIT"));
+
+    assert_eq!(cache.purge_dependency("category-7").await, 2);
+    assert_eq!(status("/products/42").await, Some("MISS"));
+    assert_eq!(status("/products/43").await, Some("MISS"));
+    assert_eq!(status("/about").await, Some("HIT"));
+
+    // Entries that are gone from the inner cache are tolerated
+    cache.inner.invalidate_all().await;
+    assert_eq!(cache.purge_dependency("category-7").await, 2);
+    assert_eq!(cache.purge_dependency("unknown").await, 0);
+}
+
+// Cache whose backend is unreachable
+#[derive(Clone, Default)]
+struct UnreachableCache;
+
+impl Cache for UnreachableCache {
+    async fn get(&self, _key: &CommonCacheKey) -> Option<CachedResponseRef> {
+        None
+    }
+
+    async fn put(&self, _key: CommonCacheKey, _cached_response: CachedResponseRef) {}
+
+    async fn invalidate(&self, _key: &CommonCacheKey) {}
+
+    async fn invalidate_all(&self) {}
+
+    async fn invalidate_all_with_outcome(&self) -> InvalidationOutcome {
+        InvalidationOutcome::failed("unreachable")
+    }
+}
+
+// The reset report has the count and outcome of each tier, is a partial success if only one tier
+// failed and an error if all did, and carries the request's correlation ID; the legacy handler
+// still resets with no content
+#[cfg(feature = "axum")]
+#[tokio::test]
+async fn reset_report() {
+    use {::axum::extract::State, tower_http_response_cache::cache::axum::*};
+
+    async fn report<CacheT>(cache: CacheT) -> (StatusCode, String)
+    where
+        CacheT: Cache<CommonCacheKey>,
+    {
+        let mut headers = HeaderMap::default();
+        headers.insert("x-request-id", HeaderValue::from_static("audit-1"));
+        let response = reset_cache_report_handler::<_, CommonCacheKey>(State(cache), headers).await;
+        let content_type = response.headers().get(CONTENT_TYPE).cloned();
+        assert_eq!(content_type, Some(HeaderValue::from_static("application/json")));
+        let status = response.status();
+        let body = String::from_utf8(body_bytes(response.into_body()).await).expect("UTF-8");
+        assert!(body.contains("\"correlation_id\":\"audit-1\""), "{}", body);
+        (status, body)
+    }
+
+    let populated = async || {
+        let cache = MockCache::default();
+        for path in ["/a", "/b"] {
+            cache.put(key(path), entry("\"v1\"", None)).await;
+        }
+        cache
+    };
+    let outcome = |success, next_count, next_error| {
+        format!(
+            "\"success\":{},\"outcome\":{{\"count\":2,\"error\":null,\"tiers\":[\
+             {{\"tier\":\"first\",\"outcome\":{{\"count\":2,\"error\":null,\"tiers\":[]}}}},\
+             {{\"tier\":\"next\",\"outcome\":{{\"count\":{},\"error\":{},\"tiers\":[]}}}}]}}",
+            success, next_count, next_error
+        )
+    };
+
+    // Both tiers succeed
+    let (first, next) = (populated().await, populated().await);
+    let (status, body) = report(TieredCache::new(first.clone(), next.clone())).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert!(body.contains(&outcome(true, "2", "null")), "{}", body);
+    assert_eq!((first.entry_count(), next.entry_count()), (Some(0), Some(0)));
+
+    // The next tier is unreachable
+    let first = populated().await;
+    let (status, body) = report(TieredCache::new(first.clone(), UnreachableCache)).await;
+    assert_eq!(status, StatusCode::MULTI_STATUS, "{}", body);
+    assert!(body.contains(&outcome(false, "null", "\"unreachable\"")), "{}", body);
+    assert_eq!(first.entry_count(), Some(0), "{}", body);
+
+    // Both tiers are unreachable
+    let (status, body) = report(TieredCache::new(UnreachableCache, UnreachableCache)).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR, "{}", body);
+
+    // Legacy
+    let cache = populated().await;
+    let response = reset_cache_handler::<_, CommonCacheKey>(State(cache.clone())).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(cache.entry_count(), Some(0));
+}
+
+// Warming stores cacheable responses exactly as a client request would, reports entries that
+// already exist, uncacheable responses, and upstream errors, and entries made from bytes in hand
+// are served too, all without calling a failing upstream again
+#[tokio::test]
+async fn warm_cache() {
+    let body = "Hello, world!\n".repeat(100);
+    let upstream = service_fn({
+        let body = body.clone();
+        move |request: Request<()>| {
+            let body = ImmutableBytes::from(body.clone().into_bytes());
+            async move {
+                let mut response = Response::new(FramesBody::from(body));
+                match request.uri().path() {
+                    "/error" => return Err(io::Error::other("upstream error")),
+                    "/uncacheable" => {
+                        response.headers_mut().insert(XX_CACHE, HeaderValue::from_static("false"));
+                    }
+                    _ => {}
+                }
+                Ok::<_, io::Error>(response)
+            }
+        }
+    });
+    let failing = service_fn(|_request: Request<()>| async {
+        Err::<Response<FramesBody>, _>(io::Error::other("failing"))
+    });
+
+    let cache = MockCache::default();
+    let layer = CachingLayer::<(), MockCache>::default().cache(cache.clone());
+    let mut warming = layer.layer(upstream);
+    let mut serving = layer.layer(failing);
+
+    let requests = ["/a", "/b", "/a", "/uncacheable", "/error"]
+        .map(|path| Request::get(path).body(()).expect("Request::get"));
+    let report = warming.warm(requests).await;
+    let outcomes: Vec<_> =
+        report.outcomes.iter().map(|(uri, outcome)| (uri.path(), outcome.clone())).collect();
+    assert_eq!(
+        outcomes,
+        [
+            ("/a", WarmOutcome::Stored),
+            ("/b", WarmOutcome::Stored),
+            ("/a", WarmOutcome::Cached),
+            ("/uncacheable", WarmOutcome::Skipped { reason: "uncacheable" }),
+            ("/error", WarmOutcome::Error("upstream error".into())),
+        ]
+    );
+    assert_eq!((report.stored(), report.cached(), report.skipped(), report.errors()), (2, 1, 1, 1));
+
+    // A pre-rendered page
+    let caching = MiddlewareCachingConfiguration::<(), (), CommonCacheKey>::default().inner;
+    let encoding = MiddlewareEncodingConfiguration::default().inner;
+    let (mut parts, _) = Response::new(()).into_parts();
+    parts.headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/html"));
+    let prerendered = CachedResponse::from_bytes(
+        &Uri::from_static("/prerendered"),
+        parts,
+        ImmutableBytes::from(body.clone().into_bytes()),
+        Encoding::GZip.into(),
+        false,
+        &caching,
+        &encoding,
+    )
+    .await
+    .expect("from_bytes");
+    cache.put(key("/prerendered"), Arc::new(prerendered)).await;
+
+    for path in ["/a", "/b", "/prerendered"] {
+        let request =
+            Request::get(path).header(ACCEPT_ENCODING, "gzip").body(()).expect("Request::get");
+        let response = serving.oneshot_ready(request).await.expect("oneshot_ready");
+        let status = response.extensions().get::<CacheStatus>().map(CacheStatus::as_str);
+        assert_eq!(status, Some("HIT"), "{}", path);
+        assert_eq!(decoded_body(response).await, body.as_bytes(), "{}", path);
+    }
+
+    // Not warmed, so they reach the failing upstream
+    for path in ["/uncacheable", "/error"] {
+        let request = Request::get(path).body(()).expect("Request::get");
+        assert!(serving.oneshot_ready(request).await.is_err(), "{}", path);
+    }
+}
+
+// Invalidating by URI string builds the keys through the middleware's own pipeline: the query is
+// normalized, the cache_key hook applies, all language variants go, and nothing else does
+#[tokio::test]
+async fn invalidate_uri() {
+    let cache = MockCache::default();
+    let supported = ["en", "fr"].into_iter().map(Language::from).collect();
+    let layer = CachingLayer::<(), MockCache>::default()
+        .cache(cache.clone())
+        .negotiate_languages(supported)
+        .cache_key(|context: CacheKeyHookContext<CommonCacheKey, ()>| {
+            // "/t/{tenant}/..."
+            let path = context.request.uri().path();
+            if let Some(tenant) = path.strip_prefix("/t/").and_then(|path| path.split('/').next()) {
+                context.cache_key.extensions.get_or_insert_default().insert(
+                    ImmutableBytes::from(b"tenant".to_vec()),
+                    ImmutableBytes::from(tenant.as_bytes().to_vec()),
+                );
+            }
+        });
+    let mut service = layer.layer(ValidatedUpstream);
+
+    let mut status = async |uri: &str, language: &str| {
+        let request = Request::get(uri)
+            .header(ACCEPT_LANGUAGE, language)
+            .body(())
+            .expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+        response.extensions().get::<CacheStatus>().map(CacheStatus::as_str)
+    };
+
+    for (uri, language) in [
+        ("/t/acme/page?b=2&a=1", "en"),
+        ("/t/other/page?b=2&a=1", "en"),
+        ("/docs", "en"),
+        ("/docs", "fr"),
+        ("/docs/more", "en"),
+    ] {
+        assert_eq!(status(uri, language).await, Some("MISS"), "{} {}", uri, language);
+    }
+    assert_eq!(cache.entry_count(), Some(5));
+
+    let invalidate = async |uri| layer.invalidate_uri(&Uri::from_static(uri)).await;
+    assert_eq!(invalidate("/t/acme/page?a=1&b=2").await, 1);
+    assert_eq!(invalidate("/t/acme/page?a=1&b=2").await, 0, "already gone");
+    assert_eq!(invalidate("/docs").await, 2);
+    assert_eq!(invalidate("/missing").await, 0);
+
+    assert_eq!(status("/t/acme/page?b=2&a=1", "en").await, Some("MISS"));
+    assert_eq!(status("/t/other/page?b=2&a=1", "en").await, Some("HIT"));
+    assert_eq!(status("/docs", "fr").await, Some("MISS"));
+    assert_eq!(status("/docs/more", "en").await, Some("HIT"));
+}
+
+// An authorized PURGE invalidates exactly the URI's variants and a BAN the entries under a path
+// prefix, without reaching the upstream and with an audit trail, while an unauthorized PURGE is
+// passed to the upstream untouched and never cached
+#[tokio::test]
+async fn admin_methods() {
+    let upstream_calls = Arc::new(atomic::AtomicUsize::default());
+    let upstream = {
+        let upstream_calls = upstream_calls.clone();
+        service_fn(move |_request: Request<()>| {
+            upstream_calls.fetch_add(1, atomic::Ordering::SeqCst);
+            let body = ImmutableBytes::from(b"hello".to_vec());
+            ready(Ok::<_, io::Error>(Response::new(FramesBody::from(body))))
+        })
+    };
+
+    let audit = Arc::new(Mutex::new(Vec::new()));
+    let admin_methods = {
+        let audit = audit.clone();
+        AdminMethodConfig::trusting_peers(|ip| ip.is_loopback()).with_audit(move |event| {
+            audit.lock().expect("lock").push((event.verb, event.peer, event.invalidated))
+        })
+    };
+
+    let cache = MockCache::default();
+    let supported = ["en", "fr"].into_iter().map(Language::from).collect();
+    let mut service = CachingLayer::<(), MockCache>::default()
+        .cache(cache.clone())
+        .negotiate_languages(supported)
+        .admin_methods(admin_methods)
+        .layer(upstream);
+
+    let peer = SocketAddr::from(([127, 0, 0, 1], 4000));
+    let mut send = async |method: &str, path: &str, header: Option<(&str, &str)>, peer| {
+        let mut request = Request::builder().method(method).uri(path);
+        if let Some((name, value)) = header {
+            request = request.header(name, value);
+        }
+        let mut request = request.body(()).expect("Request::builder");
+        if let Some(peer) = peer {
+            request.extensions_mut().insert::<SocketAddr>(peer);
+        }
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+        (response.status(), body_bytes(response.into_body()).await)
+    };
+    let entries = || cache.keys().expect("keys").len();
+    let calls = || upstream_calls.swap(0, atomic::Ordering::SeqCst);
+
+    let stored = [("/docs/a", "en"), ("/docs/a", "fr"), ("/docs/b", "en"), ("/about", "en")];
+    for (path, language) in stored {
+        send("GET", path, Some(("accept-language", language)), None).await;
+    }
+    assert_eq!((entries(), calls()), (4, 4), "stored");
+
+    // Unauthorized
+    for _ in 0..2 {
+        assert_eq!(send("PURGE", "/docs/a", None, None).await.1, b"hello", "unauthorized");
+    }
+    assert_eq!((entries(), calls()), (4, 2), "unauthorized");
+
+    // (method, path, header, expected status, expected entries)
+    let steps = [
+        ("PURGE", "/docs/a", None, StatusCode::OK, 2),
+        ("PURGE", "/docs/a", None, StatusCode::NOT_FOUND, 2),
+        ("BAN", "/", Some(("x-ban-path-prefix", "/docs/")), StatusCode::OK, 1),
+    ];
+
+    for (method, path, header, expected_status, expected_entries) in steps {
+        let (status, _) = send(method, path, header, Some(peer)).await;
+        assert_eq!(status, expected_status, "{} {}", method, path);
+        assert_eq!(entries(), expected_entries, "{} {}: entries", method, path);
+    }
+    assert_eq!(calls(), 0, "authorized: upstream calls");
+
+    let audit = mem::take(&mut *audit.lock().expect("lock"));
+    assert_eq!(
+        audit,
+        [
+            (AdminVerb::Purge, Some(peer), 2),
+            (AdminVerb::Purge, Some(peer), 0),
+            (AdminVerb::Ban, Some(peer), 1)
+        ],
+        "audit"
+    );
+}
+
+// A paced purge of 80k entries hides them as soon as it starts, invalidates them in batches of
+// the configured size with a tick's pause after each (during which concurrent gets are served),
+// and completes with accurate counts; a cancelled purge keeps hiding the entries it didn't reach
+// until its fence is lifted
+#[cfg(feature = "paced-purge")]
+#[tokio::test(start_paused = true)]
+async fn paced_purge() {
+    const PURGED: usize = 80_000;
+    const KEPT: usize = 100;
+    const BATCH: usize = 1_000;
+    const TICK: Duration = Duration::from_millis(10);
+
+    let cache = MockCache::default();
+    for index in 0..PURGED {
+        cache.put(key(&format!("/tenant/{}", index)), entry("\"v1\"", None)).await;
+    }
+    for index in 0..KEPT {
+        cache.put(key(&format!("/other/{}", index)), entry("\"v1\"", None)).await;
+    }
+
+    let layer = CachingLayer::<(), MockCache>::default()
+        .cache(cache.clone())
+        .paced_purges(PurgePacing::new(BATCH, TICK));
+    let purge_jobs = layer.purge_jobs().expect("purge_jobs");
+    let service = layer.layer(ValidatedUpstream);
+
+    let status = |path: String| {
+        let mut service = service.clone();
+        async move {
+            let request = Request::get(path).body(()).expect("Request::get");
+            let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+            response.extensions().get::<CacheStatus>().map(CacheStatus::as_str)
+        }
+    };
+    let purge = |prefix: &str, pacing| {
+        let expression = BanExpression::PathPrefix(prefix.into());
+        purge_jobs.start(cache.clone(), expression, XX_CACHE_TAGS, pacing).expect("start")
+    };
+
+    // Created before the fence
+    let stale = entry("\"v1\"", None);
+
+    let start = tokio::time::Instant::now();
+    let id = purge("/tenant/", None);
+
+    // Fenced at once
+    assert_eq!(status("/tenant/0".into()).await, Some("MISS"));
+    assert_eq!(status("/other/0".into()).await, Some("HIT"));
+
+    // Look in the middle of each tick: exactly one more batch, and gets are served
+    tokio::time::sleep(TICK / 2).await;
+    let mut scanned = Vec::default();
+    loop {
+        let job = purge_jobs.status(id).expect("status");
+        scanned.push(job.scanned as usize);
+        assert_eq!(status(format!("/other/{}", scanned.len() % KEPT)).await, Some("HIT"));
+        if job.state != PurgeJobState::Running {
+            break;
+        }
+        tokio::time::sleep(TICK).await;
+    }
+
+    // The last look is after the pause that follows the last batch
+    let batches = (PURGED + KEPT).div_ceil(BATCH);
+    let expected_scanned: Vec<_> =
+        (1..=batches).map(|batch| (batch * BATCH).min(PURGED + KEPT)).collect();
+    assert_eq!(scanned[..batches], expected_scanned, "scanned per tick");
+    assert_eq!(scanned.len(), batches + 1, "ticks");
+    assert_eq!(start.elapsed(), TICK * batches as u32 + TICK / 2, "paused time");
+
+    let job = purge_jobs.status(id).expect("status");
+    assert_eq!(job.state, PurgeJobState::Completed);
+    assert_eq!(job.total, PURGED + KEPT);
+    assert_eq!((job.scanned, job.invalidated), ((PURGED + KEPT) as u64, PURGED as u64));
+    assert_eq!(cache.keys().expect("keys").len(), KEPT);
+    cache.put(key("/tenant/0"), stale).await;
+    assert_eq!(status("/tenant/0".into()).await, Some("HIT"), "fence down");
+
+    // Cancelled after two batches
+    let id = purge("/other/", Some(PurgePacing::new(10, TICK)));
+    tokio::time::sleep(TICK + TICK / 2).await;
+    assert!(purge_jobs.cancel(id, false));
+    tokio::time::sleep(TICK).await;
+    let job = purge_jobs.status(id).expect("status");
+    assert_eq!(job.state, PurgeJobState::Cancelled);
+    assert_eq!(job.scanned, 20, "{}", job);
+    assert!(job.invalidated > 0 && job.invalidated < KEPT as u64, "{}", job);
+
+    // Entries the purge didn't reach
+    let keys = cache.keys().expect("keys");
+    let remaining: Vec<_> =
+        keys.iter().filter_map(|key| key.path()).filter(|path| *path != "/tenant/0").collect();
+    assert!(remaining.len() >= 2, "{:?}", remaining);
+    assert_eq!(status(remaining[0].into()).await, Some("MISS"), "{}: fenced", remaining[0]);
+
+    assert!(purge_jobs.lift_fence(id));
+    assert_eq!(status(remaining[1].into()).await, Some("HIT"), "{}: lifted", remaining[1]);
+}
+
+// A client doing optimistic concurrency whose write fails with 412 Precondition Failed reads the
+// current version in one round trip instead of the stale entry, unrelated entries are untouched,
+// and with ETag mismatch any write response with a different ETag invalidates
+#[tokio::test]
+async fn conflict_invalidation() {
+    // Versioned resource: a PUT succeeds only if its If-Match is the current version
+    let upstream_calls = Arc::new(atomic::AtomicUsize::default());
+    let upstream = || {
+        let upstream_calls = upstream_calls.clone();
+        let version = Arc::new(Mutex::new(1));
+        service_fn(move |request: Request<()>| {
+            upstream_calls.fetch_add(1, atomic::Ordering::SeqCst);
+            let mut version = version.lock().expect("lock");
+            let mut status = StatusCode::OK;
+            if request.method() == Method::PUT {
+                let current = format!("\"v{}\"", *version);
+                if request.headers().get(IF_MATCH).is_some_and(|if_match| if_match == &current) {
+                    *version += 1;
+                    status = StatusCode::NO_CONTENT;
+                } else {
+                    status = StatusCode::PRECONDITION_FAILED;
+                }
+            }
+            let mut response = Response::new(FramesBody::from(ImmutableBytes::from(
+                format!("version {}", *version).into_bytes(),
+            )));
+            *response.status_mut() = status;
+            let etag = HeaderValue::try_from(format!("\"v{}\"", *version)).expect("ETag");
+            response.headers_mut().insert(ETAG, etag);
+            ready(Ok::<_, io::Error>(response))
+        })
+    };
+
+    // (status, cache status, ETag)
+    let send = async |service: &mut CachingService<_, (), SimpleLruCache>, request| {
+        let response: Response<_> = service.oneshot_ready(request).await.expect("oneshot_ready");
+        (
+            response.status(),
+            response.extensions().get::<CacheStatus>().map(CacheStatus::as_str),
+            response.headers().get(ETAG).and_then(|etag| etag.to_str().ok()).map(String::from),
+        )
+    };
+    let get = |path| Request::get(path).body(()).expect("Request::get");
+    let put = |if_match| {
+        Request::put("/resource").header(IF_MATCH, if_match).body(()).expect("Request::put")
+    };
+    let etag = |version: &str| Some(format!("\"{}\"", version));
+
+    let mut service = CachingLayer::<(), SimpleLruCache>::default()
+        .cache(SimpleLruCache::new(1024 * 1024, None))
+        .methods(MethodPolicy::GetOnly)
+        .conflict_invalidation(ConflictInvalidation::default())
+        .layer(upstream());
+
+    let (_, cache_status, _) = send(&mut service, get("/resource")).await;
+    assert_eq!(cache_status, Some("MISS"));
+    let (_, cache_status, _) = send(&mut service, get("/other")).await;
+    assert_eq!(cache_status, Some("MISS"));
+
+    // Another client's successful write does not invalidate, so we now have a stale entry
+    let (status, _, _) = send(&mut service, put("\"v1\"")).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (_, cache_status, stale) = send(&mut service, get("/resource")).await;
+    assert_eq!((cache_status, stale), (Some("HIT"), etag("v1")));
+
+    // Our write with the stale ETag fails, after which we read the current version
+    let (status, _, _) = send(&mut service, put("\"v1\"")).await;
+    assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+    upstream_calls.store(0, atomic::Ordering::SeqCst);
+    let (_, cache_status, current) = send(&mut service, get("/resource")).await;
+    assert_eq!((cache_status, current), (Some("MISS"), etag("v2")));
+    assert_eq!(upstream_calls.load(atomic::Ordering::SeqCst), 1, "one round trip");
+
+    // And retry successfully
+    let (status, _, _) = send(&mut service, put("\"v2\"")).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    // The unrelated entry is untouched
+    let (_, cache_status, _) = send(&mut service, get("/other")).await;
+    assert_eq!(cache_status, Some("HIT"));
+
+    // ETag mismatch: a response with a status outside the configured ones invalidates only if its
+    // ETag differs from the entry's
+    let mut service = CachingLayer::<(), SimpleLruCache>::default()
+        .cache(SimpleLruCache::new(1024 * 1024, None))
+        .methods(MethodPolicy::GetOnly)
+        .conflict_invalidation(
+            ConflictInvalidation::new([StatusCode::CONFLICT]).etag_mismatch(true),
+        )
+        .layer(upstream());
+
+    send(&mut service, get("/resource")).await;
+    let (status, _, _) = send(&mut service, put("\"v0\"")).await;
+    assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+    let (_, cache_status, _) = send(&mut service, get("/resource")).await;
+    assert_eq!(cache_status, Some("HIT"), "same ETag");
+
+    let (status, _, _) = send(&mut service, put("\"v1\"")).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (_, cache_status, current) = send(&mut service, get("/resource")).await;
+    assert_eq!((cache_status, current), (Some("MISS"), etag("v2")), "different ETag");
+}
+
+// A corrupted entry is replaced on its next request with exactly one extra upstream call, a key
+// whose entries are always rejected cools down to one uncached upstream call per request, and
+// validators get a body prefix only if one of them needs it
+#[tokio::test]
+async fn serve_quarantine() {
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\nimage";
+
+    // The first response is an error page under an image type (as if by a buggy hook)
+    let upstream_calls = Arc::new(atomic::AtomicUsize::default());
+    let upstream = || {
+        let upstream_calls = upstream_calls.clone();
+        service_fn(move |_request: Request<()>| {
+            let corrupt = upstream_calls.fetch_add(1, atomic::Ordering::SeqCst) == 0;
+            let body: &[u8] = if corrupt { b"<!DOCTYPE html><p>error</p>" } else { PNG };
+            let mut response = Response::new(FramesBody::from(ImmutableBytes::from(body.to_vec())));
+            response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("image/png"));
+            ready(Ok::<_, io::Error>(response))
+        })
+    };
+    let layer = |serve_validation| {
+        CachingLayer::<(), MockCache>::default()
+            .cache(MockCache::default())
+            .validate_on_serve(serve_validation)
+            .layer(upstream())
+    };
+
+    // (cache status, body)
+    let send = async |service: &mut CachingService<_, (), MockCache>| {
+        let request = Request::get("/image").body(()).expect("Request::get");
+        let response: Response<_> = service.oneshot_ready(request).await.expect("oneshot_ready");
+        let status = response.extensions().get::<CacheStatus>().copied();
+        (status, body_bytes(response.into_body()).await)
+    };
+
+    let quarantined = Arc::new(Mutex::new(Vec::new()));
+    let serve_validation = {
+        let quarantined = quarantined.clone();
+        ServeValidation::new(vec![ServeValidator::content_type_sniff(&SniffedType::ALL)])
+            .on_quarantine(move |event| quarantined.lock().expect("lock").push(event.reason))
+    };
+    let mut service = layer(serve_validation.clone());
+
+    let (status, _) = send(&mut service).await;
+    assert_eq!(status, Some(CacheStatus::Miss));
+    let (status, body) = send(&mut service).await;
+    assert_eq!((status, body.as_slice()), (Some(CacheStatus::Miss), PNG), "replaced");
+    let (status, body) = send(&mut service).await;
+    assert_eq!((status, body.as_slice()), (Some(CacheStatus::Hit), PNG));
+    assert_eq!(upstream_calls.load(atomic::Ordering::SeqCst), 2, "one extra upstream call");
+    assert_eq!(*quarantined.lock().expect("lock"), ["HTML body under Content-Type image/png"]);
+    let stats = serve_validation.stats();
+    assert_eq!((stats.quarantined, stats.cooled_down, stats.tracked), (1, 0, 0));
+
+    // Always rejecting: the second consecutive rejection cools the key down
+    let cooldown = Duration::from_millis(100);
+    let serve_validation = ServeValidation::new(vec![ServeValidator::new(false, |_context| {
+        Err("always".into())
+    })])
+    .cooldown(2, cooldown);
+    let mut service = layer(serve_validation.clone());
+
+    // (cache status, upstream calls)
+    let bypass = Some(CacheStatus::Bypass { reason: "quarantine" });
+    let expected = [
+        (Some(CacheStatus::Miss), 1),
+        (Some(CacheStatus::Miss), 1),
+        (Some(CacheStatus::Miss), 1), // cooled down
+        (bypass, 1),
+        (bypass, 1),
+    ];
+    for (index, (expected_status, expected_calls)) in expected.into_iter().enumerate() {
+        upstream_calls.store(0, atomic::Ordering::SeqCst);
+        let (status, _) = send(&mut service).await;
+        assert_eq!(status, expected_status, "request {}", index);
+        assert_eq!(upstream_calls.load(atomic::Ordering::SeqCst), expected_calls, "{}", index);
+    }
+    assert_eq!(serve_validation.stats().cooled_down, 1);
+
+    // Cooldown over
+    tokio::time::sleep(cooldown + Duration::from_millis(20)).await;
+    let (status, _) = send(&mut service).await;
+    assert_eq!(status, Some(CacheStatus::Miss));
+    assert_eq!(serve_validation.stats().tracked, 0);
+
+    // Body prefixes are only read for validators that need them
+    let prefixes = Arc::new(Mutex::new(Vec::new()));
+    let recording = |needs_body| {
+        let prefixes = prefixes.clone();
+        ServeValidator::new(needs_body, move |context| {
+            prefixes.lock().expect("lock").push(context.body_prefix.map(<[u8]>::to_vec));
+            Ok(())
+        })
+    };
+    for (validators, expected) in [
+        (vec![ServeValidator::content_length(), recording(false)], None),
+        (vec![recording(false), ServeValidator::utf8()], Some(&PNG[..4])),
+    ] {
+        let mut service = layer(ServeValidation::new(validators).body_prefix(4));
+        send(&mut service).await;
+        assert_eq!(send(&mut service).await.0, Some(CacheStatus::Hit));
+        let prefixes = mem::take(&mut *prefixes.lock().expect("lock"));
+        assert_eq!(prefixes, [expected.map(<[u8]>::to_vec)]);
+    }
+}
+
+// The body size histogram matches a synthetic distribution of stored, rejected, and hit sizes, an
+// undeclared oversized body is recorded as it was when we stopped reading it, and the
+// recommendation admits the target fractions of that traffic
+#[tokio::test]
+async fn body_size_histogram() {
+    // "/{size}/..." in frames of 1 KiB, with Content-Length unless "/{size}/undeclared"
+    let upstream = service_fn(|request: Request<()>| async move {
+        let mut segments = request.uri().path().split('/').skip(1);
+        let size: usize = segments.next().and_then(|size| size.parse().ok()).expect("size");
+        let frames = (0..size).step_by(1024).map(|offset| {
+            Frame::data(ImmutableBytes::from(vec![b'x'; (size - offset).min(1024)]))
+        });
+        let mut response = Response::new(FramesBody(frames.collect()));
+        if segments.next() != Some("undeclared") {
+            response.headers_mut().insert(CONTENT_LENGTH, size.into());
+        }
+        Ok::<_, io::Error>(response)
+    });
+    let layer = CachingLayer::<(), SimpleLruCache>::default()
+        .cache(SimpleLruCache::new(1024 * 1024, None))
+        .max_cacheable_body_size(16 * 1024)
+        .observe_body_sizes();
+    let body_sizes = layer.body_sizes().expect("body_sizes");
+    let mut service = layer.layer(upstream);
+
+    assert_eq!(body_sizes.recommend_max_body_size(0.5), None, "nothing observed");
+
+    // (path, cache status); oversized responses are looked up but not stored
+    let requests = [
+        ("/100/a", "MISS"),
+        ("/100/b", "MISS"),
+        ("/100/c", "MISS"),
+        ("/100/d", "MISS"),
+        ("/100/a", "HIT"),
+        ("/100/b", "HIT"),
+        ("/1000/a", "MISS"),
+        ("/1000/b", "MISS"),
+        ("/5000/a", "MISS"),
+        ("/5000/a", "HIT"),
+        ("/100000/a", "MISS"),
+        ("/100000/b", "MISS"),
+        ("/1048576/undeclared", "MISS"),
+    ];
+
+    for (path, expected) in requests {
+        let request = Request::get(path).body(()).expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+        let status = response.extensions().get::<CacheStatus>().map(CacheStatus::as_str);
+        assert_eq!(status, Some(expected), "{}", path);
+        let size: usize = path.split('/').nth(1).and_then(|size| size.parse().ok()).expect("size");
+        assert_eq!(body_bytes(response.into_body()).await.len(), size, "{}", path);
+    }
+
+    // (upper bound, stored, rejected, hits) of the non-empty buckets
+    let histogram: Vec<_> = body_sizes
+        .histogram()
+        .into_iter()
+        .filter(|bucket| bucket.traffic() != 0)
+        .map(|bucket| (bucket.upper_bound, bucket.stored, bucket.rejected, bucket.hits))
+        .collect();
+    assert_eq!(
+        histogram,
+        [
+            (Some(256), 4, 0, 2),
+            (Some(1024), 2, 0, 0),
+            (Some(8 * 1024), 1, 0, 1),
+            // We stopped reading the undeclared body soon after the maximum
+            (Some(32 * 1024), 0, 1, 0),
+            (Some(128 * 1024), 0, 2, 0),
+        ]
+    );
+
+    // Cumulative traffic is 6, 8, 10, 11, and 13 of 13
+    for (target_fraction, expected) in [
+        (0., 256),
+        (0.4, 256),
+        (0.5, 1024),
+        (0.75, 8 * 1024),
+        (0.8, 32 * 1024),
+        (1., 128 * 1024),
+    ] {
+        let recommendation = body_sizes.recommend_max_body_size(target_fraction);
+        assert_eq!(recommendation, Some(expected), "{}", target_fraction);
+    }
+}
+
+// The weight audit samples only stored keys (hits and skips aren't recorded), keeps a bounded
+// reservoir, counts evicted keys as missing, and keeps its last report
+#[tokio::test]
+async fn weight_audit_sampling() {
+    let cache = MockCache::default();
+    let layer_with = |capacity| {
+        CachingLayer::<(), MockCache>::default()
+            .cache(cache.clone())
+            .bypass_cache_on_headers(&[COOKIE])
+            .audit_weights(WeightAudit::new_with_capacity(capacity))
+    };
+
+    let layer = layer_with(16);
+    let weight_audit = layer.weight_audit().expect("weight_audit");
+    let mut service = layer.layer(ValidatedUpstream);
+    assert!(weight_audit.last_report().is_none());
+
+    // (path, cookie, expected cache status)
+    let requests = [
+        ("/a", false, "MISS"),
+        ("/b", false, "MISS"),
+        ("/a", false, "HIT"),
+        ("/c", true, "BYPASS"),
+        ("/d", false, "MISS"),
+    ];
+    for (path, cookie, expected) in requests {
+        let mut request = Request::get(path).body(()).expect("Request::get");
+        if cookie {
+            request.headers_mut().insert(COOKIE, HeaderValue::from_static("session=1"));
+        }
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+        let status = response.extensions().get::<CacheStatus>().map(CacheStatus::as_str);
+        assert_eq!(status, Some(expected), "{}", path);
+        body_bytes(response.into_body()).await;
+    }
+
+    // Bounded by n, and by the three stored keys
+    let report = weight_audit.sample(&cache, 2).await;
+    assert_eq!((report.sampled, report.missing), (2, 0));
+    let report = weight_audit.sample(&cache, 10).await;
+    assert_eq!((report.sampled, report.missing), (3, 0));
+    assert!(report.min_ratio > 0.0 && report.min_ratio <= report.max_ratio, "{}", report);
+    assert!(report.divergent.iter().all(|sample| sample.diverges(DEFAULT_WEIGHT_DIVERGENCE)));
+
+    cache.invalidate(&key("/b")).await;
+    let report = weight_audit.sample(&cache, 10).await;
+    assert_eq!((report.sampled, report.missing), (2, 1));
+    let last_report = weight_audit.last_report().expect("last_report");
+    assert_eq!((last_report.sampled, last_report.missing), (2, 1));
+
+    // Many stored keys, a few sampled
+    let layer = layer_with(4);
+    let weight_audit = layer.weight_audit().expect("weight_audit");
+    let mut service = layer.layer(ValidatedUpstream);
+    for index in 0..32 {
+        let request = Request::get(format!("/many/{}", index)).body(()).expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+        body_bytes(response.into_body()).await;
+    }
+    let report = weight_audit.sample(&cache, 100).await;
+    assert_eq!((report.sampled, report.missing), (4, 0));
+}
+
+// Disabling caching at runtime passes requests through to the upstream, and overrides take effect
+// on subsequent requests until cleared
+#[tokio::test]
+async fn runtime_control() {
+    let cache = SimpleLruCache::new(1024 * 1024, None);
+    let calls = Arc::new(atomic::AtomicUsize::default());
+    let upstream = {
+        let calls = calls.clone();
+        service_fn(move |_request: Request<()>| {
+            calls.fetch_add(1, atomic::Ordering::SeqCst);
+            async move {
+                Ok::<_, io::Error>(Response::new(FramesBody::from(ImmutableBytes::from(
+                    b"hello".to_vec(),
+                ))))
+            }
+        })
+    };
+    let layer = CachingLayer::<(), SimpleLruCache>::default().cache(cache.clone());
+    let control = layer.control();
+    let mut service = layer.layer(upstream);
+
+    let mut status = async |path: &'static str| {
+        let request = Request::get(path).body(()).expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+        response.extensions().get::<CacheStatus>().map(CacheStatus::as_str)
+    };
+
+    assert_eq!(status("/control").await, Some("MISS"));
+    assert_eq!(status("/control").await, Some("HIT"));
+    assert_eq!(calls.load(atomic::Ordering::SeqCst), 1);
+
+    control.set_enabled(false);
+    for call in [2, 3] {
+        assert_eq!(status("/control").await, Some("BYPASS"), "disabled");
+        assert_eq!(calls.load(atomic::Ordering::SeqCst), call, "disabled");
+    }
+    assert_eq!(cache.len(), 1, "disabled: entries");
+
+    control.set_enabled(true);
+    assert_eq!(status("/control").await, Some("HIT"), "enabled");
+
+    control.set_max_cacheable_body_size(1);
+    assert_eq!(status("/too-large").await, Some("MISS"));
+    assert_eq!(status("/too-large").await, Some("MISS"));
+    assert_eq!(cache.len(), 1, "overridden: entries");
+
+    control.clear_overrides();
+    assert_eq!(status("/too-large").await, Some("MISS"), "cleared");
+    assert_eq!(status("/too-large").await, Some("HIT"), "cleared");
+}
+
+// Bypassing reads sends a hitting request upstream and overwrites the poisoned entry, bypassing
+// all doesn't store, and an override clears itself at its deadline
+#[tokio::test]
+async fn cache_override() {
+    let cache = MockCache::default();
+    let layer = CachingLayer::<(), MockCache>::default().cache(cache.clone());
+    let cache_override = layer.cache_override();
+    let mut service = layer.layer(ValidatedUpstream);
+
+    let mut etag = async || {
+        let request = Request::get("/override").body(()).expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+        response.headers().get(ETAG).cloned()
+    };
+    let poison = async || cache.put(key("/override"), entry("\"poisoned\"", None)).await;
+    let poisoned = Some(HeaderValue::from_static("\"poisoned\""));
+
+    poison().await;
+    assert_eq!(etag().await, poisoned, "hit");
+
+    cache_override.bypass_reads(Duration::from_secs(60));
+    assert_eq!(etag().await, Some(ValidatedUpstream::etag()), "bypass reads");
+    assert_version(&cache, "/override", Some("\"v1\"")).await;
+
+    poison().await;
+    cache_override.bypass_all(Duration::from_millis(100));
+    assert_eq!(etag().await, Some(ValidatedUpstream::etag()), "bypass all");
+    assert_version(&cache, "/override", Some("\"poisoned\"")).await;
+
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert_eq!(cache_override.mode(), None, "deadline");
+    assert_eq!(etag().await, poisoned, "deadline");
+
+    cache_override.bypass_reads(Duration::from_secs(60));
+    cache_override.resume();
+    assert_eq!(etag().await, poisoned, "resumed");
+}
+
+// Concurrent engages and resumes leave the override in the state of one of them, with its setter
+#[test]
+fn cache_override_concurrency() {
+    let cache_override = CacheOverride::default();
+
+    thread::scope(|scope| {
+        for index in 0..8 {
+            let cache_override = &cache_override;
+            scope.spawn(move || {
+                for _ in 0..100 {
+                    match index % 3 {
+                        0 => cache_override.engage(
+                            BypassMode::Reads,
+                            Duration::from_secs(60),
+                            Some("reads".into()),
+                        ),
+                        1 => cache_override.engage(
+                            BypassMode::All,
+                            Duration::from_secs(60),
+                            Some("all".into()),
+                        ),
+                        _ => cache_override.resume(),
+                    }
+                }
+            });
+        }
+    });
+
+    match cache_override.status() {
+        Some(status) => {
+            let expected_set_by = match status.mode {
+                BypassMode::Reads => "reads",
+                BypassMode::All => "all",
+            };
+            assert_eq!(status.set_by.as_deref(), Some(expected_set_by));
+        }
+
+        None => assert_eq!(cache_override.mode(), None),
+    }
+}
+
+// With eviction history, recurring one-hit wonders stop being stored while hot keys are hit as
+// often as with plain "cache on second request" admission
+#[test]
+fn admission_history() {
+    const CAPACITY: usize = 12;
+
+    // Steps: (key, is hot); every round requests the hot keys and then 4 of 40 recurring one-hit
+    // wonders, each twice in a row (so that the second request is admitted without history)
+    let steps: Vec<_> = (0..200)
+        .flat_map(|round| {
+            let hot = (0..8).map(|index| (format!("/hot/{}", index), true));
+            let wonders = (0..4).flat_map(move |index| {
+                let key = format!("/wonder/{}", (round * 4 + index) % 40);
+                [(key.clone(), false), (key, false)]
+            });
+            hot.chain(wonders).collect::<Vec<_>>()
+        })
+        .collect();
+
+    // Returns the number of stores and the number of hot hits
+    let run = |admission: &AdmissionPolicy| {
+        // LRU order, least recently used first
+        let mut entries: Vec<(String, CachedResponseRef)> = Vec::default();
+        let (mut stores, mut hot_hits) = (0, 0);
+
+        for (key, hot) in &steps {
+            if let Some(index) = entries.iter().position(|(stored, _)| stored == key) {
+                let (key, cached_response) = entries.remove(index);
+                cached_response.hits.fetch_add(1, atomic::Ordering::Relaxed);
+                entries.push((key, cached_response));
+                if *hot {
+                    hot_hits += 1;
+                }
+            } else if admission.admit(key) {
+                stores += 1;
+                if entries.len() == CAPACITY {
+                    let (evicted, cached_response) = entries.remove(0);
+                    admission.record_eviction(&evicted, &cached_response);
+                }
+                entries.push((key.clone(), entry("admitted", None)));
+            }
+        }
+
+        (stores, hot_hits)
+    };
+
+    let configuration = AdmissionConfig {
+        width: 1024,
+        rotate_every: 64,
+        ..Default::default()
+    };
+
+    let plain = AdmissionPolicy::new(AdmissionConfig {
+        negative_required_observations: configuration.required_observations,
+        positive_hits: None,
+        ..configuration
+    });
+    let (plain_stores, plain_hot_hits) = run(&plain);
+
+    let history = AdmissionPolicy::new(configuration);
+    let (history_stores, history_hot_hits) = run(&history);
+
+    assert!(
+        history_stores * 4 < plain_stores,
+        "stores: {} with history, {} without",
+        history_stores,
+        plain_stores
+    );
+    assert_eq!(history_hot_hits, plain_hot_hits, "hot hits");
+
+    let stats = history.stats();
+    assert_eq!(stats.admitted, history_stores, "admitted");
+    assert!(stats.penalized > 0, "penalized");
+    assert_eq!(plain.stats().penalized, 0, "plain: penalized");
+}
+
+// Load signal set by the test
+#[derive(Clone, Default)]
+struct ScriptedLoad(Arc<Mutex<f64>>);
+
+impl LoadProbe for ScriptedLoad {
+    fn load(&self) -> f64 {
+        *self.0.lock().expect("lock")
+    }
+}
+
+// Each load shedding level sheds exactly its work while hits are still served, and levels recover
+// one at a time below the hysteresis
+#[tokio::test]
+async fn load_shedding() {
+    let load = ScriptedLoad::default();
+    let load_shed = LoadShedPolicy::new(load.clone(), 0.5, 0.8, 0.1);
+    let cache = MockCache::default();
+    let mut service = CachingLayer::<(), MockCache>::default()
+        .cache(cache.clone())
+        .load_shed(load_shed.clone())
+        .layer(ValidatedUpstream);
+
+    let mut status = async |path: &str| {
+        let request = Request::get(path)
+            .header(ACCEPT_ENCODING, "gzip")
+            .body(())
+            .expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect(path);
+        response.extensions().get::<CacheStatus>().map(CacheStatus::as_str)
+    };
+
+    // (load, expected level, expected stored, expected encoded)
+    let steps = [
+        (0.0, LoadShedLevel::Normal, true, true),
+        (0.6, LoadShedLevel::SkipEncoding, true, false),
+        (0.9, LoadShedLevel::SkipStore, false, false),
+        (0.75, LoadShedLevel::SkipStore, false, false),
+        (0.65, LoadShedLevel::SkipEncoding, true, false),
+        (0.45, LoadShedLevel::SkipEncoding, true, false),
+        (0.3, LoadShedLevel::Normal, true, true),
+    ];
+
+    for (index, (value, expected_level, expected_stored, expected_encoded)) in
+        steps.into_iter().enumerate()
+    {
+        *load.0.lock().expect("lock") = value;
+        let path = format!("/load/{}", index);
+
+        assert_eq!(status(&path).await, Some("MISS"), "{}", value);
+        assert_eq!(load_shed.level(), expected_level, "{}", value);
+
+        let cached_response = cache.get(&key(&path)).await;
+        assert_eq!(cached_response.is_some(), expected_stored, "{}: stored", value);
+        if let Some(cached_response) = cached_response {
+            let encoded =
+                cached_response.body.representations.contains_key(&CodingId::from(Encoding::GZip));
+            assert_eq!(encoded, expected_encoded, "{}: encoded", value);
+        }
+
+        // Hits are always served
+        assert_eq!(status("/load/0").await, Some("HIT"), "{}: hit", value);
+    }
+}
+
+// A miss whose key is invalidated (by key or all at once) while its upstream call is in flight
+// doesn't store its now stale response, while the next request stores normally
+#[tokio::test]
+async fn fenced_in_flight_miss() {
+    let entered = Arc::new(tokio::sync::Barrier::new(2));
+    let release = Arc::new(tokio::sync::Barrier::new(2));
+    let upstream = {
+        let (entered, release) = (entered.clone(), release.clone());
+        service_fn(move |request: Request<()>| {
+            let (entered, release) = (entered.clone(), release.clone());
+            async move {
+                if request.headers().contains_key("x-hold") {
+                    entered.wait().await;
+                    release.wait().await;
+                }
+                Ok::<_, io::Error>(Response::new(FramesBody::from(ImmutableBytes::from(
+                    b"hello".to_vec(),
+                ))))
+            }
+        })
+    };
+    let cache = FencedCache::new(SimpleLruCache::new(1024 * 1024, None));
+    let service = CachingLayer::<(), FencedCache<SimpleLruCache>>::default()
+        .cache(cache.clone())
+        .layer(upstream);
+
+    let request = |hold: bool| {
+        let mut request = Request::get("/fenced");
+        if hold {
+            request = request.header("x-hold", "true");
+        }
+        request.body(()).expect("Request::get")
+    };
+
+    let stored = async || cache.get(&key("/fenced")).await.is_some();
+
+    for invalidate_all in [false, true] {
+        let mut in_flight = service.clone();
+        let (response, _) = tokio::join!(in_flight.oneshot_ready(request(true)), async {
+            entered.wait().await;
+            if invalidate_all {
+                cache.invalidate_all().await;
+            } else {
+                cache.invalidate(&key("/fenced")).await;
+            }
+            release.wait().await;
+        });
+        let response = response.expect("in flight");
+        let status = response.extensions().get::<CacheStatus>().map(CacheStatus::as_str);
+        assert_eq!(status, Some("MISS"), "invalidate_all={}: in flight", invalidate_all);
+        assert_eq!(body_bytes(response.into_body()).await, b"hello");
+        assert!(!stored().await, "invalidate_all={}: stale fill stored", invalidate_all);
+
+        let mut fresh = service.clone();
+        fresh.oneshot_ready(request(false)).await.expect("fresh");
+        assert!(stored().await, "invalidate_all={}: fresh fill rejected", invalidate_all);
+
+        cache.invalidate(&key("/fenced")).await;
+    }
+}
+
+// Cache whose lookups are slow
+#[derive(Clone, Default)]
+struct SlowCache(MockCache);
+
+impl Cache for SlowCache {
+    async fn get(&self, key: &CommonCacheKey) -> Option<CachedResponseRef> {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        self.0.get(key).await
+    }
+
+    async fn put(&self, key: CommonCacheKey, cached_response: CachedResponseRef) {
+        self.0.put(key, cached_response).await
+    }
+
+    async fn invalidate(&self, key: &CommonCacheKey) {
+        self.0.invalidate(key).await
+    }
+
+    async fn invalidate_all(&self) {
+        self.0.invalidate_all().await
+    }
+}
+
+// Cache whose stores are slow
+#[derive(Clone, Default)]
+struct SlowStoreCache(MockCache);
+
+impl Cache for SlowStoreCache {
+    async fn get(&self, key: &CommonCacheKey) -> Option<CachedResponseRef> {
+        self.0.get(key).await
+    }
+
+    async fn put(&self, key: CommonCacheKey, cached_response: CachedResponseRef) {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        self.0.put(key, cached_response).await
+    }
+
+    async fn invalidate(&self, key: &CommonCacheKey) {
+        self.0.invalidate(key).await
+    }
+
+    async fn invalidate_all(&self) {
+        self.0.invalidate_all().await
+    }
+}
+
+// An exhausted overhead budget fails open with a correct body, counted in the phase in which it
+// ran out; with the overhead-budget feature slow operations are abandoned, so the layer adds
+// little beyond the budget, and the time spent awaiting the upstream never counts
+#[tokio::test]
+async fn overhead_budget() {
+    let upstream = service_fn(|_request: Request<()>| async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        Ok::<_, io::Error>(Response::new(FramesBody::from(ImmutableBytes::from(b"hello".to_vec()))))
+    });
+    let abandons = cfg!(feature = "overhead-budget");
+    let max_overhead = Duration::from_millis(20);
+    let request = || {
+        Request::get("/budget")
+            .header(ACCEPT_ENCODING, "gzip")
+            .body(())
+            .expect("Request::get")
+    };
+    let exceeded = |response: &Response<_>| {
+        response.extensions().get::<OverheadExceeded>().map(|exceeded| exceeded.phase)
+    };
+
+    // Slow upstream
+    let cache = MockCache::default();
+    let layer = CachingLayer::<(), MockCache>::default()
+        .cache(cache.clone())
+        .max_overhead(max_overhead);
+    let budget = layer.overhead_budget().expect("overhead_budget");
+    let mut service = layer.layer(upstream);
+
+    for expected_status in [CacheStatus::Miss, CacheStatus::Hit] {
+        let response = service.oneshot_ready(request()).await.expect("oneshot_ready");
+        assert_eq!(response.extensions().get::<CacheStatus>(), Some(&expected_status));
+        assert_eq!(exceeded(&response), None, "slow upstream");
+    }
+    assert!(budget.exhausted_by_phase().iter().all(|(_, count)| *count == 0), "slow upstream");
+
+    // Slow lookup, with and without an entry
+    for stored in [false, true] {
+        let inner = if stored { cache.clone() } else { MockCache::default() };
+        let layer = CachingLayer::<(), SlowCache>::default()
+            .cache(SlowCache(inner.clone()))
+            .max_overhead(max_overhead);
+        let budget = layer.overhead_budget().expect("overhead_budget");
+        let mut service = layer.layer(upstream);
+
+        let start = Instant::now();
+        let response = service.oneshot_ready(request()).await.expect("oneshot_ready");
+        let elapsed = start.elapsed();
+
+        let expected_phase = match (abandons, stored) {
+            (true, _) => BudgetPhase::Lookup,
+            (false, true) => BudgetPhase::Hit,
+            (false, false) => BudgetPhase::Miss,
+        };
+        assert_eq!(exceeded(&response), Some(expected_phase), "stored={}", stored);
+        assert_eq!(budget.exhausted(expected_phase), 1, "stored={}", stored);
+        assert_eq!(decoded_body(response).await, b"hello", "stored={}: body", stored);
+        if abandons {
+            assert!(elapsed < Duration::from_millis(120), "stored={}: {:?}", stored, elapsed);
+        }
+        if !stored {
+            assert_eq!(inner.keys().expect("keys").len(), 0, "stored={}: entries", stored);
+        }
+    }
+
+    // Slow store
+    let layer = CachingLayer::<(), SlowStoreCache>::default()
+        .cache(SlowStoreCache::default())
+        .max_overhead(max_overhead);
+    let budget = layer.overhead_budget().expect("overhead_budget");
+    let mut service = layer.layer(upstream);
+
+    let start = Instant::now();
+    let response = service.oneshot_ready(request()).await.expect("oneshot_ready");
+    let elapsed = start.elapsed();
+    if abandons {
+        assert_eq!(exceeded(&response), Some(BudgetPhase::Store), "slow store");
+        assert_eq!(budget.exhausted(BudgetPhase::Store), 1, "slow store");
+        assert!(elapsed < Duration::from_millis(120), "slow store: {:?}", elapsed);
+    }
+    assert_eq!(decoded_body(response).await, b"hello", "slow store: body");
+}
+
+// Cache that counts its stores and lookups
+#[derive(Clone, Default)]
+struct CountingCache(MockCache, Arc<atomic::AtomicUsize>, Arc<atomic::AtomicUsize>);
+
+impl CountingCache {
+    fn take_puts(&self) -> usize {
+        self.1.swap(0, atomic::Ordering::SeqCst)
+    }
+
+    fn take_gets(&self) -> usize {
+        self.2.swap(0, atomic::Ordering::SeqCst)
+    }
+}
+
+impl Cache for CountingCache {
+    async fn get(&self, key: &CommonCacheKey) -> Option<CachedResponseRef> {
+        self.2.fetch_add(1, atomic::Ordering::SeqCst);
+        self.0.get(key).await
+    }
+
+    async fn put(&self, key: CommonCacheKey, cached_response: CachedResponseRef) {
+        self.1.fetch_add(1, atomic::Ordering::SeqCst);
+        self.0.put(key, cached_response).await
+    }
+
+    async fn invalidate(&self, key: &CommonCacheKey) {
+        self.0.invalidate(key).await
+    }
+
+    async fn invalidate_all(&self) {
+        self.0.invalidate_all().await
+    }
+}
+
+// A request writes its entry at most once, whether it is a miss that encodes or a hit that
+// reencodes, and the stored representations accumulate all the codings that were served
+#[cfg(all(feature = "gzip", feature = "brotli"))]
+#[tokio::test]
+async fn single_put_per_request() {
+    let preference = vec![EncodingHeaderValue::GZip, EncodingHeaderValue::Brotli];
+    let preferred = CodingId::from(Into::<Encoding>::into(preference[0]));
+    let cache = CountingCache::default();
+    let mut service = CachingLayer::<(), CountingCache>::default()
+        .cache(cache.clone())
+        .enable_encodings(preference)
+        .layer(ValidatedUpstream);
+
+    let identity = CodingId::IDENTITY;
+    let gzip = CodingId::from(Encoding::GZip);
+    let brotli = CodingId::from(Encoding::Brotli);
+
+    // (Accept-Encoding, coding served)
+    let steps = [
+        ("gzip", gzip.clone()),
+        ("gzip", gzip),
+        ("identity", identity.clone()),
+        ("br", brotli),
+    ];
+
+    let mut expected_codings = Vec::default();
+    for (encoding, served) in steps {
+        // A miss stores Identity and the most preferred coding besides the one it serves, and
+        // later requests write only to add a representation
+        let new_codings = if expected_codings.is_empty() {
+            vec![identity.clone(), preferred.clone(), served]
+        } else {
+            vec![served]
+        };
+        let stored = expected_codings.len();
+        for coding in new_codings {
+            if !expected_codings.contains(&coding) {
+                expected_codings.push(coding);
+            }
+        }
+        let expected_puts = usize::from(expected_codings.len() > stored);
+
+        let request = Request::get("/single-put")
+            .header(ACCEPT_ENCODING, encoding)
+            .body(())
+            .expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+        assert_eq!(decoded_body(response).await, b"hello", "{}: body", encoding);
+        assert_eq!(cache.take_puts(), expected_puts, "{}: puts", encoding);
+
+        let cached_response = cache.get(&key("/single-put")).await.expect("stored");
+        let representations = &cached_response.body.representations;
+        assert_eq!(representations.len(), expected_codings.len(), "{}", encoding);
+        for coding in &expected_codings {
+            assert!(representations.contains_key(coding), "{}: {}", encoding, coding);
+        }
+    }
+}
+
+// Hits in a cache that can answer immediately never await its get: a tiered cache answers for
+// first-tier hits, while misses and next-tier hits still go through the async path
+#[tokio::test]
+async fn synchronous_hits() {
+    let cache = TieredCache::new(SimpleLruCache::new(1024 * 1024, None), CountingCache::default());
+    let mut service = CachingLayer::<(), TieredCache<SimpleLruCache, CountingCache>>::default()
+        .cache(cache.clone())
+        .layer(ValidatedUpstream);
+
+    let mut status = async || {
+        let request = Request::get("/sync").body(()).expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+        let status = response.extensions().get::<CacheStatus>().map(CacheStatus::as_str);
+        assert_eq!(decoded_body(response).await, b"hello");
+        status
+    };
+
+    assert_eq!(status().await, Some("MISS"));
+    assert!(cache.next.take_gets() > 0, "miss: async get");
+
+    for _ in 0..3 {
+        assert_eq!(status().await, Some("HIT"));
+        assert_eq!(cache.next.take_gets(), 0, "first-tier hit: async get");
+    }
+
+    // Only in the next tier
+    cache.first.invalidate(&key("/sync")).await;
+    assert_eq!(status().await, Some("HIT"));
+    assert!(cache.next.take_gets() > 0, "next-tier hit: no async get");
+}
+
+// The stored coding is our most preferred one even if the client that triggered the miss sent
+// only `*` or only unknown codings; that client gets an acceptable response, and ordinary clients
+// are then served the stored coding without reencoding
+#[cfg(all(feature = "gzip", feature = "brotli"))]
+#[tokio::test]
+async fn store_coding_planning() {
+    let cache = CountingCache::default();
+    let mut service = CachingLayer::<(), CountingCache>::default()
+        .cache(cache.clone())
+        .layer(ValidatedUpstream);
+
+    let brotli = CodingId::from(Encoding::Brotli);
+
+    // (path, first Accept-Encoding, expected first Content-Encoding)
+    let cases = [("/planning/wildcard", "*", Some("br")), ("/planning/unknown", "dcb, dcz", None)];
+
+    for (path, first, expected_coding) in cases {
+        let request = |encoding| {
+            Request::get(path).header(ACCEPT_ENCODING, encoding).body(()).expect("Request::get")
+        };
+        let coding = |response: &Response<_>| response.headers().get(CONTENT_ENCODING).cloned();
+
+        let response = service.oneshot_ready(request(first)).await.expect("first");
+        let expected_coding = expected_coding.map(HeaderValue::from_static);
+        assert_eq!(coding(&response), expected_coding, "{}: first", path);
+        assert_eq!(decoded_body(response).await, b"hello", "{}: first", path);
+        assert_eq!(cache.take_puts(), 1, "{}: first", path);
+
+        let cached_response = cache.get(&key(path)).await.expect("stored");
+        let representations = &cached_response.body.representations;
+        assert!(representations.contains_key(&brotli), "{}: stored", path);
+
+        let response = service.oneshot_ready(request("gzip, deflate, br")).await.expect("then");
+        assert_eq!(response.extensions().get::<CacheStatus>(), Some(&CacheStatus::Hit));
+        let expected_coding = Some(HeaderValue::from_static("br"));
+        assert_eq!(coding(&response), expected_coding, "{}: then", path);
+        assert_eq!(decoded_body(response).await, b"hello", "{}: then", path);
+        assert_eq!(cache.take_puts(), 0, "{}: then", path);
+    }
+}
+
+// Body whose frames arrive one at a time after a delay
+struct SlowBody {
+    frames: vec::IntoIter<ImmutableBytes>,
+    delay: Duration,
+    sleep: Pin<Box<tokio::time::Sleep>>,
+}
+
+impl SlowBody {
+    fn new(frames: Vec<ImmutableBytes>, delay: Duration) -> Self {
+        Self {
+            frames: frames.into_iter(),
+            delay,
+            sleep: Box::pin(tokio::time::sleep(delay)),
+        }
+    }
+}
+
+impl Body for SlowBody {
+    type Data = ImmutableBytes;
+    type Error = io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        context: &mut task::Context<'_>,
+    ) -> task::Poll<Option<std::result::Result<Frame<Self::Data>, Self::Error>>> {
+        task::ready!(self.sleep.as_mut().poll(context));
+        let deadline = tokio::time::Instant::now() + self.delay;
+        self.sleep.as_mut().reset(deadline);
+        task::Poll::Ready(self.frames.next().map(|bytes| Ok(Frame::data(bytes))))
+    }
+}
+
+impl From<ImmutableBytes> for SlowBody {
+    fn from(bytes: ImmutableBytes) -> Self {
+        Self::new(vec![bytes], Duration::ZERO)
+    }
+}
+
+// Concurrent misses with slow bodies never buffer more than the budget between them, and all of
+// them are served in full whether stored or passed through; a miss cancelled mid-read releases
+// its reservation, and a miss that fits only without encoding is stored without it
+#[tokio::test]
+async fn inflight_buffer_budget() {
+    const BODY_SIZE: usize = 100;
+    const MAX_BYTES: usize = 250;
+
+    let upstream = service_fn(|_request: Request<()>| async move {
+        let frames = vec![ImmutableBytes::from(vec![b'a'; BODY_SIZE / 2]); 2];
+        let mut response = Response::new(SlowBody::new(frames, Duration::from_millis(20)));
+        response.headers_mut().insert(CONTENT_LENGTH, BODY_SIZE.into());
+        Ok::<_, io::Error>(response)
+    });
+    let request = |path: &str, encoding: &str| {
+        Request::get(path).header(ACCEPT_ENCODING, encoding).body(()).expect("Request::get")
+    };
+
+    let cache = MockCache::default();
+    let layer = CachingLayer::<(), MockCache>::default()
+        .cache(cache.clone())
+        .max_inflight_buffer_bytes(MAX_BYTES);
+    let budget = layer.buffer_budget().expect("buffer_budget");
+    let service = layer.layer(upstream);
+
+    // Sample the gauge while the misses are in flight
+    let done = Arc::new(atomic::AtomicBool::default());
+    let sampler = tokio::spawn({
+        let budget = budget.clone();
+        let done = done.clone();
+        async move {
+            let mut max_used = 0;
+            while !done.load(atomic::Ordering::SeqCst) {
+                max_used = max_used.max(budget.stats().used);
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            max_used
+        }
+    });
+
+    let misses: Vec<_> = (0..10)
+        .map(|index| {
+            let request = request(&format!("/buffer/{}", index), "identity");
+            tokio::spawn(service.clone().oneshot(request))
+        })
+        .collect();
+    for miss in misses {
+        let response = miss.await.expect("spawn").expect("oneshot");
+        assert_eq!(decoded_body(response).await, vec![b'a'; BODY_SIZE]);
+    }
+
+    done.store(true, atomic::Ordering::SeqCst);
+    let max_used = sampler.await.expect("spawn");
+    let stats = budget.stats();
+    assert!(max_used <= MAX_BYTES, "sampled {}", max_used);
+    assert!(stats.high_water <= MAX_BYTES, "high water {}", stats.high_water);
+    assert_eq!(stats.used, 0);
+    assert!(stats.refused > 0);
+    let stored = cache.keys().expect("keys").len();
+    assert_eq!(stored + stats.refused as usize, 10);
+
+    // Cancelled mid-read
+    let cancelled = tokio::time::timeout(
+        Duration::from_millis(30),
+        service.clone().oneshot(request("/buffer/cancelled", "identity")),
+    )
+    .await;
+    assert!(cancelled.is_err(), "not cancelled");
+    assert_eq!(budget.stats().used, 0, "cancelled");
+    assert_eq!(cache.keys().expect("keys").len(), stored, "cancelled");
+
+    // Room for the body but not for its encoded copy
+    #[cfg(feature = "gzip")]
+    {
+        let layer = CachingLayer::<(), MockCache>::default()
+            .cache(cache.clone())
+            .max_inflight_buffer_bytes(BODY_SIZE * 3 / 2);
+        let budget = layer.buffer_budget().expect("buffer_budget");
+        let mut service = layer.layer(upstream);
+
+        let response =
+            service.oneshot_ready(request("/buffer/reduced", "gzip")).await.expect("oneshot_ready");
+        assert_eq!(response.headers().get(CONTENT_ENCODING), None, "reduced");
+        assert_eq!(decoded_body(response).await, vec![b'a'; BODY_SIZE], "reduced");
+
+        let stats = budget.stats();
+        assert_eq!((stats.reduced, stats.refused, stats.used), (1, 0, 0), "reduced");
+        let cached_response = cache.get(&key("/buffer/reduced")).await.expect("stored");
+        let representations = &cached_response.body.representations;
+        assert_eq!(representations.len(), 1, "reduced");
+        assert!(representations.contains_key(&CodingId::IDENTITY), "reduced");
+    }
+}
+
+// Concurrent misses for the same key call the upstream once and the others are served the stored
+// entry; when the leader's response is uncacheable or fails the followers call the upstream
+// themselves rather than hang, and without coalescing every miss calls the upstream
+#[tokio::test]
+async fn coalesce_requests() {
+    const CONCURRENT: usize = 10;
+
+    let calls = Arc::new(atomic::AtomicUsize::default());
+    let upstream = service_fn({
+        let calls = calls.clone();
+        move |request: Request<()>| {
+            let call = calls.fetch_add(1, atomic::Ordering::SeqCst);
+            async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                let status = match request.uri().path() {
+                    "/coalesce/failing" if call == 0 => return Err(io::Error::other("failing")),
+                    "/coalesce/uncacheable" => StatusCode::SERVICE_UNAVAILABLE,
+                    _ => StatusCode::OK,
+                };
+                let mut response =
+                    Response::new(FramesBody::from(ImmutableBytes::from(b"hello".to_vec())));
+                *response.status_mut() = status;
+                Ok(response)
+            }
+        }
+    });
+
+    // (path, coalesce, expected calls, expected followers)
+    let cases = [
+        ("/coalesce/cacheable", true, 1, CONCURRENT - 1),
+        ("/coalesce/uncacheable", true, CONCURRENT, CONCURRENT - 1),
+        ("/coalesce/failing", true, CONCURRENT, CONCURRENT - 1),
+        ("/coalesce/disabled", false, CONCURRENT, 0),
+    ];
+
+    for (path, coalesce, expected_calls, expected_followers) in cases {
+        calls.store(0, atomic::Ordering::SeqCst);
+        let cache = MockCache::default();
+        let layer = CachingLayer::<(), MockCache>::default()
+            .cache(cache.clone())
+            .coalesce_requests(coalesce);
+        let coalescing = layer.request_coalescing();
+        let service = layer.layer(upstream.clone());
+
+        let misses: Vec<_> = (0..CONCURRENT)
+            .map(|_| {
+                let request = Request::get(path).body(()).expect("Request::get");
+                tokio::spawn(service.clone().oneshot(request))
+            })
+            .collect();
+        let mut failures = 0;
+        for miss in misses {
+            let response = tokio::time::timeout(Duration::from_secs(5), miss)
+                .await
+                .unwrap_or_else(|_| panic!("{}: hung", path))
+                .expect("spawn");
+            match response {
+                Ok(response) => assert_eq!(decoded_body(response).await, b"hello", "{}", path),
+                Err(_) => failures += 1,
+            }
+        }
+
+        assert_eq!(calls.load(atomic::Ordering::SeqCst), expected_calls, "{}: calls", path);
+        assert_eq!(failures, usize::from(path == "/coalesce/failing"), "{}: failures", path);
+        // Without coalescing every miss reaches the upstream (see the calls), but is still stored
+        let stored = path != "/coalesce/uncacheable";
+        assert_eq!(cache.keys().expect("keys").len(), usize::from(stored), "{}: stored", path);
+
+        match coalescing {
+            Some(coalescing) => {
+                let stats = coalescing.stats();
+                assert_eq!(stats.in_flight, 0, "{}: in flight", path);
+                assert_eq!(stats.followed as usize, expected_followers, "{}: followed", path);
+            }
+
+            None => assert!(!coalesce, "{}", path),
+        }
+    }
+}
+
+// An outer micro-cache over an inner full cache: scoped control headers reach only their layer,
+// bodies are encoded once, concurrent misses reach the inner layer once per micro TTL window, and
+// each layer's outcome is reported separately
+#[test]
+fn stacked_layers() {
+    // Polling two layers deep can overflow the default test thread's stack in debug builds
+    thread::Builder::new()
+        .stack_size(8 * 1024 * 1024)
+        .spawn(|| {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_time()
+                .start_paused(true)
+                .build()
+                .expect("Runtime")
+                .block_on(stacked_layers_paused())
+        })
+        .expect("spawn")
+        .join()
+        .expect("join");
+}
+
+async fn stacked_layers_paused() {
+    const CONCURRENT: usize = 8;
+
+    let origin_calls = Arc::new(atomic::AtomicUsize::default());
+    let origin = service_fn({
+        let origin_calls = origin_calls.clone();
+        move |request: Request<()>| {
+            origin_calls.fetch_add(1, atomic::Ordering::SeqCst);
+            async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                let body = ImmutableBytes::from("Hello, world!\n".repeat(100).into_bytes());
+                let mut response = Response::new(FramesBody::from(body));
+                let (name, value) = match request.uri().path() {
+                    "/scoped" => ("xx-cache--micro", "false"),
+                    _ => ("xx-cache-duration--micro", "2s"),
+                };
+                response.headers_mut().insert(name, HeaderValue::from_static(value));
+                Ok::<_, io::Error>(response)
+            }
+        }
+    });
+
+    let now = Arc::new(Mutex::new(SystemTime::now()));
+    let clock = {
+        let now = now.clone();
+        move || *now.lock().expect("lock")
+    };
+    let (micro_cache, full_cache) = (MockCache::default(), MockCache::default());
+    let full = CachingLayer::<(), MockCache>::default()
+        .cache(full_cache.clone())
+        .clock(clock.clone())
+        .label("full")
+        .role(StackingRole::FullCache);
+    let micro = CachingLayer::<(), MockCache>::default()
+        .cache(micro_cache.clone())
+        .clock(clock)
+        .coalesce_requests(true)
+        .label("micro")
+        .role(StackingRole::MicroCache);
+    let service = micro.layer(full.layer(origin));
+
+    // Returns whether each layer was involved, and whether it hit
+    let get = |path: &'static str| {
+        let request =
+            Request::get(path).header(ACCEPT_ENCODING, "gzip").body(()).expect("Request::get");
+        let response = service.clone().oneshot(request);
+        async move {
+            let response = response.await.expect("oneshot");
+            let encoding = response.headers().get(CONTENT_ENCODING).expect("encoded");
+            assert_eq!(encoding, "gzip", "{}", path);
+            assert!(
+                response.headers().keys().all(|name| !name.as_str().starts_with("xx-")),
+                "{}: control headers leaked: {:?}",
+                path,
+                response.headers()
+            );
+
+            let outcomes = response.extensions().get::<LayerOutcomes>().cloned().expect("outcomes");
+            let outcome = |label| outcomes.get(label).map(LayerOutcome::is_hit);
+            let outcomes = (outcome("micro"), outcome("full"));
+
+            // Decoding once must give us the original
+            assert_eq!(decoded_body(response).await, "Hello, world!\n".repeat(100).as_bytes());
+            outcomes
+        }
+    };
+    let stored = |cache: &MockCache| cache.keys().expect("keys").len();
+
+    // Not for the micro-cache, but still for the full cache
+    assert_eq!(get("/scoped").await, (Some(false), Some(false)));
+    assert_eq!(get("/scoped").await, (Some(false), Some(true)));
+    assert_eq!(origin_calls.load(atomic::Ordering::SeqCst), 1);
+    assert_eq!((stored(&micro_cache), stored(&full_cache)), (0, 1));
+
+    // Concurrent requests in each window: only the leader reaches the full cache
+    for (window, expected_full_hit) in [(0, false), (1, true), (2, true)] {
+        let requests: Vec<_> = (0..CONCURRENT).map(|_| tokio::spawn(get("/stampede"))).collect();
+        let mut reached_full = Vec::default();
+        for request in requests {
+            let (micro_hit, full_hit) = request.await.expect("spawn");
+            assert!(micro_hit.is_some(), "window {}: micro outcome", window);
+            if let Some(full_hit) = full_hit {
+                assert!(micro_hit == Some(false), "window {}: micro hit reached full", window);
+                reached_full.push(full_hit);
+            }
+        }
+        assert_eq!(reached_full, [expected_full_hit], "window {}", window);
+
+        // Beyond the micro TTL, within the full cache's
+        *now.lock().expect("lock") += Duration::from_secs(3);
+    }
+    assert_eq!(origin_calls.load(atomic::Ordering::SeqCst), 2);
+    assert_eq!((stored(&micro_cache), stored(&full_cache)), (1, 2));
+}
+
+// Once a key has been uncacheable for the threshold, its requests skip the lookup except for a
+// sample, and a sampled request that finds it cacheable again resumes caching
+#[tokio::test]
+async fn learned_bypass() {
+    let cacheable = Arc::new(atomic::AtomicBool::new(false));
+    let upstream = {
+        let cacheable = cacheable.clone();
+        service_fn(move |_request: Request<()>| {
+            let body = ImmutableBytes::from(b"rpc".to_vec());
+            let mut response = Response::new(FramesBody::from(body));
+            if !cacheable.load(atomic::Ordering::SeqCst) {
+                response.headers_mut().insert(XX_CACHE, HeaderValue::from_static("false"));
+            }
+            ready(Ok::<_, io::Error>(response))
+        })
+    };
+
+    let cache = CountingCache::default();
+    let learned_bypass = LearnedBypass::new(LearnedBypassConfig {
+        threshold: 3,
+        sample_one_in: 5,
+        ..Default::default()
+    });
+    let mut service = CachingLayer::<(), CountingCache>::default()
+        .cache(cache.clone())
+        .learned_bypass(learned_bypass.clone())
+        .layer(upstream);
+
+    let mut status = async || {
+        let request = Request::get("/api/rpc").body(()).expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+        response.extensions().get::<CacheStatus>().copied()
+    };
+
+    for _ in 0..3 {
+        assert_eq!(status().await, Some(CacheStatus::Miss), "learning");
+    }
+    assert_eq!(cache.take_gets(), 3, "learning: lookups");
+    assert_eq!(learned_bypass.stats().learned, 1, "learned");
+
+    let mut bypassed = 0;
+    for _ in 0..20 {
+        if status().await == Some(CacheStatus::Bypass { reason: "learned" }) {
+            bypassed += 1;
+        }
+    }
+    let stats = learned_bypass.stats();
+    assert_eq!((stats.bypassed, stats.sampled), (16, 4), "bypassed");
+    assert_eq!(bypassed, 16, "bypassed: status");
+    assert_eq!(cache.take_gets(), 4, "bypassed: lookups");
+
+    // Recovery within one sampling period
+    cacheable.store(true, atomic::Ordering::SeqCst);
+    let mut requests = 0;
+    while status().await != Some(CacheStatus::Hit) {
+        requests += 1;
+        assert!(requests <= 5, "recovery");
+    }
+    assert_eq!(learned_bypass.stats().forgotten, 1, "recovered");
+    assert_eq!(cache.take_puts(), 1, "recovered: stores");
+}
+
+// After a generation bump, PreviousIfMissing serves the previous generation's entries until the
+// current generation has its own, ForcePrevious serves them even then until it expires, and
+// Current never looks them up
+#[tokio::test]
+async fn serve_generation() {
+    let content = Arc::new(Mutex::new("old"));
+    let upstream = {
+        let content = content.clone();
+        service_fn(move |_request: Request<()>| {
+            let content = *content.lock().expect("lock");
+            async move {
+                let body = ImmutableBytes::from(content.as_bytes().to_vec());
+                Ok::<_, io::Error>(Response::new(FramesBody::from(body)))
+            }
+        })
+    };
+
+    let generations = CacheGenerations::new(1);
+    let cache = GenerationsCache::default();
+    let mut service = CachingLayer::<(), GenerationsCache>::default()
+        .cache(cache.clone())
+        .generations(generations.clone())
+        .layer(upstream);
+
+    // Returns the cache status, the body, and the generations looked up
+    let mut get = async |path: &'static str| {
+        let request = Request::get(path).body(()).expect("Request::get");
+        let response = service.oneshot_ready(request).await.expect(path);
+        let status = response.extensions().get::<CacheStatus>().map(CacheStatus::as_str);
+        let body = body_bytes(response.into_body()).await;
+        let lookups = mem::take(&mut *cache.1.lock().expect("lock"));
+        (status, String::from_utf8(body).expect("UTF-8"), lookups)
+    };
+
+    let miss = |body: &str, lookups: &[u64]| (Some("MISS"), body.into(), lookups.to_vec());
+    let hit = |body: &str, lookups: &[u64]| (Some("HIT"), body.into(), lookups.to_vec());
+
+    assert_eq!(get("/a").await, miss("old", &[1]), "a: generation 1");
+    assert_eq!(get("/b").await, miss("old", &[1]), "b: generation 1");
+
+    assert_eq!(generations.bump(), 2);
+    *content.lock().expect("lock") = "new";
+    assert_eq!(get("/a").await, miss("new", &[2]), "a: current");
+
+    generations.serve(ServeGeneration::PreviousIfMissing);
+    assert_eq!(get("/a").await, hit("new", &[2]), "a: previous if missing");
+    assert_eq!(get("/b").await, hit("old", &[2, 1]), "b: previous if missing");
+
+    generations.serve(ServeGeneration::ForcePrevious(Duration::from_millis(100)));
+    assert_eq!(get("/a").await, hit("old", &[1]), "a: force previous");
+    assert_eq!(get("/c").await, miss("new", &[1, 2]), "c: force previous");
+
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert_eq!(generations.mode(), ServeGeneration::PreviousIfMissing, "expired");
+    assert_eq!(get("/a").await, hit("new", &[2]), "a: expired");
+
+    generations.serve(ServeGeneration::Current);
+    assert_eq!(get("/b").await, miss("new", &[2]), "b: current");
+    assert_eq!(get("/b").await, hit("new", &[2]), "b: current");
+}
+
+// Records the generations of the keys it looks up
+#[derive(Clone, Default)]
+struct GenerationsCache(MockCache, Arc<Mutex<Vec<u64>>>);
+
+impl Cache for GenerationsCache {
+    async fn get(&self, key: &CommonCacheKey) -> Option<CachedResponseRef> {
+        self.1.lock().expect("lock").extend(key.generation);
+        self.0.get(key).await
+    }
+
+    async fn put(&self, key: CommonCacheKey, cached_response: CachedResponseRef) {
+        self.0.put(key, cached_response).await
+    }
+
+    async fn invalidate(&self, key: &CommonCacheKey) {
+        self.0.invalidate(key).await
+    }
+
+    async fn invalidate_all(&self) {
+        self.0.invalidate_all().await
+    }
+}
+
+// Key fragmentation trips the hit rate SLO within one window, recovery is reported when the hit
+// rate returns, and windows with trickle traffic are not evaluated but merged into the next
+#[tokio::test]
+async fn hit_rate_slo() {
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    let reports = Arc::new(Mutex::new(Vec::default()));
+    let action = {
+        let reports = reports.clone();
+        EmitEvent::new(move |report| reports.lock().expect("lock").push(report.violated))
+    };
+
+    let now = Arc::new(Mutex::new(SystemTime::now()));
+    let clock = {
+        let now = now.clone();
+        move || *now.lock().expect("lock")
+    };
+
+    let mut service = CachingLayer::<(), MockCache>::default()
+        .cache(MockCache::default())
+        .hit_rate_slo(
+            "catalog",
+            PathMatcher::Prefix("/catalog/".into()),
+            SloConfig {
+                target: 0.8,
+                window: WINDOW,
+                min_samples: 20,
+                grace_after_start: Duration::ZERO,
+                action: Arc::new(action),
+                clock: Some(Arc::new(Box::new(clock))),
+            },
+        )
+        .layer(ValidatedUpstream);
+
+    let sessions = |start: usize, end: usize| {
+        (start..end).map(|index| format!("/catalog/1?session={}", index)).collect::<Vec<_>>()
+    };
+    let popular: Vec<_> = (0..40).map(|index| format!("/catalog/{}", index % 4)).collect();
+
+    // Phases: paths, expected reports (true for violation) when the window is evaluated
+    let phases = [
+        ("warm", popular.clone(), vec![]),
+        ("fragmented", sessions(0, 40), vec![true]),
+        ("recovered", popular, vec![false]),
+        ("trickle", sessions(40, 45), vec![]),
+        ("merged", sessions(45, 60), vec![true]),
+    ];
+
+    for (name, paths, expected_reports) in phases {
+        for path in &paths {
+            let request = Request::get(path.as_str()).body(()).expect("Request::get");
+            service.oneshot_ready(request).await.expect(name);
+        }
+
+        // The next request completes the window (and counts as a hit in it)
+        *now.lock().expect("lock") += WINDOW;
+        let request = Request::get("/catalog/0").body(()).expect("Request::get");
+        service.oneshot_ready(request).await.expect(name);
+
+        let reports = mem::take(&mut *reports.lock().expect("lock"));
+        assert_eq!(reports, expected_reports, "{}", name);
+    }
+}
+
+// Slow requests log their decision trail at info level with the dominant phase, and fast requests
+// log nothing at info level
+#[tokio::test]
+async fn log_slow_requests() {
+    let log = Arc::new(Mutex::new(Vec::<u8>::new()));
+    let subscriber = {
+        let log = log.clone();
+        tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::INFO)
+            .with_ansi(false)
+            .with_writer(move || CapturedLog(log.clone()))
+            .finish()
+    };
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let upstream = service_fn(|request: Request<()>| async move {
+        if request.uri().path() == "/slow-upstream" {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        Ok::<_, io::Error>(Response::new(FramesBody::from(ImmutableBytes::from(b"hello".to_vec()))))
+    });
+
+    let take_log = || String::from_utf8(mem::take(&mut *log.lock().expect("lock"))).expect("UTF-8");
+    let request = |path| Request::get(path).body(()).expect("Request::get");
+
+    let mut service = CachingLayer::<(), MockCache>::default()
+        .cache(MockCache::default())
+        .log_slow_over(Duration::from_millis(50))
+        .layer(upstream);
+
+    service.oneshot_ready(request("/fast")).await.expect("fast");
+    assert_eq!(take_log(), "", "fast");
+
+    service.oneshot_ready(request("/slow-upstream")).await.expect("slow upstream");
+    let logged = take_log();
+    assert!(logged.contains("slow: "), "slow upstream: {}", logged);
+    assert!(logged.contains("dominant=\"upstream\""), "slow upstream: {}", logged);
+    assert!(logged.contains("/slow-upstream"), "slow upstream: {}", logged);
+
+    let mut service = CachingLayer::<(), SlowCache>::default()
+        .cache(SlowCache::default())
+        .log_slow_over(Duration::from_millis(50))
+        .layer(upstream);
+
+    service.oneshot_ready(request("/slow-cache")).await.expect("slow cache");
+    let logged = take_log();
+    assert!(logged.contains("dominant=\"lookup\""), "slow cache: {}", logged);
+}
+
+// Decisions beyond those stored inline keep their order, and still count for the trail's status
+// and its display
+#[test]
+fn decision_trail_overflow() {
+    let decisions = ["lookup", "miss", "fenced", "upstream", "body", "encode", "store", "serve"];
+
+    let mut trail = DecisionTrail::default();
+    assert!(trail.decisions.is_empty(), "empty");
+    for decision in decisions {
+        trail.decide(decision);
+    }
+    assert!(!trail.is_hit(), "inline");
+    assert_eq!(CacheStatus::from_trail(&trail), CacheStatus::Miss, "inline");
+
+    trail.decide("retry");
+    trail.decide("hit stale");
+    assert_eq!(trail.decisions.len(), decisions.len() + 2, "len");
+    assert!(!trail.decisions.is_empty(), "overflow");
+    assert!(trail.is_hit(), "overflow");
+    assert_eq!(CacheStatus::from_trail(&trail), CacheStatus::Hit, "overflow");
+
+    let expected: Vec<_> = decisions.into_iter().chain(["retry", "hit stale"]).collect();
+    assert_eq!(trail.decisions.iter().collect::<Vec<_>>(), expected, "order");
+    assert_eq!(trail.decisions.to_string(), expected.join(", "), "display");
+}
+
+// Under the redacted key log policy, error logs name the key without its query text
+#[tokio::test]
+async fn redacted_key_logs() {
+    let log = Arc::new(Mutex::new(Vec::<u8>::new()));
+    let subscriber = {
+        let log = log.clone();
+        tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::ERROR)
+            .with_ansi(false)
+            .with_writer(move || CapturedLog(log.clone()))
+            .finish()
+    };
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let mut service = CachingLayer::<(), MockCache>::default()
+        .cache(MockCache::default())
+        .key_log_policy(KeyLogPolicy::redacted())
+        .on_store(|_event| panic!("store hook"))
+        .layer(ValidatedUpstream);
+
+    let query = "hunter2".repeat(1024);
+    let request = Request::get(format!("/search?q={}", query)).body(()).expect("Request::get");
+    let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let logged = String::from_utf8(mem::take(&mut *log.lock().expect("lock"))).expect("UTF-8");
+    assert!(logged.contains("store hook panicked"), "{}", logged);
+    assert!(logged.contains("/search"), "{}", logged);
+    assert!(!logged.contains("hunter2"), "{}", logged);
+}
+
+// Log output captured by a tracing subscriber
+struct CapturedLog(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for CapturedLog {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.lock().expect("lock").extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// With assume_inner_compression, a compression middleware either below or above the layer never
+// causes a double-encoded body, and without it an encoded upstream response warns once per route
+#[tokio::test]
+async fn compression_interop() {
+    let below = |request: Request<()>| async move {
+        let response = ValidatedUpstream.oneshot(request).await?;
+        Ok::<_, io::Error>(gzip_shim(response).await)
+    };
+
+    let request = |path| {
+        Request::get(path)
+            .header(ACCEPT_ENCODING, "br, gzip")
+            .body(())
+            .expect("Request::get")
+    };
+
+    let mut service = CachingLayer::<(), MockCache>::default()
+        .cache(MockCache::default())
+        .assume_inner_compression(true)
+        .layer(service_fn(below));
+    for name in ["below: miss", "below: hit"] {
+        let response = service.oneshot_ready(request("/below")).await.expect(name);
+        assert_eq!(decoded_body(response).await, b"hello", "{}", name);
+    }
+
+    let cache = MockCache::default();
+    let service = CachingLayer::<(), MockCache>::default()
+        .cache(cache.clone())
+        .assume_inner_compression(true)
+        .layer(ValidatedUpstream);
+    let mut above = service_fn(move |request: Request<()>| {
+        let service = service.clone();
+        async move {
+            let response = service.oneshot(request).await?;
+            Ok::<_, io::Error>(gzip_shim(response).await)
+        }
+    });
+    for name in ["above: miss", "above: hit"] {
+        let response = above.ready().await.expect(name).call(request("/above")).await.expect(name);
+        assert_eq!(
+            response.headers().get(CONTENT_ENCODING),
+            Some(&HeaderValue::from_static("gzip")),
+            "{}",
+            name
+        );
+        assert_eq!(decoded_body(response).await, b"hello", "{}", name);
+    }
+    let cached_response = cache.get(&key("/above")).await.expect("above: stored");
+    let codings: Vec<_> = cached_response.body.representations.keys().collect();
+    assert_eq!(codings, [&CodingId::IDENTITY], "above: stored");
+
+    let log = Arc::new(Mutex::new(Vec::<u8>::new()));
+    let subscriber = {
+        let log = log.clone();
+        tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::WARN)
+            .with_ansi(false)
+            .with_writer(move || CapturedLog(log.clone()))
+            .finish()
+    };
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    // The queries make for different keys (misses) on the same route
+    let mut service = CachingLayer::<(), MockCache>::default()
+        .cache(MockCache::default())
+        .layer(service_fn(below));
+    for path in ["/a?1", "/a?2", "/b", "/a?3"] {
+        service.oneshot_ready(request(path)).await.expect(path);
+    }
+
+    let logged = String::from_utf8(mem::take(&mut *log.lock().expect("lock"))).expect("UTF-8");
+    assert!(logged.contains(COMPRESSION_CONFLICT_HELP), "help: {}", logged);
+    for path in ["/a", "/b"] {
+        let warning = format!("compression conflict for {} ", path);
+        assert_eq!(logged.matches(&warning).count(), 1, "{}: {}", path, logged);
+    }
+}
+
+// Body decoded according to its Content-Encoding
+async fn decoded_body<BodyT>(response: Response<BodyT>) -> Vec<u8>
+where
+    BodyT: Body,
+    BodyT::Error: fmt::Debug,
+{
+    let encoding: Encoding = response.headers().content_encoding().into();
+    let body = ImmutableBytes::from(body_bytes(response.into_body()).await);
+    body.decode(&encoding).await.expect("decode").to_vec()
+}
+
+// Encodes the response as GZip unless it is already encoded, as a compression middleware would
+async fn gzip_shim<BodyT>(response: Response<BodyT>) -> Response<FramesBody>
+where
+    BodyT: Body,
+    BodyT::Error: fmt::Debug,
+{
+    let (mut parts, body) = response.into_parts();
+    let mut bytes = ImmutableBytes::from(body_bytes(body).await);
+    if !parts.headers.contains_key(CONTENT_ENCODING) {
+        bytes = bytes.encode(&Encoding::GZip).await.expect("encode");
+        parts.headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        parts.headers.remove(CONTENT_LENGTH);
+    }
+    Response::from_parts(parts, FramesBody::from(bytes))
+}
+
+// Upstream that counts its calls and responds with the count after a delay
+#[cfg(feature = "idempotency")]
+fn counting_upstream(
+    calls: Arc<atomic::AtomicUsize>,
+) -> impl Service<
+    Request<FramesBody>,
+    Response = Response<FramesBody>,
+    Error = io::Error,
+    Future: Send,
+> + Clone
++ Send {
+    service_fn(move |_request: Request<FramesBody>| {
+        let calls = calls.clone();
+        async move {
+            let call = calls.fetch_add(1, atomic::Ordering::SeqCst) + 1;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let body = ImmutableBytes::from(format!("call {}", call).into_bytes());
+            Ok(Response::new(FramesBody::from(body)))
+        }
+    })
+}
+
+#[cfg(feature = "idempotency")]
+fn idempotent_request(path: &str, key: &str, body: &'static str) -> Request<FramesBody> {
+    Request::post(path)
+        .header(IDEMPOTENCY_KEY, key)
+        .header(CONTENT_LENGTH, body.len())
+        .body(FramesBody::from(ImmutableBytes::from(body.as_bytes().to_vec())))
+        .expect("Request::post")
+}
+
+#[cfg(feature = "idempotency")]
+fn idempotent_service(
+    idempotency: IdempotencyConfig,
+    calls: Arc<atomic::AtomicUsize>,
+) -> CachingService<
+    impl Service<
+        Request<FramesBody>,
+        Response = Response<FramesBody>,
+        Error = io::Error,
+        Future: Send,
+    > + Clone
+    + Send,
+    FramesBody,
+    SimpleLruCache,
+> {
+    CachingLayer::<FramesBody, SimpleLruCache>::default()
+        .idempotency(idempotency)
+        .layer(counting_upstream(calls))
+}
+
+// A retry with the same key and payload is replayed, one with another payload (but the same
+// headers) is rejected, and requests to other paths are not deduplicated
+#[cfg(feature = "idempotency")]
+#[tokio::test]
+async fn idempotency_replay() {
+    let calls = Arc::new(atomic::AtomicUsize::default());
+    let idempotency = IdempotencyConfig::new(PathMatcher::Prefix("/orders".into()));
+    let mut service = idempotent_service(idempotency, calls.clone());
+
+    let replayed = |response: &Response<_>| response.headers().get(IDEMPOTENCY_REPLAYED).cloned();
+
+    let request = || idempotent_request("/orders", "a", "{}");
+    let first = service.oneshot_ready(request()).await.expect("first");
+    assert_eq!(replayed(&first), None);
+    assert_eq!(body_bytes(first.into_body()).await, b"call 1");
+
+    let retry = service.oneshot_ready(request()).await.expect("retry");
+    assert_eq!(replayed(&retry), Some(HeaderValue::from_static("true")));
+    assert_eq!(body_bytes(retry.into_body()).await, b"call 1");
+
+    let request = idempotent_request("/orders", "a", "[]");
+    let mismatch = service.oneshot_ready(request).await.expect("mismatch");
+    assert_eq!(mismatch.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    assert_eq!(calls.load(atomic::Ordering::SeqCst), 1);
+
+    for call in [2, 3] {
+        let request = idempotent_request("/other", "a", "{}");
+        let response = service.oneshot_ready(request).await.expect("other path");
+        assert_eq!(replayed(&response), None);
+        assert_eq!(body_bytes(response.into_body()).await, format!("call {}", call).as_bytes());
+    }
+}
+
+// Concurrent duplicates wait for the first execution instead of executing themselves
+#[cfg(feature = "idempotency")]
+#[tokio::test]
+async fn idempotency_single_flight() {
+    let calls = Arc::new(atomic::AtomicUsize::default());
+    let idempotency = IdempotencyConfig::new(PathMatcher::Prefix("/orders".into()));
+    let service = idempotent_service(idempotency, calls.clone());
+
+    let call = || {
+        let mut service = service.clone();
+        async move {
+            let request = idempotent_request("/orders", "a", "{}");
+            let response = service.oneshot_ready(request).await.expect("oneshot_ready");
+            body_bytes(response.into_body()).await
+        }
+    };
+
+    let (first, second, third) = tokio::join!(call(), call(), call());
+    for body in [first, second, third] {
+        assert_eq!(body, b"call 1");
+    }
+    assert_eq!(calls.load(atomic::Ordering::SeqCst), 1);
+}
+
+// Kept responses expire, after which the key executes again
+#[cfg(feature = "idempotency")]
+#[tokio::test]
+async fn idempotency_ttl() {
+    let calls = Arc::new(atomic::AtomicUsize::default());
+    let idempotency = IdempotencyConfig::new(PathMatcher::Prefix("/orders".into()))
+        .ttl(Duration::from_millis(100));
+    let mut service = idempotent_service(idempotency, calls.clone());
+
+    let request = || idempotent_request("/orders", "a", "{}");
+    service.oneshot_ready(request()).await.expect("first");
+    service.oneshot_ready(request()).await.expect("retry");
+    assert_eq!(calls.load(atomic::Ordering::SeqCst), 1);
+
+    tokio::time::sleep(Duration::from_millis(150)).await;
+
+    let response = service.oneshot_ready(request()).await.expect("expired");
+    assert!(!response.headers().contains_key(IDEMPOTENCY_REPLAYED), "expired: replayed");
+    assert_eq!(calls.load(atomic::Ordering::SeqCst), 2);
+}
+
+// Duplicates that outwait the timeout are rejected with a conflict, and without a required key
+// requests are rejected before executing
+#[cfg(feature = "idempotency")]
+#[tokio::test]
+async fn idempotency_rejections() {
+    let calls = Arc::new(atomic::AtomicUsize::default());
+    let idempotency = IdempotencyConfig::new(PathMatcher::Prefix("/orders".into()))
+        .wait_timeout(Duration::from_millis(10))
+        .require_key(true);
+    let service = idempotent_service(idempotency, calls.clone());
+
+    let call = |key| {
+        let mut service = service.clone();
+        async move {
+            let mut request = idempotent_request("/orders", key, "{}");
+            if key.is_empty() {
+                request.headers_mut().remove(IDEMPOTENCY_KEY);
+            }
+            service.oneshot_ready(request).await.expect("oneshot_ready").status()
+        }
+    };
+
+    let (first, duplicate) = tokio::join!(call("a"), call("a"));
+    assert_eq!((first, duplicate), (StatusCode::OK, StatusCode::CONFLICT));
+    assert_eq!(calls.load(atomic::Ordering::SeqCst), 1);
+
+    assert_eq!(call("").await, StatusCode::BAD_REQUEST);
+    assert_eq!(calls.load(atomic::Ordering::SeqCst), 1);
+}
+
+// Error responses are kept and replayed as received, never encoded, and kept responses are not
+// cache entries, so invalidation doesn't affect them
+#[cfg(feature = "idempotency")]
+#[tokio::test]
+async fn idempotency_isolation() {
+    let calls = Arc::new(atomic::AtomicUsize::default());
+    let upstream = service_fn({
+        let calls = calls.clone();
+        move |_request: Request<FramesBody>| {
+            let call = calls.fetch_add(1, atomic::Ordering::SeqCst) + 1;
+            let body = ImmutableBytes::from(format!("failure {}", call).repeat(64).into_bytes());
+            let mut response = Response::new(FramesBody::from(body));
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            response.headers_mut().insert(RETRY_AFTER, HeaderValue::from_static("5"));
+            ready(Ok::<_, io::Error>(response))
+        }
+    });
+
+    let cache = MockCache::default();
+    let idempotency = IdempotencyConfig::new(PathMatcher::Prefix("/orders".into()));
+    let layer = CachingLayer::<FramesBody, MockCache>::default()
+        .cache(cache.clone())
+        .idempotency(idempotency);
+    let mut service = layer.layer(upstream);
+
+    let request = || {
+        let mut request = idempotent_request("/orders", "a", "{}");
+        request.headers_mut().insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        request
+    };
+
+    let first = service.oneshot_ready(request()).await.expect("first");
+    let first_headers = first.headers().clone();
+    let first_body = body_bytes(first.into_body()).await;
+
+    assert_eq!(layer.invalidate_request(&mut request()).await, 0);
+    cache.invalidate_all().await;
+
+    let retry = service.oneshot_ready(request()).await.expect("retry");
+    assert_eq!(retry.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(retry.headers().get(IDEMPOTENCY_REPLAYED), Some(&HeaderValue::from_static("true")));
+    assert_eq!(retry.headers().get(RETRY_AFTER), first_headers.get(RETRY_AFTER));
+    assert_eq!(retry.headers().get(CONTENT_ENCODING), None);
+    assert_eq!(body_bytes(retry.into_body()).await, first_body);
+
+    assert_eq!(calls.load(atomic::Ordering::SeqCst), 1);
+    assert_eq!(cache.keys().expect("keys").len(), 0);
+}
+
+// Concurrent misses for the variants of a resource call the upstream at most N at a time, all of
+// them are eventually stored, and requests that are not grouped are not throttled
+#[tokio::test]
+async fn resource_limiter() {
+    // Per path: calls in flight, maximum calls in flight
+    let in_flight = Arc::new(Mutex::new(std::collections::HashMap::<_, (usize, usize)>::new()));
+    let upstream = {
+        let in_flight = in_flight.clone();
+        service_fn(move |request: Request<()>| {
+            let in_flight = in_flight.clone();
+            async move {
+                let path = request.uri().path().to_string();
+                {
+                    let mut in_flight = in_flight.lock().expect("lock");
+                    let (current, max) = in_flight.entry(path.clone()).or_default();
+                    *current += 1;
+                    *max = (*max).max(*current);
+                }
+
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                in_flight.lock().expect("lock").entry(path).or_default().0 -= 1;
+
+                Ok::<_, io::Error>(Response::new(FramesBody::from(ImmutableBytes::from(
+                    b"hello".to_vec(),
+                ))))
+            }
+        })
+    };
+
+    let cache = MockCache::default();
+    let service = CachingLayer::<(), MockCache>::default()
+        .cache(cache.clone())
+        .resource_id(|context| {
+            let path = context.uri.path();
+            (path == "/page").then(|| path.into())
+        })
+        .max_concurrent_per_resource(2)
+        .layer(upstream);
+
+    let languages = ["en", "fr", "de", "es", "it", "pt", "ja", "zh"];
+    let paths: Vec<_> = languages
+        .iter()
+        .map(|language| format!("/page?lang={}", language))
+        .chain(languages[..4].iter().map(|language| format!("/other?lang={}", language)))
+        .collect();
+
+    let tasks: Vec<_> = paths
+        .iter()
+        .map(|path| {
+            let service = service.clone();
+            let request = Request::get(path.as_str()).body(()).expect("Request::get");
+            tokio::spawn(service.oneshot(request))
+        })
+        .collect();
+    for task in tasks {
+        task.await.expect("join").expect("oneshot");
+    }
+
+    for path in &paths {
+        assert!(cache.get(&key(path)).await.is_some(), "{}: stored", path);
+    }
+    let in_flight = in_flight.lock().expect("lock");
+    assert_eq!(in_flight["/page"], (0, 2), "throttled");
+    assert_eq!(in_flight["/other"], (0, 4), "not grouped");
+}
+
+// The request's cache key and cacheability are computed once per request, whether it misses, hits,
+// or hits conditionally, and the stored entry is found by the hooked key
+#[tokio::test]
+async fn request_context_computed_once() {
+    let cache = MockCache::default();
+    let cache_key_calls = Arc::new(atomic::AtomicUsize::default());
+    let cacheable_calls = Arc::new(atomic::AtomicUsize::default());
+    let mut service = {
+        let (cache_key_calls, cacheable_calls) = (cache_key_calls.clone(), cacheable_calls.clone());
+        CachingLayer::<(), MockCache>::default()
+            .cache(cache.clone())
+            .cache_key(move |context| {
+                cache_key_calls.fetch_add(1, atomic::Ordering::SeqCst);
+                context.cache_key.query = None;
+            })
+            .cacheable_by_request(move |_context| {
+                cacheable_calls.fetch_add(1, atomic::Ordering::SeqCst);
+                true
+            })
+            .layer(ValidatedUpstream)
+    };
+
+    let requests = [("/context?a=1", None), ("/context?a=2", None), ("/context", Some(()))];
+    for (calls, (uri, conditional)) in (1..).zip(requests) {
+        let mut request = Request::get(uri).body(()).expect("Request::get");
+        if conditional.is_some() {
+            request.headers_mut().insert(IF_NONE_MATCH, ValidatedUpstream::etag());
+        }
+        service.oneshot_ready(request).await.expect(uri);
+
+        assert_eq!(cache_key_calls.load(atomic::Ordering::SeqCst), calls, "{}: cache_key", uri);
+        assert_eq!(cacheable_calls.load(atomic::Ordering::SeqCst), calls, "{}: cacheable", uri);
+    }
+
+    assert_eq!(cache.entry_count(), Some(1), "entries");
+    assert_version(&cache, "/context", Some("\"v1\"")).await;
+}
+
+// Golden sequences of stages and extension points (all of them probed at once) for a miss, a hit,
+// a conditional hit, a hit that reencodes, a request that skips the cache, a HEAD miss, and an
+// upstream error
+#[cfg(feature = "test-util")]
+#[tokio::test]
+async fn pipeline_golden_sequences() {
+    let probe = PipelineProbe::default();
+    let layer = CachingLayer::<(), SimpleLruCache>::default()
+        .cache(SimpleLruCache::new(1024 * 1024, None))
+        .bypass_cache_on_headers(&[COOKIE])
+        .pipeline_probe(probe.clone())
+        .cacheable_by_request({
+            let probe = probe.clone();
+            move |context| {
+                probe.record_extension("cacheable_by_request", context.uri.path());
+                true
+            }
+        })
+        .cache_key({
+            let probe = probe.clone();
+            move |context| probe.record_extension("cache_key", context.request.uri().path())
+        })
+        .resource_id({
+            let probe = probe.clone();
+            move |context| {
+                probe.record_extension("resource_id", context.uri.path());
+                None
+            }
+        })
+        .encodable_by_request({
+            let probe = probe.clone();
+            move |context| {
+                probe.record_extension("encodable_by_request", context.coding);
+                true
+            }
+        })
+        .cacheable_by_response({
+            let probe = probe.clone();
+            move |context| {
+                probe.record_extension("cacheable_by_response", context.uri.path());
+                true
+            }
+        })
+        .encodable_by_response({
+            let probe = probe.clone();
+            move |context| {
+                probe.record_extension("encodable_by_response", context.coding);
+                true
+            }
+        })
+        .cache_duration({
+            let probe = probe.clone();
+            move |context| {
+                probe.record_extension("cache_duration", context.uri.path());
+                None
+            }
+        })
+        .transform_before_store({
+            let probe = probe.clone();
+            move |context| {
+                probe.record_extension("transform_before_store", context.uri.path());
+                Ok(None)
+            }
+        })
+        .on_trailers({
+            let probe = probe.clone();
+            move |context| probe.record_extension("on_trailers", context.uri.path())
+        })
+        .on_store({
+            let probe = probe.clone();
+            move |event| probe.record_extension("on_store", event.pathway)
+        })
+        .on_cache_event({
+            let probe = probe.clone();
+            move |event| match event.kind {
+                // The size depends on the compression
+                CacheEventKind::Store { .. } => probe.record_extension("on_cache_event", "store"),
+                CacheEventKind::ReencodeInCache { to, .. } => {
+                    probe.record_extension("on_cache_event", format!("reencode to {}", to))
+                }
+                kind => probe.record_extension("on_cache_event", kind),
+            }
+        });
+
+    let upstream = service_fn(|request: Request<()>| async move {
+        if request.uri().path() == "/golden-error" {
+            return Err(io::Error::other("upstream error"));
+        }
+        ValidatedUpstream.call(request).await
+    });
+    let mut service = layer.layer(upstream);
+
+    let request = |method, path, encoding, header: Option<(HeaderName, HeaderValue)>| {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(ACCEPT_ENCODING, encoding)
+            .body(())
+            .expect("Request::builder");
+        if let Some((name, value)) = header {
+            request.headers_mut().insert(name, value);
+        }
+        request
+    };
+
+    let scenarios = [
+        (
+            "miss",
+            request(Method::GET, "/golden", "br", None),
+            "request\n+cacheable_by_request /golden\n+cache_key /golden\n+resource_id /golden\n\
+             +encodable_by_request br\nlookup\nconditional\n+on_cache_event miss\n\
+             +encodable_by_request br\nupstream\nresponse\n+cacheable_by_response /golden\n\
+             +encodable_by_response br\n+encodable_by_response br\n+cache_duration /golden\n\
+             +transform_before_store /golden\nfeedback\nstore\n+on_store miss\n\
+             +on_cache_event store\nserve\nrecord\n",
+        ),
+        (
+            "hit",
+            request(Method::GET, "/golden", "br", None),
+            "request\n+cacheable_by_request /golden\n+cache_key /golden\n+resource_id /golden\n\
+             +encodable_by_request br\nlookup\nconditional\n+on_cache_event hit\nfeedback\nstore\n\
+             serve\nrecord\n",
+        ),
+        (
+            "not modified",
+            request(
+                Method::GET,
+                "/golden",
+                "br",
+                Some((IF_NONE_MATCH, ValidatedUpstream::etag())),
+            ),
+            "request\n+cacheable_by_request /golden\n+cache_key /golden\n+resource_id /golden\n\
+             +encodable_by_request br\nlookup\nconditional\n+on_cache_event hit (not modified)\n\
+             feedback\nstore\nserve\nrecord\n",
+        ),
+        (
+            "reencode",
+            request(Method::GET, "/golden", "gzip", None),
+            "request\n+cacheable_by_request /golden\n+cache_key /golden\n+resource_id /golden\n\
+             +encodable_by_request gzip\nlookup\nconditional\n+on_cache_event hit\n\
+             +on_cache_event reencode to gzip\nfeedback\nstore\n+on_store reencode\nserve\n\
+             record\n",
+        ),
+        (
+            "skip",
+            request(Method::GET, "/golden", "br", Some((COOKIE, HeaderValue::from_static("a=1")))),
+            "request\n+encodable_by_request br\nlookup\n+on_cache_event skip request (request)\n\
+             upstream\nresponse\n+encodable_by_response br\nfeedback\nstore\nserve\nrecord\n",
+        ),
+        (
+            "HEAD miss",
+            request(Method::HEAD, "/golden-head", "br", None),
+            "request\n+cacheable_by_request /golden-head\n+cache_key /golden-head\n\
+             +resource_id /golden-head\n+encodable_by_request br\nlookup\nconditional\n\
+             +on_cache_event miss\nupstream\nresponse\nfeedback\nstore\nserve\nrecord\n",
+        ),
+        (
+            "error",
+            request(Method::GET, "/golden-error", "br", None),
+            "request\n+cacheable_by_request /golden-error\n+cache_key /golden-error\n\
+             +resource_id /golden-error\n+encodable_by_request br\nlookup\nconditional\n\
+             +on_cache_event miss\n+encodable_by_request br\nupstream\nrecord\n",
+        ),
+    ];
+
+    for (name, request, golden) in scenarios {
+        probe.clear();
+        let response = service.oneshot_ready(request).await;
+        assert_eq!(response.is_err(), name == "error", "{}: error", name);
+        assert_eq!(probe.sequence(), golden, "{}", name);
+    }
+}
+
+// Entering a stage after a later one fails loudly
+#[cfg(debug_assertions)]
+#[test]
+#[should_panic(expected = "pipeline stage out of order")]
+fn pipeline_out_of_order() {
+    let mut stages = PipelineStages::default();
+    stages.enter(PipelineStage::Store, None);
+    stages.enter(PipelineStage::Lookup, None);
+}
@@ -0,0 +1,88 @@
+mod common;
+
+use {
+    common::*,
+    std::{alloc::*, cell::*, mem},
+    tower_http_response_cache::cache::{middleware::*, *},
+};
+
+// Deep memory measurement needs to see every allocation, so this test has its own binary with a
+// counting global allocator
+
+// The declared cache weight of a synthetic corpus of entries must be within
+// WEIGHT_ESTIMATE_FACTOR of the heap memory they actually retain (plus their inline size)
+#[test]
+fn weight_memory() {
+    for header_count in [0, 8, 64] {
+        for coding_count in [1, 3] {
+            for dependency_count in [0, 16] {
+                for body_size in [1024, 64 * 1024] {
+                    let before = live_bytes();
+                    let cached_response =
+                        synthetic_entry(header_count, coding_count, dependency_count, body_size);
+                    let retained = live_bytes() - before;
+
+                    let sample = WeightSample {
+                        key: format!(
+                            "headers={} codings={} dependencies={} body={}",
+                            header_count, coding_count, dependency_count, body_size
+                        ),
+                        declared: cached_response.cache_weight(),
+                        actual: retained.max(0) as usize + mem::size_of::<CachedResponse>(),
+                    };
+
+                    assert!(!sample.diverges(WEIGHT_ESTIMATE_FACTOR), "{}", sample);
+                }
+            }
+        }
+    }
+}
+
+//
+// CountingAllocator
+//
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+thread_local! {
+    static LIVE_BYTES: Cell<isize> = const { Cell::new(0) };
+}
+
+// Bytes allocated and not yet freed by the current thread.
+fn live_bytes() -> isize {
+    LIVE_BYTES.with(Cell::get)
+}
+
+/// Allocator that counts the live bytes of each thread.
+struct CountingAllocator;
+
+impl CountingAllocator {
+    fn count(bytes: isize) {
+        // Allocations made while the thread is being torn down are not counted
+        _ = LIVE_BYTES.try_with(|live_bytes| live_bytes.set(live_bytes.get() + bytes));
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let pointer = unsafe { System.alloc(layout) };
+        if !pointer.is_null() {
+            Self::count(layout.size() as isize);
+        }
+        pointer
+    }
+
+    unsafe fn dealloc(&self, pointer: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(pointer, layout) };
+        Self::count(-(layout.size() as isize));
+    }
+
+    unsafe fn realloc(&self, pointer: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_pointer = unsafe { System.realloc(pointer, layout, new_size) };
+        if !new_pointer.is_null() {
+            Self::count(new_size as isize - layout.size() as isize);
+        }
+        new_pointer
+    }
+}